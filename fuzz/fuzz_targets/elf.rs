@@ -0,0 +1,20 @@
+// Arbitrary bytes as an executable for the loader's parser
+//
+// Anything `parse` accepts must have every load segment's file data in
+// bounds and no address range that wraps.
+
+#![no_main]
+
+use elf_parser::Elf;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(elf) = Elf::parse(data) else { return };
+    for segment in elf.load_segments() {
+        let bytes = elf.segment_data(&segment);
+        assert_eq!(bytes.len() as u64, segment.filesz);
+        assert!(segment.filesz <= segment.memsz);
+        assert!(segment.vaddr.checked_add(segment.memsz).is_some());
+    }
+    let _ = elf.program_headers().count();
+});
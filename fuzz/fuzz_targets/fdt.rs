@@ -0,0 +1,20 @@
+// Arbitrary bytes as a device tree blob
+//
+// Whatever `validate` accepts, the kernel's pointer walkers will follow,
+// so it must agree exactly with the owned parser, and what the owned
+// parser reads must survive a round trip.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let valid = fdt_parser::validate(data).is_ok();
+    let parsed = fdt_parser::unflatten(data);
+    assert_eq!(valid, parsed.is_ok(), "validate and unflatten disagree");
+    let Ok(fdt) = parsed else { return };
+    
+    let blob = fdt_parser::flatten(&fdt);
+    assert!(fdt_parser::validate(&blob).is_ok(), "flatten produced an invalid blob");
+    assert_eq!(fdt_parser::unflatten(&blob).as_ref(), Ok(&fdt), "round trip changed the tree");
+});
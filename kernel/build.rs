@@ -0,0 +1,83 @@
+// Link-time kernel layout
+//
+// The kernel is linked at KERNEL_VIRT_OFFSET above its load address. With
+// the no-mmu feature it runs at its physical address, so the offset is 0.
+// Must match KERNEL_VIRT_OFFSET in src/memory/paging.rs.
+//
+// The load address depends on the board (a board-* feature, else QEMU
+// virt) and must match its `load_address` in src/board.
+//
+// With the MMU the kernel is linked position-independent, keeping a
+// relocation for every absolute address in .rela.dyn so that early boot
+// can move it to a random base (src/memory/kaslr.rs). The link-time values
+// are still written in place, so the image also runs where it was linked.
+//
+// linker.ld includes unwind.ld from OUT_DIR: with the eh-unwind feature it
+// keeps .eh_frame and its search table, otherwise it discards .eh_frame.
+//
+// The ssp feature is refused unless the rustflags turn on stack protection.
+
+use std::path::PathBuf;
+
+const KEEP_UNWIND: &str = "\
+.eh_frame_hdr : AT(ADDR(.eh_frame_hdr) - KERNEL_VIRT_OFFSET) {
+    __eh_frame_hdr_start = .;
+    KEEP(*(.eh_frame_hdr))
+    __eh_frame_hdr_end = .;
+}
+.eh_frame : AT(ADDR(.eh_frame) - KERNEL_VIRT_OFFSET) {
+    __eh_frame_start = .;
+    KEEP(*(.eh_frame))
+    __eh_frame_end = .;
+}
+";
+
+const DISCARD_UNWIND: &str = "\
+/DISCARD/ : {
+    *(.eh_frame)
+}
+";
+
+fn main() {
+    let offset: u64 = if std::env::var_os("CARGO_FEATURE_NO_MMU").is_some() {
+        0
+    } else {
+        0xFFFF_0000_0000_0000
+    };
+    println!("cargo:rustc-link-arg-bins=--defsym=KERNEL_VIRT_OFFSET={:#x}", offset);
+    let load_address: u64 = if std::env::var_os("CARGO_FEATURE_BOARD_RPI4").is_some() {
+        0x8_0000
+    } else {
+        0x4008_0000
+    };
+    println!("cargo:rustc-link-arg-bins=--defsym=KERNEL_PHYS_BASE={:#x}", load_address);
+    if offset != 0 {
+        // Text holds literal pools (`ldr x0, =symbol`), hence -znotext
+        for arg in ["-pie", "-znotext", "--apply-dynamic-relocs", "--no-dynamic-linker"] {
+            println!("cargo:rustc-link-arg-bins={}", arg);
+        }
+    }
+    
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    let unwind = if std::env::var_os("CARGO_FEATURE_EH_UNWIND").is_some() {
+        KEEP_UNWIND
+    } else {
+        DISCARD_UNWIND
+    };
+    std::fs::write(out_dir.join("unwind.ld"), unwind).unwrap();
+    println!("cargo:rustc-link-arg-bins=-L{}", out_dir.display());
+    
+    // The ssp self-test overruns a buffer on purpose; unprotected, that
+    // corrupts a real return address
+    if std::env::var_os("CARGO_FEATURE_SSP").is_some() {
+        let flags = std::env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+        let protected = flags.split('\x1f').any(|flag| {
+            flag.contains("stack-protector") && !flag.ends_with("none")
+        });
+        if !protected {
+            panic!("the ssp feature needs -Z stack-protector in the rustflags (make SSP=1)");
+        }
+    }
+    println!("cargo:rerun-if-changed=linker.ld");
+    println!("cargo:rerun-if-changed=build.rs");
+}
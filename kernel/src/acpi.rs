@@ -0,0 +1,591 @@
+// Minimal ACPI support: a hardware description for boots without a DTB
+//
+// UEFI/ACPI-only platforms hand over an RSDP instead of a device tree. The
+// loader passes its physical address in x0, where a DTB boot would put the
+// tree (QEMU's direct kernel boot leaves it zero); a value without the
+// "RSD PTR " signature is simply not ACPI.
+//
+// Only the tables the kernel has a use for are read: MADT (GIC and CPUs),
+// GTDT (architected timer interrupts, SBSA watchdogs), SPCR (console
+// UART) and the FADT's ARM boot flags (PSCI conduit). Rather than teaching
+// every driver a second description format, they are translated into a
+// device tree with the same nodes and properties QEMU's generated one
+// has, which is then installed with set_active_blob. Everything after that, overlays included, works as
+// on a DTB boot.
+//
+// The tables must lie in RAM: they are read through the linear map, and
+// their frames are claimed before the allocator hands anything out.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use fdt_parser::{flatten, Fdt, Node};
+use spin::Mutex;
+use crate::devicetree::{device_tree, set_active_blob, DeviceTree, MemoryRegion};
+use crate::ktest::kernel_test;
+use crate::memory::frame_allocator::{self, PAGE_SIZE};
+use crate::memory::paging::phys_to_virt;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V2_LEN: usize = 36;
+pub const SDT_HEADER_LEN: usize = 36;
+
+// MADT interrupt controller structure types
+const MADT_GICC: u8 = 0x0B;
+const MADT_GICD: u8 = 0x0C;
+const MADT_GICR: u8 = 0x0E;
+const MADT_ENTRIES: usize = 44;
+const GICC_ENABLED: u32 = 1 << 0;
+const GICC_ONLINE_CAPABLE: u32 = 1 << 3;
+
+// GTDT timer flags
+const GTDT_EDGE: u32 = 1 << 0;
+const GTDT_ACTIVE_LOW: u32 = 1 << 1;
+
+// GTDT platform timers: count and offset of the array, and the SBSA
+// watchdog structure with its flags (the interrupt ones as above)
+const GTDT_PLATFORM_COUNT: usize = 88;
+const GTDT_PLATFORM_OFFSET: usize = 92;
+const GTDT_WATCHDOG: u8 = 1;
+const GTDT_WATCHDOG_LEN: usize = 28;
+const GTDT_WATCHDOG_SECURE: u32 = 1 << 2;
+
+// SPCR interface types the PL011 driver can run
+const SPCR_PL011: u8 = 0x03;
+const SPCR_SBSA_GENERIC: u8 = 0x0E;
+const SPCR_IRQ_GSIV: u8 = 1 << 3;
+
+// FADT ARM boot architecture flags
+const FADT_ARM_BOOT_FLAGS: usize = 129;
+const FADT_PSCI_COMPLIANT: u16 = 1 << 0;
+const FADT_PSCI_USE_HVC: u16 = 1 << 1;
+
+// Device tree interrupt specifiers, as in QEMU's tree
+const DT_SPI: u32 = 0;
+const DT_PPI: u32 = 1;
+const DT_EDGE_RISING: u32 = 1;
+const DT_EDGE_FALLING: u32 = 2;
+const DT_LEVEL_HIGH: u32 = 4;
+const DT_LEVEL_LOW: u32 = 8;
+const GIC_PHANDLE: u32 = 1;
+
+// Register block sizes ACPI leaves implicit
+const GICV2_DIST_SIZE: u64 = 0x1000;
+const GICV2_CPU_SIZE: u64 = 0x2000;
+const GICV3_DIST_SIZE: u64 = 0x10000;
+const GICV3_REDIST_STRIDE: u64 = 0x20000;
+const UART_SIZE: u64 = 0x1000;
+const WATCHDOG_FRAME_SIZE: u64 = 0x1000;
+
+// Boot argument from x0, recorded before anything else runs
+static BOOT_ARG: AtomicU64 = AtomicU64::new(0);
+// Tables found by reserve(), with the RAM they were checked against
+static FIRMWARE: Mutex<Option<(Tables, MemoryRegion)>> = Mutex::new(None);
+
+/// Physical location and length of one table.
+pub type TableRef = Option<(u64, usize)>;
+
+/// The tables the kernel reads. A table that is missing or fails its
+/// checksum is left out; only a bad RSDP or XSDT fails the whole lookup.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tables {
+    pub rsdp: TableRef,
+    pub xsdt: TableRef,
+    pub madt: TableRef,
+    pub gtdt: TableRef,
+    pub spcr: TableRef,
+    pub fadt: TableRef,
+}
+
+impl Tables {
+    fn all(&self) -> [TableRef; 6] {
+        [self.rsdp, self.xsdt, self.madt, self.gtdt, self.spcr, self.fadt]
+    }
+}
+
+fn le(data: &[u8], offset: usize, len: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + len)?;
+    Some(bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64))
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+// [phys, phys + len) through the linear map, if it lies in RAM
+fn ram_slice(phys: u64, len: usize, ram: &[MemoryRegion]) -> Result<&'static [u8], &'static str> {
+    let end = phys.checked_add(len as u64).ok_or("ACPI: Table address overflows")?;
+    if !ram.iter().any(|r| phys >= r.start && end <= r.start + r.size) {
+        return Err("ACPI: Table outside RAM");
+    }
+    Ok(unsafe { core::slice::from_raw_parts(phys_to_virt(phys) as *const u8, len) })
+}
+
+// A system description table: whole, with a good checksum
+fn sdt(phys: u64, ram: &[MemoryRegion]) -> Result<(&'static [u8], usize), &'static str> {
+    let header = ram_slice(phys, SDT_HEADER_LEN, ram)?;
+    let len = le(header, 4, 4).unwrap_or(0) as usize;
+    if len < SDT_HEADER_LEN {
+        return Err("ACPI: Table shorter than its header");
+    }
+    let table = ram_slice(phys, len, ram)?;
+    if !checksum_ok(table) {
+        return Err("ACPI: Table checksum mismatch");
+    }
+    Ok((table, len))
+}
+
+/// Validate the RSDP at `rsdp` and find the tables its XSDT lists.
+pub fn find_tables(rsdp: u64, ram: &[MemoryRegion]) -> Result<Tables, &'static str> {
+    let root = ram_slice(rsdp, RSDP_V2_LEN, ram)?;
+    if &root[..8] != RSDP_SIGNATURE {
+        return Err("ACPI: No RSDP signature");
+    }
+    if !checksum_ok(&root[..20]) {
+        return Err("ACPI: RSDP checksum mismatch");
+    }
+    // Revision 0 (ACPI 1.0) only has the 32-bit RSDT; ARM requires the XSDT
+    if root[15] < 2 || !checksum_ok(root) {
+        return Err("ACPI: RSDP has no valid XSDT pointer");
+    }
+    let xsdt_phys = le(root, 24, 8).unwrap_or(0);
+    let (xsdt, xsdt_len) = sdt(xsdt_phys, ram)?;
+    if &xsdt[..4] != b"XSDT" {
+        return Err("ACPI: XSDT signature mismatch");
+    }
+    
+    let mut tables = Tables {
+        rsdp: Some((rsdp, RSDP_V2_LEN)),
+        xsdt: Some((xsdt_phys, xsdt_len)),
+        ..Tables::default()
+    };
+    for entry in xsdt[SDT_HEADER_LEN..].chunks_exact(8) {
+        let phys = le(entry, 0, 8).unwrap_or(0);
+        let (table, len) = match sdt(phys, ram) {
+            Ok(found) => found,
+            Err(e) => {
+                crate::println!("{} at 0x{:x}, skipped", e, phys);
+                continue;
+            }
+        };
+        let slot = match &table[..4] {
+            b"APIC" => &mut tables.madt,
+            b"GTDT" => &mut tables.gtdt,
+            b"SPCR" => &mut tables.spcr,
+            b"FACP" => &mut tables.fadt,
+            _ => continue,
+        };
+        slot.get_or_insert((phys, len));
+    }
+    Ok(tables)
+}
+
+fn table(found: TableRef, ram: &[MemoryRegion]) -> Option<&'static [u8]> {
+    let (phys, len) = found?;
+    ram_slice(phys, len, ram).ok()
+}
+
+fn cells(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_be_bytes()).collect()
+}
+
+// "reg" with two address and two size cells
+fn reg(ranges: &[(u64, u64)]) -> Vec<u8> {
+    let split: Vec<u32> = ranges
+        .iter()
+        .flat_map(|&(base, size)| [(base >> 32) as u32, base as u32, (size >> 32) as u32, size as u32])
+        .collect();
+    cells(&split)
+}
+
+fn dt_trigger(gtdt_flags: u32) -> u32 {
+    match (gtdt_flags & GTDT_EDGE != 0, gtdt_flags & GTDT_ACTIVE_LOW != 0) {
+        (true, false) => DT_EDGE_RISING,
+        (true, true) => DT_EDGE_FALLING,
+        (false, false) => DT_LEVEL_HIGH,
+        (false, true) => DT_LEVEL_LOW,
+    }
+}
+
+// GIC node plus the MPIDRs of usable CPUs
+fn describe_madt(madt: &[u8]) -> Result<(Node, Vec<u64>), &'static str> {
+    let mut version = 0;
+    let mut gicd = None;
+    let mut gicc = None;
+    let mut gicr = Vec::new();
+    let mut gicc_redists = Vec::new();
+    let mut cpus = Vec::new();
+    
+    let mut offset = MADT_ENTRIES;
+    while offset + 2 <= madt.len() {
+        let kind = madt[offset];
+        let len = madt[offset + 1] as usize;
+        let Some(entry) = madt.get(offset..offset + len).filter(|_| len >= 2) else {
+            return Err("ACPI: Truncated MADT entry");
+        };
+        offset += len;
+        match kind {
+            MADT_GICC if len >= 76 => {
+                let flags = le(entry, 12, 4).unwrap_or(0) as u32;
+                if flags & (GICC_ENABLED | GICC_ONLINE_CAPABLE) == 0 {
+                    continue;
+                }
+                cpus.push(le(entry, 68, 8).unwrap_or(0));
+                gicc.get_or_insert(le(entry, 32, 8).unwrap_or(0));
+                match le(entry, 60, 8) {
+                    Some(base) if base != 0 => gicc_redists.push((base, GICV3_REDIST_STRIDE)),
+                    _ => {}
+                }
+            }
+            MADT_GICD if len >= 21 => {
+                gicd = le(entry, 8, 8);
+                version = entry[20];
+            }
+            MADT_GICR if len >= 16 => {
+                gicr.push((le(entry, 4, 8).unwrap_or(0), le(entry, 12, 4).unwrap_or(0)));
+            }
+            _ => {}
+        }
+    }
+    let gicd = gicd.ok_or("ACPI: MADT has no GIC distributor")?;
+    
+    let mut gic = Node::new(&format!("intc@{:x}", gicd));
+    // Version 0 means "detect": a memory-mapped CPU interface is GICv2
+    match (version, gicc) {
+        (0..=2, Some(gicc)) if gicc != 0 => {
+            gic.set_property("compatible", b"arm,cortex-a15-gic\0");
+            gic.set_property("reg", &reg(&[(gicd, GICV2_DIST_SIZE), (gicc, GICV2_CPU_SIZE)]));
+        }
+        (0 | 3 | 4, _) => {
+            if gicr.is_empty() {
+                gicr = gicc_redists;
+            }
+            let mut regions = alloc::vec![(gicd, GICV3_DIST_SIZE)];
+            regions.extend_from_slice(&gicr);
+            gic.set_property("compatible", b"arm,gic-v3\0");
+            gic.set_property("#redistributor-regions", &cells(&[gicr.len() as u32]));
+            gic.set_property("reg", &reg(&regions));
+        }
+        _ => return Err("ACPI: Unsupported GIC version"),
+    }
+    gic.set_property("interrupt-controller", b"");
+    gic.set_property("#interrupt-cells", &cells(&[3]));
+    gic.set_property("phandle", &cells(&[GIC_PHANDLE]));
+    Ok((gic, cpus))
+}
+
+// interrupts = <secure phys>, <non-secure phys>, <virt>, <hyp>
+fn describe_gtdt(gtdt: &[u8]) -> Option<Node> {
+    let mut interrupts = Vec::new();
+    for offset in [48, 56, 64, 72] {
+        let gsiv = le(gtdt, offset, 4)? as u32;
+        let flags = le(gtdt, offset + 4, 4)? as u32;
+        interrupts.extend_from_slice(&[DT_PPI, gsiv.saturating_sub(crate::gic::PPI_BASE), dt_trigger(flags)]);
+    }
+    let mut timer = Node::new("timer");
+    timer.set_property("compatible", b"arm,armv8-timer\0");
+    timer.set_property("interrupts", &cells(&interrupts));
+    Some(timer)
+}
+
+// Non-secure SBSA watchdogs, as "arm,sbsa-gwdt" nodes with the control
+// frame first
+fn describe_gtdt_watchdogs(gtdt: &[u8]) -> Vec<Node> {
+    let mut watchdogs = Vec::new();
+    let count = le(gtdt, GTDT_PLATFORM_COUNT, 4).unwrap_or(0);
+    let mut offset = le(gtdt, GTDT_PLATFORM_OFFSET, 4).unwrap_or(0) as usize;
+    for _ in 0..count {
+        let (Some(&kind), Some(len)) = (gtdt.get(offset), le(gtdt, offset + 1, 2)) else {
+            break;
+        };
+        let Some(entry) = gtdt.get(offset..offset + len as usize).filter(|_| len >= 4) else {
+            break;
+        };
+        offset += len as usize;
+        if kind != GTDT_WATCHDOG || entry.len() < GTDT_WATCHDOG_LEN {
+            continue;
+        }
+        let (Some(refresh), Some(control), Some(gsiv), Some(flags)) =
+            (le(entry, 4, 8), le(entry, 12, 8), le(entry, 20, 4), le(entry, 24, 4)) else {
+            continue;
+        };
+        if flags as u32 & GTDT_WATCHDOG_SECURE != 0 {
+            continue;
+        }
+        let mut watchdog = Node::new(&format!("watchdog@{:x}", control));
+        watchdog.set_property("compatible", b"arm,sbsa-gwdt\0");
+        watchdog.set_property("reg", &reg(&[(control, WATCHDOG_FRAME_SIZE), (refresh, WATCHDOG_FRAME_SIZE)]));
+        let gsiv = gsiv as u32;
+        if gsiv >= crate::gic::SPI_BASE {
+            watchdog.set_property("interrupts", &cells(&[DT_SPI, gsiv - crate::gic::SPI_BASE, dt_trigger(flags as u32)]));
+        }
+        watchdogs.push(watchdog);
+    }
+    watchdogs
+}
+
+fn describe_spcr(spcr: &[u8]) -> Option<Node> {
+    if ![SPCR_PL011, SPCR_SBSA_GENERIC].contains(spcr.get(36)?) {
+        return None;
+    }
+    // Generic address structure: system memory space only
+    if *spcr.get(40)? != 0 {
+        return None;
+    }
+    let base = le(spcr, 44, 8)?;
+    let mut uart = Node::new(&format!("pl011@{:x}", base));
+    uart.set_property("compatible", b"arm,pl011\0arm,primecell\0");
+    uart.set_property("reg", &reg(&[(base, UART_SIZE)]));
+    let gsiv = le(spcr, 54, 4)? as u32;
+    if spcr.get(52)? & SPCR_IRQ_GSIV != 0 && gsiv >= crate::gic::SPI_BASE {
+        uart.set_property("interrupts", &cells(&[DT_SPI, gsiv - crate::gic::SPI_BASE, DT_LEVEL_HIGH]));
+    }
+    Some(uart)
+}
+
+fn describe_psci(fadt: &[u8]) -> Option<Node> {
+    let flags = le(fadt, FADT_ARM_BOOT_FLAGS, 2)? as u16;
+    if flags & FADT_PSCI_COMPLIANT == 0 {
+        return None;
+    }
+    let mut psci = Node::new("psci");
+    psci.set_property("compatible", b"arm,psci-1.0\0arm,psci-0.2\0");
+    psci.set_property("method", if flags & FADT_PSCI_USE_HVC != 0 { b"hvc\0" } else { b"smc\0" });
+    Some(psci)
+}
+
+/// Translate `tables` into a device tree shaped like QEMU's: a GIC, the
+/// architected timer, any SBSA watchdogs, the SPCR console, /psci, /cpus,
+/// and `ram` as memory nodes. The MADT is required; the others are optional.
+pub fn describe(tables: &Tables, ram: &[MemoryRegion]) -> Result<Fdt, &'static str> {
+    let madt = table(tables.madt, ram).ok_or("ACPI: No MADT")?;
+    let (gic, mpidrs) = describe_madt(madt)?;
+    
+    let mut root = Node::new("");
+    root.set_property("#address-cells", &cells(&[2]));
+    root.set_property("#size-cells", &cells(&[2]));
+    root.set_property("model", b"ACPI platform\0");
+    root.set_property("interrupt-parent", &cells(&[GIC_PHANDLE]));
+    for region in ram {
+        let mut memory = Node::new(&format!("memory@{:x}", region.start));
+        memory.set_property("device_type", b"memory\0");
+        memory.set_property("reg", &reg(&[(region.start, region.size)]));
+        root.children.push(memory);
+    }
+    root.children.push(gic);
+    root.children.extend(table(tables.gtdt, ram).and_then(describe_gtdt));
+    root.children.extend(table(tables.gtdt, ram).map(describe_gtdt_watchdogs).unwrap_or_default());
+    
+    let mut chosen = Node::new("chosen");
+    if let Some(uart) = table(tables.spcr, ram).and_then(describe_spcr) {
+        chosen.set_property("stdout-path", format!("/{}\0", uart.name).as_bytes());
+        root.children.push(uart);
+    }
+    let psci = table(tables.fadt, ram).and_then(describe_psci);
+    
+    let mut cpus = Node::new("cpus");
+    cpus.set_property("#address-cells", &cells(&[2]));
+    cpus.set_property("#size-cells", &cells(&[0]));
+    for mpidr in mpidrs {
+        let mut cpu = Node::new(&format!("cpu@{:x}", mpidr));
+        cpu.set_property("device_type", b"cpu\0");
+        cpu.set_property("compatible", b"arm,armv8\0");
+        cpu.set_property("reg", &cells(&[(mpidr >> 32) as u32, mpidr as u32]));
+        if psci.is_some() {
+            cpu.set_property("enable-method", b"psci\0");
+        }
+        cpus.children.push(cpu);
+    }
+    root.children.push(cpus);
+    root.children.extend(psci);
+    root.children.push(chosen);
+    Ok(Fdt { root, reserved: Vec::new(), boot_cpuid: 0 })
+}
+
+/// Record the loader's x0. Called first thing from rust_main.
+pub fn set_boot_arg(x0: u64) {
+    BOOT_ARG.store(x0, Ordering::Relaxed);
+}
+
+/// Without a device tree, look for ACPI tables at the boot argument and
+/// keep their frames from the allocator. Runs before anything is
+/// allocated, so it must not touch the heap.
+pub fn reserve(dt: Option<&DeviceTree>, ram: &[MemoryRegion]) {
+    let rsdp = BOOT_ARG.load(Ordering::Relaxed);
+    if dt.is_some() || rsdp == 0 {
+        return;
+    }
+    let tables = match find_tables(rsdp, ram) {
+        Ok(tables) => tables,
+        Err(e) => {
+            crate::println!("{} (boot argument 0x{:x})", e, rsdp);
+            return;
+        }
+    };
+    let page = PAGE_SIZE as u64;
+    for (phys, len) in tables.all().into_iter().flatten() {
+        let mut addr = phys & !(page - 1);
+        while addr < phys + len as u64 {
+            if let Some(frame) = core::ptr::NonNull::new(phys_to_virt(addr) as *mut u8) {
+                frame_allocator::claim_frame(frame);
+            }
+            addr += page;
+        }
+    }
+    crate::println!("ACPI: RSDP at 0x{:x}, {} tables used", rsdp, tables.all().iter().flatten().count() - 2);
+    *FIRMWARE.lock() = Some((tables, ram[0]));
+}
+
+/// Install the device tree translated from the reserved ACPI tables, if
+/// there are any. Runs once the heap is up, before overlays and drivers.
+pub fn init() {
+    let Some((tables, ram)) = *FIRMWARE.lock() else { return };
+    if device_tree().is_some() {
+        return;
+    }
+    let fdt = match describe(&tables, &[ram]) {
+        Ok(fdt) => fdt,
+        Err(e) => {
+            crate::println!("{}, falling back to defaults", e);
+            return;
+        }
+    };
+    
+    // The MMU only mapped RAM and the boot UART; map what ACPI described
+    let mut regs = Vec::new();
+    crate::dtoverlay::node_regs(&fdt.root, &mut regs);
+    for (base, size) in regs {
+        let in_ram = base >= ram.start && base < ram.start + ram.size;
+        if size != 0 && !in_ram {
+            if let Err(e) = crate::memory::mmu::map_device(base, size) {
+                crate::println!("ACPI: Failed to map 0x{:x}: {}", base, e);
+            }
+        }
+    }
+    
+    let bytes = flatten(&fdt);
+    let blob: &'static [u32] = Box::leak(crate::dtoverlay::aligned_blob(&bytes));
+    set_active_blob(unsafe { core::slice::from_raw_parts(blob.as_ptr() as *const u8, bytes.len()) });
+    crate::println!("ACPI: Device tree built from firmware tables ({} nodes, {} bytes)",
+                   fdt.root.children.len(), bytes.len());
+    // SPCR's UART is the console now in stdout-path
+    crate::uart::probe();
+}
+
+// Lay out an ACPI table header and fix its checksum once the body is in
+fn acpi_table(page: &mut [u8], offset: usize, signature: &[u8; 4], len: usize, fill: impl FnOnce(&mut [u8])) {
+    let table = &mut page[offset..offset + len];
+    table[..4].copy_from_slice(signature);
+    table[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+    table[8] = 2;
+    fill(table);
+    let sum = table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    table[9] = 0u8.wrapping_sub(sum);
+}
+
+#[kernel_test]
+fn test_acpi() {
+    use crate::devicetree::{parse_device_tree, MemoryRegion};
+    use crate::memory::frame_allocator::{allocate_frame, deallocate_frame, PAGE_SIZE};
+    use crate::memory::paging::virt_to_phys;
+    
+    crate::println!("ACPI Test: Testing ACPI table translation...");
+    
+    let Some(frame) = allocate_frame() else {
+        crate::println!("ACPI Test: ✗ No frame for the ACPI tables");
+        return;
+    };
+    let page = unsafe { core::slice::from_raw_parts_mut(frame.as_ptr(), PAGE_SIZE) };
+    page.fill(0);
+    let base = virt_to_phys(frame.as_ptr() as u64);
+    let ram = [MemoryRegion { start: base, size: PAGE_SIZE as u64 }];
+    let (xsdt, madt, gtdt, spcr, fadt) = (64, 160, 320, 480, 576);
+    
+    // QEMU virt's layout: GICv2, timer PPIs 13/14/11/10, PL011 on SPI 1;
+    // plus sbsa-ref's watchdog, on SPI 16
+    page[..8].copy_from_slice(b"RSD PTR ");
+    page[15] = 2;
+    page[20..24].copy_from_slice(&36u32.to_le_bytes());
+    page[24..32].copy_from_slice(&(base + xsdt as u64).to_le_bytes());
+    page[8] = 0u8.wrapping_sub(page[..20].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+    page[32] = 0u8.wrapping_sub(page[..36].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+    acpi_table(page, xsdt, b"XSDT", SDT_HEADER_LEN + 4 * 8, |table| {
+        for (i, offset) in [madt, gtdt, spcr, fadt].into_iter().enumerate() {
+            let entry = SDT_HEADER_LEN + i * 8;
+            table[entry..entry + 8].copy_from_slice(&(base + offset as u64).to_le_bytes());
+        }
+    });
+    acpi_table(page, madt, b"APIC", 44 + 80 + 24, |table| {
+        let gicc = &mut table[44..124];
+        gicc[0] = 0x0B;
+        gicc[1] = 80;
+        gicc[12] = 1;  // Enabled
+        gicc[32..40].copy_from_slice(&0x0801_0000u64.to_le_bytes());
+        let gicd = &mut table[124..148];
+        gicd[0] = 0x0C;
+        gicd[1] = 24;
+        gicd[8..16].copy_from_slice(&0x0800_0000u64.to_le_bytes());
+        gicd[20] = 2;
+    });
+    acpi_table(page, gtdt, b"GTDT", 104 + 28, |table| {
+        for (offset, gsiv) in [(48, 29u32), (56, 30), (64, 27), (72, 26)] {
+            table[offset..offset + 4].copy_from_slice(&gsiv.to_le_bytes());
+        }
+        table[88] = 1;
+        table[92] = 104;
+        let watchdog = &mut table[104..132];
+        watchdog[0] = 1;
+        watchdog[1] = 28;
+        watchdog[4..12].copy_from_slice(&0x5001_0000u64.to_le_bytes());
+        watchdog[12..20].copy_from_slice(&0x5001_1000u64.to_le_bytes());
+        watchdog[20..24].copy_from_slice(&48u32.to_le_bytes());
+    });
+    acpi_table(page, spcr, b"SPCR", 80, |table| {
+        table[36] = 0x03;  // PL011
+        table[41] = 8;
+        table[43] = 1;
+        table[44..52].copy_from_slice(&0x0900_0000u64.to_le_bytes());
+        table[52] = 1 << 3;
+        table[54..58].copy_from_slice(&33u32.to_le_bytes());
+    });
+    acpi_table(page, fadt, b"FACP", 276, |table| table[129] = 0b11);  // PSCI over HVC
+    
+    let described = find_tables(base, &ram).and_then(|tables| describe(&tables, &ram));
+    let words = described.map(|fdt| crate::dtoverlay::aligned_blob(&fdt_parser::flatten(&fdt)));
+    let checked = words.as_ref().ok().and_then(|words| parse_device_tree(words.as_ptr() as *const u8)).map(|dt| {
+        let gic = dt.find_compatible("arm,cortex-a15-gic").next().map(|node| (node.reg(0), node.reg(1)));
+        let timer = dt.find_compatible("arm,armv8-timer").next().and_then(|node| crate::gic::dt_interrupt(&node, 1));
+        let uart = dt.find_compatible("arm,pl011").next()
+            .map(|node| (node.reg(0), crate::gic::dt_interrupt(&node, 0)));
+        let psci = dt.find_by_name("psci").and_then(|node| node.property_str("method"));
+        let memory = dt.memory_regions().iter().flatten().next().map(|region| region.start);
+        let watchdog = dt.find_compatible("arm,sbsa-gwdt").next()
+            .map(|node| (node.reg(0), node.reg(1), crate::gic::dt_interrupt(&node, 0)));
+        (gic, timer, uart, psci, memory, dt.find_by_name("cpu@0").is_some(), watchdog)
+    });
+    match checked {
+        Some((Some((Some((0x0800_0000, _)), Some((0x0801_0000, _)))), Some(30),
+              Some((Some((0x0900_0000, 0x1000)), Some(33))), Some("hvc"), Some(memory), true,
+              Some((Some((0x5001_1000, _)), Some((0x5001_0000, _)), Some(48)))))
+            if memory == base =>
+        {
+            crate::println!("ACPI Test: ✓ MADT/GTDT/SPCR/FADT translated to GIC, timer, watchdog, UART and PSCI nodes");
+        }
+        other => crate::println!("ACPI Test: ✗ ACPI translation wrong ({:?})", other.is_some()),
+    }
+    
+    // A corrupt optional table is dropped; a corrupt RSDP fails the lookup
+    page[gtdt + 60] ^= 1;
+    let without_timer = find_tables(base, &ram).map(|tables| tables.gtdt.is_none() && tables.madt.is_some());
+    page[0] ^= 1;
+    let bad_rsdp = find_tables(base, &ram).is_err();
+    if without_timer == Ok(true) && bad_rsdp {
+        crate::println!("ACPI Test: ✓ Bad table checksums skipped, bad RSDP rejected");
+    } else {
+        crate::println!("ACPI Test: ✗ Corrupt ACPI tables accepted");
+    }
+    
+    deallocate_frame(frame);
+    crate::println!("ACPI Test: ACPI test completed");
+}
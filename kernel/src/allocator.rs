@@ -3,11 +3,19 @@ use linked_list_allocator::LockedHeap;
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
-const HEAP_START: usize = 0x_4444_4444_0000;
-const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+// Upper-half heap location, clear of the linear map; memory::init backs it with frames
+pub const HEAP_START: usize = 0xFFFF_8000_0000_0000;
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
-pub fn init_heap() {
+/// Hand [start, start + size) to the global allocator. The range must be mapped.
+pub fn init_heap(start: usize, size: usize) {
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+        ALLOCATOR.lock().init(start as *mut u8, size);
     }
-}
\ No newline at end of file
+}
+
+/// Heap bytes in use and still free.
+pub fn heap_stats() -> (usize, usize) {
+    let heap = ALLOCATOR.lock();
+    (heap.used(), heap.free())
+}
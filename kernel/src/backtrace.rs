@@ -0,0 +1,129 @@
+// Kernel stack walking
+//
+// By default walks go over AArch64 frame records: with
+// -C force-frame-pointers every non-leaf function saves a frame record
+// {previous fp, return address} and points x29 at it. Records only move
+// towards the top of the stack, so a walk that stays inside the stack and
+// strictly climbs cannot loop or wander into other memory. Code that has
+// not saved its record yet (a prologue, assembler, a leaf) hides its
+// caller, whose address is still in x30.
+//
+// With the eh-unwind feature, walks use the DWARF call frame information
+// in the kernel's .eh_frame instead (see unwind.rs), which is exact at
+// every instruction and steps through exception frames.
+
+use core::arch::asm;
+use crate::interrupts::ExceptionContext;
+
+/// Registers of the innermost frame a walk starts from.
+#[derive(Copy, Clone)]
+pub struct StartFrame {
+    /// x0-x30, then sp.
+    pub regs: [u64; 32],
+    /// Only the CFI walk needs the pc.
+    #[cfg_attr(not(feature = "eh-unwind"), allow(dead_code))]
+    pub pc: u64,
+}
+
+impl StartFrame {
+    /// The code an exception interrupted.
+    pub fn from_context(ctx: &ExceptionContext) -> Self {
+        let mut regs = [0u64; 32];
+        for (n, reg) in regs[..31].iter_mut().enumerate() {
+            *reg = ctx.x(n);
+        }
+        // EL1 exceptions push the frame on the interrupted stack
+        regs[31] = ctx as *const ExceptionContext as u64 + core::mem::size_of::<ExceptionContext>() as u64;
+        StartFrame { regs, pc: ctx.elr_el1 }
+    }
+    
+    /// The caller's own frame. Only the callee-saved registers are
+    /// captured; the others cannot be needed to find a caller.
+    #[inline(always)]
+    pub fn current() -> Self {
+        let mut regs = [0u64; 32];
+        let pc: u64;
+        unsafe {
+            asm!(
+                "stp x19, x20, [{0}, #152]",
+                "stp x21, x22, [{0}, #168]",
+                "stp x23, x24, [{0}, #184]",
+                "stp x25, x26, [{0}, #200]",
+                "stp x27, x28, [{0}, #216]",
+                "stp x29, x30, [{0}, #232]",
+                "mov {1}, sp",
+                "str {1}, [{0}, #248]",
+                "adr {1}, .",
+                in(reg) regs.as_mut_ptr(),
+                out(reg) pc,
+                options(nostack, preserves_flags),
+            );
+        }
+        StartFrame { regs, pc }
+    }
+    
+    pub fn sp(&self) -> u64 {
+        self.regs[31]
+    }
+}
+
+/// Return addresses up a frame-pointer chain, innermost first.
+pub struct FrameChain {
+    fp: u64,
+    stack_low: u64,
+    stack_high: u64,
+}
+
+impl Iterator for FrameChain {
+    type Item = u64;
+    
+    fn next(&mut self) -> Option<u64> {
+        let fp = self.fp;
+        if fp <= self.stack_low || fp + 16 > self.stack_high || !fp.is_multiple_of(8) {
+            return None;
+        }
+        let record = fp as *const u64;
+        let (next_fp, lr) = unsafe { (record.read(), record.add(1).read()) };
+        if lr == 0 {
+            return None;
+        }
+        // A record that does not climb ends the walk after this frame
+        self.fp = if next_fp > fp { next_fp } else { 0 };
+        Some(lr)
+    }
+}
+
+/// Walk the chain starting at `fp`, trusting only records strictly above
+/// `stack_low` and wholly below `stack_high`.
+pub fn frames(fp: u64, stack_low: u64, stack_high: u64) -> FrameChain {
+    FrameChain { fp, stack_low, stack_high }
+}
+
+#[cfg(feature = "eh-unwind")]
+pub type Walk = crate::unwind::Unwinder;
+#[cfg(not(feature = "eh-unwind"))]
+pub type Walk = FrameChain;
+
+/// Return addresses of the callers of `start`, innermost first, reading
+/// nothing outside `[stack_low, stack_high)`. A frame resumed from an
+/// exception is reported as its pc + 4, so that stepping back over "the
+/// call" lands on the interrupted instruction.
+#[cfg(feature = "eh-unwind")]
+pub fn walk(start: &StartFrame, stack_low: u64, stack_high: u64) -> Walk {
+    crate::unwind::unwind(start, stack_low, stack_high)
+}
+
+#[cfg(not(feature = "eh-unwind"))]
+pub fn walk(start: &StartFrame, stack_low: u64, stack_high: u64) -> Walk {
+    frames(start.regs[29], stack_low, stack_high)
+}
+
+/// The caller's stack pointer.
+#[inline(always)]
+pub fn current_sp() -> u64 {
+    let sp: u64;
+    unsafe {
+        asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags));
+    }
+    sp
+}
@@ -0,0 +1,371 @@
+// Block device layer: registry, sector I/O and MBR partitions
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::drivers::pm::{self, PmDevice, RuntimePm};
+use crate::ktest::kernel_test;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// MBR partition table layout
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_PARTITION_TABLE_OFFSET: usize = 446;
+const MBR_PARTITION_ENTRY_SIZE: usize = 16;
+const MBR_PARTITION_TYPE_EMPTY: u8 = 0x00;
+const MBR_PARTITION_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// A device addressed in fixed-size sectors.
+///
+/// Buffers must be a whole number of sectors long.
+pub trait BlockDevice: Send + Sync {
+    fn num_blocks(&self) -> u64;
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str>;
+    
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+    
+    fn flush(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+    
+    /// Idle time after which the block layer suspends the device, or
+    /// None if it has no low-power state.
+    fn autosuspend_ms(&self) -> Option<u64> {
+        None
+    }
+    
+    /// Enter a low-power state; no requests arrive until resumed.
+    fn runtime_suspend(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+    
+    fn runtime_resume(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+// Check an I/O request against the device size
+pub fn check_request(dev: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, &'static str> {
+    let block_size = dev.block_size();
+    if !len.is_multiple_of(block_size) {
+        return Err("Block I/O length not a multiple of the block size");
+    }
+    let count = (len / block_size) as u64;
+    if lba.checked_add(count).is_none_or(|end| end > dev.num_blocks()) {
+        return Err("Block I/O beyond end of device");
+    }
+    Ok(count)
+}
+
+struct BlockEntry {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    // Runtime PM registration of a whole disk, dropped with it
+    pm: Option<Arc<PmDevice>>,
+}
+
+static BLOCK_DEVICES: Mutex<Vec<BlockEntry>> = Mutex::new(Vec::new());
+
+/// A window onto part of a parent device.
+pub struct Partition {
+    parent: Arc<dyn BlockDevice>,
+    start: u64,
+    blocks: u64,
+}
+
+impl BlockDevice for Partition {
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+    
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_request(self, lba, buf.len())?;
+        self.parent.read_blocks(self.start + lba, buf)
+    }
+    
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_request(self, lba, buf.len())?;
+        self.parent.write_blocks(self.start + lba, buf)
+    }
+    
+    fn block_size(&self) -> usize {
+        self.parent.block_size()
+    }
+    
+    fn flush(&self) -> Result<(), &'static str> {
+        self.parent.flush()
+    }
+}
+
+/// A device under runtime PM: every request holds a usage reference, so
+/// the device is resumed for it and suspended once requests stop.
+struct PmBlockDevice {
+    inner: Arc<dyn BlockDevice>,
+    pm: Arc<PmDevice>,
+}
+
+// The PM core's view of the driver
+struct BlockPm(Arc<dyn BlockDevice>);
+
+impl RuntimePm for BlockPm {
+    fn runtime_suspend(&self) -> Result<(), &'static str> {
+        self.0.runtime_suspend()
+    }
+    
+    fn runtime_resume(&self) -> Result<(), &'static str> {
+        self.0.runtime_resume()
+    }
+}
+
+impl BlockDevice for PmBlockDevice {
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+    
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let _awake = self.pm.get()?;
+        self.inner.read_blocks(lba, buf)
+    }
+    
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        let _awake = self.pm.get()?;
+        self.inner.write_blocks(lba, buf)
+    }
+    
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+    
+    fn flush(&self) -> Result<(), &'static str> {
+        let _awake = self.pm.get()?;
+        self.inner.flush()
+    }
+}
+
+/// A device backed by kernel heap memory.
+pub struct RamDisk {
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    pub fn new(sectors: usize) -> Self {
+        Self { data: Mutex::new(vec![0; sectors * SECTOR_SIZE]) }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn num_blocks(&self) -> u64 {
+        (self.data.lock().len() / SECTOR_SIZE) as u64
+    }
+    
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_request(self, lba, buf.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+    
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_request(self, lba, buf.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// Register a whole-disk device and any MBR partitions on it.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), &'static str> {
+    let pm = device.autosuspend_ms().map(|delay| pm::register(name, Arc::new(BlockPm(device.clone())), delay));
+    let device: Arc<dyn BlockDevice> = match &pm {
+        Some(pm) => Arc::new(PmBlockDevice { inner: device, pm: pm.clone() }),
+        None => device,
+    };
+    if let Err(e) = add_device(name, device.clone(), pm.clone()) {
+        if let Some(pm) = pm {
+            let _ = pm::unregister(&pm);
+        }
+        return Err(e);
+    }
+    crate::println!("Block: {} registered ({} MB)", name,
+                   device.num_blocks() * device.block_size() as u64 / (1024 * 1024));
+    
+    match scan_partitions(name, &device) {
+        Ok(count) if count > 0 => crate::println!("Block: {} has {} partitions", name, count),
+        Ok(_) => {}
+        Err(e) => crate::println!("Block: {} partition scan failed: {}", name, e),
+    }
+    Ok(())
+}
+
+fn add_device(name: &str, device: Arc<dyn BlockDevice>, pm: Option<Arc<PmDevice>>) -> Result<(), &'static str> {
+    let mut devices = BLOCK_DEVICES.lock();
+    if devices.iter().any(|entry| entry.name == name) {
+        return Err("Block device name already registered");
+    }
+    devices.push(BlockEntry {
+        name: String::from(name),
+        device,
+        pm,
+    });
+    Ok(())
+}
+
+fn scan_partitions(name: &str, device: &Arc<dyn BlockDevice>) -> Result<usize, &'static str> {
+    if device.block_size() != SECTOR_SIZE || device.num_blocks() == 0 {
+        return Ok(0);
+    }
+    
+    let mut mbr = vec![0u8; SECTOR_SIZE];
+    device.read_blocks(0, &mut mbr)?;
+    if mbr[MBR_SIGNATURE_OFFSET] != 0x55 || mbr[MBR_SIGNATURE_OFFSET + 1] != 0xAA {
+        return Ok(0);
+    }
+    
+    let mut count = 0;
+    for index in 0..4 {
+        let entry = &mbr[MBR_PARTITION_TABLE_OFFSET + index * MBR_PARTITION_ENTRY_SIZE..]
+            [..MBR_PARTITION_ENTRY_SIZE];
+        let part_type = entry[4];
+        if part_type == MBR_PARTITION_TYPE_EMPTY || part_type == MBR_PARTITION_TYPE_GPT_PROTECTIVE {
+            continue;
+        }
+        
+        let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64;
+        let blocks = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as u64;
+        if blocks == 0 || start + blocks > device.num_blocks() {
+            crate::println!("Block: {} partition {} out of range, ignored", name, index + 1);
+            continue;
+        }
+        
+        let partition = Partition {
+            parent: device.clone(),
+            start,
+            blocks,
+        };
+        let part_name = partition_name(name, index + 1);
+        crate::println!("Block: {} type 0x{:02x} at sector {} ({} sectors)",
+                       part_name, part_type, start, blocks);
+        add_device(&part_name, Arc::new(partition), None)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// "mmcblk0" -> "mmcblk0p1", "vda" -> "vda1"
+fn partition_name(disk: &str, number: usize) -> String {
+    let separator = if disk.ends_with(|c: char| c.is_ascii_digit()) { "p" } else { "" };
+    format!("{}{}{}", disk, separator, number)
+}
+
+/// Remove a whole-disk device and its partitions. Users holding the
+/// device keep it until they drop it.
+pub fn unregister(name: &str) -> Result<(), &'static str> {
+    let pm = {
+        let mut devices = BLOCK_DEVICES.lock();
+        let index = devices
+            .iter()
+            .position(|entry| entry.name == name)
+            .ok_or("No such block device")?;
+        let pm = devices.remove(index).pm;
+        devices.retain(|entry| !(1..=4).any(|number| entry.name == partition_name(name, number)));
+        pm
+    };
+    match pm {
+        Some(pm) => pm::unregister(&pm),
+        None => Ok(()),
+    }
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    BLOCK_DEVICES
+        .lock()
+        .iter()
+        .find(|entry| entry.name == name)
+        .map(|entry| entry.device.clone())
+}
+
+pub fn list() -> Vec<String> {
+    BLOCK_DEVICES.lock().iter().map(|entry| entry.name.clone()).collect()
+}
+
+#[kernel_test]
+fn test_mbr_partitions() {
+    use alloc::string::String;
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    
+    crate::println!("Block Test: Testing MBR partition scanning...");
+    
+    // Table of (type, first sector, sectors) in a signed boot sector
+    let mbr = |entries: &[(u8, u32, u32)]| {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        for (index, &(part_type, start, blocks)) in entries.iter().enumerate() {
+            let entry = &mut sector[446 + index * 16..][..16];
+            entry[4] = part_type;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+        }
+        sector[510] = 0x55;
+        sector[511] = 0xAA;
+        sector
+    };
+    
+    // 1 ends before 3 starts, 2 is empty, 3 ends on the last sector and
+    // 4 runs past it
+    let disk = Arc::new(RamDisk::new(64));
+    let table = mbr(&[(0x0C, 2, 10), (0x00, 0, 0), (0x83, 20, 44), (0x83, 60, 8)]);
+    let registered = disk.write_blocks(0, &table).and_then(|()| register("mbrtest0", disk.clone()));
+    let names: Vec<String> = list().into_iter().filter(|name| name.starts_with("mbrtest0")).collect();
+    let sizes = (get("mbrtest0p1").map(|part| part.num_blocks()),
+                 get("mbrtest0p3").map(|part| part.num_blocks()));
+    if registered.is_ok() && names == ["mbrtest0", "mbrtest0p1", "mbrtest0p3"] && sizes == (Some(10), Some(44)) {
+        crate::println!("Block Test: ✓ Partitions 1 and 3 found; empty and oversized entries skipped");
+    } else {
+        crate::println!("Block Test: ✗ Found {:?} with sizes {:?} ({:?})", names, sizes, registered);
+    }
+    
+    // Partition sectors land at their offset on the disk and stop at its end
+    let pattern = vec![0x5Au8; SECTOR_SIZE];
+    let mut raw = vec![0u8; SECTOR_SIZE];
+    let mut past = vec![0u8; SECTOR_SIZE];
+    let mapped = get("mbrtest0p3").is_some_and(|part| {
+        part.write_blocks(43, &pattern).is_ok()
+            && disk.read_blocks(63, &mut raw).is_ok()
+            && part.read_blocks(44, &mut past).is_err()
+    });
+    if mapped && raw == pattern {
+        crate::println!("Block Test: ✓ Partition sector 43 is disk sector 63; sector 44 refused");
+    } else {
+        crate::println!("Block Test: ✗ Partition I/O not mapped onto the disk");
+    }
+    
+    // A signed but empty table and an unsigned sector both give no partitions
+    let empty = Arc::new(RamDisk::new(16));
+    let blank = Arc::new(RamDisk::new(16));
+    let scanned = empty.write_blocks(0, &mbr(&[]))
+        .and_then(|()| register("mbrtest1", empty.clone()))
+        .and_then(|()| register("mbrtest2", blank.clone()));
+    let extra = list().iter().filter(|name| name.starts_with("mbrtest1p") || name.starts_with("mbrtest2p")).count();
+    if scanned.is_ok() && extra == 0 {
+        crate::println!("Block Test: ✓ Empty and unsigned tables give no partitions");
+    } else {
+        crate::println!("Block Test: ✗ {} partitions from empty tables ({:?})", extra, scanned);
+    }
+    
+    let removed = ["mbrtest0", "mbrtest1", "mbrtest2"].iter().all(|name| unregister(name).is_ok());
+    let left: Vec<String> = list().into_iter().filter(|name| name.starts_with("mbrtest")).collect();
+    if removed && left.is_empty() {
+        crate::println!("Block Test: ✓ Unregistering a disk removed its partitions");
+    } else {
+        crate::println!("Block Test: ✗ Left registered: {:?}", left);
+    }
+    
+    crate::println!("Block Test: MBR partition test completed");
+}
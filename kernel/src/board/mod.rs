@@ -0,0 +1,134 @@
+// Board support
+//
+// What differs between machines before the device tree can be read, or
+// when there is none: where the loader puts the image, which of the
+// first 4GB the boot page tables map as device memory, the console UART,
+// and fallbacks for RAM and the device tree. The build picks one board
+// (QEMU virt, or a board-* feature); at boot the root node's "compatible"
+// says which machine this really is, and drivers bind to whatever the
+// device tree describes either way.
+
+pub mod qemu_virt;
+pub mod rpi4;
+
+use crate::devicetree::MemoryRegion;
+use crate::ktest::kernel_test;
+use crate::uart::UartKind;
+
+pub struct Board {
+    pub name: &'static str,
+    /// Root "compatible" entries that identify the machine
+    pub compatible: &'static [&'static str],
+    /// Physical load address; build.rs links the kernel there, so the two
+    /// must match
+    pub load_address: u64,
+    /// Bit n set: the boot tables map the nth GB as device memory rather
+    /// than RAM
+    pub boot_device_gigabytes: u8,
+    /// RAM to assume when the device tree describes none
+    pub fallback_ram: MemoryRegion,
+    /// Where to look for a device tree the loader did not pass in x0
+    pub fallback_fdt: Option<u64>,
+    /// Console from the first message until the device tree is read
+    pub early_console: (u64, UartKind),
+}
+
+impl Board {
+    /// Whether the boot page tables map `phys` as device memory.
+    pub const fn early_device(&self, phys: u64) -> bool {
+        phys < (4 << 30) && self.boot_device_gigabytes & (1 << (phys >> 30)) != 0
+    }
+}
+
+/// The board this kernel was built for.
+#[cfg(not(feature = "board-rpi4"))]
+pub const BUILD: &Board = &qemu_virt::BOARD;
+#[cfg(feature = "board-rpi4")]
+pub const BUILD: &Board = &rpi4::BOARD;
+
+const BOARDS: [&Board; 2] = [&qemu_virt::BOARD, &rpi4::BOARD];
+
+/// The machine the device tree describes, if it is one this kernel knows.
+pub fn detect() -> Option<&'static Board> {
+    let dt = crate::devicetree::device_tree()?;
+    let root = dt.nodes().next()?;
+    BOARDS.into_iter().find(|board| board.compatible.iter().any(|compatible| root.is_compatible(compatible)))
+}
+
+/// Report the machine, and warn when the build was meant for another.
+pub fn init() {
+    let model = crate::devicetree::device_tree()
+        .and_then(|dt| dt.nodes().next())
+        .and_then(|root| root.property_str("model"));
+    match detect() {
+        Some(board) if core::ptr::eq(board, BUILD) => crate::println!("Board: {}, kernel loaded at 0x{:x}",
+                                                                     model.unwrap_or(board.name), board.load_address),
+        Some(board) => crate::println!("Board: {} on a kernel built for {}; the early console and memory layout may be wrong",
+                                      board.name, BUILD.name),
+        None => crate::println!("Board: {} (unrecognized, using {} defaults)", model.unwrap_or("no model"), BUILD.name),
+    }
+}
+
+#[kernel_test]
+fn test_board() {
+    use alloc::vec::Vec;
+    use crate::board;
+    use crate::devicetree::DeviceTree;
+    use fdt_parser::{Fdt, Node};
+    
+    crate::println!("Board Test: Testing board support...");
+    
+    match board::detect() {
+        Some(found) => crate::println!("Board Test: ✓ Running on {} (built for {})", found.name, board::BUILD.name),
+        None => crate::println!("Board Test: ✓ Machine not one the kernel knows; {} defaults", board::BUILD.name),
+    }
+    if board::BUILD.early_device(board::BUILD.early_console.0) && !board::BUILD.early_device(board::BUILD.fallback_ram.start) {
+        crate::println!("Board Test: ✓ Boot tables map the early console as device memory, RAM as normal");
+    } else {
+        crate::println!("Board Test: ✗ Boot device memory 0b{:04b} misses the console or covers RAM",
+                       board::BUILD.boot_device_gigabytes);
+    }
+    
+    // A Raspberry Pi 4 style tree: devices at bus addresses under a /soc
+    // with one-cell addresses, RAM in two ranges
+    let words = |values: &[u32]| values.iter().flat_map(|value| value.to_be_bytes()).collect::<Vec<u8>>();
+    let mut uart = Node::new("serial@7e215040");
+    uart.set_property("compatible", b"brcm,bcm2835-aux-uart\0");
+    uart.set_property("reg", &words(&[0x7E21_5040, 0x40]));
+    let mut soc = Node::new("soc");
+    soc.set_property("#address-cells", &words(&[1]));
+    soc.set_property("#size-cells", &words(&[1]));
+    soc.set_property("ranges", &words(&[0x7E00_0000, 0, 0xFE00_0000, 0x0180_0000]));
+    soc.children.push(uart);
+    let mut memory = Node::new("memory@0");
+    memory.set_property("device_type", b"memory\0");
+    memory.set_property("reg", &words(&[0, 0, 0, 0x3B40_0000, 0, 0x4000_0000, 0, 0xBC00_0000]));
+    let mut root = Node::new("");
+    root.set_property("compatible", b"raspberrypi,4-model-b\0brcm,bcm2711\0");
+    root.set_property("#address-cells", &words(&[2]));
+    root.set_property("#size-cells", &words(&[2]));
+    root.children = alloc::vec![soc, memory];
+    let blob = crate::dtoverlay::aligned_blob(&fdt_parser::flatten(&Fdt { root, reserved: Vec::new(), boot_cpuid: 0 }));
+    
+    let Some(mut dt) = DeviceTree::new(blob.as_ptr() as *const u8) else {
+        crate::println!("Board Test: ✗ Board test tree rejected");
+        return;
+    };
+    let uart_reg = dt.find_compatible("brcm,bcm2835-aux-uart").next().and_then(|node| node.reg(0));
+    if uart_reg == Some((0xFE21_5040, 0x40)) {
+        crate::println!("Board Test: ✓ /soc bus address translated to 0xfe215040");
+    } else {
+        crate::println!("Board Test: ✗ /soc translation gave {:x?}", uart_reg);
+    }
+    let ram: Vec<_> = match dt.parse_memory() {
+        Ok(()) => dt.memory_regions().iter().flatten().map(|region| (region.start, region.size)).collect(),
+        Err(_) => Vec::new(),
+    };
+    if ram == [(0, 0x3B40_0000), (0x4000_0000, 0xBC00_0000)] {
+        crate::println!("Board Test: ✓ Both RAM ranges of one memory node found");
+    } else {
+        crate::println!("Board Test: ✗ RAM ranges {:x?}", ram);
+    }
+    
+    crate::println!("Board Test: Board test completed");
+}
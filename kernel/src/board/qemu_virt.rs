@@ -0,0 +1,18 @@
+// QEMU's virt machine
+//
+// RAM from 1GB with the device tree at its start and the kernel 512KB in;
+// devices (GIC, PL011, PL031, virtio-mmio) in the first GB.
+
+use super::Board;
+use crate::devicetree::MemoryRegion;
+use crate::uart::UartKind;
+
+pub const BOARD: Board = Board {
+    name: "QEMU virt",
+    compatible: &["linux,dummy-virt"],
+    load_address: 0x4008_0000,
+    boot_device_gigabytes: 0b0001,
+    fallback_ram: MemoryRegion { start: 0x4000_0000, size: 1 << 30 },
+    fallback_fdt: Some(0x4000_0000),
+    early_console: (0x0900_0000, UartKind::Pl011),
+};
@@ -0,0 +1,28 @@
+// Raspberry Pi 4 (BCM2711)
+//
+// The firmware loads kernel8.img at 0x80000 (arm_64bit=1 in config.txt),
+// enters it at EL2 with the device tree in x0 and parks the other cores.
+// RAM starts at 0; peripherals sit in the last 64MB of the 4th GB ("low
+// peripheral" mode), the GIC-400 with them at 0xff840000. The device tree
+// gives their VideoCore bus addresses (0x7exxxxxx) under /soc, which
+// DeviceNode::reg translates.
+//
+// enable_uart=1 turns on the mini-UART on GPIO 14/15 for the console; with
+// dtoverlay=disable-bt it is the PL011 there instead. Either way the
+// firmware has set the baud rate, which the drivers leave alone.
+
+use super::Board;
+use crate::devicetree::MemoryRegion;
+use crate::uart::UartKind;
+
+pub const BOARD: Board = Board {
+    name: "Raspberry Pi 4",
+    compatible: &["raspberrypi,4-model-b", "brcm,bcm2711"],
+    load_address: 0x8_0000,
+    boot_device_gigabytes: 0b1000,
+    // The smallest model; the rest comes from the device tree
+    fallback_ram: MemoryRegion { start: 0, size: 0x3B40_0000 },
+    fallback_fdt: None,
+    // AUX_MU_IO; the PL011 is at 0xfe201000
+    early_console: (0xFE21_5040, UartKind::MiniUart),
+};
@@ -4,24 +4,65 @@
 .extern rust_main
 .extern __bss_start
 .extern __bss_end
+.extern kaslr_early_init
+.global boot_l0_table
 
 // ARM64 boot entry point
+//
+// Entered at the physical load address with the MMU off, while the kernel
+// is linked in the upper half. Everything up to the jump to the linked
+// address must be PC-relative.
 _start:
     // Disable interrupts
     msr daifset, #0xf
     
+    // Keep the loader's x0 (DTB or ACPI RSDP address) for rust_main
+    mov x19, x0
+    
     // Check if we're running on the primary CPU (MPIDR_EL1)
     mrs x0, mpidr_el1
     and x0, x0, #0xFF
     cbnz x0, halt         // If not CPU 0, halt
     
-    // Set up stack pointer
-    ldr x0, =_stack_top
-    mov sp, x0
+    // Firmware that enters at EL2 (the Raspberry Pi's, QEMU with
+    // virtualization=on) gets an EL1 kernel: AArch64 at EL1, timer,
+    // FP/SIMD and GIC not trapped, then eret to EL1h
+    mrs x0, CurrentEL
+    cmp x0, #(2 << 2)
+    b.ne at_el1
+    mov x0, #(1 << 31)    // HCR_EL2.RW
+    msr hcr_el2, x0
+    mrs x0, cnthctl_el2
+    orr x0, x0, #3        // EL1PCTEN, EL1PCEN
+    msr cnthctl_el2, x0
+    msr cntvoff_el2, xzr
+    mov x0, #0x33ff       // CPTR_EL2: RES1 bits, TFP clear
+    msr cptr_el2, x0
+    msr hstr_el2, xzr
+    // A GICv3 CPU interface (ID_AA64PFR0_EL1.GIC) is left to EL1's
+    // system registers: ICC_SRE_EL2.SRE and Enable, no virtual interface
+    mrs x0, id_aa64pfr0_el1
+    ubfx x0, x0, #24, #4
+    cbz x0, no_gicv3
+    mov x0, #0x9
+    msr S3_4_C12_C9_5, x0 // ICC_SRE_EL2
+    isb
+    msr S3_4_C12_C11_0, xzr // ICH_HCR_EL2
+no_gicv3:
+    ldr x0, =0x30d00800   // SCTLR_EL1 RES1 bits, MMU and caches off
+    msr sctlr_el1, x0
+    mov x0, #0x3c5        // SPSR: DAIF masked, EL1h
+    msr spsr_el2, x0
+    adr x0, at_el1
+    msr elr_el2, x0
+    eret
+at_el1:
     
-    // Clear BSS section
-    ldr x0, =__bss_start
-    ldr x1, =__bss_end
+    // Clear BSS section (boot stack and early page tables live there)
+    adrp x0, __bss_start
+    add x0, x0, :lo12:__bss_start
+    adrp x1, __bss_end
+    add x1, x1, :lo12:__bss_end
     
 clear_bss_loop:
     cmp x0, x1
@@ -30,7 +71,72 @@ clear_bss_loop:
     b clear_bss_loop
     
 clear_bss_done:
+.if {enable_mmu}
+    // Early tables: L0[0] -> L1, the first 4GB as 1GB blocks of device
+    // memory or RAM as the board has them (mmu.rs). Shared by TTBR0
+    // (identity, for the next few instructions) and TTBR1 (the kernel's
+    // linear map) until memory::init builds the real ones.
+    adrp x0, boot_l0_table
+    adrp x1, boot_l1_table
+    orr x2, x1, #3        // Table descriptor
+    str x2, [x0]
+    
+    ldr x2, ={l1_block0}
+    str x2, [x1]
+    ldr x2, ={l1_block1}
+    str x2, [x1, #8]
+    ldr x2, ={l1_block2}
+    str x2, [x1, #16]
+    ldr x2, ={l1_block3}
+    str x2, [x1, #24]
+    
+    ldr x2, ={mair}
+    msr mair_el1, x2
+    
+    // Physical address size as implemented (ID_AA64MMFR0_EL1.PARange)
+    ldr x2, ={tcr}
+    mrs x3, id_aa64mmfr0_el1
+    and x3, x3, #0x7
+    orr x2, x2, x3, lsl #32
+    msr tcr_el1, x2
+    
+    msr ttbr0_el1, x0
+    msr ttbr1_el1, x0
+    isb
+    tlbi vmalle1
+    dsb nsh
+    isb
+    
+    // MMU, data and instruction caches on; alignment checks off
+    mrs x2, sctlr_el1
+    ldr x3, ={sctlr_set}
+    orr x2, x2, x3
+    bic x2, x2, #(1 << 1)
+    msr sctlr_el1, x2
+    isb
+    
+    // Continue at the linked (upper half) address
+    ldr x2, =boot_high
+    br x2
+boot_high:
+    // Move to a random address (memory/kaslr.rs), on the boot stack as
+    // linked, and continue there. It is passed the loader's x0 and
+    // returns the distance, possibly 0.
+    ldr x0, =_stack_top
+    mov sp, x0
+    mov x0, x19
+    bl kaslr_early_init
+    adr x1, boot_relocated
+    add x1, x1, x0
+    br x1
+boot_relocated:
+.endif
+    // Set up stack pointer (the literal is relocated by now)
+    ldr x0, =_stack_top
+    mov sp, x0
+    
     // Jump to Rust main function
+    mov x0, x19
     bl rust_main
     
 halt:
@@ -40,6 +146,10 @@ halt:
 
 .section ".bss"
 .align 12
+boot_l0_table:
+    .space 4096
+boot_l1_table:
+    .space 4096
 _stack_bottom:
     .space 0x10000  // 64KB stack
-_stack_top:
\ No newline at end of file
+_stack_top:
@@ -0,0 +1,148 @@
+// Kernel command line from /chosen/bootargs
+//
+// Space-separated `key=value` pairs or bare `key` flags; a value may be
+// double-quoted to hold spaces (`init="/bin/sh -l"`). A key given twice
+// takes its last value, so a loader can append overrides. Subsystems ask
+// for their own keys at init; the line is read from the device tree on
+// first use, which lets the memory code ask before anything else is up.
+// KASLR runs before rust_main and reads the device tree directly.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::ktest::kernel_test;
+
+/// Keys something in the kernel reads, with what they do. Anything else
+/// gets a warning at boot, which catches typos.
+pub const KNOWN: &[(&str, &str)] = &[
+    ("console", "console UART: serialN or a PL011's physical address"),
+    ("dtoverlay", "initramfs overlays to apply, comma-separated, or none"),
+    ("gdbwait", "wait for a debugger on the GDB stub's UART"),
+    ("latency", "measure scheduling latency at boot, optionally =iterations"),
+    ("loglevel", "most verbose message level printed, 0-7 or a syslog name"),
+    ("memstress", "allocator stress test: [ms][,threads[,seed]]"),
+    ("memtest", "RAM test before boot: [passes][,preserve]"),
+    ("netconsole", "mirror the log over UDP: [src-port]@[src-ip]/[dev],[dst-port]@<dst-ip>/[dst-mac]"),
+    ("noaslr", "load user programs at fixed addresses"),
+    ("nokaslr", "keep the kernel at its link address"),
+    ("nowatchdog", "no lockup detection and no hardware watchdog"),
+    ("panic", "on panic: halt, reboot or poweroff"),
+    ("qemu_test", "automated test run: failures panic, panics power off"),
+    ("sched", "scheduler policy: rr or mlfq"),
+    ("semihosting", "QEMU runs with semihosting: test runs exit with a status"),
+    ("test", "boot self-tests to run, comma-separated names with * wildcards"),
+    ("test_exit", "power off after the boot self-tests with their result"),
+];
+
+// The /chosen/bootargs string, None until first read
+static CMDLINE: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Split a command line into (key, value) pairs; a bare key has no value.
+/// Quotes around a value are stripped.
+pub fn parse(cmdline: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    let mut rest = cmdline;
+    core::iter::from_fn(move || {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return None;
+        }
+        // A token ends at whitespace outside quotes
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c.is_ascii_whitespace() && !quoted
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (token, tail) = rest.split_at(end);
+        rest = tail;
+        Some(match token.split_once('=') {
+            Some((key, value)) => {
+                let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                (key, Some(value))
+            }
+            None => (token, None),
+        })
+    })
+}
+
+/// Value of `key` in `cmdline`: the last occurrence, "" for a bare key.
+pub fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    parse(cmdline).filter(|&(name, _)| name == key).last().map(|(_, value)| value.unwrap_or(""))
+}
+
+/// The whole command line ("" when the device tree has none).
+pub fn cmdline() -> &'static str {
+    let mut cmdline = CMDLINE.lock();
+    cmdline.get_or_insert_with(|| {
+        crate::devicetree::device_tree()
+            .and_then(|dt| dt.find_by_name("chosen"))
+            .and_then(|chosen| chosen.property_str("bootargs"))
+            .unwrap_or("")
+    })
+}
+
+/// Value of a boot parameter: `key=value` gives "value", a bare `key` "".
+pub fn get(key: &str) -> Option<&'static str> {
+    find(cmdline(), key)
+}
+
+/// Whether a flag is set: present, and not `=0`, `=off`, `=no` or `=false`.
+pub fn flag(key: &str) -> bool {
+    get(key).is_some_and(|value| !matches!(value, "0" | "off" | "no" | "false"))
+}
+
+fn proc_cmdline(out: &mut Vec<u8>) {
+    out.extend_from_slice(cmdline().as_bytes());
+    out.push(b'\n');
+}
+
+/// Print the command line, warn about keys nothing reads and export
+/// /proc/cmdline.
+pub fn init() {
+    let cmdline = cmdline();
+    if cmdline.is_empty() {
+        crate::println!("Boot: Empty command line");
+    } else {
+        crate::println!("Boot: Command line: {}", cmdline);
+    }
+    for (key, _) in parse(cmdline) {
+        if !KNOWN.iter().any(|&(name, _)| name == key) {
+            crate::println!("Boot: Unknown parameter {} ignored", key);
+        }
+    }
+    let _ = crate::procfs::register("cmdline", proc_cmdline);
+}
+
+#[kernel_test]
+fn test_bootparams() {
+    use alloc::vec::Vec;
+    
+    crate::println!("Bootparams Test: Testing boot command line parsing...");
+    
+    let cmdline = "  loglevel=debug noaslr init=\"/bin/sh -l\"\tconsole=uart0 loglevel=4 empty= ";
+    let params: Vec<_> = parse(cmdline).collect();
+    let expected = [
+        ("loglevel", Some("debug")),
+        ("noaslr", None),
+        ("init", Some("/bin/sh -l")),
+        ("console", Some("uart0")),
+        ("loglevel", Some("4")),
+        ("empty", Some("")),
+    ];
+    if params == expected {
+        crate::println!("Bootparams Test: ✓ Command line split into {} parameters", params.len());
+    } else {
+        crate::println!("Bootparams Test: ✗ Command line parsed as {:?}", params);
+    }
+    if find(cmdline, "loglevel") == Some("4") && find(cmdline, "noaslr") == Some("")
+        && find(cmdline, "console") == Some("uart0") && find(cmdline, "nokaslr").is_none() && find("", "x").is_none() {
+        crate::println!("Bootparams Test: ✓ Last value wins, bare flags read as empty");
+    } else {
+        crate::println!("Bootparams Test: ✗ Parameter lookup wrong");
+    }
+    
+    crate::println!("Bootparams Test: Boot command line test completed");
+}
@@ -0,0 +1,18 @@
+// CPU numbering for per-CPU state
+//
+// A CPU is numbered by its MPIDR Aff0, which is also its bit in the GIC's
+// u8 CPU mask (gic::this_cpu), so per-CPU arrays have MAX_CPUS entries:
+// a CPU the mask cannot name is never brought online, and every CPU that
+// runs the kernel has a slot. Secondary CPUs do not run the kernel yet.
+
+/// Entries in a per-CPU array; one per bit of a GIC CPU mask.
+pub const MAX_CPUS: usize = u8::BITS as usize;
+
+/// The calling CPU's number, its MPIDR Aff0.
+pub fn cpu_index() -> usize {
+    let mpidr: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, mpidr_el1", out(reg) mpidr);
+    }
+    (mpidr & 0xFF) as usize
+}
@@ -0,0 +1,412 @@
+// Crash dumps kept across a reset
+//
+// After printing its report the panic handler serializes the same state
+// into the crash zone at the end of the pstore region: the message, the
+// registers, the backtrace, the bottom of the stack it was walking and as
+// much of the log leading up to the crash as fits. Nothing is allocated
+// and no lock is waited for, so a dump can be taken from a panic with
+// the heap or the log in any state.
+//
+// A warm reset leaves RAM alone, so the next boot finds the dump, prints
+// a summary and keeps the whole report as /proc/lastcrash. The zone is
+// then cleared, so the crash is reported once. Addresses are from the
+// crashed kernel; they are named against this one, which is only right
+// when the same image booted again (KASLR offsets are allowed for).
+//
+// The zone is a 16-byte header (magic, version, record bytes, CRC-32 of
+// the records) and then records: a 16-bit kind and 16-bit length, both
+// little-endian, and the data. Unknown kinds are skipped.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use crate::backtrace::{self, StartFrame};
+use crate::cpu::cpu_index;
+use crate::ktest::kernel_test;
+
+const CRASH_MAGIC: u32 = 0x4853_5243; // "CRSH"
+const CRASH_VERSION: u32 = 1;
+const HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 4;
+
+// Record kinds
+const RECORD_INFO: u16 = 1;        // uptime ns, kernel offset, CPU (u64 each)
+const RECORD_MESSAGE: u16 = 2;     // text
+const RECORD_THREAD: u16 = 3;      // text
+const RECORD_REGISTERS: u16 = 4;   // flags, then REGISTER_COUNT u64s
+const RECORD_BACKTRACE: u16 = 5;   // u64 addresses, innermost first
+const RECORD_STACK: u16 = 6;       // u64 start address, then the bytes
+const RECORD_LOG: u16 = 7;         // text
+
+// x0-x30, sp, pc, spsr, esr, far
+pub const REGISTER_COUNT: usize = 36;
+// Registers flag: all of x0-x30 are valid, not just the callee-saved ones
+const REGISTERS_ALL: u64 = 1 << 0;
+
+const MAX_MESSAGE: usize = 512;
+const MAX_FRAMES: usize = 32;
+const STACK_SNAPSHOT: usize = 2048;
+
+static LASTCRASH: Mutex<Option<String>> = Mutex::new(None);
+
+/// What the panic handler knows about a crash.
+pub struct Report<'a> {
+    pub message: fmt::Arguments<'a>,
+    pub location: Option<&'a core::panic::Location<'a>>,
+    /// Where a backtrace starts, and the stack it may read.
+    pub start: StartFrame,
+    pub stack_low: u64,
+    pub stack_high: u64,
+    /// Taken from an exception frame, so every register is valid.
+    pub exception: bool,
+    pub spsr: u64,
+}
+
+/// A dump read back.
+pub struct Crash {
+    pub message: String,
+    pub thread: String,
+    pub cpu: u64,
+    pub uptime_ns: u64,
+    pub kernel_offset: u64,
+    pub all_registers: bool,
+    /// x0-x30, sp, pc, spsr, esr, far.
+    pub registers: [u64; REGISTER_COUNT],
+    pub frames: Vec<u64>,
+    pub stack_base: u64,
+    pub stack: Vec<u8>,
+    pub log: Vec<u8>,
+}
+
+// Appends to a buffer, dropping whatever does not fit
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) {
+        let room = (self.buf.len() - self.len).min(bytes.len());
+        self.buf[self.len..self.len + room].copy_from_slice(&bytes[..room]);
+        self.len += room;
+    }
+    
+    fn put_u64(&mut self, value: u64) {
+        self.put(&value.to_le_bytes());
+    }
+    
+    // Write a record holding what `fill` puts, at most `max` bytes of it
+    fn record(&mut self, kind: u16, max: usize, fill: impl FnOnce(&mut Writer)) {
+        let start = self.len;
+        if start + RECORD_HEADER_LEN > self.buf.len() {
+            return;
+        }
+        let end = (start + RECORD_HEADER_LEN + max.min(u16::MAX as usize)).min(self.buf.len());
+        let mut data = Writer { buf: &mut self.buf[start + RECORD_HEADER_LEN..end], len: 0 };
+        fill(&mut data);
+        let len = data.len;
+        self.buf[start..start + 2].copy_from_slice(&kind.to_le_bytes());
+        self.buf[start + 2..start + 4].copy_from_slice(&(len as u16).to_le_bytes());
+        self.len = start + RECORD_HEADER_LEN + len;
+    }
+}
+
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.put(s.as_bytes());
+        Ok(())
+    }
+}
+
+fn le(data: &[u8], offset: usize) -> u64 {
+    data.get(offset..offset + 8).map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap_or([0; 8])))
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4).map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap_or([0; 4])))
+}
+
+/// Serialize `report`, with the current thread and the tail of the
+/// mirrored log, into `zone`; returns the bytes used.
+pub fn write_dump(zone: &mut [u8], report: &Report) -> usize {
+    if zone.len() < HEADER_LEN {
+        return 0;
+    }
+    let (esr, far): (u64, u64);
+    unsafe {
+        asm!("mrs {}, esr_el1", out(reg) esr);
+        asm!("mrs {}, far_el1", out(reg) far);
+    }
+    
+    let (header, body) = zone.split_at_mut(HEADER_LEN);
+    let mut out = Writer { buf: body, len: 0 };
+    out.record(RECORD_INFO, 24, |w| {
+        w.put_u64(crate::time::monotonic_ns());
+        w.put_u64(crate::memory::kaslr::offset());
+        w.put_u64(cpu_index() as u64);
+    });
+    out.record(RECORD_MESSAGE, MAX_MESSAGE, |w| {
+        let _ = w.write_fmt(report.message);
+        if let Some(location) = report.location {
+            let _ = write!(w, " ({}:{})", location.file(), location.line());
+        }
+    });
+    out.record(RECORD_THREAD, 64, |w| {
+        let thread = crate::process::scheduler::current_thread_id();
+        let _ = write!(w, "{}", crate::process::scheduler::thread_ref(thread));
+    });
+    out.record(RECORD_REGISTERS, 8 * (1 + REGISTER_COUNT), |w| {
+        w.put_u64(if report.exception { REGISTERS_ALL } else { 0 });
+        for &reg in &report.start.regs {
+            w.put_u64(reg);
+        }
+        for value in [report.start.pc, report.spsr, esr, far] {
+            w.put_u64(value);
+        }
+    });
+    out.record(RECORD_BACKTRACE, 8 * (MAX_FRAMES + 1), |w| {
+        if report.exception {
+            w.put_u64(report.start.pc);
+        }
+        for lr in backtrace::walk(&report.start, report.stack_low, report.stack_high).take(MAX_FRAMES) {
+            w.put_u64(lr.wrapping_sub(4));
+        }
+    });
+    let stack_len = (report.stack_high.saturating_sub(report.stack_low) as usize).min(STACK_SNAPSHOT);
+    out.record(RECORD_STACK, 8 + stack_len, |w| {
+        w.put_u64(report.stack_low);
+        let stack = unsafe { core::slice::from_raw_parts(report.stack_low as *const u8, stack_len) };
+        w.put(stack);
+    });
+    // The log takes whatever room is left
+    out.record(RECORD_LOG, usize::MAX, |w| {
+        let start = w.len;
+        w.len += crate::pstore::copy_tail(&mut w.buf[start..]);
+    });
+    
+    let len = out.len;
+    let crc = crate::pstore::crc32(&out.buf[..len]);
+    for (chunk, field) in header.chunks_mut(4).zip([CRASH_MAGIC, CRASH_VERSION, len as u32, crc]) {
+        chunk.copy_from_slice(&field.to_le_bytes());
+    }
+    HEADER_LEN + len
+}
+
+/// Read back a dump written by `write_dump`, if `zone` holds a whole one.
+pub fn parse(zone: &[u8]) -> Option<Crash> {
+    let len = le32(zone, 8) as usize;
+    if le32(zone, 0) != CRASH_MAGIC || le32(zone, 4) != CRASH_VERSION || len > zone.len() - HEADER_LEN {
+        return None;
+    }
+    let body = &zone[HEADER_LEN..HEADER_LEN + len];
+    if crate::pstore::crc32(body) != le32(zone, 12) {
+        return None;
+    }
+    
+    let mut crash = Crash {
+        message: String::new(),
+        thread: String::new(),
+        cpu: 0,
+        uptime_ns: 0,
+        kernel_offset: 0,
+        all_registers: false,
+        registers: [0; REGISTER_COUNT],
+        frames: Vec::new(),
+        stack_base: 0,
+        stack: Vec::new(),
+        log: Vec::new(),
+    };
+    let text = |data: &[u8]| String::from_utf8_lossy(data).into_owned();
+    let mut offset = 0;
+    while offset + RECORD_HEADER_LEN <= body.len() {
+        let kind = u16::from_le_bytes([body[offset], body[offset + 1]]);
+        let data_len = u16::from_le_bytes([body[offset + 2], body[offset + 3]]) as usize;
+        let data = body.get(offset + RECORD_HEADER_LEN..offset + RECORD_HEADER_LEN + data_len)?;
+        offset += RECORD_HEADER_LEN + data_len;
+        match kind {
+            RECORD_INFO => {
+                crash.uptime_ns = le(data, 0);
+                crash.kernel_offset = le(data, 8);
+                crash.cpu = le(data, 16);
+            }
+            RECORD_MESSAGE => crash.message = text(data),
+            RECORD_THREAD => crash.thread = text(data),
+            RECORD_REGISTERS => {
+                crash.all_registers = le(data, 0) & REGISTERS_ALL != 0;
+                for (n, reg) in crash.registers.iter_mut().enumerate() {
+                    *reg = le(data, 8 * (n + 1));
+                }
+            }
+            RECORD_BACKTRACE => crash.frames = data.chunks_exact(8).map(|word| le(word, 0)).collect(),
+            RECORD_STACK if data.len() >= 8 => {
+                crash.stack_base = le(data, 0);
+                crash.stack = data[8..].to_vec();
+            }
+            RECORD_LOG => crash.log = data.to_vec(),
+            _ => {}
+        }
+    }
+    Some(crash)
+}
+
+/// Called by the panic handler once its report is out: keep a dump for
+/// the next boot. The caller writes the pstore region back afterwards.
+pub fn save(report: &Report) {
+    if let Some((zone, size)) = crate::pstore::crash_zone() {
+        let zone = unsafe { core::slice::from_raw_parts_mut(zone, size) };
+        let len = write_dump(zone, report);
+        crate::println!("Crashdump: {} bytes saved for the next boot", len);
+    }
+}
+
+// The crashed kernel's `addr` named in this one, moved by the difference
+// in KASLR offsets
+fn symbol(addr: u64, kernel_offset: u64) -> Option<crate::symbols::Symbol> {
+    let link = if crate::memory::kaslr::in_region(addr) { addr.wrapping_sub(kernel_offset) } else { addr };
+    crate::symbols::lookup(link.wrapping_add(crate::memory::kaslr::offset()))
+}
+
+fn write_frames(text: &mut String, crash: &Crash) {
+    for (depth, &addr) in crash.frames.iter().enumerate() {
+        match symbol(addr, crash.kernel_offset) {
+            Some(symbol) => {
+                let _ = writeln!(text, "  #{:<2} {:016x} {}", depth, addr, symbol);
+            }
+            None => {
+                let _ = writeln!(text, "  #{:<2} {:016x}", depth, addr);
+            }
+        }
+    }
+}
+
+/// The full report: everything `parse` recovered, as text.
+pub fn render(crash: &Crash) -> String {
+    let mut text = String::new();
+    let _ = writeln!(text, "Panic {}.{:06}s after boot on CPU {}, thread {}", crash.uptime_ns / 1_000_000_000,
+                     crash.uptime_ns % 1_000_000_000 / 1000, crash.cpu, crash.thread);
+    let _ = writeln!(text, "Message: {}", crash.message);
+    if crash.kernel_offset != 0 {
+        let _ = writeln!(text, "Kernel offset 0x{:x} from the link address", crash.kernel_offset);
+    }
+    
+    let regs = &crash.registers;
+    let _ = writeln!(text, "Registers ({}):", if crash.all_registers { "at exception" } else { "callee-saved" });
+    let first = if crash.all_registers { 0 } else { 19 };
+    for (row, values) in regs[first..31].chunks(3).enumerate() {
+        for (col, value) in values.iter().enumerate() {
+            let _ = write!(text, "  x{:<2} {:016x}", first + row * 3 + col, value);
+        }
+        let _ = writeln!(text);
+    }
+    let _ = writeln!(text, "  pc  {:016x}  sp  {:016x}", regs[32], regs[31]);
+    let _ = writeln!(text, "  spsr {:08x}  esr {:08x}  far {:016x}", regs[33], regs[34], regs[35]);
+    
+    let _ = writeln!(text, "Backtrace:");
+    write_frames(&mut text, crash);
+    
+    let _ = writeln!(text, "Stack from {:016x}:", crash.stack_base);
+    for (line, words) in crash.stack.chunks(32).enumerate() {
+        let _ = write!(text, "  {:016x}:", crash.stack_base + line as u64 * 32);
+        for word in words.chunks_exact(8) {
+            let _ = write!(text, " {:016x}", le(word, 0));
+        }
+        let _ = writeln!(text);
+    }
+    
+    let _ = writeln!(text, "Log before the crash:");
+    text.push_str(&String::from_utf8_lossy(&crash.log));
+    text
+}
+
+fn read_lastcrash(out: &mut Vec<u8>) {
+    if let Some(report) = LASTCRASH.lock().as_ref() {
+        out.extend_from_slice(report.as_bytes());
+    }
+}
+
+/// Report a dump left by the previous boot and clear it. Needs the heap
+/// and the pstore region; names frames best once symbols are up.
+pub fn init() {
+    let Some((zone, size)) = crate::pstore::crash_zone() else {
+        crate::println!("Crashdump: No room in the pstore region, crashes not kept");
+        return;
+    };
+    let zone = unsafe { core::slice::from_raw_parts_mut(zone, size) };
+    let Some(crash) = parse(zone) else {
+        crate::println!("Crashdump: No crash recorded by the previous boot");
+        return;
+    };
+    
+    crate::println!("Crashdump: The previous boot panicked on CPU {} in thread {}: {}",
+                   crash.cpu, crash.thread, crash.message);
+    let mut frames = String::new();
+    write_frames(&mut frames, &crash);
+    for line in frames.lines().take(8) {
+        crate::println!("Crashdump: {}", line);
+    }
+    crate::println!("Crashdump: Full report in /proc/lastcrash");
+    *LASTCRASH.lock() = Some(render(&crash));
+    let _ = crate::procfs::register("lastcrash", read_lastcrash);
+    
+    // Reported once: a later clean reboot finds nothing
+    zone[..HEADER_LEN].fill(0);
+    crate::pstore::flush();
+}
+
+#[kernel_test]
+fn test_crashdump() {
+    use alloc::vec;
+    use crate::backtrace::StartFrame;
+    
+    crate::println!("Crashdump Test: Testing crash dumps...");
+    
+    // A dump of this function, into a scratch zone rather than the real one
+    let start = StartFrame::current();
+    let stack_high = crate::process::thread::stack_bounds(start.sp()).map_or(start.sp() + 4096, |(_, top)| top);
+    let report = |zone: &mut [u8]| write_dump(zone, &Report {
+        message: format_args!("test crash {}", 42),
+        location: None,
+        start,
+        stack_low: start.sp(),
+        stack_high,
+        exception: false,
+        spsr: 0,
+    });
+    let mut zone = vec![0u8; crate::pstore::CRASH_ZONE_SIZE];
+    let len = report(&mut zone);
+    match parse(&zone) {
+        Some(crash) if crash.message == "test crash 42" && crash.registers[32] == start.pc
+            && crash.stack_base == start.sp() && !crash.stack.is_empty() && !crash.thread.is_empty() =>
+        {
+            let rendered = render(&crash);
+            crate::println!("Crashdump Test: ✓ Dump of {} bytes read back: {} frames, {} stack and {} log bytes{}",
+                           len, crash.frames.len(), crash.stack.len(), crash.log.len(),
+                           if rendered.contains("Backtrace:") { "" } else { " (render incomplete)" });
+        }
+        Some(crash) => crate::println!("Crashdump Test: ✗ Dump read back wrong: {:?} pc {:x}", crash.message, crash.registers[32]),
+        None => crate::println!("Crashdump Test: ✗ Dump of {} bytes not read back", len),
+    }
+    
+    // A flipped byte fails the CRC; a small zone keeps the leading records
+    zone[len / 2] ^= 1;
+    let corrupt = parse(&zone).is_none();
+    let mut small = [0u8; 256];
+    report(&mut small);
+    let truncated = parse(&small).is_some_and(|crash| crash.message == "test crash 42" && crash.log.is_empty());
+    if corrupt && truncated {
+        crate::println!("Crashdump Test: ✓ Corrupt dump rejected, dump in a small zone truncated");
+    } else {
+        crate::println!("Crashdump Test: ✗ Corrupt accepted {} / small zone {}", !corrupt, truncated);
+    }
+    
+    // Only present after a crash and a warm reset
+    let mut buf = [0u8; 64];
+    match crate::procfs::read("lastcrash", 0, &mut buf) {
+        Ok(len) => crate::println!("Crashdump Test: /proc/lastcrash readable ({} bytes at start)", len),
+        Err(_) => crate::println!("Crashdump Test: /proc/lastcrash absent (no previous crash)"),
+    }
+    
+    crate::println!("Crashdump Test: Crash dump test completed");
+}
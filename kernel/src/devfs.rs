@@ -0,0 +1,152 @@
+// Device namespace exposing kernel drivers by path (like /dev)
+//
+// Mounted at /dev, so userspace reads and writes devices as files through
+// the VFS (ring OP_READ/OP_WRITE) and sends control requests with the
+// device_ioctl syscall. Directories are implied by the registered paths:
+// "leds/status" makes /dev/leds a directory.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::vfs::{DirEntry, FileSystem, Metadata, NodeKind};
+
+/// Where the namespace is mounted.
+pub const MOUNT_POINT: &str = "/dev";
+
+/// A character device reachable through the device namespace.
+///
+/// Reads and writes are whole-record operations: a read returns the
+/// device's current state and a write applies a new one.
+pub trait Device: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str>;
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str>;
+    
+    /// Device-specific control request (numbers follow Linux where one exists).
+    fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<usize, &'static str> {
+        Err("Operation not supported")
+    }
+}
+
+struct DeviceEntry {
+    path: String,
+    device: Arc<dyn Device>,
+}
+
+static DEVICES: Mutex<Vec<DeviceEntry>> = Mutex::new(Vec::new());
+
+pub fn init() {
+    match crate::vfs::mount(MOUNT_POINT, Arc::new(DevFs)) {
+        Ok(()) => crate::println!("Devfs: Device namespace ready"),
+        Err(e) => crate::println!("Devfs: Failed to mount {}: {}", MOUNT_POINT, e),
+    }
+}
+
+/// Register a device under a path such as "leds/status".
+pub fn register(path: &str, device: Arc<dyn Device>) -> Result<(), &'static str> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|entry| entry.path == path) {
+        return Err("Device path already registered");
+    }
+    devices.push(DeviceEntry {
+        path: String::from(path),
+        device,
+    });
+    crate::println!("Devfs: Registered /dev/{}", path);
+    Ok(())
+}
+
+/// Remove a device; open handles keep it alive until dropped.
+pub fn unregister(path: &str) -> Result<(), &'static str> {
+    let mut devices = DEVICES.lock();
+    let index = devices
+        .iter()
+        .position(|entry| entry.path == path)
+        .ok_or("No such device")?;
+    devices.remove(index);
+    Ok(())
+}
+
+/// Look up a device by path.
+pub fn open(path: &str) -> Option<Arc<dyn Device>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|entry| entry.path == path)
+        .map(|entry| entry.device.clone())
+}
+
+pub fn ioctl(path: &str, cmd: u32, arg: usize) -> Result<usize, &'static str> {
+    open(path).ok_or("No such device")?.ioctl(cmd, arg)
+}
+
+/// Paths of all registered devices, optionally restricted to a prefix.
+pub fn list(prefix: &str) -> Vec<String> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|entry| entry.path.starts_with(prefix))
+        .map(|entry| entry.path.clone())
+        .collect()
+}
+
+// The namespace as a filesystem. Devices have no size and no offsets: a
+// read at offset 0 returns the current record and any later offset ends
+// the file, and every write goes to the device whole.
+struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+    
+    fn stat(&self, path: &str) -> Result<Metadata, &'static str> {
+        if open(path).is_some() {
+            return Ok(Metadata { kind: NodeKind::File, size: 0 });
+        }
+        if path.is_empty() || !children(path).is_empty() {
+            return Ok(Metadata { kind: NodeKind::Directory, size: 0 });
+        }
+        Err("No such file or directory")
+    }
+    
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let device = open(path).ok_or("No such file or directory")?;
+        if offset > 0 {
+            return Ok(0);
+        }
+        device.read(buf)
+    }
+    
+    fn write(&self, path: &str, _offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        open(path).ok_or("No such file or directory")?.write(buf)
+    }
+    
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, &'static str> {
+        if open(path).is_some() {
+            return Err("Not a directory");
+        }
+        let entries = children(path);
+        if entries.is_empty() && !path.is_empty() {
+            return Err("No such file or directory");
+        }
+        Ok(entries)
+    }
+}
+
+// Entries directly below directory `path` ("" for the root)
+fn children(path: &str) -> Vec<DirEntry> {
+    let prefix = if path.is_empty() { String::new() } else { alloc::format!("{}/", path) };
+    let mut entries: Vec<DirEntry> = Vec::new();
+    for device in list(&prefix) {
+        let rest = &device[prefix.len()..];
+        let (name, kind) = match rest.split_once('/') {
+            Some((dir, _)) => (dir, NodeKind::Directory),
+            None => (rest, NodeKind::File),
+        };
+        if !entries.iter().any(|entry| entry.name == name) {
+            entries.push(DirEntry { name: String::from(name), kind, size: 0 });
+        }
+    }
+    entries
+}
@@ -1,7 +1,8 @@
-// Basic device tree parsing for ARM64 memory discovery
+// Basic device tree parsing for ARM64 memory and device discovery
 
 use core::ptr::read_volatile;
 use core::slice;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 // FDT (Flattened Device Tree) header
 #[repr(C)]
@@ -18,12 +19,13 @@ pub struct FdtHeader {
     size_dt_struct: u32,
 }
 
-const FDT_MAGIC: u32 = 0xd00dfeed;
-const FDT_BEGIN_NODE: u32 = 0x00000001;
-const FDT_END_NODE: u32 = 0x00000002;
-const FDT_PROP: u32 = 0x00000003;
-const FDT_NOP: u32 = 0x00000004;
-const FDT_END: u32 = 0x00000009;
+use fdt_parser::{FDT_BEGIN_NODE, FDT_END_NODE, FDT_HEADER_LEN, FDT_MAGIC, FDT_NOP, FDT_PROP};
+
+// Physical address of the blob the loader passed in x0; 0 = none
+static BOOT_FDT: AtomicU64 = AtomicU64::new(0);
+
+// Kernel virtual address of a replacement blob (overlays applied); 0 = none
+static ACTIVE_FDT: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Copy, Clone, Debug)]
 pub struct MemoryRegion {
@@ -38,6 +40,268 @@ fn read_be(ptr: *const u32) -> u32 {
     }
 }
 
+// Read a big-endian cell from a property value at the given cell index
+pub fn read_cell(data: &[u8], index: usize) -> Option<u32> {
+    let bytes = data.get(index * 4..index * 4 + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// A value of 1 or 2 cells starting at cell `index`
+fn read_cells(data: &[u8], index: usize, cells: usize) -> Option<u64> {
+    match cells {
+        1 => Some(read_cell(data, index)? as u64),
+        2 => Some(((read_cell(data, index)? as u64) << 32) | read_cell(data, index + 1)? as u64),
+        _ => None,
+    }
+}
+
+// Ancestors a node keeps for address translation; buses nested deeper
+// than this are taken to map 1:1
+const MAX_BUS_DEPTH: usize = 4;
+// Nesting NodeIter tracks ancestors through
+const MAX_DEPTH: usize = 16;
+
+// Look a property up in the block at `props`
+unsafe fn find_property(props: *const u32, strings: *const u8, name: &str) -> Option<&'static [u8]> {
+    let mut current = props;
+    loop {
+        match read_be(current) {
+            FDT_PROP => {
+                let len = read_be(current.offset(1));
+                let nameoff = read_be(current.offset(2));
+                let data = current.offset(3) as *const u8;
+                
+                let (prop_name, _) = skip_node_name(strings.offset(nameoff as isize));
+                if prop_name == name {
+                    return Some(slice::from_raw_parts(data, len as usize));
+                }
+                
+                let aligned_len = (len + 3) & !3;
+                current = data.offset(aligned_len as isize) as *const u32;
+            }
+            FDT_NOP => {
+                current = current.offset(1);
+            }
+            // Properties always precede child nodes
+            _ => return None,
+        }
+    }
+}
+
+// Skip a null-terminated name and return a pointer to the next aligned token
+unsafe fn skip_node_name(name_ptr: *const u8) -> (&'static str, *const u32) {
+    let mut len = 0;
+    while *name_ptr.offset(len) != 0 {
+        len += 1;
+    }
+    let name = slice::from_raw_parts(name_ptr, len as usize);
+    let name = core::str::from_utf8(name).unwrap_or("");
+    // Include terminator and align to 4 bytes
+    let aligned = (len + 4) & !3;
+    (name, name_ptr.offset(aligned) as *const u32)
+}
+
+/// A node in the flattened device tree.
+///
+/// Properties are looked up lazily by rescanning the node's property
+/// block, which keeps the node itself small enough to copy around. It
+/// remembers where its nearest ancestors' properties are, for their
+/// #address-cells, #size-cells and "ranges".
+#[derive(Copy, Clone)]
+pub struct DeviceNode {
+    name: &'static str,
+    props: *const u32,
+    strings: *const u8,
+    depth: usize,
+    // Depth below the root, whatever the iteration started from
+    level: usize,
+    // Parent first; null past the root
+    ancestors: [*const u32; MAX_BUS_DEPTH],
+}
+
+impl DeviceNode {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+    
+    /// Raw value of the named property, if present.
+    pub fn property(&self, name: &str) -> Option<&'static [u8]> {
+        unsafe { find_property(self.props, self.strings, name) }
+    }
+    
+    // A property of the nth ancestor (0 = parent)
+    fn ancestor_property(&self, n: usize, name: &str) -> Option<&'static [u8]> {
+        let props = *self.ancestors.get(n)?;
+        if props.is_null() {
+            return None;
+        }
+        unsafe { find_property(props, self.strings, name) }
+    }
+    
+    // (#address-cells, #size-cells) the nth ancestor gives its children
+    fn bus_cells(&self, n: usize) -> (usize, usize) {
+        let cells = |name, default| {
+            self.ancestor_property(n, name).and_then(|value| read_cell(value, 0)).map_or(default, |cells| cells as usize)
+        };
+        (cells("#address-cells", 2), cells("#size-cells", 1))
+    }
+    
+    // Carry a bus address up through each ancestor's "ranges" to a CPU
+    // physical address; None where a bus is not memory mapped
+    fn translate(&self, mut addr: u64) -> Option<u64> {
+        // The root's children are already in the CPU's address space
+        for n in 0..self.level.saturating_sub(1).min(MAX_BUS_DEPTH) {
+            let ranges = self.ancestor_property(n, "ranges")?;
+            if ranges.is_empty() {
+                continue;
+            }
+            let (child_cells, size_cells) = self.bus_cells(n);
+            let (parent_cells, _) = self.bus_cells(n + 1);
+            let stride = child_cells + parent_cells + size_cells;
+            addr = (0..ranges.len() / 4 / stride).find_map(|i| {
+                let child = read_cells(ranges, i * stride, child_cells)?;
+                let parent = read_cells(ranges, i * stride + child_cells, parent_cells)?;
+                let size = read_cells(ranges, i * stride + child_cells + parent_cells, size_cells)?;
+                (addr >= child && addr - child < size).then(|| parent + (addr - child))
+            })?;
+        }
+        Some(addr)
+    }
+    
+    /// First cell of a u32 property.
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        read_cell(self.property(name)?, 0)
+    }
+    
+    /// A string property without its null terminator.
+    pub fn property_str(&self, name: &str) -> Option<&'static str> {
+        let data = self.property(name)?;
+        let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+        core::str::from_utf8(&data[..len]).ok()
+    }
+    
+    /// Whether any entry of the "compatible" string list matches.
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        match self.property("compatible") {
+            Some(list) => list
+                .split(|&b| b == 0)
+                .any(|entry| entry == compatible.as_bytes()),
+            None => false,
+        }
+    }
+    
+    pub fn phandle(&self) -> Option<u32> {
+        self.property_u32("phandle")
+    }
+    
+    /// Nth (address, size) pair of the "reg" property, laid out by the
+    /// parent's #address-cells and #size-cells and translated through the
+    /// buses' "ranges" to a physical address (Raspberry Pi peripherals sit
+    /// at bus address 0x7e000000 under /soc). None for devices on a bus
+    /// that is not memory mapped, such as I2C.
+    pub fn reg(&self, index: usize) -> Option<(u64, u64)> {
+        if self.level == 0 {
+            return None;
+        }
+        let (address_cells, size_cells) = self.bus_cells(0);
+        let reg = self.property("reg")?;
+        let base = index * (address_cells + size_cells);
+        let addr = read_cells(reg, base, address_cells)?;
+        let size = if size_cells == 0 { 0 } else { read_cells(reg, base + address_cells, size_cells)? };
+        Some((self.translate(addr)?, size))
+    }
+    
+    /// Status is "okay" or absent.
+    pub fn is_enabled(&self) -> bool {
+        match self.property_str("status") {
+            Some(status) => status == "okay" || status == "ok",
+            None => true,
+        }
+    }
+}
+
+/// Depth-first iterator over device tree nodes.
+///
+/// When created for a subtree, iteration stops at the end of that subtree
+/// and depths are relative to the subtree root.
+pub struct NodeIter {
+    current: *const u32,
+    end: *const u8,
+    strings: *const u8,
+    depth: usize,
+    subtree: bool,
+    // Property blocks of the open nodes, by depth
+    open: [*const u32; MAX_DEPTH],
+    // Ancestors above where iteration started, and its level
+    outer: [*const u32; MAX_BUS_DEPTH],
+    base_level: usize,
+}
+
+impl NodeIter {
+    fn ancestors(&self) -> [*const u32; MAX_BUS_DEPTH] {
+        core::array::from_fn(|n| match self.depth.checked_sub(n + 1) {
+            Some(depth) => self.open.get(depth).copied().unwrap_or(core::ptr::null()),
+            None => self.outer.get(n - self.depth).copied().unwrap_or(core::ptr::null()),
+        })
+    }
+}
+
+impl Iterator for NodeIter {
+    type Item = DeviceNode;
+    
+    fn next(&mut self) -> Option<DeviceNode> {
+        unsafe {
+            while (self.current as *const u8) < self.end {
+                let token = read_be(self.current);
+                self.current = self.current.offset(1);
+                
+                match token {
+                    FDT_BEGIN_NODE => {
+                        let (name, props) = skip_node_name(self.current as *const u8);
+                        self.current = props;
+                        let node = DeviceNode {
+                            name,
+                            props,
+                            strings: self.strings,
+                            depth: self.depth,
+                            level: self.base_level + self.depth,
+                            ancestors: self.ancestors(),
+                        };
+                        if let Some(open) = self.open.get_mut(self.depth) {
+                            *open = props;
+                        }
+                        self.depth += 1;
+                        return Some(node);
+                    }
+                    FDT_END_NODE => {
+                        if self.depth == 0 {
+                            return None;
+                        }
+                        self.depth -= 1;
+                        if self.subtree && self.depth == 0 {
+                            self.current = self.end as *const u32;
+                            return None;
+                        }
+                    }
+                    FDT_PROP => {
+                        let len = read_be(self.current);
+                        let aligned_len = (len + 3) & !3;
+                        self.current = (self.current.offset(2) as *const u8)
+                            .offset(aligned_len as isize) as *const u32;
+                    }
+                    FDT_NOP => {}
+                    _ => return None,
+                }
+            }
+        }
+        None
+    }
+}
+
 pub struct DeviceTree {
     header: *const FdtHeader,
     memory_regions: [Option<MemoryRegion>; 8],
@@ -45,18 +309,18 @@ pub struct DeviceTree {
 }
 
 impl DeviceTree {
+    /// Check the blob at `fdt_addr` and wrap it. The walkers below follow
+    /// raw pointers, so every offset and length is validated here first.
     pub fn new(fdt_addr: *const u8) -> Option<Self> {
         let header = fdt_addr as *const FdtHeader;
+        if !header.is_aligned() {
+            return None;
+        }
         
         unsafe {
-            // Verify magic number
-            if read_be(&(*header).magic) != FDT_MAGIC {
-                return None;
-            }
-            
-            // Check version
-            let version = read_be(&(*header).version);
-            if version < 16 {
+            let totalsize = fdt_parser::total_size(slice::from_raw_parts(fdt_addr, FDT_HEADER_LEN)).ok()?;
+            if let Err(e) = fdt_parser::validate(slice::from_raw_parts(fdt_addr, totalsize)) {
+                crate::println!("DeviceTree: Rejecting blob at {:p}: {}", fdt_addr, e);
                 return None;
             }
         }
@@ -68,115 +332,174 @@ impl DeviceTree {
         })
     }
     
+    /// Collect the RAM ranges of the /memory nodes (a node may list
+    /// several, as the Raspberry Pi firmware's does).
     pub fn parse_memory(&mut self) -> Result<(), &'static str> {
-        unsafe {
-            let header = &*self.header;
-            let totalsize = read_be(&header.totalsize) as isize;
-            let struct_offset = read_be(&header.off_dt_struct) as isize;
-            
-            let struct_ptr = (self.header as *const u8).offset(struct_offset) as *const u32;
-            let mut current = struct_ptr;
-            let end = (self.header as *const u8).offset(totalsize);
-            
-            while (current as *const u8) < end {
-                let token = read_be(&*current);
-                current = current.offset(1);
-                
-                match token {
-                    FDT_BEGIN_NODE => {
-                        // Skip node name
-                        let name_ptr = current as *const u8;
-                        let mut len = 0;
-                        while *name_ptr.offset(len) != 0 {
-                            len += 1;
-                        }
-                        // Align to 4 bytes
-                        len = (len + 4) & !3;
-                        current = (current as *const u8).offset(len) as *const u32;
-                        
-                        // Check if this is a memory node
-                        let name = slice::from_raw_parts(name_ptr, len as usize);
-                        if let Ok(name_str) = core::str::from_utf8(name) {
-                            if name_str.starts_with("memory") {
-                                self.parse_memory_node(&mut current)?;
-                            }
-                        }
-                    }
-                    FDT_END_NODE => {
-                        // End of current node
-                    }
-                    FDT_PROP => {
-                        // Skip property
-                        let len = read_be(&*current);
-                        current = current.offset(1);
-                        let _nameoff = read_be(&*current);
-                        current = current.offset(1);
-                        // Skip property data (aligned to 4 bytes)
-                        let aligned_len = (len + 3) & !3;
-                        current = (current as *const u8).offset(aligned_len as isize) as *const u32;
-                    }
-                    FDT_NOP => {
-                        // No operation
-                    }
-                    FDT_END => {
-                        break;
-                    }
-                    _ => {
-                        return Err("Invalid FDT token");
-                    }
+        let memory_nodes = self.nodes().filter(|node| {
+            node.depth() == 1 && (node.name() == "memory" || node.name().starts_with("memory@")) && node.is_enabled()
+        });
+        for node in memory_nodes {
+            let mut index = 0;
+            while let Some((start, size)) = node.reg(index) {
+                index += 1;
+                if size == 0 {
+                    continue;
                 }
+                // Any further ranges go unused
+                if self.region_count == self.memory_regions.len() {
+                    return Ok(());
+                }
+                self.memory_regions[self.region_count] = Some(MemoryRegion { start, size });
+                self.region_count += 1;
             }
         }
-        
         Ok(())
     }
     
-    unsafe fn parse_memory_node(&mut self, current: &mut *const u32) -> Result<(), &'static str> {
-        // Look for "reg" property in memory node
-        while read_be(&**current) != FDT_END_NODE {
-            let token = read_be(&**current);
-            *current = current.offset(1);
-            
-            if token == FDT_PROP {
-                let len = read_be(&**current);
-                *current = current.offset(1);
-                let _nameoff = read_be(&**current);
-                *current = current.offset(1);
-                
-                // Parse reg property (address, size pairs)
-                if len >= 16 && self.region_count < 8 {
-                    let addr_high = read_be(&**current);
-                    *current = current.offset(1);
-                    let addr_low = read_be(&**current);
-                    *current = current.offset(1);
-                    let size_high = read_be(&**current);
-                    *current = current.offset(1);
-                    let size_low = read_be(&**current);
-                    *current = current.offset(1);
-                    
-                    let start = ((addr_high as u64) << 32) | (addr_low as u64);
-                    let size = ((size_high as u64) << 32) | (size_low as u64);
-                    
-                    self.memory_regions[self.region_count] = Some(MemoryRegion { start, size });
-                    self.region_count += 1;
-                } else {
-                    // Skip remaining property data
-                    let aligned_len = (len + 3) & !3;
-                    *current = (*current as *const u8).offset(aligned_len as isize) as *const u32;
-                }
-            }
+    /// The whole flattened blob, header included.
+    pub fn blob(&self) -> &'static [u8] {
+        unsafe {
+            let totalsize = read_be(&(*self.header).totalsize) as usize;
+            slice::from_raw_parts(self.header as *const u8, totalsize)
         }
-        
-        Ok(())
     }
     
     pub fn memory_regions(&self) -> &[Option<MemoryRegion>] {
         &self.memory_regions[..self.region_count]
     }
+    
+    /// Iterate over every node in the tree, depth first.
+    pub fn nodes(&self) -> NodeIter {
+        unsafe {
+            let header = &*self.header;
+            let base = self.header as *const u8;
+            NodeIter {
+                current: base.offset(read_be(&header.off_dt_struct) as isize) as *const u32,
+                end: base.offset(read_be(&header.totalsize) as isize),
+                strings: base.offset(read_be(&header.off_dt_strings) as isize),
+                depth: 0,
+                subtree: false,
+                open: [core::ptr::null(); MAX_DEPTH],
+                outer: [core::ptr::null(); MAX_BUS_DEPTH],
+                base_level: 0,
+            }
+        }
+    }
+    
+    /// Direct children of a node.
+    pub fn children(&self, parent: &DeviceNode) -> impl Iterator<Item = DeviceNode> {
+        let end = unsafe {
+            (self.header as *const u8).offset(read_be(&(*self.header).totalsize) as isize)
+        };
+        let mut open = [core::ptr::null(); MAX_DEPTH];
+        open[0] = parent.props;
+        NodeIter {
+            current: parent.props,
+            end,
+            strings: parent.strings,
+            depth: 1,
+            subtree: true,
+            open,
+            outer: parent.ancestors,
+            base_level: parent.level,
+        }
+        .filter(|node| node.depth == 1)
+    }
+    
+    /// Enabled nodes matching a compatible string.
+    pub fn find_compatible<'a>(&self, compatible: &'a str) -> impl Iterator<Item = DeviceNode> + 'a {
+        self.nodes()
+            .filter(move |node| node.is_compatible(compatible) && node.is_enabled())
+    }
+    
+    pub fn find_by_name(&self, name: &str) -> Option<DeviceNode> {
+        self.nodes().find(|node| node.name() == name)
+    }
+    
+    /// The node at an absolute path ("/soc/serial@7e201000"), or the one an
+    /// /aliases entry names ("serial0"). A component without a unit address
+    /// matches any unit of that name.
+    pub fn find_by_path(&self, path: &str) -> Option<DeviceNode> {
+        let path = match path.strip_prefix('/') {
+            Some(path) => path,
+            None => self.find_by_name("aliases")?.property_str(path)?.strip_prefix('/')?,
+        };
+        let mut components = path.split('/').filter(|component| !component.is_empty());
+        let mut nodes = self.nodes();
+        let root = nodes.next()?;
+        let Some(mut wanted) = components.next() else {
+            return Some(root);
+        };
+        let mut depth = 1;
+        for node in nodes {
+            // Past the end of the last match's subtree
+            if node.depth() < depth {
+                return None;
+            }
+            let name = node.name();
+            let matches = name == wanted || (!wanted.contains('@') && name.split('@').next() == Some(wanted));
+            if node.depth() == depth && matches {
+                match components.next() {
+                    Some(next) => wanted = next,
+                    None => return Some(node),
+                }
+                depth += 1;
+            }
+        }
+        None
+    }
+    
+    /// Value of `key=value` in /chosen/bootargs; a bare `key` gives "".
+    /// For code that runs before the heap; everything else asks bootparams.
+    pub fn bootarg(&self, key: &str) -> Option<&'static str> {
+        crate::bootparams::find(self.find_by_name("chosen")?.property_str("bootargs")?, key)
+    }
 }
 
 pub fn parse_device_tree(fdt_addr: *const u8) -> Option<DeviceTree> {
     let mut dt = DeviceTree::new(fdt_addr)?;
     dt.parse_memory().ok()?;
     Some(dt)
+}
+
+/// Replace the boot device tree for every later `device_tree()` call. The
+/// blob must be 4-byte aligned and is never freed.
+pub fn set_active_blob(blob: &'static [u8]) {
+    ACTIVE_FDT.store(blob.as_ptr() as usize, Ordering::Release);
+}
+
+/// Record the loader's x0 if it points at a device tree; it may instead be
+/// an ACPI RSDP, or nothing (QEMU loading an ELF). Only the low 4GB the
+/// boot page tables map is looked at.
+pub fn set_boot_arg(x0: u64) {
+    if x0 == 0 || x0 >= 4 << 30 || !x0.is_multiple_of(4) {
+        return;
+    }
+    let magic = unsafe { (crate::memory::paging::phys_to_virt(x0) as *const u32).read_volatile() };
+    if u32::from_be(magic) == FDT_MAGIC {
+        BOOT_FDT.store(x0, Ordering::Relaxed);
+    }
+}
+
+/// Physical address and size of the blob the loader passed, if any.
+pub fn boot_blob() -> Option<(u64, usize)> {
+    let phys = BOOT_FDT.load(Ordering::Relaxed);
+    if phys == 0 {
+        return None;
+    }
+    let header = unsafe { slice::from_raw_parts(crate::memory::paging::phys_to_virt(phys) as *const u8, FDT_HEADER_LEN) };
+    Some((phys, fdt_parser::total_size(header).ok()?))
+}
+
+/// Parse the device tree: the overlaid copy once one has been installed,
+/// else the one the loader passed in x0, else the board's usual location.
+pub fn device_tree() -> Option<DeviceTree> {
+    let phys = match ACTIVE_FDT.load(Ordering::Acquire) {
+        0 => match BOOT_FDT.load(Ordering::Relaxed) {
+            0 => crate::board::BUILD.fallback_fdt?,
+            phys => phys,
+        },
+        addr => return parse_device_tree(addr as *const u8),
+    };
+    parse_device_tree(crate::memory::paging::phys_to_virt(phys) as *const u8)
 }
\ No newline at end of file
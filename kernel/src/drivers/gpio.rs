@@ -0,0 +1,128 @@
+// GPIO controller abstraction and consumer lookup
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::devicetree::{read_cell, DeviceNode};
+
+// Flags cell of a "gpios" specifier
+pub const GPIO_ACTIVE_LOW: u32 = 1 << 0;
+
+/// A GPIO controller driver.
+pub trait GpioController: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn ngpio(&self) -> u32;
+    fn direction_input(&self, pin: u32) -> Result<(), &'static str>;
+    fn direction_output(&self, pin: u32, value: bool) -> Result<(), &'static str>;
+    fn get(&self, pin: u32) -> Result<bool, &'static str>;
+    fn set(&self, pin: u32, value: bool) -> Result<(), &'static str>;
+}
+
+struct GpioChip {
+    phandle: Option<u32>,
+    controller: Box<dyn GpioController>,
+}
+
+static GPIO_CHIPS: Mutex<Vec<GpioChip>> = Mutex::new(Vec::new());
+
+/// A single GPIO line as referenced by a consumer.
+///
+/// Values passed through a descriptor are logical: "true" means asserted,
+/// taking active-low wiring into account.
+#[derive(Copy, Clone, Debug)]
+pub struct GpioDesc {
+    chip: usize,
+    pin: u32,
+    active_low: bool,
+}
+
+impl GpioDesc {
+    pub fn direction_input(&self) -> Result<(), &'static str> {
+        with_chip(self.chip, |chip| chip.direction_input(self.pin))
+    }
+    
+    pub fn direction_output(&self, asserted: bool) -> Result<(), &'static str> {
+        with_chip(self.chip, |chip| chip.direction_output(self.pin, asserted != self.active_low))
+    }
+    
+    pub fn get(&self) -> Result<bool, &'static str> {
+        with_chip(self.chip, |chip| chip.get(self.pin)).map(|level| level != self.active_low)
+    }
+    
+    pub fn set(&self, asserted: bool) -> Result<(), &'static str> {
+        with_chip(self.chip, |chip| chip.set(self.pin, asserted != self.active_low))
+    }
+    
+    /// Drive the physical level, for consumers that take polarity from
+    /// elsewhere than the specifier flags.
+    pub fn direction_output_raw(&self, level: bool) -> Result<(), &'static str> {
+        with_chip(self.chip, |chip| chip.direction_output(self.pin, level))
+    }
+    
+    pub fn set_raw(&self, level: bool) -> Result<(), &'static str> {
+        with_chip(self.chip, |chip| chip.set(self.pin, level))
+    }
+    
+    pub fn pin(&self) -> u32 {
+        self.pin
+    }
+}
+
+fn with_chip<T>(
+    chip: usize,
+    f: impl FnOnce(&dyn GpioController) -> Result<T, &'static str>,
+) -> Result<T, &'static str> {
+    let chips = GPIO_CHIPS.lock();
+    let chip = chips.get(chip).ok_or("No such GPIO controller")?;
+    f(chip.controller.as_ref())
+}
+
+/// Register a controller; consumers find it through its device tree phandle.
+pub fn register_controller(phandle: Option<u32>, controller: Box<dyn GpioController>) -> usize {
+    crate::println!("GPIO: Registered controller {} ({} lines)", controller.name(), controller.ngpio());
+    let mut chips = GPIO_CHIPS.lock();
+    chips.push(GpioChip { phandle, controller });
+    chips.len() - 1
+}
+
+/// Line `pin` of the controller `register_controller` numbered `chip`, for
+/// consumers the device tree does not describe.
+pub fn gpio_line(chip: usize, pin: u32, active_low: bool) -> Result<GpioDesc, &'static str> {
+    let chips = GPIO_CHIPS.lock();
+    let controller = &chips.get(chip).ok_or("No such GPIO controller")?.controller;
+    if pin >= controller.ngpio() {
+        return Err("GPIO pin out of range");
+    }
+    Ok(GpioDesc { chip, pin, active_low })
+}
+
+/// Resolve the first entry of a consumer's "gpios" property.
+pub fn gpio_from_node(node: &DeviceNode) -> Result<GpioDesc, &'static str> {
+    gpio_from_property(node, "gpios", 0)
+}
+
+/// Resolve entry `index` of a named GPIO list such as "cs-gpios".
+///
+/// Expects the two-cell specifier <&controller pin flags> used by PL061
+/// and most SoC GPIO blocks.
+pub fn gpio_from_property(node: &DeviceNode, property: &str, index: usize) -> Result<GpioDesc, &'static str> {
+    let spec = node.property(property).ok_or("Node has no such GPIO property")?;
+    let phandle = read_cell(spec, index * 3).ok_or("Malformed GPIO specifier")?;
+    let pin = read_cell(spec, index * 3 + 1).ok_or("Malformed GPIO specifier")?;
+    let flags = read_cell(spec, index * 3 + 2).unwrap_or(0);
+    
+    let chips = GPIO_CHIPS.lock();
+    let chip = chips
+        .iter()
+        .position(|chip| chip.phandle == Some(phandle))
+        .ok_or("GPIO controller not registered")?;
+    if pin >= chips[chip].controller.ngpio() {
+        return Err("GPIO pin out of range");
+    }
+    
+    Ok(GpioDesc {
+        chip,
+        pin,
+        active_low: flags & GPIO_ACTIVE_LOW != 0,
+    })
+}
@@ -0,0 +1,271 @@
+// Simulated GPIO controller for the GPIO consumer tests
+//
+// Lines read back what the driver under test leaves on them, and an
+// emulated I2C EEPROM or SPI device can be wired to them to answer the
+// bit-banged protocol.
+
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use super::gpio::GpioController;
+
+// Lines of the mock GPIO controller
+pub(super) const MOCK_GPIO_LINES: usize = 8;
+
+// Where the emulated targets sit on the mock controller
+pub(super) const MOCK_SDA: usize = 0;
+pub(super) const MOCK_SCL: usize = 1;
+pub(super) const MOCK_SCK: usize = 0;
+pub(super) const MOCK_MOSI: usize = 1;
+pub(super) const MOCK_MISO: usize = 2;
+pub(super) const MOCK_CS: [usize; 2] = [3, 4];
+
+// Address of the emulated EEPROM
+pub(super) const MOCK_EEPROM_ADDR: u8 = 0x50;
+
+// Output level of each mock line, None while it is an input, and the
+// device wired to them
+pub(super) struct MockLines {
+    pub(super) output: [Option<bool>; MOCK_GPIO_LINES],
+    pub(super) target: MockTarget,
+}
+
+pub(super) enum MockTarget {
+    None,
+    Eeprom(MockEeprom),
+    SpiEcho(MockSpiEcho),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum EepromPhase {
+    Idle,
+    Address,
+    Pointer,
+    Store,
+    Send,
+}
+
+// A small I2C EEPROM: the first byte of a write sets the address pointer,
+// later ones are stored, and reads continue from the pointer
+pub(super) struct MockEeprom {
+    pub(super) memory: [u8; 16],
+    pointer: usize,
+    phase: EepromPhase,
+    // SCL pulses since the start condition or the last acknowledge
+    bit: u32,
+    shift: u8,
+    clocked: bool,
+    pull_sda: bool,
+    master_ack: bool,
+}
+
+impl MockEeprom {
+    pub(super) fn new(memory: [u8; 16]) -> Self {
+        MockEeprom {
+            memory,
+            pointer: 0,
+            phase: EepromPhase::Idle,
+            bit: 0,
+            shift: 0,
+            clocked: false,
+            pull_sda: false,
+            master_ack: false,
+        }
+    }
+    
+    // The controller moved SCL or SDA from `before` to `after` (scl, sda)
+    fn edge(&mut self, before: (bool, bool), after: (bool, bool)) {
+        let (scl, sda) = after;
+        if before.0 && scl && before.1 != sda {
+            // SDA falling with SCL high is a start, rising a stop
+            self.phase = if sda { EepromPhase::Idle } else { EepromPhase::Address };
+            self.bit = 0;
+            self.shift = 0;
+            self.clocked = false;
+            self.pull_sda = false;
+            return;
+        }
+        if self.phase == EepromPhase::Idle {
+            return;
+        }
+        if !before.0 && scl {
+            self.clocked = true;
+            if self.bit < 8 {
+                self.shift = (self.shift << 1) | sda as u8;
+            } else {
+                self.master_ack = !sda;
+            }
+        } else if before.0 && !scl && self.clocked {
+            self.clocked = false;
+            self.bit += 1;
+            match self.bit {
+                8 => self.byte_done(),
+                9 => self.ack_done(),
+                bit => {
+                    if self.phase == EepromPhase::Send {
+                        self.pull_sda = self.memory[self.pointer] & (0x80 >> bit) == 0;
+                    }
+                }
+            }
+        }
+    }
+    
+    // Eight bits in: acknowledge what was received, or let the master
+    // acknowledge what was sent
+    fn byte_done(&mut self) {
+        let len = self.memory.len();
+        match self.phase {
+            EepromPhase::Address if self.shift >> 1 == MOCK_EEPROM_ADDR => self.pull_sda = true,
+            EepromPhase::Address => self.phase = EepromPhase::Idle,
+            EepromPhase::Pointer => {
+                self.pointer = self.shift as usize % len;
+                self.pull_sda = true;
+            }
+            EepromPhase::Store => {
+                self.memory[self.pointer] = self.shift;
+                self.pointer = (self.pointer + 1) % len;
+                self.pull_sda = true;
+            }
+            EepromPhase::Send => self.pull_sda = false,
+            EepromPhase::Idle => {}
+        }
+    }
+    
+    fn ack_done(&mut self) {
+        self.phase = match self.phase {
+            EepromPhase::Address if self.shift & 1 != 0 => EepromPhase::Send,
+            EepromPhase::Address => EepromPhase::Pointer,
+            EepromPhase::Pointer | EepromPhase::Store => EepromPhase::Store,
+            EepromPhase::Send => {
+                self.pointer = (self.pointer + 1) % self.memory.len();
+                if self.master_ack { EepromPhase::Send } else { EepromPhase::Idle }
+            }
+            EepromPhase::Idle => EepromPhase::Idle,
+        };
+        self.bit = 0;
+        self.shift = 0;
+        self.pull_sda = self.phase == EepromPhase::Send && self.memory[self.pointer] & 0x80 == 0;
+    }
+}
+
+// An SPI device in mode 0 that answers each byte with the one it received
+// before, across messages. It is selected while exactly one chip select is
+// at its active level: (line, active high).
+pub(super) struct MockSpiEcho {
+    selects: [(usize, bool); 2],
+    shift: u8,
+    bits: u32,
+    sending: u8,
+    last: u8,
+}
+
+impl MockSpiEcho {
+    pub(super) fn new(selects: [(usize, bool); 2]) -> Self {
+        MockSpiEcho { selects, shift: 0, bits: 0, sending: 0, last: 0 }
+    }
+}
+
+impl MockLines {
+    // Level the controller leaves a line at; inputs are pulled up
+    fn driven(&self, pin: usize) -> bool {
+        self.output[pin].unwrap_or(true)
+    }
+    
+    fn selected(&self, echo: &MockSpiEcho) -> bool {
+        echo.selects.iter().filter(|&&(pin, active)| self.driven(pin) == active).count() == 1
+    }
+    
+    fn level(&self, pin: usize) -> bool {
+        match &self.target {
+            MockTarget::Eeprom(eeprom) if pin == MOCK_SDA => self.driven(pin) && !eeprom.pull_sda,
+            MockTarget::SpiEcho(echo) if pin == MOCK_MISO => {
+                !self.selected(echo) || echo.bits == 0 || echo.sending & (0x80 >> (echo.bits - 1)) != 0
+            }
+            _ => self.driven(pin),
+        }
+    }
+    
+    fn drive(&mut self, pin: usize, output: Option<bool>) {
+        let i2c_before = (self.driven(MOCK_SCL), self.driven(MOCK_SDA));
+        let sck_before = self.driven(MOCK_SCK);
+        let selected_before = match &self.target {
+            MockTarget::SpiEcho(echo) => self.selected(echo),
+            _ => false,
+        };
+        self.output[pin] = output;
+        let i2c_after = (self.driven(MOCK_SCL), self.driven(MOCK_SDA));
+        let sck_after = self.driven(MOCK_SCK);
+        let mosi = self.driven(MOCK_MOSI);
+        let selected = match &self.target {
+            MockTarget::SpiEcho(echo) => self.selected(echo),
+            _ => false,
+        };
+        match &mut self.target {
+            MockTarget::Eeprom(eeprom) => eeprom.edge(i2c_before, i2c_after),
+            MockTarget::SpiEcho(echo) => {
+                if selected != selected_before {
+                    echo.bits = 0;
+                } else if selected && !sck_before && sck_after {
+                    if echo.bits == 8 {
+                        echo.bits = 0;
+                    }
+                    if echo.bits == 0 {
+                        echo.sending = echo.last;
+                    }
+                    echo.shift = (echo.shift << 1) | mosi as u8;
+                    echo.bits += 1;
+                    if echo.bits == 8 {
+                        echo.last = echo.shift;
+                    }
+                }
+            }
+            MockTarget::None => {}
+        }
+    }
+}
+
+// GPIO controller for driver tests, with no hardware behind it: an output
+// reads back what it drives, an input reads high as if pulled up, unless
+// the emulated target pulls it
+pub(super) struct MockGpio(pub(super) Arc<Mutex<MockLines>>);
+
+impl MockGpio {
+    pub(super) fn new(target: MockTarget) -> Self {
+        MockGpio(Arc::new(Mutex::new(MockLines { output: [None; MOCK_GPIO_LINES], target })))
+    }
+}
+
+impl GpioController for MockGpio {
+    fn name(&self) -> &'static str {
+        "mock-gpio"
+    }
+    
+    fn ngpio(&self) -> u32 {
+        MOCK_GPIO_LINES as u32
+    }
+    
+    fn direction_input(&self, pin: u32) -> Result<(), &'static str> {
+        self.0.lock().drive(pin as usize, None);
+        Ok(())
+    }
+    
+    fn direction_output(&self, pin: u32, value: bool) -> Result<(), &'static str> {
+        self.0.lock().drive(pin as usize, Some(value));
+        Ok(())
+    }
+    
+    fn get(&self, pin: u32) -> Result<bool, &'static str> {
+        Ok(self.0.lock().level(pin as usize))
+    }
+    
+    fn set(&self, pin: u32, value: bool) -> Result<(), &'static str> {
+        let mut lines = self.0.lock();
+        match lines.output[pin as usize] {
+            Some(_) => {
+                lines.drive(pin as usize, Some(value));
+                Ok(())
+            }
+            None => Err("Line is an input"),
+        }
+    }
+}
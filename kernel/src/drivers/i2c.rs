@@ -0,0 +1,197 @@
+// I2C bus framework: adapters, device-tree client instantiation, /dev/i2c-N
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::devfs::{self, Device};
+use crate::devicetree::{DeviceNode, DeviceTree};
+
+// ioctl: select the target address for /dev/i2c-N reads and writes
+pub const I2C_SLAVE: u32 = 0x0703;
+
+// Largest single passthrough transfer
+const I2C_DEV_MAX_TRANSFER: usize = 8192;
+
+/// One segment of a combined I2C transaction.
+pub enum I2cMsg<'a> {
+    Write { addr: u16, data: &'a [u8] },
+    Read { addr: u16, buf: &'a mut [u8] },
+}
+
+/// An I2C bus controller driver.
+pub trait I2cAdapter: Send + Sync {
+    fn name(&self) -> &'static str;
+    
+    /// Run the messages as one transaction, with a repeated start between
+    /// segments and a stop at the end.
+    fn transfer(&self, msgs: &mut [I2cMsg]) -> Result<(), &'static str>;
+}
+
+pub struct I2cBus {
+    number: usize,
+    adapter: Box<dyn I2cAdapter>,
+    // Transactions from different clients must not interleave
+    lock: Mutex<()>,
+}
+
+impl I2cBus {
+    pub fn number(&self) -> usize {
+        self.number
+    }
+    
+    pub fn transfer(&self, msgs: &mut [I2cMsg]) -> Result<(), &'static str> {
+        let _guard = self.lock.lock();
+        self.adapter.transfer(msgs)
+    }
+}
+
+/// A device at a fixed address on a bus.
+pub struct I2cClient {
+    bus: Arc<I2cBus>,
+    addr: u16,
+}
+
+impl I2cClient {
+    pub fn new(bus: Arc<I2cBus>, addr: u16) -> Self {
+        Self { bus, addr }
+    }
+    
+    pub fn addr(&self) -> u16 {
+        self.addr
+    }
+    
+    pub fn bus(&self) -> &Arc<I2cBus> {
+        &self.bus
+    }
+    
+    pub fn write(&self, data: &[u8]) -> Result<(), &'static str> {
+        self.bus.transfer(&mut [I2cMsg::Write { addr: self.addr, data }])
+    }
+    
+    pub fn read(&self, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.bus.transfer(&mut [I2cMsg::Read { addr: self.addr, buf }])
+    }
+    
+    /// Write (typically a register index) then read without releasing the bus.
+    pub fn write_read(&self, data: &[u8], buf: &mut [u8]) -> Result<(), &'static str> {
+        self.bus.transfer(&mut [
+            I2cMsg::Write { addr: self.addr, data },
+            I2cMsg::Read { addr: self.addr, buf },
+        ])
+    }
+}
+
+/// A client driver matched against child nodes of an I2C controller.
+pub struct I2cDriver {
+    pub compatible: &'static str,
+    pub probe: fn(I2cClient, &DeviceNode) -> Result<(), &'static str>,
+}
+
+static I2C_BUSES: Mutex<Vec<Arc<I2cBus>>> = Mutex::new(Vec::new());
+static I2C_DRIVERS: Mutex<Vec<I2cDriver>> = Mutex::new(Vec::new());
+
+/// Register a client driver; must happen before controllers are probed.
+pub fn register_driver(driver: I2cDriver) {
+    I2C_DRIVERS.lock().push(driver);
+}
+
+/// Register a bus controller and instantiate the devices below its node.
+pub fn register_adapter(
+    dt: &DeviceTree,
+    node: Option<&DeviceNode>,
+    adapter: Box<dyn I2cAdapter>,
+) -> Arc<I2cBus> {
+    let bus = {
+        let mut buses = I2C_BUSES.lock();
+        let bus = Arc::new(I2cBus {
+            number: buses.len(),
+            adapter,
+            lock: Mutex::new(()),
+        });
+        buses.push(bus.clone());
+        bus
+    };
+    crate::println!("I2C: Bus {} registered ({})", bus.number, bus.adapter.name());
+    
+    let dev = I2cDev {
+        bus: bus.clone(),
+        addr: Mutex::new(None),
+    };
+    let _ = devfs::register(&format!("i2c-{}", bus.number), Arc::new(dev));
+    
+    if let Some(node) = node {
+        instantiate_clients(dt, node, &bus);
+    }
+    bus
+}
+
+fn instantiate_clients(dt: &DeviceTree, node: &DeviceNode, bus: &Arc<I2cBus>) {
+    for child in dt.children(node) {
+        if !child.is_enabled() {
+            continue;
+        }
+        let addr = match child.property_u32("reg") {
+            Some(addr) if addr < 0x400 => addr as u16,
+            _ => {
+                crate::println!("I2C: Skipping {}: missing or invalid reg", child.name());
+                continue;
+            }
+        };
+        
+        let drivers = I2C_DRIVERS.lock();
+        match drivers.iter().find(|driver| child.is_compatible(driver.compatible)) {
+            Some(driver) => {
+                let client = I2cClient::new(bus.clone(), addr);
+                match (driver.probe)(client, &child) {
+                    Ok(()) => crate::println!("I2C: {}-{:04x} bound to {}",
+                                              bus.number, addr, driver.compatible),
+                    Err(e) => crate::println!("I2C: {}-{:04x} probe failed: {}",
+                                              bus.number, addr, e),
+                }
+            }
+            None => crate::println!("I2C: {}-{:04x} ({}) has no driver",
+                                    bus.number, addr, child.name()),
+        }
+    }
+}
+
+pub fn bus(number: usize) -> Option<Arc<I2cBus>> {
+    I2C_BUSES.lock().get(number).cloned()
+}
+
+// Userspace passthrough: select an address with I2C_SLAVE, then read/write
+struct I2cDev {
+    bus: Arc<I2cBus>,
+    addr: Mutex<Option<u16>>,
+}
+
+impl Device for I2cDev {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let addr = (*self.addr.lock()).ok_or("No target address set")?;
+        let len = buf.len().min(I2C_DEV_MAX_TRANSFER);
+        self.bus.transfer(&mut [I2cMsg::Read { addr, buf: &mut buf[..len] }])?;
+        Ok(len)
+    }
+    
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+        let addr = (*self.addr.lock()).ok_or("No target address set")?;
+        let len = buf.len().min(I2C_DEV_MAX_TRANSFER);
+        self.bus.transfer(&mut [I2cMsg::Write { addr, data: &buf[..len] }])?;
+        Ok(len)
+    }
+    
+    fn ioctl(&self, cmd: u32, arg: usize) -> Result<usize, &'static str> {
+        match cmd {
+            I2C_SLAVE => {
+                if arg >= 0x400 {
+                    return Err("Invalid I2C address");
+                }
+                *self.addr.lock() = Some(arg as u16);
+                Ok(0)
+            }
+            _ => Err("Operation not supported"),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// GPIO-driven buttons ("gpio-keys" device tree binding)
+
+use alloc::format;
+use alloc::sync::Arc;
+use crate::devfs::{self, Device};
+use crate::devicetree::DeviceTree;
+use super::gpio::{gpio_from_node, GpioDesc};
+
+pub struct GpioKey {
+    label: &'static str,
+    // Input event code from "linux,code" (e.g. 116 = KEY_POWER)
+    code: u32,
+    gpio: GpioDesc,
+}
+
+impl GpioKey {
+    pub fn is_pressed(&self) -> Result<bool, &'static str> {
+        self.gpio.get()
+    }
+}
+
+// /dev/keys/<label>: read "0\n"/"1\n" for released/pressed
+impl Device for GpioKey {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if buf.len() < 2 {
+            return Err("Buffer too small");
+        }
+        buf[0] = if self.is_pressed()? { b'1' } else { b'0' };
+        buf[1] = b'\n';
+        Ok(2)
+    }
+    
+    fn write(&self, _buf: &[u8]) -> Result<usize, &'static str> {
+        Err("Keys are read-only")
+    }
+}
+
+/// Instantiate a key for every child of each "gpio-keys" node.
+pub fn probe(dt: &DeviceTree) -> usize {
+    let mut count = 0;
+    for parent in dt.find_compatible("gpio-keys") {
+        for child in dt.children(&parent) {
+            let gpio = match gpio_from_node(&child) {
+                Ok(gpio) => gpio,
+                Err(e) => {
+                    crate::println!("Keys: Skipping {}: {}", child.name(), e);
+                    continue;
+                }
+            };
+            
+            if let Err(e) = gpio.direction_input() {
+                crate::println!("Keys: Failed to configure {}: {}", child.name(), e);
+                continue;
+            }
+            
+            let key = GpioKey {
+                label: child.property_str("label").unwrap_or(child.name()),
+                code: child.property_u32("linux,code").unwrap_or(0),
+                gpio,
+            };
+            
+            crate::println!("Keys: {} (code {}) on GPIO {}", key.label, key.code, gpio.pin());
+            let path = format!("keys/{}", key.label);
+            if devfs::register(&path, Arc::new(key)).is_ok() {
+                count += 1;
+            }
+        }
+    }
+    count
+}
@@ -0,0 +1,172 @@
+// GPIO-driven LEDs ("gpio-leds" device tree binding)
+
+use alloc::format;
+use alloc::sync::Arc;
+use crate::devfs::{self, Device};
+use crate::devicetree::DeviceTree;
+use crate::ktest::kernel_test;
+use super::gpio::{gpio_from_node, GpioDesc};
+
+pub struct GpioLed {
+    label: &'static str,
+    gpio: GpioDesc,
+}
+
+impl GpioLed {
+    pub fn new(label: &'static str, gpio: GpioDesc) -> Self {
+        Self { label, gpio }
+    }
+    
+    pub fn set(&self, on: bool) -> Result<(), &'static str> {
+        self.gpio.set(on)
+    }
+    
+    pub fn get(&self) -> Result<bool, &'static str> {
+        self.gpio.get()
+    }
+}
+
+// /dev/leds/<label>: read "0\n"/"1\n", write "0"/"1" to switch
+impl Device for GpioLed {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if buf.len() < 2 {
+            return Err("Buffer too small");
+        }
+        buf[0] = if self.get()? { b'1' } else { b'0' };
+        buf[1] = b'\n';
+        Ok(2)
+    }
+    
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+        match buf.first() {
+            Some(b'0') => self.set(false)?,
+            Some(b'1') => self.set(true)?,
+            _ => return Err("Expected '0' or '1'"),
+        }
+        Ok(buf.len())
+    }
+}
+
+/// Instantiate an LED for every child of each "gpio-leds" node.
+pub fn probe(dt: &DeviceTree) -> usize {
+    let mut count = 0;
+    for parent in dt.find_compatible("gpio-leds") {
+        for child in dt.children(&parent) {
+            let gpio = match gpio_from_node(&child) {
+                Ok(gpio) => gpio,
+                Err(e) => {
+                    crate::println!("LEDs: Skipping {}: {}", child.name(), e);
+                    continue;
+                }
+            };
+            
+            let led = GpioLed::new(child.property_str("label").unwrap_or(child.name()), gpio);
+            
+            // "keep" leaves the bootloader's state alone
+            let result = match child.property_str("default-state") {
+                Some("on") => gpio.direction_output(true),
+                Some("keep") => Ok(()),
+                _ => gpio.direction_output(false),
+            };
+            if let Err(e) = result {
+                crate::println!("LEDs: Failed to configure {}: {}", led.label, e);
+                continue;
+            }
+            
+            let path = format!("leds/{}", led.label);
+            if devfs::register(&path, Arc::new(led)).is_ok() {
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+#[kernel_test]
+fn test_gpio_leds() {
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use crate::drivers::gpio::{gpio_line, register_controller};
+    use crate::drivers::leds::GpioLed;
+    use crate::process::capability::{self, Capability};
+    use crate::process::scheduler::current_thread_id;
+    use crate::syscall::{EFAULT, ENOTTY, EPERM, SYS_DEVICE_IOCTL};
+    use crate::{devfs, vfs};
+    use super::gpio_mock::{MockGpio, MockTarget, MOCK_GPIO_LINES};
+    use crate::interrupts::ExceptionContext;
+    
+    crate::println!("LED Test: Testing GPIO LEDs through /dev...");
+    
+    let mock = MockGpio::new(MockTarget::None);
+    let lines = mock.0.clone();
+    let chip = register_controller(None, Box::new(mock));
+    if gpio_line(chip, MOCK_GPIO_LINES as u32, false).is_ok() {
+        crate::println!("LED Test: ✗ GPIO line past the controller's last accepted");
+    }
+    
+    // Wired active low: on drives the line low
+    let gpio = match gpio_line(chip, 3, true) {
+        Ok(gpio) => gpio,
+        Err(e) => {
+            crate::println!("LED Test: ✗ Mock GPIO line: {}", e);
+            return;
+        }
+    };
+    let registered = gpio.direction_output(false)
+        .and_then(|_| devfs::register("leds/selftest", Arc::new(GpioLed::new("selftest", gpio))));
+    if let Err(e) = registered {
+        crate::println!("LED Test: ✗ Could not register the test LED: {}", e);
+        return;
+    }
+    
+    let off = lines.lock().output[3];
+    let written = vfs::write("/dev/leds/selftest", 0, b"1\n");
+    let on = lines.lock().output[3];
+    let state = vfs::read_all("/dev/leds/selftest");
+    if off == Some(true) && written == Ok(2) && on == Some(false) && state.as_deref() == Ok(b"1\n".as_slice()) {
+        crate::println!("LED Test: ✓ Writing /dev/leds/selftest drove the line low; reads back 1");
+    } else {
+        crate::println!("LED Test: ✗ LED line {:?} -> {:?}, write {:?}, read {:?}", off, on, written, state);
+    }
+    
+    let listed = vfs::read_dir("/dev/leds").is_ok_and(|entries| {
+        entries.iter().any(|entry| entry.name == "selftest" && entry.kind == vfs::NodeKind::File)
+    });
+    let dev_listed = vfs::read_dir("/").is_ok_and(|entries| entries.iter().any(|entry| entry.name == "dev"));
+    let rejected = vfs::write("/dev/leds/selftest", 0, b"on").is_err()
+        && devfs::ioctl("leds/selftest", 0, 0) == Err("Operation not supported");
+    if listed && dev_listed && rejected {
+        crate::println!("LED Test: ✓ /dev lists the LED; bad values and ioctls refused");
+    } else {
+        crate::println!("LED Test: ✗ Listed {} (/dev {}), bad input refused {}", listed, dev_listed, rejected);
+    }
+    
+    // From EL0 only with the Device capability; the path here is kernel
+    // memory, so a caller let through faults on it
+    let path = "/dev/leds/selftest";
+    let ioctl = |spsr_el1| {
+        let mut ctx = ExceptionContext { x0: path.as_ptr() as u64, x1: path.len() as u64, spsr_el1, ..Default::default() };
+        crate::syscall::dispatch(&mut ctx, SYS_DEVICE_IOCTL);
+        ctx.x0 as i64
+    };
+    let me = current_thread_id();
+    let kernel = ioctl(0x3c5);
+    let denied = ioctl(0);
+    let granted = capability::grant(me, me, Capability::Device).map(|()| ioctl(0));
+    let _ = capability::revoke(me, me, Capability::Device);
+    if kernel == ENOTTY && denied == EPERM && granted == Ok(EFAULT) {
+        crate::println!("LED Test: ✓ device_ioctl from EL0 refused without the Device capability");
+    } else {
+        crate::println!("LED Test: ✗ device_ioctl gave {} from EL1, {} from EL0, {:?} with the capability",
+                       kernel, denied, granted);
+    }
+    
+    let removed = devfs::unregister("leds/selftest").is_ok();
+    if removed && vfs::stat("/dev/leds/selftest").is_err() && devfs::unregister("leds/selftest").is_err() {
+        crate::println!("LED Test: ✓ Unregistered LED gone from /dev");
+    } else {
+        crate::println!("LED Test: ✗ LED still present after unregister");
+    }
+    
+    crate::println!("LED Test: GPIO LED test completed");
+}
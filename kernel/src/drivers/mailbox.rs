@@ -0,0 +1,262 @@
+// BCM2835 mailbox: the Raspberry Pi firmware's property interface
+//
+// The ARM asks the VideoCore firmware for things (board revision, clock
+// rates, a framebuffer) by writing the bus address of a tagged message to
+// the property channel and waiting for the answer in the same buffer. The
+// VideoCore does not snoop the ARM caches, so the buffer is cleaned before
+// the request and invalidated before the answer is read.
+
+use core::arch::asm;
+use core::ptr::{read_volatile, write_volatile};
+use spin::Mutex;
+use crate::devicetree::DeviceTree;
+use crate::interrupts::{counter_frequency, counter_ticks};
+use crate::memory::frame_allocator::{allocate_frame, PAGE_SIZE};
+use crate::memory::paging::{phys_to_virt, virt_to_phys};
+
+// Register offsets: mailbox 0 is read, mailbox 1 written
+const MAIL0_READ: usize = 0x00;
+const MAIL0_STATUS: usize = 0x18;
+const MAIL1_WRITE: usize = 0x20;
+const MAIL1_STATUS: usize = 0x38;
+
+const STATUS_FULL: u32 = 1 << 31;
+const STATUS_EMPTY: u32 = 1 << 30;
+
+// ARM to VideoCore property tags
+const CHANNEL_PROPERTY: u32 = 8;
+const REQUEST: u32 = 0;
+const RESPONSE_OK: u32 = 0x8000_0000;
+// Set in a tag's length word once the firmware has answered it
+const TAG_RESPONSE: u32 = 1 << 31;
+
+const TAG_BOARD_REVISION: u32 = 0x0001_0002;
+const TAG_GET_CLOCK_RATE: u32 = 0x0003_0002;
+const TAG_SET_CLOCK_RATE: u32 = 0x0003_8002;
+const TAG_ALLOCATE_BUFFER: u32 = 0x0004_0001;
+const TAG_GET_PITCH: u32 = 0x0004_0008;
+const TAG_SET_PHYSICAL_SIZE: u32 = 0x0004_8003;
+const TAG_SET_VIRTUAL_SIZE: u32 = 0x0004_8004;
+const TAG_SET_DEPTH: u32 = 0x0004_8005;
+const TAG_SET_PIXEL_ORDER: u32 = 0x0004_8006;
+
+// The VideoCore sees ARM RAM from this bus address, uncached (the
+// "dma-ranges" of the BCM2711 /soc node)
+const DMA_BUS_OFFSET: u64 = 0xC000_0000;
+// Framebuffer addresses come back as bus addresses in any alias
+const BUS_ALIAS_MASK: u32 = 0x3FFF_FFFF;
+
+const TIMEOUT_US: u64 = 100_000;
+const MESSAGE_WORDS: usize = 64;
+
+/// Firmware clock IDs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Clock {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+    Emmc2 = 12,
+}
+
+impl Clock {
+    pub const ALL: [Clock; 5] = [Clock::Emmc, Clock::Uart, Clock::Arm, Clock::Core, Clock::Emmc2];
+    
+    pub fn name(self) -> &'static str {
+        match self {
+            Clock::Emmc => "emmc",
+            Clock::Uart => "uart",
+            Clock::Arm => "arm",
+            Clock::Core => "core",
+            Clock::Emmc2 => "emmc2",
+        }
+    }
+}
+
+/// A framebuffer the firmware allocated.
+#[derive(Copy, Clone, Debug)]
+pub struct Framebuffer {
+    pub phys: u64,
+    pub size: usize,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per line
+    pub pitch: u32,
+    pub depth: u32,
+}
+
+struct Mailbox {
+    base: usize,
+    // Kernel virtual address of the message page
+    buffer: usize,
+}
+
+static MAILBOX: Mutex<Option<Mailbox>> = Mutex::new(None);
+
+// Clean or clean-and-invalidate every data cache line of the buffer
+fn flush_buffer(virt: usize, len: usize) {
+    let ctr: u64;
+    unsafe {
+        asm!("mrs {}, ctr_el0", out(reg) ctr);
+    }
+    let line = 4usize << ((ctr >> 16) & 0xF);
+    let mut addr = virt & !(line - 1);
+    unsafe {
+        while addr < virt + len {
+            asm!("dc civac, {}", in(reg) addr);
+            addr += line;
+        }
+        asm!("dsb sy");
+    }
+}
+
+impl Mailbox {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+    
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+    
+    fn wait_for(&self, mut done: impl FnMut() -> bool) -> Result<(), &'static str> {
+        let deadline = counter_ticks() + counter_frequency() * TIMEOUT_US / 1_000_000;
+        while !done() {
+            if counter_ticks() > deadline {
+                return Err("Mailbox: Timeout");
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+    
+    // Send `tags` (each: id, value size, 0, values) and copy the answered
+    // message back into them
+    fn property(&self, tags: &mut [u32]) -> Result<(), &'static str> {
+        let words = tags.len() + 3;
+        if words > MESSAGE_WORDS {
+            return Err("Mailbox: Message too long");
+        }
+        let message = unsafe { core::slice::from_raw_parts_mut(self.buffer as *mut u32, words) };
+        message[0] = (words * 4) as u32;
+        message[1] = REQUEST;
+        message[2..words - 1].copy_from_slice(tags);
+        message[words - 1] = 0;
+        flush_buffer(self.buffer, words * 4);
+        
+        let bus = (virt_to_phys(self.buffer as u64) | DMA_BUS_OFFSET) as u32;
+        self.wait_for(|| self.read(MAIL1_STATUS) & STATUS_FULL == 0)?;
+        self.write(MAIL1_WRITE, bus | CHANNEL_PROPERTY);
+        loop {
+            self.wait_for(|| self.read(MAIL0_STATUS) & STATUS_EMPTY == 0)?;
+            // Answers for other channels are not ours
+            if self.read(MAIL0_READ) == bus | CHANNEL_PROPERTY {
+                break;
+            }
+        }
+        flush_buffer(self.buffer, words * 4);
+        
+        let message = unsafe { core::slice::from_raw_parts(self.buffer as *const u32, words) };
+        if message[1] != RESPONSE_OK {
+            return Err("Mailbox: Firmware rejected the request");
+        }
+        tags.copy_from_slice(&message[2..words - 1]);
+        Ok(())
+    }
+}
+
+// Run one property request on the probed mailbox
+fn property(tags: &mut [u32]) -> Result<(), &'static str> {
+    MAILBOX.lock().as_ref().ok_or("Mailbox: Not present")?.property(tags)
+}
+
+// A request with one tag whose values fill `values`, answered in place
+fn single(tag: u32, values: &mut [u32]) -> Result<(), &'static str> {
+    let mut tags = [0u32; 8];
+    let len = values.len();
+    if len + 3 > tags.len() {
+        return Err("Mailbox: Message too long");
+    }
+    tags[0] = tag;
+    tags[1] = (len * 4) as u32;
+    tags[3..3 + len].copy_from_slice(values);
+    property(&mut tags[..3 + len])?;
+    if tags[2] & TAG_RESPONSE == 0 {
+        return Err("Mailbox: Tag not answered");
+    }
+    values.copy_from_slice(&tags[3..3 + len]);
+    Ok(())
+}
+
+/// Board revision code (see the Raspberry Pi documentation for the fields).
+pub fn board_revision() -> Result<u32, &'static str> {
+    let mut values = [0];
+    single(TAG_BOARD_REVISION, &mut values)?;
+    Ok(values[0])
+}
+
+/// Current rate of `clock` in Hz.
+pub fn clock_rate(clock: Clock) -> Result<u32, &'static str> {
+    let mut values = [clock as u32, 0];
+    single(TAG_GET_CLOCK_RATE, &mut values)?;
+    Ok(values[1])
+}
+
+/// Set `clock` to `hz` and return the rate the firmware chose.
+pub fn set_clock_rate(clock: Clock, hz: u32) -> Result<u32, &'static str> {
+    // The third value: do not also change the turbo setting
+    let mut values = [clock as u32, hz, 1];
+    single(TAG_SET_CLOCK_RATE, &mut values)?;
+    match values[1] {
+        0 => Err("Mailbox: Clock rate refused"),
+        rate => Ok(rate),
+    }
+}
+
+/// Have the firmware allocate a `width` x `height` framebuffer of `depth`
+/// bits per pixel, RGB order.
+pub fn allocate_framebuffer(width: u32, height: u32, depth: u32) -> Result<Framebuffer, &'static str> {
+    let mut tags = [
+        TAG_SET_PHYSICAL_SIZE, 8, 0, width, height,
+        TAG_SET_VIRTUAL_SIZE, 8, 0, width, height,
+        TAG_SET_DEPTH, 4, 0, depth,
+        TAG_SET_PIXEL_ORDER, 4, 0, 1,
+        TAG_ALLOCATE_BUFFER, 8, 0, PAGE_SIZE as u32, 0,
+        TAG_GET_PITCH, 4, 0, 0,
+    ];
+    property(&mut tags)?;
+    let (bus, size, pitch) = (tags[21], tags[22], tags[26]);
+    if bus == 0 || size == 0 {
+        return Err("Mailbox: No framebuffer");
+    }
+    Ok(Framebuffer {
+        phys: (bus & BUS_ALIAS_MASK) as u64,
+        size: size as usize,
+        width: tags[3],
+        height: tags[4],
+        pitch,
+        depth: tags[13],
+    })
+}
+
+/// Find the firmware mailbox. Returns 1 if found.
+pub fn probe(dt: &DeviceTree) -> usize {
+    let Some((phys, _)) = dt.find_compatible("brcm,bcm2835-mbox").next().and_then(|node| node.reg(0)) else {
+        return 0;
+    };
+    let Some(page) = allocate_frame() else {
+        crate::println!("Mailbox: Out of memory");
+        return 0;
+    };
+    *MAILBOX.lock() = Some(Mailbox { base: phys_to_virt(phys) as usize, buffer: page.as_ptr() as usize });
+    match board_revision() {
+        Ok(revision) => crate::println!("Mailbox: Firmware at 0x{:x}, board revision 0x{:x}", phys, revision),
+        Err(e) => crate::println!("Mailbox: Firmware at 0x{:x} not answering: {}", phys, e),
+    }
+    for clock in Clock::ALL {
+        if let Ok(hz) = clock_rate(clock) {
+            crate::println!("Mailbox: {} clock {} MHz", clock.name(), hz / 1_000_000);
+        }
+    }
+    1
+}
@@ -0,0 +1,79 @@
+pub mod gpio;
+mod gpio_mock;
+pub mod pl061;
+pub mod pl031;
+pub mod sbsa_gwdt;
+pub mod leds;
+pub mod keys;
+pub mod i2c;
+pub mod i2c_gpio;
+pub mod spi;
+pub mod spi_gpio;
+pub mod pci;
+pub mod sdhci;
+pub mod usb;
+pub mod virtio;
+pub mod pm;
+pub mod mailbox;
+
+use crate::devicetree::device_tree;
+
+// A driver that faults while probing is reported and skipped rather than
+// taking the kernel down; it counts as having found nothing
+fn probe(driver: &'static str, probe: impl FnOnce() -> usize) -> usize {
+    crate::oops::guard(driver, probe).unwrap_or_else(|e| {
+        crate::println!("Drivers: {} probe failed: {}", driver, e);
+        0
+    })
+}
+
+/// Probe device-tree described hardware and register drivers
+pub fn init() {
+    crate::println!("Drivers: Probing devices...");
+    pm::init();
+    
+    let dt = match device_tree() {
+        Some(dt) => dt,
+        None => {
+            crate::println!("Drivers: Warning - No device tree, skipping device probe");
+            return;
+        }
+    };
+    
+    // Raspberry Pi firmware, which other drivers may ask for clock rates
+    probe("mailbox", || mailbox::probe(&dt));
+    
+    // GPIO controllers must come before their consumers
+    let gpio_count = probe("pl061", || pl061::probe(&dt));
+    let led_count = probe("leds", || leds::probe(&dt));
+    let key_count = probe("keys", || keys::probe(&dt));
+    crate::println!("Drivers: {} GPIO controllers, {} LEDs, {} keys",
+                   gpio_count, led_count, key_count);
+    
+    // The RTC sets the wall clock for everything probed after it
+    if probe("pl031", || pl031::probe(&dt)) == 0 {
+        crate::println!("Drivers: No RTC, wall-clock time unavailable");
+    }
+    
+    // A hardware watchdog starts counting as soon as it registers
+    probe("sbsa_gwdt", || sbsa_gwdt::probe(&dt));
+    
+    // Bus controllers instantiate their child devices as they register
+    let i2c_count = probe("i2c_gpio", || i2c_gpio::probe(&dt));
+    let spi_count = probe("spi_gpio", || spi_gpio::probe(&dt));
+    crate::println!("Drivers: {} I2C buses, {} SPI buses", i2c_count, spi_count);
+    
+    // Storage and network: devices register with the block and net layers
+    let pci_count = probe("pci", || pci::probe(&dt));
+    let sd_count = probe("sdhci", || sdhci::probe(&dt));
+    let virtio_count = probe("virtio", || virtio::probe(&dt));
+    crate::println!("Drivers: {} PCI functions, {} SD cards, {} virtio devices",
+                   pci_count, sd_count, virtio_count);
+    
+    // USB: devices are enumerated and bound to class drivers per port
+    usb::init();
+    let usb_count = probe("xhci", || usb::xhci::probe(&dt));
+    crate::println!("Drivers: {} USB host controllers", usb_count);
+    
+    crate::println!("Drivers: Device probe complete");
+}
@@ -0,0 +1,227 @@
+// Minimal PCIe ECAM host support ("pci-host-ecam-generic" on QEMU virt)
+//
+// Enumerates bus 0 and assigns memory BARs from the host bridge's 32-bit
+// window, since nothing runs before the kernel to do it on QEMU -kernel.
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use spin::Mutex;
+use crate::devicetree::{read_cell, DeviceTree};
+use crate::memory::paging::phys_to_virt;
+
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_COMMAND: usize = 0x04;
+const PCI_CLASS_REVISION: usize = 0x08;
+const PCI_HEADER_TYPE: usize = 0x0E;
+const PCI_BAR0: usize = 0x10;
+
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+const PCI_COMMAND_MASTER: u16 = 1 << 2;
+
+const PCI_BAR_IO: u32 = 1 << 0;
+const PCI_BAR_MEM_TYPE_64: u32 = 0b10 << 1;
+
+// "ranges" space code for 32-bit memory
+const PCI_RANGE_MEM32: u32 = 0b10 << 24;
+
+#[derive(Copy, Clone, Debug)]
+pub struct PciDevice {
+    config_base: usize,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+impl PciDevice {
+    pub fn read_config32(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.config_base + offset) as *const u32) }
+    }
+    
+    pub fn write_config32(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.config_base + offset) as *mut u32, value) }
+    }
+    
+    pub fn read_config16(&self, offset: usize) -> u16 {
+        unsafe { read_volatile((self.config_base + offset) as *const u16) }
+    }
+    
+    pub fn write_config16(&self, offset: usize, value: u16) {
+        unsafe { write_volatile((self.config_base + offset) as *mut u16, value) }
+    }
+    
+    /// Turn on memory decoding and bus mastering.
+    pub fn enable(&self) {
+        let command = self.read_config16(PCI_COMMAND);
+        self.write_config16(PCI_COMMAND, command | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER);
+    }
+    
+    /// Kernel virtual address of a memory BAR, assigning one if firmware did not.
+    pub fn map_bar(&self, bar: usize) -> Result<usize, &'static str> {
+        let offset = PCI_BAR0 + bar * 4;
+        let original = self.read_config32(offset);
+        if original & PCI_BAR_IO != 0 {
+            return Err("PCI: I/O BARs not supported");
+        }
+        let is_64 = original & PCI_BAR_MEM_TYPE_64 != 0;
+        
+        // Size the BAR by writing all ones and reading back the mask
+        self.write_config32(offset, 0xFFFF_FFFF);
+        let mask = self.read_config32(offset) & !0xF;
+        if mask == 0 {
+            self.write_config32(offset, original);
+            return Err("PCI: BAR not implemented");
+        }
+        let size = (!mask).wrapping_add(1) as usize;
+        
+        let addr = PCI_HOST
+            .lock()
+            .as_mut()
+            .ok_or("PCI: No host bridge")?
+            .allocate_mmio(size)?;
+        self.write_config32(offset, addr as u32);
+        if is_64 {
+            self.write_config32(offset + 4, (addr as u64 >> 32) as u32);
+        }
+        Ok(phys_to_virt(addr as u64) as usize)
+    }
+}
+
+struct PciHost {
+    ecam_base: usize,
+    mmio_next: usize,
+    mmio_end: usize,
+    devices: Vec<PciDevice>,
+}
+
+impl PciHost {
+    fn config_base(&self, bus: u8, device: u8, function: u8) -> usize {
+        self.ecam_base
+            + ((bus as usize) << 20)
+            + ((device as usize) << 15)
+            + ((function as usize) << 12)
+    }
+    
+    // BARs are naturally aligned to their size
+    fn allocate_mmio(&mut self, size: usize) -> Result<usize, &'static str> {
+        let addr = (self.mmio_next + size - 1) & !(size - 1);
+        if addr + size > self.mmio_end {
+            return Err("PCI: MMIO window exhausted");
+        }
+        self.mmio_next = addr + size;
+        Ok(addr)
+    }
+    
+    fn scan_bus0(&mut self) {
+        for device in 0..32u8 {
+            for function in 0..8u8 {
+                let config_base = self.config_base(0, device, function);
+                let id = unsafe { read_volatile((config_base + PCI_VENDOR_ID) as *const u32) };
+                if id & 0xFFFF == 0xFFFF {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                
+                let class_rev = unsafe { read_volatile((config_base + PCI_CLASS_REVISION) as *const u32) };
+                self.devices.push(PciDevice {
+                    config_base,
+                    bus: 0,
+                    device,
+                    function,
+                    vendor_id: id as u16,
+                    device_id: (id >> 16) as u16,
+                    class: (class_rev >> 24) as u8,
+                    subclass: (class_rev >> 16) as u8,
+                    prog_if: (class_rev >> 8) as u8,
+                });
+                
+                // Only multi-function devices have functions beyond 0
+                let header_type = unsafe { read_volatile((config_base + PCI_HEADER_TYPE) as *const u8) };
+                if function == 0 && header_type & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+static PCI_HOST: Mutex<Option<PciHost>> = Mutex::new(None);
+
+/// Find the ECAM host bridge in the device tree and enumerate bus 0.
+pub fn probe(dt: &DeviceTree) -> usize {
+    let node = match dt.find_compatible("pci-host-ecam-generic").next() {
+        Some(node) => node,
+        None => return 0,
+    };
+    let (ecam_base, _) = match node.reg(0) {
+        Some(reg) => reg,
+        None => return 0,
+    };
+    
+    // ranges: <pci-addr(3) cpu-addr(2) size(2)>
+    let mut window = None;
+    if let Some(ranges) = node.property("ranges") {
+        for entry in 0..ranges.len() / 28 {
+            let cell = |i| read_cell(ranges, entry * 7 + i).unwrap_or(0);
+            if cell(0) & (0b11 << 24) == PCI_RANGE_MEM32 {
+                let cpu_addr = ((cell(3) as u64) << 32) | cell(4) as u64;
+                let size = ((cell(5) as u64) << 32) | cell(6) as u64;
+                window = Some((cpu_addr as usize, size as usize));
+                break;
+            }
+        }
+    }
+    let (mmio_base, mmio_size) = match window {
+        Some(window) => window,
+        None => {
+            crate::println!("PCI: Host bridge has no 32-bit memory window");
+            return 0;
+        }
+    };
+    
+    // Neither the ECAM space nor BAR windows may be in the DT-derived map
+    for (base, size) in [(ecam_base, 1u64 << 28), (mmio_base as u64, mmio_size as u64)] {
+        if let Err(e) = crate::memory::mmu::map_device(base, size) {
+            crate::println!("PCI: Failed to map 0x{:x}: {}", base, e);
+            return 0;
+        }
+    }
+    
+    let mut host = PciHost {
+        ecam_base: phys_to_virt(ecam_base) as usize,
+        mmio_next: mmio_base,
+        mmio_end: mmio_base + mmio_size,
+        devices: Vec::new(),
+    };
+    host.scan_bus0();
+    
+    for dev in &host.devices {
+        crate::println!("PCI: {:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}{:02x}",
+                       dev.bus, dev.device, dev.function, dev.vendor_id, dev.device_id,
+                       dev.class, dev.subclass);
+    }
+    let count = host.devices.len();
+    *PCI_HOST.lock() = Some(host);
+    count
+}
+
+/// All functions matching a class/subclass pair.
+pub fn find_by_class(class: u8, subclass: u8) -> Vec<PciDevice> {
+    PCI_HOST
+        .lock()
+        .as_ref()
+        .map(|host| {
+            host.devices
+                .iter()
+                .filter(|dev| dev.class == class && dev.subclass == subclass)
+                .copied()
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,22 @@
+// ARM PL031 real-time clock (QEMU virt "arm,pl031")
+//
+// Read once at boot to set the wall clock; the kernel never writes it.
+
+use core::ptr::read_volatile;
+use crate::devicetree::DeviceTree;
+use crate::memory::paging::phys_to_virt;
+use crate::time::{self, UtcTime};
+
+// Register offsets (bytes)
+const RTCDR: usize = 0x000;    // Data: seconds since the epoch
+
+/// Set the wall clock from the first PL031 in the device tree.
+pub fn probe(dt: &DeviceTree) -> usize {
+    let Some((base, _size)) = dt.find_compatible("arm,pl031").next().and_then(|node| node.reg(0)) else {
+        return 0;
+    };
+    let seconds = unsafe { read_volatile((phys_to_virt(base) as usize + RTCDR) as *const u32) };
+    time::set_realtime(seconds as u64);
+    crate::println!("PL031: RTC at 0x{:08x}, time {}", base, UtcTime(seconds as u64));
+    1
+}
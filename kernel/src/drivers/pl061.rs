@@ -0,0 +1,110 @@
+// ARM PL061 GPIO controller driver (QEMU virt "arm,pl061")
+
+use alloc::boxed::Box;
+use core::ptr::{read_volatile, write_volatile};
+use spin::Mutex;
+use crate::devicetree::DeviceTree;
+use crate::memory::paging::phys_to_virt;
+use super::gpio::{register_controller, GpioController};
+
+// Register offsets (bytes)
+const GPIODIR: usize = 0x400;  // Direction, 1 = output
+const GPIOIE: usize = 0x410;   // Interrupt mask
+const GPIOIC: usize = 0x41C;   // Interrupt clear
+const GPIOAFSEL: usize = 0x420; // Alternate function select
+
+const PL061_NGPIO: u32 = 8;
+
+pub struct Pl061 {
+    base: usize,
+    // Serializes read-modify-write of the direction register
+    lock: Mutex<()>,
+}
+
+impl Pl061 {
+    pub fn new(base: usize) -> Self {
+        let gpio = Self {
+            base,
+            lock: Mutex::new(()),
+        };
+        
+        // Software control of every line, interrupts masked and cleared
+        gpio.write_reg(GPIOAFSEL, 0);
+        gpio.write_reg(GPIOIE, 0);
+        gpio.write_reg(GPIOIC, 0xFF);
+        gpio
+    }
+    
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+    
+    fn write_reg(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+    
+    // GPIODATA is masked by address bits [9:2], so each pin has its own word
+    fn data_offset(pin: u32) -> usize {
+        1 << (pin + 2)
+    }
+    
+    fn check_pin(pin: u32) -> Result<(), &'static str> {
+        if pin < PL061_NGPIO {
+            Ok(())
+        } else {
+            Err("PL061: Pin out of range")
+        }
+    }
+}
+
+impl GpioController for Pl061 {
+    fn name(&self) -> &'static str {
+        "pl061"
+    }
+    
+    fn ngpio(&self) -> u32 {
+        PL061_NGPIO
+    }
+    
+    fn direction_input(&self, pin: u32) -> Result<(), &'static str> {
+        Self::check_pin(pin)?;
+        let _guard = self.lock.lock();
+        let dir = self.read_reg(GPIODIR);
+        self.write_reg(GPIODIR, dir & !(1 << pin));
+        Ok(())
+    }
+    
+    fn direction_output(&self, pin: u32, value: bool) -> Result<(), &'static str> {
+        Self::check_pin(pin)?;
+        let _guard = self.lock.lock();
+        // Set the level first so the line never glitches
+        self.write_reg(Self::data_offset(pin), if value { 0xFF } else { 0 });
+        let dir = self.read_reg(GPIODIR);
+        self.write_reg(GPIODIR, dir | (1 << pin));
+        Ok(())
+    }
+    
+    fn get(&self, pin: u32) -> Result<bool, &'static str> {
+        Self::check_pin(pin)?;
+        Ok(self.read_reg(Self::data_offset(pin)) != 0)
+    }
+    
+    fn set(&self, pin: u32, value: bool) -> Result<(), &'static str> {
+        Self::check_pin(pin)?;
+        self.write_reg(Self::data_offset(pin), if value { 0xFF } else { 0 });
+        Ok(())
+    }
+}
+
+/// Probe every enabled PL061 in the device tree.
+pub fn probe(dt: &DeviceTree) -> usize {
+    let mut count = 0;
+    for node in dt.find_compatible("arm,pl061") {
+        if let Some((base, _size)) = node.reg(0) {
+            crate::println!("PL061: GPIO controller at 0x{:08x}", base);
+            register_controller(node.phandle(), Box::new(Pl061::new(phys_to_virt(base) as usize)));
+            count += 1;
+        }
+    }
+    count
+}
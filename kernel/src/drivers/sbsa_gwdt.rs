@@ -0,0 +1,107 @@
+// Arm SBSA generic watchdog ("arm,sbsa-gwdt")
+//
+// Two register frames: the control frame enables the watchdog and holds
+// its offset, the refresh frame restarts it. It counts on the system
+// counter; when the offset runs out it raises its first signal (WS0) and
+// starts over, and running out a second time raises WS1, which resets the
+// system. Nothing here handles WS0, so the offset is half the timeout and
+// an unrefreshed watchdog resets after the whole of it.
+//
+// QEMU's sbsa-ref machine has one, described in the ACPI GTDT (which
+// acpi.rs turns into this node); `-M virt` has none.
+
+use alloc::boxed::Box;
+use core::ptr::{read_volatile, write_volatile};
+use crate::devicetree::DeviceTree;
+use crate::interrupts::counter_frequency;
+use crate::memory::paging::phys_to_virt;
+use crate::watchdog::HardwareWatchdog;
+
+// Control frame register offsets (bytes)
+const WCS: usize = 0x000;      // Control and status
+const WOR: usize = 0x008;      // Offset, low word
+const WOR_HIGH: usize = 0x00C; // Offset, high word (architecture version 1)
+const W_IIDR: usize = 0xFCC;   // Implementation ID
+// Refresh frame: any write restarts the count
+const WRR: usize = 0x000;
+
+const WCS_EN: u32 = 1 << 0;
+const WCS_WS0: u32 = 1 << 1;
+
+const IIDR_ARCH_SHIFT: u32 = 16;
+const IIDR_ARCH_MASK: u32 = 0xF;
+
+struct SbsaGwdt {
+    control: usize,
+    refresh: usize,
+    // 32-bit offset in version 0, 48-bit from version 1
+    wide_offset: bool,
+}
+
+impl SbsaGwdt {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.control + offset) as *const u32) }
+    }
+    
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.control + offset) as *mut u32, value) }
+    }
+}
+
+impl HardwareWatchdog for SbsaGwdt {
+    fn name(&self) -> &'static str {
+        "sbsa-gwdt"
+    }
+    
+    fn start(&self, timeout_ms: u64) -> u64 {
+        let frequency = counter_frequency().max(1);
+        let limit = if self.wide_offset { (1 << 48) - 1 } else { u32::MAX as u64 };
+        let offset = (frequency * timeout_ms / 2000).clamp(1, limit);
+        // Writing the offset also refreshes
+        self.write(WOR, offset as u32);
+        if self.wide_offset {
+            self.write(WOR_HIGH, (offset >> 32) as u32);
+        }
+        self.write(WCS, WCS_EN);
+        offset * 2000 / frequency
+    }
+    
+    fn stop(&self) {
+        self.write(WCS, 0);
+    }
+    
+    fn refresh(&self) {
+        unsafe { write_volatile((self.refresh + WRR) as *mut u32, 0) }
+    }
+}
+
+/// Hand the first SBSA watchdog in the device tree to the watchdog.
+pub fn probe(dt: &DeviceTree) -> usize {
+    let Some(node) = dt.find_compatible("arm,sbsa-gwdt").next() else {
+        return 0;
+    };
+    let (Some((control, _)), Some((refresh, _))) = (node.reg(0), node.reg(1)) else {
+        crate::println!("SBSA watchdog: Node lacks its two register frames");
+        return 0;
+    };
+    let mut wdt = SbsaGwdt {
+        control: phys_to_virt(control) as usize,
+        refresh: phys_to_virt(refresh) as usize,
+        wide_offset: false,
+    };
+    let arch = wdt.read(W_IIDR) >> IIDR_ARCH_SHIFT & IIDR_ARCH_MASK;
+    wdt.wide_offset = arch >= 1;
+    let status = wdt.read(WCS);
+    crate::println!("SBSA watchdog: Control 0x{:08x}, refresh 0x{:08x}, version {}{}", control, refresh, arch,
+                   if status & WCS_EN != 0 { ", left running by firmware" } else { "" });
+    if status & WCS_WS0 != 0 {
+        crate::println!("SBSA watchdog: First signal already raised");
+    }
+    match crate::watchdog::register_hardware(Box::new(wdt)) {
+        Ok(()) => 1,
+        Err(e) => {
+            crate::println!("SBSA watchdog: {}", e);
+            0
+        }
+    }
+}
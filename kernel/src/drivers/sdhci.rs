@@ -0,0 +1,563 @@
+// SD Host Controller Interface (SDHCI) driver for SD memory cards
+//
+// PIO transfers only. Controllers come from the device tree or, on QEMU,
+// from an sdhci-pci function.
+
+use alloc::format;
+use alloc::sync::Arc;
+use core::ptr::{read_volatile, write_volatile};
+use spin::Mutex;
+use crate::block::{self, check_request, BlockDevice, SECTOR_SIZE};
+use crate::devicetree::DeviceTree;
+use crate::interrupts::{counter_frequency, counter_ticks, delay_us};
+use crate::memory::paging::phys_to_virt;
+use super::pci;
+
+// Register offsets
+const SDHCI_BLOCK_SIZE: usize = 0x04;
+const SDHCI_BLOCK_COUNT: usize = 0x06;
+const SDHCI_ARGUMENT: usize = 0x08;
+const SDHCI_TRANSFER_MODE: usize = 0x0C;
+const SDHCI_COMMAND: usize = 0x0E;
+const SDHCI_RESPONSE: usize = 0x10;
+const SDHCI_BUFFER: usize = 0x20;
+const SDHCI_PRESENT_STATE: usize = 0x24;
+const SDHCI_HOST_CONTROL: usize = 0x28;
+const SDHCI_POWER_CONTROL: usize = 0x29;
+const SDHCI_CLOCK_CONTROL: usize = 0x2C;
+const SDHCI_TIMEOUT_CONTROL: usize = 0x2E;
+const SDHCI_SOFTWARE_RESET: usize = 0x2F;
+const SDHCI_INT_STATUS: usize = 0x30;
+const SDHCI_INT_ENABLE: usize = 0x34;
+const SDHCI_SIGNAL_ENABLE: usize = 0x38;
+const SDHCI_CAPABILITIES: usize = 0x40;
+const SDHCI_HOST_VERSION: usize = 0xFE;
+
+// Present state bits
+const PRESENT_CMD_INHIBIT: u32 = 1 << 0;
+const PRESENT_DAT_INHIBIT: u32 = 1 << 1;
+const PRESENT_CARD_INSERTED: u32 = 1 << 16;
+
+// Interrupt status bits (normal in low half, error in high half)
+const INT_CMD_COMPLETE: u32 = 1 << 0;
+const INT_XFER_COMPLETE: u32 = 1 << 1;
+const INT_BUFFER_WRITE_READY: u32 = 1 << 4;
+const INT_BUFFER_READ_READY: u32 = 1 << 5;
+const INT_ERROR: u32 = 1 << 15;
+const INT_ALL: u32 = 0xFFFF_FFFF;
+
+// Command register
+const CMD_RESP_NONE: u16 = 0b00;
+const CMD_RESP_136: u16 = 0b01;
+const CMD_RESP_48: u16 = 0b10;
+const CMD_RESP_48_BUSY: u16 = 0b11;
+const CMD_CRC_CHECK: u16 = 1 << 3;
+const CMD_INDEX_CHECK: u16 = 1 << 4;
+const CMD_DATA_PRESENT: u16 = 1 << 5;
+
+// Transfer mode register
+const MODE_BLOCK_COUNT_ENABLE: u16 = 1 << 1;
+const MODE_AUTO_CMD12: u16 = 1 << 2;
+const MODE_READ: u16 = 1 << 4;
+const MODE_MULTI_BLOCK: u16 = 1 << 5;
+
+// Software reset bits
+const RESET_ALL: u8 = 1 << 0;
+const RESET_CMD: u8 = 1 << 1;
+const RESET_DATA: u8 = 1 << 2;
+
+// Clock control bits
+const CLOCK_INTERNAL_ENABLE: u16 = 1 << 0;
+const CLOCK_INTERNAL_STABLE: u16 = 1 << 1;
+const CLOCK_CARD_ENABLE: u16 = 1 << 2;
+
+// Power control: 3.3V, bus power on
+const POWER_330: u8 = 0b111 << 1;
+const POWER_ON: u8 = 1 << 0;
+
+// Host control: 4-bit data bus
+const HOST_CONTROL_4BIT: u8 = 1 << 1;
+
+// SD commands
+const CMD_GO_IDLE_STATE: u8 = 0;
+const CMD_ALL_SEND_CID: u8 = 2;
+const CMD_SEND_RELATIVE_ADDR: u8 = 3;
+const CMD_SELECT_CARD: u8 = 7;
+const CMD_SEND_IF_COND: u8 = 8;
+const CMD_SEND_CSD: u8 = 9;
+const CMD_SET_BLOCKLEN: u8 = 16;
+const CMD_READ_SINGLE_BLOCK: u8 = 17;
+const CMD_READ_MULTIPLE_BLOCK: u8 = 18;
+const CMD_WRITE_BLOCK: u8 = 24;
+const CMD_WRITE_MULTIPLE_BLOCK: u8 = 25;
+const CMD_APP_CMD: u8 = 55;
+const ACMD_SET_BUS_WIDTH: u8 = 6;
+const ACMD_SD_SEND_OP_COND: u8 = 41;
+
+// OCR bits
+const OCR_BUSY: u32 = 1 << 31;
+const OCR_CCS: u32 = 1 << 30;
+const OCR_VOLTAGE_WINDOW: u32 = 0x00FF_8000;
+
+const CLOCK_IDENT_HZ: u32 = 400_000;
+const CLOCK_TRANSFER_HZ: u32 = 25_000_000;
+
+// Idle time before the SD clock is gated
+const AUTOSUSPEND_MS: u64 = 2000;
+
+const COMMAND_TIMEOUT_US: u64 = 100_000;
+const DATA_TIMEOUT_US: u64 = 1_000_000;
+const IO_RETRIES: u32 = 3;
+
+// Largest run of blocks moved by one command
+const MAX_BLOCKS_PER_COMMAND: usize = 128;
+
+#[derive(Copy, Clone)]
+enum Response {
+    None,
+    R1,
+    R1b,
+    R2,
+    R3,
+    R6,
+    R7,
+}
+
+pub struct Sdhci {
+    base: usize,
+    base_clock_hz: u32,
+    version: u8,
+}
+
+impl Sdhci {
+    fn read8(&self, offset: usize) -> u8 {
+        unsafe { read_volatile((self.base + offset) as *const u8) }
+    }
+    
+    fn write8(&self, offset: usize, value: u8) {
+        unsafe { write_volatile((self.base + offset) as *mut u8, value) }
+    }
+    
+    fn read16(&self, offset: usize) -> u16 {
+        unsafe { read_volatile((self.base + offset) as *const u16) }
+    }
+    
+    fn write16(&self, offset: usize, value: u16) {
+        unsafe { write_volatile((self.base + offset) as *mut u16, value) }
+    }
+    
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+    
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+    
+    // Poll until `done` holds or the timeout expires
+    fn wait_for(&self, timeout_us: u64, mut done: impl FnMut(&Self) -> bool) -> Result<(), &'static str> {
+        let deadline = counter_ticks() + counter_frequency() * timeout_us / 1_000_000;
+        while !done(self) {
+            if counter_ticks() > deadline {
+                return Err("SDHCI: Timeout");
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+    
+    pub fn new(base: usize) -> Self {
+        let mut host = Self {
+            base,
+            base_clock_hz: 0,
+            version: 0,
+        };
+        host.version = (host.read16(SDHCI_HOST_VERSION) & 0xFF) as u8 + 1;
+        
+        // Base clock in MHz: 8 bits from v3, 6 bits before
+        let caps = host.read32(SDHCI_CAPABILITIES);
+        let mask = if host.version >= 3 { 0xFF } else { 0x3F };
+        host.base_clock_hz = ((caps >> 8) & mask) * 1_000_000;
+        host
+    }
+    
+    fn reset(&self, mask: u8) -> Result<(), &'static str> {
+        self.write8(SDHCI_SOFTWARE_RESET, mask);
+        self.wait_for(COMMAND_TIMEOUT_US, |host| host.read8(SDHCI_SOFTWARE_RESET) & mask == 0)
+    }
+    
+    fn set_clock(&self, hz: u32) -> Result<(), &'static str> {
+        self.write16(SDHCI_CLOCK_CONTROL, 0);
+        if self.base_clock_hz == 0 {
+            return Err("SDHCI: Base clock unknown");
+        }
+        
+        // SD clock = base / (2 * divider); v3 has a 10-bit divider,
+        // earlier versions an 8-bit power of two
+        let mut divider = 0u32;
+        if hz < self.base_clock_hz {
+            if self.version >= 3 {
+                divider = self.base_clock_hz.div_ceil(2 * hz).min(0x3FF);
+            } else {
+                divider = 1;
+                while divider < 0x80 && self.base_clock_hz / (2 * divider) > hz {
+                    divider <<= 1;
+                }
+            }
+        }
+        let clock = (((divider & 0xFF) << 8) | ((divider & 0x300) >> 2)) as u16;
+        
+        self.write16(SDHCI_CLOCK_CONTROL, clock | CLOCK_INTERNAL_ENABLE);
+        self.wait_for(COMMAND_TIMEOUT_US, |host| {
+            host.read16(SDHCI_CLOCK_CONTROL) & CLOCK_INTERNAL_STABLE != 0
+        })?;
+        self.write16(SDHCI_CLOCK_CONTROL, clock | CLOCK_INTERNAL_ENABLE | CLOCK_CARD_ENABLE);
+        Ok(())
+    }
+    
+    fn init_controller(&self) -> Result<(), &'static str> {
+        self.reset(RESET_ALL)?;
+        
+        self.write8(SDHCI_POWER_CONTROL, POWER_330 | POWER_ON);
+        self.set_clock(CLOCK_IDENT_HZ)?;
+        self.write8(SDHCI_TIMEOUT_CONTROL, 0xE);
+        
+        // Status bits latch, but nothing is signalled: the driver polls
+        self.write32(SDHCI_INT_ENABLE, INT_ALL);
+        self.write32(SDHCI_SIGNAL_ENABLE, 0);
+        self.write32(SDHCI_INT_STATUS, INT_ALL);
+        
+        // Card needs 74 clocks after power up before the first command
+        delay_us(1000);
+        Ok(())
+    }
+    
+    // Stop the SD clock and the internal clock for runtime suspend; the
+    // card keeps its state and set_clock restarts both
+    fn gate_clock(&self) {
+        self.write16(SDHCI_CLOCK_CONTROL, 0);
+    }
+    
+    // Reset command and data state machines after an error
+    fn recover(&self) {
+        let _ = self.reset(RESET_CMD | RESET_DATA);
+        self.write32(SDHCI_INT_STATUS, INT_ALL);
+    }
+    
+    fn send_command(&self, index: u8, arg: u32, response: Response, data_flags: u16) -> Result<[u32; 4], &'static str> {
+        let inhibit = if data_flags != 0 || matches!(response, Response::R1b) {
+            PRESENT_CMD_INHIBIT | PRESENT_DAT_INHIBIT
+        } else {
+            PRESENT_CMD_INHIBIT
+        };
+        self.wait_for(COMMAND_TIMEOUT_US, |host| host.read32(SDHCI_PRESENT_STATE) & inhibit == 0)?;
+        
+        let flags = match response {
+            Response::None => CMD_RESP_NONE,
+            Response::R2 => CMD_RESP_136 | CMD_CRC_CHECK,
+            Response::R3 => CMD_RESP_48,
+            Response::R1b => CMD_RESP_48_BUSY | CMD_CRC_CHECK | CMD_INDEX_CHECK,
+            Response::R1 | Response::R6 | Response::R7 => CMD_RESP_48 | CMD_CRC_CHECK | CMD_INDEX_CHECK,
+        };
+        
+        self.write32(SDHCI_INT_STATUS, INT_ALL);
+        self.write32(SDHCI_ARGUMENT, arg);
+        self.write16(SDHCI_COMMAND, ((index as u16) << 8) | flags | data_flags);
+        
+        let mut status = 0;
+        self.wait_for(COMMAND_TIMEOUT_US, |host| {
+            status = host.read32(SDHCI_INT_STATUS);
+            status & (INT_CMD_COMPLETE | INT_ERROR) != 0
+        })?;
+        if status & INT_ERROR != 0 {
+            self.recover();
+            return Err("SDHCI: Command error");
+        }
+        self.write32(SDHCI_INT_STATUS, INT_CMD_COMPLETE);
+        
+        let mut resp = [0u32; 4];
+        for (i, word) in resp.iter_mut().enumerate() {
+            *word = self.read32(SDHCI_RESPONSE + i * 4);
+        }
+        Ok(resp)
+    }
+    
+    fn send_app_command(&self, rca: u32, index: u8, arg: u32, response: Response) -> Result<[u32; 4], &'static str> {
+        self.send_command(CMD_APP_CMD, rca << 16, Response::R1, 0)?;
+        self.send_command(index, arg, response, 0)
+    }
+    
+    // Wait for a status bit during a data phase, failing on any error
+    fn wait_data(&self, bit: u32) -> Result<(), &'static str> {
+        let mut status = 0;
+        self.wait_for(DATA_TIMEOUT_US, |host| {
+            status = host.read32(SDHCI_INT_STATUS);
+            status & (bit | INT_ERROR) != 0
+        })?;
+        if status & INT_ERROR != 0 {
+            return Err("SDHCI: Data error");
+        }
+        self.write32(SDHCI_INT_STATUS, bit);
+        Ok(())
+    }
+    
+    fn data_command(&self, index: u8, arg: u32, blocks: usize, read: bool) -> Result<(), &'static str> {
+        self.write16(SDHCI_BLOCK_SIZE, SECTOR_SIZE as u16);
+        self.write16(SDHCI_BLOCK_COUNT, blocks as u16);
+        
+        let mut mode = MODE_BLOCK_COUNT_ENABLE;
+        if read {
+            mode |= MODE_READ;
+        }
+        if blocks > 1 {
+            mode |= MODE_MULTI_BLOCK | MODE_AUTO_CMD12;
+        }
+        self.write16(SDHCI_TRANSFER_MODE, mode);
+        self.send_command(index, arg, Response::R1, CMD_DATA_PRESENT).map(|_| ())
+    }
+}
+
+struct SdCard {
+    host: Sdhci,
+    rca: u32,
+    high_capacity: bool,
+    blocks: u64,
+}
+
+impl SdCard {
+    // Identify and select the card, leaving it in transfer state
+    fn initialize(host: Sdhci) -> Result<Self, &'static str> {
+        if host.read32(SDHCI_PRESENT_STATE) & PRESENT_CARD_INSERTED == 0 {
+            return Err("SDHCI: No card inserted");
+        }
+        host.init_controller()?;
+        
+        host.send_command(CMD_GO_IDLE_STATE, 0, Response::None, 0)?;
+        
+        // CMD8 checks the voltage range and identifies v2+ cards
+        let sd_v2 = match host.send_command(CMD_SEND_IF_COND, 0x1AA, Response::R7, 0) {
+            Ok(resp) if resp[0] & 0xFFF == 0x1AA => true,
+            Ok(_) => return Err("SDHCI: Card rejected voltage range"),
+            Err(_) => {
+                host.recover();
+                false
+            }
+        };
+        
+        let hcs = if sd_v2 { OCR_CCS } else { 0 };
+        let mut ocr = 0;
+        for _ in 0..100 {
+            ocr = host.send_app_command(0, ACMD_SD_SEND_OP_COND, hcs | OCR_VOLTAGE_WINDOW, Response::R3)?[0];
+            if ocr & OCR_BUSY != 0 {
+                break;
+            }
+            delay_us(10_000);
+        }
+        if ocr & OCR_BUSY == 0 {
+            return Err("SDHCI: Card did not power up");
+        }
+        let high_capacity = ocr & OCR_CCS != 0;
+        
+        host.send_command(CMD_ALL_SEND_CID, 0, Response::R2, 0)?;
+        let rca = host.send_command(CMD_SEND_RELATIVE_ADDR, 0, Response::R6, 0)?[0] >> 16;
+        let csd = host.send_command(CMD_SEND_CSD, rca << 16, Response::R2, 0)?;
+        let blocks = Self::capacity_blocks(&csd)?;
+        
+        host.send_command(CMD_SELECT_CARD, rca << 16, Response::R1b, 0)?;
+        if !high_capacity {
+            host.send_command(CMD_SET_BLOCKLEN, SECTOR_SIZE as u32, Response::R1, 0)?;
+        }
+        
+        // Switch to a 4-bit bus and full speed; stay at 1-bit if refused
+        if host.send_app_command(rca, ACMD_SET_BUS_WIDTH, 2, Response::R1).is_ok() {
+            let control = host.read8(SDHCI_HOST_CONTROL);
+            host.write8(SDHCI_HOST_CONTROL, control | HOST_CONTROL_4BIT);
+        } else {
+            host.recover();
+        }
+        host.set_clock(CLOCK_TRANSFER_HZ)?;
+        
+        Ok(Self {
+            host,
+            rca,
+            high_capacity,
+            blocks,
+        })
+    }
+    
+    // Card size from the CSD register (responses drop the CRC byte,
+    // so CSD bit n appears at response bit n - 8)
+    fn capacity_blocks(csd: &[u32; 4]) -> Result<u64, &'static str> {
+        let resp = (csd[0] as u128)
+            | ((csd[1] as u128) << 32)
+            | ((csd[2] as u128) << 64)
+            | ((csd[3] as u128) << 96);
+        let bits = |hi: u32, lo: u32| ((resp >> (lo - 8)) & ((1u128 << (hi - lo + 1)) - 1)) as u64;
+        
+        match bits(127, 126) {
+            // CSD v2: (C_SIZE + 1) * 512KiB
+            1 => Ok((bits(69, 48) + 1) * 1024),
+            // CSD v1: (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) * 2^READ_BL_LEN bytes
+            0 => {
+                let c_size = bits(73, 62);
+                let c_size_mult = bits(49, 47);
+                let read_bl_len = bits(83, 80);
+                let bytes = (c_size + 1) << (c_size_mult + 2 + read_bl_len);
+                Ok(bytes / SECTOR_SIZE as u64)
+            }
+            _ => Err("SDHCI: Unknown CSD structure"),
+        }
+    }
+    
+    fn card_address(&self, lba: u64) -> u32 {
+        if self.high_capacity {
+            lba as u32
+        } else {
+            (lba * SECTOR_SIZE as u64) as u32
+        }
+    }
+    
+    fn read_run(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let blocks = buf.len() / SECTOR_SIZE;
+        let index = if blocks > 1 { CMD_READ_MULTIPLE_BLOCK } else { CMD_READ_SINGLE_BLOCK };
+        self.host.data_command(index, self.card_address(lba), blocks, true)?;
+        
+        for block in buf.chunks_mut(SECTOR_SIZE) {
+            self.host.wait_data(INT_BUFFER_READ_READY)?;
+            for word in block.chunks_mut(4) {
+                word.copy_from_slice(&self.host.read32(SDHCI_BUFFER).to_le_bytes());
+            }
+        }
+        self.host.wait_data(INT_XFER_COMPLETE)
+    }
+    
+    fn write_run(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        let blocks = buf.len() / SECTOR_SIZE;
+        let index = if blocks > 1 { CMD_WRITE_MULTIPLE_BLOCK } else { CMD_WRITE_BLOCK };
+        self.host.data_command(index, self.card_address(lba), blocks, false)?;
+        
+        for block in buf.chunks(SECTOR_SIZE) {
+            self.host.wait_data(INT_BUFFER_WRITE_READY)?;
+            for word in block.chunks(4) {
+                self.host.write32(SDHCI_BUFFER, u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+            }
+        }
+        self.host.wait_data(INT_XFER_COMPLETE)
+    }
+    
+    // Retry a transfer after resetting the controller's state machines
+    fn with_retries(&self, mut op: impl FnMut() -> Result<(), &'static str>) -> Result<(), &'static str> {
+        let mut result = op();
+        for _ in 1..IO_RETRIES {
+            if result.is_ok() {
+                break;
+            }
+            self.host.recover();
+            result = op();
+        }
+        if result.is_err() {
+            self.host.recover();
+        }
+        result
+    }
+}
+
+/// An SD card exposed to the block layer.
+pub struct SdBlockDevice {
+    card: Mutex<SdCard>,
+    blocks: u64,
+}
+
+impl BlockDevice for SdBlockDevice {
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+    
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_request(self, lba, buf.len())?;
+        let card = self.card.lock();
+        for (i, run) in buf.chunks_mut(MAX_BLOCKS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let run_lba = lba + (i * MAX_BLOCKS_PER_COMMAND) as u64;
+            card.with_retries(|| card.read_run(run_lba, run))?;
+        }
+        Ok(())
+    }
+    
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_request(self, lba, buf.len())?;
+        let card = self.card.lock();
+        for (i, run) in buf.chunks(MAX_BLOCKS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let run_lba = lba + (i * MAX_BLOCKS_PER_COMMAND) as u64;
+            card.with_retries(|| card.write_run(run_lba, run))?;
+        }
+        Ok(())
+    }
+    
+    fn autosuspend_ms(&self) -> Option<u64> {
+        Some(AUTOSUSPEND_MS)
+    }
+    
+    fn runtime_suspend(&self) -> Result<(), &'static str> {
+        self.card.lock().host.gate_clock();
+        Ok(())
+    }
+    
+    fn runtime_resume(&self) -> Result<(), &'static str> {
+        self.card.lock().host.set_clock(CLOCK_TRANSFER_HZ)
+    }
+}
+
+fn attach(base: usize, index: &mut usize) {
+    let host = Sdhci::new(base);
+    crate::println!("SDHCI: Controller v{} at 0x{:08x}, base clock {} MHz",
+                   host.version, base, host.base_clock_hz / 1_000_000);
+    
+    match SdCard::initialize(host) {
+        Ok(card) => {
+            crate::println!("SDHCI: {} card, RCA 0x{:04x}, {} blocks",
+                           if card.high_capacity { "SDHC/SDXC" } else { "SDSC" },
+                           card.rca, card.blocks);
+            let blocks = card.blocks;
+            let device = SdBlockDevice {
+                card: Mutex::new(card),
+                blocks,
+            };
+            let name = format!("mmcblk{}", *index);
+            if block::register(&name, Arc::new(device)).is_ok() {
+                *index += 1;
+            }
+        }
+        Err(e) => crate::println!("SDHCI: Card initialization failed: {}", e),
+    }
+}
+
+/// Probe device-tree and PCI SDHCI controllers and register their cards.
+pub fn probe(dt: &DeviceTree) -> usize {
+    const COMPATIBLE: [&str; 4] = [
+        "arasan,sdhci-5.1",
+        "arasan,sdhci-8.9a",
+        "brcm,bcm2711-emmc2",
+        "brcm,bcm2835-sdhci",
+    ];
+    
+    let mut index = 0;
+    for compatible in COMPATIBLE {
+        for node in dt.find_compatible(compatible) {
+            if let Some((base, _)) = node.reg(0) {
+                attach(phys_to_virt(base) as usize, &mut index);
+            }
+        }
+    }
+    
+    // PCI class 08h subclass 05h: SD host controller (QEMU sdhci-pci)
+    for dev in pci::find_by_class(0x08, 0x05) {
+        match dev.map_bar(0) {
+            Ok(base) => {
+                dev.enable();
+                attach(base, &mut index);
+            }
+            Err(e) => crate::println!("SDHCI: PCI BAR setup failed: {}", e),
+        }
+    }
+    index
+}
@@ -0,0 +1,269 @@
+// SPI bus framework: controllers, device-tree device instantiation, spidev
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::devfs::{self, Device};
+use crate::devicetree::{DeviceNode, DeviceTree};
+
+// Mode bits (match Linux spidev)
+pub const SPI_CPHA: u8 = 0x01;
+pub const SPI_CPOL: u8 = 0x02;
+pub const SPI_CS_HIGH: u8 = 0x04;
+pub const SPI_LSB_FIRST: u8 = 0x08;
+
+// spidev ioctls: _IOW('k', 1, u8) and _IOW('k', 4, u32)
+pub const SPI_IOC_WR_MODE: u32 = 0x4001_6b01;
+pub const SPI_IOC_WR_MAX_SPEED_HZ: u32 = 0x4004_6b04;
+
+const DEFAULT_MAX_SPEED_HZ: u32 = 1_000_000;
+
+// Largest single passthrough transfer
+const SPIDEV_MAX_TRANSFER: usize = 4096;
+
+/// Per-device settings a controller applies for each message.
+#[derive(Copy, Clone, Debug)]
+pub struct SpiConfig {
+    pub chip_select: u32,
+    pub mode: u8,
+    pub max_speed_hz: u32,
+}
+
+/// One full-duplex segment; a missing tx sends zeros, a missing rx discards.
+pub struct SpiTransfer<'a> {
+    pub tx: Option<&'a [u8]>,
+    pub rx: Option<&'a mut [u8]>,
+    pub len: usize,
+}
+
+/// An SPI bus controller driver.
+pub trait SpiController: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn num_chipselect(&self) -> u32;
+    
+    /// Apply a device's settings while it is idle; chip select must end up
+    /// at the level its mode makes inactive.
+    fn setup(&self, _config: &SpiConfig) -> Result<(), &'static str> {
+        Ok(())
+    }
+    
+    /// Run the transfers with chip select asserted for the whole message.
+    fn transfer(&self, config: &SpiConfig, transfers: &mut [SpiTransfer]) -> Result<(), &'static str>;
+}
+
+pub struct SpiBus {
+    number: usize,
+    controller: Box<dyn SpiController>,
+    lock: Mutex<()>,
+}
+
+impl SpiBus {
+    pub fn number(&self) -> usize {
+        self.number
+    }
+    
+    pub fn setup(&self, config: &SpiConfig) -> Result<(), &'static str> {
+        if config.chip_select >= self.controller.num_chipselect() {
+            return Err("SPI: Chip select out of range");
+        }
+        let _guard = self.lock.lock();
+        self.controller.setup(config)
+    }
+    
+    pub fn transfer(&self, config: &SpiConfig, transfers: &mut [SpiTransfer]) -> Result<(), &'static str> {
+        if config.chip_select >= self.controller.num_chipselect() {
+            return Err("SPI: Chip select out of range");
+        }
+        for transfer in transfers.iter() {
+            let tx_short = transfer.tx.is_some_and(|tx| tx.len() < transfer.len);
+            let rx_short = transfer.rx.as_ref().is_some_and(|rx| rx.len() < transfer.len);
+            if tx_short || rx_short {
+                return Err("SPI: Buffer shorter than transfer");
+            }
+        }
+        let _guard = self.lock.lock();
+        self.controller.transfer(config, transfers)
+    }
+}
+
+/// A device on one chip select of a bus.
+pub struct SpiDevice {
+    bus: Arc<SpiBus>,
+    config: Mutex<SpiConfig>,
+}
+
+impl SpiDevice {
+    pub fn new(bus: Arc<SpiBus>, config: SpiConfig) -> Self {
+        Self {
+            bus,
+            config: Mutex::new(config),
+        }
+    }
+    
+    pub fn config(&self) -> SpiConfig {
+        *self.config.lock()
+    }
+    
+    pub fn write(&self, data: &[u8]) -> Result<(), &'static str> {
+        let config = self.config();
+        self.bus.transfer(&config, &mut [SpiTransfer { tx: Some(data), rx: None, len: data.len() }])
+    }
+    
+    pub fn read(&self, buf: &mut [u8]) -> Result<(), &'static str> {
+        let config = self.config();
+        let len = buf.len();
+        self.bus.transfer(&config, &mut [SpiTransfer { tx: None, rx: Some(buf), len }])
+    }
+    
+    /// Send a command then read the response in one chip-select window.
+    pub fn write_then_read(&self, data: &[u8], buf: &mut [u8]) -> Result<(), &'static str> {
+        let config = self.config();
+        let len = buf.len();
+        self.bus.transfer(&config, &mut [
+            SpiTransfer { tx: Some(data), rx: None, len: data.len() },
+            SpiTransfer { tx: None, rx: Some(buf), len },
+        ])
+    }
+}
+
+/// A protocol driver matched against child nodes of an SPI controller.
+pub struct SpiDriver {
+    pub compatible: &'static str,
+    pub probe: fn(SpiDevice, &DeviceNode) -> Result<(), &'static str>,
+}
+
+static SPI_BUSES: Mutex<Vec<Arc<SpiBus>>> = Mutex::new(Vec::new());
+static SPI_DRIVERS: Mutex<Vec<SpiDriver>> = Mutex::new(Vec::new());
+
+/// Register a protocol driver; must happen before controllers are probed.
+pub fn register_driver(driver: SpiDriver) {
+    SPI_DRIVERS.lock().push(driver);
+}
+
+/// Register a controller and instantiate the devices below its node.
+///
+/// Devices without a matching driver are exposed as /dev/spidevB.C so
+/// they can be driven from userspace.
+pub fn register_controller(
+    dt: &DeviceTree,
+    node: Option<&DeviceNode>,
+    controller: Box<dyn SpiController>,
+) -> Arc<SpiBus> {
+    let bus = {
+        let mut buses = SPI_BUSES.lock();
+        let bus = Arc::new(SpiBus {
+            number: buses.len(),
+            controller,
+            lock: Mutex::new(()),
+        });
+        buses.push(bus.clone());
+        bus
+    };
+    crate::println!("SPI: Bus {} registered ({}, {} chip selects)",
+                   bus.number, bus.controller.name(), bus.controller.num_chipselect());
+    
+    if let Some(node) = node {
+        instantiate_devices(dt, node, &bus);
+    }
+    bus
+}
+
+fn instantiate_devices(dt: &DeviceTree, node: &DeviceNode, bus: &Arc<SpiBus>) {
+    for child in dt.children(node) {
+        if !child.is_enabled() {
+            continue;
+        }
+        let chip_select = match child.property_u32("reg") {
+            Some(cs) => cs,
+            None => {
+                crate::println!("SPI: Skipping {}: missing reg", child.name());
+                continue;
+            }
+        };
+        
+        let mut mode = 0;
+        if child.property("spi-cpha").is_some() {
+            mode |= SPI_CPHA;
+        }
+        if child.property("spi-cpol").is_some() {
+            mode |= SPI_CPOL;
+        }
+        if child.property("spi-cs-high").is_some() {
+            mode |= SPI_CS_HIGH;
+        }
+        if child.property("spi-lsb-first").is_some() {
+            mode |= SPI_LSB_FIRST;
+        }
+        let config = SpiConfig {
+            chip_select,
+            mode,
+            max_speed_hz: child.property_u32("spi-max-frequency").unwrap_or(DEFAULT_MAX_SPEED_HZ),
+        };
+        if let Err(e) = bus.setup(&config) {
+            crate::println!("SPI: Skipping {}: {}", child.name(), e);
+            continue;
+        }
+        let device = SpiDevice::new(bus.clone(), config);
+        
+        let drivers = SPI_DRIVERS.lock();
+        match drivers.iter().find(|driver| child.is_compatible(driver.compatible)) {
+            Some(driver) => match (driver.probe)(device, &child) {
+                Ok(()) => crate::println!("SPI: spi{}.{} bound to {}",
+                                          bus.number, chip_select, driver.compatible),
+                Err(e) => crate::println!("SPI: spi{}.{} probe failed: {}",
+                                          bus.number, chip_select, e),
+            },
+            None => {
+                let path = format!("spidev{}.{}", bus.number, chip_select);
+                let _ = devfs::register(&path, Arc::new(Spidev { device }));
+            }
+        }
+    }
+}
+
+pub fn bus(number: usize) -> Option<Arc<SpiBus>> {
+    SPI_BUSES.lock().get(number).cloned()
+}
+
+// Userspace passthrough: write transmits, read clocks in with zeros sent
+struct Spidev {
+    device: SpiDevice,
+}
+
+impl Device for Spidev {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let len = buf.len().min(SPIDEV_MAX_TRANSFER);
+        self.device.read(&mut buf[..len])?;
+        Ok(len)
+    }
+    
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+        let len = buf.len().min(SPIDEV_MAX_TRANSFER);
+        self.device.write(&buf[..len])?;
+        Ok(len)
+    }
+    
+    fn ioctl(&self, cmd: u32, arg: usize) -> Result<usize, &'static str> {
+        let mut config = self.device.config.lock();
+        match cmd {
+            SPI_IOC_WR_MODE => {
+                let mut changed = *config;
+                changed.mode = arg as u8 & (SPI_CPHA | SPI_CPOL | SPI_CS_HIGH | SPI_LSB_FIRST);
+                // A new SPI_CS_HIGH moves the idle chip-select level
+                self.device.bus.setup(&changed)?;
+                *config = changed;
+                Ok(0)
+            }
+            SPI_IOC_WR_MAX_SPEED_HZ => {
+                if arg == 0 {
+                    return Err("Invalid SPI speed");
+                }
+                config.max_speed_hz = arg as u32;
+                Ok(0)
+            }
+            _ => Err("Operation not supported"),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+// USB HID boot-protocol keyboards feeding console input
+//
+// Keyboards are polled from a kernel thread that sleeps for the shortest
+// endpoint interval between rounds; each report is translated to ASCII
+// (US layout) and queued on the console. A keyboard whose endpoint fails
+// is reported once and dropped.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::process::{kthread_spawn, yield_now, KTHREAD_DEFAULT_PRIORITY};
+use crate::timer;
+use super::{InterfaceDescriptor, SetupPacket, TransferType, UsbDevice, USB_RECIP_INTERFACE, USB_TYPE_CLASS};
+
+const HID_SUBCLASS_BOOT: u8 = 1;
+const HID_PROTOCOL_KEYBOARD: u8 = 1;
+
+// HID class requests
+const HID_REQ_SET_IDLE: u8 = 0x0A;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0B;
+const HID_BOOT_PROTOCOL: u16 = 0;
+
+// Boot report: modifiers, reserved, six key usages
+const BOOT_REPORT_LEN: usize = 8;
+const MOD_CTRL: u8 = 0x11;  // Left | right
+const MOD_SHIFT: u8 = 0x22; // Left | right
+const USAGE_ROLLOVER: u8 = 0x01;
+
+struct Keyboard {
+    device: Arc<UsbDevice>,
+    endpoint: u8,
+    interval_ns: u64,
+    pressed: [u8; 6],
+}
+
+impl Keyboard {
+    // Emit a byte for each key that went down since the last report
+    fn handle_report(&mut self, report: &[u8]) {
+        let modifiers = report[0];
+        let keys = &report[2..BOOT_REPORT_LEN];
+        if keys.iter().all(|&usage| usage == USAGE_ROLLOVER) {
+            return;
+        }
+        
+        for &usage in keys {
+            if usage != 0 && !self.pressed.contains(&usage) {
+                if let Some(byte) = usage_to_ascii(usage, modifiers) {
+                    crate::console::push_input(byte);
+                }
+            }
+        }
+        self.pressed.copy_from_slice(keys);
+    }
+}
+
+static KEYBOARDS: Mutex<Vec<Keyboard>> = Mutex::new(Vec::new());
+static POLLER_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Byte a boot-report key usage types with the given modifier bits, or
+/// None for keys without one.
+pub fn usage_to_ascii(usage: u8, modifiers: u8) -> Option<u8> {
+    const DIGITS: &[u8; 10] = b"1234567890";
+    const DIGITS_SHIFTED: &[u8; 10] = b"!@#$%^&*()";
+    const SYMBOLS: &[u8; 12] = b"-=[]\\#;'`,./";
+    const SYMBOLS_SHIFTED: &[u8; 12] = b"_+{}|~:\"~<>?";
+    
+    let shift = modifiers & MOD_SHIFT != 0;
+    let byte = match usage {
+        0x04..=0x1D => {
+            let letter = b'a' + (usage - 0x04);
+            if modifiers & MOD_CTRL != 0 {
+                letter & 0x1F
+            } else if shift {
+                letter.to_ascii_uppercase()
+            } else {
+                letter
+            }
+        }
+        0x1E..=0x27 => {
+            let index = (usage - 0x1E) as usize;
+            if shift { DIGITS_SHIFTED[index] } else { DIGITS[index] }
+        }
+        0x28 => b'\n',
+        0x29 => 0x1B, // Escape
+        0x2A => 0x08, // Backspace
+        0x2B => b'\t',
+        0x2C => b' ',
+        0x2D..=0x38 => {
+            let index = (usage - 0x2D) as usize;
+            if shift { SYMBOLS_SHIFTED[index] } else { SYMBOLS[index] }
+        }
+        _ => return None,
+    };
+    Some(byte)
+}
+
+// Kernel thread: service every keyboard's interrupt endpoint, then sleep
+// until the most frequent one is due again. Exits with the last keyboard.
+fn poll_keyboards() {
+    let mut report = [0u8; BOOT_REPORT_LEN];
+    loop {
+        let interval_ns = {
+            let mut keyboards = KEYBOARDS.lock();
+            keyboards.retain_mut(|keyboard| match keyboard.device.poll_in(keyboard.endpoint, &mut report) {
+                Ok(Some(len)) => {
+                    if len == BOOT_REPORT_LEN {
+                        keyboard.handle_report(&report);
+                    }
+                    true
+                }
+                Ok(None) => true,
+                Err(e) => {
+                    crate::println!("USB HID: Keyboard on port {} dropped: {}", keyboard.device.port(), e);
+                    false
+                }
+            });
+            match keyboards.iter().map(|keyboard| keyboard.interval_ns).min() {
+                Some(interval_ns) => interval_ns,
+                None => {
+                    // Cleared under the lock, so a probe either sees it or
+                    // its keyboard is seen here
+                    POLLER_STARTED.store(false, Ordering::SeqCst);
+                    return;
+                }
+            }
+        };
+        if timer::sleep_ns(interval_ns).is_err() {
+            yield_now();
+        }
+    }
+}
+
+/// Bind a boot-protocol keyboard interface.
+pub fn probe(device: &Arc<UsbDevice>, interface: &InterfaceDescriptor) -> Result<(), &'static str> {
+    if interface.subclass != HID_SUBCLASS_BOOT || interface.protocol != HID_PROTOCOL_KEYBOARD {
+        crate::println!("USB HID: Interface {} is not a boot keyboard, ignoring", interface.number);
+        return Ok(());
+    }
+    let endpoint = interface
+        .endpoints
+        .iter()
+        .find(|ep| ep.transfer_type() == TransferType::Interrupt && ep.is_in())
+        .ok_or("USB HID: No interrupt IN endpoint")?;
+    
+    let request = |request: u8, value: u16| SetupPacket {
+        request_type: USB_TYPE_CLASS | USB_RECIP_INTERFACE,
+        request,
+        value,
+        index: interface.number as u16,
+        length: 0,
+    };
+    device.control_out(request(HID_REQ_SET_PROTOCOL, HID_BOOT_PROTOCOL), &[])?;
+    // Report only on change; optional, so a stall is fine
+    let _ = device.control_out(request(HID_REQ_SET_IDLE, 0), &[]);
+    
+    KEYBOARDS.lock().push(Keyboard {
+        device: device.clone(),
+        endpoint: endpoint.address,
+        interval_ns: endpoint.interval_ns(device.speed()),
+        pressed: [0; 6],
+    });
+    crate::println!("USB HID: Keyboard on port {}", device.port());
+    
+    if !POLLER_STARTED.swap(true, Ordering::SeqCst) {
+        kthread_spawn(poll_keyboards, "usb-kbd", KTHREAD_DEFAULT_PRIORITY)?;
+    }
+    Ok(())
+}
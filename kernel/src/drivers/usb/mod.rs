@@ -0,0 +1,459 @@
+// USB core: descriptors, device enumeration and class driver binding
+
+pub mod xhci;
+pub mod storage;
+pub mod hid;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::Mutex;
+use crate::ktest::kernel_test;
+
+// bmRequestType fields
+pub const USB_DIR_IN: u8 = 0x80;
+pub const USB_TYPE_CLASS: u8 = 0x20;
+pub const USB_RECIP_INTERFACE: u8 = 0x01;
+pub const USB_RECIP_ENDPOINT: u8 = 0x02;
+
+// Standard requests
+pub const USB_REQ_CLEAR_FEATURE: u8 = 0x01;
+pub const USB_REQ_GET_DESCRIPTOR: u8 = 0x06;
+pub const USB_REQ_SET_CONFIGURATION: u8 = 0x09;
+
+// Descriptor types
+pub const USB_DT_DEVICE: u8 = 0x01;
+pub const USB_DT_CONFIG: u8 = 0x02;
+pub const USB_DT_INTERFACE: u8 = 0x04;
+pub const USB_DT_ENDPOINT: u8 = 0x05;
+
+// Feature selector for CLEAR_FEATURE on an endpoint
+const USB_ENDPOINT_HALT: u16 = 0;
+
+// Interface classes with drivers
+pub const USB_CLASS_HID: u8 = 0x03;
+pub const USB_CLASS_MASS_STORAGE: u8 = 0x08;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UsbSpeed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+/// The 8-byte SETUP packet of a control transfer.
+#[derive(Copy, Clone, Debug)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// Packed little-endian form, as placed in an immediate-data TRB.
+    pub fn to_u64(self) -> u64 {
+        (self.request_type as u64)
+            | ((self.request as u64) << 8)
+            | ((self.value as u64) << 16)
+            | ((self.index as u64) << 32)
+            | ((self.length as u64) << 48)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TransferType {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn number(&self) -> u8 {
+        self.address & 0x0F
+    }
+    
+    pub fn is_in(&self) -> bool {
+        self.address & USB_DIR_IN != 0
+    }
+    
+    /// Polling period of an interrupt endpoint: bInterval frames at low
+    /// and full speed, 2^(bInterval-1) microframes above.
+    pub fn interval_ns(&self, speed: UsbSpeed) -> u64 {
+        match speed {
+            UsbSpeed::Low | UsbSpeed::Full => self.interval.max(1) as u64 * 1_000_000,
+            UsbSpeed::High | UsbSpeed::Super => 125_000 << (self.interval.clamp(1, 16) - 1),
+        }
+    }
+    
+    pub fn transfer_type(&self) -> TransferType {
+        match self.attributes & 0x3 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alt_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub num_configurations: u8,
+}
+
+/// Operations a host controller driver provides to the USB core.
+///
+/// Endpoints are addressed by their descriptor address (number plus
+/// direction bit); endpoint 0 is the default control pipe.
+pub trait UsbHostController: Send + Sync {
+    fn control_in(&self, slot: u8, setup: SetupPacket, buf: &mut [u8]) -> Result<usize, &'static str>;
+    fn control_out(&self, slot: u8, setup: SetupPacket, data: &[u8]) -> Result<(), &'static str>;
+    
+    /// Fix up the default pipe once the real bMaxPacketSize0 is known.
+    fn set_max_packet_size0(&self, slot: u8, max_packet_size: u16) -> Result<(), &'static str>;
+    
+    /// Set up transfer rings for the endpoints of the active configuration.
+    fn configure_endpoints(&self, slot: u8, endpoints: &[EndpointDescriptor]) -> Result<(), &'static str>;
+    
+    fn transfer_in(&self, slot: u8, endpoint: u8, buf: &mut [u8]) -> Result<usize, &'static str>;
+    fn transfer_out(&self, slot: u8, endpoint: u8, data: &[u8]) -> Result<(), &'static str>;
+    
+    /// Non-blocking IN transfer for interrupt endpoints: queues a transfer
+    /// if none is outstanding and returns its data once complete.
+    fn poll_in(&self, slot: u8, endpoint: u8, buf: &mut [u8]) -> Result<Option<usize>, &'static str>;
+    
+    /// Clear the host side of a halted endpoint.
+    fn reset_endpoint(&self, slot: u8, endpoint: u8) -> Result<(), &'static str>;
+}
+
+/// An addressed and configured USB device.
+pub struct UsbDevice {
+    host: Arc<dyn UsbHostController>,
+    slot: u8,
+    speed: UsbSpeed,
+    port: u8,
+    descriptor: DeviceDescriptor,
+    interfaces: Vec<InterfaceDescriptor>,
+}
+
+impl UsbDevice {
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+    
+    pub fn speed(&self) -> UsbSpeed {
+        self.speed
+    }
+    
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+    
+    pub fn descriptor(&self) -> &DeviceDescriptor {
+        &self.descriptor
+    }
+    
+    pub fn interfaces(&self) -> &[InterfaceDescriptor] {
+        &self.interfaces
+    }
+    
+    pub fn control_in(&self, setup: SetupPacket, buf: &mut [u8]) -> Result<usize, &'static str> {
+        self.host.control_in(self.slot, setup, buf)
+    }
+    
+    pub fn control_out(&self, setup: SetupPacket, data: &[u8]) -> Result<(), &'static str> {
+        self.host.control_out(self.slot, setup, data)
+    }
+    
+    pub fn transfer_in(&self, endpoint: u8, buf: &mut [u8]) -> Result<usize, &'static str> {
+        self.host.transfer_in(self.slot, endpoint, buf)
+    }
+    
+    pub fn transfer_out(&self, endpoint: u8, data: &[u8]) -> Result<(), &'static str> {
+        self.host.transfer_out(self.slot, endpoint, data)
+    }
+    
+    pub fn poll_in(&self, endpoint: u8, buf: &mut [u8]) -> Result<Option<usize>, &'static str> {
+        self.host.poll_in(self.slot, endpoint, buf)
+    }
+    
+    /// Recover a stalled endpoint on both the device and the host.
+    pub fn clear_halt(&self, endpoint: u8) -> Result<(), &'static str> {
+        self.control_out(SetupPacket {
+            request_type: USB_RECIP_ENDPOINT,
+            request: USB_REQ_CLEAR_FEATURE,
+            value: USB_ENDPOINT_HALT,
+            index: endpoint as u16,
+            length: 0,
+        }, &[])?;
+        self.host.reset_endpoint(self.slot, endpoint)
+    }
+    
+    fn get_descriptor(&self, desc_type: u8, buf: &mut [u8]) -> Result<usize, &'static str> {
+        self.control_in(SetupPacket {
+            request_type: USB_DIR_IN,
+            request: USB_REQ_GET_DESCRIPTOR,
+            value: (desc_type as u16) << 8,
+            index: 0,
+            length: buf.len() as u16,
+        }, buf)
+    }
+}
+
+fn parse_device_descriptor(raw: &[u8]) -> DeviceDescriptor {
+    DeviceDescriptor {
+        usb_version: u16::from_le_bytes([raw[2], raw[3]]),
+        class: raw[4],
+        subclass: raw[5],
+        protocol: raw[6],
+        max_packet_size0: raw[7],
+        vendor_id: u16::from_le_bytes([raw[8], raw[9]]),
+        product_id: u16::from_le_bytes([raw[10], raw[11]]),
+        num_configurations: raw[17],
+    }
+}
+
+/// Walk a configuration descriptor and its trailing interface/endpoint
+/// descriptors. Only alternate setting 0 is kept.
+pub fn parse_configuration(raw: &[u8]) -> Vec<InterfaceDescriptor> {
+    let mut interfaces: Vec<InterfaceDescriptor> = Vec::new();
+    let mut offset = 0;
+    while offset + 2 <= raw.len() {
+        let len = raw[offset] as usize;
+        if len < 2 || offset + len > raw.len() {
+            break;
+        }
+        let desc = &raw[offset..offset + len];
+        match desc[1] {
+            USB_DT_INTERFACE if len >= 9 => interfaces.push(InterfaceDescriptor {
+                number: desc[2],
+                alt_setting: desc[3],
+                class: desc[5],
+                subclass: desc[6],
+                protocol: desc[7],
+                endpoints: Vec::new(),
+            }),
+            USB_DT_ENDPOINT if len >= 7 => {
+                if let Some(interface) = interfaces.last_mut() {
+                    interface.endpoints.push(EndpointDescriptor {
+                        address: desc[2],
+                        attributes: desc[3],
+                        max_packet_size: u16::from_le_bytes([desc[4], desc[5]]) & 0x7FF,
+                        interval: desc[6],
+                    });
+                }
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    interfaces.retain(|interface| interface.alt_setting == 0);
+    interfaces
+}
+
+// Every configured device, for /proc/usb
+static DEVICES: Mutex<Vec<Arc<UsbDevice>>> = Mutex::new(Vec::new());
+
+/// Publish the device list as /proc/usb.
+pub fn init() {
+    let _ = crate::procfs::register("usb", proc_usb);
+}
+
+fn proc_usb(out: &mut Vec<u8>) {
+    let mut text = String::new();
+    for device in DEVICES.lock().iter() {
+        let desc = device.descriptor();
+        let _ = writeln!(text, "slot {} port {} {:04x}:{:04x} usb {:x}.{:02x} {:?} class {:02x}/{:02x}/{:02x} ep0 {} configs {}",
+                         device.slot(), device.port(), desc.vendor_id, desc.product_id,
+                         desc.usb_version >> 8, desc.usb_version & 0xFF, device.speed(),
+                         desc.class, desc.subclass, desc.protocol,
+                         desc.max_packet_size0, desc.num_configurations);
+        for interface in device.interfaces() {
+            let _ = write!(text, "  if {} class {:02x}/{:02x}/{:02x}",
+                           interface.number, interface.class, interface.subclass, interface.protocol);
+            for endpoint in &interface.endpoints {
+                let dir = if endpoint.is_in() { "in" } else { "out" };
+                let _ = write!(text, " ep{}{} {:?}", endpoint.number(), dir, endpoint.transfer_type());
+            }
+            let _ = writeln!(text);
+        }
+    }
+    out.extend_from_slice(text.as_bytes());
+}
+
+/// Enumerate a newly addressed device and bind class drivers to it.
+///
+/// Called by a host controller driver after it has assigned the device
+/// an address on `slot`.
+pub fn attach_device(host: Arc<dyn UsbHostController>, slot: u8, speed: UsbSpeed, port: u8) -> Result<(), &'static str> {
+    let mut device = UsbDevice {
+        host,
+        slot,
+        speed,
+        port,
+        descriptor: DeviceDescriptor::default(),
+        interfaces: Vec::new(),
+    };
+    
+    // Full-speed devices may use an 8..64 byte EP0; learn it from the
+    // first 8 bytes before reading the whole descriptor
+    let mut raw = [0u8; 18];
+    device.get_descriptor(USB_DT_DEVICE, &mut raw[..8])?;
+    let max_packet_size0 = if speed == UsbSpeed::Super { 1 << raw[7] } else { raw[7] as u16 };
+    device.host.set_max_packet_size0(slot, max_packet_size0)?;
+    
+    if device.get_descriptor(USB_DT_DEVICE, &mut raw)? < raw.len() {
+        return Err("USB: Short device descriptor");
+    }
+    device.descriptor = parse_device_descriptor(&raw);
+    
+    // Configuration header first for its total length, then all of it
+    let mut header = [0u8; 9];
+    device.get_descriptor(USB_DT_CONFIG, &mut header)?;
+    let total_len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    let config_value = header[5];
+    let mut config = vec![0u8; total_len];
+    let len = device.get_descriptor(USB_DT_CONFIG, &mut config)?;
+    device.interfaces = parse_configuration(&config[..len]);
+    
+    let endpoints: Vec<EndpointDescriptor> = device
+        .interfaces
+        .iter()
+        .flat_map(|interface| interface.endpoints.iter().copied())
+        .collect();
+    device.host.configure_endpoints(slot, &endpoints)?;
+    device.control_out(SetupPacket {
+        request_type: 0,
+        request: USB_REQ_SET_CONFIGURATION,
+        value: config_value as u16,
+        index: 0,
+        length: 0,
+    }, &[])?;
+    
+    crate::println!("USB: Device {:04x}:{:04x} on port {} ({:?} speed, {} interfaces)",
+                   device.descriptor.vendor_id, device.descriptor.product_id,
+                   port, speed, device.interfaces.len());
+    
+    let device = Arc::new(device);
+    DEVICES.lock().push(device.clone());
+    for interface in device.interfaces() {
+        let result = match interface.class {
+            USB_CLASS_MASS_STORAGE => storage::probe(&device, interface),
+            USB_CLASS_HID => hid::probe(&device, interface),
+            _ => {
+                crate::println!("USB: No driver for interface {} (class {:02x})",
+                               interface.number, interface.class);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            crate::println!("USB: Interface {} probe failed: {}", interface.number, e);
+        }
+    }
+    Ok(())
+}
+
+#[kernel_test]
+fn test_usb_hid_parsing() {
+    use alloc::vec::Vec;
+    use crate::drivers::usb::hid::usage_to_ascii;
+    use crate::drivers::usb::{parse_configuration, TransferType, UsbSpeed};
+    
+    crate::println!("USB Test: Testing USB descriptor and keyboard report parsing...");
+    
+    // (usage, modifiers, expected): left shift 0x02, right shift 0x20,
+    // left ctrl 0x01
+    let keys: [(u8, u8, Option<u8>); 12] = [
+        (0x04, 0x00, Some(b'a')),
+        (0x1D, 0x02, Some(b'Z')),
+        (0x06, 0x01, Some(0x03)),
+        (0x1E, 0x00, Some(b'1')),
+        (0x1E, 0x20, Some(b'!')),
+        (0x27, 0x00, Some(b'0')),
+        (0x28, 0x00, Some(b'\n')),
+        (0x2A, 0x00, Some(0x08)),
+        (0x2D, 0x00, Some(b'-')),
+        (0x38, 0x02, Some(b'?')),
+        (0x39, 0x00, None),
+        (0x00, 0x00, None),
+    ];
+    let wrong: Vec<_> = keys.iter().filter(|&&(usage, modifiers, expected)| usage_to_ascii(usage, modifiers) != expected).collect();
+    if wrong.is_empty() {
+        crate::println!("USB Test: ✓ Letters, shifted digits, Ctrl, symbols and unmapped usages translate");
+    } else {
+        crate::println!("USB Test: ✗ Usages translated wrongly: {:x?}", wrong);
+    }
+    
+    // A boot keyboard (with its HID class descriptor and an alternate
+    // setting) and a bulk-only interface, then a descriptor cut short
+    let config = [
+        9, 0x02, 78, 0, 2, 1, 0, 0xA0, 50,
+        9, 0x04, 0, 0, 1, 0x03, 1, 1, 0,
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0,
+        7, 0x05, 0x81, 0x03, 8, 0, 10,
+        9, 0x04, 0, 1, 1, 0x03, 0, 0, 0,
+        7, 0x05, 0x83, 0x03, 64, 0, 1,
+        9, 0x04, 1, 0, 2, 0x08, 6, 0x50, 0,
+        7, 0x05, 0x82, 0x02, 0x00, 0x02, 0,
+        7, 0x05, 0x02, 0x02, 0x00, 0x02, 0,
+        7, 0x05, 0x84, 0x03, 8,
+    ];
+    let interfaces = parse_configuration(&config);
+    let keyboard = interfaces.first().filter(|interface| {
+        interface.number == 0 && interface.class == 0x03 && interface.subclass == 1 && interface.protocol == 1
+    });
+    let endpoint = keyboard.and_then(|interface| interface.endpoints.first().copied());
+    let endpoint_ok = keyboard.is_some_and(|interface| interface.endpoints.len() == 1) && endpoint.is_some_and(|ep| {
+        ep.address == 0x81 && ep.is_in() && ep.transfer_type() == TransferType::Interrupt && ep.max_packet_size == 8
+    });
+    let intervals = endpoint.map(|ep| (ep.interval_ns(UsbSpeed::Full), ep.interval_ns(UsbSpeed::High)));
+    if interfaces.len() == 2 && endpoint_ok && intervals == Some((10_000_000, 64_000_000)) {
+        crate::println!("USB Test: ✓ Keyboard interface found, alternate setting dropped, 10 ms interval");
+    } else {
+        crate::println!("USB Test: ✗ {} interfaces, endpoint {:x?}, intervals {:?}",
+                       interfaces.len(), endpoint, intervals);
+    }
+    let storage = interfaces.get(1).filter(|interface| interface.number == 1 && interface.class == 0x08);
+    let bulk = storage.is_some_and(|interface| {
+        interface.endpoints.len() == 2
+            && interface.endpoints.iter().all(|ep| ep.transfer_type() == TransferType::Bulk && ep.max_packet_size == 512)
+            && interface.endpoints[0].is_in() && !interface.endpoints[1].is_in()
+    });
+    if bulk {
+        crate::println!("USB Test: ✓ Bulk interface kept both endpoints; truncated descriptor ignored");
+    } else {
+        crate::println!("USB Test: ✗ Storage interface parsed as {:x?}", storage);
+    }
+    
+    crate::println!("USB Test: USB parsing test completed");
+}
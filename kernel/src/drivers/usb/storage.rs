@@ -0,0 +1,262 @@
+// USB mass storage: SCSI block commands over the Bulk-Only Transport
+
+use alloc::format;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::block::{self, check_request, BlockDevice};
+use crate::memory::frame_allocator::PAGE_SIZE;
+use super::{InterfaceDescriptor, SetupPacket, TransferType, UsbDevice, USB_RECIP_INTERFACE, USB_TYPE_CLASS};
+
+const USB_SUBCLASS_SCSI: u8 = 0x06;
+const USB_PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+// Bulk-Only Mass Storage Reset class request
+const BOT_REQ_RESET: u8 = 0xFF;
+
+// Command and status wrappers
+const CBW_SIGNATURE: u32 = 0x4342_5355; // "USBC"
+const CSW_SIGNATURE: u32 = 0x5342_5355; // "USBS"
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+const CBW_FLAG_DATA_IN: u8 = 0x80;
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+
+// SCSI operation codes
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+const SCSI_SYNCHRONIZE_CACHE_10: u8 = 0x35;
+
+const INQUIRY_LEN: usize = 36;
+const SENSE_LEN: usize = 18;
+const READY_RETRIES: usize = 10;
+
+// The host controller bounces each transfer through one page
+const MAX_TRANSFER: usize = PAGE_SIZE;
+
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+enum DataPhase<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+struct BulkOnly {
+    device: Arc<UsbDevice>,
+    interface: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    tag: u32,
+}
+
+impl BulkOnly {
+    // Run one SCSI command, returning the number of data bytes moved
+    fn command(&mut self, cdb: &[u8], data: DataPhase) -> Result<usize, &'static str> {
+        self.tag = self.tag.wrapping_add(1);
+        let (len, flags) = match &data {
+            DataPhase::None => (0, 0),
+            DataPhase::In(buf) => (buf.len(), CBW_FLAG_DATA_IN),
+            DataPhase::Out(buf) => (buf.len(), 0),
+        };
+        
+        let mut cbw = [0u8; CBW_LEN];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        cbw[12] = flags;
+        cbw[14] = cdb.len() as u8;
+        cbw[15..15 + cdb.len()].copy_from_slice(cdb);
+        if let Err(e) = self.device.transfer_out(self.bulk_out, &cbw) {
+            self.reset_recovery();
+            return Err(e);
+        }
+        
+        // A stalled data phase still ends with a status wrapper
+        let transferred = match data {
+            DataPhase::None => 0,
+            DataPhase::In(buf) => match self.device.transfer_in(self.bulk_in, buf) {
+                Ok(n) => n,
+                Err(_) => {
+                    self.device.clear_halt(self.bulk_in)?;
+                    0
+                }
+            },
+            DataPhase::Out(buf) => match self.device.transfer_out(self.bulk_out, buf) {
+                Ok(()) => buf.len(),
+                Err(_) => {
+                    self.device.clear_halt(self.bulk_out)?;
+                    0
+                }
+            },
+        };
+        
+        // Retry the status phase once after a stall
+        let mut csw = [0u8; CSW_LEN];
+        let csw_len = match self.device.transfer_in(self.bulk_in, &mut csw) {
+            Ok(n) => n,
+            Err(_) => {
+                self.device.clear_halt(self.bulk_in)?;
+                self.device.transfer_in(self.bulk_in, &mut csw)?
+            }
+        };
+        let signature = u32::from_le_bytes([csw[0], csw[1], csw[2], csw[3]]);
+        let tag = u32::from_le_bytes([csw[4], csw[5], csw[6], csw[7]]);
+        if csw_len != CSW_LEN || signature != CSW_SIGNATURE || tag != self.tag {
+            self.reset_recovery();
+            return Err("USB storage: Invalid status wrapper");
+        }
+        
+        match csw[12] {
+            CSW_STATUS_PASSED => Ok(transferred),
+            CSW_STATUS_FAILED => Err("USB storage: Command failed"),
+            _ => {
+                self.reset_recovery();
+                Err("USB storage: Phase error")
+            }
+        }
+    }
+    
+    // Best-effort return to a known state after a transport error
+    fn reset_recovery(&self) {
+        let _ = self.device.control_out(SetupPacket {
+            request_type: USB_TYPE_CLASS | USB_RECIP_INTERFACE,
+            request: BOT_REQ_RESET,
+            value: 0,
+            index: self.interface as u16,
+            length: 0,
+        }, &[]);
+        let _ = self.device.clear_halt(self.bulk_in);
+        let _ = self.device.clear_halt(self.bulk_out);
+    }
+    
+    fn wait_ready(&mut self) -> Result<(), &'static str> {
+        let mut last_error = "USB storage: Unit not ready";
+        for _ in 0..READY_RETRIES {
+            match self.command(&[SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0], DataPhase::None) {
+                Ok(_) => return Ok(()),
+                Err(e) => last_error = e,
+            }
+            // Consume the pending sense data (typically UNIT ATTENTION)
+            let mut sense = [0u8; SENSE_LEN];
+            let _ = self.command(&[SCSI_REQUEST_SENSE, 0, 0, 0, SENSE_LEN as u8, 0], DataPhase::In(&mut sense));
+        }
+        Err(last_error)
+    }
+    
+    fn read_capacity(&mut self) -> Result<(u64, usize), &'static str> {
+        let mut data = [0u8; 8];
+        let cdb = [SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        if self.command(&cdb, DataPhase::In(&mut data))? < data.len() {
+            return Err("USB storage: Short capacity data");
+        }
+        let last_lba = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let block_size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        if block_size == 0 || block_size > MAX_TRANSFER {
+            return Err("USB storage: Unsupported block size");
+        }
+        Ok((last_lba as u64 + 1, block_size))
+    }
+}
+
+// READ(10)/WRITE(10) command block
+fn rw10_cdb(opcode: u8, lba: u64, count: usize) -> Result<[u8; 10], &'static str> {
+    let lba = u32::try_from(lba).map_err(|_| "USB storage: LBA beyond 32 bits")?;
+    let lba = lba.to_be_bytes();
+    let count = (count as u16).to_be_bytes();
+    Ok([opcode, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0])
+}
+
+pub struct UsbDisk {
+    transport: Mutex<BulkOnly>,
+    blocks: u64,
+    block_size: usize,
+}
+
+impl BlockDevice for UsbDisk {
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+    
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_request(self, lba, buf.len())?;
+        let mut transport = self.transport.lock();
+        let mut lba = lba;
+        for chunk in buf.chunks_mut(MAX_TRANSFER / self.block_size * self.block_size) {
+            let count = chunk.len() / self.block_size;
+            let cdb = rw10_cdb(SCSI_READ_10, lba, count)?;
+            if transport.command(&cdb, DataPhase::In(chunk))? != chunk.len() {
+                return Err("USB storage: Short read");
+            }
+            lba += count as u64;
+        }
+        Ok(())
+    }
+    
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_request(self, lba, buf.len())?;
+        let mut transport = self.transport.lock();
+        let mut lba = lba;
+        for chunk in buf.chunks(MAX_TRANSFER / self.block_size * self.block_size) {
+            let count = chunk.len() / self.block_size;
+            let cdb = rw10_cdb(SCSI_WRITE_10, lba, count)?;
+            if transport.command(&cdb, DataPhase::Out(chunk))? != chunk.len() {
+                return Err("USB storage: Short write");
+            }
+            lba += count as u64;
+        }
+        Ok(())
+    }
+    
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    
+    fn flush(&self) -> Result<(), &'static str> {
+        let cdb = [SCSI_SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        self.transport.lock().command(&cdb, DataPhase::None).map(|_| ())
+    }
+}
+
+/// Bind a SCSI/Bulk-Only interface and register it as a block device.
+pub fn probe(device: &Arc<UsbDevice>, interface: &InterfaceDescriptor) -> Result<(), &'static str> {
+    if interface.subclass != USB_SUBCLASS_SCSI || interface.protocol != USB_PROTOCOL_BULK_ONLY {
+        return Err("USB storage: Unsupported transport");
+    }
+    let bulk = |is_in: bool| {
+        interface
+            .endpoints
+            .iter()
+            .find(|ep| ep.transfer_type() == TransferType::Bulk && ep.is_in() == is_in)
+            .map(|ep| ep.address)
+    };
+    let mut transport = BulkOnly {
+        device: device.clone(),
+        interface: interface.number,
+        bulk_in: bulk(true).ok_or("USB storage: No bulk IN endpoint")?,
+        bulk_out: bulk(false).ok_or("USB storage: No bulk OUT endpoint")?,
+        tag: 0,
+    };
+    
+    let mut inquiry = [0u8; INQUIRY_LEN];
+    transport.command(&[SCSI_INQUIRY, 0, 0, 0, INQUIRY_LEN as u8, 0], DataPhase::In(&mut inquiry))?;
+    let vendor = core::str::from_utf8(&inquiry[8..16]).unwrap_or("?").trim();
+    let product = core::str::from_utf8(&inquiry[16..32]).unwrap_or("?").trim();
+    
+    transport.wait_ready()?;
+    let (blocks, block_size) = transport.read_capacity()?;
+    
+    let name = format!("sd{}", (b'a' + NEXT_DISK.fetch_add(1, Ordering::Relaxed) as u8) as char);
+    crate::println!("USB storage: {} is {} {} ({} x {} byte blocks)", name, vendor, product, blocks, block_size);
+    
+    block::register(&name, Arc::new(UsbDisk {
+        transport: Mutex::new(transport),
+        blocks,
+        block_size,
+    }))
+}
@@ -0,0 +1,930 @@
+// eXtensible Host Controller Interface (xHCI) USB host driver
+//
+// Polled operation: interrupter 0's event ring is drained by whichever
+// caller is waiting for a completion. Every endpoint gets one ring and one
+// bounce page, so a single transfer moves at most a page. Controllers come
+// from PCI (class 0C03, prog-if 30) or "generic-xhci" device tree nodes.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use spin::Mutex;
+use crate::devicetree::DeviceTree;
+use crate::drivers::pci;
+use crate::interrupts::{counter_frequency, counter_ticks, delay_us};
+use crate::memory::frame_allocator::{allocate_frame, PAGE_SIZE};
+use crate::memory::paging::{phys_to_virt, virt_to_phys};
+use super::{attach_device, EndpointDescriptor, SetupPacket, TransferType, UsbHostController, UsbSpeed};
+
+const PCI_PROG_IF_XHCI: u8 = 0x30;
+
+// Capability registers
+const CAP_CAPLENGTH: usize = 0x00;
+const CAP_HCSPARAMS1: usize = 0x04;
+const CAP_HCSPARAMS2: usize = 0x08;
+const CAP_HCCPARAMS1: usize = 0x10;
+const CAP_DBOFF: usize = 0x14;
+const CAP_RTSOFF: usize = 0x18;
+
+// Operational registers
+const OP_USBCMD: usize = 0x00;
+const OP_USBSTS: usize = 0x04;
+const OP_CRCR: usize = 0x18;
+const OP_DCBAAP: usize = 0x30;
+const OP_CONFIG: usize = 0x38;
+const OP_PORTSC: usize = 0x400; // + 0x10 per port, ports numbered from 1
+
+// Interrupter 0 runtime registers
+const RT_ERSTSZ: usize = 0x28;
+const RT_ERSTBA: usize = 0x30;
+const RT_ERDP: usize = 0x38;
+
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_HCRST: u32 = 1 << 1;
+const USBSTS_HCH: u32 = 1 << 0;
+const USBSTS_CNR: u32 = 1 << 11;
+const HCCPARAMS1_CSZ: u32 = 1 << 2;
+const ERDP_EHB: u64 = 1 << 3;
+
+// Port status and control bits
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_SPEED_SHIFT: u32 = 10;
+const PORTSC_PRC: u32 = 1 << 21;
+const PORTSC_CHANGE_BITS: u32 = 0x7F << 17; // Write-1-to-clear
+
+// TRB types
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_STOP_ENDPOINT: u32 = 15;
+const TRB_SET_TR_DEQUEUE: u32 = 16;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+
+// TRB control bits
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1; // Link TRBs only
+const TRB_ISP: u32 = 1 << 2;
+const TRB_IOC: u32 = 1 << 5;
+const TRB_IDT: u32 = 1 << 6;
+const TRB_DIR_IN: u32 = 1 << 16;
+const TRB_TRT_OUT: u32 = 2 << 16;
+const TRB_TRT_IN: u32 = 3 << 16;
+
+// Completion codes
+const CC_SUCCESS: u8 = 1;
+const CC_STALL: u8 = 6;
+const CC_SHORT_PACKET: u8 = 13;
+
+// Endpoint context types and states
+const EP_TYPE_ISOCH_OUT: u32 = 1;
+const EP_TYPE_BULK_OUT: u32 = 2;
+const EP_TYPE_INTERRUPT_OUT: u32 = 3;
+const EP_TYPE_CONTROL: u32 = 4;
+const EP_TYPE_ISOCH_IN: u32 = 5;
+const EP_TYPE_BULK_IN: u32 = 6;
+const EP_TYPE_INTERRUPT_IN: u32 = 7;
+const EP_STATE_RUNNING: u32 = 1;
+const EP_STATE_HALTED: u32 = 2;
+
+const RING_TRBS: usize = PAGE_SIZE / 16;
+const MAX_ENDPOINTS: usize = 32; // Device context indices 1..=31
+const RESET_TIMEOUT_US: u64 = 100_000;
+const COMMAND_TIMEOUT_US: u64 = 1_000_000;
+const TRANSFER_TIMEOUT_US: u64 = 5_000_000;
+
+fn read32(addr: usize) -> u32 {
+    unsafe { read_volatile(addr as *const u32) }
+}
+
+fn write32(addr: usize, value: u32) {
+    unsafe { write_volatile(addr as *mut u32, value) }
+}
+
+// 64-bit registers and context fields are written as two halves, low first
+fn write64(addr: usize, value: u64) {
+    write32(addr, value as u32);
+    write32(addr + 4, (value >> 32) as u32);
+}
+
+// Poll until `done` holds or the timeout expires
+fn wait_for(timeout_us: u64, mut done: impl FnMut() -> bool) -> Result<(), &'static str> {
+    let deadline = counter_ticks() + counter_frequency() * timeout_us / 1_000_000;
+    while !done() {
+        if counter_ticks() > deadline {
+            return Err("xHCI: Timeout");
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+// Zeroed page for controller data structures, by kernel virtual address
+fn dma_page() -> Result<usize, &'static str> {
+    let page = allocate_frame().ok_or("xHCI: Out of memory")?;
+    unsafe { core::ptr::write_bytes(page.as_ptr(), 0, PAGE_SIZE) };
+    Ok(page.as_ptr() as usize)
+}
+
+// Address the controller uses for a `dma_page` location
+fn bus_addr(virt: usize) -> u64 {
+    virt_to_phys(virt as u64)
+}
+
+// Device context index of an endpoint address
+fn endpoint_dci(address: u8) -> usize {
+    let number = (address & 0x0F) as usize;
+    if number == 0 {
+        1
+    } else {
+        number * 2 + (address >> 7) as usize
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(parameter: u64, status: u32, trb_type: u32, flags: u32) -> Self {
+        Self {
+            parameter,
+            status,
+            control: (trb_type << 10) | flags,
+        }
+    }
+    
+    fn trb_type(&self) -> u32 {
+        (self.control >> 10) & 0x3F
+    }
+    
+    fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+    
+    fn slot_id(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+}
+
+// Producer ring (command or transfer) of one page closed by a Link TRB
+struct Ring {
+    base: usize,
+    index: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new() -> Result<Self, &'static str> {
+        Ok(Self {
+            base: dma_page()?,
+            index: 0,
+            cycle: true,
+        })
+    }
+    
+    fn write_trb(&self, index: usize, trb: Trb) {
+        // The control word carries the cycle bit, so it goes last
+        let addr = self.base + index * 16;
+        write64(addr, trb.parameter);
+        write32(addr + 8, trb.status);
+        write32(addr + 12, trb.control);
+    }
+    
+    /// Hand a TRB to the controller, returning its bus address.
+    fn push(&mut self, mut trb: Trb) -> u64 {
+        trb.control = (trb.control & !TRB_CYCLE) | self.cycle as u32;
+        let addr = bus_addr(self.base + self.index * 16);
+        self.write_trb(self.index, trb);
+        
+        self.index += 1;
+        if self.index == RING_TRBS - 1 {
+            let link = Trb::new(bus_addr(self.base), 0, TRB_LINK, TRB_TOGGLE_CYCLE | self.cycle as u32);
+            self.write_trb(self.index, link);
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        addr
+    }
+    
+    /// Next enqueue position with the cycle state, as a dequeue pointer.
+    fn dequeue_pointer(&self) -> u64 {
+        bus_addr(self.base + self.index * 16) | self.cycle as u64
+    }
+}
+
+// Consumer side of the single-segment event ring
+struct EventRing {
+    base: usize,
+    index: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn pop(&mut self) -> Option<Trb> {
+        let addr = self.base + self.index * 16;
+        let control = read32(addr + 12);
+        if (control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+        let trb = Trb {
+            parameter: read32(addr) as u64 | ((read32(addr + 4) as u64) << 32),
+            status: read32(addr + 8),
+            control,
+        };
+        
+        self.index += 1;
+        if self.index == RING_TRBS {
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+    
+    fn dequeue_pointer(&self) -> u64 {
+        bus_addr(self.base + self.index * 16)
+    }
+}
+
+struct CommandEvent {
+    trb: u64,
+    code: u8,
+    slot: u8,
+}
+
+struct TransferEvent {
+    trb: u64,
+    code: u8,
+    slot: u8,
+    dci: usize,
+    residual: usize,
+}
+
+struct Slot {
+    port: u8,
+    speed: UsbSpeed,
+    max_packet_size0: u16,
+    output_ctx: usize,
+    input_ctx: usize,
+    rings: [Option<Ring>; MAX_ENDPOINTS],
+    // Bounce page per endpoint, 0 if unconfigured
+    buffers: [usize; MAX_ENDPOINTS],
+    // Outstanding poll_in() TRB per endpoint
+    pending: [Option<u64>; MAX_ENDPOINTS],
+}
+
+impl Slot {
+    fn new(port: u8, speed: UsbSpeed) -> Result<Self, &'static str> {
+        let mut slot = Self {
+            port,
+            speed,
+            max_packet_size0: 0,
+            output_ctx: dma_page()?,
+            input_ctx: dma_page()?,
+            rings: core::array::from_fn(|_| None),
+            buffers: [0; MAX_ENDPOINTS],
+            pending: [None; MAX_ENDPOINTS],
+        };
+        slot.rings[1] = Some(Ring::new()?);
+        slot.buffers[1] = dma_page()?;
+        Ok(slot)
+    }
+    
+    fn clear_input(&self) {
+        unsafe { core::ptr::write_bytes(self.input_ctx as *mut u8, 0, PAGE_SIZE) };
+    }
+    
+    fn speed_id(&self) -> u32 {
+        match self.speed {
+            UsbSpeed::Full => 1,
+            UsbSpeed::Low => 2,
+            UsbSpeed::High => 3,
+            UsbSpeed::Super => 4,
+        }
+    }
+    
+    // Fill the input slot context for `context_entries` endpoint contexts
+    fn write_slot_context(&self, ctx_size: usize, context_entries: usize) {
+        let ctx = self.input_ctx + ctx_size;
+        write32(ctx, (self.speed_id() << 20) | ((context_entries as u32) << 27));
+        write32(ctx + 4, (self.port as u32) << 16);
+    }
+}
+
+struct XhciInner {
+    op: usize,
+    doorbells: usize,
+    runtime: usize,
+    ctx_size: usize,
+    max_ports: u8,
+    dcbaa: usize,
+    command_ring: Ring,
+    event_ring: EventRing,
+    command_events: Vec<CommandEvent>,
+    transfer_events: VecDeque<TransferEvent>,
+    slots: Vec<Option<Slot>>,
+}
+
+impl XhciInner {
+    fn portsc(&self, port: u8) -> usize {
+        self.op + OP_PORTSC + 0x10 * (port as usize - 1)
+    }
+    
+    fn ring_doorbell(&self, slot: u8, target: usize) {
+        unsafe { core::arch::asm!("dsb sy") };
+        write32(self.doorbells + 4 * slot as usize, target as u32);
+    }
+    
+    fn slot(&self, slot: u8) -> Result<&Slot, &'static str> {
+        self.slots
+            .get(slot as usize)
+            .and_then(|s| s.as_ref())
+            .ok_or("xHCI: Invalid slot")
+    }
+    
+    fn slot_mut(&mut self, slot: u8) -> Result<&mut Slot, &'static str> {
+        self.slots
+            .get_mut(slot as usize)
+            .and_then(|s| s.as_mut())
+            .ok_or("xHCI: Invalid slot")
+    }
+    
+    fn endpoint_state(&self, slot: u8, dci: usize) -> Result<u32, &'static str> {
+        let output_ctx = self.slot(slot)?.output_ctx;
+        Ok(read32(output_ctx + dci * self.ctx_size) & 0x7)
+    }
+    
+    // Sort completions into per-command and per-endpoint queues
+    fn process_events(&mut self) {
+        let mut consumed = false;
+        while let Some(trb) = self.event_ring.pop() {
+            consumed = true;
+            match trb.trb_type() {
+                TRB_COMMAND_COMPLETION => self.command_events.push(CommandEvent {
+                    trb: trb.parameter,
+                    code: trb.completion_code(),
+                    slot: trb.slot_id(),
+                }),
+                TRB_TRANSFER_EVENT => self.transfer_events.push_back(TransferEvent {
+                    trb: trb.parameter,
+                    code: trb.completion_code(),
+                    slot: trb.slot_id(),
+                    dci: ((trb.control >> 16) & 0x1F) as usize,
+                    residual: (trb.status & 0xFF_FFFF) as usize,
+                }),
+                // Port changes are not tracked; ports are scanned once at probe
+                _ => {}
+            }
+        }
+        if consumed {
+            write64(self.runtime + RT_ERDP, self.event_ring.dequeue_pointer() | ERDP_EHB);
+        }
+    }
+}
+
+pub struct Xhci {
+    inner: Mutex<XhciInner>,
+}
+
+impl Xhci {
+    /// Reset and start the controller at `base`.
+    pub fn new(base: usize) -> Result<Self, &'static str> {
+        let cap_length = (read32(base + CAP_CAPLENGTH) & 0xFF) as usize;
+        let hcsparams1 = read32(base + CAP_HCSPARAMS1);
+        let hcsparams2 = read32(base + CAP_HCSPARAMS2);
+        let max_slots = hcsparams1 & 0xFF;
+        let max_ports = (hcsparams1 >> 24) as u8;
+        let scratchpads = (((hcsparams2 >> 21) & 0x1F) << 5 | ((hcsparams2 >> 27) & 0x1F)) as usize;
+        let ctx_size = if read32(base + CAP_HCCPARAMS1) & HCCPARAMS1_CSZ != 0 { 64 } else { 32 };
+        
+        let op = base + cap_length;
+        let runtime = base + (read32(base + CAP_RTSOFF) & !0x1F) as usize;
+        let doorbells = base + (read32(base + CAP_DBOFF) & !0x3) as usize;
+        
+        // Stop, then reset
+        write32(op + OP_USBCMD, read32(op + OP_USBCMD) & !USBCMD_RUN);
+        wait_for(RESET_TIMEOUT_US, || read32(op + OP_USBSTS) & USBSTS_HCH != 0)?;
+        write32(op + OP_USBCMD, USBCMD_HCRST);
+        wait_for(RESET_TIMEOUT_US, || {
+            read32(op + OP_USBCMD) & USBCMD_HCRST == 0 && read32(op + OP_USBSTS) & USBSTS_CNR == 0
+        })?;
+        
+        write32(op + OP_CONFIG, max_slots);
+        
+        // Device context base address array, entry 0 for the scratchpad
+        let dcbaa = dma_page()?;
+        if scratchpads > 0 {
+            let array = dma_page()?;
+            for i in 0..scratchpads {
+                write64(array + i * 8, bus_addr(dma_page()?));
+            }
+            write64(dcbaa, bus_addr(array));
+        }
+        write64(op + OP_DCBAAP, bus_addr(dcbaa));
+        
+        let command_ring = Ring::new()?;
+        write64(op + OP_CRCR, command_ring.dequeue_pointer());
+        
+        // One event ring segment described by a one-entry table
+        let event_ring = EventRing {
+            base: dma_page()?,
+            index: 0,
+            cycle: true,
+        };
+        let erst = dma_page()?;
+        write64(erst, bus_addr(event_ring.base));
+        write32(erst + 8, RING_TRBS as u32);
+        write32(runtime + RT_ERSTSZ, 1);
+        write64(runtime + RT_ERDP, event_ring.dequeue_pointer());
+        write64(runtime + RT_ERSTBA, bus_addr(erst));
+        
+        write32(op + OP_USBCMD, USBCMD_RUN);
+        wait_for(RESET_TIMEOUT_US, || read32(op + OP_USBSTS) & USBSTS_HCH == 0)?;
+        
+        let mut slots = Vec::new();
+        slots.resize_with(max_slots as usize + 1, || None);
+        
+        Ok(Self {
+            inner: Mutex::new(XhciInner {
+                op,
+                doorbells,
+                runtime,
+                ctx_size,
+                max_ports,
+                dcbaa,
+                command_ring,
+                event_ring,
+                command_events: Vec::new(),
+                transfer_events: VecDeque::new(),
+                slots,
+            }),
+        })
+    }
+    
+    pub fn max_ports(&self) -> u8 {
+        self.inner.lock().max_ports
+    }
+    
+    // Drain events and poll `check` until it yields a value, letting other
+    // threads run in between
+    fn wait<T>(&self, timeout_us: u64, mut check: impl FnMut(&mut XhciInner) -> Option<T>) -> Result<T, &'static str> {
+        let deadline = counter_ticks() + counter_frequency() * timeout_us / 1_000_000;
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.process_events();
+                if let Some(value) = check(&mut inner) {
+                    return Ok(value);
+                }
+            }
+            if counter_ticks() > deadline {
+                return Err("xHCI: Timeout");
+            }
+            crate::process::yield_now();
+        }
+    }
+    
+    // Run a command, returning the slot ID from its completion
+    fn command(&self, trb: Trb) -> Result<u8, &'static str> {
+        let ptr = {
+            let mut inner = self.inner.lock();
+            let ptr = inner.command_ring.push(trb);
+            inner.ring_doorbell(0, 0);
+            ptr
+        };
+        let event = self.wait(COMMAND_TIMEOUT_US, |inner| {
+            let pos = inner.command_events.iter().position(|e| e.trb == ptr)?;
+            Some(inner.command_events.remove(pos))
+        })?;
+        if event.code != CC_SUCCESS {
+            return Err("xHCI: Command failed");
+        }
+        Ok(event.slot)
+    }
+    
+    // Wait for the TD ending at `last`, returning the residual byte count
+    fn wait_transfer(&self, slot: u8, dci: usize, last: u64) -> Result<usize, &'static str> {
+        let mut residual = 0;
+        self.wait(TRANSFER_TIMEOUT_US, |inner| {
+            while let Some(pos) = inner.transfer_events.iter().position(|e| e.slot == slot && e.dci == dci) {
+                let event = inner.transfer_events.remove(pos)?;
+                match event.code {
+                    CC_SUCCESS | CC_SHORT_PACKET => {
+                        if event.code == CC_SHORT_PACKET {
+                            residual = event.residual;
+                        }
+                        if event.trb == last {
+                            return Some(Ok(residual));
+                        }
+                    }
+                    CC_STALL => return Some(Err("USB: Endpoint stalled")),
+                    _ => return Some(Err("USB: Transfer error")),
+                }
+            }
+            None
+        })?
+    }
+    
+    // Bring a halted or stuck endpoint back to an empty, stopped ring
+    fn abort_endpoint(&self, slot: u8, dci: usize) -> Result<(), &'static str> {
+        let state = self.inner.lock().endpoint_state(slot, dci)?;
+        let endpoint = ((slot as u32) << 24) | ((dci as u32) << 16);
+        match state {
+            EP_STATE_HALTED => { self.command(Trb::new(0, 0, TRB_RESET_ENDPOINT, endpoint))?; }
+            EP_STATE_RUNNING => { self.command(Trb::new(0, 0, TRB_STOP_ENDPOINT, endpoint))?; }
+            _ => {}
+        }
+        
+        // Skip whatever is left of the failed TD
+        let dequeue = {
+            let mut inner = self.inner.lock();
+            let state = inner.slot_mut(slot)?;
+            state.pending[dci] = None;
+            state.rings[dci].as_ref().ok_or("xHCI: Endpoint not configured")?.dequeue_pointer()
+        };
+        self.command(Trb::new(dequeue, 0, TRB_SET_TR_DEQUEUE, endpoint))?;
+        
+        let mut inner = self.inner.lock();
+        inner.process_events();
+        inner.transfer_events.retain(|e| !(e.slot == slot && e.dci == dci));
+        Ok(())
+    }
+    
+    // Run a control transfer through EP0's bounce page; OUT data must
+    // already be in the page. Returns the data stage length.
+    fn control(&self, slot: u8, setup: SetupPacket, dir_in: bool) -> Result<usize, &'static str> {
+        let len = setup.length as usize;
+        if len > PAGE_SIZE {
+            return Err("USB: Control transfer too large");
+        }
+        
+        let last = {
+            let mut inner = self.inner.lock();
+            let state = inner.slot_mut(slot)?;
+            let page = state.buffers[1];
+            let ring = state.rings[1].as_mut().ok_or("xHCI: Endpoint not configured")?;
+            
+            let trt = match (len, dir_in) {
+                (0, _) => 0,
+                (_, true) => TRB_TRT_IN,
+                (_, false) => TRB_TRT_OUT,
+            };
+            ring.push(Trb::new(setup.to_u64(), 8, TRB_SETUP, TRB_IDT | trt));
+            if len > 0 {
+                let dir = if dir_in { TRB_DIR_IN } else { 0 };
+                ring.push(Trb::new(bus_addr(page), len as u32, TRB_DATA, TRB_ISP | dir));
+            }
+            // The status stage runs opposite to the data stage
+            let status_dir = if len > 0 && dir_in { 0 } else { TRB_DIR_IN };
+            let last = ring.push(Trb::new(0, 0, TRB_STATUS, TRB_IOC | status_dir));
+            inner.ring_doorbell(slot, 1);
+            last
+        };
+        
+        match self.wait_transfer(slot, 1, last) {
+            Ok(residual) => Ok(len - residual.min(len)),
+            Err(e) => {
+                let _ = self.abort_endpoint(slot, 1);
+                Err(e)
+            }
+        }
+    }
+    
+    // Queue a Normal TRB covering `len` bytes of the endpoint's bounce page
+    fn queue_normal(inner: &mut XhciInner, slot: u8, dci: usize, len: usize) -> Result<u64, &'static str> {
+        if len > PAGE_SIZE {
+            return Err("USB: Transfer too large");
+        }
+        let state = inner.slot_mut(slot)?;
+        let page = state.buffers[dci];
+        let ring = state.rings[dci].as_mut().ok_or("xHCI: Endpoint not configured")?;
+        let ptr = ring.push(Trb::new(bus_addr(page), len as u32, TRB_NORMAL, TRB_ISP | TRB_IOC));
+        inner.ring_doorbell(slot, dci);
+        Ok(ptr)
+    }
+    
+    fn normal(&self, slot: u8, dci: usize, len: usize) -> Result<usize, &'static str> {
+        let last = Self::queue_normal(&mut self.inner.lock(), slot, dci, len)?;
+        match self.wait_transfer(slot, dci, last) {
+            Ok(residual) => Ok(len - residual.min(len)),
+            Err(e) => {
+                let _ = self.abort_endpoint(slot, dci);
+                Err(e)
+            }
+        }
+    }
+    
+    fn buffer(&self, slot: u8, dci: usize) -> Result<usize, &'static str> {
+        match self.inner.lock().slot(slot)?.buffers[dci] {
+            0 => Err("xHCI: Endpoint not configured"),
+            page => Ok(page),
+        }
+    }
+    
+    fn reset_port(&self, port: u8) -> Result<(), &'static str> {
+        let portsc = self.inner.lock().portsc(port);
+        let status = read32(portsc);
+        let preserved = status & !(PORTSC_PED | PORTSC_CHANGE_BITS);
+        
+        // USB3 ports enable themselves after link training; USB2 ports
+        // need a bus reset first
+        if status & PORTSC_PED == 0 {
+            write32(portsc, preserved | PORTSC_PR);
+            wait_for(RESET_TIMEOUT_US, || read32(portsc) & PORTSC_PRC != 0)?;
+        }
+        write32(portsc, (read32(portsc) & !PORTSC_PED) | PORTSC_CHANGE_BITS);
+        
+        if read32(portsc) & PORTSC_PED == 0 {
+            return Err("xHCI: Port failed to enable");
+        }
+        Ok(())
+    }
+    
+    // Enable a slot for the device on `port` and give it an address
+    fn address_device(&self, port: u8, speed: UsbSpeed) -> Result<u8, &'static str> {
+        let slot_id = self.command(Trb::new(0, 0, TRB_ENABLE_SLOT, 0))?;
+        let mut slot = Slot::new(port, speed)?;
+        slot.max_packet_size0 = match speed {
+            UsbSpeed::Low | UsbSpeed::Full => 8,
+            UsbSpeed::High => 64,
+            UsbSpeed::Super => 512,
+        };
+        
+        let input_ctx = slot.input_ctx;
+        {
+            let mut inner = self.inner.lock();
+            let ctx_size = inner.ctx_size;
+            
+            // Add the slot and EP0 contexts
+            write32(input_ctx + 4, 0b11);
+            slot.write_slot_context(ctx_size, 1);
+            let ep0 = input_ctx + 2 * ctx_size;
+            write32(ep0 + 4, (3 << 1) | (EP_TYPE_CONTROL << 3) | ((slot.max_packet_size0 as u32) << 16));
+            write64(ep0 + 8, slot.rings[1].as_ref().ok_or("xHCI: No EP0 ring")?.dequeue_pointer());
+            write32(ep0 + 16, 8);
+            
+            write64(inner.dcbaa + 8 * slot_id as usize, bus_addr(slot.output_ctx));
+            *inner.slots.get_mut(slot_id as usize).ok_or("xHCI: Invalid slot")? = Some(slot);
+        }
+        
+        self.command(Trb::new(bus_addr(input_ctx), 0, TRB_ADDRESS_DEVICE, (slot_id as u32) << 24))?;
+        Ok(slot_id)
+    }
+    
+    fn scan_ports(self: &Arc<Self>) -> usize {
+        let mut count = 0;
+        for port in 1..=self.max_ports() {
+            let status = read32(self.inner.lock().portsc(port));
+            if status & PORTSC_CCS == 0 {
+                continue;
+            }
+            
+            let result = self.reset_port(port).and_then(|_| {
+                let status = read32(self.inner.lock().portsc(port));
+                let speed = match (status >> PORTSC_SPEED_SHIFT) & 0xF {
+                    1 => UsbSpeed::Full,
+                    2 => UsbSpeed::Low,
+                    3 => UsbSpeed::High,
+                    4 => UsbSpeed::Super,
+                    _ => return Err("xHCI: Unsupported port speed"),
+                };
+                let slot = self.address_device(port, speed)?;
+                let host: Arc<dyn UsbHostController> = self.clone();
+                attach_device(host, slot, speed, port)
+            });
+            match result {
+                Ok(()) => count += 1,
+                Err(e) => crate::println!("xHCI: Port {} device setup failed: {}", port, e),
+            }
+        }
+        count
+    }
+}
+
+// Interval field of an endpoint context, in 2^n * 125us units
+fn endpoint_interval(speed: UsbSpeed, endpoint: &EndpointDescriptor) -> u32 {
+    let interval = endpoint.interval.clamp(1, 16) as u32;
+    match (endpoint.transfer_type(), speed) {
+        // Full/low-speed interrupt bInterval counts 1ms frames
+        (TransferType::Interrupt, UsbSpeed::Low | UsbSpeed::Full) => {
+            let microframes = endpoint.interval.max(1) as u32 * 8;
+            (31 - microframes.leading_zeros()).clamp(3, 10)
+        }
+        (TransferType::Isochronous, UsbSpeed::Full) => interval - 1 + 3,
+        (TransferType::Interrupt | TransferType::Isochronous, _) => interval - 1,
+        _ => 0,
+    }
+}
+
+impl UsbHostController for Xhci {
+    fn control_in(&self, slot: u8, setup: SetupPacket, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let page = self.buffer(slot, 1)?;
+        let len = self.control(slot, setup, true)?.min(buf.len());
+        unsafe { core::ptr::copy_nonoverlapping(page as *const u8, buf.as_mut_ptr(), len) };
+        Ok(len)
+    }
+    
+    fn control_out(&self, slot: u8, setup: SetupPacket, data: &[u8]) -> Result<(), &'static str> {
+        if data.len() != setup.length as usize || data.len() > PAGE_SIZE {
+            return Err("USB: Bad control transfer length");
+        }
+        let page = self.buffer(slot, 1)?;
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), page as *mut u8, data.len()) };
+        self.control(slot, setup, false).map(|_| ())
+    }
+    
+    fn set_max_packet_size0(&self, slot: u8, max_packet_size: u16) -> Result<(), &'static str> {
+        let input_ctx = {
+            let mut inner = self.inner.lock();
+            let ctx_size = inner.ctx_size;
+            let state = inner.slot_mut(slot)?;
+            if state.max_packet_size0 == max_packet_size {
+                return Ok(());
+            }
+            state.max_packet_size0 = max_packet_size;
+            
+            state.clear_input();
+            write32(state.input_ctx + 4, 1 << 1);
+            let ep0 = state.input_ctx + 2 * ctx_size;
+            write32(ep0 + 4, (3 << 1) | (EP_TYPE_CONTROL << 3) | ((max_packet_size as u32) << 16));
+            state.input_ctx
+        };
+        self.command(Trb::new(bus_addr(input_ctx), 0, TRB_EVALUATE_CONTEXT, (slot as u32) << 24))?;
+        Ok(())
+    }
+    
+    fn configure_endpoints(&self, slot: u8, endpoints: &[EndpointDescriptor]) -> Result<(), &'static str> {
+        if endpoints.is_empty() {
+            return Ok(());
+        }
+        
+        let input_ctx = {
+            let mut inner = self.inner.lock();
+            let ctx_size = inner.ctx_size;
+            let state = inner.slot_mut(slot)?;
+            state.clear_input();
+            
+            let mut add_flags = 1; // Slot context
+            let mut max_dci = 1;
+            for endpoint in endpoints {
+                let dci = endpoint_dci(endpoint.address);
+                if state.rings[dci].is_none() {
+                    state.rings[dci] = Some(Ring::new()?);
+                    state.buffers[dci] = dma_page()?;
+                }
+                add_flags |= 1 << dci;
+                max_dci = max_dci.max(dci);
+                
+                let ep_type = match (endpoint.transfer_type(), endpoint.is_in()) {
+                    (TransferType::Isochronous, false) => EP_TYPE_ISOCH_OUT,
+                    (TransferType::Bulk, false) => EP_TYPE_BULK_OUT,
+                    (TransferType::Interrupt, false) => EP_TYPE_INTERRUPT_OUT,
+                    (TransferType::Control, _) => EP_TYPE_CONTROL,
+                    (TransferType::Isochronous, true) => EP_TYPE_ISOCH_IN,
+                    (TransferType::Bulk, true) => EP_TYPE_BULK_IN,
+                    (TransferType::Interrupt, true) => EP_TYPE_INTERRUPT_IN,
+                };
+                let max_packet = endpoint.max_packet_size as u32;
+                let (avg_trb_len, max_esit_payload) = match endpoint.transfer_type() {
+                    TransferType::Bulk => (3072, 0),
+                    _ => (max_packet, max_packet),
+                };
+                
+                let ctx = state.input_ctx + (dci + 1) * ctx_size;
+                write32(ctx, endpoint_interval(state.speed, endpoint) << 16);
+                write32(ctx + 4, (3 << 1) | (ep_type << 3) | (max_packet << 16));
+                write64(ctx + 8, state.rings[dci].as_ref().ok_or("xHCI: No ring")?.dequeue_pointer());
+                write32(ctx + 16, avg_trb_len | (max_esit_payload << 16));
+            }
+            
+            write32(state.input_ctx + 4, add_flags);
+            state.write_slot_context(ctx_size, max_dci);
+            state.input_ctx
+        };
+        self.command(Trb::new(bus_addr(input_ctx), 0, TRB_CONFIGURE_ENDPOINT, (slot as u32) << 24))?;
+        Ok(())
+    }
+    
+    fn transfer_in(&self, slot: u8, endpoint: u8, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let dci = endpoint_dci(endpoint);
+        let page = self.buffer(slot, dci)?;
+        let len = self.normal(slot, dci, buf.len())?;
+        unsafe { core::ptr::copy_nonoverlapping(page as *const u8, buf.as_mut_ptr(), len) };
+        Ok(len)
+    }
+    
+    fn transfer_out(&self, slot: u8, endpoint: u8, data: &[u8]) -> Result<(), &'static str> {
+        if data.len() > PAGE_SIZE {
+            return Err("USB: Transfer too large");
+        }
+        let dci = endpoint_dci(endpoint);
+        let page = self.buffer(slot, dci)?;
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), page as *mut u8, data.len()) };
+        if self.normal(slot, dci, data.len())? != data.len() {
+            return Err("USB: Short OUT transfer");
+        }
+        Ok(())
+    }
+    
+    fn poll_in(&self, slot: u8, endpoint: u8, buf: &mut [u8]) -> Result<Option<usize>, &'static str> {
+        let dci = endpoint_dci(endpoint);
+        let mut inner = self.inner.lock();
+        inner.process_events();
+        
+        let pending = inner.slot(slot)?.pending[dci];
+        let ptr = match pending {
+            Some(ptr) => ptr,
+            None => {
+                let ptr = Self::queue_normal(&mut inner, slot, dci, buf.len())?;
+                inner.slot_mut(slot)?.pending[dci] = Some(ptr);
+                return Ok(None);
+            }
+        };
+        
+        let pos = match inner.transfer_events.iter().position(|e| e.slot == slot && e.dci == dci && e.trb == ptr) {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        let event = inner.transfer_events.remove(pos).ok_or("xHCI: Lost event")?;
+        let state = inner.slot_mut(slot)?;
+        state.pending[dci] = None;
+        
+        match event.code {
+            CC_SUCCESS | CC_SHORT_PACKET => {
+                let len = buf.len() - event.residual.min(buf.len());
+                unsafe { core::ptr::copy_nonoverlapping(state.buffers[dci] as *const u8, buf.as_mut_ptr(), len) };
+                Ok(Some(len))
+            }
+            code => {
+                drop(inner);
+                let _ = self.abort_endpoint(slot, dci);
+                Err(if code == CC_STALL { "USB: Endpoint stalled" } else { "USB: Transfer error" })
+            }
+        }
+    }
+    
+    fn reset_endpoint(&self, slot: u8, endpoint: u8) -> Result<(), &'static str> {
+        self.abort_endpoint(slot, endpoint_dci(endpoint))
+    }
+}
+
+// Host controllers stay registered for the lifetime of the system
+static CONTROLLERS: Mutex<Vec<Arc<Xhci>>> = Mutex::new(Vec::new());
+
+fn attach(base: usize, count: &mut usize) {
+    match Xhci::new(base) {
+        Ok(xhci) => {
+            let xhci = Arc::new(xhci);
+            crate::println!("xHCI: Controller at 0x{:08x}, {} ports", base, xhci.max_ports());
+            
+            // Give freshly powered ports time to report connections
+            delay_us(20_000);
+            let devices = xhci.scan_ports();
+            crate::println!("xHCI: {} devices attached", devices);
+            
+            CONTROLLERS.lock().push(xhci);
+            *count += 1;
+        }
+        Err(e) => crate::println!("xHCI: Controller at 0x{:08x} failed: {}", base, e),
+    }
+}
+
+/// Start every xHCI controller and enumerate the devices on its ports.
+pub fn probe(dt: &DeviceTree) -> usize {
+    let mut count = 0;
+    for node in dt.find_compatible("generic-xhci") {
+        if !node.is_enabled() {
+            continue;
+        }
+        if let Some((base, _size)) = node.reg(0) {
+            attach(phys_to_virt(base) as usize, &mut count);
+        }
+    }
+    
+    for dev in pci::find_by_class(0x0C, 0x03) {
+        if dev.prog_if != PCI_PROG_IF_XHCI {
+            continue;
+        }
+        match dev.map_bar(0) {
+            Ok(base) => {
+                dev.enable();
+                attach(base, &mut count);
+            }
+            Err(e) => crate::println!("xHCI: PCI BAR setup failed: {}", e),
+        }
+    }
+    count
+}
@@ -0,0 +1,190 @@
+// virtio-blk: block devices on a virtio transport (QEMU virtio-blk-device)
+
+use alloc::format;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::block::{self, check_request, BlockDevice, SECTOR_SIZE};
+use crate::memory::frame_allocator::PAGE_SIZE;
+use super::{DmaBuffer, VirtioMmio, Virtqueue, VirtqBuffer, VIRTIO_F_VERSION_1};
+
+// Feature bits
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const FEATURES: u64 = VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+// Status byte written by the device
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Config space: capacity in 512-byte sectors
+const CONFIG_CAPACITY: usize = 0x00;
+
+const REQUEST_TIMEOUT_US: u64 = 1_000_000;
+
+// Bounce buffer size; larger requests are split
+const BOUNCE_FRAMES: usize = 16;
+const MAX_SECTORS_PER_REQUEST: usize = BOUNCE_FRAMES * PAGE_SIZE / SECTOR_SIZE;
+
+// Header and status share one page: header at 0, status byte after it
+const HEADER_OFFSET: usize = 0;
+const STATUS_OFFSET: usize = 16;
+
+#[repr(C)]
+struct VirtioBlkReqHeader {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+struct VirtioBlkQueue {
+    transport: VirtioMmio,
+    queue: Virtqueue,
+    request: DmaBuffer,
+    bounce: DmaBuffer,
+}
+
+impl VirtioBlkQueue {
+    // Issue one request, with `sectors` of data in the bounce buffer
+    fn submit(&mut self, request_type: u32, sector: u64, sectors: usize) -> Result<(), &'static str> {
+        if self.queue.is_stalled() {
+            self.restart()?;
+        }
+        let header = VirtioBlkReqHeader { request_type, reserved: 0, sector };
+        unsafe {
+            let base = self.request.as_ptr();
+            core::ptr::write_volatile(base.add(HEADER_OFFSET) as *mut VirtioBlkReqHeader, header);
+            core::ptr::write_volatile(base.add(STATUS_OFFSET), 0xFF);
+        }
+        
+        let header = VirtqBuffer {
+            addr: self.request.bus_addr(HEADER_OFFSET),
+            len: core::mem::size_of::<VirtioBlkReqHeader>() as u32,
+            device_writes: false,
+        };
+        let data = VirtqBuffer {
+            addr: self.bounce.bus_addr(0),
+            len: (sectors * SECTOR_SIZE) as u32,
+            device_writes: request_type == VIRTIO_BLK_T_IN,
+        };
+        let status = VirtqBuffer {
+            addr: self.request.bus_addr(STATUS_OFFSET),
+            len: 1,
+            device_writes: true,
+        };
+        
+        if sectors == 0 {
+            self.queue.submit_and_wait(&self.transport, &[header, status], REQUEST_TIMEOUT_US)?;
+        } else {
+            self.queue.submit_and_wait(&self.transport, &[header, data, status], REQUEST_TIMEOUT_US)?;
+        }
+        self.transport.ack_interrupt();
+        
+        match unsafe { core::ptr::read_volatile(self.request.as_ptr().add(STATUS_OFFSET)) } {
+            VIRTIO_BLK_S_OK => Ok(()),
+            VIRTIO_BLK_S_UNSUPP => Err("virtio-blk: Request not supported"),
+            _ => Err("virtio-blk: I/O error"),
+        }
+    }
+    
+    // Set the device up again after a timeout reset it. The stalled queue
+    // is freed as its replacement goes in; the device let go of it.
+    fn restart(&mut self) -> Result<(), &'static str> {
+        self.transport.negotiate(FEATURES)?;
+        self.queue = self.transport.setup_queue(0).inspect_err(|_| self.transport.fail())?;
+        self.transport.driver_ok();
+        crate::println!("virtio-blk: Device set up again after a timeout");
+        Ok(())
+    }
+}
+
+pub struct VirtioBlk {
+    queue: Mutex<VirtioBlkQueue>,
+    capacity: u64,
+    read_only: bool,
+    has_flush: bool,
+}
+
+impl VirtioBlk {
+    fn new(transport: VirtioMmio) -> Result<Self, &'static str> {
+        let features = transport.negotiate(FEATURES)?;
+        let queue = match transport.setup_queue(0) {
+            Ok(queue) => queue,
+            Err(e) => {
+                transport.fail();
+                return Err(e);
+            }
+        };
+        let request = DmaBuffer::new(1)?;
+        let bounce = DmaBuffer::new(BOUNCE_FRAMES)?;
+        transport.driver_ok();
+        
+        let capacity = transport.config_read64(CONFIG_CAPACITY);
+        Ok(Self {
+            queue: Mutex::new(VirtioBlkQueue { transport, queue, request, bounce }),
+            capacity,
+            read_only: features & VIRTIO_BLK_F_RO != 0,
+            has_flush: features & VIRTIO_BLK_F_FLUSH != 0,
+        })
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn num_blocks(&self) -> u64 {
+        self.capacity
+    }
+    
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_request(self, lba, buf.len())?;
+        let mut queue = self.queue.lock();
+        for (i, run) in buf.chunks_mut(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE).enumerate() {
+            let run_lba = lba + (i * MAX_SECTORS_PER_REQUEST) as u64;
+            queue.submit(VIRTIO_BLK_T_IN, run_lba, run.len() / SECTOR_SIZE)?;
+            run.copy_from_slice(&queue.bounce.as_slice()[..run.len()]);
+        }
+        Ok(())
+    }
+    
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_request(self, lba, buf.len())?;
+        if self.read_only {
+            return Err("virtio-blk: Device is read-only");
+        }
+        let mut queue = self.queue.lock();
+        for (i, run) in buf.chunks(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE).enumerate() {
+            let run_lba = lba + (i * MAX_SECTORS_PER_REQUEST) as u64;
+            queue.bounce.as_mut_slice()[..run.len()].copy_from_slice(run);
+            queue.submit(VIRTIO_BLK_T_OUT, run_lba, run.len() / SECTOR_SIZE)?;
+        }
+        Ok(())
+    }
+    
+    fn flush(&self) -> Result<(), &'static str> {
+        // Without FLUSH the device has no volatile write cache
+        if !self.has_flush {
+            return Ok(());
+        }
+        self.queue.lock().submit(VIRTIO_BLK_T_FLUSH, 0, 0)
+    }
+}
+
+// Next "vdX" letter
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// Bind a virtio-blk device and register it as vda, vdb, ...
+pub fn attach(transport: VirtioMmio) -> Result<(), &'static str> {
+    let legacy = transport.is_legacy();
+    let device = VirtioBlk::new(transport)?;
+    
+    let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+    let name = format!("vd{}", (b'a' + (index % 26) as u8) as char);
+    crate::println!("virtio-blk: {} ({}), {} sectors{}", name,
+                   if legacy { "legacy" } else { "modern" }, device.capacity,
+                   if device.read_only { ", read-only" } else { "" });
+    block::register(&name, Arc::new(device))
+}
@@ -0,0 +1,73 @@
+// virtio-rng: entropy from the host (QEMU virtio-rng-device)
+//
+// One request queue; each request is a buffer for the device to fill.
+// The first device found becomes an entropy source for rand.rs, which
+// reads it from thread context, so requests are polled under a plain lock.
+
+use spin::Mutex;
+use crate::memory::frame_allocator::PAGE_SIZE;
+use super::{DmaBuffer, VirtioMmio, Virtqueue, VirtqBuffer, VIRTIO_F_VERSION_1};
+
+const REQUEST_TIMEOUT_US: u64 = 100_000;
+
+struct VirtioRng {
+    transport: VirtioMmio,
+    queue: Virtqueue,
+    buffer: DmaBuffer,
+}
+
+static DEVICE: Mutex<Option<VirtioRng>> = Mutex::new(None);
+
+impl VirtioRng {
+    fn new(transport: VirtioMmio) -> Result<Self, &'static str> {
+        transport.negotiate(VIRTIO_F_VERSION_1)?;
+        let queue = match transport.setup_queue(0) {
+            Ok(queue) => queue,
+            Err(e) => {
+                transport.fail();
+                return Err(e);
+            }
+        };
+        let buffer = DmaBuffer::new(1)?;
+        transport.driver_ok();
+        Ok(Self { transport, queue, buffer })
+    }
+    
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        // A timed-out request reset the device; set it up again
+        if self.queue.is_stalled() {
+            self.transport.negotiate(VIRTIO_F_VERSION_1)?;
+            self.queue = self.transport.setup_queue(0).inspect_err(|_| self.transport.fail())?;
+            self.transport.driver_ok();
+        }
+        let len = buf.len().min(PAGE_SIZE);
+        let request = VirtqBuffer { addr: self.buffer.bus_addr(0), len: len as u32, device_writes: true };
+        let written = self.queue.submit_and_wait(&self.transport, &[request], REQUEST_TIMEOUT_US)?;
+        self.transport.ack_interrupt();
+        let written = (written as usize).min(len);
+        buf[..written].copy_from_slice(&self.buffer.as_slice()[..written]);
+        Ok(written)
+    }
+}
+
+// rand.rs source; a failed request counts as no entropy
+fn fill(buf: &mut [u8]) -> usize {
+    match DEVICE.lock().as_mut() {
+        Some(device) => device.read(buf).unwrap_or(0),
+        None => 0,
+    }
+}
+
+/// Bind a virtio-rng device. Only the first is used.
+pub fn attach(transport: VirtioMmio) -> Result<(), &'static str> {
+    let legacy = transport.is_legacy();
+    {
+        let mut device = DEVICE.lock();
+        if device.is_some() {
+            return Err("virtio-rng: Already have an entropy device");
+        }
+        *device = Some(VirtioRng::new(transport)?);
+    }
+    crate::println!("virtio-rng: Entropy device ({})", if legacy { "legacy" } else { "modern" });
+    crate::rand::register_source("virtio-rng", fill)
+}
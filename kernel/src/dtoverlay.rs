@@ -0,0 +1,203 @@
+// Device tree overlays applied at boot
+//
+// Overlays (.dtbo, from `dtc -I dts -O dtb`) are read from overlays/ in the
+// initramfs and merged into an unflattened copy of the firmware tree, which
+// is then flattened again and installed in place of the original. Test
+// configurations use this to add virtual devices or deliberately malformed
+// nodes without touching QEMU's generated tree.
+//
+// Merging is fdt_parser::apply: fragments name their target with
+// target-path or a literal target phandle, and overlays that need symbol
+// resolution (__fixups__) are refused, since QEMU's tree carries no
+// __symbols__ to resolve them against.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use fdt_parser::{apply, flatten, unflatten, Node};
+use crate::devicetree::{device_tree, read_cell, set_active_blob};
+use crate::ktest::kernel_test;
+
+/// Initramfs directory overlays are loaded from.
+pub const OVERLAY_DIR: &str = "overlays/";
+
+/// Copy a blob into word storage: the devicetree.rs walkers read aligned
+/// words.
+pub fn aligned_blob(bytes: &[u8]) -> Box<[u32]> {
+    let mut words = vec![0u32; bytes.len().div_ceil(4)].into_boxed_slice();
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+        let mut raw = [0; 4];
+        raw[..chunk.len()].copy_from_slice(chunk);
+        *word = u32::from_ne_bytes(raw);
+    }
+    words
+}
+
+// "reg" ranges in a tree, assuming two address and two size cells
+pub fn node_regs(node: &Node, regs: &mut Vec<(u64, u64)>) {
+    if let Some(reg) = node.property("reg") {
+        for entry in reg.chunks_exact(16) {
+            let cell = |i| read_cell(entry, i).unwrap_or(0) as u64;
+            regs.push((cell(0) << 32 | cell(1), cell(2) << 32 | cell(3)));
+        }
+    }
+    for child in &node.children {
+        node_regs(child, regs);
+    }
+}
+
+// Overlays named by dtoverlay=a.dtbo,b.dtbo, or all of overlays/ in name
+// order; dtoverlay=none disables them
+fn boot_overlays() -> Vec<(String, &'static [u8])> {
+    let selected = crate::bootparams::get("dtoverlay");
+    if selected == Some("none") {
+        return Vec::new();
+    }
+    let mut overlays: Vec<_> = crate::initramfs::files()
+        .into_iter()
+        .filter(|file| file.is_file() && file.name.ends_with(".dtbo"))
+        .filter_map(|file| Some((file.name.strip_prefix(OVERLAY_DIR)?.to_string(), file.data)))
+        .filter(|(name, _)| selected.is_none_or(|list| list.split(',').any(|wanted| wanted == name)))
+        .collect();
+    overlays.sort_by(|a, b| a.0.cmp(&b.0));
+    overlays
+}
+
+/// Apply boot overlays from the initramfs and switch `device_tree()` to
+/// the result. Runs once the heap and initramfs are up, before drivers
+/// probe.
+pub fn init() {
+    let overlays = boot_overlays();
+    if overlays.is_empty() {
+        return;
+    }
+    let Some(dt) = device_tree() else { return };
+    let mut fdt = match unflatten(dt.blob()) {
+        Ok(fdt) => fdt,
+        Err(e) => {
+            crate::println!("DT overlay: Cannot unflatten the boot tree: {}", e);
+            return;
+        }
+    };
+    
+    let mut regs = Vec::new();
+    let mut applied = 0;
+    for (name, data) in overlays {
+        let mut candidate = fdt.root.clone();
+        let result = unflatten(data).and_then(|overlay| {
+            let fragments = apply(&mut candidate, &overlay.root)?;
+            node_regs(&overlay.root, &mut regs);
+            Ok(fragments)
+        });
+        match result {
+            Ok(fragments) => {
+                crate::println!("DT overlay: Applied {} ({} fragments)", name, fragments);
+                fdt.root = candidate;
+                applied += 1;
+            }
+            Err(e) => crate::println!("DT overlay: Skipping {}: {}", name, e),
+        }
+    }
+    if applied == 0 {
+        return;
+    }
+    
+    let bytes = flatten(&fdt);
+    let blob: &'static [u32] = Box::leak(aligned_blob(&bytes));
+    let blob = unsafe { core::slice::from_raw_parts(blob.as_ptr() as *const u8, bytes.len()) };
+    
+    // The boot mapping only covered devices the firmware described
+    let ram: Vec<_> = dt.memory_regions().iter().flatten().copied().collect();
+    for (base, size) in regs {
+        let in_ram = ram.iter().any(|r| base >= r.start && base < r.start + r.size);
+        if size != 0 && !in_ram {
+            if let Err(e) = crate::memory::mmu::map_device(base, size) {
+                crate::println!("DT overlay: Failed to map 0x{:x}: {}", base, e);
+            }
+        }
+    }
+    set_active_blob(blob);
+    crate::println!("DT overlay: {} overlays applied, tree is {} bytes", applied, bytes.len());
+}
+
+#[kernel_test]
+fn test_dt_overlay() {
+    use alloc::vec::Vec;
+    use crate::devicetree::{device_tree, DeviceTree};
+    use fdt_parser::{self, Fdt, Node};
+    
+    crate::println!("Overlay Test: Testing device tree overlays...");
+    
+    let Some(base) = device_tree().and_then(|dt| fdt_parser::unflatten(dt.blob()).ok()) else {
+        crate::println!("Overlay Test: ✗ Boot tree did not unflatten");
+        return;
+    };
+    
+    // An extra UART and a node whose reg is too short for its cells
+    let mut uart = Node::new("serial@9100000");
+    uart.set_property("compatible", b"arm,pl011\0arm,primecell\0");
+    uart.set_property("reg", &[0, 0, 0, 0, 0x09, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0]);
+    let mut bad = Node::new("bad-cells");
+    bad.set_property("compatible", b"rustkernel,bad-cells\0");
+    bad.set_property("reg", &[0, 0, 0, 1, 0x10, 0x00]);
+    let mut root_content = Node::new("__overlay__");
+    root_content.children = alloc::vec![uart, bad];
+    let mut chosen_content = Node::new("__overlay__");
+    chosen_content.set_property("rustkernel,test", b"overlaid\0");
+    
+    let mut fragment0 = Node::new("fragment@0");
+    fragment0.set_property("target-path", b"/\0");
+    fragment0.children.push(root_content);
+    let mut fragment1 = Node::new("fragment@1");
+    fragment1.set_property("target-path", b"/chosen\0");
+    fragment1.children.push(chosen_content);
+    let mut overlay = Node::new("");
+    overlay.children = alloc::vec![fragment0, fragment1];
+    let overlay = Fdt { root: overlay, reserved: Vec::new(), boot_cpuid: 0 };
+    
+    let blob = fdt_parser::flatten(&overlay);
+    match fdt_parser::unflatten(&blob) {
+        Ok(parsed) if parsed.root == overlay.root => {
+            crate::println!("Overlay Test: ✓ Overlay blob survived a flatten/unflatten round trip");
+        }
+        _ => crate::println!("Overlay Test: ✗ Overlay round trip changed the tree"),
+    }
+    
+    let mut merged = base.clone();
+    let applied = fdt_parser::apply(&mut merged.root, &overlay.root);
+    let words = aligned_blob(&fdt_parser::flatten(&merged));
+    let base_uarts = device_tree().map_or(0, |dt| dt.find_compatible("arm,pl011").count());
+    let checked = DeviceTree::new(words.as_ptr() as *const u8).map(|dt| {
+        let uarts = dt.find_compatible("arm,pl011").count();
+        let uart_reg = dt.find_by_name("serial@9100000").and_then(|node| node.reg(0));
+        let bad_reg = dt.find_compatible("rustkernel,bad-cells").next().map(|node| node.reg(0));
+        let chosen = dt.find_by_name("chosen").and_then(|node| node.property_str("rustkernel,test"));
+        (uarts, uart_reg, bad_reg, chosen)
+    });
+    match (applied, checked) {
+        (Ok(2), Some((uarts, Some((0x0910_0000, 0x1000)), Some(None), Some("overlaid"))))
+            if uarts == base_uarts + 1 =>
+        {
+            crate::println!("Overlay Test: ✓ Merged tree has the new UART and property; short reg ignored");
+        }
+        (applied, _) => crate::println!("Overlay Test: ✗ Overlay merge wrong ({:?})", applied),
+    }
+    
+    let truncated = fdt_parser::unflatten(&blob[..blob.len() - 8]);
+    let mut unresolved = overlay.root.clone();
+    unresolved.children.push(Node::new("__fixups__"));
+    let mut missing = overlay.root.clone();
+    missing.children[1].set_property("target-path", b"/no-such-node\0");
+    let mut scratch = base.root.clone();
+    if truncated.is_err()
+        && fdt_parser::apply(&mut scratch, &unresolved).is_err()
+        && fdt_parser::apply(&mut scratch, &missing).is_err()
+    {
+        crate::println!("Overlay Test: ✓ Truncated, unresolved and mistargeted overlays rejected");
+    } else {
+        crate::println!("Overlay Test: ✗ Bad overlay accepted");
+    }
+    
+    crate::println!("Overlay Test: Device tree overlay test completed");
+}
@@ -47,54 +47,91 @@ exception_vector_table:
     b serror_lower_el_aarch32   // System Error
 
 // Exception handler macro to save/restore context
+// Frame layout matches ExceptionContext in interrupts.rs:
+//   [sp + 0]   SPSR_EL1
+//   [sp + 8]   ELR_EL1
+//   [sp + 16]  x30, x29, ... x1 (descending)
+//   [sp + 256] x0
+//   [sp + 264] SP_EL0
+// 272 bytes keeps the stack 16-byte aligned.
+.equ EXCEPTION_FRAME_SIZE, 272
+
 .macro exception_entry
+    sub sp, sp, #EXCEPTION_FRAME_SIZE
+    
     // Save general purpose registers
-    stp x0, x1, [sp, #-16]!
-    stp x2, x3, [sp, #-16]!
-    stp x4, x5, [sp, #-16]!
-    stp x6, x7, [sp, #-16]!
-    stp x8, x9, [sp, #-16]!
-    stp x10, x11, [sp, #-16]!
-    stp x12, x13, [sp, #-16]!
-    stp x14, x15, [sp, #-16]!
-    stp x16, x17, [sp, #-16]!
-    stp x18, x19, [sp, #-16]!
-    stp x20, x21, [sp, #-16]!
-    stp x22, x23, [sp, #-16]!
-    stp x24, x25, [sp, #-16]!
-    stp x26, x27, [sp, #-16]!
-    stp x28, x29, [sp, #-16]!
+    stp x30, x29, [sp, #16]
+    stp x28, x27, [sp, #32]
+    stp x26, x25, [sp, #48]
+    stp x24, x23, [sp, #64]
+    stp x22, x21, [sp, #80]
+    stp x20, x19, [sp, #96]
+    stp x18, x17, [sp, #112]
+    stp x16, x15, [sp, #128]
+    stp x14, x13, [sp, #144]
+    stp x12, x11, [sp, #160]
+    stp x10, x9, [sp, #176]
+    stp x8, x7, [sp, #192]
+    stp x6, x5, [sp, #208]
+    stp x4, x3, [sp, #224]
+    stp x2, x1, [sp, #240]
+    str x0, [sp, #256]
     
-    // Save link register and exception return address
-    mrs x0, elr_el1
-    mrs x1, spsr_el1
-    stp x30, x0, [sp, #-16]!
-    str x1, [sp, #-8]!
+    // Save exception state and the user stack pointer
+    mrs x0, spsr_el1
+    mrs x1, elr_el1
+    stp x0, x1, [sp, #0]
+    mrs x0, sp_el0
+    str x0, [sp, #264]
+.endm
+
+// Unwind info for a handler stub, from just after exception_entry: the
+// interrupted code's stack pointer is just above the frame, its registers
+// are in it, and ELR_EL1 (DWARF register 32, pc) stands in for a return
+// address. Only the registers needed to keep unwinding are described.
+.macro exception_frame_cfi
+    .cfi_def_cfa sp, EXCEPTION_FRAME_SIZE
+    .cfi_offset 32, 8 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x30, 16 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x29, 24 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x28, 32 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x27, 40 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x26, 48 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x25, 56 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x24, 64 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x23, 72 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x22, 80 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x21, 88 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x20, 96 - EXCEPTION_FRAME_SIZE
+    .cfi_offset x19, 104 - EXCEPTION_FRAME_SIZE
 .endm
 
 .macro exception_exit
-    // Restore SPSR and ELR
-    ldr x1, [sp], #8
-    ldp x30, x0, [sp], #16
-    msr spsr_el1, x1
-    msr elr_el1, x0
+    // Restore exception state and the user stack pointer
+    ldp x0, x1, [sp, #0]
+    msr spsr_el1, x0
+    msr elr_el1, x1
+    ldr x0, [sp, #264]
+    msr sp_el0, x0
     
     // Restore general purpose registers
-    ldp x28, x29, [sp], #16
-    ldp x26, x27, [sp], #16
-    ldp x24, x25, [sp], #16
-    ldp x22, x23, [sp], #16
-    ldp x20, x21, [sp], #16
-    ldp x18, x19, [sp], #16
-    ldp x16, x17, [sp], #16
-    ldp x14, x15, [sp], #16
-    ldp x12, x13, [sp], #16
-    ldp x10, x11, [sp], #16
-    ldp x8, x9, [sp], #16
-    ldp x6, x7, [sp], #16
-    ldp x4, x5, [sp], #16
-    ldp x2, x3, [sp], #16
-    ldp x0, x1, [sp], #16
+    ldp x30, x29, [sp, #16]
+    ldp x28, x27, [sp, #32]
+    ldp x26, x25, [sp, #48]
+    ldp x24, x23, [sp, #64]
+    ldp x22, x21, [sp, #80]
+    ldp x20, x19, [sp, #96]
+    ldp x18, x17, [sp, #112]
+    ldp x16, x15, [sp, #128]
+    ldp x14, x13, [sp, #144]
+    ldp x12, x11, [sp, #160]
+    ldp x10, x9, [sp, #176]
+    ldp x8, x7, [sp, #192]
+    ldp x6, x5, [sp, #208]
+    ldp x4, x3, [sp, #224]
+    ldp x2, x1, [sp, #240]
+    ldr x0, [sp, #256]
+    add sp, sp, #EXCEPTION_FRAME_SIZE
     
     eret
 .endm
@@ -116,42 +153,82 @@ serror_invalid_el1t:
     mov x0, #3
     b handle_invalid_exception
 
-// Current EL (EL1) exception handlers
+// Current EL (EL1) exception handlers; the CFI lets the eh-unwind
+// unwinder step from a handler into the interrupted kernel code.
+//
+// A kernel stack overflow shows up here first, as a fault on the guard
+// below the stack or as the frame push about to land on it. Before
+// touching memory, check that the frame would be inside a stack (see
+// KERNEL_STACK_AREA in thread.rs) with no register to spare: x0 is
+// parked in sp (sp + x0) while x0 holds the frame address.
 sync_current_el1h:
+    .cfi_startproc
+    .cfi_signal_frame
+    .cfi_return_column 32
+    sub sp, sp, #EXCEPTION_FRAME_SIZE
+    add sp, sp, x0
+    sub x0, sp, x0
+    tbz x0, #{kernel_stack_area_bit}, .Lsync_stack_ok
+    tbz x0, #{kernel_stack_shift}, kernel_stack_overflow
+.Lsync_stack_ok:
+    sub x0, sp, x0
+    sub sp, sp, x0
+    add sp, sp, #EXCEPTION_FRAME_SIZE
     exception_entry
+    exception_frame_cfi
     mov x0, sp
     bl handle_sync_exception
+    mov sp, x0              // Frame to resume (may belong to another thread)
     exception_exit
+    .cfi_endproc
 
 irq_current_el1h:
+    .cfi_startproc
+    .cfi_signal_frame
+    .cfi_return_column 32
     exception_entry
+    exception_frame_cfi
     mov x0, sp
     bl handle_irq_exception
+    mov sp, x0              // Frame to resume (may belong to another thread)
     exception_exit
+    .cfi_endproc
 
 fiq_current_el1h:
+    .cfi_startproc
+    .cfi_signal_frame
+    .cfi_return_column 32
     exception_entry
+    exception_frame_cfi
     mov x0, sp
     bl handle_fiq_exception  
     exception_exit
+    .cfi_endproc
 
 serror_current_el1h:
+    .cfi_startproc
+    .cfi_signal_frame
+    .cfi_return_column 32
     exception_entry
+    exception_frame_cfi
     mov x0, sp
     bl handle_serror_exception
     exception_exit
+    .cfi_endproc
 
 // Lower EL (EL0) AArch64 exception handlers
 sync_lower_el_aarch64:
     exception_entry
     mov x0, sp
     bl handle_sync_exception
+    mov sp, x0              // Frame to resume (may belong to another thread)
     exception_exit
 
 irq_lower_el_aarch64:
     exception_entry
     mov x0, sp
     bl handle_irq_exception
+    mov sp, x0              // Frame to resume (may belong to another thread)
     exception_exit
 
 fiq_lower_el_aarch64:
@@ -171,12 +248,14 @@ sync_lower_el_aarch32:
     exception_entry
     mov x0, sp
     bl handle_sync_exception
+    mov sp, x0              // Frame to resume (may belong to another thread)
     exception_exit
 
 irq_lower_el_aarch32:
     exception_entry
     mov x0, sp
     bl handle_irq_exception
+    mov sp, x0              // Frame to resume (may belong to another thread)
     exception_exit
 
 fiq_lower_el_aarch32:
@@ -191,9 +270,37 @@ serror_lower_el_aarch32:
     bl handle_serror_exception
     exception_exit
 
+// The stack overflowed: sp = frame address + interrupted x0, x0 = frame
+// address. Nothing returns from here, so the thread pointer registers
+// (unused by the kernel) carry x0 and the stack pointer across the switch
+// to the overflow stack.
+kernel_stack_overflow:
+    sub x0, sp, x0
+    msr tpidrro_el0, x0
+    sub x0, sp, x0
+    add x0, x0, #EXCEPTION_FRAME_SIZE
+    msr tpidr_el0, x0
+    adrp x0, overflow_stack_top
+    add x0, x0, :lo12:overflow_stack_top
+    mov sp, x0
+    exception_entry
+    mrs x0, tpidrro_el0
+    str x0, [sp, #256]
+    mov x0, sp
+    mrs x1, tpidr_el0
+    bl handle_stack_overflow
+    b .
+
 // Invalid exception handler
 handle_invalid_exception:
     // x0 contains exception type
     bl rust_handle_invalid_exception
     // Should not return
-    b .
\ No newline at end of file
+    b .
+
+// Stack for reporting a kernel stack overflow; only the boot CPU runs threads
+.section ".bss"
+.balign 16
+overflow_stack:
+    .space 0x4000
+overflow_stack_top:
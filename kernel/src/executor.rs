@@ -0,0 +1,272 @@
+// Async executor for kernel tasks
+//
+// Lets drivers and protocol code be written as async state machines that
+// await timers, interrupts (e.g. a virtqueue completion signalled from its
+// IRQ handler through a Notify) or port messages, instead of each taking
+// a kernel thread and stack. All tasks run on one kernel thread, "kasync",
+// which blocks whenever none is ready.
+//
+// A waker is just the task ID, so waking never allocates and is safe from
+// interrupt context. Waking a task that has finished is a no-op.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use spin::Mutex;
+use crate::interrupts::{counter_frequency, without_interrupts};
+use crate::process::scheduler::{block_current, wake};
+use crate::process::{kthread_spawn, yield_now, ThreadId, KTHREAD_DEFAULT_PRIORITY};
+use crate::sync::IrqSafeMutex;
+use crate::time::{now_ticks, request_event};
+
+pub type TaskId = usize;
+
+// Bounds the ready ring, which must never allocate
+pub const MAX_TASKS: usize = 64;
+
+type BoxedTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Task {
+    name: &'static str,
+    future: BoxedTask,
+}
+
+// Tasks not currently being polled
+static TASKS: Mutex<BTreeMap<TaskId, Task>> = Mutex::new(BTreeMap::new());
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(1);
+static LIVE_TASKS: AtomicU32 = AtomicU32::new(0);
+
+// Executor thread, once started
+static EXECUTOR_THREAD: Mutex<Option<ThreadId>> = Mutex::new(None);
+
+struct ReadyRing {
+    ids: [TaskId; MAX_TASKS],
+    head: usize,
+    len: usize,
+}
+
+impl ReadyRing {
+    // Queue `id` unless it already is; every live task fits
+    fn push(&mut self, id: TaskId) {
+        let queued = (0..self.len).any(|i| self.ids[(self.head + i) % MAX_TASKS] == id);
+        if !queued && self.len < MAX_TASKS {
+            self.ids[(self.head + self.len) % MAX_TASKS] = id;
+            self.len += 1;
+        }
+    }
+    
+    fn pop(&mut self) -> Option<TaskId> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = self.ids[self.head];
+        self.head = (self.head + 1) % MAX_TASKS;
+        self.len -= 1;
+        Some(id)
+    }
+}
+
+// Pushed to from wakers in any context
+static READY: IrqSafeMutex<ReadyRing> = IrqSafeMutex::new(ReadyRing {
+    ids: [0; MAX_TASKS],
+    head: 0,
+    len: 0,
+});
+
+fn make_ready(id: TaskId) {
+    READY.lock().push(id);
+    if let Some(thread) = *EXECUTOR_THREAD.lock() {
+        wake(thread);
+    }
+}
+
+static WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    |data| RawWaker::new(data, &WAKER_VTABLE),
+    |data| make_ready(data as TaskId),
+    |data| make_ready(data as TaskId),
+    |_| {},
+);
+
+fn task_waker(id: TaskId) -> Waker {
+    unsafe { Waker::from_raw(RawWaker::new(id as *const (), &WAKER_VTABLE)) }
+}
+
+/// Run `future` as a task on the executor thread.
+pub fn spawn(name: &'static str, future: impl Future<Output = ()> + Send + 'static) -> Result<TaskId, &'static str> {
+    if LIVE_TASKS.fetch_add(1, Ordering::SeqCst) as usize >= MAX_TASKS {
+        LIVE_TASKS.fetch_sub(1, Ordering::SeqCst);
+        return Err("Too many async tasks");
+    }
+    let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed) as TaskId;
+    TASKS.lock().insert(id, Task { name, future: Box::pin(future) });
+    make_ready(id);
+    Ok(id)
+}
+
+/// Tasks that have not finished yet.
+pub fn task_count() -> usize {
+    LIVE_TASKS.load(Ordering::SeqCst) as usize
+}
+
+/// Names of the tasks not being polled right now.
+pub fn task_names() -> Vec<(TaskId, &'static str)> {
+    TASKS.lock().iter().map(|(&id, task)| (id, task.name)).collect()
+}
+
+fn executor_main() {
+    loop {
+        let next = READY.lock().pop();
+        let Some(id) = next else {
+            // Block unless a wakeup raced in; IRQs masked so one from an
+            // interrupt handler cannot fall in between
+            without_interrupts(|| {
+                if READY.lock().len == 0 {
+                    block_current();
+                }
+            });
+            yield_now();
+            continue;
+        };
+        
+        // Out of the table while polling: the task may spawn others
+        let Some(mut task) = TASKS.lock().remove(&id) else { continue };
+        let waker = task_waker(id);
+        let mut cx = Context::from_waker(&waker);
+        match task.future.as_mut().poll(&mut cx) {
+            Poll::Ready(()) => {
+                LIVE_TASKS.fetch_sub(1, Ordering::SeqCst);
+            }
+            Poll::Pending => {
+                TASKS.lock().insert(id, task);
+            }
+        }
+    }
+}
+
+/// Start the executor thread.
+pub fn init() {
+    match kthread_spawn(executor_main, "kasync", KTHREAD_DEFAULT_PRIORITY) {
+        Ok(id) => {
+            *EXECUTOR_THREAD.lock() = Some(id);
+            // Tasks spawned before the thread existed
+            if READY.lock().len != 0 {
+                wake(id);
+            }
+        }
+        Err(e) => crate::println!("Executor: Failed to start: {}", e),
+    }
+}
+
+// Sleeping tasks by deadline (counter ticks)
+static TIMERS: IrqSafeMutex<Vec<(u64, Waker)>> = IrqSafeMutex::new(Vec::new());
+// Earliest deadline in TIMERS, so the tick can skip the lock
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Wake tasks whose sleep has ended; called from the timer interrupt.
+pub fn timer_tick(now: u64) {
+    if NEXT_DEADLINE.load(Ordering::SeqCst) > now {
+        return;
+    }
+    let mut timers = TIMERS.lock();
+    let mut next = u64::MAX;
+    // Removal never allocates, so this is fine in interrupt context
+    let mut i = 0;
+    while i < timers.len() {
+        if timers[i].0 <= now {
+            timers.swap_remove(i).1.wake();
+        } else {
+            next = next.min(timers[i].0);
+            i += 1;
+        }
+    }
+    NEXT_DEADLINE.store(next, Ordering::SeqCst);
+}
+
+/// Future completing once the clock reaches `deadline`.
+pub struct Sleep {
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+    
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if now_ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let mut timers = TIMERS.lock();
+        match timers.iter_mut().find(|(_, waker)| waker.will_wake(cx.waker())) {
+            Some(entry) => *entry = (self.deadline, cx.waker().clone()),
+            None => timers.push((self.deadline, cx.waker().clone())),
+        }
+        NEXT_DEADLINE.fetch_min(self.deadline, Ordering::SeqCst);
+        // Wakes the CPU in time even when the tick is stopped
+        request_event(self.deadline);
+        Poll::Pending
+    }
+}
+
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep { deadline }
+}
+
+pub fn sleep_ms(ms: u64) -> Sleep {
+    sleep_until(now_ticks() + counter_frequency() * ms / 1000)
+}
+
+/// One-waiter event: `notify` from any context (typically an IRQ
+/// handler) completes the pending or next `wait`.
+pub struct Notify {
+    pending: AtomicBool,
+    waiter: IrqSafeMutex<Option<Waker>>,
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            waiter: IrqSafeMutex::new(None),
+        }
+    }
+    
+    pub fn notify(&self) {
+        self.pending.store(true, Ordering::SeqCst);
+        if let Some(waker) = self.waiter.lock().take() {
+            waker.wake();
+        }
+    }
+    
+    /// Completes once notified, consuming the notification.
+    pub fn wait(&self) -> NotifyWait<'_> {
+        NotifyWait { notify: self }
+    }
+}
+
+pub struct NotifyWait<'a> {
+    notify: &'a Notify,
+}
+
+impl Future for NotifyWait<'_> {
+    type Output = ();
+    
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Register first so a notify between the check and the return
+        // still finds the waker
+        *self.notify.waiter.lock() = Some(cx.waker().clone());
+        if self.notify.pending.swap(false, Ordering::SeqCst) {
+            self.notify.waiter.lock().take();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
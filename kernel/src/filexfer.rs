@@ -0,0 +1,434 @@
+// File transfer with the host over the serial console
+//
+// Push: the host tool (tools/push.py) types `push <path> <size> <crc32>`
+// at the kernel shell, then sends the file as base64 lines. Each line is
+// acknowledged before the next is sent, so the console input queue never
+// overflows. Protocol replies go straight to the UART, not the kernel log:
+//
+//   kernel                   host
+//   PUSH READY          <-   push /tmp/app 1000 1a2b3c4d
+//   PUSH ACK <bytes>    <-   <base64 line>    (repeated)
+//   PUSH OK <bytes>  or  PUSH ERR <reason>
+//
+// Pull: `HostStream` carries any amount of data the other way, for
+// producers that write it piece by piece (profiles, traces, dumps). Data
+// goes out in large chunks with a sequence number and their own CRC; the
+// host acknowledges each one, and at most a window of chunks is ever
+// unacknowledged, so the host sets the pace. A damaged or missing chunk
+// is sent again from there on (go-back-N). The log is held back for the
+// duration so it does not break into data lines.
+//
+//   kernel                          host
+//   PULL BEGIN <name>          <-   pull <path> [window]  (from tools/pull.py)
+//                              ->   A 0
+//   PULL DATA <seq> <crc> <base64>  (repeated, up to <window> past the last A)
+//                              ->   A <next>    every chunk before <next> arrived
+//                              ->   R <next>    resend from chunk <next>
+//   PULL END <bytes> <crc32>
+//                              ->   OK  or  ERR <reason>
+//
+// In either direction a line holding just "!" (or Ctrl-C) aborts.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::ktest::kernel_test;
+use crate::pstore::{crc32, crc32_update};
+
+/// Largest file accepted; tmpfs applies its own cap on top.
+pub const PUSH_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+// Host lines are at most 128 base64 characters (96 bytes)
+const LINE_MAX: usize = 256;
+
+/// Bytes per pulled data line (4 KiB of base64).
+pub const STREAM_CHUNK: usize = 3072;
+/// Unacknowledged chunks allowed when the host does not pick a window.
+pub const STREAM_DEFAULT_WINDOW: usize = 8;
+pub const STREAM_MAX_WINDOW: usize = 64;
+
+const CTRL_C: u8 = 0x03;
+
+/// Reassembles a pushed file from base64 lines.
+pub struct Receiver {
+    size: usize,
+    crc: u32,
+    data: Vec<u8>,
+}
+
+impl Receiver {
+    pub fn new(size: usize, crc: u32) -> Result<Self, &'static str> {
+        if size > PUSH_MAX_SIZE {
+            return Err("file too large");
+        }
+        let mut data = Vec::new();
+        data.try_reserve_exact(size).map_err(|_| "out of memory")?;
+        Ok(Self { size, crc, data })
+    }
+    
+    /// Decode one line of base64, returning the total received so far.
+    pub fn feed_line(&mut self, line: &[u8]) -> Result<usize, &'static str> {
+        if !line.len().is_multiple_of(4) {
+            return Err("bad base64 line length");
+        }
+        for group in line.chunks(4) {
+            let mut bits = 0u32;
+            let mut pad = 0;
+            for (i, &c) in group.iter().enumerate() {
+                let value = match c {
+                    b'=' if i >= 2 => {
+                        pad += 1;
+                        0
+                    }
+                    _ if pad > 0 => return Err("bad base64 padding"),
+                    _ => base64_value(c).ok_or("bad base64 character")?,
+                };
+                bits = (bits << 6) | value;
+            }
+            let bytes = [(bits >> 16) as u8, (bits >> 8) as u8, bits as u8];
+            let bytes = &bytes[..3 - pad];
+            if self.data.len() + bytes.len() > self.size {
+                return Err("more data than announced");
+            }
+            self.data.extend_from_slice(bytes);
+        }
+        Ok(self.data.len())
+    }
+    
+    pub fn is_complete(&self) -> bool {
+        self.data.len() == self.size
+    }
+    
+    /// Check the CRC and write the file, returning its size.
+    pub fn finish(self, path: &str) -> Result<usize, &'static str> {
+        if !self.is_complete() {
+            return Err("transfer incomplete");
+        }
+        if crc32(&self.data) != self.crc {
+            return Err("CRC mismatch");
+        }
+        crate::vfs::write_all(path, &self.data)?;
+        Ok(self.data.len())
+    }
+}
+
+fn base64_value(c: u8) -> Option<u32> {
+    let value = match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return None,
+    };
+    Some(value as u32)
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Append `data` to `out` as padded base64.
+pub fn base64_encode(data: &[u8], out: &mut String) {
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+}
+
+fn reply(message: &str) {
+    crate::uart::put_raw(message.as_bytes());
+    crate::uart::put_raw(b"\n");
+}
+
+// Next line without echo; an error if the host aborted
+fn read_raw_line(line: &mut Vec<u8>) -> Result<(), &'static str> {
+    line.clear();
+    loop {
+        match crate::console::wait_byte() {
+            b'\n' => break,
+            b'\r' => {}
+            CTRL_C => return Err("aborted"),
+            _ if line.len() >= LINE_MAX => return Err("line too long"),
+            byte => line.push(byte),
+        }
+    }
+    if line.as_slice() == b"!" {
+        return Err("aborted");
+    }
+    Ok(())
+}
+
+// Handle one host line
+fn parse_host_line(line: &[u8]) -> Result<HostReply, &'static str> {
+    let line = core::str::from_utf8(line).map_err(|_| "bad host line")?;
+    let mut words = line.split_ascii_whitespace();
+    let reply = match (words.next(), words.next().map(str::parse::<u64>)) {
+        (Some("A"), Some(Ok(next))) => HostReply::Ack(next),
+        (Some("R"), Some(Ok(next))) => HostReply::Resend(next),
+        (Some("OK"), None) => HostReply::Done,
+        (Some("ERR"), _) => return Err("host rejected the stream"),
+        _ => return Err("bad host line"),
+    };
+    Ok(reply)
+}
+
+enum HostReply {
+    Ack(u64),
+    Resend(u64),
+    Done,
+}
+
+/// A flow-controlled stream of data to the host. Runs on the shell
+/// thread, which owns console input.
+pub struct HostStream {
+    window: usize,
+    // Chunks from `acked` on, oldest first; those before `next_send` are out
+    unacked: VecDeque<Vec<u8>>,
+    acked: u64,
+    next_send: u64,
+    // Partial chunk
+    pending: Vec<u8>,
+    bytes: usize,
+    crc: u32,
+    // Host input not yet a full line
+    line: Vec<u8>,
+}
+
+impl HostStream {
+    /// Announce stream `name` and wait for the host to start it.
+    pub fn open(name: &str, window: usize) -> Result<Self, &'static str> {
+        if window == 0 || window > STREAM_MAX_WINDOW {
+            return Err("bad stream window");
+        }
+        let mut stream = Self {
+            window,
+            unacked: VecDeque::new(),
+            acked: 0,
+            next_send: 0,
+            pending: Vec::with_capacity(STREAM_CHUNK),
+            bytes: 0,
+            crc: 0,
+            line: Vec::with_capacity(LINE_MAX),
+        };
+        crate::console::pause_log();
+        reply(&format!("PULL BEGIN {}", name));
+        match stream.host_reply(true) {
+            Ok(Some(HostReply::Ack(0))) => Ok(stream),
+            Ok(_) => Err(stream.fail("bad start of stream")),
+            Err(e) => Err(stream.fail(e)),
+        }
+    }
+    
+    /// Queue `data`, sending full chunks as the window allows.
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), &'static str> {
+        while !data.is_empty() {
+            let n = data.len().min(STREAM_CHUNK - self.pending.len());
+            self.pending.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.pending.len() == STREAM_CHUNK {
+                let chunk = core::mem::replace(&mut self.pending, Vec::with_capacity(STREAM_CHUNK));
+                self.push_chunk(chunk).map_err(|e| self.fail(e))?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Send what is left and wait for the host to confirm the whole
+    /// stream. Returns the bytes sent.
+    pub fn finish(mut self) -> Result<usize, &'static str> {
+        let result = self.drain();
+        match result {
+            Ok(()) => {
+                crate::console::resume_log();
+                Ok(self.bytes)
+            }
+            Err(e) => Err(self.fail(e)),
+        }
+    }
+    
+    fn drain(&mut self) -> Result<(), &'static str> {
+        if !self.pending.is_empty() {
+            let chunk = core::mem::take(&mut self.pending);
+            self.push_chunk(chunk)?;
+        }
+        while !self.unacked.is_empty() {
+            self.pump(true)?;
+        }
+        reply(&format!("PULL END {} {:08x}", self.bytes, self.crc));
+        // Late duplicate acknowledgements may still be queued
+        loop {
+            if let Some(HostReply::Done) = self.host_reply(true)? {
+                return Ok(());
+            }
+        }
+    }
+    
+    fn push_chunk(&mut self, chunk: Vec<u8>) -> Result<(), &'static str> {
+        self.bytes += chunk.len();
+        self.crc = crc32_update(self.crc, &chunk);
+        self.unacked.push_back(chunk);
+        self.send_ready();
+        // Take acknowledgements as they come so the input queue stays
+        // short; wait for them once the window is full
+        self.pump(false)?;
+        while self.unacked.len() >= self.window {
+            self.pump(true)?;
+        }
+        Ok(())
+    }
+    
+    fn send_ready(&mut self) {
+        while self.next_send < self.acked + self.unacked.len() as u64 {
+            let chunk = &self.unacked[(self.next_send - self.acked) as usize];
+            let mut line = String::with_capacity(chunk.len() * 4 / 3 + 40);
+            line.push_str(&format!("PULL DATA {} {:08x} ", self.next_send, crc32(chunk)));
+            base64_encode(chunk, &mut line);
+            line.push('\n');
+            crate::uart::put_raw(line.as_bytes());
+            self.next_send += 1;
+        }
+    }
+    
+    // Act on host replies: one, waiting for it, or all already queued
+    fn pump(&mut self, block: bool) -> Result<(), &'static str> {
+        while let Some(reply) = self.host_reply(block)? {
+            let (HostReply::Ack(next) | HostReply::Resend(next)) = reply else {
+                return Err("stream ended early");
+            };
+            if next < self.acked || next > self.next_send {
+                return Err("bad acknowledgement");
+            }
+            self.unacked.drain(..(next - self.acked) as usize);
+            self.acked = next;
+            if let HostReply::Resend(_) = reply {
+                self.next_send = next;
+            }
+            self.send_ready();
+            if block {
+                break;
+            }
+        }
+        Ok(())
+    }
+    
+    // Next complete host line, if any (always one when blocking)
+    fn host_reply(&mut self, block: bool) -> Result<Option<HostReply>, &'static str> {
+        loop {
+            let byte = if block { crate::console::wait_byte() } else {
+                match crate::console::read_byte() {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                }
+            };
+            match byte {
+                b'\n' => {
+                    let line = core::mem::take(&mut self.line);
+                    self.line = Vec::with_capacity(LINE_MAX);
+                    if line.as_slice() == b"!" {
+                        return Err("aborted");
+                    }
+                    if !line.is_empty() {
+                        return parse_host_line(&line).map(Some);
+                    }
+                }
+                b'\r' => {}
+                CTRL_C => return Err("aborted"),
+                _ if self.line.len() >= LINE_MAX => return Err("line too long"),
+                byte => self.line.push(byte),
+            }
+        }
+    }
+    
+    // Tell the host the stream failed, returning the reason
+    fn fail(&mut self, reason: &'static str) -> &'static str {
+        reply(&format!("PULL ERR {}", reason));
+        crate::console::resume_log();
+        reason
+    }
+}
+
+/// Send a VFS file, or a /proc entry, to tools/pull.py.
+pub fn send(path: &str, window: usize) -> Result<usize, &'static str> {
+    let data = match path.strip_prefix("/proc/") {
+        Some(entry) => crate::procfs::read_all(entry)?,
+        None => crate::vfs::read_all(path)?,
+    };
+    let mut stream = HostStream::open(path, window)?;
+    stream.write(&data)?;
+    stream.finish()
+}
+
+/// Receive a file from the console into the VFS. Runs on the shell thread.
+pub fn receive(path: &str, size: usize, crc: u32) -> Result<usize, &'static str> {
+    let path = crate::vfs::normalize(path)?;
+    let result = receive_lines(size, crc).and_then(|receiver| receiver.finish(&path));
+    match result {
+        Ok(written) => reply(&format!("PUSH OK {}", written)),
+        Err(e) => reply(&format!("PUSH ERR {}", e)),
+    }
+    result
+}
+
+fn receive_lines(size: usize, crc: u32) -> Result<Receiver, &'static str> {
+    let mut receiver = Receiver::new(size, crc)?;
+    reply("PUSH READY");
+    let mut line = Vec::with_capacity(LINE_MAX);
+    while !receiver.is_complete() {
+        read_raw_line(&mut line)?;
+        let received = receiver.feed_line(&line)?;
+        reply(&format!("PUSH ACK {}", received));
+    }
+    Ok(receiver)
+}
+
+#[kernel_test]
+fn test_filexfer() {
+    
+    crate::println!("Filexfer Test: Testing host file push...");
+    
+    // "hello, world\n" split across two lines, the second padded
+    let pushed = Receiver::new(13, 0xf424_7453).and_then(|mut receiver| {
+        receiver.feed_line(b"aGVsbG8s")?;
+        receiver.feed_line(b"IHdvcmxkCg==")?;
+        receiver.finish("/tmp/pushed")
+    });
+    match (pushed, crate::vfs::read_all("/tmp/pushed")) {
+        (Ok(13), Ok(data)) if data == b"hello, world\n" => {
+            crate::println!("Filexfer Test: ✓ Pushed file decoded and written to tmpfs");
+        }
+        (pushed, data) => crate::println!("Filexfer Test: ✗ Push gave {:?}, {:?}", pushed, data),
+    }
+    let _ = crate::vfs::unlink("/tmp/pushed");
+    
+    let corrupt = Receiver::new(6, 0).and_then(|mut receiver| {
+        receiver.feed_line(b"aGVsbG8s")?;
+        receiver.finish("/tmp/pushed")
+    });
+    let overrun = Receiver::new(3, 0).and_then(|mut receiver| receiver.feed_line(b"aGVsbG8s"));
+    let garbage = Receiver::new(3, 0).and_then(|mut receiver| receiver.feed_line(b"a*Vs"));
+    if corrupt == Err("CRC mismatch") && overrun.is_err() && garbage.is_err()
+        && crate::vfs::stat("/tmp/pushed").is_err()
+    {
+        crate::println!("Filexfer Test: ✓ Bad CRC, overrun and bad characters rejected");
+    } else {
+        crate::println!("Filexfer Test: ✗ Corrupt transfers accepted");
+    }
+    
+    // Pull side: encoding and the running CRC that PULL END carries
+    let mut encoded = alloc::string::String::new();
+    base64_encode(b"hello, world\n", &mut encoded);
+    let running = crate::pstore::crc32_update(crate::pstore::crc32(b"hello, "), b"world\n");
+    if encoded == "aGVsbG8sIHdvcmxkCg==" && running == 0xf424_7453 {
+        crate::println!("Filexfer Test: ✓ Pull encoding and running CRC match the host's");
+    } else {
+        crate::println!("Filexfer Test: ✗ Pull encoded {:?}, CRC {:08x}", encoded, running);
+    }
+    
+    crate::println!("Filexfer Test: Host file push test completed");
+}
@@ -0,0 +1,214 @@
+// Firmware calls through the SMC Calling Convention (SMCCC)
+//
+// PSCI, the Arm TRNG interface and SoC services all sit behind SMCCC: a
+// function ID in w0 names the owner and says SMC32 or SMC64, arguments go
+// in x1-x7 and results come back in x0-x3. The conduit (SMC to EL3
+// firmware, or HVC to a hypervisor) has no node of its own; it is the
+// /psci node's "method", which is also where ACPI's FADT flags end up.
+// QEMU virt without EL2/EL3 emulation uses hvc.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+
+// Fast call (bit 31), SMC64 (bit 30), owner in bits 29:24
+const FAST_CALL: u32 = 1 << 31;
+const SMC64: u32 = 1 << 30;
+const OWNER_SHIFT: u32 = 24;
+const MAX_ARGS: usize = 7;
+
+// Discovery. SMCCC_VERSION only exists from SMCCC 1.1, which firmware
+// advertises through PSCI_FEATURES; without it the convention is 1.0.
+const PSCI_VERSION: u32 = function_id(Owner::Standard, false, 0x00);
+const PSCI_FEATURES: u32 = function_id(Owner::Standard, false, 0x0A);
+const SMCCC_VERSION: u32 = function_id(Owner::Arch, false, 0x0000);
+const SMCCC_ARCH_FEATURES: u32 = function_id(Owner::Arch, false, 0x0001);
+
+// Arm True Random Number Generator firmware interface (DEN0098)
+const TRNG_VERSION: u32 = function_id(Owner::Standard, false, 0x50);
+const TRNG_RND64: u32 = function_id(Owner::Standard, true, 0x53);
+// RND64 returns up to 192 bits in x1-x3
+const TRNG_MAX_BITS: u64 = 192;
+
+/// Which service a function ID belongs to (the ones the kernel calls;
+/// CPU, SiP, OEM and hypervisor services fill the gaps).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Owner {
+    Arch = 0,
+    /// PSCI and TRNG live here
+    Standard = 4,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Conduit {
+    Smc,
+    Hvc,
+}
+
+// 0 = not looked up yet, 1 = none, then the conduit
+const CONDUIT_UNKNOWN: u8 = 0;
+const CONDUIT_NONE: u8 = 1;
+const CONDUIT_SMC: u8 = 2;
+const CONDUIT_HVC: u8 = 3;
+
+static CONDUIT: AtomicU8 = AtomicU8::new(CONDUIT_UNKNOWN);
+// (major << 16) | minor, 0 until init
+static VERSION: AtomicU32 = AtomicU32::new(0);
+static HAS_TRNG: AtomicBool = AtomicBool::new(false);
+
+/// Function ID of a fast call: `number` in `owner`'s range, SMC64 when
+/// the call passes 64-bit arguments.
+pub const fn function_id(owner: Owner, smc64: bool, number: u16) -> u32 {
+    FAST_CALL | (if smc64 { SMC64 } else { 0 }) | (owner as u32) << OWNER_SHIFT | number as u32
+}
+
+fn lookup_conduit() -> u8 {
+    let method = crate::devicetree::device_tree()
+        .and_then(|dt| {
+            dt.nodes().find(|node| {
+                node.name() == "psci" || node.is_compatible("arm,psci-0.2") || node.is_compatible("arm,psci-1.0")
+            })
+        })
+        .and_then(|node| node.property_str("method"));
+    match method {
+        Some("smc") => CONDUIT_SMC,
+        Some("hvc") => CONDUIT_HVC,
+        _ => CONDUIT_NONE,
+    }
+}
+
+/// The firmware conduit, if the device tree describes one.
+pub fn conduit() -> Option<Conduit> {
+    let mut value = CONDUIT.load(Ordering::Relaxed);
+    if value == CONDUIT_UNKNOWN {
+        value = lookup_conduit();
+        CONDUIT.store(value, Ordering::Relaxed);
+    }
+    match value {
+        CONDUIT_SMC => Some(Conduit::Smc),
+        CONDUIT_HVC => Some(Conduit::Hvc),
+        _ => None,
+    }
+}
+
+/// Issue `function` with up to seven arguments; x0-x3 as they come back.
+pub fn call(function: u32, args: &[u64]) -> Result<[u64; 4], &'static str> {
+    if args.len() > MAX_ARGS {
+        return Err("Too many firmware call arguments");
+    }
+    let mut regs = [0u64; 8];
+    regs[0] = function as u64;
+    regs[1..=args.len()].copy_from_slice(args);
+    unsafe {
+        // SMCCC 1.0 lets firmware corrupt x4-x17 as well
+        match conduit().ok_or("No firmware conduit in the device tree")? {
+            Conduit::Smc => asm!("smc #0",
+                                 inout("x0") regs[0], inout("x1") regs[1], inout("x2") regs[2], inout("x3") regs[3],
+                                 inout("x4") regs[4] => _, inout("x5") regs[5] => _,
+                                 inout("x6") regs[6] => _, inout("x7") regs[7] => _, clobber_abi("C")),
+            Conduit::Hvc => asm!("hvc #0",
+                                 inout("x0") regs[0], inout("x1") regs[1], inout("x2") regs[2], inout("x3") regs[3],
+                                 inout("x4") regs[4] => _, inout("x5") regs[5] => _,
+                                 inout("x6") regs[6] => _, inout("x7") regs[7] => _, clobber_abi("C")),
+        }
+    }
+    Ok([regs[0], regs[1], regs[2], regs[3]])
+}
+
+fn discover_version() -> u32 {
+    let version_1_0 = 1 << 16;
+    let psci = call(PSCI_VERSION, &[]).map(|regs| regs[0] as i32);
+    if !matches!(psci, Ok(version) if version >= version_1_0) {
+        return version_1_0 as u32;
+    }
+    match call(PSCI_FEATURES, &[SMCCC_VERSION as u64]) {
+        Ok(regs) if (regs[0] as i32) >= 0 => match call(SMCCC_VERSION, &[]) {
+            Ok(regs) if (regs[0] as i32) > 0 => regs[0] as u32,
+            _ => version_1_0 as u32,
+        },
+        _ => version_1_0 as u32,
+    }
+}
+
+/// SMCCC version as (major, minor), or None without a conduit.
+pub fn version() -> Option<(u16, u16)> {
+    conduit()?;
+    let mut version = VERSION.load(Ordering::Relaxed);
+    if version == 0 {
+        version = discover_version();
+        VERSION.store(version, Ordering::Relaxed);
+    }
+    Some(((version >> 16) as u16 & 0x7FFF, version as u16))
+}
+
+/// Whether an SMCCC architecture call (workarounds, SOC_ID and the like)
+/// is implemented. Needs SMCCC 1.1.
+pub fn arch_features(function: u32) -> bool {
+    if version().is_none_or(|version| version < (1, 1)) {
+        return false;
+    }
+    call(SMCCC_ARCH_FEATURES, &[function as u64]).is_ok_and(|regs| (regs[0] as i32) >= 0)
+}
+
+fn trng_version() -> Option<(u16, u16)> {
+    if version().is_none_or(|version| version < (1, 1)) {
+        return None;
+    }
+    let version = call(TRNG_VERSION, &[]).ok()?[0] as i32;
+    (version > 0).then_some(((version >> 16) as u16, version as u16))
+}
+
+/// Entropy source: fills `buf` from TRNG_RND64, stopping at the first
+/// refusal (the firmware may be out of entropy for now).
+pub fn trng_fill(buf: &mut [u8]) -> usize {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let Ok(regs) = call(TRNG_RND64, &[TRNG_MAX_BITS]) else {
+            break;
+        };
+        if regs[0] != 0 {
+            break;
+        }
+        for word in [regs[3], regs[2], regs[1]] {
+            let len = (buf.len() - filled).min(8);
+            buf[filled..filled + len].copy_from_slice(&word.to_le_bytes()[..len]);
+            filled += len;
+        }
+    }
+    filled
+}
+
+fn proc_firmware(out: &mut Vec<u8>) {
+    let mut text = alloc::string::String::new();
+    match (conduit(), version()) {
+        (Some(conduit), Some((major, minor))) => {
+            let _ = writeln!(text, "conduit {:?}", conduit);
+            let _ = writeln!(text, "smccc {}.{}", major, minor);
+        }
+        _ => {
+            let _ = writeln!(text, "conduit none");
+        }
+    }
+    let _ = writeln!(text, "trng {}", if HAS_TRNG.load(Ordering::Relaxed) { "yes" } else { "no" });
+    out.extend_from_slice(text.as_bytes());
+}
+
+/// Find the conduit and SMCCC version, and feed the firmware TRNG to the
+/// random generator when there is one.
+pub fn init() {
+    match (conduit(), version()) {
+        (Some(conduit), Some((major, minor))) => {
+            crate::println!("Firmware: SMCCC {}.{} via {:?}", major, minor, conduit);
+        }
+        _ => crate::println!("Firmware: No SMCCC conduit, firmware services unavailable"),
+    }
+    if let Some((major, minor)) = trng_version() {
+        crate::println!("Firmware: TRNG {}.{}", major, minor);
+        HAS_TRNG.store(true, Ordering::Relaxed);
+        if let Err(e) = crate::rand::register_source("smccc-trng", trng_fill) {
+            crate::println!("Firmware: {}", e);
+        }
+    }
+    let _ = crate::procfs::register("firmware", proc_firmware);
+}
@@ -0,0 +1,819 @@
+// GDB remote serial protocol stub on a second PL011
+//
+// Lets gdb debug the kernel itself, on real hardware as well as QEMU:
+//
+//     make run GDBSTUB=4445
+//     gdb target/aarch64-unknown-none/debug/rustkernel -ex 'target remote :4445'
+//
+// The kernel stops on a breakpoint, a completed single step, Ctrl-C from
+// gdb, or at boot with the `gdbwait` bootarg. A stop runs the protocol
+// loop inside the debug exception handler with interrupts masked, polling
+// the UART, so the rest of the kernel is frozen until gdb continues.
+// Secondary CPUs never leave boot.s, so there is nothing else to stop.
+//
+// Software breakpoints are BRK #0 written over kernel text, which is
+// read-only and so patched through `mmu::write_kernel_text`; single steps
+// use MDSCR_EL1.SS with SPSR.SS set on the way back. Memory accesses are
+// checked with an address translation first so a bad address from gdb is
+// an error reply rather than a kernel data abort.
+
+use core::arch::asm;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::interrupts::{ExceptionClass, ExceptionContext};
+use crate::ktest::kernel_test;
+use crate::memory::frame_allocator::PAGE_SIZE;
+use crate::memory::paging::phys_to_virt;
+use crate::uart::Uart;
+
+/// Immediate of the breakpoint compiled into `breakpoint()`; gdb's own
+/// breakpoints use 0.
+pub const COMPILED_BRK_IMM: u16 = 0x401;
+
+// BRK #imm; gdb's aarch64 breakpoint is BRK #0
+const BRK_INSN: u32 = 0xD420_0000;
+
+/// Largest packet payload accepted or sent, as advertised in qSupported.
+pub const PACKET_SIZE: usize = 512;
+
+// Breakpoints gdb may have inserted at once
+const MAX_BREAKPOINTS: usize = 32;
+
+// Ctrl-C from gdb requests a stop
+const INTERRUPT_BYTE: u8 = 0x03;
+
+// Times a packet is resent before giving up on the acknowledgement
+const SEND_RETRIES: usize = 8;
+
+// Register numbers in gdb's default aarch64 layout
+const REG_SP: usize = 31;
+const REG_PC: usize = 32;
+const REG_CPSR: usize = 33;
+const NUM_REGS: usize = 34;
+// Bytes of one 'g' packet: x0-x30, sp, pc, then the 32-bit cpsr
+const REGS_SIZE: usize = 33 * 8 + 4;
+
+// SPSR bits: software step, and the debug and IRQ masks
+const SPSR_SS: u64 = 1 << 21;
+const SPSR_D: u64 = 1 << 9;
+const SPSR_I: u64 = 1 << 7;
+// Only the condition flags may be changed from gdb
+const SPSR_NZCV: u64 = 0xF000_0000;
+const SPSR_MODE_MASK: u64 = 0xF;
+const SPSR_MODE_EL0T: u64 = 0;
+
+// MDSCR_EL1: software step enable, and debug exceptions at EL1
+const MDSCR_SS: u64 = 1 << 0;
+const MDSCR_KDE: u64 = 1 << 13;
+
+/// Byte transport for the protocol.
+pub trait Channel {
+    /// Next received byte, waiting for one.
+    fn read(&mut self) -> u8;
+    fn write(&mut self, bytes: &[u8]);
+}
+
+impl Channel for Uart {
+    fn read(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.get_char() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.put_char(byte);
+        }
+    }
+}
+
+/// A reply under construction.
+pub struct Reply {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    pub const fn new() -> Self {
+        Reply { data: [0; PACKET_SIZE], len: 0 }
+    }
+    
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+    
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(PACKET_SIZE - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+    
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(&[HEX_DIGITS[(byte >> 4) as usize], HEX_DIGITS[(byte & 0xF) as usize]]);
+        }
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// A big-endian hex number such as an address or length.
+pub fn parse_hex(text: &[u8]) -> Option<u64> {
+    if text.is_empty() || text.len() > 16 {
+        return None;
+    }
+    text.iter().try_fold(0u64, |value, &digit| Some(value << 4 | hex_value(digit)? as u64))
+}
+
+/// Decode hex byte pairs into `out`, which must be exactly half as long.
+pub fn decode_hex(text: &[u8], out: &mut [u8]) -> Option<()> {
+    if text.len() != out.len() * 2 {
+        return None;
+    }
+    for (byte, pair) in out.iter_mut().zip(text.chunks(2)) {
+        *byte = hex_value(pair[0])? << 4 | hex_value(pair[1])?;
+    }
+    Some(())
+}
+
+/// Modulo-256 sum used as the packet checksum.
+pub fn checksum(payload: &[u8]) -> u8 {
+    payload.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Receive one packet into `buf`, acknowledging it, and return its length.
+/// `started` means the leading '$' was already consumed.
+pub fn receive<C: Channel>(channel: &mut C, buf: &mut [u8], mut started: bool) -> usize {
+    loop {
+        // Acks, Ctrl-C and line noise between packets are dropped
+        if !started {
+            while channel.read() != b'$' {}
+        }
+        started = false;
+        
+        let mut len = 0;
+        let mut overflow = false;
+        let end = loop {
+            match channel.read() {
+                b'#' => break true,
+                // A new packet start abandons a truncated one
+                b'$' => break false,
+                byte if len < buf.len() => {
+                    buf[len] = byte;
+                    len += 1;
+                }
+                _ => overflow = true,
+            }
+        };
+        if !end {
+            started = true;
+            continue;
+        }
+        
+        let sum = [channel.read(), channel.read()];
+        let expected = hex_value(sum[0]).zip(hex_value(sum[1])).map(|(high, low)| high << 4 | low);
+        if overflow || expected != Some(checksum(&buf[..len])) {
+            channel.write(b"-");
+            continue;
+        }
+        channel.write(b"+");
+        return len;
+    }
+}
+
+/// Send one packet, resending until gdb acknowledges it.
+pub fn send<C: Channel>(channel: &mut C, payload: &[u8]) {
+    let sum = checksum(payload);
+    for _ in 0..SEND_RETRIES {
+        channel.write(b"$");
+        channel.write(payload);
+        channel.write(&[b'#', HEX_DIGITS[(sum >> 4) as usize], HEX_DIGITS[(sum & 0xF) as usize]]);
+        loop {
+            match channel.read() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// What the stopped kernel does after a packet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Send the reply and wait for the next packet.
+    Reply,
+    /// Return from the exception; no reply.
+    Resume,
+    /// Send the reply, drop every breakpoint and resume.
+    Detach,
+}
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    addr: u64,
+    original: u32,
+}
+
+/// Debugger state that outlives a stop.
+pub struct Stub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    // gdb has talked to us; stops are then announced
+    connected: bool,
+    // SPSR D and I bits to restore once a single step completes
+    step_mask: Option<u64>,
+}
+
+impl Stub {
+    pub const fn new() -> Self {
+        Stub { breakpoints: [None; MAX_BREAKPOINTS], connected: false, step_mask: None }
+    }
+    
+    pub fn has_breakpoint(&self, addr: u64) -> bool {
+        self.breakpoints.iter().flatten().any(|bp| bp.addr == addr)
+    }
+    
+    /// Carry out one packet, filling `reply`.
+    pub fn handle_packet(&mut self, ctx: &mut ExceptionContext, packet: &[u8], reply: &mut Reply) -> Action {
+        let Some((&command, args)) = packet.split_first() else {
+            return Action::Reply;
+        };
+        let result = match command {
+            b'?' => {
+                reply.push(b"S05");
+                Ok(())
+            }
+            b'g' => {
+                for reg in 0..NUM_REGS {
+                    let (value, width) = read_register(ctx, reg);
+                    reply.push_hex(&value.to_le_bytes()[..width]);
+                }
+                Ok(())
+            }
+            b'G' => write_registers(ctx, args),
+            b'p' => parse_hex(args)
+                .filter(|&reg| (reg as usize) < NUM_REGS)
+                .map(|reg| {
+                    let (value, width) = read_register(ctx, reg as usize);
+                    reply.push_hex(&value.to_le_bytes()[..width]);
+                })
+                .ok_or(ERR_ARGS),
+            b'P' => write_register_arg(ctx, args),
+            b'm' => read_memory_arg(args, reply),
+            b'M' => write_memory_arg(args),
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(addr) => ctx.elr_el1 = addr,
+                        None => return error(reply, ERR_ARGS),
+                    }
+                }
+                if command == b's' {
+                    self.begin_step(ctx);
+                }
+                return Action::Resume;
+            }
+            b'Z' | b'z' => match args.strip_prefix(b"0,") {
+                Some(args) => self.breakpoint_arg(command == b'Z', args),
+                // Hardware breakpoints and watchpoints are not supported
+                None => return Action::Reply,
+            },
+            b'D' | b'k' => {
+                reply.push(b"OK");
+                return Action::Detach;
+            }
+            b'H' | b'T' => Ok(()),
+            b'q' => {
+                if args.starts_with(b"Supported") {
+                    reply.push(b"PacketSize=");
+                    let mut digits = [0u8; 8];
+                    let size = format_hex(PACKET_SIZE as u64, &mut digits);
+                    reply.push(size);
+                } else if args == b"Attached" {
+                    reply.push(b"1");
+                }
+                return Action::Reply;
+            }
+            // Unknown packets get an empty reply
+            _ => return Action::Reply,
+        };
+        match result {
+            Ok(()) if reply.len == 0 => reply.push(b"OK"),
+            Ok(()) => {}
+            Err(code) => return error(reply, code),
+        }
+        Action::Reply
+    }
+    
+    fn breakpoint_arg(&mut self, insert: bool, args: &[u8]) -> Result<(), u8> {
+        let addr = args.split(|&b| b == b',').next().and_then(parse_hex).ok_or(ERR_ARGS)?;
+        if insert {
+            self.insert_breakpoint(addr)
+        } else {
+            self.remove_breakpoint(addr)
+        }
+    }
+    
+    fn insert_breakpoint(&mut self, addr: u64) -> Result<(), u8> {
+        if self.has_breakpoint(addr) {
+            return Ok(());
+        }
+        if !addr.is_multiple_of(4) || !writable(addr, 4) {
+            return Err(ERR_FAULT);
+        }
+        let slot = self.breakpoints.iter_mut().find(|bp| bp.is_none()).ok_or(ERR_NO_SPACE)?;
+        let original = unsafe { core::ptr::read_volatile(addr as *const u32) };
+        write_insn(addr, BRK_INSN);
+        *slot = Some(Breakpoint { addr, original });
+        Ok(())
+    }
+    
+    fn remove_breakpoint(&mut self, addr: u64) -> Result<(), u8> {
+        let slot = self.breakpoints.iter_mut().find(|bp| bp.is_some_and(|bp| bp.addr == addr)).ok_or(ERR_ARGS)?;
+        if let Some(bp) = slot.take() {
+            write_insn(bp.addr, bp.original);
+        }
+        Ok(())
+    }
+    
+    fn remove_all_breakpoints(&mut self) {
+        for bp in self.breakpoints.iter_mut().filter_map(Option::take) {
+            write_insn(bp.addr, bp.original);
+        }
+    }
+    
+    // Step one instruction with IRQs masked, so the step lands in the
+    // stepped code rather than in an interrupt handler
+    fn begin_step(&mut self, ctx: &mut ExceptionContext) {
+        self.step_mask = Some(ctx.spsr_el1 & (SPSR_D | SPSR_I));
+        ctx.spsr_el1 = (ctx.spsr_el1 | SPSR_SS | SPSR_I) & !SPSR_D;
+        write_mdscr(read_mdscr() | MDSCR_SS | MDSCR_KDE);
+    }
+    
+    fn finish_step(&mut self, ctx: &mut ExceptionContext) {
+        if let Some(mask) = self.step_mask.take() {
+            ctx.spsr_el1 = (ctx.spsr_el1 & !(SPSR_SS | SPSR_D | SPSR_I)) | mask;
+        }
+        // Hardware breakpoints need KDE kept on
+        let kde = if crate::kdebug::is_armed() { 0 } else { MDSCR_KDE };
+        write_mdscr(read_mdscr() & !(MDSCR_SS | kde));
+    }
+    
+    // Talk to gdb until it resumes the kernel
+    fn session<C: Channel>(&mut self, ctx: &mut ExceptionContext, channel: &mut C) {
+        if self.connected {
+            send(channel, b"S05");
+        }
+        let mut packet = [0u8; PACKET_SIZE];
+        loop {
+            let len = receive(channel, &mut packet, START_PENDING.swap(false, Ordering::Relaxed));
+            self.connected = true;
+            let mut reply = Reply::new();
+            match self.handle_packet(ctx, &packet[..len], &mut reply) {
+                Action::Reply => send(channel, reply.as_bytes()),
+                Action::Resume => return,
+                Action::Detach => {
+                    send(channel, reply.as_bytes());
+                    self.remove_all_breakpoints();
+                    self.finish_step(ctx);
+                    self.connected = false;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Error replies, as errno values
+const ERR_ARGS: u8 = 0x01;
+const ERR_FAULT: u8 = 0x0E;
+const ERR_NO_SPACE: u8 = 0x1C;
+
+fn error(reply: &mut Reply, code: u8) -> Action {
+    reply.len = 0;
+    reply.push(b"E");
+    reply.push_hex(&[code]);
+    Action::Reply
+}
+
+// Digits of `value` without leading zeros
+fn format_hex(value: u64, out: &mut [u8; 8]) -> &[u8] {
+    let digits = ((64 - value.leading_zeros() as usize).div_ceil(4)).max(1);
+    for (i, digit) in out[..digits].iter_mut().enumerate() {
+        *digit = HEX_DIGITS[((value >> ((digits - 1 - i) * 4)) & 0xF) as usize];
+    }
+    &out[..digits]
+}
+
+fn is_user(ctx: &ExceptionContext) -> bool {
+    ctx.spsr_el1 & SPSR_MODE_MASK == SPSR_MODE_EL0T
+}
+
+// Value and width in bytes of a gdb register
+fn read_register(ctx: &ExceptionContext, reg: usize) -> (u64, usize) {
+    match reg {
+        0..=30 => (ctx.x(reg), 8),
+        // EL1 exceptions push the frame on the interrupted stack
+        REG_SP if is_user(ctx) => (ctx.sp_el0, 8),
+        REG_SP => (ctx as *const ExceptionContext as u64 + size_of::<ExceptionContext>() as u64, 8),
+        REG_PC => (ctx.elr_el1, 8),
+        _ => (ctx.spsr_el1 & 0xFFFF_FFFF, 4),
+    }
+}
+
+fn write_register(ctx: &mut ExceptionContext, reg: usize, value: u64) {
+    match reg {
+        0..=30 => *ctx.x_mut(reg) = value,
+        REG_SP if is_user(ctx) => ctx.sp_el0 = value,
+        // The kernel stack pointer is where the frame is; it cannot move
+        REG_SP => {}
+        REG_PC => ctx.elr_el1 = value,
+        _ => ctx.spsr_el1 = (ctx.spsr_el1 & !SPSR_NZCV) | (value & SPSR_NZCV),
+    }
+}
+
+fn write_registers(ctx: &mut ExceptionContext, args: &[u8]) -> Result<(), u8> {
+    let mut bytes = [0u8; REGS_SIZE];
+    decode_hex(args, &mut bytes).ok_or(ERR_ARGS)?;
+    for reg in 0..NUM_REGS {
+        let start = reg * 8;
+        let width = if reg == REG_CPSR { 4 } else { 8 };
+        let mut value = [0u8; 8];
+        value[..width].copy_from_slice(&bytes[start..start + width]);
+        write_register(ctx, reg, u64::from_le_bytes(value));
+    }
+    Ok(())
+}
+
+// P<reg>=<value, target byte order>
+fn write_register_arg(ctx: &mut ExceptionContext, args: &[u8]) -> Result<(), u8> {
+    let split = args.iter().position(|&b| b == b'=').ok_or(ERR_ARGS)?;
+    let reg = parse_hex(&args[..split]).filter(|&reg| (reg as usize) < NUM_REGS).ok_or(ERR_ARGS)? as usize;
+    let width = if reg == REG_CPSR { 4 } else { 8 };
+    let mut value = [0u8; 8];
+    decode_hex(&args[split + 1..], &mut value[..width]).ok_or(ERR_ARGS)?;
+    write_register(ctx, reg, u64::from_le_bytes(value));
+    Ok(())
+}
+
+// <addr>,<length>, optionally followed by :<data>
+fn parse_range(args: &[u8]) -> Option<(u64, usize, Option<&[u8]>)> {
+    let (range, data) = match args.iter().position(|&b| b == b':') {
+        Some(colon) => (&args[..colon], Some(&args[colon + 1..])),
+        None => (args, None),
+    };
+    let comma = range.iter().position(|&b| b == b',')?;
+    let addr = parse_hex(&range[..comma])?;
+    let len = parse_hex(&range[comma + 1..])? as usize;
+    addr.checked_add(len as u64)?;
+    Some((addr, len, data))
+}
+
+fn read_memory_arg(args: &[u8], reply: &mut Reply) -> Result<(), u8> {
+    let (addr, len, _) = parse_range(args).ok_or(ERR_ARGS)?;
+    if len > PACKET_SIZE / 2 {
+        return Err(ERR_ARGS);
+    }
+    if !accessible(addr, len, false) {
+        return Err(ERR_FAULT);
+    }
+    for offset in 0..len as u64 {
+        let byte = unsafe { core::ptr::read_volatile((addr + offset) as *const u8) };
+        reply.push_hex(&[byte]);
+    }
+    Ok(())
+}
+
+fn write_memory_arg(args: &[u8]) -> Result<(), u8> {
+    let (addr, len, data) = parse_range(args).ok_or(ERR_ARGS)?;
+    let data = data.ok_or(ERR_ARGS)?;
+    if len > PACKET_SIZE / 2 {
+        return Err(ERR_ARGS);
+    }
+    let mut bytes = [0u8; PACKET_SIZE / 2];
+    decode_hex(data, &mut bytes[..len]).ok_or(ERR_ARGS)?;
+    if !writable(addr, len) {
+        return Err(ERR_FAULT);
+    }
+    write_bytes(addr, &bytes[..len]);
+    // gdb may have patched code
+    sync_icache(addr, len);
+    Ok(())
+}
+
+// Kernel text is read-only but can still be patched
+fn writable(addr: u64, len: usize) -> bool {
+    crate::memory::mmu::is_kernel_text(addr, len) || accessible(addr, len, true)
+}
+
+fn write_bytes(addr: u64, bytes: &[u8]) {
+    if crate::memory::mmu::write_kernel_text(addr, bytes).is_ok() {
+        return;
+    }
+    for (offset, &byte) in bytes.iter().enumerate() {
+        unsafe { core::ptr::write_volatile((addr + offset as u64) as *mut u8, byte) };
+    }
+}
+
+/// Whether every page of `[addr, addr + len)` translates for a kernel
+/// read, or a write.
+pub fn accessible(addr: u64, len: usize, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let page = PAGE_SIZE as u64;
+    let Some(last) = addr.checked_add(len as u64 - 1) else {
+        return false;
+    };
+    let mut probe = addr & !(page - 1);
+    while probe <= last {
+        let par: u64;
+        unsafe {
+            if write {
+                asm!("at s1e1w, {}", in(reg) probe);
+            } else {
+                asm!("at s1e1r, {}", in(reg) probe);
+            }
+            asm!("isb", "mrs {}, par_el1", out(reg) par);
+        }
+        // PAR_EL1.F: the translation faulted
+        if par & 1 != 0 {
+            return false;
+        }
+        match probe.checked_add(page) {
+            Some(next) => probe = next,
+            None => break,
+        }
+    }
+    true
+}
+
+fn write_insn(addr: u64, insn: u32) {
+    write_bytes(addr, &insn.to_le_bytes());
+    sync_icache(addr, 4);
+}
+
+// Make instructions written through the data cache visible to fetches
+pub fn sync_icache(addr: u64, len: usize) {
+    let ctr: u64;
+    unsafe {
+        asm!("mrs {}, ctr_el0", out(reg) ctr);
+    }
+    let dline = 4u64 << ((ctr >> 16) & 0xF);
+    let iline = 4u64 << (ctr & 0xF);
+    let end = addr + len as u64;
+    unsafe {
+        let mut line = addr & !(dline - 1);
+        while line < end {
+            asm!("dc cvau, {}", in(reg) line);
+            line += dline;
+        }
+        asm!("dsb ish");
+        let mut line = addr & !(iline - 1);
+        while line < end {
+            asm!("ic ivau, {}", in(reg) line);
+            line += iline;
+        }
+        asm!("dsb ish", "isb");
+    }
+}
+
+fn read_mdscr() -> u64 {
+    let mdscr: u64;
+    unsafe {
+        asm!("mrs {}, mdscr_el1", out(reg) mdscr);
+    }
+    mdscr
+}
+
+fn write_mdscr(mdscr: u64) {
+    unsafe {
+        asm!("msr mdscr_el1, {}", "isb", in(reg) mdscr);
+    }
+}
+
+static STUB: Mutex<Stub> = Mutex::new(Stub::new());
+
+// Linear-map address of the stub's UART, 0 when there is none
+static UART_BASE: AtomicUsize = AtomicUsize::new(0);
+
+// The receive interrupt consumed the '$' of gdb's first packet
+static START_PENDING: AtomicBool = AtomicBool::new(false);
+
+fn uart() -> Option<Uart> {
+    match UART_BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(Uart::at(base as *mut u32)),
+    }
+}
+
+pub fn is_enabled() -> bool {
+    UART_BASE.load(Ordering::Acquire) != 0
+}
+
+/// Attach the stub to the first PL011 that is not the console.
+pub fn init() {
+    let Some(dt) = crate::devicetree::device_tree() else {
+        return;
+    };
+    let node = dt.find_compatible("arm,pl011")
+        .find(|node| node.reg(0).is_some_and(|(base, _)| base != crate::uart::console_phys()));
+    let Some((node, (phys, _))) = node.and_then(|node| node.reg(0).map(|reg| (node, reg))) else {
+        crate::println!("GDB: No second UART, stub disabled");
+        return;
+    };
+    
+    // Debug exceptions other than BRK are held off while the OS lock is set
+    unsafe {
+        asm!("msr oslar_el1, xzr", "msr osdlr_el1, xzr", "isb");
+    }
+    
+    let base = phys_to_virt(phys) as usize;
+    let uart = Uart::at(base as *mut u32);
+    uart.init();
+    UART_BASE.store(base, Ordering::Release);
+    
+    // Without a receive interrupt gdb can only take over at a breakpoint
+    let irq = crate::gic::is_present().then(|| crate::gic::dt_interrupt(&node, 0)).flatten();
+    match irq.map(|irq| (irq, crate::gic::register_handler(irq, uart_rx_irq))) {
+        Some((irq, Ok(()))) => {
+            uart.enable_rx_interrupt();
+            crate::println!("GDB: Stub on UART at 0x{:x}, IRQ {}", phys, irq);
+        }
+        Some((irq, Err(e))) => crate::println!("GDB: Stub on UART at 0x{:x}, IRQ {}: {}", phys, irq, e),
+        None => crate::println!("GDB: Stub on UART at 0x{:x}, no interrupt", phys),
+    }
+    
+    if crate::bootparams::flag("gdbwait") {
+        crate::println!("GDB: Waiting for debugger");
+        breakpoint();
+    }
+}
+
+/// Stop in the debugger, if one can attach.
+#[inline(always)]
+pub fn breakpoint() {
+    if is_enabled() {
+        unsafe { asm!("brk #{imm}", imm = const COMPILED_BRK_IMM) };
+    }
+}
+
+// gdb sends Ctrl-C to interrupt, or starts talking while the kernel runs
+fn uart_rx_irq(_irq: u32) {
+    let Some(uart) = uart() else {
+        return;
+    };
+    while let Some(byte) = uart.get_char() {
+        if byte == INTERRUPT_BYTE || byte == b'$' {
+            START_PENDING.store(byte == b'$', Ordering::Relaxed);
+            uart.clear_rx_interrupt();
+            breakpoint();
+            return;
+        }
+    }
+    uart.clear_rx_interrupt();
+}
+
+/// Take a BRK or software step exception if it belongs to the debugger,
+/// returning false to let the caller treat it as unexpected.
+pub fn handle_debug_exception(ctx: &mut ExceptionContext, class: ExceptionClass, iss: u64) -> bool {
+    let Some(mut uart) = uart() else {
+        return false;
+    };
+    let mut stub = STUB.lock();
+    match class {
+        ExceptionClass::Breakpoint if is_user(ctx) => return false,
+        ExceptionClass::Breakpoint => {
+            let imm = (iss & 0xFFFF) as u16;
+            if imm == COMPILED_BRK_IMM {
+                // Resume after the compiled-in BRK, not on it
+                ctx.elr_el1 += 4;
+            } else if imm != 0 || !(stub.connected || stub.has_breakpoint(ctx.elr_el1)) {
+                return false;
+            }
+        }
+        ExceptionClass::SoftwareStepCurrentEl | ExceptionClass::SoftwareStepLowerEl => {
+            if stub.step_mask.is_none() {
+                return false;
+            }
+            stub.finish_step(ctx);
+        }
+        _ => return false,
+    }
+    // gdb may keep the kernel stopped for longer than any watchdog allows
+    crate::watchdog::pause();
+    stub.session(ctx, &mut uart);
+    crate::watchdog::resume();
+    true
+}
+
+// Scripted gdb side of a connection
+struct ScriptedGdb<'a> {
+    input: &'a [u8],
+    output: alloc::vec::Vec<u8>,
+}
+
+impl Channel for ScriptedGdb<'_> {
+    fn read(&mut self) -> u8 {
+        // Running off the script acknowledges whatever was sent
+        let (&byte, rest) = self.input.split_first().unwrap_or((&b'+', &[]));
+        self.input = rest;
+        byte
+    }
+    
+    fn write(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+}
+
+#[kernel_test]
+fn test_gdb_stub() {
+    use alloc::format;
+    use alloc::vec;
+    use crate::interrupts::ExceptionContext;
+    
+    crate::println!("GDB Test: Testing GDB stub protocol...");
+    
+    // A corrupted packet is nacked and the retransmission accepted
+    let mut gdb = ScriptedGdb { input: b"+$g#00$g#67", output: vec![] };
+    let mut packet = [0u8; 64];
+    let len = receive(&mut gdb, &mut packet, false);
+    send(&mut gdb, b"OK");
+    if &packet[..len] == b"g" && gdb.output == b"-+$OK#9a" {
+        crate::println!("GDB Test: ✓ Packet framing, checksums and acks");
+    } else {
+        crate::println!("GDB Test: ✗ Packet framing gave {:?}, sent {:?}", &packet[..len], gdb.output);
+    }
+    
+    let mut stub = Stub::new();
+    let mut ctx = ExceptionContext { x0: 0x1122_3344_5566_7788, x30: 0xabcd, elr_el1: 0x1000, spsr_el1: 0x3c5, ..Default::default() };
+    let run = |stub: &mut Stub, ctx: &mut ExceptionContext, packet: &str| {
+        let mut reply = Reply::new();
+        let action = stub.handle_packet(ctx, packet.as_bytes(), &mut reply);
+        (action, alloc::string::String::from_utf8_lossy(reply.as_bytes()).into_owned())
+    };
+    
+    let (_, regs) = run(&mut stub, &mut ctx, "g");
+    let (_, pc) = run(&mut stub, &mut ctx, "p20");
+    let (_, set) = run(&mut stub, &mut ctx, "P1e=efbeadde00000000");
+    // Mode and mask bits are kept when gdb rewrites cpsr
+    let (_, flags) = run(&mut stub, &mut ctx, "P21=ffffffff");
+    let regs_ok = regs.len() == (33 * 8 + 4) * 2 && regs.starts_with("8877665544332211")
+        && pc == "0010000000000000";
+    if regs_ok && set == "OK" && ctx.x30 == 0xdead_beef && flags == "OK" && ctx.spsr_el1 == 0xf000_03c5 {
+        crate::println!("GDB Test: ✓ Register read and write");
+    } else {
+        crate::println!("GDB Test: ✗ Registers gave {} / {} / x30 {:x} spsr {:x}", regs.len(), pc, ctx.x30, ctx.spsr_el1);
+    }
+    
+    let mut memory = alloc::boxed::Box::new([0x0403_0201u32, 0, 0, 0]);
+    let addr = memory.as_mut_ptr() as u64;
+    let (_, read) = run(&mut stub, &mut ctx, &format!("m{:x},4", addr));
+    let (_, write) = run(&mut stub, &mut ctx, &format!("M{:x},2:aabb", addr + 4));
+    let (_, bad) = run(&mut stub, &mut ctx, "mzz,4");
+    if read == "01020304" && write == "OK" && memory[1] == 0xbbaa && bad == "E01" {
+        crate::println!("GDB Test: ✓ Memory read and write");
+    } else {
+        crate::println!("GDB Test: ✗ Memory gave {} / {} / {:x} / {}", read, write, memory[1], bad);
+    }
+    
+    // Breakpoints go in and come out over the original instruction
+    let word = addr + 8;
+    memory[2] = 0xd503_201f;
+    let (_, insert) = run(&mut stub, &mut ctx, &format!("Z0,{:x},4", word));
+    let planted = memory[2] == 0xd420_0000 && stub.has_breakpoint(word);
+    let (_, remove) = run(&mut stub, &mut ctx, &format!("z0,{:x},4", word));
+    let (_, watch) = run(&mut stub, &mut ctx, &format!("Z2,{:x},4", word));
+    if insert == "OK" && planted && remove == "OK" && memory[2] == 0xd503_201f && watch.is_empty() {
+        crate::println!("GDB Test: ✓ Software breakpoint insert and remove");
+    } else {
+        crate::println!("GDB Test: ✗ Breakpoint gave {} / {} / {} / {:x}", insert, remove, watch, memory[2]);
+    }
+    
+    let (action, _) = run(&mut stub, &mut ctx, "c2000");
+    let (_, supported) = run(&mut stub, &mut ctx, "qSupported:swbreak+");
+    let (unknown_action, unknown) = run(&mut stub, &mut ctx, "vMustReplyEmpty");
+    if action == Action::Resume && ctx.elr_el1 == 0x2000 && supported == "PacketSize=200"
+        && unknown_action == Action::Reply && unknown.is_empty()
+    {
+        crate::println!("GDB Test: ✓ Continue and query packets");
+    } else {
+        crate::println!("GDB Test: ✗ Continue {:?} to {:x}, qSupported {}", action, ctx.elr_el1, supported);
+    }
+    
+    crate::println!("GDB Test: GDB stub test completed (stub {})",
+                   if is_enabled() { "attached" } else { "not attached" });
+}
@@ -0,0 +1,291 @@
+// ARM Generic Interrupt Controller driver
+//
+// The distributor (GICD) routes shared peripheral interrupts (SPIs, 32 and
+// up) to CPUs; each CPU's interface hands out the highest priority pending
+// interrupt and takes it back at end of interrupt. SGIs and PPIs (0..31)
+// are banked per CPU and always local. The device tree's compatible
+// string picks the version:
+//
+// - GICv2 (v2.rs): memory-mapped CPU interface, SPIs routed to a mask of
+//   CPU interfaces. QEMU virt's cortex-a15-gic and the Raspberry Pi 4's
+//   GIC-400 (under /soc, at a translated address).
+// - GICv3 (v3.rs): system-register CPU interface, a redistributor per CPU
+//   for its SGIs and PPIs, SPIs routed by affinity. QEMU virt with
+//   gic-version=3 or later machine types.
+//
+// Either way a CPU is one bit of a u8 mask: its GICv2 interface number,
+// or its affinity level 0 number on GICv3.
+//
+// Handlers are registered per interrupt ID and counted on delivery; the
+// counts drive irqbalance.
+
+mod v2;
+mod v3;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use crate::devicetree::{read_cell, DeviceTree};
+use crate::interrupts::{counter_ticks, HandlerTiming};
+use crate::sync::IrqSafeMutex;
+use crate::tracepoint::{self, Tracepoint};
+
+// IDs 1020..1023 are special; 1023 means nothing pending
+const SPURIOUS_IRQ: u32 = 1020;
+pub const MAX_IRQS: usize = 1020;
+
+/// First shared peripheral interrupt.
+pub const SPI_BASE: u32 = 32;
+pub const PPI_BASE: u32 = 16;
+
+// Number of software generated interrupt IDs (0..15)
+pub const NUM_SGIS: u32 = 16;
+
+// Lower is more urgent; everything shares one level for now
+const DEFAULT_PRIORITY: u8 = 0xA0;
+const PRIORITY_MASK_ALL: u32 = 0xF0;
+
+// Device tree interrupt specifier types
+const DT_IRQ_TYPE_SPI: u32 = 0;
+const DT_IRQ_TYPE_PPI: u32 = 1;
+
+const GICV2_COMPATIBLES: [&str; 3] = ["arm,cortex-a15-gic", "arm,gic-400", "arm,cortex-a9-gic"];
+const GICV3_COMPATIBLES: [&str; 1] = ["arm,gic-v3"];
+
+pub type IrqHandler = fn(u32);
+
+// Architecture version driven, 0 until init finds one
+static VERSION: AtomicU8 = AtomicU8::new(0);
+static NUM_IRQS: AtomicUsize = AtomicUsize::new(0);
+
+// Mask bit of each CPU brought online; only the boot CPU
+// runs the kernel so far
+static ONLINE_CPUS: AtomicU8 = AtomicU8::new(0);
+
+static HANDLERS: IrqSafeMutex<[Option<IrqHandler>; MAX_IRQS]> = IrqSafeMutex::new([None; MAX_IRQS]);
+static IRQ_COUNTS: [AtomicU64; MAX_IRQS] = [const { AtomicU64::new(0) }; MAX_IRQS];
+
+// Counter ticks spent in each line's handler. Only one CPU runs a given
+// line's handler at a time, so plain atomics are enough.
+struct LineTiming {
+    count: AtomicU64,
+    total: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl LineTiming {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+    
+    fn record(&self, ticks: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(ticks, Ordering::Relaxed);
+        self.min.fetch_min(ticks, Ordering::Relaxed);
+        self.max.fetch_max(ticks, Ordering::Relaxed);
+    }
+    
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.min.store(u64::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+static LINE_TIMING: [LineTiming; MAX_IRQS] = [const { LineTiming::new() }; MAX_IRQS];
+
+// Affinity set by hand; irqbalance leaves these alone
+static PINNED: [AtomicBool; MAX_IRQS] = [const { AtomicBool::new(false) }; MAX_IRQS];
+
+pub fn is_present() -> bool {
+    VERSION.load(Ordering::Relaxed) != 0
+}
+
+/// GIC architecture version in use (2 or 3), 0 without one.
+pub fn version() -> u8 {
+    VERSION.load(Ordering::Relaxed)
+}
+
+fn is_v3() -> bool {
+    VERSION.load(Ordering::Relaxed) == 3
+}
+
+/// Number of interrupt IDs the distributor implements.
+pub fn num_irqs() -> u32 {
+    NUM_IRQS.load(Ordering::Relaxed) as u32
+}
+
+pub fn online_cpus() -> u8 {
+    ONLINE_CPUS.load(Ordering::Relaxed)
+}
+
+/// Mask bit of the calling CPU.
+pub fn this_cpu() -> u8 {
+    if is_v3() { v3::this_cpu() } else { v2::this_cpu() }
+}
+
+/// Find the GIC in the device tree and bring up the distributor and this
+/// CPU's interface. Interrupts stay masked at the CPU until DAIF allows them.
+pub fn init(dt: &DeviceTree) -> Result<(), &'static str> {
+    let find = |compatibles: &[&str]| compatibles.iter().find_map(|compatible| dt.find_compatible(compatible).next());
+    let (version, num_irqs) = if let Some(node) = find(&GICV3_COMPATIBLES) {
+        (3, v3::init(&node)?)
+    } else if let Some(node) = find(&GICV2_COMPATIBLES) {
+        (2, v2::init(&node)?)
+    } else {
+        return Err("GIC: No GICv2 or GICv3 in device tree");
+    };
+    NUM_IRQS.store(num_irqs.min(MAX_IRQS), Ordering::Relaxed);
+    VERSION.store(version, Ordering::Relaxed);
+    ONLINE_CPUS.store(this_cpu(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Interrupt ID of the `index`th entry of a node's "interrupts" property.
+pub fn dt_interrupt(node: &crate::devicetree::DeviceNode, index: usize) -> Option<u32> {
+    let interrupts = node.property("interrupts")?;
+    let kind = read_cell(interrupts, index * 3)?;
+    let number = read_cell(interrupts, index * 3 + 1)?;
+    match kind {
+        DT_IRQ_TYPE_SPI => Some(number + SPI_BASE),
+        DT_IRQ_TYPE_PPI => Some(number + PPI_BASE),
+        _ => None,
+    }
+}
+
+fn check_irq(irq: u32) -> Result<usize, &'static str> {
+    if (irq as usize) < NUM_IRQS.load(Ordering::Relaxed) {
+        Ok(irq as usize)
+    } else {
+        Err("GIC: Interrupt ID out of range")
+    }
+}
+
+/// Install `handler` for `irq` and unmask it at the distributor.
+pub fn register_handler(irq: u32, handler: IrqHandler) -> Result<(), &'static str> {
+    let index = check_irq(irq)?;
+    {
+        let mut handlers = HANDLERS.lock();
+        if handlers[index].is_some() {
+            return Err("GIC: Interrupt already has a handler");
+        }
+        handlers[index] = Some(handler);
+    }
+    enable_irq(irq)
+}
+
+pub fn enable_irq(irq: u32) -> Result<(), &'static str> {
+    let index = check_irq(irq)?;
+    if is_v3() { v3::set_enabled(index, true) } else { v2::set_enabled(index, true) }
+    Ok(())
+}
+
+pub fn disable_irq(irq: u32) -> Result<(), &'static str> {
+    let index = check_irq(irq)?;
+    if is_v3() { v3::set_enabled(index, false) } else { v2::set_enabled(index, false) }
+    Ok(())
+}
+
+/// CPU mask an SPI is routed to.
+pub fn affinity(irq: u32) -> Option<u8> {
+    let index = check_irq(irq).ok()?;
+    Some(if is_v3() { v3::target(index) } else { v2::target(index) })
+}
+
+// Retarget an SPI; the change applies to the next assertion
+pub(crate) fn route(irq: u32, cpus: u8) -> Result<(), &'static str> {
+    let index = check_irq(irq)?;
+    if irq < SPI_BASE {
+        return Err("GIC: SGIs and PPIs are per CPU");
+    }
+    if cpus == 0 || cpus & !online_cpus() != 0 {
+        return Err("GIC: Target CPU not online");
+    }
+    if is_v3() { v3::set_target(index, cpus) } else { v2::set_target(index, cpus) }
+    Ok(())
+}
+
+/// Raise software generated interrupt `sgi` on every CPU in `cpus`.
+pub fn send_sgi(sgi: u32, cpus: u8) -> Result<(), &'static str> {
+    if sgi >= NUM_SGIS {
+        return Err("GIC: Not an SGI");
+    }
+    if is_v3() { v3::send_sgi(sgi, cpus) } else { v2::send_sgi(sgi, cpus) }
+    Ok(())
+}
+
+/// Pin an SPI to a set of CPU interfaces, taking it away from irqbalance.
+pub fn set_affinity(irq: u32, cpus: u8) -> Result<(), &'static str> {
+    route(irq, cpus)?;
+    PINNED[irq as usize].store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Hand a pinned SPI back to irqbalance.
+pub fn unpin(irq: u32) -> Result<(), &'static str> {
+    let index = check_irq(irq)?;
+    PINNED[index].store(false, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn is_pinned(irq: u32) -> bool {
+    check_irq(irq).is_ok_and(|index| PINNED[index].load(Ordering::Relaxed))
+}
+
+pub fn has_handler(irq: u32) -> bool {
+    check_irq(irq).is_ok_and(|index| HANDLERS.lock()[index].is_some())
+}
+
+/// Interrupts delivered for `irq` since boot.
+pub fn irq_count(irq: u32) -> u64 {
+    check_irq(irq).map_or(0, |index| IRQ_COUNTS[index].load(Ordering::Relaxed))
+}
+
+/// Time spent in `irq`'s handler since boot or the last reset.
+pub fn handler_timing(irq: u32) -> HandlerTiming {
+    let Ok(index) = check_irq(irq) else {
+        return HandlerTiming::new();
+    };
+    let timing = &LINE_TIMING[index];
+    HandlerTiming {
+        count: timing.count.load(Ordering::Relaxed),
+        total: timing.total.load(Ordering::Relaxed),
+        min: timing.min.load(Ordering::Relaxed),
+        max: timing.max.load(Ordering::Relaxed),
+    }
+}
+
+pub fn reset_handler_timing() {
+    for timing in &LINE_TIMING {
+        timing.reset();
+    }
+}
+
+/// Acknowledge and dispatch everything pending at this CPU interface.
+pub fn handle_irq() {
+    let v3 = is_v3();
+    loop {
+        let iar = if v3 { v3::acknowledge() } else { v2::acknowledge() };
+        let irq = if v3 { iar } else { iar & 0x3FF };
+        if irq >= SPURIOUS_IRQ {
+            break;
+        }
+        
+        IRQ_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+        let handler = HANDLERS.lock()[irq as usize];
+        tracepoint::hit(Tracepoint::IrqEntry, irq as u64, 0);
+        let start = counter_ticks();
+        match handler {
+            Some(handler) => handler(irq),
+            None => crate::println!("GIC: Unhandled interrupt {}", irq),
+        }
+        LINE_TIMING[irq as usize].record(counter_ticks() - start);
+        tracepoint::hit(Tracepoint::IrqExit, irq as u64, 0);
+        if v3 { v3::end_of_interrupt(iar) } else { v2::end_of_interrupt(iar) }
+    }
+}
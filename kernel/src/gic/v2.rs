@@ -0,0 +1,135 @@
+// GICv2: memory-mapped distributor and CPU interface
+//
+// SPIs go to the CPU interfaces set in their ITARGETSR byte; SGIs are
+// raised through GICD_SGIR with a target list of interfaces.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::devicetree::DeviceNode;
+use crate::memory::paging::phys_to_virt;
+use super::{DEFAULT_PRIORITY, PRIORITY_MASK_ALL, SPI_BASE};
+
+// Distributor registers
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_ICFGR: usize = 0xC00;
+const GICD_SGIR: usize = 0xF00;
+
+// CPU interface registers
+const GICC_CTLR: usize = 0x00;
+const GICC_PMR: usize = 0x04;
+const GICC_BPR: usize = 0x08;
+const GICC_IAR: usize = 0x0C;
+const GICC_EOIR: usize = 0x10;
+
+const GICD_CTLR_ENABLE: u32 = 1;
+const GICC_CTLR_ENABLE: u32 = 1;
+
+const GICD_SGIR_TARGET_SHIFT: u32 = 16;
+
+static GICD_BASE: AtomicUsize = AtomicUsize::new(0);
+static GICC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+fn gicd_read(offset: usize) -> u32 {
+    unsafe { read_volatile((GICD_BASE.load(Ordering::Relaxed) + offset) as *const u32) }
+}
+
+fn gicd_write(offset: usize, value: u32) {
+    unsafe { write_volatile((GICD_BASE.load(Ordering::Relaxed) + offset) as *mut u32, value) }
+}
+
+// Priority and target registers are byte accessible
+fn gicd_read8(offset: usize) -> u8 {
+    unsafe { read_volatile((GICD_BASE.load(Ordering::Relaxed) + offset) as *const u8) }
+}
+
+fn gicd_write8(offset: usize, value: u8) {
+    unsafe { write_volatile((GICD_BASE.load(Ordering::Relaxed) + offset) as *mut u8, value) }
+}
+
+fn gicc_read(offset: usize) -> u32 {
+    unsafe { read_volatile((GICC_BASE.load(Ordering::Relaxed) + offset) as *const u32) }
+}
+
+fn gicc_write(offset: usize, value: u32) {
+    unsafe { write_volatile((GICC_BASE.load(Ordering::Relaxed) + offset) as *mut u32, value) }
+}
+
+/// ITARGETSR bit of the calling CPU's interface.
+pub fn this_cpu() -> u8 {
+    // ITARGETSR0..7 are banked and read back the caller's own bit
+    gicd_read8(GICD_ITARGETSR)
+}
+
+/// Bring up the distributor and this CPU's interface; returns the number
+/// of interrupt IDs.
+pub fn init(node: &DeviceNode) -> Result<usize, &'static str> {
+    let (gicd, _) = node.reg(0).ok_or("GIC: Missing distributor registers")?;
+    let (gicc, _) = node.reg(1).ok_or("GIC: Missing CPU interface registers")?;
+    GICD_BASE.store(phys_to_virt(gicd) as usize, Ordering::Relaxed);
+    GICC_BASE.store(phys_to_virt(gicc) as usize, Ordering::Relaxed);
+    
+    gicd_write(GICD_CTLR, 0);
+    let lines = ((gicd_read(GICD_TYPER) & 0x1F) as usize + 1) * 32;
+    let num_irqs = lines.min(super::MAX_IRQS);
+    let this_cpu = this_cpu();
+    
+    // SPIs: disabled, not pending, level triggered, routed to this CPU
+    for irq in (SPI_BASE as usize..num_irqs).step_by(32) {
+        gicd_write(GICD_ICENABLER + irq / 8, !0);
+        gicd_write(GICD_ICPENDR + irq / 8, !0);
+    }
+    for irq in (SPI_BASE as usize..num_irqs).step_by(16) {
+        gicd_write(GICD_ICFGR + irq / 4, 0);
+    }
+    for irq in 0..num_irqs {
+        gicd_write8(GICD_IPRIORITYR + irq, DEFAULT_PRIORITY);
+        if irq >= SPI_BASE as usize {
+            gicd_write8(GICD_ITARGETSR + irq, this_cpu);
+        }
+    }
+    gicd_write(GICD_ICENABLER, !0);  // Banked SGIs/PPIs
+    gicd_write(GICD_CTLR, GICD_CTLR_ENABLE);
+    
+    // Let every priority through, no preemption grouping
+    gicc_write(GICC_PMR, PRIORITY_MASK_ALL);
+    gicc_write(GICC_BPR, 0);
+    gicc_write(GICC_CTLR, GICC_CTLR_ENABLE);
+    
+    crate::println!("GIC: GICv2 at 0x{:x}/0x{:x}, {} interrupt lines, CPU interface mask 0x{:02x}",
+                   gicd, gicc, num_irqs, this_cpu);
+    Ok(num_irqs)
+}
+
+pub fn set_enabled(index: usize, enabled: bool) {
+    let register = if enabled { GICD_ISENABLER } else { GICD_ICENABLER };
+    gicd_write(register + (index / 32) * 4, 1 << (index % 32));
+}
+
+pub fn target(index: usize) -> u8 {
+    gicd_read8(GICD_ITARGETSR + index)
+}
+
+pub fn set_target(index: usize, cpus: u8) {
+    gicd_write8(GICD_ITARGETSR + index, cpus);
+}
+
+pub fn send_sgi(sgi: u32, cpus: u8) {
+    // TargetListFilter 0: deliver to the listed interfaces
+    gicd_write(GICD_SGIR, ((cpus as u32) << GICD_SGIR_TARGET_SHIFT) | sgi);
+}
+
+/// Take the highest priority pending interrupt; the raw IAR, which
+/// `end_of_interrupt` wants back.
+pub fn acknowledge() -> u32 {
+    gicc_read(GICC_IAR)
+}
+
+pub fn end_of_interrupt(iar: u32) {
+    gicc_write(GICC_EOIR, iar);
+}
@@ -0,0 +1,291 @@
+// GICv3: distributor, per-CPU redistributors, system-register CPU interface
+//
+// With affinity routing on, SGIs and PPIs are configured in the calling
+// CPU's redistributor rather than the distributor, SPIs are routed by
+// GICD_IROUTER to one CPU's affinity (or to any CPU), and SGIs are raised
+// through ICC_SGI1R_EL1. Everything is non-secure group 1. LPIs and the
+// ITS are not used.
+//
+// A CPU's mask bit is its Aff0; SGIs and routes reach the other CPUs of
+// the calling CPU's cluster (Aff1..3), which on QEMU virt is all of them.
+
+use core::arch::asm;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::devicetree::DeviceNode;
+use crate::interrupts::{counter_frequency, counter_ticks};
+use crate::memory::paging::phys_to_virt;
+use super::{DEFAULT_PRIORITY, PRIORITY_MASK_ALL, SPI_BASE};
+
+// Distributor registers
+const GICD_CTLR: usize = 0x0000;
+const GICD_TYPER: usize = 0x0004;
+const GICD_IGROUPR: usize = 0x0080;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_ICENABLER: usize = 0x0180;
+const GICD_ICPENDR: usize = 0x0280;
+const GICD_IPRIORITYR: usize = 0x0400;
+const GICD_ICFGR: usize = 0x0C00;
+const GICD_IROUTER: usize = 0x6000;
+
+const GICD_CTLR_RWP: u32 = 1 << 31;
+// Non-secure view: Group 1 enable, Group 1 enable (single security
+// state: Group 0 and Group 1), affinity routing
+const GICD_CTLR_ENABLE_G1: u32 = 1 << 0;
+const GICD_CTLR_ENABLE_G1A: u32 = 1 << 1;
+const GICD_CTLR_ARE_NS: u32 = 1 << 4;
+// Interrupt_Routing_Mode: any participating CPU
+const GICD_IROUTER_ANY: u64 = 1 << 31;
+
+// Redistributor registers: RD_base frame, then the SGI_base frame after it
+const GICR_CTLR: usize = 0x0000;
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+const GICR_SGI_FRAME: usize = 0x1_0000;
+const GICR_IGROUPR0: usize = 0x0080;
+const GICR_ISENABLER0: usize = 0x0100;
+const GICR_ICENABLER0: usize = 0x0180;
+const GICR_ICPENDR0: usize = 0x0280;
+const GICR_IPRIORITYR: usize = 0x0400;
+
+const GICR_CTLR_RWP: u32 = 1 << 3;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+// RD_base and SGI_base, plus VLPI_base and a reserved frame with VLPIS
+const GICR_STRIDE: usize = 0x2_0000;
+const GICR_STRIDE_VLPIS: usize = 0x4_0000;
+
+// Aff3, Aff2 and Aff1 in MPIDR_EL1 and GICD_IROUTER alike
+const CLUSTER_MASK: u64 = 0xFF_00FF_FF00;
+
+const ICC_SRE_SRE: u64 = 1 << 0;
+const ICC_SGI1R_INTID_SHIFT: u64 = 24;
+
+const TIMEOUT_US: u64 = 10_000;
+
+static GICD_BASE: AtomicUsize = AtomicUsize::new(0);
+// The boot CPU's SGI_base frame
+static GICR_SGI_BASE: AtomicUsize = AtomicUsize::new(0);
+
+fn read32(addr: usize) -> u32 {
+    unsafe { read_volatile(addr as *const u32) }
+}
+
+fn write32(addr: usize, value: u32) {
+    unsafe { write_volatile(addr as *mut u32, value) }
+}
+
+fn read64(addr: usize) -> u64 {
+    unsafe { read_volatile(addr as *const u64) }
+}
+
+fn write64(addr: usize, value: u64) {
+    unsafe { write_volatile(addr as *mut u64, value) }
+}
+
+fn gicd(offset: usize) -> usize {
+    GICD_BASE.load(Ordering::Relaxed) + offset
+}
+
+fn gicr_sgi(offset: usize) -> usize {
+    GICR_SGI_BASE.load(Ordering::Relaxed) + offset
+}
+
+fn wait_for(what: &'static str, mut done: impl FnMut() -> bool) -> Result<(), &'static str> {
+    let deadline = counter_ticks() + counter_frequency() * TIMEOUT_US / 1_000_000;
+    while !done() {
+        if counter_ticks() > deadline {
+            return Err(what);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+fn mpidr() -> u64 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+    mpidr
+}
+
+// Aff3..Aff0 packed as GICR_TYPER reports them
+fn packed_affinity(mpidr: u64) -> u32 {
+    (((mpidr >> 32) & 0xFF) << 24 | (mpidr & 0xFF_FFFF)) as u32
+}
+
+/// Aff0 bit of the calling CPU (0 for an Aff0 that a u8 mask cannot hold).
+pub fn this_cpu() -> u8 {
+    match mpidr() & 0xFF {
+        aff0 @ 0..8 => 1 << aff0,
+        _ => 0,
+    }
+}
+
+// The calling CPU's redistributor, by its RD_base virtual address
+fn find_redistributor(node: &DeviceNode) -> Result<(u64, usize), &'static str> {
+    let affinity = packed_affinity(mpidr());
+    let regions = node.property_u32("#redistributor-regions").unwrap_or(1) as usize;
+    let stride = node.property("redistributor-stride")
+        .and_then(|value| value.try_into().ok())
+        .map(|value| u64::from_be_bytes(value) as usize);
+    for region in 0..regions {
+        let (phys, size) = node.reg(1 + region).ok_or("GIC: Missing redistributor registers")?;
+        let base = phys_to_virt(phys) as usize;
+        let mut offset = 0;
+        while offset + GICR_SGI_FRAME * 2 <= size as usize {
+            let typer = read64(base + offset + GICR_TYPER);
+            if (typer >> 32) as u32 == affinity {
+                return Ok((phys + offset as u64, base + offset));
+            }
+            if typer & GICR_TYPER_LAST != 0 {
+                break;
+            }
+            offset += match stride {
+                Some(stride) if stride != 0 => stride,
+                _ if typer & GICR_TYPER_VLPIS != 0 => GICR_STRIDE_VLPIS,
+                _ => GICR_STRIDE,
+            };
+        }
+    }
+    Err("GIC: No redistributor for this CPU")
+}
+
+// Switch to the system-register CPU interface; fails if EL2 keeps it
+// disabled
+fn enable_system_registers() -> Result<(), &'static str> {
+    let sre: u64;
+    unsafe {
+        asm!("mrs {0}, S3_0_C12_C12_5",     // ICC_SRE_EL1
+             "orr {0}, {0}, {sre}",
+             "msr S3_0_C12_C12_5, {0}",
+             "isb",
+             "mrs {0}, S3_0_C12_C12_5",
+             out(reg) sre, sre = const ICC_SRE_SRE);
+    }
+    if sre & ICC_SRE_SRE == 0 {
+        return Err("GIC: GICv3 system register interface disabled");
+    }
+    Ok(())
+}
+
+/// Bring up the distributor, this CPU's redistributor and CPU interface;
+/// returns the number of interrupt IDs.
+pub fn init(node: &DeviceNode) -> Result<usize, &'static str> {
+    enable_system_registers()?;
+    let (gicd_phys, _) = node.reg(0).ok_or("GIC: Missing distributor registers")?;
+    let (gicr_phys, gicr) = find_redistributor(node)?;
+    GICD_BASE.store(phys_to_virt(gicd_phys) as usize, Ordering::Relaxed);
+    GICR_SGI_BASE.store(gicr + GICR_SGI_FRAME, Ordering::Relaxed);
+    
+    // Distributor: off while SPIs are set up, then affinity routing on
+    write32(gicd(GICD_CTLR), 0);
+    wait_for("GIC: Distributor write timed out", || read32(gicd(GICD_CTLR)) & GICD_CTLR_RWP == 0)?;
+    let lines = ((read32(gicd(GICD_TYPER)) & 0x1F) as usize + 1) * 32;
+    let num_irqs = lines.min(super::MAX_IRQS);
+    
+    // SPIs: group 1, disabled, not pending, level triggered
+    for irq in (SPI_BASE as usize..num_irqs).step_by(32) {
+        write32(gicd(GICD_IGROUPR + irq / 8), !0);
+        write32(gicd(GICD_ICENABLER + irq / 8), !0);
+        write32(gicd(GICD_ICPENDR + irq / 8), !0);
+    }
+    for irq in (SPI_BASE as usize..num_irqs).step_by(16) {
+        write32(gicd(GICD_ICFGR + irq / 4), 0);
+    }
+    for irq in SPI_BASE as usize..num_irqs {
+        unsafe { write_volatile(gicd(GICD_IPRIORITYR + irq) as *mut u8, DEFAULT_PRIORITY) };
+    }
+    write32(gicd(GICD_CTLR), GICD_CTLR_ARE_NS | GICD_CTLR_ENABLE_G1A | GICD_CTLR_ENABLE_G1);
+    wait_for("GIC: Distributor write timed out", || read32(gicd(GICD_CTLR)) & GICD_CTLR_RWP == 0)?;
+    // Routed to this CPU; IROUTER only exists with affinity routing on
+    let this_cpu = this_cpu();
+    for irq in SPI_BASE as usize..num_irqs {
+        set_target(irq, this_cpu);
+    }
+    
+    // Redistributor: awake, SGIs and PPIs group 1, disabled, not pending
+    write32(gicr + GICR_WAKER, read32(gicr + GICR_WAKER) & !GICR_WAKER_PROCESSOR_SLEEP);
+    wait_for("GIC: Redistributor did not wake", || read32(gicr + GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP == 0)?;
+    write32(gicr_sgi(GICR_IGROUPR0), !0);
+    write32(gicr_sgi(GICR_ICENABLER0), !0);
+    write32(gicr_sgi(GICR_ICPENDR0), !0);
+    for irq in 0..SPI_BASE as usize {
+        unsafe { write_volatile(gicr_sgi(GICR_IPRIORITYR + irq) as *mut u8, DEFAULT_PRIORITY) };
+    }
+    wait_for("GIC: Redistributor write timed out", || read32(gicr + GICR_CTLR) & GICR_CTLR_RWP == 0)?;
+    
+    // CPU interface: every priority through, no preemption grouping,
+    // combined drop and deactivate, group 1 on
+    unsafe {
+        asm!("msr S3_0_C4_C6_0, {pmr}",      // ICC_PMR_EL1
+             "msr S3_0_C12_C12_3, xzr",      // ICC_BPR1_EL1
+             "msr S3_0_C12_C12_4, xzr",      // ICC_CTLR_EL1
+             "msr S3_0_C12_C12_7, {one}",    // ICC_IGRPEN1_EL1
+             "isb",
+             pmr = in(reg) PRIORITY_MASK_ALL as u64, one = in(reg) 1u64);
+    }
+    
+    crate::println!("GIC: GICv3 at 0x{:x}, redistributor 0x{:x}, {} interrupt lines, CPU mask 0x{:02x}",
+                   gicd_phys, gicr_phys, num_irqs, this_cpu);
+    Ok(num_irqs)
+}
+
+pub fn set_enabled(index: usize, enabled: bool) {
+    // SGIs and PPIs are the redistributor's with affinity routing
+    let (base, register) = match (index < SPI_BASE as usize, enabled) {
+        (true, true) => (gicr_sgi(0), GICR_ISENABLER0),
+        (true, false) => (gicr_sgi(0), GICR_ICENABLER0),
+        (false, true) => (gicd(0), GICD_ISENABLER + (index / 32) * 4),
+        (false, false) => (gicd(0), GICD_ICENABLER + (index / 32) * 4),
+    };
+    write32(base + register, 1 << (index % 32));
+}
+
+pub fn target(index: usize) -> u8 {
+    let route = read64(gicd(GICD_IROUTER + index * 8));
+    if route & GICD_IROUTER_ANY != 0 {
+        return super::online_cpus();
+    }
+    match route & 0xFF {
+        aff0 @ 0..8 if route & CLUSTER_MASK == mpidr() & CLUSTER_MASK => 1 << aff0,
+        _ => 0,
+    }
+}
+
+// A GICv3 SPI goes to one CPU or to any: every online CPU asks for the
+// latter, other sets for their lowest CPU
+pub fn set_target(index: usize, cpus: u8) {
+    let route = if cpus.count_ones() > 1 && cpus == super::online_cpus() {
+        GICD_IROUTER_ANY
+    } else {
+        (mpidr() & CLUSTER_MASK) | cpus.trailing_zeros() as u64
+    };
+    write64(gicd(GICD_IROUTER + index * 8), route);
+}
+
+pub fn send_sgi(sgi: u32, cpus: u8) {
+    // Target list of Aff0 values in the caller's Aff3.Aff2.Aff1
+    let mpidr = mpidr();
+    let cluster = (mpidr >> 32 & 0xFF) << 48 | (mpidr >> 16 & 0xFF) << 32 | (mpidr >> 8 & 0xFF) << 16;
+    let value = cluster | (sgi as u64) << ICC_SGI1R_INTID_SHIFT | cpus as u64;
+    unsafe {
+        asm!("msr S3_0_C12_C11_5, {}", "isb", in(reg) value);  // ICC_SGI1R_EL1
+    }
+}
+
+/// Take the highest priority pending group 1 interrupt.
+pub fn acknowledge() -> u32 {
+    let iar: u64;
+    unsafe {
+        asm!("mrs {}, S3_0_C12_C12_0", out(reg) iar);  // ICC_IAR1_EL1
+    }
+    iar as u32
+}
+
+pub fn end_of_interrupt(iar: u32) {
+    unsafe {
+        asm!("msr S3_0_C12_C12_1, {}", "isb", in(reg) iar as u64);  // ICC_EOIR1_EL1
+    }
+}
@@ -0,0 +1,216 @@
+// Initial RAM filesystem: the newc CPIO archive QEMU loads with -initrd
+//
+// The bootloader reports where it put the archive in /chosen
+// (linux,initrd-start / linux,initrd-end). Its frames are kept from the
+// allocator, and the files are served straight out of them, read-only;
+// this is where user programs are loaded from.
+
+use alloc::format;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::devicetree::{read_cell, DeviceNode, DeviceTree, MemoryRegion};
+use crate::ktest::kernel_test;
+use crate::memory::frame_allocator::{self, PAGE_SIZE};
+use crate::memory::paging::phys_to_virt;
+
+const CPIO_NEWC_MAGIC: &[u8] = b"070701";
+const CPIO_NEWC_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_LEN: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+// Header fields after the magic, 8 hex digits each
+const CPIO_FIELD_MODE: usize = 1;
+const CPIO_FIELD_FILESIZE: usize = 6;
+const CPIO_FIELD_NAMESIZE: usize = 11;
+
+// st_mode file type
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+
+/// One archive member.
+#[derive(Clone, Copy, Debug)]
+pub struct InitramfsFile {
+    /// Path without a leading "/" or "./"
+    pub name: &'static str,
+    pub mode: u32,
+    pub data: &'static [u8],
+}
+
+impl InitramfsFile {
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+    
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+// Physical base and size, set aside before any frame is allocated
+static REGION: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+static FILES: Mutex<Vec<InitramfsFile>> = Mutex::new(Vec::new());
+
+fn hex_field(header: &[u8], index: usize) -> Result<u32, &'static str> {
+    let start = CPIO_NEWC_MAGIC.len() + index * 8;
+    let digits = core::str::from_utf8(&header[start..start + 8]).map_err(|_| "CPIO: Bad header field")?;
+    u32::from_str_radix(digits, 16).map_err(|_| "CPIO: Bad header field")
+}
+
+/// Parse a newc archive into its members, in archive order.
+pub fn parse(archive: &'static [u8]) -> Result<Vec<InitramfsFile>, &'static str> {
+    let mut files = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = archive.get(offset..offset + CPIO_HEADER_LEN).ok_or("CPIO: Truncated header")?;
+        let magic = &header[..CPIO_NEWC_MAGIC.len()];
+        if magic != CPIO_NEWC_MAGIC && magic != CPIO_NEWC_CRC_MAGIC {
+            return Err("CPIO: Not a newc archive");
+        }
+        let mode = hex_field(header, CPIO_FIELD_MODE)?;
+        let file_size = hex_field(header, CPIO_FIELD_FILESIZE)? as usize;
+        let name_size = hex_field(header, CPIO_FIELD_NAMESIZE)? as usize;
+        
+        // The name includes its NUL; name and data are each padded to 4
+        let name_start = offset + CPIO_HEADER_LEN;
+        let name = archive
+            .get(name_start..name_start + name_size.saturating_sub(1))
+            .ok_or("CPIO: Truncated name")?;
+        let name = core::str::from_utf8(name).map_err(|_| "CPIO: Name not UTF-8")?;
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = archive.get(data_start..data_start + file_size).ok_or("CPIO: Truncated data")?;
+        offset = (data_start + file_size).next_multiple_of(4);
+        
+        if name == CPIO_TRAILER {
+            return Ok(files);
+        }
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        if !name.is_empty() && name != "." {
+            files.push(InitramfsFile { name, mode, data });
+        }
+    }
+}
+
+// linux,initrd-start/end are one or two cells
+fn chosen_address(chosen: &DeviceNode, name: &str) -> Option<u64> {
+    let value = chosen.property(name)?;
+    match value.len() {
+        4 => read_cell(value, 0).map(u64::from),
+        8 => Some((read_cell(value, 0)? as u64) << 32 | read_cell(value, 1)? as u64),
+        _ => None,
+    }
+}
+
+/// Find the initrd and keep the frame allocator off it. Must run before
+/// anything (including memtest) writes to free memory.
+pub fn reserve(dt: Option<&DeviceTree>, ram: &[MemoryRegion]) {
+    let Some(chosen) = dt.and_then(|dt| dt.find_by_name("chosen")) else { return };
+    let (Some(start), Some(end)) = (chosen_address(&chosen, "linux,initrd-start"),
+                                    chosen_address(&chosen, "linux,initrd-end")) else { return };
+    
+    // The files are read through the linear map
+    let in_ram = ram.iter().any(|r| start >= r.start && end <= r.start + r.size);
+    if !in_ram || end <= start {
+        crate::println!("Initramfs: Ignoring unusable initrd 0x{:x}..0x{:x}", start, end);
+        return;
+    }
+    
+    let page = PAGE_SIZE as u64;
+    let mut addr = start & !(page - 1);
+    while addr < end {
+        if let Some(frame) = core::ptr::NonNull::new(phys_to_virt(addr) as *mut u8) {
+            frame_allocator::claim_frame(frame);
+        }
+        addr += page;
+    }
+    *REGION.lock() = Some((start, end - start));
+}
+
+// /proc/initramfs: one "mode size name" line per member
+fn proc_initramfs(out: &mut Vec<u8>) {
+    for file in FILES.lock().iter() {
+        out.extend_from_slice(format!("{:06o} {:>8} {}\n", file.mode, file.data.len(), file.name).as_bytes());
+    }
+}
+
+/// Parse the reserved archive. Runs once the heap is up.
+pub fn init() {
+    let Some((base, size)) = *REGION.lock() else {
+        crate::println!("Initramfs: No initrd");
+        return;
+    };
+    
+    let archive = unsafe {
+        core::slice::from_raw_parts(phys_to_virt(base) as *const u8, size as usize)
+    };
+    match parse(archive) {
+        Ok(files) => {
+            let bytes: usize = files.iter().map(|file| file.data.len()).sum();
+            crate::println!("Initramfs: {} entries, {} bytes at 0x{:x}", files.len(), bytes, base);
+            *FILES.lock() = files;
+            let _ = crate::procfs::register("initramfs", proc_initramfs);
+        }
+        Err(e) => crate::println!("Initramfs: Failed to parse initrd: {}", e),
+    }
+}
+
+/// Contents of a regular file, e.g. "bin/init".
+pub fn lookup(path: &str) -> Option<&'static [u8]> {
+    let path = path.trim_start_matches('/');
+    FILES
+        .lock()
+        .iter()
+        .find(|file| file.name == path && file.is_file())
+        .map(|file| file.data)
+}
+
+/// Every member of the archive.
+pub fn files() -> Vec<InitramfsFile> {
+    FILES.lock().clone()
+}
+
+#[kernel_test]
+fn test_initramfs() {
+    use alloc::boxed::Box;
+    use alloc::format;
+    use alloc::vec::Vec;
+    
+    crate::println!("Initramfs Test: Testing initramfs parsing...");
+    
+    // Two-member newc archive: a directory and a file in it
+    let mut archive = Vec::new();
+    let mut append = |name: &str, mode: u32, data: &[u8]| {
+        let header = format!("070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+                             0, mode, 0, 0, 1, 0, data.len(), 0, 0, 0, 0, name.len() + 1, 0);
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    };
+    append("bin", 0o040755, b"");
+    append("bin/init", 0o100755, b"\x7fELF-test");
+    append("TRAILER!!!", 0, b"");
+    let archive: &'static [u8] = Box::leak(archive.into_boxed_slice());
+    
+    match parse(archive) {
+        Ok(files) if files.len() == 2
+            && files[0].is_dir()
+            && files[1].is_file()
+            && files[1].name == "bin/init"
+            && files[1].data == b"\x7fELF-test" => {
+            crate::println!("Initramfs Test: ✓ CPIO archive parsed into {} entries", files.len());
+        }
+        Ok(files) => crate::println!("Initramfs Test: ✗ CPIO parse gave {} wrong entries", files.len()),
+        Err(e) => crate::println!("Initramfs Test: ✗ CPIO parse failed: {}", e),
+    }
+    
+    if parse(&archive[..archive.len() - 8]).is_err() {
+        crate::println!("Initramfs Test: ✓ Truncated archive rejected");
+    } else {
+        crate::println!("Initramfs Test: ✗ Truncated archive accepted");
+    }
+    
+    crate::println!("Initramfs Test: Initramfs test completed");
+}
@@ -1,25 +1,9 @@
 // Interrupt handling testing utilities
 
-use crate::interrupts::{get_interrupt_stats, test_system_call, disable_interrupts, enable_interrupts};
-
-pub fn test_interrupt_system() {
-    crate::println!("Interrupt Test: Starting interrupt system tests...");
-    
-    // Test interrupt enable/disable
-    test_interrupt_control();
-    
-    // Test system call handling
-    test_syscall_handling();
-    
-    // Test timer interrupts
-    test_timer_functionality();
-    
-    // Display interrupt statistics
-    display_interrupt_stats();
-    
-    crate::println!("Interrupt Test: All interrupt tests completed");
-}
+use crate::interrupts::{get_interrupt_stats, ExceptionContext, test_system_call, disable_interrupts, enable_interrupts};
+use crate::ktest::kernel_test;
 
+#[kernel_test]
 fn test_interrupt_control() {
     crate::println!("Interrupt Test: Testing interrupt control...");
     
@@ -33,15 +17,23 @@ fn test_interrupt_control() {
     crate::println!("Interrupt Test: Interrupt control test completed");
 }
 
+// Passes by panicking: x31 is the stack pointer, which the frame keeps elsewhere
+#[kernel_test(should_panic)]
+fn test_register_index_checked() {
+    let ctx = ExceptionContext::default();
+    crate::println!("Interrupt Test: x31 read as 0x{:x}", ctx.x(31));
+}
+
+#[kernel_test]
 fn test_syscall_handling() {
     crate::println!("Interrupt Test: Testing system call handling...");
     
-    let (_, sync_before, _, _, _) = get_interrupt_stats();
+    let sync_before = get_interrupt_stats().sync_exceptions;
     
     // Trigger a test system call
     test_system_call();
     
-    let (_, sync_after, _, _, _) = get_interrupt_stats();
+    let sync_after = get_interrupt_stats().sync_exceptions;
     
     if sync_after > sync_before {
         crate::println!("Interrupt Test: ✓ System call handling working");
@@ -52,17 +44,20 @@ fn test_syscall_handling() {
     crate::println!("Interrupt Test: System call test completed");
 }
 
+#[kernel_test]
 fn test_timer_functionality() {
     crate::println!("Interrupt Test: Testing timer functionality...");
     
-    let (_, _, _, _, timer_before) = get_interrupt_stats();
+    let before = get_interrupt_stats();
+    let timer_before = before.timer_ticks;
     
     // Wait for timer interrupts (simple delay)
     for _ in 0..1000000 {
         core::hint::spin_loop();
     }
     
-    let (_, _, _, _, timer_after) = get_interrupt_stats();
+    let after = get_interrupt_stats();
+    let timer_after = after.timer_ticks;
     
     if timer_after > timer_before {
         crate::println!("Interrupt Test: ✓ Timer interrupts working ({}→{})", 
@@ -71,17 +66,94 @@ fn test_timer_functionality() {
         crate::println!("Interrupt Test: ✗ No timer interrupts detected");
     }
     
+    // Every tick is timed, and no handler runs before its deadline
+    let latency = after.timer_latency;
+    let irq_time = after.irq_time;
+    if latency.count > before.timer_latency.count && latency.min <= latency.average()
+        && latency.average() <= latency.max && irq_time.count > 0 && irq_time.max > 0
+    {
+        crate::println!("Interrupt Test: ✓ Timer latency min {} avg {} max {} ticks",
+                       latency.min, latency.average(), latency.max);
+    } else {
+        crate::println!("Interrupt Test: ✗ Timer latency not recorded ({:?})", latency);
+    }
+    
     crate::println!("Interrupt Test: Timer test completed");
 }
 
-fn display_interrupt_stats() {
-    let (irq, sync, fiq, serror, timer) = get_interrupt_stats();
+#[kernel_test]
+fn test_irq_affinity() {
+    use crate::gic;
+    
+    crate::println!("Interrupt Test: Testing IRQ affinity...");
+    
+    if !gic::is_present() {
+        crate::println!("Interrupt Test: ✗ No interrupt controller");
+        return;
+    }
+    
+    // The timer is a PPI and must have been delivered through the GIC
+    let delivered = (0..gic::SPI_BASE).map(gic::irq_count).sum::<u64>();
+    if delivered > 0 {
+        crate::println!("Interrupt Test: ✓ {} private interrupts delivered through the GIC", delivered);
+    } else {
+        crate::println!("Interrupt Test: ✗ No interrupts counted by the GIC");
+    }
+    
+    // Any SPI will do for routing; nothing is bound to the last one
+    let irq = gic::num_irqs() - 1;
+    let this_cpu = gic::online_cpus();
+    let offline = !this_cpu;
+    let pinned = gic::set_affinity(irq, this_cpu).is_ok()
+        && gic::affinity(irq) == Some(this_cpu)
+        && gic::is_pinned(irq);
+    let refused = gic::set_affinity(irq, offline).is_err() && gic::set_affinity(5, this_cpu).is_err();
+    if pinned && refused {
+        crate::println!("Interrupt Test: ✓ SPI {} pinned, offline CPUs and PPIs refused", irq);
+    } else {
+        crate::println!("Interrupt Test: ✗ Affinity not applied or checks missing");
+    }
+    let _ = gic::unpin(irq);
+    
+    // An SGI to this CPU is held while disabled and taken once enabled,
+    // through whichever interface this GIC version has
+    const TEST_SGI: u32 = 14;
+    fn ignore_sgi(_irq: u32) {}
+    if gic::register_handler(TEST_SGI, ignore_sgi).is_ok() {
+        let before = gic::irq_count(TEST_SGI);
+        let _ = gic::disable_irq(TEST_SGI);
+        let _ = gic::send_sgi(TEST_SGI, gic::this_cpu());
+        crate::interrupts::delay_us(1000);
+        let held = gic::irq_count(TEST_SGI) == before;
+        let _ = gic::enable_irq(TEST_SGI);
+        crate::interrupts::delay_us(1000);
+        let taken = gic::irq_count(TEST_SGI) == before + 1;
+        if held && taken {
+            crate::println!("Interrupt Test: ✓ GICv{} SGI held while disabled, delivered once enabled", gic::version());
+        } else {
+            crate::println!("Interrupt Test: ✗ GICv{} SGI held {} delivered {}", gic::version(), held, taken);
+        }
+    } else {
+        crate::println!("Interrupt Test: ✗ SGI {} already in use", TEST_SGI);
+    }
+    
+    // Single CPU: everything already lives on it
+    let moved = crate::irqbalance::balance();
+    crate::println!("Interrupt Test: irqbalance pass moved {} interrupts", moved);
+    
+    crate::println!("Interrupt Test: IRQ affinity test completed");
+}
+
+// No checks: the counters as the tests before it left them
+#[kernel_test]
+fn test_interrupt_stats() {
+    let stats = get_interrupt_stats();
     
     crate::println!("Interrupt Test: === Interrupt Statistics ===");
-    crate::println!("Interrupt Test: IRQ interrupts: {}", irq);
-    crate::println!("Interrupt Test: Sync exceptions: {}", sync);
-    crate::println!("Interrupt Test: FIQ interrupts: {}", fiq);
-    crate::println!("Interrupt Test: System errors: {}", serror);
-    crate::println!("Interrupt Test: Timer ticks: {}", timer);
+    crate::println!("Interrupt Test: IRQ interrupts: {}", stats.irq_count);
+    crate::println!("Interrupt Test: Sync exceptions: {}", stats.sync_exceptions);
+    crate::println!("Interrupt Test: FIQ interrupts: {}", stats.fiq_count);
+    crate::println!("Interrupt Test: System errors: {}", stats.serror_count);
+    crate::println!("Interrupt Test: Timer ticks: {}", stats.timer_ticks);
     crate::println!("Interrupt Test: ==============================");
 }
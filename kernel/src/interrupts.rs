@@ -1,12 +1,16 @@
 // ARM64 interrupt handling and exception management
 
 use core::arch::asm;
-use spin::Mutex;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use crate::process::fault::FaultKind;
+use crate::sync::IrqSafeMutex;
 
 // Exception context saved by assembly handler
 #[repr(C)]
+#[derive(Clone, Copy, Default)]
 pub struct ExceptionContext {
-    // Saved by exception_entry macro
+    // Saved by exception_entry macro (layout documented in exceptions.s)
     pub spsr_el1: u64,
     pub elr_el1: u64,
     pub x30: u64,   // Link register
@@ -40,8 +44,41 @@ pub struct ExceptionContext {
     pub x2: u64,
     pub x1: u64,
     pub x0: u64,
+    pub sp_el0: u64, // User stack pointer
 }
 
+// Must match EXCEPTION_FRAME_SIZE in exceptions.s
+const _: () = assert!(core::mem::size_of::<ExceptionContext>() == 272);
+
+// x0 sits last among the general registers, x30 first
+const _: () = assert!(core::mem::offset_of!(ExceptionContext, x0) == 32 * 8);
+const _: () = assert!(core::mem::offset_of!(ExceptionContext, x30) == 2 * 8);
+
+impl ExceptionContext {
+    /// General register x`n`, for n up to 30.
+    pub fn x(&self, n: usize) -> u64 {
+        assert!(n <= 30);
+        // Checked above: x(n) is the (32 - n)th word of the frame
+        unsafe { *(self as *const ExceptionContext as *const u64).add(32 - n) }
+    }
+    
+    pub fn x_mut(&mut self, n: usize) -> &mut u64 {
+        assert!(n <= 30);
+        unsafe { &mut *(self as *mut ExceptionContext as *mut u64).add(32 - n) }
+    }
+}
+
+// SPSR_EL1.M[3:0] of an exception taken from EL0
+const SPSR_MODE_MASK: u64 = 0xF;
+const SPSR_MODE_EL0T: u64 = 0;
+
+// Data abort ISS: write-not-read and the fault status code, whose low two
+// bits give the translation level
+const ESR_DABT_WNR: u64 = 1 << 6;
+const ESR_DABT_DFSC_MASK: u64 = 0x3F;
+const ESR_DFSC_LEVEL_MASK: u64 = 0x3;
+const ESR_DFSC_PERMISSION: u64 = 0b00_1100;
+
 // Exception syndrome register (ESR_EL1) decoding
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -54,7 +91,14 @@ pub enum ExceptionClass {
     InstructionAbortCurrentEl = 0b100001,
     SvcAarch64 = 0b010101,
     SvcAarch32 = 0b010001,
-    Other(u8),
+    SoftwareStepLowerEl = 0b110010,
+    SoftwareStepCurrentEl = 0b110011,
+    Breakpoint = 0b111100,
+    HardwareBreakpointLowerEl = 0b110000,
+    HardwareBreakpointCurrentEl = 0b110001,
+    WatchpointLowerEl = 0b110100,
+    WatchpointCurrentEl = 0b110101,
+    Other,
 }
 
 impl From<u8> for ExceptionClass {
@@ -68,20 +112,75 @@ impl From<u8> for ExceptionClass {
             0b100001 => ExceptionClass::InstructionAbortCurrentEl,
             0b010101 => ExceptionClass::SvcAarch64,
             0b010001 => ExceptionClass::SvcAarch32,
-            other => ExceptionClass::Other(other),
+            0b110010 => ExceptionClass::SoftwareStepLowerEl,
+            0b110011 => ExceptionClass::SoftwareStepCurrentEl,
+            0b111100 => ExceptionClass::Breakpoint,
+            0b110000 => ExceptionClass::HardwareBreakpointLowerEl,
+            0b110001 => ExceptionClass::HardwareBreakpointCurrentEl,
+            0b110100 => ExceptionClass::WatchpointLowerEl,
+            0b110101 => ExceptionClass::WatchpointCurrentEl,
+            _ => ExceptionClass::Other,
         }
     }
 }
 
 // Interrupt statistics
-static INTERRUPT_STATS: Mutex<InterruptStats> = Mutex::new(InterruptStats::new());
+// Updated from every exception path, read from thread context
+static INTERRUPT_STATS: IrqSafeMutex<InterruptStats> = IrqSafeMutex::new(InterruptStats::new());
+
+// IRQ handlers currently running; only the boot CPU takes interrupts
+static IRQ_DEPTH: AtomicU32 = AtomicU32::new(0);
+
+/// How many IRQ handlers the running code is nested inside.
+pub fn irq_depth() -> u32 {
+    IRQ_DEPTH.load(Ordering::Relaxed)
+}
 
-struct InterruptStats {
-    irq_count: u64,
-    sync_exceptions: u64,
-    fiq_count: u64,
-    serror_count: u64,
-    timer_ticks: u64,
+/// Counter ticks spent in a handler, or waited before it ran.
+#[derive(Copy, Clone, Debug)]
+pub struct HandlerTiming {
+    pub count: u64,
+    pub total: u64,
+    pub min: u64,   // u64::MAX until the first sample
+    pub max: u64,
+}
+
+impl HandlerTiming {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            total: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+    
+    pub fn record(&mut self, ticks: u64) {
+        self.count += 1;
+        self.total += ticks;
+        self.min = self.min.min(ticks);
+        self.max = self.max.max(ticks);
+    }
+    
+    /// Mean ticks per sample; 0 before the first.
+    pub fn average(&self) -> u64 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct InterruptStats {
+    pub irq_count: u64,
+    pub sync_exceptions: u64,
+    pub fiq_count: u64,
+    pub serror_count: u64,
+    pub timer_ticks: u64,
+    // Time in the IRQ and synchronous exception handlers, rescheduling
+    // on the way out excluded
+    pub irq_time: HandlerTiming,
+    pub sync_time: HandlerTiming,
+    // From the programmed timer deadline to the timer handler running
+    pub timer_latency: HandlerTiming,
 }
 
 impl InterruptStats {
@@ -92,6 +191,9 @@ impl InterruptStats {
             fiq_count: 0,
             serror_count: 0,
             timer_ticks: 0,
+            irq_time: HandlerTiming::new(),
+            sync_time: HandlerTiming::new(),
+            timer_latency: HandlerTiming::new(),
         }
     }
 }
@@ -103,11 +205,32 @@ extern "C" {
 
 // Assembly calls these Rust functions
 #[no_mangle]
-extern "C" fn handle_sync_exception(ctx: *const ExceptionContext) {
-    let ctx = unsafe { &*ctx };
+extern "C" fn handle_sync_exception(ctx: *mut ExceptionContext) -> *mut ExceptionContext {
+    let start = counter_ticks();
+    let exit = sync_exception(unsafe { &mut *ctx });
     
-    INTERRUPT_STATS.lock().sync_exceptions += 1;
+    let mut stats = INTERRUPT_STATS.lock();
+    stats.sync_exceptions += 1;
+    stats.sync_time.record(counter_ticks() - start);
+    drop(stats);
     
+    match exit {
+        SyncExit::Resume => ctx,
+        SyncExit::Preempt => crate::process::scheduler::preempt(ctx),
+        SyncExit::Schedule => crate::process::scheduler::schedule(ctx),
+    }
+}
+
+// How to leave a synchronous exception once its handler is done
+enum SyncExit {
+    Resume,
+    // Switch threads if the handler asked for it
+    Preempt,
+    // Always switch threads
+    Schedule,
+}
+
+fn sync_exception(ctx: &mut ExceptionContext) -> SyncExit {
     // Read exception syndrome register
     let esr: u64;
     unsafe {
@@ -118,38 +241,92 @@ extern "C" fn handle_sync_exception(ctx: *const ExceptionContext) {
     let iss = esr & 0x1FFFFFF;  // Instruction Specific Syndrome
     
     match exception_class {
+        ExceptionClass::SvcAarch64 if iss == crate::process::SVC_YIELD => {
+            // Voluntary reschedule from kernel thread context
+            return SyncExit::Schedule;
+        }
         ExceptionClass::SvcAarch64 => {
-            handle_system_call(ctx, iss);
+            crate::syscall::dispatch(ctx, iss);
+            // exit() and blocking calls leave the thread unable to continue
+            return SyncExit::Preempt;
         }
         ExceptionClass::DataAbortCurrentEl | ExceptionClass::DataAbortLowerEl => {
-            handle_data_abort(ctx, esr);
+            if !handle_data_abort(ctx, esr) {
+                return SyncExit::Preempt;
+            }
         }
         ExceptionClass::InstructionAbortCurrentEl | ExceptionClass::InstructionAbortLowerEl => {
             handle_instruction_abort(ctx, esr);
+            return SyncExit::Preempt;
+        }
+        // __stack_chk_fail: a protected function found its canary overwritten
+        ExceptionClass::Breakpoint
+            if iss & 0xFFFF == crate::ssp::STACK_CHK_BRK_IMM as u64 && ctx.spsr_el1 & SPSR_MODE_MASK != SPSR_MODE_EL0T =>
+        {
+            let caller = ctx.x30.wrapping_sub(4);
+            match crate::symbols::lookup(caller) {
+                Some(symbol) => kernel_fault(ctx, format_args!("Stack smashing detected in {}", symbol)),
+                None => kernel_fault(ctx, format_args!("Stack smashing detected at 0x{:016x}", caller)),
+            }
         }
+        // Breakpoints and single steps belonging to the GDB stub
+        ExceptionClass::Breakpoint | ExceptionClass::SoftwareStepCurrentEl | ExceptionClass::SoftwareStepLowerEl
+            if crate::gdbstub::handle_debug_exception(ctx, exception_class, iss) => {}
+        // Hardware breakpoints and watchpoints set from the shell
+        ExceptionClass::HardwareBreakpointCurrentEl | ExceptionClass::WatchpointCurrentEl
+        | ExceptionClass::SoftwareStepCurrentEl
+            if crate::kdebug::handle_debug_exception(ctx, exception_class, iss) => {}
         ExceptionClass::WfiWfe => {
             // WFI/WFE instructions - just continue
             crate::println!("Interrupts: WFI/WFE instruction handled");
         }
+        // Anything else from the kernel is a bug in it
+        _ if ctx.spsr_el1 & SPSR_MODE_MASK != SPSR_MODE_EL0T => {
+            kernel_fault(ctx, format_args!(
+                "Unhandled sync exception in kernel: {:?} (EC 0x{:x}), ISS: 0x{:x}", exception_class, (esr >> 26) & 0x3F, iss));
+        }
         _ => {
-            crate::println!("Interrupts: Unhandled sync exception: {:?}, ISS: 0x{:x}", 
-                           exception_class, iss);
-            crate::println!("Interrupts: PC: 0x{:016x}, SP: 0x{:016x}", ctx.elr_el1, ctx as *const _ as u64);
+            crate::println!("Interrupts: Unhandled sync exception: {:?} (EC 0x{:x}), ISS: 0x{:x}", 
+                           exception_class, (esr >> 26) & 0x3F, iss);
+            crate::process::fault::kill_current(ctx, FaultKind::Unhandled, 0, esr);
+            return SyncExit::Preempt;
         }
     }
+    
+    SyncExit::Resume
 }
 
 #[no_mangle]
-extern "C" fn handle_irq_exception(_ctx: *const ExceptionContext) {
-    INTERRUPT_STATS.lock().irq_count += 1;
+extern "C" fn handle_irq_exception(ctx: *mut ExceptionContext) -> *mut ExceptionContext {
+    let start = counter_ticks();
     
-    // Handle timer interrupt if enabled
-    if is_timer_pending() {
+    // The profiler samples the code this tick interrupted
+    if crate::profile::is_running() && is_timer_pending() {
+        crate::profile::sample(unsafe { &*ctx });
+    }
+    
+    // Dispatch through the GIC; without one only the timer can be polled
+    IRQ_DEPTH.fetch_add(1, Ordering::Relaxed);
+    if crate::gic::is_present() {
+        crate::gic::handle_irq();
+    } else if is_timer_pending() {
         handle_timer_interrupt();
     }
+    IRQ_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    
+    // Panics here if this CPU or a watched task has stopped making progress
+    crate::watchdog::check(unsafe { &*ctx });
+    
+    let mut stats = INTERRUPT_STATS.lock();
+    stats.irq_count += 1;
+    stats.irq_time.record(counter_ticks() - start);
+    drop(stats);
+    
+    // Deferred work the handlers left, with IRQs unmasked
+    crate::softirq::irq_exit();
     
-    // Handle other IRQ sources
-    // TODO: Add GIC interrupt handling
+    // Switch threads on the way out if the time slice expired
+    crate::process::scheduler::preempt(ctx)
 }
 
 #[no_mangle]
@@ -177,30 +354,105 @@ extern "C" fn rust_handle_invalid_exception(exception_type: u64) -> ! {
     }
 }
 
-fn handle_system_call(ctx: &ExceptionContext, syscall_num: u64) {
-    crate::println!("Interrupts: System call {} from PC: 0x{:016x}", 
-                   syscall_num, ctx.elr_el1);
-    // TODO: Implement system call dispatching
+/// Entered from exceptions.s, on the overflow stack, when a kernel stack
+/// has run into its guard; `sp` is the stack pointer the exception found.
+#[no_mangle]
+extern "C" fn handle_stack_overflow(ctx: &mut ExceptionContext, sp: u64) -> ! {
+    let thread = crate::process::scheduler::current_thread_id();
+    crate::panic::stack_overflow_panic(ctx, sp, format_args!(
+        "Stack overflow in thread {}: sp 0x{:016x}, PC: 0x{:016x}",
+        crate::process::scheduler::thread_ref(thread), sp, ctx.elr_el1))
 }
 
-fn handle_data_abort(ctx: &ExceptionContext, esr: u64) {
+// False if the faulting user thread was killed
+fn handle_data_abort(ctx: &mut ExceptionContext, esr: u64) -> bool {
     let far: u64;
     unsafe {
         asm!("mrs {}, far_el1", out(reg) far);
     }
     
-    crate::println!("Interrupts: Data abort at address 0x{:016x}, PC: 0x{:016x}", 
-                   far, ctx.elr_el1);
-    crate::println!("Interrupts: ESR: 0x{:016x}", esr);
+    // Writes to a copy-on-write user page, from user code or the kernel
+    // copying out, are resolved and the access retried
+    let write_permission_fault = esr & ESR_DABT_WNR != 0
+        && (esr & ESR_DABT_DFSC_MASK) & !ESR_DFSC_LEVEL_MASK == ESR_DFSC_PERMISSION;
+    if write_permission_fault && far < crate::process::thread::USER_MMAP_END && resolve_cow_fault(far) {
+        return true;
+    }
+    
+    if ctx.spsr_el1 & SPSR_MODE_MASK != SPSR_MODE_EL0T {
+        // A syscall copying through a bad user pointer fails with EFAULT
+        if crate::uaccess::fixup(ctx) {
+            return true;
+        }
+        let pc = ctx.elr_el1;
+        // Reaching below a kernel stack other than through sp (exceptions.s
+        // catches those)
+        if crate::process::thread::is_stack_guard(far) {
+            kernel_fault(ctx, format_args!(
+                "Stack overflow in thread {}: guard page hit at 0x{:016x}, PC: 0x{:016x}",
+                crate::process::scheduler::thread_ref(crate::process::scheduler::current_thread_id()), far, pc));
+            return true;
+        }
+        let permission = (esr & ESR_DABT_DFSC_MASK) & !ESR_DFSC_LEVEL_MASK == ESR_DFSC_PERMISSION;
+        if permission && crate::uaccess::is_pan_fault(ctx, far) {
+            kernel_fault(ctx, format_args!(
+                "Kernel access to user memory at 0x{:016x} blocked by PAN, PC: 0x{:016x}", far, pc));
+            return true;
+        }
+        kernel_fault(ctx, format_args!(
+            "Kernel data abort at address 0x{:016x}, PC: 0x{:016x}, ESR: 0x{:x}", far, pc, esr));
+        return true;
+    }
+    crate::process::fault::kill_current(ctx, FaultKind::DataAbort, far, esr);
+    false
 }
 
-fn handle_instruction_abort(ctx: &ExceptionContext, esr: u64) {
-    crate::println!("Interrupts: Instruction abort at PC: 0x{:016x}", ctx.elr_el1);
-    crate::println!("Interrupts: ESR: 0x{:016x}", esr);
+// An oops if the faulting code is guarded (see oops.rs), otherwise a panic.
+// Returning would re-run the faulting kernel instruction forever.
+fn kernel_fault(ctx: &mut ExceptionContext, args: fmt::Arguments) {
+    if !crate::oops::recover(ctx, args) {
+        crate::panic::exception_panic(ctx, args);
+    }
+}
+
+fn resolve_cow_fault(far: u64) -> bool {
+    let current = crate::process::scheduler::current_thread_id();
+    let resolved = crate::process::scheduler::with_thread(current, |thread| {
+        thread.address_space().map(|space| space.resolve_cow_fault(far))
+    });
+    match resolved {
+        Some(Some(Ok(resolved))) => resolved,
+        Some(Some(Err(e))) => {
+            crate::println!("Interrupts: Copy-on-write fault at 0x{:016x} failed: {}", far, e);
+            false
+        }
+        _ => false,
+    }
+}
+
+fn handle_instruction_abort(ctx: &mut ExceptionContext, esr: u64) {
+    if ctx.spsr_el1 & SPSR_MODE_MASK != SPSR_MODE_EL0T {
+        let pc = ctx.elr_el1;
+        kernel_fault(ctx, format_args!(
+            "Kernel instruction abort at PC: 0x{:016x}, ESR: 0x{:x}", pc, esr));
+        return;
+    }
+    let far: u64;
+    unsafe {
+        asm!("mrs {}, far_el1", out(reg) far);
+    }
+    crate::process::fault::kill_current(ctx, FaultKind::InstructionAbort, far, esr);
 }
 
 // ARM Generic Timer support
-const TIMER_FREQ_HZ: u64 = 100;  // 100 Hz timer (10ms interval)
+pub const TIMER_FREQ_HZ: u64 = 100;  // 100 Hz timer (10ms interval)
+
+// Earliest pending timer event as an absolute counter value (MAX = none)
+static EVENT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+// Periodic tick stopped while the CPU idles
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+static TICKLESS_SINCE: AtomicU64 = AtomicU64::new(0);
 
 fn is_timer_pending() -> bool {
     let cntp_ctl: u64;
@@ -210,24 +462,50 @@ fn is_timer_pending() -> bool {
     (cntp_ctl & 0x4) != 0  // ISTATUS bit
 }
 
+// Non-secure EL1 physical timer PPI, when the device tree does not say
+const TIMER_IRQ_DEFAULT: u32 = 30;
+
+fn timer_irq_handler(_irq: u32) {
+    if is_timer_pending() {
+        handle_timer_interrupt();
+    }
+}
+
 fn handle_timer_interrupt() {
-    INTERRUPT_STATS.lock().timer_ticks += 1;
+    // The compare value is still the deadline that fired
+    let deadline: u64;
+    unsafe {
+        asm!("mrs {}, cntp_cval_el0", out(reg) deadline);
+    }
+    let latency = counter_ticks().saturating_sub(deadline);
+    
+    let mut stats = INTERRUPT_STATS.lock();
+    stats.timer_ticks += 1;
+    stats.timer_latency.record(latency);
+    drop(stats);
     
     // Clear timer interrupt by setting IMASK
     unsafe {
-        asm!("mrs x0, cntp_ctl_el0");
-        asm!("orr x0, x0, #2");        // Set IMASK bit
-        asm!("msr cntp_ctl_el0, x0");
-        asm!("mrs x0, cntp_ctl_el0");
-        asm!("bic x0, x0, #2");        // Clear IMASK bit (bit clear)
-        asm!("msr cntp_ctl_el0, x0");
+        let mut ctl: u64;
+        asm!("mrs {}, cntp_ctl_el0", out(reg) ctl);
+        ctl |= 2;                      // Set IMASK bit
+        asm!("msr cntp_ctl_el0, {}", in(reg) ctl);
+        ctl &= !2;                     // Clear IMASK bit
+        asm!("msr cntp_ctl_el0, {}", in(reg) ctl);
     }
     
     // Set next timer interrupt
     setup_timer_interrupt();
+    expire_timer_event(counter_ticks());
+    let now = crate::time::now_ticks();
+    crate::timer::tick(now);
+    crate::executor::timer_tick(now);
+    
+    // Account the tick against the running thread
+    crate::process::scheduler::tick();
     
     let stats = INTERRUPT_STATS.lock();
-    if stats.timer_ticks % 100 == 0 {  // Every second
+    if stats.timer_ticks.is_multiple_of(100) && crate::klog::enabled(crate::klog::LOG_DEBUG) {  // Every second
         crate::println!("Interrupts: Timer tick #{} ({}s uptime)", 
                        stats.timer_ticks, stats.timer_ticks / TIMER_FREQ_HZ);
     }
@@ -248,8 +526,92 @@ fn setup_timer_interrupt() {
         asm!("msr cntp_cval_el0, {}", in(reg) next_interrupt);
         
         // Enable timer
-        asm!("mov x0, #1");           // Enable bit
-        asm!("msr cntp_ctl_el0, x0");
+        asm!("msr cntp_ctl_el0, {}", in(reg) 1u64);  // Enable bit
+    }
+}
+
+/// Ask for a timer interrupt no later than `deadline` (counter ticks).
+///
+/// With the periodic tick running the event is noticed on the next tick;
+/// while idle it becomes the only programmed wakeup.
+pub fn request_timer_event(deadline: u64) {
+    EVENT_DEADLINE.fetch_min(deadline, Ordering::SeqCst);
+}
+
+// Clear the pending event once its deadline has passed
+fn expire_timer_event(now: u64) -> bool {
+    let deadline = EVENT_DEADLINE.load(Ordering::SeqCst);
+    deadline <= now
+        && EVENT_DEADLINE
+            .compare_exchange(deadline, u64::MAX, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+}
+
+/// Stop the periodic tick before idling.
+///
+/// Programs CNTP_CVAL for the next requested event, or switches the
+/// timer off entirely when nothing is pending. Call with IRQs masked.
+pub fn enter_tickless_idle() {
+    TICKLESS_SINCE.store(counter_ticks(), Ordering::SeqCst);
+    TICKLESS.store(true, Ordering::SeqCst);
+    
+    let deadline = EVENT_DEADLINE.load(Ordering::SeqCst);
+    unsafe {
+        if deadline == u64::MAX {
+            asm!("msr cntp_ctl_el0, {}", in(reg) 0u64);  // Timer off
+        } else {
+            asm!("msr cntp_cval_el0, {}", in(reg) deadline);
+            asm!("msr cntp_ctl_el0, {}", in(reg) 1u64);
+        }
+    }
+}
+
+/// Restart the periodic tick after idling, crediting the ticks skipped.
+pub fn exit_tickless_idle() {
+    if !TICKLESS.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    
+    let now = counter_ticks();
+    let period = counter_frequency() / TIMER_FREQ_HZ;
+    let skipped = (now - TICKLESS_SINCE.load(Ordering::SeqCst)) / period;
+    INTERRUPT_STATS.lock().timer_ticks += skipped;
+    
+    expire_timer_event(now);
+    setup_timer_interrupt();
+}
+
+/// Wait for the next interrupt.
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("wfi");
+    }
+}
+
+// Current value of the physical counter
+pub fn counter_ticks() -> u64 {
+    let count: u64;
+    unsafe {
+        asm!("mrs {}, cntpct_el0", out(reg) count);
+    }
+    count
+}
+
+// Counter frequency in Hz
+pub fn counter_frequency() -> u64 {
+    let freq: u64;
+    unsafe {
+        asm!("mrs {}, cntfrq_el0", out(reg) freq);
+    }
+    freq
+}
+
+/// Busy-wait for at least `us` microseconds using the generic timer.
+pub fn delay_us(us: u64) {
+    let start = counter_ticks();
+    let ticks = (counter_frequency() * us).div_ceil(1_000_000);
+    while counter_ticks().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
     }
 }
 
@@ -263,6 +625,29 @@ pub fn init() {
         asm!("msr vbar_el1, {}", in(reg) vector_addr);
     }
     
+    // Interrupt controller, then the timer's line through it
+    match crate::devicetree::device_tree() {
+        Some(dt) => {
+            if let Err(e) = crate::gic::init(&dt) {
+                crate::println!("Interrupts: {}", e);
+            }
+            
+            // interrupts = <secure phys>, <non-secure phys>, <virt>, <hyp>
+            let timer_irq = dt
+                .find_compatible("arm,armv8-timer")
+                .next()
+                .and_then(|node| crate::gic::dt_interrupt(&node, 1))
+                .unwrap_or(TIMER_IRQ_DEFAULT);
+            if crate::gic::is_present() {
+                if let Err(e) = crate::gic::register_handler(timer_irq, timer_irq_handler) {
+                    crate::println!("Interrupts: Timer IRQ {}: {}", timer_irq, e);
+                }
+            }
+        }
+        None => crate::println!("Interrupts: No device tree, interrupt controller not configured"),
+    }
+    crate::irqbalance::init();
+    
     // Configure timer
     setup_timer_interrupt();
     crate::println!("Interrupts: Generic timer configured for {}Hz", TIMER_FREQ_HZ);
@@ -289,16 +674,60 @@ pub fn enable_interrupts() {
     }
 }
 
-pub fn get_interrupt_stats() -> (u64, u64, u64, u64, u64) {
-    let stats = INTERRUPT_STATS.lock();
-    (stats.irq_count, stats.sync_exceptions, stats.fiq_count, 
-     stats.serror_count, stats.timer_ticks)
+/// Mask IRQs, returning the previous DAIF value for `local_irq_restore`.
+pub fn local_irq_save() -> u64 {
+    let daif: u64;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif);
+        asm!("msr daifset, #2");  // Mask IRQ
+    }
+    daif
+}
+
+pub fn local_irq_restore(daif: u64) {
+    unsafe {
+        asm!("msr daif, {}", in(reg) daif);
+    }
+}
+
+/// Whether IRQs are masked on this CPU (DAIF.I).
+pub fn irqs_disabled() -> bool {
+    let daif: u64;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif);
+    }
+    daif & (1 << 7) != 0
+}
+
+/// Run `f` with IRQs masked, restoring the previous mask afterwards.
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let daif = local_irq_save();
+    let result = f();
+    local_irq_restore(daif);
+    result
+}
+
+pub fn get_interrupt_stats() -> InterruptStats {
+    *INTERRUPT_STATS.lock()
+}
+
+/// Start handler timing and timer latency over; counts are kept.
+pub fn reset_interrupt_timing() {
+    let mut stats = INTERRUPT_STATS.lock();
+    stats.irq_time = HandlerTiming::new();
+    stats.sync_time = HandlerTiming::new();
+    stats.timer_latency = HandlerTiming::new();
+    drop(stats);
+    crate::gic::reset_handler_timing();
 }
 
 // Test function to trigger a system call
 pub fn test_system_call() {
     crate::println!("Interrupts: Testing system call...");
     unsafe {
-        asm!("svc #42");  // System call with immediate value 42
+        asm!("svc #42", out("x0") _);  // System call with immediate value 42
     }
 }
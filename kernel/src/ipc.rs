@@ -1,15 +1,136 @@
 // Port-based asynchronous IPC for microkernel
+//
+// Message payloads live on the kernel heap, up to MESSAGE_MAX bytes, so a
+// protocol message goes in one piece; the system calls can gather one from
+// and scatter one into a list of user buffers. Bulk data travels as a page
+// grant instead: the sender's frames themselves, moved out of its address
+// space or shared read-only, mapped into the receiver's on receipt, so
+// nothing is copied.
+//
+// Beside its message slot each port has a 64-bit notification word:
+// `signal` ORs bits into it and `wait_bits` takes them, so a driver can
+// say "data ready" from its interrupt handler without building a Message.
+//
+// `call` is the synchronous path: the message carries a one-shot reply
+// capability, the caller blocks until the thread that received it answers
+// through `reply`, and the CPU goes straight from caller to server and
+// back instead of through the run queue. A call answered by no one (its
+// message dropped, or its server gone) leaves the caller blocked.
 
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use spin::Mutex;
+use crate::executor::Notify;
+use crate::interrupts::without_interrupts;
+use crate::memory::frame_allocator::{deallocate_frame, frame_get};
+use crate::memory::paging::{PageFlags, VirtAddr};
+use crate::process::capability::{self, Capability};
+use crate::process::scheduler::{block_current, current_thread_id, switch_directly, wake, yield_now};
+use crate::process::ThreadId;
+use crate::rcu::{self, Rcu};
+use crate::process::thread::AddressSpace;
+use crate::sync::IrqSafeMutex;
+use crate::trace::{self, TraceEvent, TRACE_ID_NONE};
 
 pub type PortId = u32;
 pub type ProcessId = u32;
+/// Largest payload a message carries. Payloads are copied to the kernel
+/// heap, so this bounds what one queued message holds.
+pub const MESSAGE_MAX: usize = 8 * 1024;
+/// Names the answer a call is waiting for. Only the thread that received
+/// the call may use it, and only once.
+pub type ReplyId = u32;
 
 #[derive(Debug, Clone)]
 pub struct Message {
     pub sender: ProcessId,
-    pub data: [u8; 256], // Fixed size for now
-    pub len: usize,
+    pub data: Vec<u8>,
+    // Causal request ID, TRACE_ID_NONE when untraced. Stamped by the kernel
+    // on send while tracing is on.
+    pub trace_id: u64,
+    // Pages travelling with the message
+    pub grant: Option<PageGrant>,
+    // Set by `call`: the receiver answers through it
+    pub reply: Option<ReplyId>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GrantMode {
+    /// The pages leave the sender and arrive writable.
+    Move,
+    /// The sender keeps its pages; the receiver gets them read-only.
+    Share,
+}
+
+/// Frames in transit between address spaces. Holds a reference to each
+/// frame until it is mapped on the other side, or dropped undelivered.
+#[derive(Debug)]
+pub struct PageGrant {
+    frames: Vec<NonNull<u8>>,
+    mode: GrantMode,
+}
+
+// Frames are only reached through their references
+unsafe impl Send for PageGrant {}
+
+impl PageGrant {
+    /// Take `pages` pages at `base` out of `space` (Move) or reference
+    /// them where they are (Share).
+    pub fn take(space: &mut AddressSpace, base: VirtAddr, pages: usize, mode: GrantMode) -> Result<Self, &'static str> {
+        let frames = space.take_frames(base, pages, mode == GrantMode::Move)?;
+        Ok(Self { frames, mode })
+    }
+    
+    pub fn pages(&self) -> usize {
+        self.frames.len()
+    }
+    
+    pub fn mode(&self) -> GrantMode {
+        self.mode
+    }
+    
+    fn flags(&self) -> PageFlags {
+        let flags = PageFlags::NORMAL_MEMORY | PageFlags::INNER_SHAREABLE | PageFlags::ACCESSED
+            | PageFlags::USER | PageFlags::PXN | PageFlags::UXN;
+        match self.mode {
+            GrantMode::Move => flags,
+            GrantMode::Share => flags | PageFlags::READ_ONLY,
+        }
+    }
+    
+    /// Map the pages into the receiver, returning where.
+    pub fn map_into(self, space: &mut AddressSpace) -> Result<VirtAddr, &'static str> {
+        space.map_frames(&self.frames, self.flags())
+    }
+    
+    /// Give moved pages back to the sender at `base`, where they came
+    /// from, when the message could not be sent.
+    pub fn restore(self, space: &mut AddressSpace, base: VirtAddr) -> Result<(), &'static str> {
+        match self.mode {
+            GrantMode::Move => space.map_frames_at(base, &self.frames, self.flags()),
+            GrantMode::Share => Ok(()),
+        }
+    }
+}
+
+impl Clone for PageGrant {
+    fn clone(&self) -> Self {
+        for &frame in &self.frames {
+            let _ = frame_get(frame);
+        }
+        Self { frames: self.frames.clone(), mode: self.mode }
+    }
+}
+
+impl Drop for PageGrant {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            deallocate_frame(frame);
+        }
+    }
 }
 
 pub struct Port {
@@ -17,12 +138,37 @@ pub struct Port {
     owner: ProcessId,
     // TODO: Replace with proper queue once we have heap allocator
     message_buffer: Mutex<Option<Message>>,
+    // Signalled on every send, for async receivers
+    arrived: Notify,
+    // Notification bits raised since the last wait
+    bits: AtomicU64,
+    // Thread blocked in wait_bits
+    bits_waiter: IrqSafeMutex<Option<ThreadId>>,
+    // Signalled on every signal, for async waiters
+    signalled: Notify,
+    // Thread blocked in receive_wait
+    receiver: IrqSafeMutex<Option<ThreadId>>,
 }
 
+// Every live port by ID. Looked up on every IPC call, changed only when a
+// port is created or destroyed, so readers go lock-free under RCU.
+static PORTS: Rcu<BTreeMap<PortId, Arc<Port>>> = Rcu::empty();
+static NEXT_PORT_ID: Mutex<PortId> = Mutex::new(1);
+
+// A call waiting for its answer
+struct ReplySlot {
+    caller: ThreadId,
+    // Thread that received the call and may answer it
+    server: Option<ThreadId>,
+    answer: Option<Message>,
+}
+
+static REPLIES: Mutex<BTreeMap<ReplyId, ReplySlot>> = Mutex::new(BTreeMap::new());
+static NEXT_REPLY_ID: AtomicU32 = AtomicU32::new(1);
+
 pub fn init() {
     crate::println!("Initializing IPC system...");
     
-    // TODO: Initialize port table
     // TODO: Set up message queues
     // TODO: Initialize async notification system
     
@@ -35,19 +181,318 @@ impl Port {
             id,
             owner,
             message_buffer: Mutex::new(None),
+            arrived: Notify::new(),
+            bits: AtomicU64::new(0),
+            bits_waiter: IrqSafeMutex::new(None),
+            signalled: Notify::new(),
+            receiver: IrqSafeMutex::new(None),
         }
     }
     
     pub fn send_message(&self, message: Message) -> Result<(), &'static str> {
+        self.send_or_return(message).map_err(|_| "Port buffer full")
+    }
+    
+    /// Send, or hand the message back if the port is full, so a page
+    /// grant in it can be returned to the sender.
+    pub fn send_or_return(&self, message: Message) -> Result<(), Message> {
+        if let Some(receiver) = self.enqueue(message)? {
+            wake(receiver);
+        }
+        Ok(())
+    }
+    
+    // Queue `message`, returning the thread blocked in receive_wait, for
+    // the sender to wake
+    fn enqueue(&self, mut message: Message) -> Result<Option<ThreadId>, Message> {
+        // A message sent while handling a traced request continues that
+        // trace; anything else starts a new one
+        if trace::is_enabled() && message.trace_id == TRACE_ID_NONE {
+            message.trace_id = match trace::current() {
+                TRACE_ID_NONE => trace::new_trace_id(),
+                trace_id => trace_id,
+            };
+        }
+        let (trace_id, len) = (message.trace_id, message.data.len());
+        
         let mut buffer = self.message_buffer.lock();
         if buffer.is_some() {
-            return Err("Port buffer full");
+            return Err(message);
         }
         *buffer = Some(message);
-        Ok(())
+        drop(buffer);
+        self.arrived.notify();
+        
+        if trace_id != TRACE_ID_NONE {
+            trace::record(TraceEvent::IpcSend, trace_id, current_thread_id(), self.id, len as u32);
+        }
+        Ok(self.receiver.lock().take())
     }
     
+    /// Take the waiting message. The receiver adopts its trace ID, so
+    /// replies and onward requests carry it too, and becomes the one to
+    /// answer it if it is a call.
     pub fn receive_message(&self) -> Option<Message> {
-        self.message_buffer.lock().take()
+        let message = self.message_buffer.lock().take()?;
+        if let Some(id) = message.reply {
+            if let Some(slot) = REPLIES.lock().get_mut(&id) {
+                slot.server = Some(current_thread_id());
+            }
+        }
+        trace::set_current(message.trace_id);
+        if message.trace_id != TRACE_ID_NONE {
+            trace::record(TraceEvent::IpcReceive, message.trace_id, current_thread_id(),
+                          self.id, message.data.len() as u32);
+        }
+        Some(message)
+    }
+    
+    /// Block the calling thread until a message arrives and take it. One
+    /// thread waits on a port at a time.
+    pub fn receive_wait(&self) -> Result<Message, &'static str> {
+        loop {
+            if let Some(message) = without_interrupts(|| self.receive_or_block())? {
+                return Ok(message);
+            }
+            yield_now();
+        }
+    }
+    
+    // Take the waiting message, or register as the receiver and block.
+    // Call with IRQs masked, and yield on Ok(None).
+    fn receive_or_block(&self) -> Result<Option<Message>, &'static str> {
+        let me = current_thread_id();
+        let mut receiver = self.receiver.lock();
+        if let Some(message) = self.receive_message() {
+            // Woken by someone else first: don't stay registered
+            if *receiver == Some(me) {
+                *receiver = None;
+            }
+            return Ok(Some(message));
+        }
+        match *receiver {
+            Some(other) if other != me => return Err("Port already has a receiver"),
+            _ => *receiver = Some(me),
+        }
+        drop(receiver);
+        block_current();
+        Ok(None)
     }
+    
+    /// Wait for a message from an async task.
+    pub async fn receive_async(&self) -> Message {
+        loop {
+            if let Some(message) = self.receive_message() {
+                return message;
+            }
+            self.arrived.wait().await;
+        }
+    }
+    
+    /// Raise notification bits and wake whoever waits for them. Safe
+    /// from interrupt context: nothing is allocated and no sleeping lock
+    /// is taken.
+    pub fn signal(&self, bits: u64) {
+        if bits == 0 {
+            return;
+        }
+        self.bits.fetch_or(bits, Ordering::SeqCst);
+        if let Some(waiter) = self.bits_waiter.lock().take() {
+            wake(waiter);
+        }
+        self.signalled.notify();
+    }
+    
+    /// Take the raised bits without blocking; 0 if none.
+    pub fn poll_bits(&self) -> u64 {
+        self.bits.swap(0, Ordering::SeqCst)
+    }
+    
+    /// Block the calling thread until some bits are raised, then take
+    /// them. One thread waits on a port at a time.
+    pub fn wait_bits(&self) -> Result<u64, &'static str> {
+        let me = current_thread_id();
+        loop {
+            // Check and block with IRQs masked so a signal from an
+            // interrupt handler cannot slip in between
+            let bits = without_interrupts(|| {
+                let mut waiter = self.bits_waiter.lock();
+                let bits = self.poll_bits();
+                if bits != 0 {
+                    // Woken by someone else first: don't stay registered
+                    if *waiter == Some(me) {
+                        *waiter = None;
+                    }
+                    return Ok(bits);
+                }
+                match *waiter {
+                    Some(other) if other != me => return Err("Port already has a waiter"),
+                    _ => *waiter = Some(me),
+                }
+                drop(waiter);
+                block_current();
+                Ok(0)
+            })?;
+            if bits != 0 {
+                return Ok(bits);
+            }
+            yield_now();
+        }
+    }
+    
+    /// Wait for notification bits from an async task.
+    pub async fn wait_bits_async(&self) -> u64 {
+        loop {
+            let bits = self.poll_bits();
+            if bits != 0 {
+                return bits;
+            }
+            self.signalled.wait().await;
+        }
+    }
+    
+    pub fn id(&self) -> PortId {
+        self.id
+    }
+    
+    pub fn owner(&self) -> ProcessId {
+        self.owner
+    }
+    
+    /// Messages waiting to be received.
+    pub fn pending(&self) -> usize {
+        self.message_buffer.lock().is_some() as usize
+    }
+}
+
+/// Send `message` to `port` and block until the receiver answers it with
+/// `reply`. A server already waiting on the port runs at once, on the
+/// rest of the caller's time slice.
+pub fn call(port: &Port, mut message: Message) -> Result<Message, &'static str> {
+    let id = NEXT_REPLY_ID.fetch_add(1, Ordering::Relaxed);
+    REPLIES.lock().insert(id, ReplySlot { caller: current_thread_id(), server: None, answer: None });
+    message.reply = Some(id);
+    
+    // IRQs stay masked from the send to the yield, so the answer cannot
+    // come before the caller is blocked
+    let sent = without_interrupts(|| {
+        let receiver = port.enqueue(message).map_err(drop)?;
+        block_current();
+        if let Some(server) = receiver {
+            switch_directly(server);
+        }
+        Ok::<_, ()>(())
+    });
+    if sent.is_err() {
+        REPLIES.lock().remove(&id);
+        return Err("Port buffer full");
+    }
+    
+    loop {
+        yield_now();
+        let answer = without_interrupts(|| {
+            let mut replies = REPLIES.lock();
+            let answer = replies.get_mut(&id).and_then(|slot| slot.answer.take());
+            match answer {
+                Some(_) => {
+                    replies.remove(&id);
+                }
+                None => block_current(),
+            }
+            answer
+        });
+        if let Some(answer) = answer {
+            return Ok(answer);
+        }
+    }
+}
+
+// Hand `answer` to the caller waiting on `id`, returning the caller
+fn deliver_reply(id: ReplyId, mut answer: Message) -> Result<ThreadId, &'static str> {
+    let mut replies = REPLIES.lock();
+    let slot = replies.get_mut(&id).ok_or("No such reply")?;
+    if slot.server != Some(current_thread_id()) {
+        return Err("Reply belongs to another thread");
+    }
+    // One-shot: nobody can answer twice, and answers are not calls
+    slot.server = None;
+    answer.reply = None;
+    slot.answer = Some(answer);
+    Ok(slot.caller)
+}
+
+/// Answer the call that `id` came with.
+pub fn reply(id: ReplyId, answer: Message) -> Result<(), &'static str> {
+    wake(deliver_reply(id, answer)?);
+    Ok(())
+}
+
+/// Answer a call and wait for the next message on `port`: the server
+/// loop's fast path. The CPU goes straight back to the caller unless a
+/// message is already waiting.
+pub fn reply_and_receive(id: ReplyId, answer: Message, port: &Port) -> Result<Message, &'static str> {
+    let caller = deliver_reply(id, answer)?;
+    let message = without_interrupts(|| {
+        let message = port.receive_or_block();
+        match message {
+            Ok(None) => switch_directly(caller),
+            _ => wake(caller),
+        }
+        message
+    })?;
+    match message {
+        Some(message) => Ok(message),
+        None => {
+            yield_now();
+            port.receive_wait()
+        }
+    }
+}
+
+/// Create a port owned by `owner` and enter it in the port table.
+pub fn create_port(owner: ProcessId) -> PortId {
+    let id = {
+        let mut next = NEXT_PORT_ID.lock();
+        let id = *next;
+        *next += 1;
+        id
+    };
+    let port = Arc::new(Port::new(id, owner));
+    PORTS.update(|ports| {
+        let mut ports = ports.cloned().unwrap_or_default();
+        ports.insert(id, port);
+        (Some(ports), ())
+    });
+    id
+}
+
+pub fn destroy_port(id: PortId) -> Result<(), &'static str> {
+    PORTS.update(|ports| match ports {
+        Some(ports) if ports.contains_key(&id) => {
+            let mut ports = ports.clone();
+            ports.remove(&id);
+            (Some(ports), Ok(()))
+        }
+        _ => (None, Err("No such port")),
+    })
+}
+
+pub fn lookup_port(id: PortId) -> Option<Arc<Port>> {
+    let guard = rcu::read_lock();
+    PORTS.read(&guard)?.get(&id).cloned()
+}
+
+/// Look up a port on behalf of `holder`. Unprivileged threads need a
+/// capability for it.
+pub fn lookup_port_for(id: PortId, holder: ThreadId, privileged: bool) -> Option<Arc<Port>> {
+    if !privileged && !capability::held(holder).contains(&Capability::Port(id)) {
+        return None;
+    }
+    lookup_port(id)
+}
+
+/// Snapshot of the port table.
+pub fn ports() -> Vec<Arc<Port>> {
+    let guard = rcu::read_lock();
+    PORTS.read(&guard).map(|ports| ports.values().cloned().collect()).unwrap_or_default()
 }
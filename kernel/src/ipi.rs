@@ -0,0 +1,231 @@
+// Inter-processor interrupts
+//
+// Each kind of IPI is its own SGI, so several can be pending at a CPU at
+// once and the GIC keeps them apart. A CPU is its bit in the GIC's CPU
+// mask (gic::this_cpu).
+//
+// - Reschedule: the target switches threads on its way out of the IRQ.
+// - TLB shootdown: the target runs a local invalidation the sender posted,
+//   for translations the broadcast TLBI does not reach; the sender waits
+//   for every target to acknowledge.
+// - Stop: the target parks for good (panic).
+//
+// Secondary CPUs do not run the kernel yet, so for now only the boot CPU
+// sends and takes these.
+
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use crate::gic;
+use crate::ktest::kernel_test;
+use crate::memory::paging::VirtAddr;
+use crate::memory::tlb::{self, Asid};
+
+// Spins to wait for shootdown acknowledgements
+const SHOOTDOWN_WAIT_SPINS: u32 = 1_000_000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IpiKind {
+    Reschedule,
+    TlbShootdown,
+    Stop,
+}
+
+impl IpiKind {
+    const ALL: [IpiKind; 3] = [IpiKind::Reschedule, IpiKind::TlbShootdown, IpiKind::Stop];
+    
+    /// The SGI that carries this kind.
+    pub const fn sgi(self) -> u32 {
+        match self {
+            IpiKind::Reschedule => 0,
+            IpiKind::TlbShootdown => 1,
+            IpiKind::Stop => 15,
+        }
+    }
+    
+    pub fn name(self) -> &'static str {
+        match self {
+            IpiKind::Reschedule => "reschedule",
+            IpiKind::TlbShootdown => "tlb-shootdown",
+            IpiKind::Stop => "stop",
+        }
+    }
+}
+
+/// A TLB invalidation run on each target CPU.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TlbFlush {
+    /// One page; `None` is a global (kernel) mapping
+    Page(Option<Asid>, VirtAddr),
+    Asid(Asid),
+    All,
+}
+
+impl TlbFlush {
+    fn run_local(self) {
+        match self {
+            TlbFlush::Page(asid, virt) => tlb::flush_page_local(asid, virt),
+            TlbFlush::Asid(asid) => tlb::flush_asid_local(asid),
+            TlbFlush::All => tlb::flush_all_local(),
+        }
+    }
+}
+
+static SENT: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+// Held by the sender for a whole shootdown, so there is one at a time;
+// the request it posted and the CPUs yet to run it
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static SHOOTDOWN_REQUEST: Mutex<Option<TlbFlush>> = Mutex::new(None);
+static SHOOTDOWN_PENDING: AtomicU8 = AtomicU8::new(0);
+
+fn index(kind: IpiKind) -> usize {
+    IpiKind::ALL.iter().position(|&other| other == kind).unwrap_or(0)
+}
+
+/// Send `kind` to every CPU in `cpus`.
+pub fn send_mask(cpus: u8, kind: IpiKind) -> Result<(), &'static str> {
+    if cpus & !gic::online_cpus() != 0 {
+        return Err("IPI: Target CPU not online");
+    }
+    gic::send_sgi(kind.sgi(), cpus)?;
+    SENT[index(kind)].fetch_add(cpus.count_ones() as u64, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Send `kind` to CPU number `cpu` (its bit in the GIC CPU mask).
+pub fn send(cpu: usize, kind: IpiKind) -> Result<(), &'static str> {
+    if cpu >= u8::BITS as usize {
+        return Err("IPI: No such CPU");
+    }
+    send_mask(1 << cpu, kind)
+}
+
+/// Send `kind` to every online CPU but this one; returns the ones sent to.
+pub fn send_others(kind: IpiKind) -> Result<u8, &'static str> {
+    let others = gic::online_cpus() & !gic::this_cpu();
+    if others != 0 {
+        send_mask(others, kind)?;
+    }
+    Ok(others)
+}
+
+/// Run `flush` on every CPU in `cpus` and wait until they all have. This
+/// CPU's share runs directly, so it may be called with IRQs masked as long
+/// as no other CPU is targeted.
+pub fn tlb_shootdown(cpus: u8, flush: TlbFlush) -> Result<(), &'static str> {
+    let this_cpu = gic::this_cpu();
+    if cpus & this_cpu != 0 {
+        flush.run_local();
+    }
+    let others = cpus & !this_cpu;
+    if others == 0 {
+        return Ok(());
+    }
+    let _shootdown = SHOOTDOWN.lock();
+    *SHOOTDOWN_REQUEST.lock() = Some(flush);
+    SHOOTDOWN_PENDING.store(others, Ordering::Release);
+    let mut result = send_mask(others, IpiKind::TlbShootdown);
+    if result.is_ok() {
+        result = Err("IPI: TLB shootdown not acknowledged");
+        for _ in 0..SHOOTDOWN_WAIT_SPINS {
+            if SHOOTDOWN_PENDING.load(Ordering::Acquire) == 0 {
+                result = Ok(());
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    *SHOOTDOWN_REQUEST.lock() = None;
+    result
+}
+
+fn reschedule_ipi(_irq: u32) {
+    crate::process::scheduler::request_resched();
+}
+
+fn shootdown_ipi(_irq: u32) {
+    let flush = *SHOOTDOWN_REQUEST.lock();
+    if let Some(flush) = flush {
+        flush.run_local();
+    }
+    SHOOTDOWN_PENDING.fetch_and(!gic::this_cpu(), Ordering::AcqRel);
+}
+
+fn stop_ipi(irq: u32) {
+    crate::panic::park_cpu(irq);
+}
+
+/// Install the IPI handlers on their SGIs and export /proc/ipi. Needs the GIC.
+pub fn init() {
+    if !gic::is_present() {
+        return;
+    }
+    let handlers: [(IpiKind, gic::IrqHandler); 3] = [
+        (IpiKind::Reschedule, reschedule_ipi),
+        (IpiKind::TlbShootdown, shootdown_ipi),
+        (IpiKind::Stop, stop_ipi),
+    ];
+    for (kind, handler) in handlers {
+        if let Err(e) = gic::register_handler(kind.sgi(), handler) {
+            crate::println!("IPI: Could not install {} handler: {}", kind.name(), e);
+        }
+    }
+    let _ = crate::procfs::register("ipi", proc_ipi);
+}
+
+fn proc_ipi(out: &mut Vec<u8>) {
+    let mut text = alloc::string::String::new();
+    for kind in IpiKind::ALL {
+        let _ = writeln!(text, "{:14} sgi {:2} sent {} received {}", kind.name(), kind.sgi(),
+                         SENT[index(kind)].load(Ordering::Relaxed), gic::irq_count(kind.sgi()));
+    }
+    out.extend_from_slice(text.as_bytes());
+}
+
+#[kernel_test]
+fn test_ipi() {
+    use crate::gic;
+    
+    crate::println!("IPI Test: Testing IPIs...");
+    
+    if !gic::is_present() {
+        crate::println!("IPI Test: ✗ No interrupt controller");
+        return;
+    }
+    
+    // A reschedule IPI to this CPU arrives like any other
+    let this_cpu = gic::this_cpu();
+    let cpu = this_cpu.trailing_zeros() as usize;
+    let before = gic::irq_count(IpiKind::Reschedule.sgi());
+    let sent = send(cpu, IpiKind::Reschedule).is_ok();
+    crate::interrupts::delay_us(1000);
+    if sent && gic::irq_count(IpiKind::Reschedule.sgi()) == before + 1 {
+        crate::println!("IPI Test: ✓ Reschedule IPI delivered to CPU {}", cpu);
+    } else {
+        crate::println!("IPI Test: ✗ Reschedule IPI sent {} but not counted", sent);
+    }
+    
+    let refused = send(u8::BITS as usize, IpiKind::Reschedule).is_err()
+        && send_mask(!gic::online_cpus(), IpiKind::Reschedule).is_err();
+    let others = send_others(IpiKind::Reschedule);
+    if refused && others.is_ok_and(|others| others & this_cpu == 0) {
+        crate::println!("IPI Test: ✓ Offline targets refused, no IPI to self among others");
+    } else {
+        crate::println!("IPI Test: ✗ IPI targets not checked ({:?})", others);
+    }
+    
+    // This CPU's part of a shootdown runs in place
+    let ttbr0: u64;
+    unsafe { core::arch::asm!("mrs {}, ttbr0_el1", out(reg) ttbr0) };
+    if tlb_shootdown(gic::online_cpus(), TlbFlush::All).is_ok()
+        && tlb_shootdown(this_cpu, TlbFlush::Page(None, test_ipi as fn() as usize as u64)).is_ok()
+        && tlb_shootdown(this_cpu, TlbFlush::Asid((ttbr0 >> 48) as u16)).is_ok()
+    {
+        crate::println!("IPI Test: ✓ TLB shootdowns acknowledged");
+    } else {
+        crate::println!("IPI Test: ✗ TLB shootdown timed out");
+    }
+    
+    crate::println!("IPI Test: IPI test completed");
+}
@@ -0,0 +1,96 @@
+// irqbalance: spread busy shared interrupts across online CPUs
+//
+// Runs from idle time every BALANCE_INTERVAL_MS. Each pass takes the
+// number of deliveries per SPI since the previous pass and, busiest first,
+// routes each interrupt to the CPU with the least load assigned so far.
+// Interrupts pinned with gic::set_affinity are left where they are but
+// still count towards their CPU's load.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::cpu::MAX_CPUS;
+use crate::gic::{self, MAX_IRQS, SPI_BASE};
+use crate::interrupts::{counter_frequency, counter_ticks};
+
+const BALANCE_INTERVAL_MS: u64 = 5000;
+
+struct BalanceState {
+    last_run: u64,
+    last_counts: [u64; MAX_IRQS],
+}
+
+static STATE: Mutex<BalanceState> = Mutex::new(BalanceState {
+    last_run: 0,
+    last_counts: [0; MAX_IRQS],
+});
+
+pub fn init() {
+    if gic::is_present() {
+        if let Err(e) = crate::process::idle::register_idle_work("irqbalance", balance_if_due) {
+            crate::println!("Irqbalance: Disabled: {}", e);
+        }
+    }
+}
+
+fn balance_if_due() -> bool {
+    let interval = counter_frequency() * BALANCE_INTERVAL_MS / 1000;
+    let due = STATE
+        .try_lock()
+        .is_some_and(|state| counter_ticks().wrapping_sub(state.last_run) >= interval);
+    if due {
+        balance();
+    }
+    false
+}
+
+/// One balancing pass. Returns how many interrupts were moved.
+pub fn balance() -> usize {
+    let mut state = STATE.lock();
+    state.last_run = counter_ticks();
+    
+    let online = gic::online_cpus();
+    let mut load = [0u64; MAX_CPUS];
+    let mut movable = Vec::new();
+    
+    for irq in SPI_BASE..gic::num_irqs() {
+        let count = gic::irq_count(irq);
+        let delta = count - state.last_counts[irq as usize];
+        state.last_counts[irq as usize] = count;
+        if delta == 0 || !gic::has_handler(irq) {
+            continue;
+        }
+        
+        if gic::is_pinned(irq) {
+            // Spread evenly over every CPU the pin allows
+            let targets = gic::affinity(irq).unwrap_or(0) & online;
+            let share = delta / targets.count_ones().max(1) as u64;
+            for (cpu, cpu_load) in load.iter_mut().enumerate() {
+                if targets & (1 << cpu) != 0 {
+                    *cpu_load += share;
+                }
+            }
+        } else {
+            movable.push((irq, delta));
+        }
+    }
+    
+    // Busiest first onto the least loaded CPU
+    movable.sort_unstable_by_key(|&(_, delta)| core::cmp::Reverse(delta));
+    let mut moved = 0;
+    for (irq, delta) in movable {
+        let cpu = (0..MAX_CPUS)
+            .filter(|&cpu| online & (1 << cpu) != 0)
+            .min_by_key(|&cpu| load[cpu]);
+        let cpu = match cpu {
+            Some(cpu) => cpu,
+            None => break,
+        };
+        load[cpu] += delta;
+        
+        let target = 1u8 << cpu;
+        if gic::affinity(irq) != Some(target) && gic::route(irq, target).is_ok() {
+            moved += 1;
+        }
+    }
+    moved
+}
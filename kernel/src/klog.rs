@@ -0,0 +1,198 @@
+// Kernel log ring: a copy of everything printed to the console
+//
+// Keeps the most recent LOG_BUF_SIZE bytes so output that scrolled off
+// the console can still be read back, and feeds the pstore and netconsole
+// mirrors. Output that arrives while the ring is held (an interrupt
+// handler printing over a writer, lock debugging reporting on the log
+// itself) waits in a lock-free queue for the next writer instead of
+// being lost. Self-test ✗ marks are counted on the way in, so a test run
+// can tell whether anything failed (ktest.rs).
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use crate::ktest::kernel_test;
+use crate::mpsc::MpscQueue;
+use crate::sync::IrqSafeMutex;
+
+const LOG_BUF_SIZE: usize = 64 * 1024;
+
+// How a self-test reports a failed check
+const FAILURE_MARK: &[u8] = "✗".as_bytes();
+
+// Output held back while the ring was busy, in chunks
+const DEFERRED_CHUNK: usize = 62;
+const DEFERRED_CHUNKS: usize = 32;
+
+// Message levels are numbered as in syslog, 0 (emergency) to 7 (debug);
+// lower is more severe. Only debug chatter is filtered so far.
+pub const LOG_DEBUG: u8 = 7;
+
+// Most verbose level printed; tunable as kernel.log_level
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LOG_DEBUG);
+static FAILURE_MARKS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct LogRing {
+    buf: [u8; LOG_BUF_SIZE],
+    // Total bytes ever written; the next write goes to written % size
+    written: usize,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        Self { buf: [0; LOG_BUF_SIZE], written: 0 }
+    }
+    
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[self.written % LOG_BUF_SIZE] = byte;
+            self.written += 1;
+        }
+    }
+    
+    /// The most recent `max` bytes, oldest first.
+    pub fn tail(&self, max: usize) -> Vec<u8> {
+        let len = self.written.min(LOG_BUF_SIZE).min(max);
+        let start = self.written - len;
+        (start..self.written).map(|i| self.buf[i % LOG_BUF_SIZE]).collect()
+    }
+    
+    pub fn written(&self) -> usize {
+        self.written
+    }
+}
+
+static LOG: IrqSafeMutex<LogRing> = IrqSafeMutex::new(LogRing::new());
+
+struct Chunk {
+    len: u8,
+    bytes: [u8; DEFERRED_CHUNK],
+}
+
+static DEFERRED: MpscQueue<Chunk, DEFERRED_CHUNKS> = MpscQueue::new();
+
+/// Most verbose message level currently printed.
+pub fn level() -> u8 {
+    LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn set_level(level: u8) -> Result<(), &'static str> {
+    if level > LOG_DEBUG {
+        return Err("Log level out of range");
+    }
+    LOG_LEVEL.store(level, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Take the level from `loglevel=`: a number or a syslog name.
+pub fn init() {
+    const NAMES: [&str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+    let Some(arg) = crate::bootparams::get("loglevel") else {
+        return;
+    };
+    let requested = NAMES.iter().position(|&name| name == arg).map(|level| level as u8).or_else(|| arg.parse().ok());
+    match requested.map(set_level) {
+        Some(Ok(())) => crate::println!("Log: Level {}", arg),
+        _ => crate::println!("Log: Bad loglevel={}, keeping {}", arg, level()),
+    }
+}
+
+/// Whether messages of `level` are printed; chatty output checks this
+/// before formatting anything.
+pub fn enabled(level: u8) -> bool {
+    level <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+// Add to the ring and its mirrors
+fn append(log: &mut LogRing, bytes: &[u8]) {
+    let marks = bytes.windows(FAILURE_MARK.len()).filter(|w| *w == FAILURE_MARK).count();
+    if marks != 0 {
+        FAILURE_MARKS.fetch_add(marks, Ordering::Relaxed);
+    }
+    log.write(bytes);
+    crate::pstore::write(bytes);
+    crate::netconsole::write(bytes);
+}
+
+// Append what was queued while the ring was held
+fn drain_deferred(log: &mut LogRing) {
+    while let Some(chunk) = DEFERRED.pop() {
+        append(log, &chunk.bytes[..chunk.len as usize]);
+    }
+}
+
+/// Append console output to the ring and its mirrors.
+pub fn write(bytes: &[u8]) {
+    // Never wait for the ring: output produced while it is held (lock
+    // debugging, a panic in here) is queued for the next writer, and only
+    // lost if the queue fills up
+    if let Some(mut log) = LOG.try_lock() {
+        drain_deferred(&mut log);
+        append(&mut log, bytes);
+        return;
+    }
+    for piece in bytes.chunks(DEFERRED_CHUNK) {
+        let mut chunk = Chunk { len: piece.len() as u8, bytes: [0; DEFERRED_CHUNK] };
+        chunk.bytes[..piece.len()].copy_from_slice(piece);
+        if DEFERRED.push(chunk).is_err() {
+            break;
+        }
+    }
+}
+
+/// Failed self-test checks printed so far.
+pub fn failure_marks() -> usize {
+    FAILURE_MARKS.load(Ordering::Relaxed)
+}
+
+/// Run `f` with the log held, so no output slips in meanwhile.
+pub fn with_log<R>(f: impl FnOnce(&LogRing) -> R) -> R {
+    let mut log = LOG.lock();
+    drain_deferred(&mut log);
+    f(&log)
+}
+
+#[kernel_test]
+fn test_kernel_log() {
+    crate::println!("Log Test: Testing kernel log persistence...");
+    
+    const MARKER: &[u8] = b"Log Test: log marker 7f3a";
+    crate::println!("{}", core::str::from_utf8(MARKER).unwrap_or(""));
+    let contains = |log: &[u8]| log.windows(MARKER.len()).any(|w| w == MARKER);
+    
+    let tail = with_log(|log| log.tail(4096));
+    if contains(&tail) {
+        crate::println!("Log Test: ✓ Console output captured in the log ring");
+    } else {
+        crate::println!("Log Test: ✗ Log ring missing recent output");
+    }
+    
+    // Written while the ring is held: queued, then added by the next user
+    const HELD_MARKER: &[u8] = b"Log Test: deferred marker 51c8\n";
+    let held = with_log(|log| {
+        write(HELD_MARKER);
+        !log.tail(4096).windows(HELD_MARKER.len()).any(|w| w == HELD_MARKER)
+    });
+    let tail = with_log(|log| log.tail(4096));
+    if held && tail.windows(HELD_MARKER.len()).any(|w| w == HELD_MARKER) {
+        crate::println!("Log Test: ✓ Output written while the ring was held is kept");
+    } else {
+        crate::println!("Log Test: ✗ Deferred log output held {} then lost", held);
+    }
+    
+    match crate::pstore::snapshot() {
+        Some(saved) if contains(&saved) => {
+            crate::println!("Log Test: ✓ Pstore region holds a valid copy ({} bytes)", saved.len());
+        }
+        Some(_) => crate::println!("Log Test: ✗ Pstore copy missing recent output"),
+        None => crate::println!("Log Test: ✗ Pstore region not active or header invalid"),
+    }
+    
+    // Only present after a warm reset
+    let mut buf = [0u8; 64];
+    match crate::procfs::read("lastlog", 0, &mut buf) {
+        Ok(len) => crate::println!("Log Test: /proc/lastlog readable ({} bytes at start)", len),
+        Err(_) => crate::println!("Log Test: /proc/lastlog absent (cold boot)"),
+    }
+    
+    crate::println!("Log Test: Kernel log test completed");
+}
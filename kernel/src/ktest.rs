@@ -0,0 +1,217 @@
+// Boot self-test harness, and the test mode that reports to the host
+//
+// A test is a function marked #[kernel_test] (libs/ktest-macros), which
+// registers it in the .kernel_tests linker section. They all run once
+// everything else is initialized, one at a time on the boot thread, in
+// source order: by file, then by line within it. Each sits at the end of
+// the module it exercises.
+//
+// Tests print a ✓ or ✗ line per check; the log counts the ✗ lines
+// (klog::failure_marks), and a test that printed any failed. A
+// #[kernel_test(should_panic)] test instead passes by panicking. It runs
+// on a thread of its own, and the panic handler hands its panic to
+// `expected_panic`, which ends that thread rather than the kernel.
+// Whatever the test held at the time stays held, so it has to panic
+// outside any lock.
+//
+// `test=frame_*,ipc_call` runs only the tests whose name (the function's,
+// without `test_`) matches one of the patterns; * matches anything.
+//
+// Booted with `test_exit` (`make test`), the kernel ends after the tests:
+// a power-off with exit status TEST_EXIT_PASS or TEST_EXIT_FAIL, which
+// reaches the host when QEMU runs with semihosting (power::test_exit). A
+// panic ends the run with TEST_EXIT_PANIC.
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use core::mem::size_of;
+use core::ptr::{self, addr_of};
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU8, Ordering};
+use crate::klog::failure_marks;
+use crate::power::{TEST_EXIT_FAIL, TEST_EXIT_PASS};
+use crate::process::scheduler::{current_thread_id, reap_exited};
+use crate::process::supervisor::ExitReason;
+use crate::process::{kthread_spawn, yield_now, ThreadId, KTHREAD_DEFAULT_PRIORITY};
+
+pub use ktest_macros::kernel_test;
+
+/// A registered test; #[kernel_test] builds these.
+pub struct KernelTest {
+    pub name: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+    pub run: fn(),
+    pub should_panic: bool,
+}
+
+extern "C" {
+    static __kernel_tests_start: u8;
+    static __kernel_tests_end: u8;
+}
+
+// How a should_panic test's thread ended
+const OUTCOME_RUNNING: u8 = 0;
+const OUTCOME_PANICKED: u8 = 1;
+const OUTCOME_RETURNED: u8 = 2;
+
+const NO_THREAD: ThreadId = ThreadId::MAX;
+
+// The should_panic test being run, the thread running it once it has
+// started, and how it ended
+static PANIC_TEST: AtomicPtr<KernelTest> = AtomicPtr::new(ptr::null_mut());
+static PANIC_THREAD: AtomicU32 = AtomicU32::new(NO_THREAD);
+static PANIC_OUTCOME: AtomicU8 = AtomicU8::new(OUTCOME_RUNNING);
+
+/// Every registered test, in source order.
+pub fn tests() -> Vec<&'static KernelTest> {
+    let section = unsafe {
+        let start = addr_of!(__kernel_tests_start) as *const KernelTest;
+        let len = (addr_of!(__kernel_tests_end) as usize - start as usize) / size_of::<KernelTest>();
+        core::slice::from_raw_parts(start, len)
+    };
+    let mut tests: Vec<&'static KernelTest> = section.iter().collect();
+    tests.sort_unstable_by_key(|test| (test.file, test.line));
+    tests
+}
+
+/// Whether `name` matches a `test=` pattern list: comma-separated, with *
+/// matching any run of characters.
+pub fn filter_matches(patterns: &str, name: &str) -> bool {
+    patterns.split(',').any(|pattern| glob_matches(pattern.as_bytes(), name.as_bytes()))
+}
+
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| glob_matches(rest, &name[skip..])),
+        Some((&c, rest)) => name.first() == Some(&c) && glob_matches(rest, &name[1..]),
+    }
+}
+
+/// Run the tests `test=` selects (all of them without it), reporting each;
+/// under `test_exit`, power off with the result.
+pub fn run() {
+    let filter = crate::bootparams::get("test").filter(|patterns| !patterns.is_empty());
+    let tests = tests();
+    
+    // Failed checks printed before any test ran count against the run
+    let boot_failures = failure_marks();
+    let mut passed = 0;
+    let mut failed = 0;
+    let mut filtered = 0;
+    for test in tests {
+        if filter.is_some_and(|patterns| !filter_matches(patterns, test.name)) {
+            filtered += 1;
+            continue;
+        }
+        crate::println!("Test: {} ({}:{})", test.name, test.file, test.line);
+        let before = failure_marks();
+        let panic_result = if test.should_panic { run_should_panic(test) } else { (test.run)(); Ok(()) };
+        let failures = failure_marks() - before;
+        match panic_result {
+            Ok(()) if failures == 0 => {
+                crate::println!("Test: {} ... ok", test.name);
+                passed += 1;
+            }
+            Ok(()) => {
+                crate::println!("Test: {} ... FAILED ({} checks)", test.name, failures);
+                failed += 1;
+            }
+            Err(e) => {
+                crate::println!("Test: {} ... FAILED ({})", test.name, e);
+                failed += 1;
+            }
+        }
+    }
+    
+    if boot_failures != 0 {
+        crate::println!("Test: {} checks failed during boot", boot_failures);
+        failed += 1;
+    }
+    crate::println!("Test: result: {}. {} passed; {} failed; {} filtered out",
+                   if failed == 0 { "ok" } else { "FAILED" }, passed, failed, filtered);
+    
+    if !crate::bootparams::flag("test_exit") {
+        return;
+    }
+    let e = crate::power::test_exit(if failed == 0 { TEST_EXIT_PASS } else { TEST_EXIT_FAIL });
+    crate::println!("Test: Could not power off: {}", e);
+}
+
+// Run a should_panic test on its own thread and wait for it to end
+fn run_should_panic(test: &'static KernelTest) -> Result<(), &'static str> {
+    PANIC_TEST.store(test as *const _ as *mut _, Ordering::SeqCst);
+    PANIC_OUTCOME.store(OUTCOME_RUNNING, Ordering::SeqCst);
+    kthread_spawn(should_panic_thread, "should_panic", KTHREAD_DEFAULT_PRIORITY)?;
+    while PANIC_OUTCOME.load(Ordering::SeqCst) == OUTCOME_RUNNING {
+        yield_now();
+    }
+    // One more switch lets the exited thread be switched out for good
+    yield_now();
+    reap_exited();
+    
+    match PANIC_OUTCOME.load(Ordering::SeqCst) {
+        OUTCOME_PANICKED => Ok(()),
+        _ => Err("returned without panicking"),
+    }
+}
+
+fn should_panic_thread() {
+    let test = unsafe { &*PANIC_TEST.load(Ordering::SeqCst) };
+    PANIC_THREAD.store(current_thread_id(), Ordering::SeqCst);
+    (test.run)();
+    PANIC_THREAD.store(NO_THREAD, Ordering::SeqCst);
+    PANIC_OUTCOME.store(OUTCOME_RETURNED, Ordering::SeqCst);
+}
+
+/// Called by the panic handler first, for panics outside exception
+/// handlers. A panic on the thread of a should_panic test ends that thread
+/// and passes the test; for any other this returns.
+pub fn expected_panic(info: &PanicInfo) {
+    let thread = PANIC_THREAD.load(Ordering::SeqCst);
+    if thread == NO_THREAD || thread != current_thread_id() || crate::interrupts::irq_depth() != 0 {
+        return;
+    }
+    PANIC_THREAD.store(NO_THREAD, Ordering::SeqCst);
+    crate::println!("Test: Panicked as expected: {}", info.message());
+    PANIC_OUTCOME.store(OUTCOME_PANICKED, Ordering::SeqCst);
+    crate::process::exit_current(ExitReason::Faulted, 0)
+}
+
+#[kernel_test]
+fn test_test_filter() {
+    
+    crate::println!("Ktest Test: Testing self-test selection...");
+    
+    let cases = [
+        ("frame_*", "frame_allocation", true),
+        ("frame_*", "kernel_mapping", false),
+        ("ipc_call", "ipc_call", true),
+        ("ipc_call", "ipc_call_timeout", false),
+        ("*mapping", "reverse_mapping", true),
+        ("*_*_*", "test_filter", false),
+        ("kaslr,*stack*", "stack_guard", true),
+        ("", "tmpfs", false),
+    ];
+    let wrong = cases.iter().filter(|&&(pattern, name, expect)| filter_matches(pattern, name) != expect).count();
+    if wrong == 0 {
+        crate::println!("Ktest Test: ✓ test= patterns select by name");
+    } else {
+        crate::println!("Ktest Test: ✗ {} test= pattern cases wrong", wrong);
+    }
+    
+    // Registered from all the test modules, each once, this one included
+    let all = tests();
+    let unique = all.iter().enumerate().all(|(i, a)| all[i + 1..].iter().all(|b| a.name != b.name));
+    let registered = all.iter().any(|test| test.name == "test_filter" && !test.should_panic)
+        && all.iter().any(|test| test.name == "register_index_checked" && test.should_panic)
+        && all.iter().any(|test| test.name == "frame_allocation")
+        && all.iter().any(|test| test.name == "kthread_spawn");
+    if unique && registered {
+        crate::println!("Ktest Test: ✓ {} tests registered, names unique", all.len());
+    } else {
+        crate::println!("Ktest Test: ✗ Test registry (unique {}, registered {})", unique, registered);
+    }
+    
+    crate::println!("Ktest Test: Self-test selection test completed");
+}
@@ -0,0 +1,215 @@
+// Interrupt and wakeup latency harness
+//
+// Programs the EL1 virtual timer, which nothing else uses, for a known
+// counter value and blocks on it, many times over. Each iteration gives
+// two numbers measured from the moment the timer fired (its deadline):
+// how long until its handler ran, and how long until the blocked thread
+// was running again. Both grow with time spent with IRQs masked and in
+// non-preemptible sections, so they are the numbers to watch as locking
+// and preemption change.
+//
+// Runs from `latency=<iterations>` at boot (`make latency`) or the
+// `latency` shell command. Timestamps are all virtual counter values.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use crate::interrupts::{counter_frequency, without_interrupts};
+use crate::process::scheduler::{block_current, current_thread_id, wake, yield_now};
+
+// EL1 virtual timer PPI, when the device tree does not say
+const VTIMER_IRQ_DEFAULT: u32 = 27;
+
+pub const LATENCY_DEFAULT_ITERATIONS: usize = 1000;
+// Bounds the sample buffers
+pub const LATENCY_MAX_ITERATIONS: usize = 100_000;
+
+// Timeout after which a waiter gives up on an interrupt that never came
+const TIMEOUT_MS: u64 = 100;
+
+// CNTV_CTL_EL0: enable; IMASK left clear
+const CNTV_ENABLE: u64 = 1 << 0;
+
+static AVAILABLE: AtomicBool = AtomicBool::new(false);
+// Only one run at a time: the timer and these slots are shared
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static WAITER: AtomicU32 = AtomicU32::new(0);
+// Counter value when the handler ran for the current iteration, 0 if not yet
+static HANDLED_AT: AtomicU64 = AtomicU64::new(0);
+
+/// Latency distribution, in counter ticks.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LatencySummary {
+    pub min: u64,
+    pub average: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl LatencySummary {
+    fn from_samples(samples: &mut [u64]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_unstable();
+        let total: u64 = samples.iter().sum();
+        Self {
+            min: samples[0],
+            average: total / samples.len() as u64,
+            p99: samples[(samples.len() * 99).div_ceil(100) - 1],
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LatencyReport {
+    pub iterations: usize,
+    // Iterations whose interrupt never arrived
+    pub timeouts: usize,
+    /// Timer deadline to handler entry.
+    pub irq: LatencySummary,
+    /// Timer deadline to the blocked thread running again.
+    pub wakeup: LatencySummary,
+}
+
+fn virtual_counter() -> u64 {
+    let count: u64;
+    unsafe {
+        asm!("isb", "mrs {}, cntvct_el0", out(reg) count);
+    }
+    count
+}
+
+fn arm_timer(deadline: u64) {
+    unsafe {
+        asm!("msr cntv_cval_el0, {}", in(reg) deadline);
+        asm!("msr cntv_ctl_el0, {}", "isb", in(reg) CNTV_ENABLE);
+    }
+}
+
+fn disarm_timer() {
+    unsafe {
+        asm!("msr cntv_ctl_el0, xzr", "isb");
+    }
+}
+
+fn vtimer_handler(_irq: u32) {
+    let now = virtual_counter();
+    // Level triggered: stays asserted until disabled
+    disarm_timer();
+    HANDLED_AT.store(now, Ordering::SeqCst);
+    if RUNNING.load(Ordering::SeqCst) {
+        wake(WAITER.load(Ordering::SeqCst));
+    }
+}
+
+// Wakes a waiter whose interrupt was lost
+fn timeout_wake(thread: usize) {
+    wake(thread as u32);
+}
+
+/// Claim the virtual timer interrupt.
+pub fn init() {
+    let dt = crate::devicetree::device_tree();
+    if !crate::gic::is_present() {
+        crate::println!("Latency: No GIC, harness unavailable");
+        return;
+    }
+    // interrupts = <secure phys>, <non-secure phys>, <virt>, <hyp>
+    let irq = dt.as_ref()
+        .and_then(|dt| dt.find_compatible("arm,armv8-timer").next())
+        .and_then(|node| crate::gic::dt_interrupt(&node, 2))
+        .unwrap_or(VTIMER_IRQ_DEFAULT);
+    disarm_timer();
+    if let Err(e) = crate::gic::register_handler(irq, vtimer_handler) {
+        crate::println!("Latency: Virtual timer IRQ {}: {}", irq, e);
+        return;
+    }
+    AVAILABLE.store(true, Ordering::Release);
+}
+
+/// Run the measurement asked for by `latency=<iterations>`, if any.
+/// Call once threads can block.
+pub fn run_boot_measurement() {
+    let Some(arg) = crate::bootparams::get("latency") else {
+        return;
+    };
+    let iterations = arg.parse().unwrap_or(LATENCY_DEFAULT_ITERATIONS);
+    match run(iterations) {
+        Ok(report) => print_report(&report),
+        Err(e) => crate::println!("Latency: {}", e),
+    }
+}
+
+/// Measure `iterations` timer interrupts and wakeups. Blocks the caller
+/// for a little over `iterations` milliseconds.
+pub fn run(iterations: usize) -> Result<LatencyReport, &'static str> {
+    if !AVAILABLE.load(Ordering::Acquire) {
+        return Err("Virtual timer interrupt not available");
+    }
+    if iterations == 0 || iterations > LATENCY_MAX_ITERATIONS {
+        return Err("Iterations out of range");
+    }
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err("Latency measurement already running");
+    }
+    
+    let me = current_thread_id();
+    WAITER.store(me, Ordering::SeqCst);
+    let frequency = counter_frequency();
+    let mut irq = Vec::with_capacity(iterations);
+    let mut wakeup = Vec::with_capacity(iterations);
+    let mut timeouts = 0;
+    
+    for i in 0..iterations {
+        // 100us to 1ms, spread so deadlines do not lock onto the tick
+        let delay_us = 100 + (i as u64 * 7919) % 900;
+        let delay = frequency * delay_us / 1_000_000;
+        let fallback = crate::timer::after_ms(TIMEOUT_MS, timeout_wake, me as usize);
+        
+        // Arm and block with IRQs masked so the wakeup cannot come first
+        let deadline = without_interrupts(|| {
+            HANDLED_AT.store(0, Ordering::SeqCst);
+            let deadline = virtual_counter() + delay;
+            arm_timer(deadline);
+            block_current();
+            deadline
+        });
+        yield_now();
+        let running = virtual_counter();
+        
+        if let Ok(fallback) = fallback {
+            crate::timer::cancel(fallback);
+        }
+        match HANDLED_AT.load(Ordering::SeqCst) {
+            0 => {
+                disarm_timer();
+                timeouts += 1;
+            }
+            handled => {
+                irq.push(handled.saturating_sub(deadline));
+                wakeup.push(running.saturating_sub(deadline));
+            }
+        }
+    }
+    
+    RUNNING.store(false, Ordering::SeqCst);
+    Ok(LatencyReport {
+        iterations,
+        timeouts,
+        irq: LatencySummary::from_samples(&mut irq),
+        wakeup: LatencySummary::from_samples(&mut wakeup),
+    })
+}
+
+pub fn print_report(report: &LatencyReport) {
+    let frequency = counter_frequency().max(1);
+    let ns = |ticks: u64| ticks * 1_000_000_000 / frequency;
+    crate::println!("Latency: {} iterations, {} timeouts", report.iterations, report.timeouts);
+    crate::println!("Latency: {:<8} {:>10} {:>10} {:>10} {:>10}", "", "MIN ns", "AVG ns", "P99 ns", "MAX ns");
+    for (name, summary) in [("irq", &report.irq), ("wakeup", &report.wakeup)] {
+        crate::println!("Latency: {:<8} {:>10} {:>10} {:>10} {:>10}", name,
+                       ns(summary.min), ns(summary.average), ns(summary.p99), ns(summary.max));
+    }
+}
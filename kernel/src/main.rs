@@ -5,58 +5,169 @@ extern crate alloc;
 
 mod memory;
 mod interrupts;
+mod gic;
+mod irqbalance;
+mod sync;
+mod mpsc;
 mod process;
+mod timer;
+mod time;
+mod executor;
 mod ipc;
+mod shm;
+mod audit;
+mod trace;
+mod sysctl;
+mod manifest;
+mod tracepoint;
+mod profile;
+mod pmu;
+mod syscall;
+mod sysinfo;
+mod uring;
+mod uaccess;
+mod board;
 mod uart;
+mod console;
+mod shell;
 mod devicetree;
+mod bootparams;
+mod dtoverlay;
+mod acpi;
 mod allocator;
 mod interrupt_test;
+mod devfs;
+mod procfs;
+mod klog;
+mod pstore;
+mod initramfs;
+mod block;
+mod net;
+mod netconsole;
+mod firmware;
+mod power;
+mod ipi;
+mod cpu;
+mod rcu;
+mod softirq;
+mod panic;
+mod backtrace;
+mod symbols;
+#[cfg(feature = "eh-unwind")]
+mod unwind;
+mod gdbstub;
+mod kdebug;
+mod latency;
+mod oops;
+mod crashdump;
+mod ktest;
+mod semihosting;
+mod watchdog;
+mod ssp;
+mod rand;
+mod vfs;
+mod fat32;
+mod tmpfs;
+mod filexfer;
+mod drivers;
 
-use core::panic::PanicInfo;
 use core::arch::global_asm;
-use devicetree::parse_device_tree;
+use devicetree::device_tree;
 
 // Include the boot assembly and exception vectors
-global_asm!(include_str!("boot.s"));
-global_asm!(include_str!("exceptions.s"));
+global_asm!(
+    include_str!("boot.s"),
+    enable_mmu = const !cfg!(feature = "no-mmu") as u8,
+    mair = const memory::mmu::MAIR_VALUE,
+    tcr = const memory::mmu::TCR_VALUE,
+    sctlr_set = const memory::mmu::SCTLR_SET,
+    l1_block0 = const memory::mmu::BOOT_L1_BLOCKS[0],
+    l1_block1 = const memory::mmu::BOOT_L1_BLOCKS[1],
+    l1_block2 = const memory::mmu::BOOT_L1_BLOCKS[2],
+    l1_block3 = const memory::mmu::BOOT_L1_BLOCKS[3],
+);
+global_asm!(
+    include_str!("exceptions.s"),
+    kernel_stack_area_bit = const process::thread::KERNEL_STACK_AREA_BIT,
+    kernel_stack_shift = const process::thread::KERNEL_STACK_SHIFT,
+);
 
 /// Main Rust entry point called from boot.s
 #[no_mangle]
-pub extern "C" fn rust_main() -> ! {
+pub extern "C" fn rust_main(boot_arg: u64) -> ! {
     // Initialize UART for early console output
     uart::init_uart();
+    acpi::set_boot_arg(boot_arg);
+    devicetree::set_boot_arg(boot_arg);
+    // Before any function that will return is entered (see ssp.rs)
+    ssp::init();
     
     println!("RustKernel v0.1.0 - ARM64 Microkernel");
     println!("Boot: CPU primary core active");
     
-    // Parse device tree (passed by bootloader in x0, but for QEMU we'll use known address)
-    let fdt_addr = 0x40000000 as *const u8; // QEMU default FDT location
-    if let Some(dt) = parse_device_tree(fdt_addr) {
+    // Parse device tree (passed by the loader in x0, else at the board's
+    // usual address)
+    if let Some(dt) = device_tree() {
         println!("Boot: Device tree parsed successfully");
-        for region in dt.memory_regions() {
-            if let Some(mem) = region {
-                println!("Boot: Memory region: 0x{:016x} - 0x{:016x} ({} MB)", 
-                    mem.start, mem.start + mem.size, mem.size / (1024 * 1024));
-            }
+        for mem in dt.memory_regions().iter().flatten() {
+            println!("Boot: Memory region: 0x{:016x} - 0x{:016x} ({} MB)", 
+                mem.start, mem.start + mem.size, mem.size / (1024 * 1024));
         }
     } else {
-        println!("Boot: Warning - Could not parse device tree, using defaults");
+        println!("Boot: Warning - Could not parse device tree, trying ACPI then defaults");
     }
+    board::init();
+    // The console may live elsewhere than the board's early UART
+    uart::probe();
     
     println!("Boot: Initializing kernel subsystems...");
     
-    // Initialize heap allocator
-    allocator::init_heap();
-    println!("Boot: Heap allocator initialized");
-    
-    // Initialize core kernel subsystems
+    // Initialize core kernel subsystems (memory brings up the heap)
     memory::init();
+    uart::late_init();
+    bootparams::init();
+    klog::init();
+    rand::init();
+    uaccess::init();
+    acpi::init();
+    dtoverlay::init();
     interrupts::init();
+    ipi::init();
+    rcu::init();
+    oops::init();
+    symbols::init();
+    #[cfg(feature = "eh-unwind")]
+    unwind::init();
+    crashdump::init();
+    time::init();
+    firmware::init();
+    power::init();
+    profile::init();
+    pmu::init();
+    tracepoint::init();
+    sysctl::init();
     ipc::init();
+    shm::init();
+    manifest::init();
     process::init();
+    softirq::init();
+    sysinfo::init();
+    watchdog::init();
+    drivers::init();
+    netconsole::init();
+    vfs::init();
+    devfs::init();
+    console::init();
+    gdbstub::init();
+    kdebug::init();
+    latency::init();
+    
+    // Boot self-tests (#[kernel_test]), all or those test= names; with
+    // test_exit the boot ends here
+    ktest::run();
     
-    // Run interrupt system tests
-    interrupt_test::test_interrupt_system();
+    // Interrupt and wakeup latency, when asked for (`make latency`)
+    latency::run_boot_measurement();
     
     println!("Boot: Kernel initialization complete");
     println!("Boot: Starting userspace services...");
@@ -64,6 +175,9 @@ pub extern "C" fn rust_main() -> ! {
     // Start core userspace services
     start_userspace();
     
+    // Debug shell on the serial console
+    shell::start();
+    
     println!("Boot: Entering kernel idle loop");
     
     // Enter idle loop - kernel should only handle interrupts now
@@ -77,23 +191,6 @@ fn start_userspace() {
 }
 
 fn kernel_idle() -> ! {
-    loop {
-        // Wait for interrupts
-        // TODO: Implement proper ARM64 WFI (Wait For Interrupt)
-        core::hint::spin_loop();
-    }
-}
-
-
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    println!("KERNEL PANIC!");
-    println!("Message: {}", info.message());
-    if let Some(location) = info.location() {
-        println!("Location: {}:{}", location.file(), location.line());
-    }
-    
-    loop {
-        core::hint::spin_loop();
-    }
+    // The boot thread becomes the idle task: wfi with the tick stopped
+    process::idle::run()
 }
@@ -0,0 +1,247 @@
+// Boot capability manifest
+//
+// The initial capability graph, which services get which ports, interrupt
+// lines, MMIO windows and settings, is data rather than code: a text
+// manifest read at boot from /etc/capabilities in the initramfs, or the
+// copy built into the kernel (capabilities.manifest) when there is none.
+// Ports it names are created up front; a thread whose name matches a
+// service block is granted that block's capabilities when it starts, each
+// grant audited as usual. /proc/manifest shows what was applied and the
+// port IDs the names resolved to.
+//
+// A manifest with any error is rejected whole, so a typo never leaves the
+// system half wired.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::Mutex;
+use crate::ipc::PortId;
+use crate::process::capability::{self, Capability};
+use crate::process::scheduler::current_thread_id;
+use crate::process::thread::THREAD_NAME_LEN;
+use crate::process::ThreadId;
+
+/// Where the initramfs may carry a manifest of its own.
+pub const MANIFEST_PATH: &str = "etc/capabilities";
+
+/// The manifest built into the kernel.
+pub const EMBEDDED: &str = include_str!("capabilities.manifest");
+
+// Longest port name
+const PORT_NAME_MAX: usize = 32;
+const PAGE_MASK: u64 = crate::memory::frame_allocator::PAGE_SIZE as u64 - 1;
+
+/// A capability as the manifest states it; ports by name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Grant {
+    Port(String),
+    Irq(u32),
+    Mmio { base: u64, size: u64 },
+    Sysctl,
+    Device,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service {
+    pub name: String,
+    pub grants: Vec<Grant>,
+}
+
+#[derive(Clone, Debug)]
+pub struct NamedPort {
+    pub name: String,
+    /// 0 until `create_ports`
+    pub id: PortId,
+}
+
+/// Where parsing stopped: 1-based line and what was wrong there.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ManifestError {
+    pub line: usize,
+    pub error: &'static str,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    pub services: Vec<Service>,
+    pub ports: Vec<NamedPort>,
+}
+
+struct Active {
+    source: &'static str,
+    manifest: Manifest,
+}
+
+static ACTIVE: Mutex<Option<Active>> = Mutex::new(None);
+
+/// Parse manifest text.
+pub fn parse(text: &str) -> Result<Manifest, ManifestError> {
+    let mut manifest = Manifest::default();
+    for (index, line) in text.lines().enumerate() {
+        let fail = |error| ManifestError { line: index + 1, error };
+        let line = line.split('#').next().unwrap_or("");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let grant = match words.as_slice() {
+            [] => continue,
+            ["service", name] => {
+                if name.len() > THREAD_NAME_LEN || name.contains('/') {
+                    return Err(fail("Bad service name"));
+                }
+                if manifest.services.iter().any(|service| service.name == *name) {
+                    return Err(fail("Service listed twice"));
+                }
+                manifest.services.push(Service { name: String::from(*name), grants: Vec::new() });
+                continue;
+            }
+            ["port", name] => {
+                if name.len() > PORT_NAME_MAX {
+                    return Err(fail("Port name too long"));
+                }
+                if !manifest.ports.iter().any(|port| port.name == *name) {
+                    manifest.ports.push(NamedPort { name: String::from(*name), id: 0 });
+                }
+                Grant::Port(String::from(*name))
+            }
+            ["irq", line] => {
+                let line = number(line).and_then(|n| u32::try_from(n).ok()).ok_or(fail("Bad interrupt line"))?;
+                Grant::Irq(line)
+            }
+            ["mmio", base, size] => {
+                let (Some(base), Some(size)) = (number(base), number(size)) else {
+                    return Err(fail("Bad MMIO window"));
+                };
+                if size == 0 || (base | size) & PAGE_MASK != 0 || base.checked_add(size).is_none() {
+                    return Err(fail("MMIO window not page aligned"));
+                }
+                Grant::Mmio { base, size }
+            }
+            ["sysctl"] => Grant::Sysctl,
+            ["device"] => Grant::Device,
+            ["service", ..] | ["port", ..] | ["irq", ..] | ["mmio", ..] | ["sysctl", ..] | ["device", ..] => {
+                return Err(fail("Wrong number of arguments"));
+            }
+            _ => return Err(fail("Unknown directive")),
+        };
+        let service = manifest.services.last_mut().ok_or(fail("Capability outside a service"))?;
+        if !service.grants.contains(&grant) {
+            service.grants.push(grant);
+        }
+    }
+    Ok(manifest)
+}
+
+// Decimal, or hex with 0x
+fn number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+impl Manifest {
+    /// Create the ports the manifest names, owned by the calling thread.
+    pub fn create_ports(&mut self) {
+        let owner = current_thread_id();
+        for port in self.ports.iter_mut().filter(|port| port.id == 0) {
+            port.id = crate::ipc::create_port(owner);
+        }
+    }
+    
+    /// ID a port name resolved to.
+    pub fn port(&self, name: &str) -> Option<PortId> {
+        self.ports.iter().find(|port| port.name == name && port.id != 0).map(|port| port.id)
+    }
+    
+    /// Capabilities for a thread named `service`; None if it is not listed.
+    pub fn capabilities(&self, service: &str) -> Option<Vec<Capability>> {
+        let service = self.services.iter().find(|listed| listed.name == service)?;
+        Some(service.grants.iter().filter_map(|grant| match grant {
+            Grant::Port(name) => self.port(name).map(Capability::Port),
+            Grant::Irq(line) => Some(Capability::Irq(*line)),
+            Grant::Mmio { base, size } => Some(Capability::Mmio { base: *base, size: *size }),
+            Grant::Sysctl => Some(Capability::Sysctl),
+            Grant::Device => Some(Capability::Device),
+        }).collect())
+    }
+    
+    /// Grant thread `tid`, named `name`, what the manifest lists for it.
+    /// Returns how many capabilities it was given.
+    pub fn apply(&self, tid: ThreadId, name: &str) -> Result<usize, &'static str> {
+        let Some(capabilities) = self.capabilities(name) else { return Ok(0) };
+        let granter = current_thread_id();
+        for &capability in &capabilities {
+            capability::grant(granter, tid, capability)?;
+        }
+        Ok(capabilities.len())
+    }
+}
+
+/// Read, check and activate the manifest. Needs the heap, the initramfs
+/// and IPC.
+pub fn init() {
+    let (source, text) = match crate::initramfs::lookup(MANIFEST_PATH) {
+        Some(bytes) => match core::str::from_utf8(bytes) {
+            Ok(text) => ("initramfs", text),
+            Err(_) => {
+                crate::println!("Manifest: /{} is not UTF-8, nothing granted", MANIFEST_PATH);
+                return;
+            }
+        },
+        None => ("embedded", EMBEDDED),
+    };
+    let mut manifest = match parse(text) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            crate::println!("Manifest: {} manifest line {}: {}; nothing granted", source, e.line, e.error);
+            return;
+        }
+    };
+    manifest.create_ports();
+    crate::println!("Manifest: {} manifest, {} services, {} ports",
+                   source, manifest.services.len(), manifest.ports.len());
+    *ACTIVE.lock() = Some(Active { source, manifest });
+    let _ = crate::procfs::register("manifest", proc_manifest);
+}
+
+/// A thread has started: wire it up if the manifest lists it.
+pub fn service_started(tid: ThreadId, name: &str) {
+    let active = ACTIVE.lock();
+    let Some(active) = active.as_ref() else { return };
+    match active.manifest.apply(tid, name) {
+        Ok(0) => {}
+        Ok(granted) => crate::println!("Manifest: Granted {} capabilities to {} '{}'", granted, tid, name),
+        Err(e) => crate::println!("Manifest: Wiring {} '{}' failed: {}", tid, name, e),
+    }
+}
+
+/// Name the active manifest gave port `id`, if any.
+pub fn port_name(id: PortId) -> Option<String> {
+    let active = ACTIVE.lock();
+    let port = active.as_ref()?.manifest.ports.iter().find(|port| port.id == id)?;
+    Some(port.name.clone())
+}
+
+fn proc_manifest(out: &mut Vec<u8>) {
+    let mut text = String::new();
+    if let Some(active) = ACTIVE.lock().as_ref() {
+        let _ = writeln!(text, "source {}", active.source);
+        for port in &active.manifest.ports {
+            let _ = writeln!(text, "port {} {}", port.name, port.id);
+        }
+        for service in &active.manifest.services {
+            let _ = write!(text, "service {}", service.name);
+            for grant in &service.grants {
+                let _ = match grant {
+                    Grant::Port(name) => write!(text, " port:{}", name),
+                    Grant::Irq(line) => write!(text, " irq:{}", line),
+                    Grant::Mmio { base, size } => write!(text, " mmio:0x{:x}+0x{:x}", base, size),
+                    Grant::Sysctl => write!(text, " sysctl"),
+                    Grant::Device => write!(text, " device"),
+                };
+            }
+            text.push('\n');
+        }
+    }
+    out.extend_from_slice(text.as_bytes());
+}
@@ -1,11 +0,0 @@
-// Physical memory allocator for ARM64
-
-pub struct PhysicalAllocator {
-    // TODO: Implement bitmap or buddy allocator
-}
-
-impl PhysicalAllocator {
-    pub fn new() -> Self {
-        Self {}
-    }
-}
\ No newline at end of file
@@ -0,0 +1,290 @@
+// ASID allocation
+//
+// A user address space is named by a space id for its whole life (the
+// reverse map records it), but runs under a hardware ASID it only holds
+// for one generation. The first switch to a space in a generation takes
+// an ASID from the bitmap; later switches find it still current and just
+// load TTBR0, with no TLB maintenance. When every ASID (8 or 16 bits,
+// whatever the CPU implements) has been handed out, a new generation
+// starts: the bitmap is cleared, the ASID each CPU is running stays
+// reserved for its owner, and every CPU flushes its TLB before its next
+// slow-path switch, so old-generation ASIDs can be reused. This is the
+// scheme arm64 Linux uses.
+//
+// Kernel threads have no address space. Switching to one leaves TTBR0
+// alone (the kernel runs from TTBR1), so it borrows whatever user tables
+// were loaded and switching back to that process costs nothing. A space
+// being torn down is unloaded from this CPU first.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::cpu::{cpu_index, MAX_CPUS};
+use crate::memory::paging::VirtualMemoryManager;
+use crate::memory::tlb::{self, Asid};
+use crate::sync::IrqSafeMutex;
+
+/// Names a user address space for as long as it exists; never 0.
+pub type SpaceId = u16;
+
+/// User address spaces that can exist at once (space ids 1..4095).
+pub const MAX_SPACES: usize = 4096;
+
+// A context is generation << GENERATION_SHIFT | ASID; 0 before first use
+const GENERATION_SHIFT: u64 = 16;
+const ASID_MASK: u64 = (1 << GENERATION_SHIFT) - 1;
+
+// ID_AA64MMFR0_EL1.ASIDBits, and TCR_EL1.AS to use all 16
+const MMFR0_ASID_BITS_SHIFT: u64 = 4;
+const MMFR0_ASID_BITS_16: u64 = 0b0010;
+const TCR_AS: u64 = 1 << 36;
+
+// TTBR_EL1.ASID
+const TTBR_ASID_SHIFT: u64 = 48;
+
+static ASID_BITS: AtomicU32 = AtomicU32::new(8);
+// Starts at 1 so that a zero context is never current
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+// Context of each space
+static CONTEXTS: [AtomicU64; MAX_SPACES] = [const { AtomicU64::new(0) }; MAX_SPACES];
+// Context each CPU runs; 0 sends its next switch down the slow path
+static ACTIVE: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+// Space loaded in each CPU's TTBR0; 0 for the empty tables
+static LOADED: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+struct Allocator {
+    spaces: [u64; MAX_SPACES / 64],
+    // ASIDs taken in the current generation; 0 is the empty tables'
+    asids: [u64; (1 << GENERATION_SHIFT) / 64],
+    next_asid: usize,
+    // Context each CPU was running at the last rollover
+    reserved: [u64; MAX_CPUS],
+    flush_pending: [bool; MAX_CPUS],
+    rollovers: u64,
+}
+
+static ALLOCATOR: IrqSafeMutex<Allocator> = IrqSafeMutex::new(Allocator {
+    spaces: [0; MAX_SPACES / 64],
+    asids: [0; (1 << GENERATION_SHIFT) / 64],
+    next_asid: 1,
+    reserved: [0; MAX_CPUS],
+    flush_pending: [false; MAX_CPUS],
+    rollovers: 0,
+});
+
+#[derive(Copy, Clone, Debug)]
+pub struct AsidStats {
+    pub bits: u32,
+    pub generation: u64,
+    pub rollovers: u64,
+    pub spaces: usize,
+    pub asids: usize,
+}
+
+fn test_and_set(bitmap: &mut [u64], bit: usize) -> bool {
+    let was_set = bitmap[bit / 64] & (1 << (bit % 64)) != 0;
+    bitmap[bit / 64] |= 1 << (bit % 64);
+    was_set
+}
+
+fn clear(bitmap: &mut [u64], bit: usize) {
+    bitmap[bit / 64] &= !(1 << (bit % 64));
+}
+
+// First clear bit in [from, limit)
+fn find_clear(bitmap: &[u64], from: usize, limit: usize) -> Option<usize> {
+    (from..limit).find(|&bit| bitmap[bit / 64] & (1 << (bit % 64)) == 0)
+}
+
+impl Allocator {
+    fn asid_limit() -> usize {
+        1 << ASID_BITS.load(Ordering::Relaxed)
+    }
+    
+    fn allocate_asid(&mut self) -> Option<usize> {
+        let limit = Self::asid_limit();
+        let asid = find_clear(&self.asids, self.next_asid, limit)
+            .or_else(|| find_clear(&self.asids, 1, self.next_asid))?;
+        test_and_set(&mut self.asids, asid);
+        self.next_asid = asid + 1;
+        Some(asid)
+    }
+    
+    // Give `context` (of an earlier generation, or 0) one in the current
+    // generation, keeping its ASID where possible
+    fn new_context(&mut self, context: u64) -> u64 {
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if context != 0 {
+            let asid = context & ASID_MASK;
+            let current = generation << GENERATION_SHIFT | asid;
+            // Running somewhere at the rollover: the ASID was kept for it
+            let mut reserved = false;
+            for slot in self.reserved.iter_mut().filter(|slot| **slot == context) {
+                *slot = current;
+                reserved = true;
+            }
+            if reserved || !test_and_set(&mut self.asids, asid as usize) {
+                return current;
+            }
+        }
+        let asid = match self.allocate_asid() {
+            Some(asid) => asid,
+            None => {
+                self.rollover();
+                self.allocate_asid().expect("No ASID free after rollover")
+            }
+        };
+        GENERATION.load(Ordering::Relaxed) << GENERATION_SHIFT | asid as u64
+    }
+    
+    fn rollover(&mut self) {
+        GENERATION.fetch_add(1, Ordering::Relaxed);
+        self.asids.fill(0);
+        self.asids[0] = 1;
+        self.next_asid = 1;
+        for (active, reserved) in ACTIVE.iter().zip(self.reserved.iter_mut()) {
+            // A CPU that has not switched since the last rollover still
+            // runs what it was reserved then
+            let mut context = active.swap(0, Ordering::Relaxed);
+            if context == 0 {
+                context = *reserved;
+            }
+            if context != 0 {
+                test_and_set(&mut self.asids, (context & ASID_MASK) as usize);
+            }
+            *reserved = context;
+        }
+        self.flush_pending = [true; MAX_CPUS];
+        self.rollovers += 1;
+    }
+}
+
+/// Use 16-bit ASIDs if the CPU has them, and export /proc/asid.
+pub fn init() {
+    unsafe {
+        let mmfr0: u64;
+        asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0);
+        if (mmfr0 >> MMFR0_ASID_BITS_SHIFT) & 0xF == MMFR0_ASID_BITS_16 {
+            let tcr: u64;
+            asm!("mrs {}, tcr_el1", out(reg) tcr);
+            asm!("msr tcr_el1, {}", in(reg) tcr | TCR_AS);
+            asm!("isb");
+            // Entries cached under the 8-bit interpretation are stale
+            tlb::flush_all_local();
+            ASID_BITS.store(16, Ordering::Relaxed);
+        }
+    }
+    
+    ALLOCATOR.lock().asids[0] = 1;
+    let _ = crate::procfs::register("asid", proc_asid);
+    crate::println!("Memory: {}-bit ASIDs", ASID_BITS.load(Ordering::Relaxed));
+}
+
+/// Id for a new address space.
+pub fn allocate_space() -> Option<SpaceId> {
+    let mut allocator = ALLOCATOR.lock();
+    let id = find_clear(&allocator.spaces, 1, MAX_SPACES)?;
+    test_and_set(&mut allocator.spaces, id);
+    CONTEXTS[id].store(0, Ordering::Relaxed);
+    Some(id as SpaceId)
+}
+
+/// The space's tables are about to be freed: stop using them on this CPU.
+pub fn unload(space: SpaceId) {
+    if LOADED[cpu_index()].load(Ordering::Relaxed) == space as u32 {
+        switch_to_empty();
+    }
+}
+
+/// Make a destroyed space's id available again. Its ASID stays taken
+/// until the next rollover, so the flush done while destroying it is the
+/// last one that ASID needs.
+pub fn free_space(space: SpaceId) {
+    clear(&mut ALLOCATOR.lock().spaces, space as usize);
+}
+
+/// ASID the space last ran under, if it has run at all. Translations
+/// cached for it can only be tagged with this one: any older ASID was
+/// flushed everywhere by the rollover that retired it.
+pub fn hardware_asid(space: SpaceId) -> Option<Asid> {
+    match CONTEXTS[space as usize].load(Ordering::Relaxed) {
+        0 => None,
+        context => Some((context & ASID_MASK) as Asid),
+    }
+}
+
+/// Load a user address space into this CPU's TTBR0.
+pub fn switch_to(vmm: &VirtualMemoryManager) {
+    let Some(space) = vmm.space_id() else {
+        return switch_to_empty();
+    };
+    let cpu = cpu_index();
+    let context = &CONTEXTS[space as usize];
+    let mut current = context.load(Ordering::Relaxed);
+    let active = ACTIVE[cpu].load(Ordering::Relaxed);
+    
+    // Fast path: the ASID is from this generation and no rollover has
+    // cleared ACTIVE since this CPU last switched
+    let fresh = current >> GENERATION_SHIFT == GENERATION.load(Ordering::Relaxed);
+    if !fresh || active == 0
+        || ACTIVE[cpu].compare_exchange(active, current, Ordering::Relaxed, Ordering::Relaxed).is_err()
+    {
+        let mut allocator = ALLOCATOR.lock();
+        current = context.load(Ordering::Relaxed);
+        if current >> GENERATION_SHIFT != GENERATION.load(Ordering::Relaxed) {
+            current = allocator.new_context(current);
+            context.store(current, Ordering::Relaxed);
+        }
+        if core::mem::take(&mut allocator.flush_pending[cpu]) {
+            tlb::flush_all_local();
+        }
+        ACTIVE[cpu].store(current, Ordering::Relaxed);
+    }
+    
+    load_ttbr0(vmm.root_table_addr() | (current & ASID_MASK) << TTBR_ASID_SHIFT);
+    LOADED[cpu].store(space as u32, Ordering::Relaxed);
+}
+
+/// Load the kernel's empty user tables into TTBR0.
+pub fn switch_to_empty() {
+    let root = crate::memory::mmu::MemoryManagementUnit::user_vmm().map_or(0, |vmm| vmm.root_table_addr());
+    load_ttbr0(root);
+    LOADED[cpu_index()].store(0, Ordering::Relaxed);
+}
+
+fn load_ttbr0(ttbr: u64) {
+    unsafe {
+        // Table updates must be visible to the walker first
+        asm!("dsb ish");
+        asm!("msr ttbr0_el1, {}", in(reg) ttbr);
+        asm!("isb");
+    }
+}
+
+/// Start a new generation now rather than when the ASIDs run out.
+pub fn rollover() {
+    ALLOCATOR.lock().rollover();
+}
+
+pub fn stats() -> AsidStats {
+    let allocator = ALLOCATOR.lock();
+    let count = |bitmap: &[u64]| bitmap.iter().map(|word| word.count_ones() as usize).sum::<usize>();
+    AsidStats {
+        bits: ASID_BITS.load(Ordering::Relaxed),
+        generation: GENERATION.load(Ordering::Relaxed),
+        rollovers: allocator.rollovers,
+        spaces: count(&allocator.spaces),
+        // Not counting the empty tables' ASID 0
+        asids: count(&allocator.asids).saturating_sub(1),
+    }
+}
+
+fn proc_asid(out: &mut Vec<u8>) {
+    let stats = stats();
+    let mut text = alloc::string::String::new();
+    let _ = writeln!(text, "bits {}\ngeneration {}\nrollovers {}", stats.bits, stats.generation, stats.rollovers);
+    let _ = writeln!(text, "spaces {}\nasids {}", stats.spaces, stats.asids);
+    out.extend_from_slice(text.as_bytes());
+}
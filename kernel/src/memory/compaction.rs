@@ -0,0 +1,123 @@
+// Memory compaction and page migration
+//
+// A frame is movable when every reference to it comes from a mapping in
+// the reverse map (refcount == map count): nobody holds a raw pointer to
+// it, so its contents can be copied elsewhere and the page tables pointed
+// at the copy. Kernel stacks, page tables and DMA buffers are unmovable.
+//
+// Large contiguous requests pick the window that needs the fewest
+// migrations, claim its free frames first so migration targets land
+// outside it, then move the remaining frames out.
+
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use crate::memory::frame_allocator::{allocate_frame, claim_frame, deallocate_frame,
+                                     find_window, frame_get, frame_put,
+                                     frame_refcount, FrameRefCount, PAGE_SIZE};
+use crate::memory::paging::{virt_to_phys, PageTableEntry, VirtualMemoryManager};
+use crate::memory::rmap;
+
+fn is_movable(frame: NonNull<u8>, refcount: FrameRefCount) -> bool {
+    let phys = virt_to_phys(frame.as_ptr() as u64);
+    refcount as usize == rmap::map_count(phys)
+}
+
+/// Move a mapped frame's contents to a new frame and retarget every
+/// mapping at it. The old frame is freed. Returns the new frame.
+pub fn migrate_frame(old: NonNull<u8>) -> Result<NonNull<u8>, &'static str> {
+    let old_phys = virt_to_phys(old.as_ptr() as u64);
+    let mappings = rmap::mappings(old_phys);
+    if mappings.is_empty() || !is_movable(old, frame_refcount(old)) {
+        return Err("Frame is not movable");
+    }
+    let new = allocate_frame().ok_or("Out of memory for migration")?;
+    let new_phys = virt_to_phys(new.as_ptr() as u64);
+    
+    // Break: no user may write the old frame while it is copied
+    let mut entries = Vec::with_capacity(mappings.len());
+    for mapping in &mappings {
+        let mut space = unsafe { VirtualMemoryManager::from_root(mapping.root) };
+        let entry = space.leaf_entry(mapping.virt).ok_or("Reverse map entry without mapping")?;
+        let saved = *entry;
+        *entry = PageTableEntry::empty();
+        space.flush_page(mapping.virt);
+        entries.push((entry, saved));
+    }
+    
+    unsafe { core::ptr::copy_nonoverlapping(old.as_ptr(), new.as_ptr(), PAGE_SIZE) };
+    
+    // Make: every mapping now points at the copy
+    for (entry, saved) in entries {
+        *entry = saved.with_addr(new_phys);
+    }
+    rmap::move_mappings(old_phys, new_phys);
+    
+    // The new frame holds one reference per mapping, like the old one did
+    for _ in 1..mappings.len() {
+        frame_get(new)?;
+    }
+    for _ in 0..mappings.len() {
+        frame_put(old)?;
+    }
+    Ok(new)
+}
+
+/// Empty the `count` frames starting at `first` and hand them to the caller.
+///
+/// Free frames are claimed, movable ones migrated away. Fails without
+/// side effects on the window if any frame cannot be freed.
+pub fn compact_range(first: NonNull<u8>, count: usize) -> Result<(), &'static str> {
+    let frame_at = |i: usize| unsafe { NonNull::new_unchecked(first.as_ptr().add(i * PAGE_SIZE)) };
+    let mut claimed = [false; 512];
+    if count > claimed.len() {
+        return Err("Compaction window too large");
+    }
+    
+    // Claim free frames first so migration targets land outside the window
+    for (i, claimed) in claimed.iter_mut().enumerate().take(count) {
+        *claimed = claim_frame(frame_at(i));
+    }
+    
+    let mut result = Ok(());
+    for (i, claimed) in claimed.iter_mut().enumerate().take(count) {
+        if *claimed {
+            continue;
+        }
+        let frame = frame_at(i);
+        if let Err(e) = migrate_frame(frame) {
+            result = Err(e);
+            break;
+        }
+        if !claim_frame(frame) {
+            result = Err("Frame taken during compaction");
+            break;
+        }
+        *claimed = true;
+    }
+    
+    if result.is_err() {
+        for (i, &claimed) in claimed.iter().enumerate().take(count) {
+            if claimed {
+                deallocate_frame(frame_at(i));
+            }
+        }
+    }
+    result
+}
+
+/// Allocate `count` contiguous frames aligned to `align` frames, migrating
+/// movable pages out of the way if memory is fragmented. Free with
+/// `deallocate_frames`.
+pub fn allocate_contiguous(count: usize, align: usize) -> Option<NonNull<u8>> {
+    // A few attempts: another allocation may race into the chosen window
+    for _ in 0..3 {
+        let (first, used) = find_window(count, align, is_movable)?;
+        if used > 0 {
+            crate::println!("Compaction: Migrating {} frames for a {}-frame allocation", used, count);
+        }
+        if compact_range(first, count).is_ok() {
+            return Some(first);
+        }
+    }
+    None
+}
@@ -3,6 +3,8 @@
 use core::ptr::NonNull;
 use spin::Mutex;
 use crate::devicetree::MemoryRegion;
+use crate::memory::paging::{phys_to_virt, virt_to_phys};
+use crate::tracepoint::{self, Tracepoint};
 
 // 4KB page size for ARM64
 pub const PAGE_SIZE: usize = 4096;
@@ -21,9 +23,22 @@ pub fn frame_to_addr(frame: FrameNumber) -> u64 {
     (frame << PAGE_SHIFT) as u64
 }
 
-// Bitmap-based frame allocator
+// The public API hands out frames by their linear-map address
+fn frame_to_ptr(frame: FrameNumber) -> Option<NonNull<u8>> {
+    NonNull::new(phys_to_virt(frame_to_addr(frame)) as *mut u8)
+}
+
+fn ptr_to_frame(ptr: NonNull<u8>) -> FrameNumber {
+    addr_to_frame(virt_to_phys(ptr.as_ptr() as u64))
+}
+
+// Reference count type for shared frames
+pub type FrameRefCount = u16;
+
+// Bitmap-based frame allocator with per-frame reference counts
 pub struct FrameAllocator {
     bitmap: &'static mut [u8],
+    refcounts: &'static mut [FrameRefCount],
     start_frame: FrameNumber,
     total_frames: usize,
     free_frames: usize,
@@ -31,7 +46,8 @@ pub struct FrameAllocator {
 }
 
 impl FrameAllocator {
-    pub fn new(memory_regions: &[MemoryRegion], bitmap_storage: &'static mut [u8]) -> Self {
+    pub fn new(memory_regions: &[MemoryRegion], bitmap_storage: &'static mut [u8],
+               refcount_storage: &'static mut [FrameRefCount]) -> Self {
         // Find the largest memory region for simplicity
         let main_region = memory_regions
             .iter()
@@ -39,31 +55,42 @@ impl FrameAllocator {
             .expect("No memory regions found");
         
         let start_frame = addr_to_frame(main_region.start);
-        let total_frames = (main_region.size as usize) >> PAGE_SHIFT;
+        let mut total_frames = (main_region.size as usize) >> PAGE_SHIFT;
+        
+        // Frames beyond what the static storage can track stay unused
+        let capacity = (bitmap_storage.len() * 8).min(refcount_storage.len());
+        if total_frames > capacity {
+            crate::println!("FrameAllocator: Tracking {} of {} frames", capacity, total_frames);
+            total_frames = capacity;
+        }
         
         // Initialize bitmap - all frames marked as used initially
-        let bitmap_bytes = (total_frames + 7) / 8;
+        let bitmap_bytes = total_frames.div_ceil(8);
         assert!(bitmap_storage.len() >= bitmap_bytes, "Bitmap storage too small");
         
         for byte in &mut bitmap_storage[..bitmap_bytes] {
             *byte = 0xFF; // All used
         }
         
+        // Reference counts only track frames handed out by the allocator
+        assert!(refcount_storage.len() >= total_frames, "Refcount storage too small");
+        for count in &mut refcount_storage[..total_frames] {
+            *count = 0;
+        }
+        
         let mut allocator = Self {
             bitmap: &mut bitmap_storage[..bitmap_bytes],
+            refcounts: &mut refcount_storage[..total_frames],
             start_frame,
             total_frames,
             free_frames: 0,
             next_free_hint: 0,
         };
         
-        // Mark usable frames as free (skip kernel area)
-        let kernel_end_frame = addr_to_frame(0x41000000); // Rough kernel end
-        let usable_start = if kernel_end_frame > start_frame {
-            kernel_end_frame - start_frame
-        } else {
-            0
-        };
+        // Mark usable frames as free: those above the kernel image (below
+        // it are the loader's, and on QEMU the device tree)
+        let kernel_end_frame = addr_to_frame(virt_to_phys(crate::memory::mmu::kernel_image().1));
+        let usable_start = kernel_end_frame.saturating_sub(start_frame);
         
         for frame_idx in usable_start..total_frames {
             allocator.mark_frame_free(frame_idx);
@@ -86,6 +113,7 @@ impl FrameAllocator {
             let frame_idx = (self.next_free_hint + i) % self.total_frames;
             if self.is_frame_free(frame_idx) {
                 self.mark_frame_used(frame_idx);
+                self.refcounts[frame_idx] = 1;
                 self.next_free_hint = (frame_idx + 1) % self.total_frames;
                 return Some(self.start_frame + frame_idx);
             }
@@ -94,16 +122,145 @@ impl FrameAllocator {
         None
     }
     
-    // Deallocate a physical frame
+    // Allocate `count` physically contiguous frames
+    pub fn allocate_frames(&mut self, count: usize) -> Option<FrameNumber> {
+        if count == 0 || self.free_frames < count {
+            return None;
+        }
+        
+        let mut run_start = 0;
+        let mut run_len = 0;
+        for frame_idx in 0..self.total_frames {
+            if self.is_frame_free(frame_idx) {
+                if run_len == 0 {
+                    run_start = frame_idx;
+                }
+                run_len += 1;
+                if run_len == count {
+                    for idx in run_start..run_start + count {
+                        self.mark_frame_used(idx);
+                        self.refcounts[idx] = 1;
+                    }
+                    return Some(self.start_frame + run_start);
+                }
+            } else {
+                run_len = 0;
+            }
+        }
+        
+        None
+    }
+    
+    // Deallocate a physical frame (drops one reference, frees on last)
     pub fn deallocate_frame(&mut self, frame: FrameNumber) {
-        if frame < self.start_frame || frame >= self.start_frame + self.total_frames {
-            return; // Invalid frame
+        let _ = self.put_frame(frame);
+    }
+    
+    // Take an additional reference to an allocated frame
+    pub fn get_frame(&mut self, frame: FrameNumber) -> Result<FrameRefCount, &'static str> {
+        let frame_idx = self.frame_index(frame).ok_or("Frame outside allocator range")?;
+        
+        if self.refcounts[frame_idx] == 0 {
+            return Err("Frame not allocated");
         }
         
-        let frame_idx = frame - self.start_frame;
-        if !self.is_frame_free(frame_idx) {
+        let count = self.refcounts[frame_idx]
+            .checked_add(1)
+            .ok_or("Frame reference count overflow")?;
+        self.refcounts[frame_idx] = count;
+        Ok(count)
+    }
+    
+    // Drop a reference to a frame, returning the remaining count.
+    // The frame goes back to the free pool when the count reaches zero.
+    pub fn put_frame(&mut self, frame: FrameNumber) -> Result<FrameRefCount, &'static str> {
+        let frame_idx = self.frame_index(frame).ok_or("Frame outside allocator range")?;
+        
+        if self.refcounts[frame_idx] == 0 {
+            return Err("Frame not allocated");
+        }
+        
+        self.refcounts[frame_idx] -= 1;
+        let count = self.refcounts[frame_idx];
+        if count == 0 {
             self.mark_frame_free(frame_idx);
         }
+        Ok(count)
+    }
+    
+    // Current reference count of a frame (0 if free or out of range)
+    pub fn refcount(&self, frame: FrameNumber) -> FrameRefCount {
+        self.frame_index(frame)
+            .map(|frame_idx| self.refcounts[frame_idx])
+            .unwrap_or(0)
+    }
+    
+    // Take a specific free frame out of the pool
+    pub fn claim_frame(&mut self, frame: FrameNumber) -> bool {
+        match self.frame_index(frame) {
+            Some(frame_idx) if self.is_frame_free(frame_idx) => {
+                self.mark_frame_used(frame_idx);
+                self.refcounts[frame_idx] = 1;
+                true
+            }
+            _ => false,
+        }
+    }
+    
+    // Window of `count` frames starting on an `align`-frame boundary whose
+    // allocated frames all pass `movable`; the one with fewest allocated
+    // frames wins. Reserved frames (used, refcount 0) are never movable.
+    pub fn find_window(&self, count: usize, align: usize,
+                       movable: impl Fn(FrameNumber, FrameRefCount) -> bool) -> Option<(FrameNumber, usize)> {
+        let align = align.max(1);
+        let mut best: Option<(FrameNumber, usize)> = None;
+        let mut frame = self.start_frame.div_ceil(align) * align;
+        
+        'windows: while frame + count <= self.start_frame + self.total_frames {
+            let first_idx = frame - self.start_frame;
+            let mut used = 0;
+            for frame_idx in first_idx..first_idx + count {
+                if self.is_frame_free(frame_idx) {
+                    continue;
+                }
+                let refcount = self.refcounts[frame_idx];
+                if refcount == 0 || !movable(self.start_frame + frame_idx, refcount) {
+                    // Nothing aligned below this frame can include it
+                    frame = (self.start_frame + frame_idx) / align * align + align;
+                    continue 'windows;
+                }
+                used += 1;
+            }
+            if best.is_none_or(|(_, best_used)| used < best_used) {
+                best = Some((frame, used));
+                if used == 0 {
+                    break;
+                }
+            }
+            frame += align;
+        }
+        best
+    }
+    
+    /// Visit every free frame; those `keep` rejects are reserved for good.
+    pub fn retain_free(&mut self, mut keep: impl FnMut(FrameNumber) -> bool) -> usize {
+        let mut reserved = 0;
+        for frame_idx in 0..self.total_frames {
+            if self.is_frame_free(frame_idx) && !keep(self.start_frame + frame_idx) {
+                self.mark_frame_used(frame_idx);
+                self.refcounts[frame_idx] = 1;
+                reserved += 1;
+            }
+        }
+        reserved
+    }
+    
+    // Convert an absolute frame number into a bitmap index
+    fn frame_index(&self, frame: FrameNumber) -> Option<usize> {
+        if frame < self.start_frame || frame >= self.start_frame + self.total_frames {
+            return None;
+        }
+        Some(frame - self.start_frame)
     }
     
     // Check if frame is free
@@ -149,29 +306,114 @@ impl FrameAllocator {
 // Global frame allocator
 static FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
 
-// Static storage for bitmap (supports up to 256MB of RAM)
-static mut BITMAP_STORAGE: [u8; 8192] = [0; 8192];
+// Frames the idle task has zeroed ahead of allocate_zeroed_frame. They are
+// allocated as far as the bitmap is concerned but count as free, and go
+// back to ordinary allocations when memory runs out.
+const ZERO_POOL_SIZE: usize = 16;
+// Free frames below which the pool is not refilled
+const ZERO_POOL_MIN_FREE: usize = 256;
+
+struct ZeroPool {
+    // Kernel addresses of the zeroed frames
+    frames: [u64; ZERO_POOL_SIZE],
+    len: usize,
+}
+
+static ZERO_POOL: Mutex<ZeroPool> = Mutex::new(ZeroPool { frames: [0; ZERO_POOL_SIZE], len: 0 });
+
+// Static storage for bitmap (supports up to 1GB of RAM)
+static mut BITMAP_STORAGE: [u8; 32768] = [0; 32768];
+
+// Static storage for per-frame reference counts (one per bitmap bit)
+static mut REFCOUNT_STORAGE: [FrameRefCount; 32768 * 8] = [0; 32768 * 8];
 
 pub fn init_frame_allocator(memory_regions: &[MemoryRegion]) {
-    let bitmap_storage = unsafe { &mut BITMAP_STORAGE };
-    let allocator = FrameAllocator::new(memory_regions, bitmap_storage);
+    let bitmap_storage = unsafe { &mut *core::ptr::addr_of_mut!(BITMAP_STORAGE) };
+    let refcount_storage = unsafe { &mut *core::ptr::addr_of_mut!(REFCOUNT_STORAGE) };
+    let allocator = FrameAllocator::new(memory_regions, bitmap_storage, refcount_storage);
     *FRAME_ALLOCATOR.lock() = Some(allocator);
 }
 
+/// Allocate a frame, returned by its kernel (linear map) address; use
+/// `virt_to_phys` for the address a device or page table needs.
 pub fn allocate_frame() -> Option<NonNull<u8>> {
     let mut allocator_guard = FRAME_ALLOCATOR.lock();
     if let Some(allocator) = allocator_guard.as_mut() {
         if let Some(frame) = allocator.allocate_frame() {
-            let addr = frame_to_addr(frame);
-            return NonNull::new(addr as *mut u8);
+            tracepoint::hit(Tracepoint::FrameAlloc, frame_to_addr(frame), 1);
+            return frame_to_ptr(frame);
+        }
+    }
+    drop(allocator_guard);
+    // Out of frames: the pre-zeroed ones are free memory too
+    take_zeroed()
+}
+
+/// Allocate a frame filled with zeroes, from the pool the idle task keeps
+/// topped up when there is one.
+pub fn allocate_zeroed_frame() -> Option<NonNull<u8>> {
+    if let Some(frame) = take_zeroed() {
+        return Some(frame);
+    }
+    let frame = allocate_frame()?;
+    unsafe { core::ptr::write_bytes(frame.as_ptr(), 0, PAGE_SIZE) };
+    Some(frame)
+}
+
+/// Keep a few frames zeroed in advance, as idle-time housekeeping.
+pub fn start_prezeroing() {
+    if let Err(e) = crate::process::idle::register_idle_work("prezero", prezero_one) {
+        crate::println!("FrameAllocator: Failed to register pre-zeroing: {}", e);
+    }
+}
+
+fn take_zeroed() -> Option<NonNull<u8>> {
+    let mut pool = ZERO_POOL.lock();
+    let frame = pool.frames[..pool.len].last().copied()?;
+    pool.len -= 1;
+    NonNull::new(frame as *mut u8)
+}
+
+/// Idle hook: zero one more frame for the pool; true until it is full.
+pub fn prezero_one() -> bool {
+    if ZERO_POOL.lock().len == ZERO_POOL_SIZE {
+        return false;
+    }
+    // Leave memory that is getting short to real allocations
+    let (free, _) = frame_allocator_stats();
+    if free < ZERO_POOL_MIN_FREE {
+        return false;
+    }
+    let Some(frame) = allocate_frame() else { return false };
+    unsafe { core::ptr::write_bytes(frame.as_ptr(), 0, PAGE_SIZE) };
+    let mut pool = ZERO_POOL.lock();
+    if pool.len == ZERO_POOL_SIZE {
+        drop(pool);
+        deallocate_frame(frame);
+        return false;
+    }
+    let len = pool.len;
+    pool.frames[len] = frame.as_ptr() as u64;
+    pool.len += 1;
+    pool.len < ZERO_POOL_SIZE
+}
+
+// Give the pool back, for a contiguous allocation that needs the frames
+fn drain_zero_pool() -> bool {
+    let mut pool = ZERO_POOL.lock();
+    let frames = pool.frames;
+    let len = core::mem::take(&mut pool.len);
+    drop(pool);
+    for &frame in &frames[..len] {
+        if let Some(frame) = NonNull::new(frame as *mut u8) {
+            deallocate_frame(frame);
         }
     }
-    None
+    len != 0
 }
 
 pub fn deallocate_frame(frame_addr: NonNull<u8>) {
-    let addr = frame_addr.as_ptr() as u64;
-    let frame = addr_to_frame(addr);
+    let frame = ptr_to_frame(frame_addr);
     
     let mut allocator_guard = FRAME_ALLOCATOR.lock();
     if let Some(allocator) = allocator_guard.as_mut() {
@@ -179,11 +421,99 @@ pub fn deallocate_frame(frame_addr: NonNull<u8>) {
     }
 }
 
+/// Allocate `count` physically contiguous frames, returning the first.
+pub fn allocate_frames(count: usize) -> Option<NonNull<u8>> {
+    let allocate = || {
+        let mut allocator_guard = FRAME_ALLOCATOR.lock();
+        let frame = allocator_guard.as_mut()?.allocate_frames(count)?;
+        tracepoint::hit(Tracepoint::FrameAlloc, frame_to_addr(frame), count as u64);
+        frame_to_ptr(frame)
+    };
+    allocate().or_else(|| if drain_zero_pool() { allocate() } else { None })
+}
+
+/// Release a run of frames obtained from `allocate_frames`.
+pub fn deallocate_frames(first_frame: NonNull<u8>, count: usize) {
+    let first = ptr_to_frame(first_frame);
+    
+    let mut allocator_guard = FRAME_ALLOCATOR.lock();
+    if let Some(allocator) = allocator_guard.as_mut() {
+        for frame in first..first + count {
+            allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+/// Take an additional reference to a shared frame.
+///
+/// Used when the same physical frame is mapped into several places
+/// (shared memory, copy-on-write, page cache). Returns the new count.
+pub fn frame_get(frame_addr: NonNull<u8>) -> Result<FrameRefCount, &'static str> {
+    let frame = ptr_to_frame(frame_addr);
+    
+    let mut allocator_guard = FRAME_ALLOCATOR.lock();
+    let allocator = allocator_guard.as_mut().ok_or("Frame allocator not initialized")?;
+    allocator.get_frame(frame)
+}
+
+/// Drop a reference to a shared frame, freeing it when the last one goes.
+///
+/// Returns the remaining count; putting an already free frame is an
+/// error rather than a double free.
+pub fn frame_put(frame_addr: NonNull<u8>) -> Result<FrameRefCount, &'static str> {
+    let frame = ptr_to_frame(frame_addr);
+    
+    let mut allocator_guard = FRAME_ALLOCATOR.lock();
+    let allocator = allocator_guard.as_mut().ok_or("Frame allocator not initialized")?;
+    allocator.put_frame(frame)
+}
+
+/// Current reference count of a frame (0 means free).
+pub fn frame_refcount(frame_addr: NonNull<u8>) -> FrameRefCount {
+    let frame = ptr_to_frame(frame_addr);
+    
+    let allocator_guard = FRAME_ALLOCATOR.lock();
+    allocator_guard.as_ref().map(|allocator| allocator.refcount(frame)).unwrap_or(0)
+}
+
+/// Take the specific frame at `frame_addr` if it is free.
+pub fn claim_frame(frame_addr: NonNull<u8>) -> bool {
+    let frame = ptr_to_frame(frame_addr);
+    FRAME_ALLOCATOR.lock().as_mut().is_some_and(|allocator| allocator.claim_frame(frame))
+}
+
+/// Check every free frame with `keep`, taking the ones it rejects out of
+/// circulation. Returns how many were reserved.
+pub fn retain_free_frames(mut keep: impl FnMut(NonNull<u8>) -> bool) -> usize {
+    FRAME_ALLOCATOR.lock().as_mut().map_or(0, |allocator| {
+        allocator.retain_free(|frame| frame_to_ptr(frame).is_none_or(&mut keep))
+    })
+}
+
+/// Best place for `count` contiguous frames aligned to `align` frames,
+/// counting allocated frames that `movable` says could be migrated away.
+/// Returns the first frame and how many frames in the window are in use.
+pub fn find_window(count: usize, align: usize,
+                   movable: impl Fn(NonNull<u8>, FrameRefCount) -> bool) -> Option<(NonNull<u8>, usize)> {
+    let allocator_guard = FRAME_ALLOCATOR.lock();
+    let allocator = allocator_guard.as_ref()?;
+    let (first, used) = allocator.find_window(count, align, |frame, refcount| {
+        frame_to_ptr(frame).is_some_and(|ptr| movable(ptr, refcount))
+    })?;
+    Some((frame_to_ptr(first)?, used))
+}
+
+/// (free, total) frames; pre-zeroed frames count as free.
 pub fn frame_allocator_stats() -> (usize, usize) {
     let allocator_guard = FRAME_ALLOCATOR.lock();
-    if let Some(allocator) = allocator_guard.as_ref() {
-        allocator.stats()
-    } else {
-        (0, 0)
-    }
+    let Some((free, total)) = allocator_guard.as_ref().map(FrameAllocator::stats) else {
+        return (0, 0);
+    };
+    drop(allocator_guard);
+    (free + ZERO_POOL.lock().len, total)
+}
+
+/// Frames zeroed in advance and waiting in the pool.
+pub fn prezeroed_frames() -> usize {
+    ZERO_POOL.lock().len
 }
\ No newline at end of file
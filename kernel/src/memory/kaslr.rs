@@ -0,0 +1,147 @@
+// Kernel address space layout randomization
+//
+// build.rs links the kernel position-independent at its usual address,
+// KERNEL_VIRT_OFFSET above its load address (inside the linear map), with
+// a relocation in .rela.dyn for every absolute address in the image. Right
+// after turning the MMU on, boot.s calls `kaslr_early_init` there. It maps
+// the image at a random 2MB-aligned slot of a region of its own, adds the
+// distance to every relocated address and returns it, and boot.s jumps
+// across; rust_main and everything after run at the new address.
+//
+// The slot comes from rand::early_u64: the counter, RNDR where the CPU
+// has it and the loader's /chosen seeds. "nokaslr" in the bootargs keeps the link address,
+// which is what gdb and addr2line expect.
+//
+// Image addresses are no longer linear-map addresses: virt_to_phys handles
+// them, and mmu::init maps the image where it runs while keeping the
+// linear alias of its text and rodata read-only and non-executable. The
+// ELF and the ksyms table have link addresses, so symbols::lookup and
+// /proc/profile convert, and a panic report prints the offset.
+
+use core::arch::asm;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::memory::mmu::{self, BOOT_NORMAL_BLOCK_FLAGS};
+use crate::memory::paging::{virt_to_phys, VirtAddr, BLOCK_SIZE_2M};
+
+/// Where the image may be moved to: the 512GB of one level 0 entry.
+pub const REGION_START: VirtAddr = 0xFFFF_C000_0000_0000;
+pub const REGION_SIZE: u64 = 512 << 30;
+
+const GIGABYTE: u64 = 1 << 30;
+const REGION_GIGABYTES: u64 = REGION_SIZE / GIGABYTE;
+const SLOTS_PER_GIGABYTE: u64 = GIGABYTE / BLOCK_SIZE_2M;
+
+const R_AARCH64_RELATIVE: u64 = 1027;
+// Table descriptor type bits
+const TABLE_DESCRIPTOR: u64 = 3;
+
+// Elf64_Rela
+#[repr(C)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+#[repr(C, align(4096))]
+struct BootTable([u64; 512]);
+
+extern "C" {
+    static mut boot_l0_table: [u64; 512];
+    static __rela_dyn_start: Rela;
+    static __rela_dyn_end: Rela;
+}
+
+// Boot-table levels below REGION_START's level 0 entry; the image never
+// crosses a gigabyte, so one of each is enough
+static mut REGION_L1: BootTable = BootTable([0; 512]);
+static mut REGION_L2: BootTable = BootTable([0; 512]);
+
+// Distance from the link address, 0 until moved
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn relocations() -> &'static [Rela] {
+    unsafe {
+        let start = &__rela_dyn_start as *const Rela;
+        let end = &__rela_dyn_end as *const Rela;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Move the kernel to a random address and return how far, or 0 to stay.
+/// Called once by boot.s, at the link address on the boot stack and boot
+/// tables, before rust_main: nothing here may depend on initialization.
+#[no_mangle]
+pub extern "C" fn kaslr_early_init(boot_arg: u64) -> u64 {
+    if cfg!(feature = "no-mmu") {
+        return 0;
+    }
+    crate::devicetree::set_boot_arg(boot_arg);
+    let dt = crate::devicetree::device_tree();
+    if dt.is_some_and(|dt| dt.bootarg("nokaslr").is_some()) {
+        return 0;
+    }
+    // Only base-relative entries are expected from the link build.rs asks for
+    let relocs = relocations();
+    if relocs.iter().any(|rela| rela.info & 0xFFFF_FFFF != R_AARCH64_RELATIVE) {
+        return 0;
+    }
+    
+    // Whole 2MB blocks around the image; they stay in one gigabyte
+    let (start, end) = mmu::kernel_image();
+    let phys = virt_to_phys(start);
+    let first = phys & !(BLOCK_SIZE_2M - 1);
+    let blocks = (virt_to_phys(end) - first).div_ceil(BLOCK_SIZE_2M);
+    if blocks > SLOTS_PER_GIGABYTE {
+        return 0;
+    }
+    let seed = crate::rand::early_u64();
+    let gigabyte = seed % REGION_GIGABYTES;
+    let slot = (seed / REGION_GIGABYTES) % (SLOTS_PER_GIGABYTE - blocks + 1);
+    let base = REGION_START + gigabyte * GIGABYTE + slot * BLOCK_SIZE_2M;
+    
+    unsafe {
+        let l0 = &mut *addr_of_mut!(boot_l0_table);
+        let l1 = &mut *addr_of_mut!(REGION_L1);
+        let l2 = &mut *addr_of_mut!(REGION_L2);
+        l0[(REGION_START >> 39) as usize % 512] = virt_to_phys(l1 as *mut BootTable as u64) | TABLE_DESCRIPTOR;
+        l1.0[gigabyte as usize] = virt_to_phys(l2 as *mut BootTable as u64) | TABLE_DESCRIPTOR;
+        for block in 0..blocks {
+            l2.0[(slot + block) as usize] = (first + block * BLOCK_SIZE_2M) | BOOT_NORMAL_BLOCK_FLAGS;
+        }
+        asm!("dsb ishst", "isb");
+    }
+    
+    // Both addresses are mapped throughout, so it does not matter what
+    // runs or is read while the values change
+    let offset = base + (phys - first) - start;
+    for rela in relocs {
+        unsafe { (rela.offset as *mut u64).write_volatile((rela.addend as u64).wrapping_add(offset)) };
+    }
+    unsafe {
+        asm!("dsb ish", "isb");
+    }
+    OFFSET.store(offset, Ordering::Relaxed);
+    offset
+}
+
+/// How far the kernel was moved from its link address (0 with nokaslr).
+pub fn offset() -> u64 {
+    OFFSET.load(Ordering::Relaxed)
+}
+
+/// Whether `addr` is in the region the image is moved to.
+pub fn in_region(addr: VirtAddr) -> bool {
+    addr.wrapping_sub(REGION_START) < REGION_SIZE
+}
+
+/// The link address (as in the ELF and the ksyms table) of an image address.
+/// Other addresses are returned unchanged.
+pub fn link_address(addr: VirtAddr) -> VirtAddr {
+    if in_region(addr) {
+        addr - offset()
+    } else {
+        addr
+    }
+}
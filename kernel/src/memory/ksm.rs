@@ -0,0 +1,210 @@
+// Kernel samepage merging
+//
+// Address spaces register regions whose read-only pages may be shared
+// (typically the text and constant data of identical services). When
+// enabled, the idle task scans those pages a few at a time, hashes them,
+// and points every mapping of a duplicate at one shared frame; the copies
+// are freed. Shared frames stay read-only, so a write fault has to copy
+// the page again (copy-on-write).
+//
+// Only frames owned solely by their mappings (refcount == map count) are
+// merged, the same condition page migration uses.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::memory::frame_allocator::{frame_get, frame_put, frame_refcount, PAGE_SIZE};
+use crate::memory::paging::{phys_to_virt, PageFlags, PageTableEntry, PhysAddr, VirtAddr, VirtualMemoryManager};
+use crate::memory::rmap;
+
+// Pages examined per idle invocation
+const DEFAULT_PAGES_PER_SCAN: usize = 64;
+
+static KSM_ENABLED: AtomicBool = AtomicBool::new(false);
+static PAGES_PER_SCAN: AtomicUsize = AtomicUsize::new(DEFAULT_PAGES_PER_SCAN);
+
+struct MergeableRegion {
+    root: u64,
+    start: VirtAddr,
+    pages: usize,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct KsmStats {
+    pub full_scans: u64,
+    pub pages_scanned: u64,
+    // Mappings redirected to a shared frame
+    pub pages_merged: u64,
+    // Distinct shared frames currently known
+    pub pages_shared: usize,
+}
+
+struct Ksm {
+    regions: Vec<MergeableRegion>,
+    // Next page to scan: (region, page within region)
+    cursor: (usize, usize),
+    // Content hash -> shared frame
+    stable: BTreeMap<u64, PhysAddr>,
+    stats: KsmStats,
+}
+
+static KSM: Mutex<Ksm> = Mutex::new(Ksm {
+    regions: Vec::new(),
+    cursor: (0, 0),
+    stable: BTreeMap::new(),
+    stats: KsmStats { full_scans: 0, pages_scanned: 0, pages_merged: 0, pages_shared: 0 },
+});
+
+pub fn init() {
+    if let Err(e) = crate::process::idle::register_idle_work("ksm", idle_scan) {
+        crate::println!("KSM: Failed to register idle scanner: {}", e);
+    }
+}
+
+pub fn is_enabled() -> bool {
+    KSM_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turn background merging on or off (off by default).
+pub fn set_enabled(enabled: bool) {
+    KSM_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn pages_per_scan() -> usize {
+    PAGES_PER_SCAN.load(Ordering::Relaxed)
+}
+
+pub fn set_pages_per_scan(pages: usize) {
+    PAGES_PER_SCAN.store(pages.max(1), Ordering::Relaxed);
+}
+
+/// Allow merging of the pages in [start, start + pages * PAGE_SIZE) of the
+/// address space whose `ttbr()` is `root`.
+pub fn register_region(root: u64, start: VirtAddr, pages: usize) {
+    KSM.lock().regions.push(MergeableRegion { root, start, pages });
+}
+
+/// Stop merging in every region of an address space (before it is torn down).
+pub fn unregister_space(root: u64) {
+    let mut ksm = KSM.lock();
+    ksm.regions.retain(|region| region.root != root);
+    ksm.cursor = (0, 0);
+}
+
+pub fn stats() -> KsmStats {
+    let ksm = KSM.lock();
+    KsmStats { pages_shared: ksm.stable.len(), ..ksm.stats }
+}
+
+fn idle_scan() -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    !scan(pages_per_scan())
+}
+
+/// Examine up to `budget` pages; true once a full pass has completed.
+pub fn scan(budget: usize) -> bool {
+    let mut ksm = KSM.lock();
+    for _ in 0..budget {
+        let (region_index, page) = ksm.cursor;
+        let region = match ksm.regions.get(region_index) {
+            Some(region) => region,
+            None => {
+                ksm.cursor = (0, 0);
+                ksm.stats.full_scans += 1;
+                return true;
+            }
+        };
+        let (root, virt) = (region.root, region.start + (page * PAGE_SIZE) as u64);
+        ksm.cursor = if page + 1 < region.pages { (region_index, page + 1) } else { (region_index + 1, 0) };
+        
+        ksm.stats.pages_scanned += 1;
+        scan_page(&mut ksm, root, virt);
+    }
+    false
+}
+
+fn scan_page(ksm: &mut Ksm, root: u64, virt: VirtAddr) {
+    let mut space = unsafe { VirtualMemoryManager::from_root(root) };
+    let entry = match space.leaf_entry(virt) {
+        Some(entry) if entry.is_valid() => *entry,
+        _ => return,
+    };
+    let phys = entry.physical_addr();
+    if !mergeable(phys) {
+        return;
+    }
+    
+    let hash = page_hash(phys);
+    match ksm.stable.get(&hash).copied() {
+        Some(shared) if shared == phys => {}
+        // Shared frames can be freed and reused; check before trusting one
+        Some(shared) if mergeable(shared) && same_contents(shared, phys) => {
+            if merge_into(phys, shared).is_ok() {
+                ksm.stats.pages_merged += 1;
+            }
+        }
+        _ => {
+            ksm.stable.insert(hash, phys);
+        }
+    }
+}
+
+// Owned only by read-only mappings
+fn mergeable(phys: PhysAddr) -> bool {
+    let mappings = rmap::mappings(phys);
+    let frame = match NonNull::new(phys_to_virt(phys) as *mut u8) {
+        Some(frame) => frame,
+        None => return false,
+    };
+    if mappings.is_empty() || frame_refcount(frame) as usize != mappings.len() {
+        return false;
+    }
+    mappings.iter().all(|mapping| {
+        let mut space = unsafe { VirtualMemoryManager::from_root(mapping.root) };
+        space
+            .leaf_entry(mapping.virt)
+            .is_some_and(|entry| entry.flags().contains(PageFlags::READ_ONLY))
+    })
+}
+
+fn page(phys: PhysAddr) -> &'static [u64] {
+    unsafe { core::slice::from_raw_parts(phys_to_virt(phys) as *const u64, PAGE_SIZE / 8) }
+}
+
+// FNV-1a over 64-bit words
+fn page_hash(phys: PhysAddr) -> u64 {
+    page(phys)
+        .iter()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, &word| (hash ^ word).wrapping_mul(0x0000_0100_0000_01B3))
+}
+
+fn same_contents(a: PhysAddr, b: PhysAddr) -> bool {
+    page(a) == page(b)
+}
+
+// Point every mapping of `dup` at `shared`; `dup` is freed with its last mapping
+fn merge_into(dup: PhysAddr, shared: PhysAddr) -> Result<(), &'static str> {
+    let dup_frame = NonNull::new(phys_to_virt(dup) as *mut u8).ok_or("Bad frame")?;
+    let shared_frame = NonNull::new(phys_to_virt(shared) as *mut u8).ok_or("Bad frame")?;
+    
+    for mapping in rmap::mappings(dup) {
+        let mut space = unsafe { VirtualMemoryManager::from_root(mapping.root) };
+        let entry = space.leaf_entry(mapping.virt).ok_or("Reverse map entry without mapping")?;
+        frame_get(shared_frame)?;
+        
+        // Break-before-make: the output address changes
+        let saved = *entry;
+        *entry = PageTableEntry::empty();
+        space.flush_page(mapping.virt);
+        *entry = saved.with_addr(shared);
+        
+        rmap::remove(dup, mapping.root, mapping.virt);
+        rmap::add(shared, mapping.root, mapping.virt);
+        frame_put(dup_frame)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,118 @@
+// Boot-time RAM pattern test (memtest=<passes>[,preserve])
+//
+// Runs over every free frame right after the frame allocator is set up,
+// before anything has been handed out. Frames that fail are reserved so
+// they are never allocated. Meant for bring-up on real boards where RAM
+// may be marginal; QEMU memory never fails.
+//
+// Free frames hold nothing, so by default the test is destructive;
+// `preserve` saves and restores each frame around the patterns.
+
+use core::ptr::{read_volatile, write_volatile, NonNull};
+use crate::memory::frame_allocator::{self, PAGE_SIZE};
+use crate::memory::paging::virt_to_phys;
+
+const WORDS_PER_FRAME: usize = PAGE_SIZE / 8;
+
+// Stuck bits first, then alternating and walking bits for coupling
+// between neighbouring cells
+const PATTERNS: [u64; 8] = [
+    0x0000_0000_0000_0000,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0x5555_5555_5555_5555,
+    0xAAAA_AAAA_AAAA_AAAA,
+    0x1111_1111_1111_1111,
+    0x2222_2222_2222_2222,
+    0x4444_4444_4444_4444,
+    0x8888_8888_8888_8888,
+];
+
+/// Fixed patterns plus the final address-in-address pass.
+pub const MAX_PASSES: usize = PATTERNS.len() + 1;
+
+// Bad frames reported individually before falling back to a count
+const REPORT_LIMIT: usize = 16;
+
+#[derive(Clone, Copy)]
+pub struct MemtestConfig {
+    pub passes: usize,
+    pub preserve: bool,
+}
+
+/// Parse the `memtest=` boot argument. A bare `memtest` runs every pass.
+pub fn config() -> Option<MemtestConfig> {
+    let value = crate::bootparams::get("memtest")?;
+    let (passes, options) = value.split_once(',').unwrap_or((value, ""));
+    let passes = match passes {
+        "" => MAX_PASSES,
+        n => n.parse::<usize>().ok()?.min(MAX_PASSES),
+    };
+    if passes == 0 {
+        return None;
+    }
+    Some(MemtestConfig { passes, preserve: options == "preserve" })
+}
+
+// Fill with one pattern and read it back
+fn fill_and_check(words: *mut u64, value: impl Fn(usize) -> u64) -> bool {
+    unsafe {
+        for i in 0..WORDS_PER_FRAME {
+            write_volatile(words.add(i), value(i));
+        }
+        (0..WORDS_PER_FRAME).all(|i| read_volatile(words.add(i)) == value(i))
+    }
+}
+
+/// Run `passes` patterns over one frame. Returns false if any word failed.
+pub fn test_frame(frame: NonNull<u8>, passes: usize, preserve: bool) -> bool {
+    let words = frame.as_ptr() as *mut u64;
+    let mut backup = [0u64; WORDS_PER_FRAME];
+    if preserve {
+        for (i, word) in backup.iter_mut().enumerate() {
+            *word = unsafe { read_volatile(words.add(i)) };
+        }
+    }
+    
+    let mut good = PATTERNS
+        .iter()
+        .take(passes)
+        .all(|&pattern| fill_and_check(words, |_| pattern));
+    if good && passes > PATTERNS.len() {
+        // Each word holds its own address: catches aliased address lines
+        let base = virt_to_phys(words as u64);
+        good = fill_and_check(words, |i| base + (i * 8) as u64);
+    }
+    
+    if preserve {
+        for (i, &word) in backup.iter().enumerate() {
+            unsafe { write_volatile(words.add(i), word) };
+        }
+    }
+    good
+}
+
+/// Test every free frame and reserve the bad ones.
+pub fn run(config: MemtestConfig) -> usize {
+    crate::println!("Memtest: {} pass(es) over free memory{}", config.passes,
+                   if config.preserve { ", preserving contents" } else { "" });
+    
+    let mut tested = 0;
+    let mut reported = 0;
+    let bad = frame_allocator::retain_free_frames(|frame| {
+        tested += 1;
+        let good = test_frame(frame, config.passes, config.preserve);
+        if !good && reported < REPORT_LIMIT {
+            reported += 1;
+            crate::println!("Memtest: Bad frame at 0x{:x}, reserved",
+                           virt_to_phys(frame.as_ptr() as u64));
+        }
+        good
+    });
+    
+    if bad > reported {
+        crate::println!("Memtest: ... and {} more bad frames", bad - reported);
+    }
+    crate::println!("Memtest: {} frames tested, {} bad ({} KiB reserved)",
+                   tested, bad, bad * PAGE_SIZE / 1024);
+    bad
+}
@@ -1,137 +1,252 @@
 // ARM64 Memory Management Unit (MMU) setup and management
+//
+// The kernel lives in the upper half (TTBR1): the image is linked at
+// KERNEL_VIRT_OFFSET above its load address, and all of RAM plus every
+// MMIO region from the device tree is mapped at physical + offset, RAM as
+// normal write-back memory and MMIO as device memory. TTBR0 is left to
+// user space.
+//
+// boot.s turns the MMU on with coarse 1GB tables so Rust code starts at
+// its linked address, from where kaslr.rs usually moves it; `init`
+// replaces them with the real kernel map.
+//
+// Nothing is both writable and executable (W^X): kernel text is read-only,
+// everything else is execute-never, and `init` refuses tables that break
+// the rule before switching to them. SCTLR_EL1.WXN backs this up in
+// hardware. Text is patched (by the gdb stub) through a temporary
+// writable alias, see `write_kernel_text`.
+// Drivers with regions not described by a "reg" property (PCI windows)
+// call `map_device`.
 
 use core::arch::asm;
-use crate::memory::paging::{VirtualMemoryManager, PageFlags, VirtAddr, PhysAddr};
+use spin::Mutex;
+use crate::devicetree::{DeviceTree, MemoryRegion};
+use crate::memory::frame_allocator::PAGE_SIZE;
+use crate::memory::tlb;
+use crate::memory::paging::{
+    phys_to_virt, virt_to_phys, PageFlags, PhysAddr, VirtAddr, VirtualMemoryManager, BLOCK_SIZE_2M,
+};
 
-// Memory attribute indices for MAIR_EL1
-const MAIR_DEVICE_nGnRnE: u64 = 0x00;  // Device memory
-const MAIR_NORMAL_NC: u64 = 0x44;       // Normal memory, non-cacheable  
-const MAIR_NORMAL_WB: u64 = 0xFF;       // Normal memory, write-back
+// MAIR_EL1 attribute slots; PageFlags AttrIndx values must match
+const MAIR_DEVICE_NGNRNE: u64 = 0x00;  // Slot 0: device memory
+const MAIR_NORMAL_NC: u64 = 0x44;      // Slot 1: normal memory, non-cacheable
+const MAIR_NORMAL_WB: u64 = 0xFF;      // Slot 2: normal memory, write-back
+pub const MAIR_VALUE: u64 = MAIR_DEVICE_NGNRNE | (MAIR_NORMAL_NC << 8) | (MAIR_NORMAL_WB << 16);
 
-// TCR_EL1 configuration
-const TCR_T0SZ: u64 = 16;     // 48-bit virtual address space
-const TCR_T1SZ: u64 = 16 << 16;
-const TCR_TG0_4K: u64 = 0;    // 4KB granule for TTBR0
+// TCR_EL1 configuration; IPS is filled in from ID_AA64MMFR0_EL1 at boot
+const TCR_T0SZ: u64 = 16;     // 48-bit user address space
+const TCR_IRGN0_WB: u64 = 1 << 8;
+const TCR_ORGN0_WB: u64 = 1 << 10;
+const TCR_SH0_INNER: u64 = 3 << 12;
+const TCR_TG0_4K: u64 = 0 << 14; // 4KB granule for TTBR0
+const TCR_T1SZ: u64 = 16 << 16;  // 48-bit kernel address space
+const TCR_IRGN1_WB: u64 = 1 << 24;
+const TCR_ORGN1_WB: u64 = 1 << 26;
+const TCR_SH1_INNER: u64 = 3 << 28;
 const TCR_TG1_4K: u64 = 2 << 30; // 4KB granule for TTBR1
-const TCR_IPS_44BIT: u64 = 2 << 32; // 44-bit physical address space
+pub const TCR_VALUE: u64 = TCR_T0SZ | TCR_IRGN0_WB | TCR_ORGN0_WB | TCR_SH0_INNER | TCR_TG0_4K
+    | TCR_T1SZ | TCR_IRGN1_WB | TCR_ORGN1_WB | TCR_SH1_INNER | TCR_TG1_4K;
 
+// SCTLR_EL1 bits
+const SCTLR_M: u64 = 1 << 0;   // MMU enable
+const SCTLR_C: u64 = 1 << 2;   // Data cache
+const SCTLR_I: u64 = 1 << 12;  // Instruction cache
+pub const SCTLR_SET: u64 = SCTLR_M | SCTLR_C | SCTLR_I;
+
+// Boot-time 1GB level 1 blocks for the first 4GB (see boot.s), device
+// memory or RAM as the board has them
+const BOOT_L1_DEVICE_FLAGS: u64 = PageFlags::DEVICE_MEMORY
+    .union(PageFlags::ACCESSED)
+    .union(PageFlags::VALID)
+    .bits();
+pub const BOOT_L1_BLOCKS: [u64; 4] = {
+    let mut blocks = [0; 4];
+    let mut n = 0;
+    while n < 4 {
+        let phys = (n as u64) << 30;
+        let flags = if crate::board::BUILD.early_device(phys) { BOOT_L1_DEVICE_FLAGS } else { BOOT_NORMAL_BLOCK_FLAGS };
+        blocks[n] = phys | flags;
+        n += 1;
+    }
+    blocks
+};
+// Attributes of the boot tables' RAM blocks, at either level
+pub const BOOT_NORMAL_BLOCK_FLAGS: u64 = PageFlags::NORMAL_MEMORY
+    .union(PageFlags::INNER_SHAREABLE)
+    .union(PageFlags::ACCESSED)
+    .union(PageFlags::VALID)
+    .bits();
+
+// Kernel RAM: read-write, never executed (the image is mapped separately)
+const RAM_FLAGS: PageFlags = PageFlags::NORMAL_MEMORY
+    .union(PageFlags::INNER_SHAREABLE)
+    .union(PageFlags::ACCESSED)
+    .union(PageFlags::PXN)
+    .union(PageFlags::UXN);
+// Kernel image: text read-only and executable, then read-only data
+// (.rodata, .ksyms, .eh_frame), then .data and .bss as ordinary RAM
+const KERNEL_TEXT_FLAGS: PageFlags = PageFlags::NORMAL_MEMORY
+    .union(PageFlags::INNER_SHAREABLE)
+    .union(PageFlags::ACCESSED)
+    .union(PageFlags::READ_ONLY)
+    .union(PageFlags::UXN);
+const KERNEL_RODATA_FLAGS: PageFlags = KERNEL_TEXT_FLAGS.union(PageFlags::PXN);
+const DEVICE_FLAGS: PageFlags = PageFlags::DEVICE_MEMORY
+    .union(PageFlags::ACCESSED)
+    .union(PageFlags::PXN)
+    .union(PageFlags::UXN);
+
+// Last 2MB of the address space: the writable alias used to patch text
+const TEXT_POKE_ADDR: VirtAddr = 0xFFFF_FFFF_FFE0_0000;
+
+// W^X violations printed before the rest are only counted
+const WX_REPORT_MAX: usize = 8;
+
+extern "C" {
+    static __kernel_start: u8;
+    static __text_end: u8;
+    static __rodata_end: u8;
+    static __kernel_end: u8;
+}
+
+// Serializes use of TEXT_POKE_ADDR
+static TEXT_POKE: Mutex<()> = Mutex::new(());
+
+// Kernel (TTBR1) tables
 static mut KERNEL_VMM: Option<VirtualMemoryManager> = None;
 
+// Empty TTBR0 tables: lower-half accesses fault until user space exists
+static mut USER_VMM: Option<VirtualMemoryManager> = None;
+
 pub struct MemoryManagementUnit;
 
 impl MemoryManagementUnit {
-    pub fn init() -> Result<(), &'static str> {
+    pub fn init(ram: &[MemoryRegion], dt: Option<&DeviceTree>) -> Result<(), &'static str> {
         crate::println!("MMU: Initializing ARM64 Memory Management Unit...");
         
-        // Create kernel virtual memory manager
-        let vmm = VirtualMemoryManager::new().ok_or("Failed to create VMM")?;
+        // Create kernel and (empty) user virtual memory managers
+        let mut vmm = VirtualMemoryManager::new().ok_or("Failed to create VMM")?;
+        let user = VirtualMemoryManager::new().ok_or("Failed to create VMM")?;
         
-        // Set up identity mapping for kernel (first 1GB)
-        Self::setup_kernel_mappings(&vmm)?;
+        // Linear map of everything the kernel touches
+        Self::setup_kernel_mappings(&mut vmm, ram, dt)?;
         
-        // Configure MMU registers
-        Self::configure_mmu_registers(&vmm);
+        // Never switch to tables with a writable, executable mapping
+        let violations = audit_wx(&vmm, crate::memory::paging::KERNEL_VIRT_OFFSET);
+        if violations != 0 {
+            crate::println!("MMU: {} writable and executable kernel mappings", violations);
+            return Err("Kernel mappings violate W^X");
+        }
+        crate::println!("MMU: W^X audit passed");
         
-        // Enable MMU
-        Self::enable_mmu();
+        // Switch from the boot tables
+        Self::switch_tables(&vmm, &user);
         
-        // Store VMM globally
+        // Store VMMs globally
         unsafe {
             KERNEL_VMM = Some(vmm);
+            USER_VMM = Some(user);
         }
         
-        crate::println!("MMU: ARM64 MMU enabled successfully");
+        crate::println!("MMU: Kernel running in the upper half (offset 0x{:016x})",
+                       crate::memory::paging::KERNEL_VIRT_OFFSET);
         Ok(())
     }
     
-    fn setup_kernel_mappings(_vmm: &VirtualMemoryManager) -> Result<(), &'static str> {
-        crate::println!("MMU: Setting up kernel identity mappings...");
+    fn setup_kernel_mappings(vmm: &mut VirtualMemoryManager, ram: &[MemoryRegion],
+                             dt: Option<&DeviceTree>) -> Result<(), &'static str> {
+        crate::println!("MMU: Setting up kernel mappings...");
         
-        // Identity map first 256MB (covers kernel, device tree, etc.)
-        let kernel_size = 256 * 1024 * 1024; // 256MB
-        let kernel_pages = kernel_size / 4096;
+        // Kernel image first, so its parts keep their own permissions
+        let (image_start, image_end) = kernel_image();
+        let (text_end, rodata_end) = unsafe {
+            (&__text_end as *const u8 as u64, &__rodata_end as *const u8 as u64)
+        };
+        map_image(vmm, image_start, text_end, KERNEL_TEXT_FLAGS)?;
+        map_image(vmm, text_end, rodata_end, KERNEL_RODATA_FLAGS)?;
+        map_image(vmm, rodata_end, image_end, RAM_FLAGS)?;
+        crate::println!("MMU: Kernel image 0x{:016x}-0x{:016x} (text to 0x{:016x}, rodata to 0x{:016x})",
+                       image_start, image_end, text_end, rodata_end);
+        // Once moved (kaslr.rs), the image's linear alias is ordinary RAM
+        // but for text and rodata, which stay read-only and never executed
+        let offset = crate::memory::kaslr::offset();
+        if offset != 0 {
+            map_linear(vmm, virt_to_phys(image_start), rodata_end - image_start, KERNEL_RODATA_FLAGS)?;
+            crate::println!("MMU: Kernel moved 0x{:x} from its link address", offset);
+        }
         
-        for page in 0..kernel_pages {
-            let _addr = (page * 4096) as u64;
-            
-            // Map kernel pages as read-write, supervisor only
-            let _flags = PageFlags::VALID | PageFlags::NORMAL_MEMORY | PageFlags::INNER_SHAREABLE;
-            
-            // For simplicity, we'll skip the actual mapping here since we need mutable access
-            // This would typically be done during early boot with MMU disabled
+        // The rest of RAM holds the FDT, frames, stacks and page tables
+        for region in ram {
+            map_linear(vmm, region.start, region.size, RAM_FLAGS)?;
+            crate::println!("MMU: RAM 0x{:08x}-0x{:08x}", region.start, region.start + region.size);
         }
         
-        crate::println!("MMU: Kernel mappings prepared");
-        Ok(())
-    }
-    
-    fn configure_mmu_registers(vmm: &VirtualMemoryManager) {
-        crate::println!("MMU: Configuring MMU registers...");
+        // The console, mapped explicitly in case the device tree is unusable
+        map_linear(vmm, crate::uart::console_phys(), PAGE_SIZE as u64, DEVICE_FLAGS)?;
         
-        unsafe {
-            // Set up MAIR_EL1 (Memory Attribute Indirection Register)
-            let mair = MAIR_DEVICE_nGnRnE | (MAIR_NORMAL_NC << 8) | (MAIR_NORMAL_WB << 16);
-            asm!("msr mair_el1, {}", in(reg) mair);
-            
-            // Set up TCR_EL1 (Translation Control Register)
-            let tcr = TCR_T0SZ | TCR_T1SZ | TCR_TG0_4K | TCR_TG1_4K | TCR_IPS_44BIT;
-            asm!("msr tcr_el1, {}", in(reg) tcr);
-            
-            // Set TTBR0_EL1 (Translation Table Base Register 0)
-            let ttbr0 = vmm.root_table_addr();
-            asm!("msr ttbr0_el1, {}", in(reg) ttbr0);
-            
-            // Set TTBR1_EL1 to same value (for higher half)
-            asm!("msr ttbr1_el1, {}", in(reg) ttbr0);
-            
-            // Instruction synchronization barrier
-            asm!("isb");
+        // Every register block the device tree describes outside RAM
+        let mut device_regions = 0;
+        if let Some(dt) = dt {
+            let in_ram = |base: u64| ram.iter().any(|r| base >= r.start && base < r.start + r.size);
+            for node in dt.nodes() {
+                if node.name().starts_with("memory") || !node.is_enabled() {
+                    continue;
+                }
+                let mut index = 0;
+                while let Some((base, size)) = node.reg(index) {
+                    index += 1;
+                    if size == 0 || in_ram(base) {
+                        continue;
+                    }
+                    map_linear(vmm, base, size, DEVICE_FLAGS)?;
+                    device_regions += 1;
+                }
+            }
         }
+        
+        crate::println!("MMU: Kernel mappings prepared ({} device regions)", device_regions);
+        Ok(())
     }
     
-    fn enable_mmu() {
-        crate::println!("MMU: Enabling MMU...");
+    fn switch_tables(kernel: &VirtualMemoryManager, user: &VirtualMemoryManager) {
+        // Writable memory is never executable; not in SCTLR_SET, since
+        // boot.s runs from writable blocks
+        const SCTLR_WXN: u64 = 1 << 19;
+        
+        crate::println!("MMU: Switching to kernel page tables...");
         
         unsafe {
-            // Read current SCTLR_EL1
-            let mut sctlr: u64;
-            asm!("mrs {}, sctlr_el1", out(reg) sctlr);
-            
-            // Enable MMU (M bit), data cache (C bit), instruction cache (I bit)
-            sctlr |= (1 << 0) | (1 << 2) | (1 << 12);
-            
-            // Disable alignment checking (A bit)
-            sctlr &= !(1 << 1);
+            // Tables must be visible to the walker before use
+            asm!("dsb ish");
+            asm!("msr ttbr1_el1, {}", in(reg) kernel.root_table_addr());
+            asm!("msr ttbr0_el1, {}", in(reg) user.ttbr());
+            asm!("isb");
             
-            // Write back SCTLR_EL1
-            asm!("msr sctlr_el1, {}", in(reg) sctlr);
+            // The new tables are W^X; have the hardware enforce it too
+            let sctlr: u64;
+            asm!("mrs {}, sctlr_el1", out(reg) sctlr);
+            asm!("msr sctlr_el1, {}", in(reg) sctlr | SCTLR_WXN);
+            asm!("isb");
             
-            // Instruction synchronization barrier
+            // Drop everything cached from the boot tables (and WXN with them)
+            asm!("tlbi vmalle1");
+            asm!("dsb ish");
             asm!("isb");
         }
     }
     
     // Get current virtual memory manager
     pub fn current_vmm() -> Option<&'static mut VirtualMemoryManager> {
-        unsafe { KERNEL_VMM.as_mut() }
+        unsafe { (*core::ptr::addr_of_mut!(KERNEL_VMM)).as_mut() }
     }
     
-    // Map a virtual page to physical page
-    pub fn map_page(virt_addr: VirtAddr, phys_addr: PhysAddr, flags: PageFlags) -> Result<(), &'static str> {
-        if let Some(vmm) = Self::current_vmm() {
-            vmm.map_page(virt_addr, phys_addr, flags)
-        } else {
-            Err("MMU not initialized")
-        }
+    /// The TTBR0 tables, empty until user space maps something.
+    pub fn user_vmm() -> Option<&'static mut VirtualMemoryManager> {
+        unsafe { (*core::ptr::addr_of_mut!(USER_VMM)).as_mut() }
     }
     
-    // Unmap a virtual page
-    pub fn unmap_page(virt_addr: VirtAddr) -> Result<PhysAddr, &'static str> {
-        if let Some(vmm) = Self::current_vmm() {
-            vmm.unmap_page(virt_addr)
-        } else {
-            Err("MMU not initialized")
-        }
+    pub fn is_enabled() -> bool {
+        Self::current_vmm().is_some()
     }
     
     // Translate virtual to physical address
@@ -145,20 +260,161 @@ impl MemoryManagementUnit {
     
     // Flush TLB (Translation Lookaside Buffer)
     pub fn flush_tlb() {
+        tlb::flush_all();
+    }
+}
+
+/// The kernel image, `[start, end)`, at its linked addresses.
+pub fn kernel_image() -> (VirtAddr, VirtAddr) {
+    unsafe { (&__kernel_start as *const u8 as u64, &__kernel_end as *const u8 as u64) }
+}
+
+/// The kernel's text, `[start, end)`.
+pub fn kernel_text() -> (VirtAddr, VirtAddr) {
+    (kernel_image().0, unsafe { &__text_end as *const u8 as u64 })
+}
+
+/// Read-only data after the text (.rodata, .ksyms, .eh_frame), `[start, end)`.
+pub fn kernel_rodata() -> (VirtAddr, VirtAddr) {
+    unsafe { (&__text_end as *const u8 as u64, &__rodata_end as *const u8 as u64) }
+}
+
+/// Whether `[addr, addr + len)` lies inside the kernel's text.
+pub fn is_kernel_text(addr: VirtAddr, len: usize) -> bool {
+    let (start, end) = kernel_text();
+    addr >= start && addr.checked_add(len as u64).is_some_and(|last| last <= end)
+}
+
+/// Write `bytes` over kernel text at `addr`. Text is read-only, so each
+/// page is written through a temporary writable, non-executable alias of
+/// its frame. The caller makes the new instructions visible to fetches.
+pub fn write_kernel_text(addr: VirtAddr, bytes: &[u8]) -> Result<(), &'static str> {
+    if !is_kernel_text(addr, bytes.len()) {
+        return Err("Not kernel text");
+    }
+    let written = poke_text(addr, bytes);
+    // Deliberate, so not something for the integrity check to report
+    crate::memory::textcheck::rebaseline(addr, bytes.len());
+    written
+}
+
+fn poke_text(addr: VirtAddr, bytes: &[u8]) -> Result<(), &'static str> {
+    let Some(vmm) = MemoryManagementUnit::current_vmm() else {
+        // With the MMU off text is ordinary memory
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), addr as *mut u8, bytes.len()) };
+        return Ok(());
+    };
+    
+    let _poke = TEXT_POKE.lock();
+    let page = PAGE_SIZE as u64;
+    let mut done = 0;
+    while done < bytes.len() {
+        let target = addr + done as u64;
+        let offset = target & (page - 1);
+        let chunk = (bytes.len() - done).min((page - offset) as usize);
+        let phys = vmm.translate(target - offset).ok_or("Text not mapped")?;
+        vmm.map_page(TEXT_POKE_ADDR, phys, RAM_FLAGS)?;
         unsafe {
-            asm!("tlbi vmalle1is");  // Invalidate all TLB entries
-            asm!("dsb ish");         // Data synchronization barrier
-            asm!("isb");             // Instruction synchronization barrier
+            core::ptr::copy_nonoverlapping(bytes[done..].as_ptr(), (TEXT_POKE_ADDR + offset) as *mut u8, chunk);
         }
+        // Flushes the alias, so the next page cannot hit a stale entry
+        vmm.unmap_page(TEXT_POKE_ADDR)?;
+        done += chunk;
     }
+    Ok(())
+}
+
+/// Report every leaf entry in `vmm` that is both writable and executable
+/// (see `PageFlags::is_writable_executable`) and return how many there
+/// are. `base` is added to the addresses printed: `KERNEL_VIRT_OFFSET`
+/// for TTBR1 tables, 0 for user tables.
+pub fn audit_wx(vmm: &VirtualMemoryManager, base: VirtAddr) -> usize {
+    let mut violations = 0;
+    vmm.for_each_leaf(|virt, size, entry| {
+        if !entry.flags().is_writable_executable() {
+            return;
+        }
+        if violations < WX_REPORT_MAX {
+            crate::println!("MMU: W^X violation at 0x{:016x} ({} KiB, flags {:?})",
+                           base | virt, size / 1024, entry.flags());
+        }
+        violations += 1;
+    });
+    violations
+}
+
+// Map part of the kernel image, [start, end), where it runs.
+fn map_image(vmm: &mut VirtualMemoryManager, start: VirtAddr, end: VirtAddr, flags: PageFlags) -> Result<(), &'static str> {
+    let page = PAGE_SIZE as u64;
+    let mut virt = start & !(page - 1);
+    while virt < end {
+        vmm.map_page(virt, virt_to_phys(virt), flags)?;
+        virt += page;
+    }
+    Ok(())
+}
+
+// Map physical [base, base + size) at its linear-map address, using 2MB
+// blocks where alignment allows. Pages already mapped are left as they are.
+fn map_linear(vmm: &mut VirtualMemoryManager, base: PhysAddr, size: u64, flags: PageFlags) -> Result<(), &'static str> {
+    let page = PAGE_SIZE as u64;
+    let mut addr = base & !(page - 1);
+    let end = (base + size + page - 1) & !(page - 1);
     
-    // Flush TLB for specific virtual address
-    pub fn flush_tlb_page(virt_addr: VirtAddr) {
-        unsafe {
-            let page_addr = virt_addr >> 12;  // Convert to page number
-            asm!("tlbi vae1is, {}", in(reg) page_addr);
-            asm!("dsb ish");
-            asm!("isb");
+    while addr < end {
+        let virt = phys_to_virt(addr);
+        if addr.is_multiple_of(BLOCK_SIZE_2M) && end - addr >= BLOCK_SIZE_2M
+            && vmm.map_block_2m(virt, addr, flags).is_ok()
+        {
+            addr += BLOCK_SIZE_2M;
+            continue;
+        }
+        if vmm.translate(virt).is_none() {
+            vmm.map_page(virt, addr, flags)?;
+        }
+        addr += page;
+    }
+    Ok(())
+}
+
+/// Map an MMIO range as device memory at its linear-map address
+/// (no-op with the MMU off). Use `phys_to_virt` to access it.
+pub fn map_device(base: PhysAddr, size: u64) -> Result<(), &'static str> {
+    match MemoryManagementUnit::current_vmm() {
+        Some(vmm) => {
+            map_linear(vmm, base, size, DEVICE_FLAGS)?;
+            // Only invalid entries changed, but stale negative walks may be cached
+            MemoryManagementUnit::flush_tlb();
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Back [virt, virt + size) with freshly allocated frames as kernel RAM.
+pub fn map_kernel_range(virt: VirtAddr, size: u64) -> Result<(), &'static str> {
+    let vmm = MemoryManagementUnit::current_vmm().ok_or("MMU not initialized")?;
+    let mut offset = 0;
+    while offset < size {
+        let frame = crate::memory::frame_allocator::allocate_frame().ok_or("Out of frames")?;
+        vmm.map_page(virt + offset, virt_to_phys(frame.as_ptr() as u64), RAM_FLAGS)?;
+        offset += PAGE_SIZE as u64;
+    }
+    MemoryManagementUnit::flush_tlb();
+    Ok(())
+}
+
+/// Undo `map_kernel_range`, freeing the frames; pages in the range that
+/// were never mapped are skipped.
+pub fn unmap_kernel_range(virt: VirtAddr, size: u64) {
+    let Some(vmm) = MemoryManagementUnit::current_vmm() else { return };
+    let mut offset = 0;
+    while offset < size {
+        if let Ok(phys) = vmm.unmap_page(virt + offset) {
+            if let Some(frame) = core::ptr::NonNull::new(phys_to_virt(phys) as *mut u8) {
+                crate::memory::frame_allocator::deallocate_frame(frame);
+            }
         }
+        offset += PAGE_SIZE as u64;
     }
 }
@@ -1,10 +1,17 @@
-pub mod allocator;
 pub mod paging;
 pub mod frame_allocator;
+pub mod memtest;
 pub mod mmu;
+pub mod rmap;
+pub mod tlb;
+pub mod asid;
+pub mod textcheck;
+pub mod kaslr;
+pub mod compaction;
+pub mod ksm;
 pub mod test;
 
-use crate::devicetree::parse_device_tree;
+use crate::devicetree::{device_tree, MemoryRegion};
 use frame_allocator::init_frame_allocator;
 
 /// Initialize memory management subsystem
@@ -12,61 +19,104 @@ pub fn init() {
     crate::println!("Initializing memory management...");
     
     // Parse device tree to discover memory regions
-    let fdt_addr = 0x40000000 as *const u8; // QEMU default FDT location
-    if let Some(dt) = parse_device_tree(fdt_addr) {
-        // Extract non-None memory regions into a fixed array
-        let mut memory_regions = [None; 8];
-        let mut region_count = 0;
-        
-        for region in dt.memory_regions() {
-            if let Some(mem_region) = region {
-                memory_regions[region_count] = Some(*mem_region);
-                region_count += 1;
-            }
-        }
-        
-        if region_count > 0 {
-            // Use the first valid memory region found
-            if let Some(first_region) = memory_regions[0] {
-                // Initialize physical frame allocator with the first region
-                init_frame_allocator(&[first_region]);
-                
-                // Get frame allocator statistics
-                let (free, total) = frame_allocator::frame_allocator_stats();
-                crate::println!("Memory: Physical frame allocator ready ({} free / {} total frames)", 
-                               free, total);
-                
-                // Initialize MMU (for now, skip to avoid complexity)
-                // TODO: Enable MMU initialization once we handle identity mapping properly
-                // if let Err(e) = MemoryManagementUnit::init() {
-                //     crate::println!("Memory: MMU initialization failed: {}", e);
-                // }
-                
-                crate::println!("Memory: Virtual memory management ready");
-            } else {
-                crate::println!("Memory: Warning - Invalid memory region found");
-            }
-        } else {
-            crate::println!("Memory: Warning - No memory regions found");
+    let dt = device_tree();
+    
+    // Extract non-None memory regions into a fixed array
+    let mut memory_regions = [MemoryRegion { start: 0, size: 0 }; 8];
+    let mut region_count = 0;
+    if let Some(dt) = &dt {
+        for mem_region in dt.memory_regions().iter().flatten() {
+            memory_regions[region_count] = *mem_region;
+            region_count += 1;
         }
-    } else {
+    }
+    
+    if region_count == 0 {
         crate::println!("Memory: Warning - Using fallback memory configuration");
         
-        // Fallback: assume 1GB of RAM starting at 0x40000000
-        use crate::devicetree::MemoryRegion;
-        let fallback_region = MemoryRegion {
-            start: 0x40000000,
-            size: 1024 * 1024 * 1024,  // 1GB
-        };
-        init_frame_allocator(&[fallback_region]);
-        
-        let (free, total) = frame_allocator::frame_allocator_stats();
-        crate::println!("Memory: Fallback frame allocator ready ({} free / {} total frames)", 
-                       free, total);
+        memory_regions[0] = crate::board::BUILD.fallback_ram;
+        region_count = 1;
     }
+    let ram = &memory_regions[..region_count];
     
-    // Run memory tests to verify functionality
-    test::run_memory_tests();
+    // Initialize physical frame allocator with the first region
+    init_frame_allocator(&ram[..1]);
+    
+    // Keep the previous boot's log away from the allocator (and memtest)
+    crate::pstore::reserve(dt.as_ref(), &ram[..1]);
+    crate::initramfs::reserve(dt.as_ref(), &ram[..1]);
+    crate::acpi::reserve(dt.as_ref(), &ram[..1]);
+    reserve_boot_fdt();
+    
+    // Optional RAM test before any frame is handed out
+    if let Some(config) = memtest::config() {
+        memtest::run(config);
+    }
+    
+    // Get frame allocator statistics
+    let (free, total) = frame_allocator::frame_allocator_stats();
+    crate::println!("Memory: Physical frame allocator ready ({} free / {} total frames)", 
+                   free, total);
+    
+    // Identity map RAM and MMIO, then turn on translation
+    #[cfg(not(feature = "no-mmu"))]
+    {
+        if let Err(e) = mmu::MemoryManagementUnit::init(ram, dt.as_ref()) {
+            panic!("Memory: MMU initialization failed: {}", e);
+        }
+        crate::println!("Memory: Virtual memory management ready");
+    }
+    #[cfg(feature = "no-mmu")]
+    crate::println!("Memory: MMU disabled (no-mmu), running on physical addresses");
+    
+    init_heap();
+    crate::pstore::init();
+    crate::initramfs::init();
+    ksm::init();
+    asid::init();
+    textcheck::init();
+    frame_allocator::start_prezeroing();
     
     crate::println!("Memory: Memory management system initialized");
 }
+
+// The blob the loader passed stays in use: device_tree() parses it again
+// on every call.
+fn reserve_boot_fdt() {
+    let Some((phys, size)) = crate::devicetree::boot_blob() else { return };
+    let page = frame_allocator::PAGE_SIZE as u64;
+    let mut addr = phys & !(page - 1);
+    while addr < phys + size as u64 {
+        if let Some(frame) = core::ptr::NonNull::new(paging::phys_to_virt(addr) as *mut u8) {
+            frame_allocator::claim_frame(frame);
+        }
+        addr += page;
+    }
+}
+
+// The heap lives at a fixed virtual address with the MMU on; without it,
+// a physically contiguous run of frames is used directly.
+fn init_heap() {
+    use crate::allocator::{HEAP_SIZE, HEAP_START};
+    
+    #[cfg(not(feature = "no-mmu"))]
+    let heap_start = {
+        if let Err(e) = mmu::map_kernel_range(HEAP_START as u64, HEAP_SIZE as u64) {
+            panic!("Memory: Failed to map kernel heap: {}", e);
+        }
+        HEAP_START
+    };
+    #[cfg(feature = "no-mmu")]
+    let heap_start = {
+        let _ = HEAP_START;
+        let pages = HEAP_SIZE.div_ceil(frame_allocator::PAGE_SIZE);
+        match frame_allocator::allocate_frames(pages) {
+            Some(frames) => frames.as_ptr() as usize,
+            None => panic!("Memory: No frames for kernel heap"),
+        }
+    };
+    
+    crate::allocator::init_heap(heap_start, HEAP_SIZE);
+    crate::println!("Memory: Heap allocator initialized at 0x{:x} ({} KiB)",
+                   heap_start, HEAP_SIZE / 1024);
+}
@@ -1,7 +1,12 @@
 // ARM64 paging implementation using 4-level page tables
 
+use core::ptr::NonNull;
 use bitflags::bitflags;
-use crate::memory::frame_allocator::allocate_frame;
+use crate::memory::frame_allocator::{allocate_frame, deallocate_frame, frame_get, frame_put, PAGE_SIZE};
+use crate::memory::rmap;
+use crate::memory::asid::{self, SpaceId};
+use crate::memory::kaslr;
+use crate::memory::tlb;
 
 // Virtual address type
 pub type VirtAddr = u64;
@@ -9,25 +14,74 @@ pub type VirtAddr = u64;
 // Physical address type  
 pub type PhysAddr = u64;
 
+// All RAM and MMIO are mapped at physical + KERNEL_VIRT_OFFSET (the linear
+// map in TTBR1), and the kernel image is linked there, though it usually
+// runs elsewhere (kaslr.rs). Must match build.rs.
+#[cfg(not(feature = "no-mmu"))]
+pub const KERNEL_VIRT_OFFSET: u64 = 0xFFFF_0000_0000_0000;
+#[cfg(feature = "no-mmu")]
+pub const KERNEL_VIRT_OFFSET: u64 = 0;
+
+/// Kernel virtual address of a physical address (linear map).
+pub const fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    phys + KERNEL_VIRT_OFFSET
+}
+
+/// Physical address of a linear-map or kernel image address. Not valid for
+/// other kernel mappings such as the heap.
+pub fn virt_to_phys(virt: VirtAddr) -> PhysAddr {
+    if kaslr::in_region(virt) {
+        return virt - kaslr::offset() - KERNEL_VIRT_OFFSET;
+    }
+    virt - KERNEL_VIRT_OFFSET
+}
+
 // ARM64 page table entry
 #[derive(Clone, Copy, Debug)]
 #[repr(transparent)]
 pub struct PageTableEntry(u64);
 
+// Bytes mapped by one level 2 block descriptor
+pub const BLOCK_SIZE_2M: u64 = 2 * 1024 * 1024;
+
 bitflags! {
     /// ARM64 page table entry flags
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct PageFlags: u64 {
         const VALID = 1 << 0;
         const TABLE = 1 << 1;  // For intermediate levels
-        const PAGE = 0 << 1;   // For final level (page)
+        const PAGE = 1 << 1;   // For final level (page); blocks leave it clear
         const USER = 1 << 6;   // User accessible
         const READ_WRITE = 0 << 7;  // Read-write (default)
         const READ_ONLY = 1 << 7;   // Read-only
         const INNER_SHAREABLE = 3 << 8;
         const OUTER_SHAREABLE = 2 << 8;
         const NON_SHAREABLE = 0 << 8;
-        const NORMAL_MEMORY = 0 << 2;  // Normal memory
-        const DEVICE_MEMORY = 1 << 2;  // Device memory
+        const ACCESSED = 1 << 10;      // Access flag; clear faults on first use
+        const NOT_GLOBAL = 1 << 11;    // Tagged with the ASID (user mappings)
+        const PXN = 1 << 53;           // Privileged execute never
+        const UXN = 1 << 54;           // Unprivileged execute never
+        // Software bits. A copy-on-write page is read-only in hardware;
+        // COW_WRITE records that a write may copy it and make it writable
+        const COW = 1 << 55;
+        const COW_WRITE = 1 << 56;
+        // Memory types: AttrIndx[4:2] selects a MAIR_EL1 slot (see mmu.rs)
+        const DEVICE_MEMORY = 0 << 2;  // Device-nGnRnE
+        const NORMAL_NC = 1 << 2;      // Normal, non-cacheable
+        const NORMAL_MEMORY = 2 << 2;  // Normal, write-back cacheable
+    }
+}
+
+impl PageFlags {
+    /// Whether memory with these attributes can be both written and
+    /// executed, at any exception level. A copy-on-write page counts as
+    /// writable. Memory EL0 can write is never executable at EL1, whatever
+    /// PXN says.
+    pub fn is_writable_executable(self) -> bool {
+        let writable = !self.contains(PageFlags::READ_ONLY) || self.contains(PageFlags::COW_WRITE);
+        let user_exec = !self.contains(PageFlags::UXN);
+        let kernel_exec = !self.contains(PageFlags::PXN) && !self.contains(PageFlags::USER);
+        writable && (user_exec || kernel_exec)
     }
 }
 
@@ -46,6 +100,11 @@ impl PageTableEntry {
         (self.0 & PageFlags::VALID.bits()) != 0
     }
     
+    // Above level 3, a valid entry is either a table or a block
+    pub fn is_table(&self) -> bool {
+        self.is_valid() && (self.0 & PageFlags::TABLE.bits()) != 0
+    }
+    
     pub fn physical_addr(&self) -> PhysAddr {
         self.0 & 0x0000FFFFFFFFF000  // Extract physical address
     }
@@ -54,9 +113,14 @@ impl PageTableEntry {
         PageFlags::from_bits_truncate(self.0)
     }
     
-    pub fn set_addr(&mut self, addr: PhysAddr, flags: PageFlags) {
-        let aligned_addr = addr & !0xFFF;
-        self.0 = aligned_addr | flags.bits();
+    /// Same frame with different attributes; bits PageFlags does not name are kept.
+    pub fn with_flags(&self, flags: PageFlags) -> Self {
+        Self((self.0 & !PageFlags::all().bits()) | flags.bits())
+    }
+    
+    /// Same entry pointing at another frame; every attribute bit is kept.
+    pub fn with_addr(&self, addr: PhysAddr) -> Self {
+        Self((self.0 & !0x0000FFFFFFFFF000) | (addr & 0x0000FFFFFFFFF000))
     }
 }
 
@@ -67,12 +131,6 @@ pub struct PageTable {
 }
 
 impl PageTable {
-    pub fn new() -> Self {
-        Self {
-            entries: [PageTableEntry::empty(); 512],
-        }
-    }
-    
     pub fn zero(&mut self) {
         for entry in self.entries.iter_mut() {
             *entry = PageTableEntry::empty();
@@ -90,8 +148,8 @@ impl PageTable {
     // Get page table entry for next level
     pub fn get_next_table(&self, index: usize) -> Option<&'static mut PageTable> {
         if let Some(entry) = self.get_entry(index) {
-            if entry.is_valid() {
-                let addr = entry.physical_addr();
+            if entry.is_table() {
+                let addr = phys_to_virt(entry.physical_addr());
                 return Some(unsafe { &mut *(addr as *mut PageTable) });
             }
         }
@@ -101,16 +159,14 @@ impl PageTable {
     // Create next level page table
     pub fn create_next_table(&mut self, index: usize) -> Option<&'static mut PageTable> {
         if let Some(frame) = allocate_frame() {
-            let table_addr = frame.as_ptr() as u64;
-            
             // Initialize new page table
-            let new_table = unsafe { &mut *(table_addr as *mut PageTable) };
+            let new_table = unsafe { &mut *(frame.as_ptr() as *mut PageTable) };
             new_table.zero();
             
             // Set entry to point to new table
             if let Some(entry) = self.get_entry_mut(index) {
                 *entry = PageTableEntry::new(
-                    table_addr,
+                    virt_to_phys(frame.as_ptr() as u64),
                     PageFlags::VALID | PageFlags::TABLE
                 );
                 return Some(new_table);
@@ -123,34 +179,178 @@ impl PageTable {
 // Virtual memory manager
 pub struct VirtualMemoryManager {
     root_table: &'static mut PageTable,
+    // None for the kernel's global mappings
+    space: Option<SpaceId>,
 }
 
+// TTBR_EL1 fields; ttbr() puts the space id where the ASID goes
+const TTBR_ASID_SHIFT: u64 = 48;
+const TTBR_BADDR_MASK: u64 = 0x0000_FFFF_FFFF_FFFE;
+
 impl VirtualMemoryManager {
     pub fn new() -> Option<Self> {
         // Allocate root page table
         if let Some(frame) = allocate_frame() {
-            let root_addr = frame.as_ptr() as u64;
-            let root_table = unsafe { &mut *(root_addr as *mut PageTable) };
+            let root_table = unsafe { &mut *(frame.as_ptr() as *mut PageTable) };
             root_table.zero();
             
-            return Some(Self { root_table });
+            return Some(Self { root_table, space: None });
         }
         None
     }
     
+    /// Tables for a user address space, with non-global mappings tagged
+    /// by whichever ASID it is given when it runs.
+    pub fn new_user() -> Option<Self> {
+        let space = asid::allocate_space()?;
+        match Self::new() {
+            Some(mut vmm) => {
+                vmm.space = Some(space);
+                Some(vmm)
+            }
+            None => {
+                asid::free_space(space);
+                None
+            }
+        }
+    }
+    
+    /// View of existing tables from a `ttbr()` value.
+    ///
+    /// # Safety
+    /// The tables must stay allocated while the view is used, and nothing
+    /// else may modify them concurrently.
+    pub unsafe fn from_root(ttbr: u64) -> Self {
+        let root = ttbr & TTBR_BADDR_MASK;
+        let space = (ttbr >> TTBR_ASID_SHIFT) as SpaceId;
+        Self {
+            root_table: &mut *(phys_to_virt(root) as *mut PageTable),
+            space: if space != 0 { Some(space) } else { None },
+        }
+    }
+    
+    pub fn space_id(&self) -> Option<SpaceId> {
+        self.space
+    }
+    
+    /// Root table address plus space id, laid out like a TTBR. Identifies
+    /// the address space in the reverse map; what asid::switch_to loads
+    /// carries the hardware ASID instead.
+    pub fn ttbr(&self) -> u64 {
+        self.root_table_addr() | (self.space.unwrap_or(0) as u64) << TTBR_ASID_SHIFT
+    }
+    
+    /// Load these tables into TTBR0 on this CPU.
+    pub fn activate(&self) {
+        asid::switch_to(self);
+    }
+    
+    // User mappings are per-ASID, kernel ones shared by all
+    fn leaf_flags(&self, flags: PageFlags) -> PageFlags {
+        if self.space.is_some() {
+            flags | PageFlags::NOT_GLOBAL
+        } else {
+            flags
+        }
+    }
+    
+    /// Invalidate any cached translation of `virt_addr` on every CPU.
+    pub fn flush_page(&self, virt_addr: VirtAddr) {
+        match self.space {
+            // Never run, so nothing cached
+            Some(space) => if let Some(asid) = asid::hardware_asid(space) {
+                tlb::flush_page(Some(asid), virt_addr);
+            },
+            None => tlb::flush_page(None, virt_addr),
+        }
+    }
+    
+    /// Invalidate every cached translation of this address space.
+    pub fn flush_all(&self) {
+        match self.space {
+            Some(space) => if let Some(asid) = asid::hardware_asid(space) {
+                tlb::flush_asid(asid);
+            },
+            None => tlb::flush_all(),
+        }
+    }
+    
+    /// Tear down a user address space: every frame mapped with map_frame
+    /// drops its reference, and every table frame goes back to the
+    /// allocator. Frames mapped with map_page are borrowed and left alone,
+    /// as are block mappings. The tables are unloaded from this CPU's
+    /// TTBR0 and must not be live on any other. Returns how many frames
+    /// were freed.
+    pub fn destroy(self) -> usize {
+        // Nothing can walk the tables once they are out of TTBR0, but the
+        // TLB may still hold translations into frames about to be reused
+        if let Some(space) = self.space {
+            asid::unload(space);
+        }
+        self.flush_all();
+        
+        let ttbr = self.ttbr();
+        let root = self.root_table as *mut PageTable;
+        let freed = unsafe {
+            Self::free_table(&mut *root, 0, 0, ttbr) + Self::free_table_frame(root)
+        };
+        if let Some(space) = self.space {
+            asid::free_space(space);
+        }
+        freed
+    }
+    
+    // Free everything below `table` (a level `level` table mapping from `base`)
+    unsafe fn free_table(table: &mut PageTable, level: usize, base: VirtAddr, ttbr: u64) -> usize {
+        // Bytes covered by one entry: 512GB at level 0 down to 4KB at level 3
+        let span = 1u64 << (39 - 9 * level);
+        let mut freed = 0;
+        
+        for (index, entry) in table.entries.iter_mut().enumerate() {
+            if !entry.is_valid() {
+                continue;
+            }
+            let virt = base + index as u64 * span;
+            
+            if level < 3 && entry.is_table() {
+                let next = phys_to_virt(entry.physical_addr()) as *mut PageTable;
+                freed += Self::free_table(&mut *next, level + 1, virt, ttbr);
+                freed += Self::free_table_frame(next);
+            } else if level == 3 {
+                let phys = entry.physical_addr();
+                let owned = rmap::remove(phys, ttbr, virt);
+                if let Some(frame) = NonNull::new(phys_to_virt(phys) as *mut u8).filter(|_| owned) {
+                    if frame_put(frame) == Ok(0) {
+                        freed += 1;
+                    }
+                }
+            }
+            *entry = PageTableEntry::empty();
+        }
+        freed
+    }
+    
+    unsafe fn free_table_frame(table: *mut PageTable) -> usize {
+        match NonNull::new(table as *mut u8) {
+            Some(frame) => {
+                deallocate_frame(frame);
+                1
+            }
+            None => 0,
+        }
+    }
+    
     // Map a virtual page to a physical frame
     pub fn map_page(&mut self, virt_addr: VirtAddr, phys_addr: PhysAddr, flags: PageFlags) -> Result<(), &'static str> {
         let indices = self.get_page_table_indices(virt_addr);
+        let flags = self.leaf_flags(flags);
         
         // Walk through page table levels
         let mut current_table = &mut *self.root_table;
         
         // L0 -> L1 -> L2 (intermediate levels)
         for &index in &indices[0..3] {
-            current_table = match current_table.get_next_table(index) {
-                Some(table) => table,
-                None => current_table.create_next_table(index).ok_or("Failed to create page table")?,
-            };
+            current_table = Self::next_table_or_create(current_table, index)?;
         }
         
         // L3 - final level (actual page mapping)
@@ -159,13 +359,71 @@ impl VirtualMemoryManager {
             if entry.is_valid() {
                 return Err("Page already mapped");
             }
-            *entry = PageTableEntry::new(phys_addr, flags | PageFlags::VALID);
+            *entry = PageTableEntry::new(phys_addr, flags | PageFlags::VALID | PageFlags::PAGE);
             Ok(())
         } else {
             Err("Invalid page table index")
         }
     }
     
+    /// Map a 2MB block with a single level 2 descriptor.
+    pub fn map_block_2m(&mut self, virt_addr: VirtAddr, phys_addr: PhysAddr, flags: PageFlags) -> Result<(), &'static str> {
+        if !virt_addr.is_multiple_of(BLOCK_SIZE_2M) || !phys_addr.is_multiple_of(BLOCK_SIZE_2M) {
+            return Err("Block mapping not 2MB aligned");
+        }
+        let indices = self.get_page_table_indices(virt_addr);
+        let flags = self.leaf_flags(flags);
+        
+        let mut current_table = &mut *self.root_table;
+        for &index in &indices[0..2] {
+            current_table = Self::next_table_or_create(current_table, index)?;
+        }
+        
+        let entry = current_table.get_entry_mut(indices[2]).ok_or("Invalid page table index")?;
+        if entry.is_valid() {
+            return Err("Block already mapped");
+        }
+        // Block descriptor: bit 1 clear
+        *entry = PageTableEntry::new(phys_addr, (flags - PageFlags::TABLE) | PageFlags::VALID);
+        Ok(())
+    }
+    
+    // Descend into a table, creating it if absent; a block is in the way
+    fn next_table_or_create(table: &mut PageTable, index: usize) -> Result<&'static mut PageTable, &'static str> {
+        let entry = table.get_entry(index).ok_or("Invalid page table index")?;
+        if entry.is_table() {
+            table.get_next_table(index).ok_or("Invalid page table")
+        } else if entry.is_valid() {
+            Err("Address covered by a block mapping")
+        } else {
+            table.create_next_table(index).ok_or("Failed to create page table")
+        }
+    }
+    
+    /// Map an allocator frame (by its linear-map pointer), taking a frame
+    /// reference and recording the mapping in the reverse map.
+    pub fn map_frame(&mut self, virt_addr: VirtAddr, frame: NonNull<u8>, flags: PageFlags) -> Result<(), &'static str> {
+        let phys = virt_to_phys(frame.as_ptr() as u64);
+        frame_get(frame)?;
+        if let Err(e) = self.map_page(virt_addr, phys, flags) {
+            let _ = frame_put(frame);
+            return Err(e);
+        }
+        rmap::add(phys, self.ttbr(), virt_addr);
+        Ok(())
+    }
+    
+    /// Undo `map_frame`, dropping its frame reference.
+    pub fn unmap_frame(&mut self, virt_addr: VirtAddr) -> Result<PhysAddr, &'static str> {
+        let phys = self.unmap_page(virt_addr)?;
+        if rmap::remove(phys, self.ttbr(), virt_addr) {
+            if let Some(frame) = NonNull::new(phys_to_virt(phys) as *mut u8) {
+                frame_put(frame)?;
+            }
+        }
+        Ok(phys)
+    }
+    
     // Unmap a virtual page
     pub fn unmap_page(&mut self, virt_addr: VirtAddr) -> Result<PhysAddr, &'static str> {
         let indices = self.get_page_table_indices(virt_addr);
@@ -186,8 +444,7 @@ impl VirtualMemoryManager {
             
             let phys_addr = entry.physical_addr();
             *entry = PageTableEntry::empty();
-            
-            // TODO: TLB invalidation
+            self.flush_page(virt_addr);
             
             Ok(phys_addr)
         } else {
@@ -195,6 +452,133 @@ impl VirtualMemoryManager {
         }
     }
     
+    /// Change the permissions of a mapped page, keeping its frame and
+    /// memory type. `flags` replaces the access and execute-never bits.
+    pub fn protect_page(&mut self, virt_addr: VirtAddr, flags: PageFlags) -> Result<(), &'static str> {
+        self.protect_range(virt_addr, 1, flags)
+    }
+    
+    /// mprotect: rewrite the permissions of every page overlapping
+    /// [virt_addr, virt_addr + len). Either all pages change or, if any is
+    /// unmapped, none do. Only 4KB mappings are handled.
+    pub fn protect_range(&mut self, virt_addr: VirtAddr, len: u64, flags: PageFlags) -> Result<(), &'static str> {
+        const PROTECTION: PageFlags = PageFlags::USER
+            .union(PageFlags::READ_ONLY)
+            .union(PageFlags::PXN)
+            .union(PageFlags::UXN);
+        // Past this many pages one ASID-wide flush is cheaper
+        const FLUSH_PAGES_MAX: u64 = 32;
+        
+        let page_size = PAGE_SIZE as u64;
+        let start = virt_addr & !(page_size - 1);
+        let end = virt_addr.checked_add(len).ok_or("Range wraps")?.div_ceil(page_size) * page_size;
+        let pages = (end - start) / page_size;
+        
+        // W^X: a mapping may be writable or executable, never both
+        if (flags & PROTECTION).is_writable_executable() {
+            return Err("Writable and executable");
+        }
+        
+        // Check first so a hole leaves the range untouched
+        for page in 0..pages {
+            if !self.leaf_entry(start + page * page_size).is_some_and(|entry| entry.is_valid()) {
+                return Err("Page not mapped");
+            }
+        }
+        
+        for page in 0..pages {
+            if let Some(entry) = self.leaf_entry(start + page * page_size) {
+                let mut new = (entry.flags() - PROTECTION - PageFlags::COW_WRITE) | (flags & PROTECTION);
+                // A copy-on-write frame stays read-only until a write fault copies it
+                if new.contains(PageFlags::COW) && !new.contains(PageFlags::READ_ONLY) {
+                    new |= PageFlags::READ_ONLY | PageFlags::COW_WRITE;
+                }
+                *entry = entry.with_flags(new);
+            }
+        }
+        
+        if pages > FLUSH_PAGES_MAX {
+            self.flush_all();
+        } else {
+            for page in 0..pages {
+                self.flush_page(start + page * page_size);
+            }
+        }
+        Ok(())
+    }
+    
+    /// Call `f` with every valid 4KB page entry, in address order.
+    pub fn for_each_page(&mut self, mut f: impl FnMut(VirtAddr, &mut PageTableEntry)) {
+        unsafe { Self::walk_pages(&mut *self.root_table, 0, 0, &mut f) }
+    }
+    
+    unsafe fn walk_pages(table: &mut PageTable, level: usize, base: VirtAddr, f: &mut impl FnMut(VirtAddr, &mut PageTableEntry)) {
+        let span = 1u64 << (39 - 9 * level);
+        for (index, entry) in table.entries.iter_mut().enumerate() {
+            if !entry.is_valid() {
+                continue;
+            }
+            let virt = base + index as u64 * span;
+            if level < 3 && entry.is_table() {
+                let next = phys_to_virt(entry.physical_addr()) as *mut PageTable;
+                Self::walk_pages(&mut *next, level + 1, virt, f);
+            } else if level == 3 {
+                f(virt, entry);
+            }
+        }
+    }
+    
+    /// Call `f` with every valid leaf entry, block or page, and the number
+    /// of bytes it maps, in address order.
+    pub fn for_each_leaf(&self, mut f: impl FnMut(VirtAddr, u64, &PageTableEntry)) {
+        unsafe { Self::walk_leaves(&*self.root_table, 0, 0, &mut f) }
+    }
+    
+    unsafe fn walk_leaves(table: &PageTable, level: usize, base: VirtAddr, f: &mut impl FnMut(VirtAddr, u64, &PageTableEntry)) {
+        let span = 1u64 << (39 - 9 * level);
+        for (index, entry) in table.entries.iter().enumerate() {
+            if !entry.is_valid() {
+                continue;
+            }
+            let virt = base + index as u64 * span;
+            if level < 3 && entry.is_table() {
+                let next = phys_to_virt(entry.physical_addr()) as *const PageTable;
+                Self::walk_leaves(&*next, level + 1, virt, f);
+            } else {
+                f(virt, span, entry);
+            }
+        }
+    }
+    
+    /// The attributes of the page or block mapping `virt_addr`.
+    pub fn flags_at(&self, virt_addr: VirtAddr) -> Option<PageFlags> {
+        let indices = self.get_page_table_indices(virt_addr);
+        let mut current_table = &*self.root_table;
+        for &index in &indices[0..3] {
+            let entry = current_table.get_entry(index)?;
+            if !entry.is_valid() {
+                return None;
+            }
+            if !entry.is_table() {
+                return Some(entry.flags());
+            }
+            current_table = unsafe { &*(phys_to_virt(entry.physical_addr()) as *const PageTable) };
+        }
+        current_table.get_entry(indices[3]).filter(|entry| entry.is_valid()).map(|entry| entry.flags())
+    }
+    
+    /// The level 3 entry for `virt_addr`, if the tables reach that far.
+    pub fn leaf_entry(&mut self, virt_addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+        let indices = self.get_page_table_indices(virt_addr);
+        let mut current_table = &mut *self.root_table;
+        for &index in &indices[0..3] {
+            current_table = current_table.get_next_table(index)?;
+        }
+        let entry = current_table.get_entry_mut(indices[3])?;
+        // Only the reference into the table outlives the walk
+        Some(unsafe { &mut *(entry as *mut PageTableEntry) })
+    }
+    
     // Get page table indices for 4-level paging
     fn get_page_table_indices(&self, virt_addr: VirtAddr) -> [usize; 4] {
         [
@@ -213,10 +597,21 @@ impl VirtualMemoryManager {
         let mut current_table = &*self.root_table;
         
         // Walk through page table levels
-        for &index in &indices[0..3] {
+        for (level, &index) in indices[0..3].iter().enumerate() {
+            let entry = current_table.get_entry(index)?;
+            if !entry.is_valid() {
+                return None;
+            }
+            
+            // Block descriptor at level 1 (1GB) or 2 (2MB)
+            if !entry.is_table() {
+                let block_size = 1u64 << (39 - 9 * level);
+                return Some((entry.physical_addr() & !(block_size - 1)) + (virt_addr & (block_size - 1)));
+            }
+            
             // For read-only access, we need to be more careful about borrowing
             current_table = unsafe {
-                let addr = current_table.get_entry(index)?.physical_addr();
+                let addr = phys_to_virt(entry.physical_addr());
                 &*(addr as *const PageTable)
             };
         }
@@ -234,6 +629,6 @@ impl VirtualMemoryManager {
     
     // Get root page table physical address for TTBR register
     pub fn root_table_addr(&self) -> PhysAddr {
-        self.root_table as *const _ as u64
+        virt_to_phys(self.root_table as *const _ as u64)
     }
 }
@@ -0,0 +1,90 @@
+// Reverse mapping: which page tables map each frame
+//
+// Every mapping made through `VirtualMemoryManager::map_frame` is recorded
+// here by the address space's root table and virtual address, and holds a
+// frame reference. Swap, migration and copy-on-write use `unmap_all` to
+// take a frame away from every user before reclaiming or replacing it.
+// File-backed frames are tracked the same way as anonymous ones; whoever
+// caches a frame keeps its own reference on top of the mappings.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::memory::frame_allocator::{addr_to_frame, FrameNumber};
+use crate::memory::paging::{PhysAddr, VirtAddr, VirtualMemoryManager};
+
+/// One place a frame is mapped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    // The address space's TTBR value (root table and ASID)
+    pub root: u64,
+    pub virt: VirtAddr,
+}
+
+// Frames with no mappings have no entry
+static RMAP: Mutex<BTreeMap<FrameNumber, Vec<Mapping>>> = Mutex::new(BTreeMap::new());
+
+/// Record that `root` maps `phys` at `virt`.
+pub fn add(phys: PhysAddr, root: u64, virt: VirtAddr) {
+    RMAP.lock()
+        .entry(addr_to_frame(phys))
+        .or_default()
+        .push(Mapping { root, virt });
+}
+
+/// Forget a mapping; false if it was not recorded.
+pub fn remove(phys: PhysAddr, root: u64, virt: VirtAddr) -> bool {
+    let frame = addr_to_frame(phys);
+    let mut rmap = RMAP.lock();
+    let mappings = match rmap.get_mut(&frame) {
+        Some(mappings) => mappings,
+        None => return false,
+    };
+    let found = match mappings.iter().position(|m| *m == Mapping { root, virt }) {
+        Some(index) => {
+            mappings.swap_remove(index);
+            true
+        }
+        None => false,
+    };
+    if mappings.is_empty() {
+        rmap.remove(&frame);
+    }
+    found
+}
+
+/// Every current mapping of the frame at `phys`.
+pub fn mappings(phys: PhysAddr) -> Vec<Mapping> {
+    RMAP.lock()
+        .get(&addr_to_frame(phys))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Number of page tables entries mapping the frame at `phys`.
+pub fn map_count(phys: PhysAddr) -> usize {
+    RMAP.lock().get(&addr_to_frame(phys)).map_or(0, |mappings| mappings.len())
+}
+
+/// Transfer every mapping record of `old` to `new` after migration.
+pub fn move_mappings(old: PhysAddr, new: PhysAddr) {
+    let mut rmap = RMAP.lock();
+    if let Some(mappings) = rmap.remove(&addr_to_frame(old)) {
+        rmap.entry(addr_to_frame(new)).or_default().extend(mappings);
+    }
+}
+
+/// Unmap the frame at `phys` from every address space, dropping the
+/// mappings' frame references. Returns how many mappings were removed.
+pub fn unmap_all(phys: PhysAddr) -> usize {
+    let mut count = 0;
+    for mapping in mappings(phys) {
+        // Each mapping's table is live for as long as it is recorded here
+        let mut space = unsafe { VirtualMemoryManager::from_root(mapping.root) };
+        // unmap_page invalidates the TLB entry
+        if space.unmap_frame(mapping.virt).is_ok() {
+            count += 1;
+        }
+    }
+    count
+}
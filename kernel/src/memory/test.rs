@@ -1,7 +1,13 @@
 // Memory management testing utilities
 
-use crate::memory::frame_allocator::{allocate_frame, deallocate_frame, frame_allocator_stats};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::ktest::kernel_test;
+use crate::memory::frame_allocator::{allocate_frame, allocate_frames, deallocate_frame, deallocate_frames,
+                                     frame_allocator_stats, frame_get, frame_put, frame_refcount, PAGE_SIZE};
 
+#[kernel_test]
 pub fn test_frame_allocation() {
     crate::println!("Memory Test: Testing frame allocation...");
     
@@ -12,9 +18,9 @@ pub fn test_frame_allocation() {
     let mut allocated_frames = [None; 10];
     let mut allocated_count = 0;
     
-    for i in 0..10 {
+    for (i, slot) in allocated_frames.iter_mut().enumerate() {
         if let Some(frame) = allocate_frame() {
-            allocated_frames[i] = Some(frame);
+            *slot = Some(frame);
             allocated_count += 1;
         } else {
             crate::println!("Memory Test: Failed to allocate frame {}", i);
@@ -51,6 +57,7 @@ pub fn test_frame_allocation() {
     crate::println!("Memory Test: Frame allocation test completed");
 }
 
+#[kernel_test]
 pub fn test_heap_allocation() {
     crate::println!("Memory Test: Testing heap allocation...");
     
@@ -81,9 +88,945 @@ pub fn test_heap_allocation() {
     crate::println!("Memory Test: Heap allocation test completed");
 }
 
-pub fn run_memory_tests() {
-    crate::println!("Memory Test: Starting memory management tests...");
-    test_heap_allocation();
-    test_frame_allocation();
-    crate::println!("Memory Test: All memory tests completed");
-}
\ No newline at end of file
+#[kernel_test]
+pub fn test_frame_refcounting() {
+    crate::println!("Memory Test: Testing shared frame reference counts...");
+    
+    let (free_before, _) = frame_allocator_stats();
+    
+    let frame = match allocate_frame() {
+        Some(frame) => frame,
+        None => {
+            crate::println!("Memory Test: ✗ Could not allocate frame for refcount test");
+            return;
+        }
+    };
+    
+    // Share the frame twice more, then drop references one at a time
+    let shared = frame_get(frame) == Ok(2) && frame_get(frame) == Ok(3);
+    let dropped = frame_put(frame) == Ok(2) && frame_put(frame) == Ok(1);
+    let (free_while_shared, _) = frame_allocator_stats();
+    
+    if shared && dropped && free_while_shared == free_before - 1 {
+        crate::println!("Memory Test: ✓ Shared frame stays allocated while referenced");
+    } else {
+        crate::println!("Memory Test: ✗ Shared frame reference counts incorrect");
+    }
+    
+    // Last reference frees the frame; a further put must not double-free
+    let released = frame_put(frame) == Ok(0) && frame_refcount(frame) == 0;
+    let double_free_rejected = frame_put(frame).is_err() && frame_get(frame).is_err();
+    let (free_after, _) = frame_allocator_stats();
+    
+    if released && double_free_rejected && free_after == free_before {
+        crate::println!("Memory Test: ✓ Last reference frees frame, double free rejected");
+    } else {
+        crate::println!("Memory Test: ✗ Frame release on last reference incorrect");
+    }
+    
+    crate::println!("Memory Test: Frame refcount test completed");
+}
+
+#[kernel_test]
+pub fn test_prezeroing() {
+    use crate::memory::frame_allocator::{allocate_zeroed_frame, prezero_one, prezeroed_frames};
+    
+    crate::println!("Memory Test: Testing frame pre-zeroing...");
+    
+    // Leave dirty frames behind for the pool to pick up
+    let (free_before, _) = frame_allocator_stats();
+    if let Some(dirty) = allocate_frames(4) {
+        unsafe { core::ptr::write_bytes(dirty.as_ptr(), 0xA5, 4 * PAGE_SIZE) };
+        deallocate_frames(dirty, 4);
+    }
+    
+    let mut rounds = 0;
+    while prezero_one() && rounds < 64 {
+        rounds += 1;
+    }
+    let pooled = prezeroed_frames();
+    let (free_pooled, _) = frame_allocator_stats();
+    if pooled > 0 && free_pooled == free_before {
+        crate::println!("Memory Test: ✓ {} frames pre-zeroed, still counted as free", pooled);
+    } else {
+        crate::println!("Memory Test: ✗ Pool holds {} frames, free count moved by {}",
+                       pooled, free_before.abs_diff(free_pooled));
+    }
+    
+    // Empty the pool, then take one more that has to be zeroed on the spot
+    let mut frames = [None; 32];
+    let mut all_zero = true;
+    for slot in frames.iter_mut().take(pooled + 1) {
+        *slot = allocate_zeroed_frame();
+        all_zero &= slot.is_some_and(|frame| {
+            unsafe { core::slice::from_raw_parts(frame.as_ptr(), PAGE_SIZE) }.iter().all(|&b| b == 0)
+        });
+    }
+    let drained = prezeroed_frames() == 0;
+    for frame in frames.into_iter().flatten() {
+        deallocate_frame(frame);
+    }
+    let (free_after, _) = frame_allocator_stats();
+    if all_zero && drained && free_after == free_before {
+        crate::println!("Memory Test: ✓ Pooled and fresh frames came back zeroed");
+    } else {
+        crate::println!("Memory Test: ✗ Zeroed allocation wrong (zero {}, drained {})", all_zero, drained);
+    }
+    
+    crate::println!("Memory Test: Pre-zeroing test completed");
+}
+
+#[kernel_test]
+pub fn test_memtest() {
+    use crate::memory::frame_allocator::PAGE_SIZE;
+    use crate::memory::memtest;
+    
+    crate::println!("Memory Test: Testing RAM pattern test...");
+    
+    let frame = match allocate_frame() {
+        Some(frame) => frame,
+        None => {
+            crate::println!("Memory Test: ✗ Could not allocate frame");
+            return;
+        }
+    };
+    for i in 0..PAGE_SIZE {
+        unsafe { *frame.as_ptr().add(i) = i as u8 };
+    }
+    
+    let good = memtest::test_frame(frame, memtest::MAX_PASSES, true);
+    let preserved = (0..PAGE_SIZE).all(|i| unsafe { *frame.as_ptr().add(i) } == i as u8);
+    if good && preserved {
+        crate::println!("Memory Test: ✓ Frame passed every pattern with contents preserved");
+    } else {
+        crate::println!("Memory Test: ✗ Pattern test failed (good: {}, preserved: {})", good, preserved);
+    }
+    
+    deallocate_frame(frame);
+    crate::println!("Memory Test: RAM pattern test completed");
+}
+
+#[kernel_test]
+pub fn test_kernel_mapping() {
+    use crate::memory::mmu::MemoryManagementUnit;
+    use crate::memory::paging::{phys_to_virt, virt_to_phys, KERNEL_VIRT_OFFSET};
+    
+    if !MemoryManagementUnit::is_enabled() {
+        crate::println!("Memory Test: MMU disabled, skipping mapping test");
+        return;
+    }
+    crate::println!("Memory Test: Testing upper-half kernel mapping...");
+    
+    // Code, RAM and the console UART translate through the linear map
+    let code = test_kernel_mapping as fn() as usize as u64;
+    let ram = crate::devicetree::boot_blob().map_or(crate::board::BUILD.fallback_ram.start, |(phys, _)| phys);
+    let linear = [code, phys_to_virt(ram), phys_to_virt(crate::uart::console_phys())]
+        .iter()
+        .all(|&addr| MemoryManagementUnit::translate(addr) == Some(virt_to_phys(addr)));
+    if code >= KERNEL_VIRT_OFFSET && linear {
+        crate::println!("Memory Test: ✓ Kernel image, RAM and UART in the upper half");
+    } else {
+        crate::println!("Memory Test: ✗ Linear map incomplete");
+    }
+    
+    // Frames come back as linear-map pointers
+    match allocate_frame() {
+        Some(frame) => {
+            let addr = frame.as_ptr() as u64;
+            if addr >= KERNEL_VIRT_OFFSET && MemoryManagementUnit::translate(addr) == Some(virt_to_phys(addr)) {
+                crate::println!("Memory Test: ✓ Frame pointers are linear-map addresses");
+            } else {
+                crate::println!("Memory Test: ✗ Frame pointer 0x{:x} not in the linear map", addr);
+            }
+            deallocate_frame(frame);
+        }
+        None => crate::println!("Memory Test: ✗ Could not allocate frame"),
+    }
+    
+    // The heap sits at a virtual address backed by allocated frames
+    let heap = crate::allocator::HEAP_START as u64;
+    match MemoryManagementUnit::translate(heap) {
+        Some(phys) if phys != heap => {
+            crate::println!("Memory Test: ✓ Heap 0x{:x} backed by frame 0x{:x}", heap, phys);
+        }
+        _ => crate::println!("Memory Test: ✗ Heap virtual range not mapped"),
+    }
+    
+    crate::println!("Memory Test: Kernel mapping test completed");
+}
+
+#[kernel_test]
+pub fn test_reverse_mapping() {
+    use crate::memory::paging::{virt_to_phys, PageFlags, VirtualMemoryManager};
+    use crate::memory::rmap;
+    
+    crate::println!("Memory Test: Testing reverse mapping...");
+    
+    let (frame, mut space_a, mut space_b) =
+        match (allocate_frame(), VirtualMemoryManager::new(), VirtualMemoryManager::new()) {
+            (Some(frame), Some(a), Some(b)) => (frame, a, b),
+            _ => {
+                crate::println!("Memory Test: ✗ Could not allocate frame or address spaces");
+                return;
+            }
+        };
+    let phys = virt_to_phys(frame.as_ptr() as u64);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER;
+    
+    // Shared twice in one space and once in another
+    let mapped = space_a.map_frame(0x1000_0000, frame, flags).is_ok()
+        && space_a.map_frame(0x2000_0000, frame, flags).is_ok()
+        && space_b.map_frame(0x1000_0000, frame, flags).is_ok();
+    if mapped && rmap::map_count(phys) == 3 && frame_refcount(frame) == 4 {
+        crate::println!("Memory Test: ✓ Reverse map tracks every mapping of a frame");
+    } else {
+        crate::println!("Memory Test: ✗ Reverse map has {} mappings, refcount {}",
+                       rmap::map_count(phys), frame_refcount(frame));
+    }
+    
+    // Reclaim-style removal from all users
+    let removed = rmap::unmap_all(phys);
+    let gone = space_a.translate(0x1000_0000).is_none()
+        && space_a.translate(0x2000_0000).is_none()
+        && space_b.translate(0x1000_0000).is_none();
+    if removed == 3 && gone && rmap::map_count(phys) == 0 && frame_refcount(frame) == 1 {
+        crate::println!("Memory Test: ✓ unmap_all removed the frame from every address space");
+    } else {
+        crate::println!("Memory Test: ✗ unmap_all left mappings behind ({} removed)", removed);
+    }
+    
+    deallocate_frame(frame);
+    crate::println!("Memory Test: Reverse mapping test completed");
+}
+
+#[kernel_test]
+pub fn test_tlb_invalidation() {
+    use crate::memory::paging::{virt_to_phys, PageFlags, VirtualMemoryManager};
+    
+    crate::println!("Memory Test: Testing protection changes and TLB invalidation...");
+    
+    const VIRT: u64 = 0x3000_0000;
+    let (frame, mut space) = match (allocate_frame(), VirtualMemoryManager::new_user()) {
+        (Some(frame), Some(space)) => (frame, space),
+        _ => {
+            crate::println!("Memory Test: ✗ Could not allocate frame or address space");
+            return;
+        }
+    };
+    let phys = virt_to_phys(frame.as_ptr() as u64);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER;
+    
+    // User mappings are non-global, and the TTBR value round-trips the space id
+    let tagged = space.map_frame(VIRT, frame, flags).is_ok()
+        && space.leaf_entry(VIRT).is_some_and(|e| e.flags().contains(PageFlags::NOT_GLOBAL))
+        && space.space_id().is_some()
+        && unsafe { VirtualMemoryManager::from_root(space.ttbr()) }.space_id() == space.space_id();
+    if tagged {
+        crate::println!("Memory Test: ✓ User mapping is non-global in space {:?}", space.space_id());
+    } else {
+        crate::println!("Memory Test: ✗ User mapping not tagged with its ASID");
+    }
+    
+    let protected = space.protect_page(VIRT, PageFlags::USER | PageFlags::READ_ONLY | PageFlags::UXN).is_ok()
+        && space.leaf_entry(VIRT).is_some_and(|e| {
+            e.flags().contains(PageFlags::READ_ONLY | PageFlags::UXN | PageFlags::NOT_GLOBAL)
+                && e.physical_addr() == phys
+        });
+    if protected {
+        crate::println!("Memory Test: ✓ protect_page changed permissions in place");
+    } else {
+        crate::println!("Memory Test: ✗ protect_page did not update the entry");
+    }
+    
+    let unmapped = space.unmap_frame(VIRT).is_ok()
+        && space.translate(VIRT).is_none()
+        && space.protect_page(VIRT, PageFlags::USER | PageFlags::UXN).is_err();
+    if unmapped {
+        crate::println!("Memory Test: ✓ Unmapped page is gone and cannot be reprotected");
+    } else {
+        crate::println!("Memory Test: ✗ Page still reachable after unmap");
+    }
+    
+    space.destroy();
+    deallocate_frame(frame);
+    crate::println!("Memory Test: TLB invalidation test completed");
+}
+
+#[kernel_test]
+pub fn test_protect_range() {
+    use core::ptr::NonNull;
+    use crate::memory::frame_allocator::{allocate_frames, deallocate_frames, PAGE_SIZE};
+    use crate::memory::paging::{PageFlags, VirtualMemoryManager};
+    
+    crate::println!("Memory Test: Testing range protection changes...");
+    
+    const VIRT: u64 = 0x4000_0000;
+    const PAGES: usize = 4;
+    let (frames, mut space) = match (allocate_frames(PAGES), VirtualMemoryManager::new_user()) {
+        (Some(frames), Some(space)) => (frames, space),
+        _ => {
+            crate::println!("Memory Test: ✗ Could not allocate frames or address space");
+            return;
+        }
+    };
+    let frame = |i: usize| unsafe { NonNull::new_unchecked(frames.as_ptr().add(i * PAGE_SIZE)) };
+    let page = |i: usize| VIRT + (i * PAGE_SIZE) as u64;
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER;
+    let read_only = |space: &mut VirtualMemoryManager, i: usize| {
+        space.leaf_entry(page(i)).is_some_and(|e| e.flags().contains(PageFlags::READ_ONLY))
+    };
+    
+    // Three pages mapped, the fourth left as a hole
+    let mapped = (0..PAGES - 1).all(|i| space.map_frame(page(i), frame(i), flags).is_ok());
+    
+    // Unaligned range covering pages 0..=1 (ELF segments rarely start on a page)
+    let changed = mapped
+        && space.protect_range(VIRT + 0x10, PAGE_SIZE as u64, PageFlags::USER | PageFlags::READ_ONLY).is_ok()
+        && read_only(&mut space, 0) && read_only(&mut space, 1) && !read_only(&mut space, 2);
+    if changed {
+        crate::println!("Memory Test: ✓ protect_range rewrote exactly the covered pages");
+    } else {
+        crate::println!("Memory Test: ✗ protect_range changed the wrong pages");
+    }
+    
+    let rejected = space.protect_range(page(2), 2 * PAGE_SIZE as u64, PageFlags::USER | PageFlags::READ_ONLY).is_err()
+        && !read_only(&mut space, 2);
+    if rejected {
+        crate::println!("Memory Test: ✓ Range with an unmapped page left untouched");
+    } else {
+        crate::println!("Memory Test: ✗ Partial protection change applied across a hole");
+    }
+    
+    space.destroy();
+    deallocate_frames(frames, PAGES);
+    crate::println!("Memory Test: Range protection test completed");
+}
+
+// mov x0, #1; ret - patched to return 2 by test_wx_enforcement
+core::arch::global_asm!(
+    ".text",
+    ".balign 4",
+    ".type wx_test_patch_target, %function",
+    "wx_test_patch_target:",
+    "mov x0, #1",
+    "ret",
+    ".size wx_test_patch_target, . - wx_test_patch_target",
+);
+
+extern "C" {
+    fn wx_test_patch_target() -> u64;
+}
+
+#[kernel_test]
+pub fn test_wx_enforcement() {
+    use crate::interrupts::ExceptionContext;
+    use crate::memory::mmu::{self, MemoryManagementUnit};
+    use crate::memory::paging::{PageFlags, VirtualMemoryManager, KERNEL_VIRT_OFFSET};
+    use crate::syscall::{EPERM, PROT_EXEC, PROT_READ, PROT_WRITE, SYS_MMAP};
+    
+    const MOV_X0_1: u32 = 0xD280_0020;
+    const MOV_X0_2: u32 = 0xD280_0040;
+    const VIRT: u64 = 0x4000_0000;
+    static DATA: AtomicU64 = AtomicU64::new(1);
+    static RODATA: [u8; 4] = *b"W^X!";
+    
+    let Some(kernel) = MemoryManagementUnit::current_vmm() else {
+        crate::println!("Memory Test: MMU disabled, skipping W^X test");
+        return;
+    };
+    crate::println!("Memory Test: Testing W^X enforcement...");
+    
+    // Text executes but is read-only; the rest of the image never executes
+    let code = wx_test_patch_target as unsafe extern "C" fn() -> u64 as usize as u64;
+    let flags_of = |addr: u64| kernel.flags_at(addr).unwrap_or(PageFlags::empty());
+    let text = flags_of(code);
+    let rodata = flags_of(RODATA.as_ptr() as u64);
+    let data = flags_of(&DATA as *const AtomicU64 as u64);
+    let split = text.contains(PageFlags::READ_ONLY) && !text.contains(PageFlags::PXN)
+        && rodata.contains(PageFlags::READ_ONLY | PageFlags::PXN)
+        && !data.contains(PageFlags::READ_ONLY) && data.contains(PageFlags::PXN)
+        && DATA.fetch_add(1, Ordering::SeqCst) == 1;
+    if split && mmu::audit_wx(kernel, KERNEL_VIRT_OFFSET) == 0 {
+        crate::println!("Memory Test: ✓ Kernel text RX, rodata RO, data RW+XN, audit clean");
+    } else {
+        crate::println!("Memory Test: ✗ Kernel image flags text {:?} rodata {:?} data {:?}", text, rodata, data);
+    }
+    
+    // The audit finds a writable, executable user page; protect_range
+    // fixes it and will not put it back
+    let (frame, mut space) = match (allocate_frame(), VirtualMemoryManager::new_user()) {
+        (Some(frame), Some(space)) => (frame, space),
+        _ => {
+            crate::println!("Memory Test: ✗ Could not allocate frame or address space");
+            return;
+        }
+    };
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER;
+    let found = space.map_frame(VIRT, frame, flags).is_ok() && mmu::audit_wx(&space, 0) == 1;
+    let fixed = space.protect_page(VIRT, PageFlags::USER | PageFlags::UXN).is_ok() && mmu::audit_wx(&space, 0) == 0;
+    let refused = space.protect_page(VIRT, PageFlags::USER).is_err() && mmu::audit_wx(&space, 0) == 0;
+    let mut ctx = ExceptionContext {
+        x0: PAGE_SIZE as u64, x1: PROT_READ | PROT_WRITE | PROT_EXEC, spsr_el1: 0x3c5, ..Default::default()
+    };
+    crate::syscall::dispatch(&mut ctx, SYS_MMAP);
+    if found && fixed && refused && ctx.x0 as i64 == EPERM {
+        crate::println!("Memory Test: ✓ Writable+executable page reported, reprotect and mmap refused");
+    } else {
+        crate::println!("Memory Test: ✗ W^X user checks gave {} / {} / {} / mmap {}", found, fixed, refused, ctx.x0 as i64);
+    }
+    space.destroy();
+    deallocate_frame(frame);
+    
+    // Text patches go through the writable alias and take effect
+    let patch = |insn: u32| {
+        let done = mmu::write_kernel_text(code, &insn.to_le_bytes()).is_ok();
+        crate::gdbstub::sync_icache(code, 4);
+        done
+    };
+    let patched = patch(MOV_X0_2) && unsafe { wx_test_patch_target() } == 2;
+    let restored = patch(MOV_X0_1) && unsafe { wx_test_patch_target() } == 1;
+    let outside = mmu::write_kernel_text(RODATA.as_ptr() as u64, b"no").is_err();
+    if patched && restored && outside {
+        crate::println!("Memory Test: ✓ Read-only text patched through an alias");
+    } else {
+        crate::println!("Memory Test: ✗ Text patch gave {} / {} / {}", patched, restored, outside);
+    }
+    
+    crate::println!("Memory Test: W^X test completed");
+}
+
+#[kernel_test]
+pub fn test_text_integrity() {
+    use crate::memory::mmu::{self, MemoryManagementUnit};
+    use crate::memory::paging::PageFlags;
+    use crate::memory::textcheck;
+    
+    const MOV_X0_3: u32 = 0xD280_0060;
+    // Unused kernel address for a stray writable alias of a text page
+    const STRAY_ALIAS: u64 = 0xFFFF_FFFF_FFC0_0000;
+    
+    crate::println!("Memory Test: Testing kernel text integrity check...");
+    let code = wx_test_patch_target as unsafe extern "C" fn() -> u64 as usize as u64;
+    let original = unsafe { core::ptr::read_volatile(code as *const u32) };
+    
+    // Patches through write_kernel_text are expected and not reported
+    let clean = textcheck::verify_all() == 0;
+    let patched = mmu::write_kernel_text(code, &MOV_X0_3.to_le_bytes()).is_ok() && textcheck::verify_all() == 0;
+    let restored = mmu::write_kernel_text(code, &original.to_le_bytes()).is_ok() && textcheck::verify_all() == 0;
+    if clean && patched && restored {
+        crate::println!("Memory Test: ✓ {} image pages match, patches rechecksummed", textcheck::stats().pages);
+    } else {
+        crate::println!("Memory Test: ✗ Integrity check gave clean {} / patched {} / restored {}", clean, patched, restored);
+    }
+    
+    // Any other write is caught, and reported once until undone
+    let stray_write = |value: u32| {
+        let Some(kernel) = MemoryManagementUnit::current_vmm() else {
+            unsafe { core::ptr::write_volatile(code as *mut u32, value) };
+            return true;
+        };
+        let offset = code & (PAGE_SIZE as u64 - 1);
+        let flags = PageFlags::NORMAL_MEMORY | PageFlags::INNER_SHAREABLE | PageFlags::ACCESSED
+            | PageFlags::PXN | PageFlags::UXN;
+        let Some(phys) = kernel.translate(code - offset) else {
+            return false;
+        };
+        if kernel.map_page(STRAY_ALIAS, phys, flags).is_err() {
+            return false;
+        }
+        unsafe { core::ptr::write_volatile((STRAY_ALIAS + offset) as *mut u32, value) };
+        kernel.unmap_page(STRAY_ALIAS).is_ok()
+    };
+    let before = textcheck::stats().detections;
+    let caught = stray_write(MOV_X0_3) && textcheck::verify_all() == 1 && textcheck::verify_all() == 1;
+    let after = textcheck::stats();
+    let undone = stray_write(original) && textcheck::verify_all() == 0 && textcheck::stats().modified == 0;
+    if caught && after.detections == before + 1 && after.modified == 1 && undone {
+        crate::println!("Memory Test: ✓ Stray write to text reported once, cleared when undone");
+    } else {
+        crate::println!("Memory Test: ✗ Stray write caught {}, detections {} -> {}, undone {}",
+                        caught, before, after.detections, undone);
+    }
+    
+    crate::println!("Memory Test: Text integrity test completed");
+}
+
+// An absolute address in data, fixed up when the kernel moved
+static KASLR_PROBE: fn() = test_kaslr;
+
+#[kernel_test]
+pub fn test_kaslr() {
+    use crate::memory::kaslr;
+    use crate::memory::mmu::MemoryManagementUnit;
+    use crate::memory::paging::{phys_to_virt, virt_to_phys, PageFlags};
+    
+    let Some(kernel) = MemoryManagementUnit::current_vmm() else {
+        crate::println!("Memory Test: MMU disabled, skipping KASLR test");
+        return;
+    };
+    crate::println!("Memory Test: Testing kernel address randomization...");
+    
+    // Moved unless told not to, with data pointers agreeing with the PC
+    let disabled = crate::bootparams::get("nokaslr").is_some();
+    let offset = kaslr::offset();
+    let code = test_kaslr as fn() as usize as u64;
+    let probe = unsafe { core::ptr::read_volatile(core::ptr::addr_of!(KASLR_PROBE)) } as usize as u64;
+    let placed = if disabled { offset == 0 } else { offset != 0 && kaslr::in_region(code) };
+    if placed && probe == code {
+        crate::println!("Memory Test: ✓ Kernel at 0x{:x} from its link address (nokaslr {})", offset, disabled);
+    } else {
+        crate::println!("Memory Test: ✗ Offset 0x{:x} with nokaslr {}, code 0x{:x}, relocated pointer 0x{:x}",
+                        offset, disabled, code, probe);
+    }
+    
+    // The image and its linear alias are the same memory; only the image
+    // is executable
+    let phys = virt_to_phys(code);
+    let alias = phys_to_virt(phys);
+    let same = kernel.translate(code) == Some(phys) && kernel.translate(alias) == Some(phys);
+    let alias_flags = kernel.flags_at(alias).unwrap_or(PageFlags::empty());
+    let inert = alias == code || alias_flags.contains(PageFlags::READ_ONLY | PageFlags::PXN);
+    // Names come from link addresses
+    let named = crate::symbols::kernel_table().is_none() || crate::symbols::lookup(code)
+        .is_some_and(|symbol| symbol.addr == code && symbol.name().ends_with("test_kaslr"));
+    if same && inert && named {
+        crate::println!("Memory Test: ✓ Image at phys 0x{:x}, linear alias read-only, symbols resolve", phys);
+    } else {
+        crate::println!("Memory Test: ✗ Same frame {}, alias flags {:?}, named {}", same, alias_flags, named);
+    }
+    
+    crate::println!("Memory Test: KASLR test completed");
+}
+
+#[kernel_test]
+pub fn test_address_space_teardown() {
+    use crate::memory::paging::{virt_to_phys, PageFlags, VirtualMemoryManager};
+    use crate::memory::rmap;
+    
+    crate::println!("Memory Test: Testing address space teardown...");
+    
+    let (owned, shared, borrowed) = match (allocate_frame(), allocate_frame(), allocate_frame()) {
+        (Some(a), Some(b), Some(c)) => (a, b, c),
+        _ => {
+            crate::println!("Memory Test: ✗ Could not allocate frames");
+            return;
+        }
+    };
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER;
+    let (free_before, _) = frame_allocator_stats();
+    
+    let mut space = match VirtualMemoryManager::new_user() {
+        Some(space) => space,
+        None => {
+            crate::println!("Memory Test: ✗ Could not allocate address space");
+            return;
+        }
+    };
+    // Spread over two level 1 entries so several table levels exist
+    let mapped = space.map_frame(0x1000_0000, owned, flags).is_ok()
+        && space.map_frame(0x1000_1000, shared, flags).is_ok()
+        && space.map_frame(0x80_0000_0000, shared, flags).is_ok()
+        && space.map_page(0x2000_0000, virt_to_phys(borrowed.as_ptr() as u64), flags).is_ok();
+    // The mapping now holds the only reference to `owned`
+    deallocate_frame(owned);
+    
+    let freed = space.destroy();
+    let (free_after, _) = frame_allocator_stats();
+    let shared_phys = virt_to_phys(shared.as_ptr() as u64);
+    
+    // Everything but the owned frame was already allocated before
+    if mapped && free_after == free_before + 1 && frame_refcount(owned) == 0 {
+        crate::println!("Memory Test: ✓ destroy() freed {} frames including every table", freed);
+    } else {
+        crate::println!("Memory Test: ✗ destroy() leaked frames ({} free before, {} after)",
+                       free_before, free_after);
+    }
+    
+    if frame_refcount(shared) == 1 && rmap::map_count(shared_phys) == 0 && frame_refcount(borrowed) == 1 {
+        crate::println!("Memory Test: ✓ Shared and borrowed frames kept their other owners");
+    } else {
+        crate::println!("Memory Test: ✗ Teardown dropped references it did not hold");
+    }
+    
+    deallocate_frame(shared);
+    deallocate_frame(borrowed);
+    crate::println!("Memory Test: Address space teardown test completed");
+}
+
+#[kernel_test]
+pub fn test_compaction() {
+    use core::ptr::NonNull;
+    use crate::memory::compaction::{allocate_contiguous, compact_range};
+    use crate::memory::frame_allocator::{allocate_frames, deallocate_frames, PAGE_SIZE};
+    use crate::memory::paging::{phys_to_virt, virt_to_phys, PageFlags, VirtualMemoryManager};
+    use crate::memory::rmap;
+    
+    crate::println!("Memory Test: Testing compaction and page migration...");
+    
+    let (window, mut space) = match (allocate_frames(4), VirtualMemoryManager::new()) {
+        (Some(window), Some(space)) => (window, space),
+        _ => {
+            crate::println!("Memory Test: ✗ Could not allocate compaction window");
+            return;
+        }
+    };
+    let frame = |i: usize| unsafe { NonNull::new_unchecked(window.as_ptr().add(i * PAGE_SIZE)) };
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER;
+    
+    // Frames 1 and 2 become user pages owned only by their mappings; 0 and 3 are freed
+    for i in 1..3 {
+        unsafe { (frame(i).as_ptr() as *mut u64).write(0xC0FFEE00 + i as u64) };
+        if space.map_frame(0x3000_0000 + (i * PAGE_SIZE) as u64, frame(i), flags).is_err() {
+            crate::println!("Memory Test: ✗ Could not map page for migration");
+            return;
+        }
+    }
+    for i in 0..4 {
+        deallocate_frame(frame(i));
+    }
+    
+    let window_phys = virt_to_phys(window.as_ptr() as u64);
+    let window_end = window_phys + 4 * PAGE_SIZE as u64;
+    let compacted = compact_range(window, 4).is_ok();
+    let mut moved = true;
+    for i in 1..3 {
+        let virt = 0x3000_0000 + (i * PAGE_SIZE) as u64;
+        match space.translate(virt) {
+            Some(phys) if phys < window_phys || phys >= window_end => {
+                let value = unsafe { (phys_to_virt(phys) as *const u64).read() };
+                moved &= value == 0xC0FFEE00 + i as u64;
+                rmap::unmap_all(phys);
+            }
+            _ => moved = false,
+        }
+    }
+    if compacted && moved && (0..4).all(|i| frame_refcount(frame(i)) == 1) {
+        crate::println!("Memory Test: ✓ Mapped pages migrated out, window claimed intact");
+    } else {
+        crate::println!("Memory Test: ✗ Compaction failed (compacted {}, moved {})", compacted, moved);
+    }
+    deallocate_frames(window, 4);
+    
+    // A hugepage-sized, hugepage-aligned request
+    match allocate_contiguous(512, 512) {
+        Some(huge) if virt_to_phys(huge.as_ptr() as u64).is_multiple_of(512 * PAGE_SIZE as u64) => {
+            crate::println!("Memory Test: ✓ 2MB aligned contiguous allocation");
+            deallocate_frames(huge, 512);
+        }
+        _ => crate::println!("Memory Test: ✗ 2MB aligned contiguous allocation failed"),
+    }
+    
+    crate::println!("Memory Test: Compaction test completed");
+}
+
+#[kernel_test]
+pub fn test_samepage_merging() {
+    use crate::memory::ksm;
+    use crate::memory::paging::{virt_to_phys, PageFlags, VirtualMemoryManager};
+    use crate::memory::rmap;
+    
+    crate::println!("Memory Test: Testing samepage merging...");
+    
+    let (mut space_a, mut space_b) = match (VirtualMemoryManager::new(), VirtualMemoryManager::new()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => {
+            crate::println!("Memory Test: ✗ Could not create address spaces");
+            return;
+        }
+    };
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER | PageFlags::READ_ONLY;
+    
+    // Two "service instances" with the same page, plus one different page
+    let mut frames = [None; 3];
+    for (i, fill) in [0x5A5A_5A5A_u64, 0x5A5A_5A5A, 0x1234_5678].into_iter().enumerate() {
+        let frame = match allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                crate::println!("Memory Test: ✗ Could not allocate frame");
+                return;
+            }
+        };
+        let words = unsafe { core::slice::from_raw_parts_mut(frame.as_ptr() as *mut u64, 512) };
+        words.fill(fill);
+        frames[i] = Some(virt_to_phys(frame.as_ptr() as u64));
+        let space = if i == 1 { &mut space_b } else { &mut space_a };
+        let virt = 0x4000_0000 + (i as u64) * 0x1000;
+        let mapped = space.map_frame(virt, frame, flags).is_ok();
+        deallocate_frame(frame);
+        if !mapped {
+            crate::println!("Memory Test: ✗ Could not map page");
+            return;
+        }
+    }
+    
+    let (free_before, _) = frame_allocator_stats();
+    ksm::register_region(space_a.ttbr(), 0x4000_0000, 3);
+    ksm::register_region(space_b.ttbr(), 0x4000_0000, 3);
+    let merged_before = ksm::stats().pages_merged;
+    while !ksm::scan(16) {}
+    
+    let a0 = space_a.translate(0x4000_0000);
+    let b1 = space_b.translate(0x4000_1000);
+    let a2 = space_a.translate(0x4000_2000);
+    let (free_after, _) = frame_allocator_stats();
+    if a0.is_some() && a0 == b1 && a2 == frames[2] && free_after == free_before + 1
+        && ksm::stats().pages_merged == merged_before + 1
+    {
+        crate::println!("Memory Test: ✓ Identical read-only pages share one frame");
+    } else {
+        crate::println!("Memory Test: ✗ Samepage merge incorrect ({:?} {:?} {:?})", a0, b1, a2);
+    }
+    
+    ksm::unregister_space(space_a.ttbr());
+    ksm::unregister_space(space_b.ttbr());
+    for phys in [a0, a2].into_iter().flatten() {
+        rmap::unmap_all(phys);
+    }
+    crate::println!("Memory Test: Samepage merging test completed");
+}
+
+// Randomized allocator stress (memstress=<ms>[,<threads>[,<seed>]])
+//
+// Worker threads allocate and free single frames and short runs in a
+// random order, fill each allocation with a pattern derived from its
+// address and a per-allocation tag, and check it is intact when it is
+// freed or revisited. Any mismatch means two owners were handed the same
+// frame, or something wrote to memory it did not own. The run also checks
+// that every frame is back on the free list at the end.
+//
+// Workers are kernel threads preempting each other on the boot CPU; once
+// secondary CPUs run threads they allocate concurrently for real.
+
+// Allocations a worker keeps live at once
+const STRESS_SLOTS: usize = 64;
+// Longest contiguous run requested
+const STRESS_MAX_RUN: usize = 8;
+// Used under `make test` when memstress= is not given
+const STRESS_TEST_MODE_DEFAULT: StressConfig = StressConfig { duration_ms: 200, threads: 2, seed: 0 };
+
+#[derive(Clone, Copy, Debug)]
+pub struct StressConfig {
+    pub duration_ms: u64,
+    pub threads: usize,
+    // 0 picks one from the counter
+    pub seed: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StressStats {
+    pub allocations: u64,
+    pub frees: u64,
+    pub verified: u64,
+    pub failed_allocations: u64,
+    pub corruptions: u64,
+}
+
+static STRESS_CONFIG: Mutex<StressConfig> = Mutex::new(STRESS_TEST_MODE_DEFAULT);
+static STRESS_TOTALS: Mutex<StressStats> = Mutex::new(StressStats {
+    allocations: 0,
+    frees: 0,
+    verified: 0,
+    failed_allocations: 0,
+    corruptions: 0,
+});
+static STRESS_NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+static STRESS_DONE: AtomicUsize = AtomicUsize::new(0);
+static STRESS_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Parse `memstress=`; a bare `memstress` runs the defaults.
+pub fn stress_config() -> Option<StressConfig> {
+    let value = crate::bootparams::get("memstress")?;
+    let mut config = STRESS_TEST_MODE_DEFAULT;
+    let mut fields = value.split(',');
+    if let Some(ms) = fields.next().filter(|ms| !ms.is_empty()) {
+        config.duration_ms = ms.parse().ok()?;
+    }
+    if let Some(threads) = fields.next() {
+        config.threads = threads.parse::<usize>().ok()?.max(1);
+    }
+    if let Some(seed) = fields.next() {
+        config.seed = seed.parse().ok()?;
+    }
+    Some(config)
+}
+
+// xorshift64*: cheap, and reproducible from the printed seed
+struct StressRng(u64);
+
+impl StressRng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[derive(Clone, Copy)]
+struct StressBlock {
+    base: NonNull<u8>,
+    frames: usize,
+    tag: u64,
+}
+
+impl StressBlock {
+    fn words(&self) -> *mut u64 {
+        self.base.as_ptr() as *mut u64
+    }
+    
+    fn word_count(&self) -> usize {
+        self.frames * PAGE_SIZE / 8
+    }
+    
+    // Unique per word and per allocation, so stale or shared frames show
+    fn expected(&self, i: usize) -> u64 {
+        self.tag ^ (self.words() as u64 + i as u64 * 8)
+    }
+    
+    fn fill(&self) {
+        for i in 0..self.word_count() {
+            unsafe { core::ptr::write_volatile(self.words().add(i), self.expected(i)) };
+        }
+    }
+    
+    fn check(&self) -> bool {
+        (0..self.word_count()).all(|i| unsafe { core::ptr::read_volatile(self.words().add(i)) } == self.expected(i))
+    }
+    
+    fn free(self) {
+        if self.frames == 1 {
+            deallocate_frame(self.base);
+        } else {
+            deallocate_frames(self.base, self.frames);
+        }
+    }
+}
+
+// One worker's randomized run until the shared deadline
+fn stress_loop(rng: &mut StressRng) -> StressStats {
+    let deadline = STRESS_DEADLINE.load(Ordering::Relaxed);
+    let mut stats = StressStats::default();
+    let mut live: [Option<StressBlock>; STRESS_SLOTS] = [None; STRESS_SLOTS];
+    let retire = |block: StressBlock, stats: &mut StressStats| {
+        if !block.check() {
+            stats.corruptions += 1;
+            crate::println!("Memory Stress: ✗ Corrupted allocation at 0x{:x} ({} frames)",
+                           block.base.as_ptr() as usize, block.frames);
+        }
+        block.free();
+        stats.frees += 1;
+    };
+    
+    while crate::interrupts::counter_ticks() < deadline {
+        let slot = rng.below(STRESS_SLOTS);
+        match live[slot] {
+            // Mostly free, sometimes only revisit
+            Some(block) if rng.below(4) != 0 => {
+                live[slot] = None;
+                retire(block, &mut stats);
+            }
+            Some(block) => {
+                if block.check() {
+                    stats.verified += 1;
+                } else {
+                    stats.corruptions += 1;
+                    crate::println!("Memory Stress: ✗ Live allocation at 0x{:x} overwritten",
+                                   block.base.as_ptr() as usize);
+                }
+            }
+            None => {
+                // Single frames dominate, as in real use
+                let frames = if rng.below(4) == 0 { 1 + rng.below(STRESS_MAX_RUN) } else { 1 };
+                let base = if frames == 1 { allocate_frame() } else { allocate_frames(frames) };
+                match base {
+                    Some(base) => {
+                        let block = StressBlock { base, frames, tag: rng.next() };
+                        block.fill();
+                        live[slot] = Some(block);
+                        stats.allocations += 1;
+                    }
+                    None => stats.failed_allocations += 1,
+                }
+            }
+        }
+    }
+    
+    for block in live.iter_mut().filter_map(Option::take) {
+        retire(block, &mut stats);
+    }
+    stats
+}
+
+fn stress_worker() {
+    let index = STRESS_NEXT_WORKER.fetch_add(1, Ordering::SeqCst) as u64;
+    let seed = STRESS_CONFIG.lock().seed;
+    // Distinct, non-zero stream per worker
+    let mut rng = StressRng(seed.wrapping_add(index.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1);
+    let stats = stress_loop(&mut rng);
+    
+    let mut totals = STRESS_TOTALS.lock();
+    totals.allocations += stats.allocations;
+    totals.frees += stats.frees;
+    totals.verified += stats.verified;
+    totals.failed_allocations += stats.failed_allocations;
+    totals.corruptions += stats.corruptions;
+    drop(totals);
+    STRESS_DONE.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Run the allocator stress if `memstress=` asks for it, or by default
+/// under `make test`. Needs the scheduler. A failure in test mode panics,
+/// so QEMU exits with the report in the log.
+#[kernel_test]
+pub fn test_memory_stress() {
+    use crate::process::{kthread_spawn, scheduler, yield_now, KTHREAD_DEFAULT_PRIORITY};
+    
+    let requested = stress_config();
+    let Some(mut config) = requested.or(crate::power::test_mode().then_some(STRESS_TEST_MODE_DEFAULT)) else {
+        return;
+    };
+    if config.seed == 0 {
+        config.seed = crate::interrupts::counter_ticks();
+    }
+    crate::println!("Memory Stress: {} ms, {} threads, seed {}", config.duration_ms, config.threads, config.seed);
+    
+    *STRESS_CONFIG.lock() = config;
+    *STRESS_TOTALS.lock() = StressStats::default();
+    STRESS_NEXT_WORKER.store(0, Ordering::SeqCst);
+    STRESS_DONE.store(0, Ordering::SeqCst);
+    let (free_before, _) = frame_allocator_stats();
+    let ticks = crate::timer::ms_to_ticks(config.duration_ms);
+    STRESS_DEADLINE.store(crate::interrupts::counter_ticks() + ticks, Ordering::SeqCst);
+    
+    let mut spawned = 0;
+    for _ in 0..config.threads {
+        match kthread_spawn(stress_worker, "memstress", KTHREAD_DEFAULT_PRIORITY) {
+            Ok(_) => spawned += 1,
+            Err(e) => crate::println!("Memory Stress: Could not spawn worker: {}", e),
+        }
+    }
+    while STRESS_DONE.load(Ordering::SeqCst) < spawned {
+        yield_now();
+    }
+    // Worker stacks are only freed once they are reaped
+    scheduler::reap_exited();
+    
+    let totals = *STRESS_TOTALS.lock();
+    let (free_after, _) = frame_allocator_stats();
+    crate::println!("Memory Stress: {} allocations, {} frees, {} rechecks, {} allocation failures",
+                   totals.allocations, totals.frees, totals.verified, totals.failed_allocations);
+    let passed = spawned > 0 && totals.corruptions == 0 && free_after == free_before;
+    if passed {
+        crate::println!("Memory Stress: ✓ PASS");
+    } else {
+        crate::println!("Memory Stress: ✗ FAIL ({} workers, {} corruptions, {} frames leaked)",
+                       spawned, totals.corruptions, free_before as i64 - free_after as i64);
+        if crate::power::test_mode() {
+            panic!("Memory stress test failed (seed {})", config.seed);
+        }
+    }
+}
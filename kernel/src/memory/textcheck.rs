@@ -0,0 +1,179 @@
+// Kernel image integrity check
+//
+// Text and read-only data only change through write_kernel_text
+// (breakpoints, self-test patches), so a checksum of each page taken at
+// boot should hold for the life of the kernel. The idle task re-verifies
+// a few pages per call, one full pass per interval (sysctl
+// kernel.textcheck_interval_ms, 0 turns it off), and the `textcheck`
+// shell command runs a pass on demand. A page that no longer matches is
+// reported once, with the function it starts in, until it matches again;
+// deliberate patches update the checksums of the pages they touch.
+//
+// A tripwire for wild writes through bad mappings, not a defence: a write
+// that can reach the text could as well reach the checksums.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::interrupts::{counter_frequency, counter_ticks};
+use crate::memory::frame_allocator::PAGE_SIZE;
+use crate::memory::mmu;
+use crate::memory::paging::VirtAddr;
+use crate::sync::IrqSafeMutex;
+
+const DEFAULT_INTERVAL_MS: u64 = 10_000;
+// Pages verified per idle call
+const PAGES_PER_CALL: usize = 8;
+
+// FNV-1a, over 64-bit words
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(DEFAULT_INTERVAL_MS);
+
+struct Baseline {
+    start: VirtAddr,
+    text_end: VirtAddr,
+    sums: Vec<u64>,
+    // Pages reported as modified and not yet back to their checksum
+    reported: Vec<bool>,
+    // Next page of the background pass; 0 between passes
+    cursor: usize,
+    last_pass: u64,
+    passes: u64,
+    detections: u64,
+}
+
+static BASELINE: IrqSafeMutex<Option<Baseline>> = IrqSafeMutex::new(None);
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TextcheckStats {
+    pub pages: usize,
+    /// Completed background passes
+    pub passes: u64,
+    /// Pages found modified, counting each change once
+    pub detections: u64,
+    /// Pages currently differing from their checksum
+    pub modified: usize,
+}
+
+// Each step is a bijection, so changing any one word changes the sum
+fn page_sum(page: VirtAddr) -> u64 {
+    (0..PAGE_SIZE as u64 / 8).fold(FNV_OFFSET, |sum, word| {
+        let word = unsafe { core::ptr::read_volatile((page + word * 8) as *const u64) };
+        (sum ^ word).wrapping_mul(FNV_PRIME)
+    })
+}
+
+impl Baseline {
+    fn page(&self, index: usize) -> VirtAddr {
+        self.start + (index * PAGE_SIZE) as u64
+    }
+    
+    // Whether the page differs from its checksum; reports it the first time
+    fn verify(&mut self, index: usize) -> bool {
+        let page = self.page(index);
+        let modified = page_sum(page) != self.sums[index];
+        if modified && !self.reported[index] {
+            self.detections += 1;
+            let section = if page < self.text_end { ".text" } else { ".rodata" };
+            match crate::symbols::lookup(page) {
+                Some(symbol) => crate::println!("Textcheck: {} page 0x{:016x} ({}) modified", section, page, symbol),
+                None => crate::println!("Textcheck: {} page 0x{:016x} modified", section, page),
+            }
+        }
+        self.reported[index] = modified;
+        modified
+    }
+}
+
+/// Checksum the text and read-only data, and verify them when idle.
+pub fn init() {
+    let (start, text_end) = mmu::kernel_text();
+    let (_, end) = mmu::kernel_rodata();
+    let pages = (end - start) as usize / PAGE_SIZE;
+    let sums = (0..pages).map(|index| page_sum(start + (index * PAGE_SIZE) as u64)).collect();
+    *BASELINE.lock() = Some(Baseline {
+        start,
+        text_end,
+        sums,
+        reported: alloc::vec![false; pages],
+        cursor: 0,
+        last_pass: counter_ticks(),
+        passes: 0,
+        detections: 0,
+    });
+    if let Err(e) = crate::process::idle::register_idle_work("textcheck", idle_verify) {
+        crate::println!("Textcheck: Failed to register idle verification: {}", e);
+    }
+    crate::println!("Textcheck: Checksummed {} KiB of text and {} KiB of read-only data",
+                   (text_end - start) / 1024, (end - text_end) / 1024);
+}
+
+/// Verify every page now; returns how many differ from their checksum.
+pub fn verify_all() -> usize {
+    let mut baseline = BASELINE.lock();
+    let Some(baseline) = baseline.as_mut() else {
+        return 0;
+    };
+    (0..baseline.sums.len()).filter(|&index| baseline.verify(index)).count()
+}
+
+/// `[addr, addr + len)` was changed on purpose: checksum it again.
+pub fn rebaseline(addr: VirtAddr, len: usize) {
+    let mut baseline = BASELINE.lock();
+    let Some(baseline) = baseline.as_mut() else {
+        return;
+    };
+    let first = (addr.saturating_sub(baseline.start) as usize) / PAGE_SIZE;
+    let last = ((addr + len as u64).saturating_sub(baseline.start) as usize).div_ceil(PAGE_SIZE);
+    for index in first..last.min(baseline.sums.len()) {
+        baseline.sums[index] = page_sum(baseline.page(index));
+        baseline.reported[index] = false;
+    }
+}
+
+// Idle housekeeping: a few pages of the current pass, if one is due
+fn idle_verify() -> bool {
+    let interval = INTERVAL_MS.load(Ordering::Relaxed);
+    if interval == 0 {
+        return false;
+    }
+    let mut baseline = BASELINE.lock();
+    let Some(baseline) = baseline.as_mut() else {
+        return false;
+    };
+    let now = counter_ticks();
+    if baseline.cursor == 0 && now - baseline.last_pass < interval * counter_frequency() / 1000 {
+        return false;
+    }
+    let end = (baseline.cursor + PAGES_PER_CALL).min(baseline.sums.len());
+    for index in baseline.cursor..end {
+        baseline.verify(index);
+    }
+    if end < baseline.sums.len() {
+        baseline.cursor = end;
+        return true;
+    }
+    baseline.cursor = 0;
+    baseline.last_pass = now;
+    baseline.passes += 1;
+    false
+}
+
+pub fn interval_ms() -> u64 {
+    INTERVAL_MS.load(Ordering::Relaxed)
+}
+
+/// Time between background passes; 0 stops them.
+pub fn set_interval_ms(ms: u64) {
+    INTERVAL_MS.store(ms, Ordering::Relaxed);
+}
+
+pub fn stats() -> TextcheckStats {
+    BASELINE.lock().as_ref().map_or(TextcheckStats::default(), |baseline| TextcheckStats {
+        pages: baseline.sums.len(),
+        passes: baseline.passes,
+        detections: baseline.detections,
+        modified: baseline.reported.iter().filter(|&&reported| reported).count(),
+    })
+}
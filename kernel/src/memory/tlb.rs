@@ -0,0 +1,94 @@
+// TLB maintenance
+//
+// Page table updates that remove or change a valid entry must be followed
+// by an invalidation, or the CPU keeps using the stale translation. Kernel
+// (TTBR1) mappings are global and invalidated for every ASID; user
+// mappings are tagged with their address space's ASID. The broadcast
+// variants reach every CPU in the inner shareable domain; the local ones
+// only this CPU, for address spaces that have never run elsewhere.
+
+use core::arch::asm;
+use crate::memory::paging::VirtAddr;
+
+/// Hardware address space identifier, 8 or 16 bits (see asid.rs).
+pub type Asid = u16;
+
+// tlbi operand: VA[55:12] in bits 43:0, ASID in bits 63:48
+fn operand(asid: Option<Asid>, virt: VirtAddr) -> u64 {
+    let page = (virt >> 12) & 0xFFF_FFFF_FFFF;
+    match asid {
+        Some(asid) => page | (asid as u64) << 48,
+        None => page,
+    }
+}
+
+/// Invalidate one page on every CPU. `None` is a global (kernel) mapping.
+pub fn flush_page(asid: Option<Asid>, virt: VirtAddr) {
+    let op = operand(asid, virt);
+    unsafe {
+        // The table update must be visible before the invalidation
+        asm!("dsb ishst");
+        match asid {
+            Some(_) => asm!("tlbi vae1is, {}", in(reg) op),
+            None => asm!("tlbi vaae1is, {}", in(reg) op),
+        }
+        asm!("dsb ish");
+        asm!("isb");
+    }
+}
+
+/// Invalidate one page on this CPU only.
+pub fn flush_page_local(asid: Option<Asid>, virt: VirtAddr) {
+    let op = operand(asid, virt);
+    unsafe {
+        asm!("dsb nshst");
+        match asid {
+            Some(_) => asm!("tlbi vae1, {}", in(reg) op),
+            None => asm!("tlbi vaae1, {}", in(reg) op),
+        }
+        asm!("dsb nsh");
+        asm!("isb");
+    }
+}
+
+/// Invalidate every non-global entry of an address space on every CPU.
+pub fn flush_asid(asid: Asid) {
+    let op = (asid as u64) << 48;
+    unsafe {
+        asm!("dsb ishst");
+        asm!("tlbi aside1is, {}", in(reg) op);
+        asm!("dsb ish");
+        asm!("isb");
+    }
+}
+
+/// Invalidate every non-global entry of an address space on this CPU only.
+pub fn flush_asid_local(asid: Asid) {
+    let op = (asid as u64) << 48;
+    unsafe {
+        asm!("dsb nshst");
+        asm!("tlbi aside1, {}", in(reg) op);
+        asm!("dsb nsh");
+        asm!("isb");
+    }
+}
+
+/// Invalidate everything on this CPU only.
+pub fn flush_all_local() {
+    unsafe {
+        asm!("dsb nshst");
+        asm!("tlbi vmalle1");
+        asm!("dsb nsh");
+        asm!("isb");
+    }
+}
+
+/// Invalidate everything on every CPU.
+pub fn flush_all() {
+    unsafe {
+        asm!("dsb ishst");
+        asm!("tlbi vmalle1is");
+        asm!("dsb ish");
+        asm!("isb");
+    }
+}
@@ -0,0 +1,171 @@
+// Network device layer: registry and the few protocol headers the kernel
+// writes itself
+//
+// There is no network stack; kernel services that talk to the network
+// (netconsole) build whole Ethernet frames with the helpers here and hand
+// them to a device.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub type MacAddr = [u8; 6];
+pub type Ipv4Addr = [u8; 4];
+
+pub const MAC_BROADCAST: MacAddr = [0xFF; 6];
+
+pub const ETH_HEADER_LEN: usize = 14;
+pub const IPV4_HEADER_LEN: usize = 20;
+pub const UDP_HEADER_LEN: usize = 8;
+pub const UDP_FRAME_OVERHEAD: usize = ETH_HEADER_LEN + IPV4_HEADER_LEN + UDP_HEADER_LEN;
+
+/// Payload of a standard 1500-byte MTU, without the Ethernet header.
+pub const ETH_MTU: usize = 1500;
+/// Largest UDP payload that fits one unfragmented frame.
+pub const UDP_PAYLOAD_MAX: usize = ETH_MTU - IPV4_HEADER_LEN - UDP_HEADER_LEN;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const IPPROTO_UDP: u8 = 17;
+const IPV4_DEFAULT_TTL: u8 = 64;
+
+/// A device that sends and receives Ethernet frames (without FCS).
+pub trait NetDevice: Send + Sync {
+    fn mac(&self) -> MacAddr;
+    
+    /// Send one frame; returns once the device has taken it. May be called
+    /// from several CPUs at once: multiqueue devices give each CPU its own
+    /// transmit queue rather than serializing them.
+    fn transmit(&self, frame: &[u8]) -> Result<(), &'static str>;
+    
+    /// Copy the next received frame into `buf`, returning its length.
+    fn receive(&self, buf: &mut [u8]) -> Option<usize>;
+}
+
+struct NetEntry {
+    name: String,
+    device: Arc<dyn NetDevice>,
+}
+
+static NET_DEVICES: Mutex<Vec<NetEntry>> = Mutex::new(Vec::new());
+
+/// Register a device as `name` ("eth0", ...).
+pub fn register(name: &str, device: Arc<dyn NetDevice>) -> Result<(), &'static str> {
+    let mut devices = NET_DEVICES.lock();
+    if devices.iter().any(|entry| entry.name == name) {
+        return Err("Network device name already registered");
+    }
+    let mac = device.mac();
+    devices.push(NetEntry {
+        name: String::from(name),
+        device,
+    });
+    drop(devices);
+    crate::println!("Net: {} registered ({})", name, MacDisplay(&mac));
+    Ok(())
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn NetDevice>> {
+    NET_DEVICES
+        .lock()
+        .iter()
+        .find(|entry| entry.name == name)
+        .map(|entry| entry.device.clone())
+}
+
+pub fn list() -> Vec<String> {
+    NET_DEVICES.lock().iter().map(|entry| entry.name.clone()).collect()
+}
+
+/// Formats a MAC address as "52:54:00:12:34:56".
+pub struct MacDisplay<'a>(pub &'a MacAddr);
+
+impl core::fmt::Display for MacDisplay<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let m = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m[0], m[1], m[2], m[3], m[4], m[5])
+    }
+}
+
+/// Parse "10.0.2.15".
+pub fn parse_ipv4(s: &str) -> Option<Ipv4Addr> {
+    let mut addr = [0u8; 4];
+    let mut parts = s.split('.');
+    for octet in addr.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    parts.next().is_none().then_some(addr)
+}
+
+/// Parse "52:54:00:12:34:56".
+pub fn parse_mac(s: &str) -> Option<MacAddr> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split(':');
+    for byte in mac.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
+
+/// Internet checksum (RFC 1071) of `data`.
+pub fn ip_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Endpoints of a UDP flow, as seen by the sender.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UdpEndpoints {
+    pub src_mac: MacAddr,
+    pub dst_mac: MacAddr,
+    pub src_ip: Ipv4Addr,
+    pub dst_ip: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+/// Write an Ethernet/IPv4/UDP frame carrying `payload` into `frame`,
+/// replacing its contents. The UDP checksum is left out (0), which IPv4
+/// allows. `frame` should have room for UDP_FRAME_OVERHEAD + payload so
+/// this does not allocate.
+pub fn build_udp_frame(frame: &mut Vec<u8>, flow: &UdpEndpoints, ident: u16,
+                       payload: &[u8]) -> Result<(), &'static str> {
+    if payload.len() > UDP_PAYLOAD_MAX {
+        return Err("UDP payload exceeds the MTU");
+    }
+    let udp_len = (UDP_HEADER_LEN + payload.len()) as u16;
+    let ip_len = IPV4_HEADER_LEN as u16 + udp_len;
+    
+    frame.clear();
+    frame.extend_from_slice(&flow.dst_mac);
+    frame.extend_from_slice(&flow.src_mac);
+    frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    
+    let ip_start = frame.len();
+    frame.extend_from_slice(&[0x45, 0]); // Version 4, 5-word header; no DSCP
+    frame.extend_from_slice(&ip_len.to_be_bytes());
+    frame.extend_from_slice(&ident.to_be_bytes());
+    frame.extend_from_slice(&0x4000u16.to_be_bytes()); // Don't fragment
+    frame.extend_from_slice(&[IPV4_DEFAULT_TTL, IPPROTO_UDP, 0, 0]);
+    frame.extend_from_slice(&flow.src_ip);
+    frame.extend_from_slice(&flow.dst_ip);
+    let checksum = ip_checksum(&frame[ip_start..]);
+    frame[ip_start + 10..ip_start + 12].copy_from_slice(&checksum.to_be_bytes());
+    
+    frame.extend_from_slice(&flow.src_port.to_be_bytes());
+    frame.extend_from_slice(&flow.dst_port.to_be_bytes());
+    frame.extend_from_slice(&udp_len.to_be_bytes());
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(payload);
+    Ok(())
+}
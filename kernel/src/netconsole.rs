@@ -0,0 +1,253 @@
+// Netconsole: the kernel log mirrored to a UDP listener
+//
+// Configured from the bootargs or the shell with Linux's syntax:
+//
+//   netconsole=[src-port]@[src-ip]/[dev],[dst-port]@<dst-ip>/[dst-mac]
+//
+// Omitted fields default to port 6665 -> 6666, source 10.0.2.15 (QEMU user
+// networking), the first network device and the broadcast MAC. When the
+// console starts, everything already in the log ring is sent first, so
+// messages from before the device came up are not lost. Output is sent a
+// line at a time; listen with e.g. `nc -u -l 6666`.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::ktest::kernel_test;
+use crate::net::{self, Ipv4Addr, MacAddr, NetDevice, UdpEndpoints, MAC_BROADCAST, UDP_FRAME_OVERHEAD, UDP_PAYLOAD_MAX};
+use crate::sync::IrqSafeMutex;
+
+const DEFAULT_SRC_PORT: u16 = 6665;
+const DEFAULT_DST_PORT: u16 = 6666;
+const DEFAULT_SRC_IP: Ipv4Addr = [10, 0, 2, 15];
+
+// Output not yet sent; beyond this the oldest is dropped
+const PENDING_MAX: usize = 4 * UDP_PAYLOAD_MAX;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetConsoleConfig {
+    pub device: Option<String>,
+    pub src_ip: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_ip: Ipv4Addr,
+    pub dst_port: u16,
+    pub dst_mac: MacAddr,
+}
+
+impl NetConsoleConfig {
+    /// Parse the part after "netconsole=".
+    pub fn parse(s: &str) -> Result<Self, &'static str> {
+        const BAD: &str = "expected [src-port]@[src-ip]/[dev],[dst-port]@<dst-ip>/[dst-mac]";
+        let (local, remote) = s.split_once(',').ok_or(BAD)?;
+        let (src_port, rest) = local.split_once('@').ok_or(BAD)?;
+        let (src_ip, device) = rest.split_once('/').unwrap_or((rest, ""));
+        let (dst_port, rest) = remote.split_once('@').ok_or(BAD)?;
+        let (dst_ip, dst_mac) = rest.split_once('/').unwrap_or((rest, ""));
+        
+        let port = |s: &str, default| if s.is_empty() { Ok(default) } else { s.parse().map_err(|_| "bad port") };
+        Ok(Self {
+            device: (!device.is_empty()).then(|| String::from(device)),
+            src_ip: if src_ip.is_empty() { DEFAULT_SRC_IP } else { net::parse_ipv4(src_ip).ok_or("bad source IP")? },
+            src_port: port(src_port, DEFAULT_SRC_PORT)?,
+            dst_ip: net::parse_ipv4(dst_ip).ok_or("bad destination IP")?,
+            dst_port: port(dst_port, DEFAULT_DST_PORT)?,
+            dst_mac: if dst_mac.is_empty() { MAC_BROADCAST } else { net::parse_mac(dst_mac).ok_or("bad destination MAC")? },
+        })
+    }
+}
+
+struct NetConsole {
+    config: NetConsoleConfig,
+    device_name: String,
+    device: Arc<dyn NetDevice>,
+    flow: UdpEndpoints,
+    // Both sized up front: output may come from interrupt context
+    pending: Vec<u8>,
+    frame: Vec<u8>,
+    ident: u16,
+    packets: u64,
+    dropped: u64,
+}
+
+impl NetConsole {
+    fn send(&mut self, payload_len: usize) -> Result<(), &'static str> {
+        net::build_udp_frame(&mut self.frame, &self.flow, self.ident, &self.pending[..payload_len])?;
+        self.ident = self.ident.wrapping_add(1);
+        self.device.transmit(&self.frame)?;
+        self.pending.drain(..payload_len);
+        self.packets += 1;
+        Ok(())
+    }
+    
+    // Send complete lines, packing as many as fit in each packet
+    fn flush(&mut self) -> Result<(), &'static str> {
+        loop {
+            let window = &self.pending[..self.pending.len().min(UDP_PAYLOAD_MAX)];
+            let len = match window.iter().rposition(|&b| b == b'\n') {
+                Some(newline) => newline + 1,
+                // A line too long for one packet goes out in pieces
+                None if window.len() == UDP_PAYLOAD_MAX => window.len(),
+                None => return Ok(()),
+            };
+            self.send(len)?;
+        }
+    }
+    
+    fn queue(&mut self, bytes: &[u8]) {
+        let excess = (self.pending.len() + bytes.len()).saturating_sub(PENDING_MAX);
+        let from_pending = excess.min(self.pending.len());
+        self.pending.drain(..from_pending);
+        self.pending.extend_from_slice(&bytes[excess - from_pending..]);
+        self.dropped += excess as u64;
+    }
+}
+
+static CONSOLE: IrqSafeMutex<Option<NetConsole>> = IrqSafeMutex::new(None);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Start a netconsole from the "netconsole=" bootarg, if given. Network
+/// drivers must have been probed.
+pub fn init() {
+    let Some(arg) = crate::bootparams::get("netconsole") else {
+        return;
+    };
+    match NetConsoleConfig::parse(arg).and_then(start) {
+        Ok(()) => {}
+        Err(e) => crate::println!("Netconsole: Not started: {}", e),
+    }
+}
+
+/// Start (or restart) mirroring the log with `config`, beginning with
+/// the history still in the log ring.
+pub fn start(config: NetConsoleConfig) -> Result<(), &'static str> {
+    stop();
+    let device_name = match &config.device {
+        Some(name) => name.clone(),
+        None => net::list().into_iter().next().ok_or("no network device")?,
+    };
+    let device = net::get(&device_name).ok_or("no such network device")?;
+    let flow = UdpEndpoints {
+        src_mac: device.mac(),
+        dst_mac: config.dst_mac,
+        src_ip: config.src_ip,
+        dst_ip: config.dst_ip,
+        src_port: config.src_port,
+        dst_port: config.dst_port,
+    };
+    let mut console = NetConsole {
+        config,
+        device_name,
+        device,
+        flow,
+        pending: Vec::with_capacity(PENDING_MAX),
+        frame: Vec::with_capacity(UDP_FRAME_OVERHEAD + UDP_PAYLOAD_MAX),
+        ident: 0,
+        packets: 0,
+        dropped: 0,
+    };
+    
+    // Replay with the log held so nothing is printed in between
+    let result = crate::klog::with_log(|log| {
+        for chunk in log.tail(usize::MAX).chunks(PENDING_MAX) {
+            console.queue(chunk);
+            console.flush()?;
+        }
+        *CONSOLE.lock() = Some(console);
+        ENABLED.store(true, Ordering::Release);
+        Ok(())
+    });
+    if result.is_ok() {
+        if let Some(console) = CONSOLE.lock().as_ref() {
+            crate::println!("Netconsole: Logging to {}.{}.{}.{}:{} via {}",
+                           console.config.dst_ip[0], console.config.dst_ip[1],
+                           console.config.dst_ip[2], console.config.dst_ip[3],
+                           console.config.dst_port, console.device_name);
+        }
+    }
+    result
+}
+
+/// Stop mirroring; unsent output is discarded.
+pub fn stop() {
+    ENABLED.store(false, Ordering::Release);
+    CONSOLE.lock().take();
+}
+
+/// Mirror console output. Called from the log with it held.
+pub fn write(bytes: &[u8]) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    // Output from a transmit in progress (an error message, say) is only
+    // kept in the log
+    let Some(mut console) = CONSOLE.try_lock() else {
+        return;
+    };
+    let Some(console) = console.as_mut() else {
+        return;
+    };
+    console.queue(bytes);
+    if console.flush().is_err() {
+        // Give up rather than stall every line on a dead link
+        ENABLED.store(false, Ordering::Release);
+    }
+}
+
+/// Current configuration, device, packets sent and bytes dropped.
+pub fn status() -> Option<(NetConsoleConfig, String, u64, u64)> {
+    let console = CONSOLE.lock();
+    let console = console.as_ref()?;
+    Some((console.config.clone(), console.device_name.clone(), console.packets, console.dropped))
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[kernel_test]
+fn test_netconsole() {
+    use alloc::vec::Vec;
+    use crate::net::{self, UdpEndpoints, ETH_HEADER_LEN, MAC_BROADCAST, UDP_FRAME_OVERHEAD};
+    
+    crate::println!("Netconsole Test: Testing netconsole...");
+    
+    let full = NetConsoleConfig::parse("4444@10.0.2.16/eth0,9353@10.0.2.2/52:55:0a:00:02:02");
+    let short = NetConsoleConfig::parse("@/,@10.0.2.2/");
+    let parsed = match (&full, &short) {
+        (Ok(full), Ok(short)) => {
+            full.device.as_deref() == Some("eth0") && full.src_port == 4444
+                && full.src_ip == [10, 0, 2, 16] && full.dst_port == 9353
+                && full.dst_mac == [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]
+                && short.device.is_none() && short.src_ip == [10, 0, 2, 15]
+                && short.src_port == 6665 && short.dst_port == 6666 && short.dst_mac == MAC_BROADCAST
+        }
+        _ => false,
+    };
+    if parsed && NetConsoleConfig::parse("6666@10.0.2.2").is_err() {
+        crate::println!("Netconsole Test: ✓ netconsole= syntax parsed with defaults");
+    } else {
+        crate::println!("Netconsole Test: ✗ netconsole= parsing wrong");
+    }
+    
+    let flow = UdpEndpoints {
+        src_mac: [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+        dst_mac: MAC_BROADCAST,
+        src_ip: [10, 0, 2, 15],
+        dst_ip: [10, 0, 2, 2],
+        src_port: 6665,
+        dst_port: 6666,
+    };
+    let mut frame = Vec::new();
+    let built = net::build_udp_frame(&mut frame, &flow, 7, b"hello\n");
+    let ip = &frame[ETH_HEADER_LEN.min(frame.len())..];
+    if built.is_ok() && frame.len() == UDP_FRAME_OVERHEAD + 6 && net::ip_checksum(&ip[..20]) == 0
+        && ip[16..20] == flow.dst_ip && frame.ends_with(b"hello\n")
+    {
+        crate::println!("Netconsole Test: ✓ UDP frame built with a valid IPv4 header");
+    } else {
+        crate::println!("Netconsole Test: ✗ UDP frame malformed");
+    }
+    
+    crate::println!("Netconsole Test: Netconsole test completed");
+}
@@ -0,0 +1,283 @@
+// Recoverable kernel faults ("oops")
+//
+// Code that may fault on memory it cannot vouch for, such as a driver
+// probing registers the device tree may have got wrong, runs inside
+// `guard`. A kernel abort or other unhandled synchronous exception there
+// prints the report a panic would, taints the guarded subsystem and makes
+// `guard` return OOPS instead of taking the system down.
+//
+// The guarded code is abandoned where it faulted, like a longjmp: nothing
+// it owned is dropped and any lock it held stays held. Keep guarded code
+// to work that takes no locks the rest of the kernel needs.
+
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use spin::Mutex;
+use crate::interrupts::ExceptionContext;
+use crate::ktest::kernel_test;
+use crate::process::scheduler::current_thread_id;
+use crate::process::ThreadId;
+
+/// Error from `guard` when the guarded code faulted.
+pub const OOPS: &str = "Kernel fault in guarded code";
+
+// Subsystems remembered by name; oopses beyond them are only counted
+const MAX_TAINTED: usize = 16;
+
+// DAIF bits as they appear in SPSR_EL1 and the DAIF register
+const DAIF_MASK: u64 = 0xF << 6;
+
+// Registers restored by oops_guard_recover: x19-x30, then sp
+#[repr(C)]
+#[derive(Default)]
+struct JumpBuffer {
+    regs: [u64; 12],
+    sp: u64,
+}
+
+// x0 = jump buffer, x1 = function, x2 = its argument. Returns 0 when the
+// function returns, or 1 through oops_guard_recover after a fault.
+global_asm!(
+    ".global oops_guard_call",
+    ".type oops_guard_call, %function",
+    "oops_guard_call:",
+    ".cfi_startproc",
+    "stp x19, x20, [x0]",
+    "stp x21, x22, [x0, #16]",
+    "stp x23, x24, [x0, #32]",
+    "stp x25, x26, [x0, #48]",
+    "stp x27, x28, [x0, #64]",
+    "stp x29, x30, [x0, #80]",
+    "mov x9, sp",
+    "str x9, [x0, #96]",
+    "stp x29, x30, [sp, #-16]!",
+    ".cfi_def_cfa_offset 16",
+    ".cfi_offset 29, -16",
+    ".cfi_offset 30, -8",
+    "mov x29, sp",
+    "mov x0, x2",
+    "blr x1",
+    "ldp x29, x30, [sp], #16",
+    ".cfi_def_cfa_offset 0",
+    ".cfi_restore 29",
+    ".cfi_restore 30",
+    "mov x0, #0",
+    "ret",
+    ".cfi_endproc",
+    ".size oops_guard_call, . - oops_guard_call",
+    "",
+    // Exception return lands here with x0 = the jump buffer
+    ".global oops_guard_recover",
+    ".type oops_guard_recover, %function",
+    "oops_guard_recover:",
+    "ldp x19, x20, [x0]",
+    "ldp x21, x22, [x0, #16]",
+    "ldp x23, x24, [x0, #32]",
+    "ldp x25, x26, [x0, #48]",
+    "ldp x27, x28, [x0, #64]",
+    "ldp x29, x30, [x0, #80]",
+    "ldr x9, [x0, #96]",
+    "mov sp, x9",
+    "mov x0, #1",
+    "ret",
+    ".size oops_guard_recover, . - oops_guard_recover",
+);
+
+extern "C" {
+    fn oops_guard_call(jump: *mut JumpBuffer, f: extern "C" fn(*mut u8), arg: *mut u8) -> u64;
+    fn oops_guard_recover();
+}
+
+struct Guard {
+    tid: ThreadId,
+    subsystem: &'static str,
+    jump: *mut JumpBuffer,
+    // Interrupt mask and IRQ nesting at entry, restored on recovery
+    daif: u64,
+    irq_depth: u32,
+}
+
+// Guards point into their own thread's stack
+unsafe impl Send for Guard {}
+
+/// A subsystem that has oopsed since boot.
+#[derive(Copy, Clone, Debug)]
+pub struct Taint {
+    pub subsystem: &'static str,
+    pub oopses: u32,
+}
+
+struct Taints {
+    subsystems: [Option<Taint>; MAX_TAINTED],
+    total: u32,
+}
+
+// Innermost guard of each thread last
+static GUARDS: Mutex<Vec<Guard>> = Mutex::new(Vec::new());
+// Fixed size: updated from the exception handler, which must not allocate
+static TAINTS: Mutex<Taints> = Mutex::new(Taints { subsystems: [None; MAX_TAINTED], total: 0 });
+
+/// Export /proc/tainted.
+pub fn init() {
+    let _ = crate::procfs::register("tainted", proc_tainted);
+}
+
+/// Run `f` so that a kernel fault inside it oopses `subsystem` and
+/// returns OOPS rather than panicking. Guards nest.
+pub fn guard<R>(subsystem: &'static str, f: impl FnOnce() -> R) -> Result<R, &'static str> {
+    let mut jump = JumpBuffer::default();
+    let daif: u64;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack, preserves_flags));
+    }
+    let tid = current_thread_id();
+    GUARDS.lock().push(Guard {
+        tid,
+        subsystem,
+        jump: &mut jump,
+        daif,
+        irq_depth: crate::interrupts::irq_depth(),
+    });
+    
+    let mut result = None;
+    let faulted = call_guarded(&mut jump, &mut Some(|| result = Some(f())));
+    
+    let mut guards = GUARDS.lock();
+    if let Some(at) = guards.iter().rposition(|guard| guard.tid == tid) {
+        guards.remove(at);
+    }
+    drop(guards);
+    if faulted {
+        return Err(OOPS);
+    }
+    result.ok_or(OOPS)
+}
+
+// True if `call` faulted
+fn call_guarded<F: FnOnce()>(jump: &mut JumpBuffer, call: &mut Option<F>) -> bool {
+    unsafe { oops_guard_call(jump, run_guarded::<F>, call as *mut Option<F> as *mut u8) != 0 }
+}
+
+extern "C" fn run_guarded<F: FnOnce()>(call: *mut u8) {
+    let call = unsafe { &mut *(call as *mut Option<F>) };
+    if let Some(call) = call.take() {
+        call();
+    }
+}
+
+/// Called for a fault the kernel cannot handle. If the faulting code is
+/// guarded, report the oops and point `ctx` at the guard's recovery path.
+pub fn recover(ctx: &mut ExceptionContext, args: fmt::Arguments) -> bool {
+    let tid = current_thread_id();
+    let depth = crate::interrupts::irq_depth();
+    // A fault in an interrupt handler belongs to the handler, not to the
+    // guarded code it interrupted
+    let found = GUARDS.lock().iter().rev()
+        .find(|guard| guard.tid == tid && guard.irq_depth == depth)
+        .map(|guard| (guard.subsystem, guard.jump, guard.daif));
+    let Some((subsystem, jump, daif)) = found else {
+        return false;
+    };
+    
+    let count = taint(subsystem);
+    crate::println!("Oops: {}", args);
+    crate::println!("Oops: #{} in {}, thread {}", count, subsystem, crate::process::scheduler::thread_ref(tid));
+    crate::panic::dump_exception(ctx);
+    crate::println!("Oops: {} tainted, operation failed; continuing", subsystem);
+    
+    ctx.elr_el1 = oops_guard_recover as *const () as u64;
+    ctx.x0 = jump as u64;
+    ctx.spsr_el1 = (ctx.spsr_el1 & !DAIF_MASK) | (daif & DAIF_MASK);
+    true
+}
+
+// Count an oops against `subsystem`; returns the total so far
+fn taint(subsystem: &'static str) -> u32 {
+    let mut taints = TAINTS.lock();
+    taints.total += 1;
+    let slot = taints.subsystems.iter().position(|taint| {
+        taint.is_none_or(|taint| taint.subsystem == subsystem)
+    });
+    if let Some(slot) = slot {
+        let taint = taints.subsystems[slot].get_or_insert(Taint { subsystem, oopses: 0 });
+        taint.oopses += 1;
+    }
+    taints.total
+}
+
+/// Whether `subsystem` has oopsed.
+pub fn is_tainted(subsystem: &str) -> bool {
+    TAINTS.lock().subsystems.iter().flatten().any(|taint| taint.subsystem == subsystem)
+}
+
+/// Oopses since boot.
+pub fn oops_count() -> u32 {
+    TAINTS.lock().total
+}
+
+/// Tainted subsystems, in the order they first oopsed.
+pub fn tainted() -> Vec<Taint> {
+    TAINTS.lock().subsystems.iter().flatten().copied().collect()
+}
+
+/// Print the tainted subsystems on one line, if any, for panic reports.
+pub fn print_tainted() {
+    let Some(taints) = TAINTS.try_lock() else { return };
+    if taints.total == 0 {
+        return;
+    }
+    crate::print!("Tainted: {} oopses,", taints.total);
+    for taint in taints.subsystems.iter().flatten() {
+        crate::print!(" {}({})", taint.subsystem, taint.oopses);
+    }
+    crate::println!();
+}
+
+fn proc_tainted(out: &mut Vec<u8>) {
+    let mut text = alloc::string::String::new();
+    let _ = writeln!(text, "oopses {}", oops_count());
+    for taint in tainted() {
+        let _ = writeln!(text, "{} {}", taint.subsystem, taint.oopses);
+    }
+    out.extend_from_slice(text.as_bytes());
+}
+
+// Outside every translation regime: bits 55:48 are set, which neither
+// TTBR covers and no implemented physical address reaches
+const OOPS_TEST_ADDR: u64 = 0x00FF_0000_0000_0000;
+
+fn oops_test_read() -> u64 {
+    unsafe { core::ptr::read_volatile(OOPS_TEST_ADDR as *const u64) }
+}
+
+#[kernel_test]
+fn test_oops_recovery() {
+    
+    crate::println!("Oops Test: Testing oops recovery...");
+    
+    let before = oops_count();
+    let result = guard("oops-test", oops_test_read);
+    if result == Err(OOPS) && oops_count() == before + 1 && is_tainted("oops-test") {
+        crate::println!("Oops Test: ✓ Guarded fault failed the operation and tainted it");
+    } else {
+        crate::println!("Oops Test: ✗ Guarded fault gave {:?}", result);
+    }
+    
+    let result = guard("oops-test-clean", || 42);
+    if result == Ok(42) && !is_tainted("oops-test-clean") {
+        crate::println!("Oops Test: ✓ Guarded code that does not fault returns its value");
+    } else {
+        crate::println!("Oops Test: ✗ Clean guard gave {:?}", result);
+    }
+    
+    // The innermost guard takes the fault; the outer one carries on
+    let result = guard("oops-test-outer", || guard("oops-test", oops_test_read).is_err());
+    if result == Ok(true) && !is_tainted("oops-test-outer") {
+        crate::println!("Oops Test: ✓ Nested guard recovered without unwinding the outer one");
+    } else {
+        crate::println!("Oops Test: ✗ Nested guard gave {:?}", result);
+    }
+    
+    crate::println!("Oops Test: Oops recovery test completed");
+}
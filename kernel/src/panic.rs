@@ -0,0 +1,370 @@
+// Kernel panic handling
+//
+// A panic first stops the other CPUs with an IPI, then reports what it can
+// without leaning on the rest of the kernel: the message, the registers
+// (the faulting context when the panic came from an exception handler,
+// otherwise the panicking code's own) and a backtrace, from frame records
+// or, with the eh-unwind feature, the kernel's DWARF CFI. The same state
+// goes into a crash dump for the next boot (crashdump.rs).
+// Frames are named from the embedded symbol table when the build filled it
+// in; otherwise `make symbolize ADDRS="..."` resolves the raw addresses
+// against the kernel ELF, once the reported kernel offset (kaslr.rs) is
+// taken off them. Finally the persistent log is flushed and panic=
+// decides between halting, rebooting and powering off.
+
+use core::arch::asm;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering};
+use crate::backtrace::{self, StartFrame};
+use crate::cpu::cpu_index;
+use crate::interrupts::ExceptionContext;
+use crate::ktest::kernel_test;
+
+// Return addresses printed in a backtrace
+const PANIC_MAX_FRAMES: usize = 32;
+
+// How far above its starting point a backtrace may read outside the
+// thread stacks, whose exact bounds are known: the 64KB boot stack is the
+// largest kernel stack
+const PANIC_STACK_WINDOW: u64 = 64 * 1024;
+
+// Spins to wait for the other CPUs to acknowledge the stop SGI
+const PARK_WAIT_SPINS: u32 = 1_000_000;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+static PARKED_CPUS: AtomicU32 = AtomicU32::new(0);
+
+// Set by exception_panic so the report shows the faulting registers
+static EXCEPTION_CONTEXT: AtomicPtr<ExceptionContext> = AtomicPtr::new(ptr::null_mut());
+// Set by stack_overflow_panic, whose context is not on the faulting stack
+static EXCEPTION_SP: AtomicU64 = AtomicU64::new(0);
+
+/// Panic on behalf of an exception handler, reporting the registers at the
+/// time of the exception rather than the handler's.
+pub fn exception_panic(ctx: &ExceptionContext, args: fmt::Arguments) -> ! {
+    EXCEPTION_CONTEXT.store(ctx as *const _ as *mut _, Ordering::Relaxed);
+    panic!("{}", args)
+}
+
+/// `exception_panic` for a kernel stack overflow: `ctx` was saved on the
+/// overflow stack and `sp` is the stack pointer of the faulting code.
+pub fn stack_overflow_panic(ctx: &ExceptionContext, sp: u64, args: fmt::Arguments) -> ! {
+    EXCEPTION_SP.store(sp, Ordering::Relaxed);
+    exception_panic(ctx, args)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // A should_panic self-test ends its own thread instead
+    if EXCEPTION_CONTEXT.load(Ordering::Relaxed).is_null() {
+        crate::ktest::expected_panic(info);
+    }
+    crate::interrupts::disable_interrupts();
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        crate::println!("KERNEL PANIC while panicking: {}", info.message());
+        halt();
+    }
+    stop_other_cpus();
+    
+    crate::println!("KERNEL PANIC!");
+    crate::println!("Message: {}", info.message());
+    if let Some(location) = info.location() {
+        crate::println!("Location: {}:{}", location.file(), location.line());
+    }
+    crate::println!("CPU {}, thread {}", cpu_index(),
+                   crate::process::scheduler::thread_ref(crate::process::scheduler::current_thread_id()));
+    crate::oops::print_tainted();
+    let offset = crate::memory::kaslr::offset();
+    if offset != 0 {
+        crate::println!("Kernel offset 0x{:x} from the link address", offset);
+    }
+    
+    let ctx = EXCEPTION_CONTEXT.load(Ordering::Relaxed);
+    let (start, stack_low, ctx) = if let Some(ctx) = unsafe { ctx.as_ref() } {
+        dump_exception(ctx);
+        let (start, stack_low) = exception_start(ctx);
+        (start, stack_low, Some(ctx))
+    } else {
+        dump_live_registers();
+        let start = StartFrame::current();
+        print_backtrace(0, backtrace::walk(&start, start.sp(), stack_high(start.sp())));
+        (start, start.sp(), None)
+    };
+    
+    // Keep a dump for the next boot, then make it and the log survive the
+    // reset that usually follows
+    crate::crashdump::save(&crate::crashdump::Report {
+        message: format_args!("{}", info.message()),
+        location: info.location(),
+        start,
+        stack_low,
+        stack_high: stack_high(stack_low),
+        exception: ctx.is_some(),
+        spsr: ctx.map_or(0, |ctx| ctx.spsr_el1),
+    });
+    crate::pstore::flush();
+    
+    // panic=reboot or panic=poweroff (or qemu_test); returns when halting
+    crate::power::panic_exit();
+    
+    halt();
+}
+
+/// Registers and backtrace of the code an exception interrupted, as in a
+/// panic report. Also used for oopses.
+pub fn dump_exception(ctx: &ExceptionContext) {
+    dump_exception_context(ctx);
+    print_exception_backtrace(ctx);
+}
+
+/// Backtrace of the code an exception interrupted.
+pub fn print_exception_backtrace(ctx: &ExceptionContext) {
+    let (start, stack_low) = exception_start(ctx);
+    print_backtrace(ctx.elr_el1, backtrace::walk(&start, stack_low, stack_high(stack_low)));
+}
+
+// Where a walk of the code an exception interrupted starts, and the
+// lowest stack address it may read
+fn exception_start(ctx: &ExceptionContext) -> (StartFrame, u64) {
+    let mut stack_low = ctx as *const ExceptionContext as u64;
+    let mut start = StartFrame::from_context(ctx);
+    let overflow_sp = EXCEPTION_SP.load(Ordering::Relaxed);
+    if overflow_sp != 0 {
+        // Walk the stack that overflowed, not reading its guard
+        start.regs[31] = overflow_sp;
+        stack_low = crate::process::thread::stack_bounds(overflow_sp)
+            .map_or(overflow_sp, |(base, _)| base.max(overflow_sp));
+    }
+    (start, stack_low)
+}
+
+// Where a walk from `low` must stop: the top of its thread stack, with an
+// unmapped guard above, or a fixed window for other stacks
+fn stack_high(low: u64) -> u64 {
+    crate::process::thread::stack_bounds(low).map_or(low + PANIC_STACK_WINDOW, |(_, top)| top)
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("wfe") };
+    }
+}
+
+// Stop SGI handler: never returns, so the SGI is never completed either
+/// Stop IPI handler (ipi.rs): park this CPU for good.
+pub(crate) fn park_cpu(_irq: u32) {
+    crate::interrupts::disable_interrupts();
+    PARKED_CPUS.fetch_add(1, Ordering::AcqRel);
+    halt();
+}
+
+fn stop_other_cpus() {
+    // Without a GIC there is only the boot CPU to stop
+    if !crate::gic::is_present() {
+        return;
+    }
+    let others = match crate::ipi::send_others(crate::ipi::IpiKind::Stop) {
+        Ok(others) if others != 0 => others,
+        _ => return,
+    };
+    let expected = others.count_ones();
+    for _ in 0..PARK_WAIT_SPINS {
+        if PARKED_CPUS.load(Ordering::Acquire) >= expected {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    crate::println!("Panic: {} of {} other CPUs did not stop", expected - PARKED_CPUS.load(Ordering::Acquire), expected);
+}
+
+fn read_syndrome() -> (u64, u64) {
+    let (esr, far): (u64, u64);
+    unsafe {
+        asm!("mrs {}, esr_el1", out(reg) esr);
+        asm!("mrs {}, far_el1", out(reg) far);
+    }
+    (esr, far)
+}
+
+// Three registers per line, numbered from `first`
+fn print_registers(first: usize, values: &[u64]) {
+    for (row, regs) in values.chunks(3).enumerate() {
+        for (col, value) in regs.iter().enumerate() {
+            crate::print!("  x{:<2} {:016x}", first + row * 3 + col, value);
+        }
+        crate::println!();
+    }
+}
+
+fn dump_exception_context(ctx: &ExceptionContext) {
+    let x = [
+        ctx.x0, ctx.x1, ctx.x2, ctx.x3, ctx.x4, ctx.x5, ctx.x6, ctx.x7,
+        ctx.x8, ctx.x9, ctx.x10, ctx.x11, ctx.x12, ctx.x13, ctx.x14, ctx.x15,
+        ctx.x16, ctx.x17, ctx.x18, ctx.x19, ctx.x20, ctx.x21, ctx.x22, ctx.x23,
+        ctx.x24, ctx.x25, ctx.x26, ctx.x27, ctx.x28, ctx.x29, ctx.x30,
+    ];
+    crate::println!("Registers at exception:");
+    print_registers(0, &x);
+    let (esr, far) = read_syndrome();
+    // EL1h exceptions push the frame on the interrupted stack
+    let sp = ctx as *const ExceptionContext as u64 + core::mem::size_of::<ExceptionContext>() as u64;
+    crate::println!("  pc  {:016x}  sp  {:016x}  sp_el0 {:016x}", ctx.elr_el1, sp, ctx.sp_el0);
+    crate::println!("  spsr {:08x}  esr {:08x}  far {:016x}", ctx.spsr_el1, esr, far);
+}
+
+// x19-x30 are all that survive into the handler in a meaningful state
+fn dump_live_registers() {
+    let mut regs = [0u64; 12];
+    unsafe {
+        asm!(
+            "stp x19, x20, [{0}]",
+            "stp x21, x22, [{0}, #16]",
+            "stp x23, x24, [{0}, #32]",
+            "stp x25, x26, [{0}, #48]",
+            "stp x27, x28, [{0}, #64]",
+            "stp x29, x30, [{0}, #80]",
+            in(reg) regs.as_mut_ptr(),
+            options(nostack, preserves_flags),
+        );
+    }
+    crate::println!("Registers at panic (callee-saved):");
+    print_registers(19, &regs);
+    let daif: u64;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif);
+    }
+    let (esr, far) = read_syndrome();
+    crate::println!("  sp  {:016x}  daif {:x}  esr {:08x}  far {:016x}", backtrace::current_sp(), daif >> 6, esr, far);
+}
+
+fn print_frame(depth: usize, addr: u64) {
+    match crate::symbols::lookup(addr) {
+        Some(symbol) => crate::println!("  #{:<2} {:016x} {}", depth, addr, symbol),
+        None => crate::println!("  #{:<2} {:016x}", depth, addr),
+    }
+}
+
+fn print_backtrace(pc: u64, frames: backtrace::Walk) {
+    crate::println!("Backtrace:");
+    let mut depth = 0;
+    if pc != 0 {
+        print_frame(depth, pc);
+        depth += 1;
+    }
+    for lr in frames.take(PANIC_MAX_FRAMES) {
+        // The return address points after the call; report the call itself
+        print_frame(depth, lr.wrapping_sub(4));
+        depth += 1;
+    }
+    if depth == 0 {
+        crate::println!("  (no frames)");
+    }
+}
+
+// Three real frames deep, so the walk has records to follow
+#[inline(never)]
+fn backtrace_depth_3(out: &mut [u64; 8]) -> usize {
+    let start = crate::backtrace::StartFrame::current();
+    let frames = crate::backtrace::walk(&start, start.sp(), start.sp() + 64 * 1024);
+    let mut count = 0;
+    for (slot, lr) in out.iter_mut().zip(frames) {
+        *slot = lr;
+        count += 1;
+    }
+    core::hint::black_box(count)
+}
+
+#[inline(never)]
+fn backtrace_depth_2(out: &mut [u64; 8]) -> usize {
+    core::hint::black_box(backtrace_depth_3(out))
+}
+
+#[inline(never)]
+fn backtrace_depth_1(out: &mut [u64; 8]) -> usize {
+    core::hint::black_box(backtrace_depth_2(out))
+}
+
+#[cfg(feature = "eh-unwind")]
+#[inline(never)]
+fn backtrace_leaf(x: u64) -> u64 {
+    core::hint::black_box(x) + 1
+}
+
+#[cfg(feature = "eh-unwind")]
+fn test_cfi_unwind() {
+    use crate::backtrace::{self, StartFrame};
+    
+    // Where both walks see frames they must agree
+    let start = StartFrame::current();
+    let high = start.sp() + 64 * 1024;
+    let cfi = backtrace::walk(&start, start.sp(), high).take(4);
+    let records = backtrace::frames(start.regs[29], start.sp(), high).take(4);
+    let (mut compared, mut agree) = (0, true);
+    for (a, b) in cfi.zip(records) {
+        agree &= a == b;
+        compared += 1;
+    }
+    if compared >= 2 && agree {
+        crate::println!("Panic Test: ✓ CFI unwind matches {} frame records", compared);
+    } else {
+        crate::println!("Panic Test: ✗ CFI unwind and frame records disagree");
+    }
+    
+    // Stopped on a function's first instruction, before its prologue has
+    // saved anything, the caller comes from x30
+    let caller = test_cfi_unwind as fn() as usize as u64 + 4;
+    let mut leaf = start;
+    leaf.pc = backtrace_leaf as fn(u64) -> u64 as usize as u64;
+    leaf.regs[30] = caller;
+    if backtrace::walk(&leaf, leaf.sp(), high).next() == Some(caller) {
+        crate::println!("Panic Test: ✓ CFI unwind finds a leaf function's caller");
+    } else {
+        crate::println!("Panic Test: ✗ CFI unwind lost a leaf function's caller");
+    }
+}
+
+#[kernel_test]
+fn test_panic_support() {
+    use crate::memory::paging::KERNEL_VIRT_OFFSET;
+    use crate::ipi::IpiKind;
+    
+    crate::println!("Panic Test: Testing panic support...");
+    
+    let mut callers = [0u64; 8];
+    let depth = backtrace_depth_1(&mut callers);
+    let callers = &callers[..depth];
+    // Each return address follows the call, so they are distinct and kernel text
+    let distinct = callers.windows(2).all(|pair| pair[0] != pair[1]);
+    if depth >= 3 && distinct && callers.iter().all(|&lr| lr >= KERNEL_VIRT_OFFSET && lr % 4 == 0) {
+        crate::println!("Panic Test: ✓ Frame-pointer walk found {} callers", depth);
+    } else {
+        crate::println!("Panic Test: ✗ Frame-pointer walk gave {:x?}", callers);
+    }
+    
+    #[cfg(feature = "eh-unwind")]
+    test_cfi_unwind();
+    
+    // A chain that does not climb must stop rather than loop
+    let mut looped = [0u64, 0x1234];
+    looped[0] = looped.as_ptr() as u64;
+    let base = looped.as_ptr() as u64;
+    if crate::backtrace::frames(base, base - 16, base + 16).count() == 1 {
+        crate::println!("Panic Test: ✓ Self-referencing frame record ends the walk");
+    } else {
+        crate::println!("Panic Test: ✗ Walk followed a looping frame record");
+    }
+    
+    if crate::gic::is_present() {
+        let installed = crate::gic::has_handler(IpiKind::Stop.sgi());
+        let rejected = crate::gic::send_sgi(crate::gic::NUM_SGIS, 0).is_err();
+        if installed && rejected {
+            crate::println!("Panic Test: ✓ Panic stop SGI {} installed", IpiKind::Stop.sgi());
+        } else {
+            crate::println!("Panic Test: ✗ Panic stop SGI installed {}, bad SGI rejected {}", installed, rejected);
+        }
+    }
+    
+    crate::println!("Panic Test: Panic support test completed");
+}
@@ -0,0 +1,256 @@
+// Performance monitor unit
+//
+// Counts cycles in PMCCNTR and three architected events in the first
+// event counters: instructions retired, L1 data cache refills (misses)
+// and mispredicted branches. The counters run freely from boot at EL0
+// and EL1; users take differences between two snapshots, either around
+// a piece of code with PmuScope or per thread, which the scheduler does
+// at every context switch.
+//
+// Event counters are 32 bits wide, so differences are taken modulo 2^32:
+// a measurement must not span more than 2^32 events. Events the CPU does
+// not implement (PMCEID0) read as zero; QEMU's TCG only has a few.
+
+use core::arch::asm;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::ktest::kernel_test;
+
+// ID_AA64DFR0_EL1.PMUVer: 0 none, 0xF implementation defined
+const DFR0_PMUVER_SHIFT: u64 = 8;
+
+// PMCR_EL0: enable, reset event counters, reset cycle counter, 64-bit
+// cycle counter; N is the number of event counters
+const PMCR_E: u64 = 1 << 0;
+const PMCR_P: u64 = 1 << 1;
+const PMCR_C: u64 = 1 << 2;
+const PMCR_LC: u64 = 1 << 6;
+const PMCR_N_SHIFT: u64 = 11;
+
+// PMCNTENSET_EL0 bit of the cycle counter
+const PMCNTEN_CYCLES: u64 = 1 << 31;
+
+/// Architected common events counted, in event counter order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Instructions = 0x08,    // INST_RETIRED
+    CacheMisses = 0x03,     // L1D_CACHE_REFILL
+    BranchMisses = 0x10,    // BR_MIS_PRED
+}
+
+const EVENTS: [Event; 3] = [Event::Instructions, Event::CacheMisses, Event::BranchMisses];
+
+static PRESENT: AtomicBool = AtomicBool::new(false);
+// Bit n set when EVENTS[n] is both implemented and has a counter
+static COUNTING: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PmuCounts {
+    pub cycles: u64,
+    pub instructions: u64,
+    pub cache_misses: u64,
+    pub branch_misses: u64,
+}
+
+impl PmuCounts {
+    pub const ZERO: PmuCounts = PmuCounts { cycles: 0, instructions: 0, cache_misses: 0, branch_misses: 0 };
+}
+
+impl Sub for PmuCounts {
+    type Output = PmuCounts;
+    
+    // Event counters wrap at 32 bits
+    fn sub(self, earlier: PmuCounts) -> PmuCounts {
+        let delta32 = |now: u64, then: u64| (now as u32).wrapping_sub(then as u32) as u64;
+        PmuCounts {
+            cycles: self.cycles.wrapping_sub(earlier.cycles),
+            instructions: delta32(self.instructions, earlier.instructions),
+            cache_misses: delta32(self.cache_misses, earlier.cache_misses),
+            branch_misses: delta32(self.branch_misses, earlier.branch_misses),
+        }
+    }
+}
+
+impl Add for PmuCounts {
+    type Output = PmuCounts;
+    
+    fn add(self, other: PmuCounts) -> PmuCounts {
+        PmuCounts {
+            cycles: self.cycles + other.cycles,
+            instructions: self.instructions + other.instructions,
+            cache_misses: self.cache_misses + other.cache_misses,
+            branch_misses: self.branch_misses + other.branch_misses,
+        }
+    }
+}
+
+impl AddAssign for PmuCounts {
+    fn add_assign(&mut self, other: PmuCounts) {
+        *self = *self + other;
+    }
+}
+
+fn write_event_type(n: usize, event: u64) {
+    unsafe {
+        match n {
+            0 => asm!("msr pmevtyper0_el0, {}", in(reg) event),
+            1 => asm!("msr pmevtyper1_el0, {}", in(reg) event),
+            2 => asm!("msr pmevtyper2_el0, {}", in(reg) event),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn read_event_counter(n: usize) -> u64 {
+    let value: u64;
+    unsafe {
+        match n {
+            0 => asm!("mrs {}, pmevcntr0_el0", out(reg) value),
+            1 => asm!("mrs {}, pmevcntr1_el0", out(reg) value),
+            2 => asm!("mrs {}, pmevcntr2_el0", out(reg) value),
+            _ => unreachable!(),
+        }
+    }
+    value
+}
+
+/// Program and start the counters on this CPU.
+pub fn init() {
+    let dfr0: u64;
+    unsafe {
+        asm!("mrs {}, id_aa64dfr0_el1", out(reg) dfr0);
+    }
+    let version = (dfr0 >> DFR0_PMUVER_SHIFT) & 0xF;
+    if version == 0 || version == 0xF {
+        crate::println!("PMU: No PMUv3, counters unavailable");
+        return;
+    }
+    
+    let (pmcr, ceid0): (u64, u64);
+    unsafe {
+        asm!("mrs {}, pmcr_el0", out(reg) pmcr);
+        asm!("mrs {}, pmceid0_el0", out(reg) ceid0);
+    }
+    let counters = ((pmcr >> PMCR_N_SHIFT) & 0x1F) as usize;
+    
+    let mut enable = PMCNTEN_CYCLES;
+    let mut counting = 0;
+    for (n, &event) in EVENTS.iter().enumerate().take(counters) {
+        // Count at EL0 and EL1 (no filter bits set)
+        write_event_type(n, event as u64);
+        enable |= 1 << n;
+        if ceid0 & (1 << event as u64) != 0 {
+            counting |= 1 << n;
+        }
+    }
+    unsafe {
+        asm!("msr pmccfiltr_el0, xzr");
+        asm!("msr pmcntenset_el0, {}", in(reg) enable);
+        asm!("msr pmcr_el0, {}", "isb", in(reg) pmcr | PMCR_E | PMCR_P | PMCR_C | PMCR_LC);
+    }
+    
+    COUNTING.store(counting, Ordering::Relaxed);
+    PRESENT.store(true, Ordering::Release);
+    crate::println!("PMU: PMUv3, {} event counters; counting cycles{}{}{}", counters,
+                   if is_counting(Event::Instructions) { ", instructions" } else { "" },
+                   if is_counting(Event::CacheMisses) { ", cache misses" } else { "" },
+                   if is_counting(Event::BranchMisses) { ", branch mispredicts" } else { "" });
+}
+
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+/// Whether `event` is counted; if not it always reads zero.
+pub fn is_counting(event: Event) -> bool {
+    let index = EVENTS.iter().position(|&counted| counted == event).unwrap_or(0);
+    COUNTING.load(Ordering::Relaxed) & (1 << index) != 0
+}
+
+/// The counters right now; all zero without a PMU.
+pub fn read() -> PmuCounts {
+    if !is_present() {
+        return PmuCounts::default();
+    }
+    let counting = COUNTING.load(Ordering::Relaxed);
+    let event = |n: usize| if counting & (1 << n) != 0 { read_event_counter(n) } else { 0 };
+    let cycles: u64;
+    unsafe {
+        asm!("mrs {}, pmccntr_el0", out(reg) cycles);
+    }
+    PmuCounts {
+        cycles,
+        instructions: event(0),
+        cache_misses: event(1),
+        branch_misses: event(2),
+    }
+}
+
+/// A measurement in progress: the counts since `start`.
+pub struct PmuScope {
+    start: PmuCounts,
+}
+
+impl PmuScope {
+    pub fn start() -> Self {
+        Self { start: read() }
+    }
+    
+    /// Events since the scope started.
+    pub fn elapsed(&self) -> PmuCounts {
+        read() - self.start
+    }
+    
+    /// Run `f` and return its result with the events it took. Includes
+    /// anything that interrupts or preempts it meanwhile.
+    pub fn measure<R>(f: impl FnOnce() -> R) -> (R, PmuCounts) {
+        let scope = Self::start();
+        let result = f();
+        (result, scope.elapsed())
+    }
+}
+
+#[kernel_test]
+fn test_pmu() {
+    
+    crate::println!("PMU Test: Testing PMU...");
+    
+    if !is_present() {
+        crate::println!("PMU Test: PMU skipped: no PMUv3");
+        return;
+    }
+    
+    let ((), short) = PmuScope::measure(|| {
+        for i in 0..1_000u64 {
+            core::hint::black_box(i);
+        }
+    });
+    let ((), long) = PmuScope::measure(|| {
+        for i in 0..100_000u64 {
+            core::hint::black_box(i);
+        }
+    });
+    
+    if short.cycles > 0 && long.cycles > short.cycles {
+        crate::println!("PMU Test: ✓ Cycles scale with work ({} vs {})", short.cycles, long.cycles);
+    } else {
+        crate::println!("PMU Test: ✗ Cycle counts {} and {} do not scale", short.cycles, long.cycles);
+    }
+    if !is_counting(Event::Instructions) {
+        crate::println!("PMU Test: Instruction count skipped: event not implemented");
+    } else if long.instructions >= 100_000 && long.instructions > short.instructions {
+        crate::println!("PMU Test: ✓ {} instructions retired by the long loop", long.instructions);
+    } else {
+        crate::println!("PMU Test: ✗ {} instructions retired by a 100000-iteration loop", long.instructions);
+    }
+    
+    // The boot thread has been switched out at least once by now
+    let charged = crate::process::scheduler::with_thread(0, |thread| thread.pmu.cycles).unwrap_or(0);
+    if charged > 0 {
+        crate::println!("PMU Test: ✓ Boot thread charged {} cycles at context switches", charged);
+    } else {
+        crate::println!("PMU Test: ✗ No cycles charged to the boot thread");
+    }
+    
+    crate::println!("PMU Test: PMU test completed");
+}
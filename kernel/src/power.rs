@@ -0,0 +1,207 @@
+// System reset and power-off through PSCI
+//
+// The calls go out through the firmware module's SMCCC conduit. Both
+// calls flush the persistent log and sync filesystems first, since nothing
+// runs after them. Resets are warm (PSCI 1.1 SYSTEM_RESET2) where the
+// firmware offers it, so RAM, and with it pstore and any crash dump, is
+// more likely to survive.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use crate::firmware::{self, function_id, Conduit, Owner};
+use crate::ktest::kernel_test;
+
+// PSCI 0.2 function IDs (SMC32 calling convention)
+const PSCI_VERSION: u32 = function_id(Owner::Standard, false, 0x00);
+const PSCI_SYSTEM_OFF: u32 = function_id(Owner::Standard, false, 0x08);
+const PSCI_SYSTEM_RESET: u32 = function_id(Owner::Standard, false, 0x09);
+const PSCI_FEATURES: u32 = function_id(Owner::Standard, false, 0x0A);
+const PSCI_SYSTEM_RESET2: u32 = function_id(Owner::Standard, false, 0x12);
+
+// SYSTEM_RESET2 reset type
+const PSCI_SYSTEM_WARM_RESET: u64 = 0;
+
+/// Exit statuses of a `test_exit` run, as the host sees them with
+/// semihosting: every test passed, a check failed, the kernel panicked.
+pub const TEST_EXIT_PASS: u32 = 0;
+pub const TEST_EXIT_FAIL: u32 = 1;
+pub const TEST_EXIT_PANIC: u32 = 2;
+
+/// What to do when the kernel panics.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PanicAction {
+    Halt,
+    Reboot,
+    PowerOff,
+}
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+static TEST_MODE: AtomicBool = AtomicBool::new(false);
+static WARM_RESET: AtomicBool = AtomicBool::new(false);
+
+/// The PSCI conduit, if the device tree describes one.
+pub fn conduit() -> Option<Conduit> {
+    firmware::conduit()
+}
+
+/// Issue a PSCI call with up to three arguments.
+pub fn psci_call(function: u32, arg0: u64, arg1: u64, arg2: u64) -> Result<i64, &'static str> {
+    // SMC32 results are 32 bits wide
+    Ok(firmware::call(function, &[arg0, arg1, arg2])?[0] as i32 as i64)
+}
+
+/// Implemented PSCI version as (major, minor).
+pub fn psci_version() -> Result<(u16, u16), &'static str> {
+    let version = psci_call(PSCI_VERSION, 0, 0, 0)?;
+    if version < 0 {
+        return Err("PSCI_VERSION not supported");
+    }
+    Ok(((version >> 16) as u16 & 0x7FFF, version as u16))
+}
+
+// Last chance to get state out before the machine goes away
+fn prepare() {
+    if let Err(e) = crate::vfs::sync_all() {
+        crate::println!("Power: Filesystem sync failed: {}", e);
+    }
+    crate::pstore::flush();
+}
+
+// Warm if possible, falling back to SYSTEM_RESET should that return
+fn system_reset() -> Result<i64, &'static str> {
+    if WARM_RESET.load(Ordering::Relaxed) {
+        let _ = psci_call(PSCI_SYSTEM_RESET2, PSCI_SYSTEM_WARM_RESET, 0, 0);
+    }
+    psci_call(PSCI_SYSTEM_RESET, 0, 0, 0)
+}
+
+/// Reset the machine. Only returns if PSCI is missing or the call failed.
+pub fn reboot() -> &'static str {
+    crate::println!("Power: Rebooting...");
+    prepare();
+    match system_reset() {
+        Ok(_) => "PSCI SYSTEM_RESET returned",
+        Err(e) => e,
+    }
+}
+
+/// Turn the machine off (QEMU exits). Only returns on failure.
+pub fn poweroff() -> &'static str {
+    crate::println!("Power: Powering off...");
+    prepare();
+    match psci_call(PSCI_SYSTEM_OFF, 0, 0, 0) {
+        Ok(_) => "PSCI SYSTEM_OFF returned",
+        Err(e) => e,
+    }
+}
+
+/// End a test run, handing `status` to the host through semihosting if
+/// it is on, else just powering off. Only returns on failure.
+pub fn test_exit(status: u32) -> &'static str {
+    prepare();
+    crate::semihosting::exit(status);
+    match psci_call(PSCI_SYSTEM_OFF, 0, 0, 0) {
+        Ok(_) => "PSCI SYSTEM_OFF returned",
+        Err(e) => e,
+    }
+}
+
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        action if action == PanicAction::Reboot as u8 => PanicAction::Reboot,
+        action if action == PanicAction::PowerOff as u8 => PanicAction::PowerOff,
+        _ => PanicAction::Halt,
+    }
+}
+
+/// Called from the panic handler once the message is out. Skips the
+/// filesystem sync: the state it would write is suspect. A test run
+/// exits with TEST_EXIT_PANIC where semihosting is on.
+pub fn panic_exit() {
+    if test_mode() {
+        crate::semihosting::exit(TEST_EXIT_PANIC);
+    }
+    let _ = match panic_action() {
+        PanicAction::Halt => return,
+        PanicAction::Reboot => system_reset(),
+        PanicAction::PowerOff => psci_call(PSCI_SYSTEM_OFF, 0, 0, 0),
+    };
+}
+
+/// Find the conduit, report the PSCI version and read panic= from bootargs
+/// (halt, reboot or poweroff). A bare `qemu_test` (`make test`) makes the
+/// default poweroff, so a panicking test run ends QEMU instead of hanging.
+pub fn init() {
+    match conduit() {
+        Some(conduit) => match psci_version() {
+            Ok((major, minor)) => {
+                // PSCI_FEATURES arrived in 1.0; SYSTEM_RESET2 in 1.1
+                let warm = (major, minor) >= (1, 1)
+                    && psci_call(PSCI_FEATURES, PSCI_SYSTEM_RESET2 as u64, 0, 0).is_ok_and(|result| result >= 0);
+                WARM_RESET.store(warm, Ordering::Relaxed);
+                crate::println!("Power: PSCI {}.{} via {:?}{}", major, minor, conduit,
+                               if warm { ", warm reset" } else { "" });
+            }
+            Err(e) => crate::println!("Power: PSCI version query failed: {}", e),
+        },
+        None => crate::println!("Power: No PSCI, reboot and poweroff unavailable"),
+    }
+    crate::semihosting::init();
+    
+    let test_mode = crate::bootparams::flag("qemu_test");
+    let action = match crate::bootparams::get("panic") {
+        Some("reboot") => PanicAction::Reboot,
+        Some("poweroff") => PanicAction::PowerOff,
+        None if test_mode => PanicAction::PowerOff,
+        Some("halt") | None => PanicAction::Halt,
+        Some(other) => {
+            crate::println!("Power: Unknown panic={}, halting on panic", other);
+            PanicAction::Halt
+        }
+    };
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+    TEST_MODE.store(test_mode, Ordering::Relaxed);
+}
+
+/// Booted by `make test`: self-tests that fail should panic, which ends
+/// the QEMU run with the failure in its log.
+pub fn test_mode() -> bool {
+    TEST_MODE.load(Ordering::Relaxed)
+}
+
+#[kernel_test]
+fn test_power() {
+    use crate::firmware::{self, function_id, Owner};
+    
+    crate::println!("Power Test: Testing PSCI...");
+    
+    // PSCI_VERSION, SMCCC_VERSION and TRNG_RND64 as the specs spell them
+    if function_id(Owner::Standard, false, 0) == 0x8400_0000 && function_id(Owner::Arch, false, 0) == 0x8000_0000
+        && function_id(Owner::Standard, true, 0x53) == 0xC400_0053 {
+        crate::println!("Power Test: ✓ SMCCC function IDs encoded");
+    } else {
+        crate::println!("Power Test: ✗ SMCCC function ID encoding wrong");
+    }
+    match firmware::version() {
+        Some(version) if version >= (1, 0) => crate::println!("Power Test: ✓ SMCCC {}.{}", version.0, version.1),
+        Some(version) => crate::println!("Power Test: ✗ SMCCC version {:?}", version),
+        None => crate::println!("Power Test: SMCCC skipped: no conduit"),
+    }
+    // Spectre-v2 workaround call, only asked about under SMCCC 1.1+
+    let workaround = firmware::arch_features(function_id(Owner::Arch, false, 0x8000));
+    crate::println!("Power Test: SMCCC_ARCH_WORKAROUND_1 {}", if workaround { "implemented" } else { "absent" });
+    if firmware::call(0, &[0; 8]).is_err() {
+        crate::println!("Power Test: ✓ Over-long firmware call refused");
+    } else {
+        crate::println!("Power Test: ✗ Firmware call took eight arguments");
+    }
+    
+    match (conduit(), psci_version()) {
+        (Some(conduit), Ok((major, minor))) if (major, minor) >= (0, 2) => {
+            crate::println!("Power Test: ✓ PSCI {}.{} reachable via {:?}", major, minor, conduit);
+        }
+        (None, _) => crate::println!("Power Test: PSCI skipped: no conduit in the device tree"),
+        (_, version) => crate::println!("Power Test: ✗ PSCI version {:?} lacks SYSTEM_OFF/RESET", version),
+    }
+    
+    crate::println!("Power Test: PSCI test completed");
+}
@@ -1,11 +0,0 @@
-// Process management for microkernel
-
-pub fn init() {
-    crate::println!("Initializing process management...");
-    
-    // TODO: Set up process table
-    // TODO: Initialize scheduler
-    // TODO: Set up context switching
-    
-    crate::println!("Process management initialized");
-}
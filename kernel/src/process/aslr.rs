@@ -0,0 +1,61 @@
+// User address space layout randomization
+//
+// Every program started by program::spawn gets its own layout from the
+// kernel's random generator: the load bias of a position-independent
+// image, the start of the mmap area (where the runtime's heap arenas and
+// every other anonymous mapping come from) and the top of its stack, which
+// sits at the far end of that area. Each moves in steps that keep what
+// lies there aligned: PIE images by 2MB blocks, the rest by pages.
+//
+// A process started without randomization (`run -R` in the shell), or
+// any process when the kernel was booted with `noaslr`, gets the fixed
+// layout instead, so addresses repeat from run to run under a
+// debugger. Forked children keep their parent's layout and flag.
+
+use crate::memory::frame_allocator::PAGE_SIZE;
+use crate::memory::paging::{VirtAddr, BLOCK_SIZE_2M};
+use super::elf::PIE_LOAD_BIAS;
+use super::thread::{USER_MMAP_BASE, USER_MMAP_END};
+
+// Bits of entropy for each part of the layout: PIE bias in 2MB blocks
+// (8GB of the image window), mmap base in pages (64GB), stack top in
+// pages (4GB below the end of the mmap area)
+pub const PIE_BIAS_BITS: u32 = 12;
+pub const MMAP_BASE_BITS: u32 = 24;
+pub const STACK_TOP_BITS: u32 = 20;
+
+/// Where a program's image, anonymous mappings and stack go.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub pie_bias: VirtAddr,
+    pub mmap_base: VirtAddr,
+    pub stack_top: VirtAddr,
+    pub randomized: bool,
+}
+
+impl Layout {
+    /// The same addresses every time.
+    pub const FIXED: Layout = Layout {
+        pie_bias: PIE_LOAD_BIAS,
+        mmap_base: USER_MMAP_BASE,
+        stack_top: USER_MMAP_END,
+        randomized: false,
+    };
+    
+    /// A fresh random layout.
+    pub fn random() -> Self {
+        let bits = |count: u32| crate::rand::get_random_u64() & ((1 << count) - 1);
+        let page = PAGE_SIZE as VirtAddr;
+        Layout {
+            pie_bias: PIE_LOAD_BIAS + bits(PIE_BIAS_BITS) * BLOCK_SIZE_2M,
+            mmap_base: USER_MMAP_BASE + bits(MMAP_BASE_BITS) * page,
+            stack_top: USER_MMAP_END - bits(STACK_TOP_BITS) * page,
+            randomized: true,
+        }
+    }
+    
+    /// A random layout if asked for and not turned off at boot.
+    pub fn new(randomize: bool) -> Self {
+        if randomize && !crate::bootparams::flag("noaslr") { Self::random() } else { Self::FIXED }
+    }
+}
@@ -0,0 +1,71 @@
+// Capabilities: the resources a thread may use beyond its own memory
+//
+// Held per thread and handed out by privileged code (the process manager
+// or the kernel on its behalf). Every grant and revoke is audited.
+
+use alloc::vec::Vec;
+use crate::ipc::PortId;
+use crate::shm::ShmId;
+use super::scheduler::with_thread;
+use super::ThreadId;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Send to and receive from an IPC port
+    Port(PortId),
+    /// Receive an interrupt line
+    Irq(u32),
+    /// Map a physical MMIO window
+    Mmio { base: u64, size: u64 },
+    /// Change kernel tunables through sysctl
+    Sysctl,
+    /// Map a shared memory object, writable or read-only
+    Shm { id: ShmId, writable: bool },
+    /// Issue control requests to devices under /dev
+    Device,
+}
+
+impl Capability {
+    /// Compact form for audit records: kind in the top byte.
+    pub fn audit_code(&self) -> u32 {
+        match *self {
+            Capability::Port(port) => 1 << 24 | (port & 0xFF_FFFF),
+            Capability::Irq(irq) => 2 << 24 | (irq & 0xFF_FFFF),
+            Capability::Mmio { base, .. } => 3 << 24 | ((base >> 12) as u32 & 0xFF_FFFF),
+            Capability::Sysctl => 4 << 24,
+            Capability::Shm { id, writable } => 5 << 24 | (writable as u32) << 23 | (id & 0x7F_FFFF),
+            Capability::Device => 6 << 24,
+        }
+    }
+}
+
+/// Give `holder` a capability; granting one it already has is a no-op.
+pub fn grant(granter: ThreadId, holder: ThreadId, capability: Capability) -> Result<(), &'static str> {
+    with_thread(holder, |thread| {
+        if !thread.capabilities.contains(&capability) {
+            thread.capabilities.push(capability);
+        }
+    })
+    .ok_or("No such thread")?;
+    crate::audit::capability_grant(granter, holder, capability.audit_code());
+    Ok(())
+}
+
+pub fn revoke(revoker: ThreadId, holder: ThreadId, capability: Capability) -> Result<(), &'static str> {
+    let held = with_thread(holder, |thread| {
+        let before = thread.capabilities.len();
+        thread.capabilities.retain(|&held| held != capability);
+        thread.capabilities.len() != before
+    })
+    .ok_or("No such thread")?;
+    if !held {
+        return Err("Capability not held");
+    }
+    crate::audit::capability_revoke(revoker, holder, capability.audit_code());
+    Ok(())
+}
+
+/// Capabilities `holder` has right now.
+pub fn held(holder: ThreadId) -> Vec<Capability> {
+    with_thread(holder, |thread| thread.capabilities.clone()).unwrap_or_default()
+}
@@ -0,0 +1,213 @@
+// ELF executable loader for user address spaces
+//
+// The whole PT_LOAD layout is validated before the first page is mapped:
+// segments must sit inside the user image window, be page aligned, stay
+// within the size limits and not overlap, so a malformed binary is
+// rejected as a whole and can never reach kernel addresses.
+
+use alloc::vec::Vec;
+use core::fmt;
+use elf_parser::{Elf, ElfError, ProgramHeader, PF_W, PF_X};
+use crate::memory::frame_allocator::PAGE_SIZE;
+use crate::memory::paging::{phys_to_virt, PageFlags, VirtAddr, BLOCK_SIZE_2M};
+use super::thread::{AddressSpace, USER_IMAGE_BASE, USER_IMAGE_END};
+
+// Position-independent images are loaded at this bias, unless the
+// program's layout is randomized (aslr.rs)
+pub const PIE_LOAD_BIAS: VirtAddr = 0x0000_0000_0040_0000;
+
+// Per-segment and whole-image memory limits
+pub const MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+pub const MAX_IMAGE_SIZE: u64 = 256 * 1024 * 1024;
+
+// Segments must be aligned to at least a page and at most a 2MB block
+const MIN_SEGMENT_ALIGN: u64 = PAGE_SIZE as u64;
+const MAX_SEGMENT_ALIGN: u64 = BLOCK_SIZE_2M;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The file itself failed to parse.
+    Malformed(ElfError),
+    /// A segment reaches below the image window or into kernel space.
+    OutsideUserRange,
+    /// Two segments share a page.
+    SegmentOverlap,
+    /// Alignment below a page, above 2MB, or file and memory offsets
+    /// disagree within a page.
+    BadAlignment,
+    /// A segment asks to be both writable and executable (W^X).
+    WritableExecutable,
+    SegmentTooLarge,
+    ImageTooLarge,
+    /// The entry point is not inside an executable segment.
+    BadEntry,
+    /// The layout was valid but mapping it failed.
+    MapFailed(&'static str),
+}
+
+impl LoadError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LoadError::Malformed(e) => e.as_str(),
+            LoadError::OutsideUserRange => "segment outside the user image range",
+            LoadError::SegmentOverlap => "segments overlap",
+            LoadError::BadAlignment => "segment alignment not supported",
+            LoadError::WritableExecutable => "segment both writable and executable",
+            LoadError::SegmentTooLarge => "segment too large",
+            LoadError::ImageTooLarge => "image too large",
+            LoadError::BadEntry => "entry point not in an executable segment",
+            LoadError::MapFailed(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<ElfError> for LoadError {
+    fn from(e: ElfError) -> Self {
+        LoadError::Malformed(e)
+    }
+}
+
+/// A PT_LOAD segment placed at its final, page-rounded address.
+#[derive(Copy, Clone, Debug)]
+struct Placed {
+    header: ProgramHeader,
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl Placed {
+    fn pages(&self) -> usize {
+        ((self.end - self.start) / PAGE_SIZE as u64) as usize
+    }
+    
+    fn flags(&self) -> PageFlags {
+        let mut flags = PageFlags::NORMAL_MEMORY | PageFlags::INNER_SHAREABLE | PageFlags::ACCESSED
+            | PageFlags::USER | PageFlags::PXN;
+        if self.header.flags & PF_W == 0 {
+            flags |= PageFlags::READ_ONLY;
+        }
+        if self.header.flags & PF_X == 0 {
+            flags |= PageFlags::UXN;
+        }
+        flags
+    }
+}
+
+/// Where an image ended up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LoadedImage {
+    pub entry: VirtAddr,
+    pub bias: VirtAddr,
+    /// Lowest and one past the highest mapped address.
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+}
+
+// Check every segment and return them sorted by address; nothing is mapped
+fn plan(elf: &Elf, pie_bias: VirtAddr) -> Result<(VirtAddr, Vec<Placed>), LoadError> {
+    let page = PAGE_SIZE as u64;
+    let bias = if elf.is_pie() { pie_bias } else { 0 };
+    
+    let mut placed = Vec::new();
+    let mut total = 0u64;
+    for header in elf.load_segments().filter(|header| header.memsz != 0) {
+        if header.align < MIN_SEGMENT_ALIGN || header.align > MAX_SEGMENT_ALIGN
+            || header.vaddr % page != header.offset % page
+        {
+            return Err(LoadError::BadAlignment);
+        }
+        if header.flags & PF_W != 0 && header.flags & PF_X != 0 {
+            return Err(LoadError::WritableExecutable);
+        }
+        if header.memsz > MAX_SEGMENT_SIZE {
+            return Err(LoadError::SegmentTooLarge);
+        }
+        total += header.memsz;
+        if total > MAX_IMAGE_SIZE {
+            return Err(LoadError::ImageTooLarge);
+        }
+        
+        // The parser has checked vaddr + memsz; the bias and rounding can still wrap
+        let start = header.vaddr.checked_add(bias).ok_or(LoadError::OutsideUserRange)? & !(page - 1);
+        let end = header.vaddr_end()
+            .checked_add(bias)
+            .and_then(|end| end.checked_next_multiple_of(page))
+            .ok_or(LoadError::OutsideUserRange)?;
+        if start < USER_IMAGE_BASE || end > USER_IMAGE_END {
+            return Err(LoadError::OutsideUserRange);
+        }
+        placed.push(Placed { header, start, end });
+    }
+    
+    placed.sort_unstable_by_key(|segment| segment.start);
+    if placed.windows(2).any(|pair| pair[0].end > pair[1].start) {
+        return Err(LoadError::SegmentOverlap);
+    }
+    
+    let entry = elf.entry.checked_add(bias).ok_or(LoadError::BadEntry)?;
+    let executable = placed.iter().any(|segment| {
+        segment.header.flags & PF_X != 0
+            && entry >= segment.header.vaddr + bias
+            && entry < segment.header.vaddr_end() + bias
+    });
+    if !executable {
+        return Err(LoadError::BadEntry);
+    }
+    Ok((bias, placed))
+}
+
+/// Check `image` and map its PT_LOAD segments into `space`, copying file
+/// contents and zeroing the rest. On error nothing is left mapped.
+pub fn load(space: &mut AddressSpace, image: &[u8]) -> Result<LoadedImage, LoadError> {
+    load_at(space, image, PIE_LOAD_BIAS)
+}
+
+/// `load`, with a position-independent image moved by `pie_bias`, which
+/// must be a multiple of the largest segment alignment (2MB).
+pub fn load_at(space: &mut AddressSpace, image: &[u8], pie_bias: VirtAddr) -> Result<LoadedImage, LoadError> {
+    debug_assert!(pie_bias.is_multiple_of(MAX_SEGMENT_ALIGN));
+    let elf = Elf::parse(image)?;
+    let (bias, placed) = plan(&elf, pie_bias)?;
+    
+    for (index, segment) in placed.iter().enumerate() {
+        if let Err(e) = map_segment(space, &elf, segment, bias) {
+            for done in &placed[..=index] {
+                let _ = space.unmap_fixed(done.start, done.pages());
+            }
+            return Err(LoadError::MapFailed(e));
+        }
+    }
+    
+    Ok(LoadedImage {
+        entry: elf.entry + bias,
+        bias,
+        start: placed.first().map_or(0, |segment| segment.start),
+        end: placed.last().map_or(0, |segment| segment.end),
+    })
+}
+
+// Map fresh pages with the final permissions and fill them through the
+// linear map, so read-only text never needs a writable user mapping
+fn map_segment(space: &mut AddressSpace, elf: &Elf, segment: &Placed, bias: VirtAddr) -> Result<(), &'static str> {
+    space.map_fixed(segment.start, segment.pages(), segment.flags())?;
+    
+    let mut virt = segment.header.vaddr + bias;
+    let mut remaining = elf.segment_data(&segment.header);
+    while !remaining.is_empty() {
+        let offset = (virt % PAGE_SIZE as u64) as usize;
+        let len = remaining.len().min(PAGE_SIZE - offset);
+        let phys = space.vmm().translate(virt - offset as u64).ok_or("Segment page not mapped")?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(remaining.as_ptr(), (phys_to_virt(phys) as *mut u8).add(offset), len);
+        }
+        remaining = &remaining[len..];
+        virt += len as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,134 @@
+// Kill-on-fault for user threads
+//
+// An abort or other unhandled synchronous exception from EL0 ends the
+// thread instead of returning into the instruction that faulted. The
+// fault is reported on the console and, if the thread's parent has set a
+// fault port, sent there as a FaultReport message.
+
+use crate::interrupts::ExceptionContext;
+use crate::ipc::{self, Message, PortId};
+use super::scheduler::{self, current_thread_id, with_thread};
+use super::supervisor::ExitReason;
+use super::ThreadId;
+
+pub const FAULT_NAME_LEN: usize = 16;
+
+/// Encoded size of a FaultReport in a message.
+pub const FAULT_REPORT_LEN: usize = 40 + FAULT_NAME_LEN;
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FaultKind {
+    DataAbort = 1,
+    InstructionAbort = 2,
+    /// Any other synchronous exception the kernel does not handle
+    Unhandled = 3,
+}
+
+/// What a parent is told about a child killed by a fault.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FaultReport {
+    pub tid: ThreadId,
+    pub kind: u32,       // FaultKind
+    pub pc: u64,
+    pub far: u64,        // Faulting address; 0 unless an abort
+    pub esr: u64,
+    pub sp: u64,
+    pub name: [u8; FAULT_NAME_LEN],
+}
+
+impl FaultReport {
+    /// Little-endian fields in declaration order.
+    pub fn encode(&self, out: &mut [u8; FAULT_REPORT_LEN]) {
+        out[0..4].copy_from_slice(&self.tid.to_le_bytes());
+        out[4..8].copy_from_slice(&self.kind.to_le_bytes());
+        out[8..16].copy_from_slice(&self.pc.to_le_bytes());
+        out[16..24].copy_from_slice(&self.far.to_le_bytes());
+        out[24..32].copy_from_slice(&self.esr.to_le_bytes());
+        out[32..40].copy_from_slice(&self.sp.to_le_bytes());
+        out[40..].copy_from_slice(&self.name);
+    }
+    
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; FAULT_REPORT_LEN] = bytes.get(..FAULT_REPORT_LEN)?.try_into().ok()?;
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        Some(Self {
+            tid: u32_at(0),
+            kind: u32_at(4),
+            pc: u64_at(8),
+            far: u64_at(16),
+            esr: u64_at(24),
+            sp: u64_at(32),
+            name: bytes[40..].try_into().unwrap(),
+        })
+    }
+    
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(FAULT_NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+}
+
+/// Have faults of the calling thread's children reported to `port`, which
+/// the caller must own. None stops the reports.
+pub fn set_fault_port(port: Option<PortId>) -> Result<(), &'static str> {
+    let me = current_thread_id();
+    if let Some(port) = port {
+        let port = ipc::lookup_port(port).ok_or("No such port")?;
+        if port.owner() != me {
+            return Err("Port not owned by the caller");
+        }
+    }
+    with_thread(me, |thread| thread.fault_port = port).ok_or("No current thread")
+}
+
+/// Kill the calling thread for a fault it cannot recover from; its exit
+/// status is the fault kind. Called from the synchronous exception
+/// handler, which must reschedule afterwards.
+pub fn kill_current(ctx: &ExceptionContext, kind: FaultKind, far: u64, esr: u64) {
+    let tid = current_thread_id();
+    let mut report = FaultReport {
+        tid,
+        kind: kind as u32,
+        pc: ctx.elr_el1,
+        far,
+        esr,
+        sp: ctx.sp_el0,
+        name: [0; FAULT_NAME_LEN],
+    };
+    let parent = with_thread(tid, |thread| {
+        let name = thread.name.as_str().as_bytes();
+        let len = name.len().min(FAULT_NAME_LEN);
+        report.name[..len].copy_from_slice(&name[..len]);
+        thread.parent
+    }).flatten();
+    
+    crate::println!("Process: Thread {} '{}' killed by {:?}: PC 0x{:016x}, FAR 0x{:016x}, ESR 0x{:x}",
+                   tid, report.name(), kind, report.pc, far, esr);
+    if let Some(parent) = parent {
+        if let Err(e) = notify_parent(parent, &report) {
+            crate::println!("Process: Fault report to thread {} not delivered: {}", parent, e);
+        }
+    }
+    
+    super::mark_exited(ExitReason::Faulted, kind as u32);
+    scheduler::request_resched();
+}
+
+fn notify_parent(parent: ThreadId, report: &FaultReport) -> Result<(), &'static str> {
+    let Some(port) = with_thread(parent, |thread| thread.fault_port).flatten() else {
+        return Ok(());
+    };
+    let port = ipc::lookup_port(port).ok_or("Fault port destroyed")?;
+    let mut message = Message {
+        sender: report.tid,
+        data: alloc::vec![0; FAULT_REPORT_LEN],
+        trace_id: crate::trace::TRACE_ID_NONE,
+        grant: None,
+        reply: None,
+    };
+    let encoded: &mut [u8; FAULT_REPORT_LEN] = message.data.as_mut_slice().try_into().unwrap();
+    report.encode(encoded);
+    port.send_message(message)
+}
@@ -0,0 +1,222 @@
+// Fork: copy-on-write accounting and the borrow (vfork) fast path
+//
+// A copy fork shares every page copy-on-write (AddressSpace::fork); the
+// counters here record how many pages that marked and how many were later
+// copied, or simply taken over by their last mapper. Children that exec
+// soon after fork copy almost nothing, and for them borrowing is cheaper
+// still: the child runs in the parent's address space while the parent
+// sleeps, and hands it back on exec or exit. `recommended_mode` picks
+// between the two from the exec-after-fork history.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::IrqSafeMutex;
+use super::scheduler::{block_current, current_thread_id, for_each_thread, wake, with_thread};
+use super::thread::AddressSpace;
+use super::{yield_now, ThreadId};
+
+// Borrowing pays off once this many forked children have exec'd having
+// copied at most this share of the pages they were given
+const BORROW_MIN_EXECS: u64 = 8;
+const BORROW_MAX_COPY_PERCENT: u64 = 5;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ForkMode {
+    /// Child gets its own copy-on-write address space.
+    Copy,
+    /// Child runs in the parent's address space until it execs or exits;
+    /// the parent sleeps meanwhile.
+    Borrow,
+}
+
+/// Copy-on-write activity of one address space.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CowCounters {
+    /// Pages shared copy-on-write by forks of (or into) this space.
+    pub marked: u64,
+    /// Write faults that had to copy the page.
+    pub copied: u64,
+    /// Write faults where this space was the last mapper and kept the frame.
+    pub reused: u64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForkStats {
+    pub forks: u64,
+    pub borrows: u64,
+    pub cow_marked: u64,
+    pub cow_copied: u64,
+    pub cow_reused: u64,
+    /// Forked children replaced by exec, with the pages they were given
+    /// and the pages they copied first.
+    pub execs_after_fork: u64,
+    pub exec_shared: u64,
+    pub exec_copied: u64,
+}
+
+static FORKS: AtomicU64 = AtomicU64::new(0);
+static BORROWS: AtomicU64 = AtomicU64::new(0);
+static COW_MARKED: AtomicU64 = AtomicU64::new(0);
+static COW_COPIED: AtomicU64 = AtomicU64::new(0);
+static COW_REUSED: AtomicU64 = AtomicU64::new(0);
+static EXECS_AFTER_FORK: AtomicU64 = AtomicU64::new(0);
+static EXEC_SHARED: AtomicU64 = AtomicU64::new(0);
+static EXEC_COPIED: AtomicU64 = AtomicU64::new(0);
+
+// Borrowing child -> the parent waiting for its address space
+static LENDERS: IrqSafeMutex<BTreeMap<ThreadId, ThreadId>> = IrqSafeMutex::new(BTreeMap::new());
+
+pub(super) fn record_fork(pages: u64) {
+    FORKS.fetch_add(1, Ordering::Relaxed);
+    COW_MARKED.fetch_add(pages, Ordering::Relaxed);
+}
+
+pub(super) fn record_cow_copy() {
+    COW_COPIED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(super) fn record_cow_reuse() {
+    COW_REUSED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn stats() -> ForkStats {
+    ForkStats {
+        forks: FORKS.load(Ordering::Relaxed),
+        borrows: BORROWS.load(Ordering::Relaxed),
+        cow_marked: COW_MARKED.load(Ordering::Relaxed),
+        cow_copied: COW_COPIED.load(Ordering::Relaxed),
+        cow_reused: COW_REUSED.load(Ordering::Relaxed),
+        execs_after_fork: EXECS_AFTER_FORK.load(Ordering::Relaxed),
+        exec_shared: EXEC_SHARED.load(Ordering::Relaxed),
+        exec_copied: EXEC_COPIED.load(Ordering::Relaxed),
+    }
+}
+
+/// Borrow when forked children have mostly exec'd without copying: the
+/// copy-on-write setup was wasted on them.
+pub fn recommended_mode() -> ForkMode {
+    recommend(&stats())
+}
+
+pub fn recommend(stats: &ForkStats) -> ForkMode {
+    if stats.execs_after_fork >= BORROW_MIN_EXECS
+        && stats.exec_copied * 100 <= stats.exec_shared * BORROW_MAX_COPY_PERCENT
+    {
+        ForkMode::Borrow
+    } else {
+        ForkMode::Copy
+    }
+}
+
+/// Export /proc/forkstat.
+pub fn init() {
+    let _ = crate::procfs::register("forkstat", proc_forkstat);
+}
+
+/// Give `child` the calling thread's memory. A borrow returns once the
+/// child has exec'd or exited.
+pub fn fork(child: ThreadId, mode: ForkMode) -> Result<(), &'static str> {
+    let parent = current_thread_id();
+    if child == parent {
+        return Err("Cannot fork into the caller");
+    }
+    match mode {
+        ForkMode::Copy => {
+            let space = with_thread(parent, |thread| thread.address_space().map(|space| space.fork()))
+                .flatten()
+                .ok_or("Caller has no address space")??;
+            with_thread(child, |thread| thread.set_address_space(space)).ok_or("No such thread")
+        }
+        ForkMode::Borrow => borrow(parent, child),
+    }
+}
+
+fn borrow(parent: ThreadId, child: ThreadId) -> Result<(), &'static str> {
+    let space = with_thread(parent, |thread| thread.take_address_space())
+        .flatten()
+        .ok_or("Caller has no address space")?;
+    {
+        let mut lenders = LENDERS.lock();
+        // Hand over under the lock so the child cannot give it back first
+        let space = match with_thread(child, |thread| thread.address_space().is_none()) {
+            Some(true) => space,
+            _ => {
+                with_thread(parent, |thread| thread.set_address_space(space));
+                return Err("Child missing or already has an address space");
+            }
+        };
+        with_thread(child, |thread| thread.set_address_space(space));
+        lenders.insert(child, parent);
+    }
+    BORROWS.fetch_add(1, Ordering::Relaxed);
+    
+    loop {
+        // Block under the lock, so give_back either sees the sleeper or
+        // has already returned the space
+        {
+            let lenders = LENDERS.lock();
+            if !lenders.contains_key(&child) {
+                return Ok(());
+            }
+            block_current();
+        }
+        yield_now();
+    }
+}
+
+// Return a borrowed address space to the parent waiting for it
+fn give_back(child: ThreadId) -> bool {
+    let mut lenders = LENDERS.lock();
+    let Some(parent) = lenders.remove(&child) else {
+        return false;
+    };
+    if let Some(space) = with_thread(child, |thread| thread.take_address_space()).flatten() {
+        // A parent killed while waiting takes the space with it
+        with_thread(parent, |thread| thread.set_address_space(space));
+    }
+    wake(parent);
+    true
+}
+
+/// Replace the calling thread's memory with a freshly loaded image. A
+/// borrowed address space goes back to its parent; an own one is freed,
+/// and if it came from fork its copy-on-write history is recorded.
+pub fn exec(space: AddressSpace) {
+    let me = current_thread_id();
+    if !give_back(me) {
+        with_thread(me, |thread| {
+            if let Some(old) = thread.address_space().filter(|old| old.is_forked()) {
+                let cow = old.cow_counters();
+                EXECS_AFTER_FORK.fetch_add(1, Ordering::Relaxed);
+                EXEC_SHARED.fetch_add(cow.marked, Ordering::Relaxed);
+                EXEC_COPIED.fetch_add(cow.copied, Ordering::Relaxed);
+            }
+        });
+    }
+    with_thread(me, |thread| thread.set_address_space(space));
+}
+
+/// A thread is exiting or being killed: return anything it borrowed.
+pub fn release_borrowed(id: ThreadId) {
+    give_back(id);
+}
+
+fn proc_forkstat(out: &mut Vec<u8>) {
+    let stats = stats();
+    let mut text = alloc::string::String::new();
+    let _ = writeln!(text, "forks {}\nborrows {}", stats.forks, stats.borrows);
+    let _ = writeln!(text, "cow_marked {}\ncow_copied {}\ncow_reused {}",
+                     stats.cow_marked, stats.cow_copied, stats.cow_reused);
+    let _ = writeln!(text, "execs_after_fork {}\nexec_shared {}\nexec_copied {}",
+                     stats.execs_after_fork, stats.exec_shared, stats.exec_copied);
+    let _ = writeln!(text, "recommended {:?}", recommended_mode());
+    for_each_thread(|thread| {
+        if let Some(cow) = thread.cow_counters() {
+            let _ = writeln!(text, "thread {} {} marked {} copied {} reused {}",
+                             thread.id, thread.label(), cow.marked, cow.copied, cow.reused);
+        }
+    });
+    out.extend_from_slice(text.as_bytes());
+}
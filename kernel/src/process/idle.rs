@@ -0,0 +1,145 @@
+// Idle task: sleeps the CPU with wfi and stops the tick while idle
+//
+// Before sleeping it runs the housekeeping class: background work such as
+// pre-zeroing frames, flushing the persistent log and KSM scanning, which
+// only ever gets a CPU nobody else wants. Time spent on it is counted per
+// hook and kept apart from time asleep, so /proc/cpustat shows how much
+// of the "idle" CPU went on housekeeping.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use crate::interrupts::{counter_frequency, counter_ticks, enter_tickless_idle, exit_tickless_idle,
+                        wait_for_interrupt, without_interrupts};
+use super::scheduler::{become_idle, has_runnable, reap_exited, yield_now};
+
+const MAX_IDLE_WORK: usize = 8;
+
+#[derive(Copy, Clone)]
+struct IdleWork {
+    name: &'static str,
+    work: fn() -> bool,
+    runs: u64,
+    ticks: u64,
+}
+
+/// What one housekeeping hook has cost so far.
+#[derive(Copy, Clone, Debug)]
+pub struct HousekeepingStats {
+    pub name: &'static str,
+    pub runs: u64,
+    pub ticks: u64,
+}
+
+// Background work the idle task runs before sleeping. Each hook does a
+// bounded amount of work per call, since a wakeup cannot preempt it early,
+// and returns true while it has more to do.
+static IDLE_WORK: Mutex<[Option<IdleWork>; MAX_IDLE_WORK]> = Mutex::new([None; MAX_IDLE_WORK]);
+
+// Counter ticks since the idle task started, split by what it was doing
+static IDLE_START: AtomicU64 = AtomicU64::new(0);
+static HOUSEKEEPING_TICKS: AtomicU64 = AtomicU64::new(0);
+static SLEEP_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Export /proc/cpustat.
+pub fn init() {
+    let _ = crate::procfs::register("cpustat", proc_cpustat);
+}
+
+/// Run `work` whenever the CPU would otherwise go idle.
+pub fn register_idle_work(name: &'static str, work: fn() -> bool) -> Result<(), &'static str> {
+    let mut hooks = IDLE_WORK.lock();
+    let slot = hooks.iter_mut().find(|slot| slot.is_none()).ok_or("Too many idle work hooks")?;
+    *slot = Some(IdleWork { name, work, runs: 0, ticks: 0 });
+    Ok(())
+}
+
+/// Cost of each registered housekeeping hook.
+pub fn housekeeping_stats() -> Vec<HousekeepingStats> {
+    IDLE_WORK.lock().iter().flatten()
+        .map(|hook| HousekeepingStats { name: hook.name, runs: hook.runs, ticks: hook.ticks })
+        .collect()
+}
+
+// Whether any hook has work left
+fn run_idle_work() -> bool {
+    // Copy out so hooks may register others or take their own locks
+    let hooks = *IDLE_WORK.lock();
+    let mut pending = false;
+    for (slot, hook) in hooks.iter().enumerate() {
+        let Some(hook) = hook else { continue };
+        if has_runnable() {
+            return true;
+        }
+        let start = counter_ticks();
+        pending |= (hook.work)();
+        let spent = counter_ticks() - start;
+        HOUSEKEEPING_TICKS.fetch_add(spent, Ordering::Relaxed);
+        if let Some(hook) = IDLE_WORK.lock()[slot].as_mut() {
+            hook.runs += 1;
+            hook.ticks += spent;
+        }
+    }
+    pending
+}
+
+/// Turn the calling thread into the idle task. Never returns.
+pub fn run() -> ! {
+    become_idle("idle");
+    IDLE_START.store(counter_ticks(), Ordering::Relaxed);
+    crate::println!("Process: Idle task running (wfi, tickless)");
+    
+    loop {
+        reap_exited();
+        crate::rcu::quiescent_state();
+        crate::watchdog::touch();
+        
+        if has_runnable() {
+            yield_now();
+            continue;
+        }
+        
+        // Keep going without sleeping while background work is pending
+        if run_idle_work() {
+            continue;
+        }
+        
+        // Check and sleep with IRQs masked so a wakeup cannot slip in
+        // between; wfi still wakes on a pending IRQ, which is taken as
+        // soon as the mask is restored
+        without_interrupts(|| {
+            if !has_runnable() {
+                let start = counter_ticks();
+                enter_tickless_idle();
+                wait_for_interrupt();
+                exit_tickless_idle();
+                SLEEP_TICKS.fetch_add(counter_ticks() - start, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+// Since the idle task started: time asleep, on housekeeping, and the rest
+// busy with threads and interrupts. Secondary CPUs are parked, so there
+// is one line.
+fn proc_cpustat(out: &mut Vec<u8>) {
+    let frequency = counter_frequency().max(1);
+    let ms = |ticks: u64| ticks * 1000 / frequency;
+    let mut text = String::new();
+    let start = IDLE_START.load(Ordering::Relaxed);
+    if start != 0 {
+        let total = counter_ticks() - start;
+        let sleep = SLEEP_TICKS.load(Ordering::Relaxed);
+        let housekeeping = HOUSEKEEPING_TICKS.load(Ordering::Relaxed);
+        let _ = writeln!(text, "cpu0 busy_ms {} housekeeping_ms {} idle_ms {}",
+                         ms(total.saturating_sub(sleep + housekeeping)), ms(housekeeping), ms(sleep));
+    }
+    let _ = writeln!(text, "prezeroed_frames {}", crate::memory::frame_allocator::prezeroed_frames());
+    for hook in housekeeping_stats() {
+        let _ = writeln!(text, "housekeeping {} runs {} time_us {}",
+                         hook.name, hook.runs, hook.ticks * 1_000_000 / frequency);
+    }
+    out.extend_from_slice(text.as_bytes());
+}
@@ -0,0 +1,96 @@
+// Reading and writing other threads' user memory, for the debug shell
+// and debuggers
+//
+// The target's pages are found by walking its own page tables and reached
+// through the kernel's linear map of their frames: nothing new is mapped
+// and the running TTBR0 and ASID stay as they are, so a read needs no TLB
+// maintenance at all. A write to a copy-on-write page first gives the
+// target its own copy, invalidating the old entry under the target's ASID
+// as a write fault would; one to a frame mapped anywhere else is refused,
+// and one to executable code is pushed through to instruction fetch.
+//
+// Each page is copied with the target's thread entry locked, so it cannot
+// unmap the frame underneath. Only the kernel calls in here.
+
+use crate::memory::frame_allocator::PAGE_SIZE;
+use crate::memory::paging::{phys_to_virt, PageFlags, VirtAddr};
+use crate::memory::rmap;
+use super::scheduler::with_thread;
+use super::ThreadId;
+
+// Memory type (MAIR index) bits of a leaf entry
+const ATTR_INDEX_MASK: u64 = 7 << 2;
+
+/// Copy `buf.len()` bytes of `target`'s memory from `addr`.
+pub fn read(target: ThreadId, addr: VirtAddr, buf: &mut [u8]) -> Result<(), &'static str> {
+    for_each_page(addr, buf.len(), |virt, offset, len| {
+        let out = &mut buf[offset..offset + len];
+        in_page(target, virt, len, false, |page| unsafe {
+            core::ptr::copy_nonoverlapping(page, out.as_mut_ptr(), len);
+        })
+    })
+}
+
+/// Write `data` into `target`'s memory at `addr`, read-only pages
+/// included (a debugger planting a breakpoint), as long as each page is
+/// the target's alone.
+pub fn write(target: ThreadId, addr: VirtAddr, data: &[u8]) -> Result<(), &'static str> {
+    for_each_page(addr, data.len(), |virt, offset, len| {
+        let bytes = &data[offset..offset + len];
+        in_page(target, virt, len, true, |page| unsafe {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), page, len);
+        })
+    })
+}
+
+// Split `addr..addr + len` at page boundaries: f(address, offset, length)
+fn for_each_page(
+    addr: VirtAddr,
+    len: usize,
+    mut f: impl FnMut(VirtAddr, usize, usize) -> Result<(), &'static str>,
+) -> Result<(), &'static str> {
+    addr.checked_add(len as VirtAddr).ok_or("Address range wraps")?;
+    let mut done = 0;
+    while done < len {
+        let virt = addr + done as VirtAddr;
+        let chunk = (PAGE_SIZE - virt as usize % PAGE_SIZE).min(len - done);
+        f(virt, done, chunk)?;
+        done += chunk;
+    }
+    Ok(())
+}
+
+// Run `f` on the kernel's view of `virt` in `target`, with the page
+// checked for a `len`-byte access and the target unable to change its
+// mappings
+fn in_page(target: ThreadId, virt: VirtAddr, len: usize, write: bool, f: impl FnOnce(*mut u8)) -> Result<(), &'static str> {
+    with_thread(target, |thread| {
+        let space = thread.address_space().ok_or("Thread has no user address space")?;
+        if write {
+            space.resolve_cow_fault(virt)?;
+        }
+        let page = virt & !(PAGE_SIZE as VirtAddr - 1);
+        let entry = space.vmm().leaf_entry(page).filter(|entry| entry.is_valid()).ok_or("Address not mapped")?;
+        let flags = entry.flags();
+        let phys = entry.physical_addr();
+        if !flags.contains(PageFlags::USER) {
+            return Err("Address not mapped");
+        }
+        // Device registers may have read side effects, and a second,
+        // cacheable alias of uncached memory would not be coherent
+        if flags.bits() & ATTR_INDEX_MASK != PageFlags::NORMAL_MEMORY.bits() {
+            return Err("Not normal memory");
+        }
+        if write && rmap::map_count(phys) > 1 {
+            return Err("Page is shared");
+        }
+        
+        let linear = phys_to_virt(phys) + (virt - page);
+        f(linear as *mut u8);
+        if write && !flags.contains(PageFlags::UXN) {
+            crate::gdbstub::sync_icache(linear, len);
+        }
+        Ok(())
+    })
+    .ok_or("No such thread")?
+}
@@ -0,0 +1,186 @@
+// Process management for microkernel
+
+pub mod thread;
+pub mod scheduler;
+pub mod policy;
+pub mod idle;
+pub mod oom;
+pub mod capability;
+pub mod supervisor;
+pub mod elf;
+pub mod aslr;
+pub mod program;
+pub mod fork;
+pub mod fault;
+pub mod inspect;
+pub mod test;
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::memory::frame_allocator::{allocate_frames, frame_allocator_stats};
+
+pub use thread::{Priority, ThreadId};
+
+// SVC immediate used by kernel threads to yield (syscalls use other values)
+pub const SVC_YIELD: u64 = 0xFFFF;
+
+// Default priority for kernel threads
+pub const KTHREAD_DEFAULT_PRIORITY: Priority = 16;
+
+// Reclaim watermarks in free frames (vm.min_free_frames, vm.low_free_frames).
+// Thread allocations may not dip into the last `min` frames, which are kept
+// for the kernel; once fewer than `low` are free exited threads are reaped
+// straight away rather than when an allocation fails.
+static MIN_FREE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static LOW_FREE_FRAMES: AtomicUsize = AtomicUsize::new(64);
+
+pub fn init() {
+    crate::println!("Initializing process management...");
+    
+    // TODO: Set up process table
+    
+    if let Some(name) = crate::bootparams::get("sched") {
+        if let Err(e) = scheduler::select_policy(name) {
+            crate::println!("Process: {} '{}', keeping {}", e, name, scheduler::policy_name());
+        }
+    }
+    // The boot path becomes thread 0 so it can be preempted like any other
+    scheduler::init("kmain", KTHREAD_DEFAULT_PRIORITY);
+    crate::println!("Process: Scheduler started (priority, preemptive, {} policy)", scheduler::policy_name());
+    crate::executor::init();
+    fork::init();
+    idle::init();
+    
+    crate::println!("Process management initialized");
+}
+
+/// Start a kernel thread running `entry`.
+///
+/// The thread gets its own kernel stack and begins with interrupts
+/// enabled. Returning from `entry` exits the thread.
+pub fn kthread_spawn(entry: fn(), name: &str, priority: Priority) -> Result<ThreadId, &'static str> {
+    // Helpers a running service starts are labelled as part of it; threads
+    // started by the boot thread stand on their own
+    let spawner = scheduler::current_thread_id();
+    let process = scheduler::with_thread(spawner, |thread| {
+        thread.parent.map(|_| thread.process.unwrap_or(thread.name))
+    }).flatten();
+    let id = scheduler::spawn(|id| {
+        let mut thread = thread::Thread::new_kernel(id, name, priority, entry, kthread_trampoline)?;
+        thread.process = process;
+        Ok(thread)
+    })?;
+    let label = scheduler::with_thread(id, |thread| thread.label()).unwrap_or(thread::ThreadLabel::new(name));
+    crate::println!("Process: Spawned kernel thread {} '{}' (priority {})", id, label, priority);
+    crate::audit::process_spawn(spawner, id, label.as_str());
+    crate::manifest::service_started(id, name);
+    Ok(id)
+}
+
+/// Terminate the calling kernel thread.
+pub fn kthread_exit() -> ! {
+    exit_current(supervisor::ExitReason::Exited, 0)
+}
+
+/// Terminate the calling thread, recording why for its supervisor.
+pub fn exit_current(reason: supervisor::ExitReason, status: u32) -> ! {
+    mark_exited(reason, status);
+    loop {
+        scheduler::yield_now();
+    }
+}
+
+/// Record the calling thread's exit; it never runs again once switched
+/// out. For exception context, which cannot yield itself.
+pub fn mark_exited(reason: supervisor::ExitReason, status: u32) {
+    let id = scheduler::current_thread_id();
+    fork::release_borrowed(id);
+    crate::audit::process_exit(id, status);
+    supervisor::thread_exited(id, reason, status);
+    scheduler::exit_current();
+}
+
+/// Terminate another thread (see `scheduler::kill`), telling its supervisor.
+pub fn kill(id: ThreadId) -> Result<(), &'static str> {
+    if scheduler::is_idle(id) || id == scheduler::current_thread_id() {
+        return scheduler::kill(id);
+    }
+    fork::release_borrowed(id);
+    supervisor::thread_exited(id, supervisor::ExitReason::Killed, 0);
+    scheduler::kill(id)
+}
+
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+/// The (min, low) reclaim watermarks.
+pub fn watermarks() -> (usize, usize) {
+    (MIN_FREE_FRAMES.load(Ordering::Relaxed), LOW_FREE_FRAMES.load(Ordering::Relaxed))
+}
+
+/// Reserve `frames` for the kernel; at most half of memory.
+pub fn set_min_free_frames(frames: usize) -> Result<(), &'static str> {
+    let (_, total) = frame_allocator_stats();
+    if frames > total / 2 {
+        return Err("Reserve larger than half of memory");
+    }
+    MIN_FREE_FRAMES.store(frames, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn set_low_free_frames(frames: usize) -> Result<(), &'static str> {
+    LOW_FREE_FRAMES.store(frames, Ordering::Relaxed);
+    Ok(())
+}
+
+// Allocate for a thread, leaving the kernel reserve alone
+fn allocate_above_min(frames: usize) -> Option<NonNull<u8>> {
+    let (free, _) = frame_allocator_stats();
+    if free < frames + MIN_FREE_FRAMES.load(Ordering::Relaxed) {
+        return None;
+    }
+    allocate_frames(frames)
+}
+
+/// Allocate `frames` contiguous frames charged to the calling thread.
+///
+/// If memory is short, exited threads are reaped first; if that does not
+/// help the OOM killer frees memory, possibly by killing the caller.
+pub fn alloc_pages(frames: usize) -> Result<NonNull<u8>, &'static str> {
+    let base = match allocate_above_min(frames) {
+        Some(base) => base,
+        None => {
+            scheduler::reap_exited();
+            loop {
+                if let Some(base) = allocate_above_min(frames) {
+                    break base;
+                }
+                oom::out_of_memory(frames)?;
+            }
+        }
+    };
+    if frame_allocator_stats().0 < LOW_FREE_FRAMES.load(Ordering::Relaxed) {
+        scheduler::reap_exited();
+    }
+    
+    let run = thread::PageRun { base, frames };
+    scheduler::with_thread(scheduler::current_thread_id(), |thread| thread.charge(run))
+        .ok_or("No current thread")?;
+    Ok(base)
+}
+
+/// Free pages from `alloc_pages`.
+pub fn free_pages(base: NonNull<u8>) -> Result<(), &'static str> {
+    match scheduler::with_thread(scheduler::current_thread_id(), |thread| thread.uncharge(base)) {
+        Some(true) => Ok(()),
+        _ => Err("Pages not charged to this thread"),
+    }
+}
+
+// First code a new kernel thread runs after its initial exception return
+extern "C" fn kthread_trampoline(entry: usize) -> ! {
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    entry();
+    kthread_exit();
+}
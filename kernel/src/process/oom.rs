@@ -0,0 +1,140 @@
+// Out-of-memory killer
+//
+// When an allocation charged to a thread fails even after exited threads
+// are reaped, one thread is killed to free memory instead of failing the
+// allocation or panicking. Candidates are ranked by badness: their share
+// of RAM in thousandths, plus oom_score_adj, less a bonus per priority
+// level. The idle thread, the boot thread and threads the process manager
+// protected are never chosen.
+//
+// A victim is killed where it was preempted, so kernel threads must not
+// be preempted while holding a lock another thread needs to free memory.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::memory::frame_allocator::frame_allocator_stats;
+use super::scheduler;
+use super::supervisor::{self, ExitReason};
+use super::thread::{Priority, ThreadId, ThreadLabel};
+
+pub const OOM_SCORE_ADJ_MIN: i16 = -1000;
+pub const OOM_SCORE_ADJ_MAX: i16 = 1000;
+
+// Badness subtracted per priority level, so urgent threads lose ties
+const PRIORITY_BONUS: i64 = 2;
+
+static OOM_KILLS: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Copy, Clone, Debug)]
+pub struct OomCandidate {
+    pub id: ThreadId,
+    pub name: ThreadLabel,
+    pub rss_frames: usize,
+    pub priority: Priority,
+    pub score_adj: i16,
+    pub protected: bool,
+    pub score: i64,
+}
+
+impl OomCandidate {
+    fn eligible(&self) -> bool {
+        !self.protected && self.score_adj > OOM_SCORE_ADJ_MIN
+    }
+}
+
+/// Badness of a thread holding `rss_frames` of `total_frames`.
+pub fn badness(rss_frames: usize, total_frames: usize, priority: Priority, score_adj: i16) -> i64 {
+    let share = (rss_frames as i64 * 1000) / total_frames.max(1) as i64;
+    share + score_adj as i64 - priority as i64 * PRIORITY_BONUS
+}
+
+/// Set a thread's OOM policy; the process manager's knob for each service.
+pub fn set_policy(id: ThreadId, score_adj: i16, protected: bool) -> Result<(), &'static str> {
+    if !(OOM_SCORE_ADJ_MIN..=OOM_SCORE_ADJ_MAX).contains(&score_adj) {
+        return Err("OOM score adjustment out of range");
+    }
+    scheduler::with_thread(id, |thread| {
+        thread.oom_score_adj = score_adj;
+        thread.oom_protected = protected;
+    })
+    .ok_or("No such thread")
+}
+
+/// Every live thread with its badness; unkillable threads are marked protected.
+pub fn candidates() -> Vec<OomCandidate> {
+    let (_, total_frames) = frame_allocator_stats();
+    let mut list = Vec::new();
+    scheduler::for_each_thread(|thread| {
+        let rss_frames = thread.rss_frames();
+        list.push(OomCandidate {
+            id: thread.id,
+            name: thread.label(),
+            rss_frames,
+            priority: thread.priority,
+            score_adj: thread.oom_score_adj,
+            // The boot thread runs on the boot stack and cannot be torn down
+            protected: thread.oom_protected || !thread.has_own_stack(),
+            score: badness(rss_frames, total_frames, thread.priority, thread.oom_score_adj),
+        });
+    });
+    list.retain(|candidate| !scheduler::is_idle(candidate.id));
+    list
+}
+
+/// The thread the OOM killer would pick right now.
+pub fn select_victim() -> Option<OomCandidate> {
+    candidates()
+        .into_iter()
+        .filter(OomCandidate::eligible)
+        .max_by_key(|candidate| (candidate.score, candidate.rss_frames))
+}
+
+/// Kill the worst thread after `frames` could not be allocated for the caller.
+///
+/// Prints a report of every candidate. Returns the victim once its memory
+/// has been released; if the caller itself is chosen this does not return.
+pub fn out_of_memory(frames: usize) -> Result<ThreadId, &'static str> {
+    let requester = scheduler::current_thread_id();
+    let (free, total) = frame_allocator_stats();
+    crate::println!("OOM: Allocation of {} frames for thread {} failed ({} / {} frames free)",
+                   frames, requester, free, total);
+    
+    let list = candidates();
+    crate::println!("OOM:   tid   rss  prio   adj  score  name");
+    for candidate in &list {
+        crate::println!("OOM: {:5} {:5} {:5} {:5} {:6}  {}{}",
+                       candidate.id, candidate.rss_frames, candidate.priority,
+                       candidate.score_adj, candidate.score, candidate.name,
+                       if candidate.eligible() { "" } else { " (protected)" });
+    }
+    
+    let victim = match list
+        .into_iter()
+        .filter(OomCandidate::eligible)
+        .max_by_key(|candidate| (candidate.score, candidate.rss_frames))
+    {
+        Some(victim) => victim,
+        None => {
+            crate::println!("OOM: No killable thread, failing the allocation");
+            return Err("Out of memory");
+        }
+    };
+    
+    crate::println!("OOM: Killed thread {} '{}' (score {}), freeing {} frames",
+                   victim.id, victim.name, victim.score, victim.rss_frames);
+    crate::audit::oom_kill(requester, victim.id, victim.rss_frames as u32, victim.name.as_str());
+    OOM_KILLS.fetch_add(1, Ordering::Relaxed);
+    
+    if victim.id == requester {
+        super::exit_current(ExitReason::OomKilled, 0);
+    }
+    supervisor::thread_exited(victim.id, ExitReason::OomKilled, 0);
+    scheduler::kill(victim.id)?;
+    scheduler::reap_exited();
+    Ok(victim.id)
+}
+
+/// Threads killed by the OOM killer since boot.
+pub fn kill_count() -> u32 {
+    OOM_KILLS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,303 @@
+// Scheduling policies
+//
+// The scheduler (scheduler.rs) owns the threads, the running one, the
+// time slice countdown, directed switches and idle; a policy owns the
+// queue of ready threads and decides which runs next and for how long.
+// Whatever the policy, a thread of higher effective priority runs first,
+// since priority inheritance relies on it; policies differ in how they
+// share the CPU between threads of equal priority.
+//
+// The policy is chosen with sched= in the bootargs ("rr" or "mlfq"); the
+// default is round robin, or the feedback queue with the sched-mlfq
+// feature. A new policy implements `Scheduler` and gets a `Policy` variant.
+//
+// Policies run under the scheduler lock, often in interrupt context: only
+// `admit` may allocate, and must reserve whatever the rest will need.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use super::scheduler::time_slice;
+use super::thread::{Priority, ThreadId};
+
+/// Feedback queue levels; a thread's time slice doubles at each.
+pub const MLFQ_LEVELS: usize = 4;
+
+/// Ticks of CPU use between moves of every thread back to the top level.
+pub const MLFQ_BOOST_TICKS: u32 = 100;
+
+/// A policy for the ready queue. `priority` gives a thread's effective
+/// priority at the time of the call.
+pub trait Scheduler: Send {
+    fn name(&self) -> &'static str;
+    
+    /// A new thread, `threads` with it; the only call that may allocate.
+    fn admit(&mut self, id: ThreadId, threads: usize);
+    
+    /// An exited thread, no longer queued.
+    fn forget(&mut self, id: ThreadId);
+    
+    /// Queue a ready thread: new, preempted or yielding.
+    fn enqueue(&mut self, id: ThreadId);
+    
+    /// Take a particular thread off the queue; false if it was not queued.
+    fn remove(&mut self, id: ThreadId) -> bool;
+    
+    /// Take the thread to run next.
+    fn pick_next(&mut self, priority: &dyn Fn(ThreadId) -> Priority) -> Option<ThreadId>;
+    
+    /// Timer ticks `id` runs for when it is switched in.
+    fn time_slice(&self, id: ThreadId) -> u32;
+    
+    /// A timer tick spent running `id`. True to switch away now, before its
+    /// slice is up.
+    fn tick(&mut self, id: ThreadId) -> bool;
+    
+    fn for_each_queued(&self, f: &mut dyn FnMut(ThreadId));
+    
+    /// `id` stopped running to wait for something.
+    fn block(&mut self, _id: ThreadId) {}
+    
+    /// `id` is ready again after waiting.
+    fn wake(&mut self, id: ThreadId) {
+        self.enqueue(id);
+    }
+    
+    fn contains(&self, id: ThreadId) -> bool {
+        let mut found = false;
+        self.for_each_queued(&mut |queued| found |= queued == id);
+        found
+    }
+    
+    fn is_empty(&self) -> bool {
+        let mut empty = true;
+        self.for_each_queued(&mut |_| empty = false);
+        empty
+    }
+}
+
+/// The policies built in, by value so the scheduler can hold one in a static.
+pub enum Policy {
+    RoundRobin(RoundRobin),
+    Mlfq(Mlfq),
+}
+
+impl Policy {
+    /// The policy used unless the bootargs choose another.
+    pub const fn default() -> Self {
+        if cfg!(feature = "sched-mlfq") {
+            Policy::Mlfq(Mlfq::new())
+        } else {
+            Policy::RoundRobin(RoundRobin::new())
+        }
+    }
+    
+    /// A policy by its sched= name.
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "rr" => Some(Policy::RoundRobin(RoundRobin::new())),
+            "mlfq" => Some(Policy::Mlfq(Mlfq::new())),
+            _ => None,
+        }
+    }
+    
+    pub fn get(&self) -> &dyn Scheduler {
+        match self {
+            Policy::RoundRobin(policy) => policy,
+            Policy::Mlfq(policy) => policy,
+        }
+    }
+    
+    pub fn get_mut(&mut self) -> &mut dyn Scheduler {
+        match self {
+            Policy::RoundRobin(policy) => policy,
+            Policy::Mlfq(policy) => policy,
+        }
+    }
+}
+
+/// Highest priority first, first come first served within a priority, and
+/// everyone gets the same slice.
+pub struct RoundRobin {
+    queue: VecDeque<ThreadId>,
+}
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        Self { queue: VecDeque::new() }
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        "rr"
+    }
+    
+    fn admit(&mut self, _id: ThreadId, threads: usize) {
+        self.queue.reserve(threads);
+    }
+    
+    fn forget(&mut self, _id: ThreadId) {}
+    
+    fn enqueue(&mut self, id: ThreadId) {
+        self.queue.push_back(id);
+    }
+    
+    fn remove(&mut self, id: ThreadId) -> bool {
+        let index = self.queue.iter().position(|&queued| queued == id);
+        index.and_then(|index| self.queue.remove(index)).is_some()
+    }
+    
+    fn pick_next(&mut self, priority: &dyn Fn(ThreadId) -> Priority) -> Option<ThreadId> {
+        let mut best: Option<(usize, Priority)> = None;
+        for (index, &id) in self.queue.iter().enumerate() {
+            let priority = priority(id);
+            if best.is_none_or(|(_, best_priority)| priority > best_priority) {
+                best = Some((index, priority));
+            }
+        }
+        self.queue.remove(best?.0)
+    }
+    
+    fn time_slice(&self, _id: ThreadId) -> u32 {
+        time_slice()
+    }
+    
+    fn tick(&mut self, _id: ThreadId) -> bool {
+        false
+    }
+    
+    fn for_each_queued(&self, f: &mut dyn FnMut(ThreadId)) {
+        self.queue.iter().for_each(|&id| f(id));
+    }
+}
+
+// What the feedback queue knows about a thread
+struct MlfqThread {
+    id: ThreadId,
+    level: usize,
+    // Ticks run at this level, across however many slices
+    used: u32,
+}
+
+/// Multilevel feedback queue: within a priority, threads that have run
+/// least recently go first. A thread starts on the top level and moves
+/// down one once it has run for a slice there in total, so compute-bound
+/// threads sink while ones that block early stay on top; lower levels
+/// get longer slices. Every MLFQ_BOOST_TICKS everything returns to the
+/// top, so nothing starves.
+pub struct Mlfq {
+    levels: [VecDeque<ThreadId>; MLFQ_LEVELS],
+    threads: Vec<MlfqThread>,
+    since_boost: u32,
+}
+
+impl Mlfq {
+    pub const fn new() -> Self {
+        Self {
+            levels: [const { VecDeque::new() }; MLFQ_LEVELS],
+            threads: Vec::new(),
+            since_boost: 0,
+        }
+    }
+    
+    fn thread(&self, id: ThreadId) -> Option<&MlfqThread> {
+        self.threads.iter().find(|thread| thread.id == id)
+    }
+    
+    /// The level `id` is queued at, or would be.
+    pub fn level(&self, id: ThreadId) -> Option<usize> {
+        self.thread(id).map(|thread| thread.level)
+    }
+    
+    // Everything back to the top, queue order kept level by level
+    fn boost(&mut self) {
+        for thread in &mut self.threads {
+            thread.level = 0;
+            thread.used = 0;
+        }
+        let (top, lower) = self.levels.split_at_mut(1);
+        for level in lower {
+            top[0].extend(level.drain(..));
+        }
+        self.since_boost = 0;
+    }
+}
+
+impl Scheduler for Mlfq {
+    fn name(&self) -> &'static str {
+        "mlfq"
+    }
+    
+    fn admit(&mut self, id: ThreadId, threads: usize) {
+        self.threads.reserve(threads.saturating_sub(self.threads.len()));
+        self.threads.push(MlfqThread { id, level: 0, used: 0 });
+        // A boost can put every thread on one level
+        for level in &mut self.levels {
+            level.reserve(threads);
+        }
+    }
+    
+    fn forget(&mut self, id: ThreadId) {
+        self.threads.retain(|thread| thread.id != id);
+    }
+    
+    fn enqueue(&mut self, id: ThreadId) {
+        let level = self.level(id).unwrap_or(0);
+        self.levels[level].push_back(id);
+    }
+    
+    fn remove(&mut self, id: ThreadId) -> bool {
+        for level in &mut self.levels {
+            if let Some(index) = level.iter().position(|&queued| queued == id) {
+                level.remove(index);
+                return true;
+            }
+        }
+        false
+    }
+    
+    fn pick_next(&mut self, priority: &dyn Fn(ThreadId) -> Priority) -> Option<ThreadId> {
+        // Upper levels first, so the first of the highest priority wins
+        let mut best: Option<(usize, usize, Priority)> = None;
+        for (level, queue) in self.levels.iter().enumerate() {
+            for (index, &id) in queue.iter().enumerate() {
+                let priority = priority(id);
+                if best.is_none_or(|(_, _, best_priority)| priority > best_priority) {
+                    best = Some((level, index, priority));
+                }
+            }
+        }
+        let (level, index, _) = best?;
+        self.levels[level].remove(index)
+    }
+    
+    fn time_slice(&self, id: ThreadId) -> u32 {
+        time_slice() << self.level(id).unwrap_or(0)
+    }
+    
+    fn tick(&mut self, id: ThreadId) -> bool {
+        self.since_boost += 1;
+        if self.since_boost >= MLFQ_BOOST_TICKS {
+            self.boost();
+            return true;
+        }
+        let allotment = self.time_slice(id);
+        let Some(thread) = self.threads.iter_mut().find(|thread| thread.id == id) else {
+            return false;
+        };
+        thread.used += 1;
+        if thread.used < allotment {
+            return false;
+        }
+        thread.used = 0;
+        if thread.level + 1 < MLFQ_LEVELS {
+            thread.level += 1;
+            return true;
+        }
+        false
+    }
+    
+    fn for_each_queued(&self, f: &mut dyn FnMut(ThreadId)) {
+        self.levels.iter().flatten().for_each(|&id| f(id));
+    }
+}
@@ -0,0 +1,100 @@
+// User programs: an ELF image started in a fresh address space
+//
+// The new thread enters the image at EL0 with sp at the System V initial
+// stack that userland/runtime/src/start.rs decodes: argc, the argv
+// pointers, an empty environment and the auxiliary vector, with the
+// argument strings and AT_RANDOM's bytes above them. Where the image, the
+// mmap area and the stack go is randomized per program unless the caller
+// asks for the fixed layout (aslr.rs).
+
+use alloc::vec::Vec;
+use crate::memory::frame_allocator::PAGE_SIZE;
+use crate::memory::paging::{phys_to_virt, PageFlags, VirtAddr, VirtualMemoryManager};
+use super::aslr::Layout;
+use super::elf::{self, LoadedImage};
+use super::thread::{AddressSpace, Thread};
+use super::{scheduler, Priority, ThreadId};
+
+/// Size of a program's initial stack.
+pub const USER_STACK_PAGES: usize = 16;
+/// Most the arguments may take of it, strings and pointers together.
+pub const USER_ARGS_MAX: usize = PAGE_SIZE;
+
+// Auxiliary vector types
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+// Bytes of AT_RANDOM seed
+const RANDOM_BYTES: usize = 16;
+
+/// Load `image` into a new address space and start it as thread `name`
+/// with arguments `args` (argv[0] included). `randomize` false keeps the
+/// fixed layout, for debugging.
+pub fn spawn(name: &str, image: &[u8], args: &[&str], priority: Priority, randomize: bool) -> Result<ThreadId, &'static str> {
+    let layout = Layout::new(randomize);
+    let vmm = VirtualMemoryManager::new_user().ok_or("Out of memory for address space")?;
+    let mut space = AddressSpace::with_layout(vmm, &layout);
+    let loaded = elf::load_at(&mut space, image, layout.pie_bias).map_err(|e| e.as_str())?;
+    let sp = build_stack(&mut space, &loaded, layout.stack_top, args)?;
+    
+    let spawner = scheduler::current_thread_id();
+    let id = scheduler::spawn(|id| Thread::new_user(id, name, priority, space, loaded.entry, sp))?;
+    crate::println!("Process: Started program {} '{}' at 0x{:x} (priority {}, {} layout)", id, name, loaded.entry,
+                   priority, if layout.randomized { "random" } else { "fixed" });
+    crate::audit::process_spawn(spawner, id, name);
+    Ok(id)
+}
+
+// Map the stack and lay out the initial frame; returns the stack pointer
+fn build_stack(space: &mut AddressSpace, loaded: &LoadedImage, top: VirtAddr, args: &[&str]) -> Result<VirtAddr, &'static str> {
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::INNER_SHAREABLE | PageFlags::ACCESSED
+        | PageFlags::USER | PageFlags::PXN | PageFlags::UXN;
+    space.map_stack(top, USER_STACK_PAGES, flags)?;
+    
+    // Strings at the top, the random bytes below them, then the words
+    let strings_len: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    let strings = (top - strings_len as VirtAddr) & !0xF;
+    let random = strings - RANDOM_BYTES as VirtAddr;
+    let word_count = 1 + args.len() + 1 + 1 + 2 * 4;
+    let sp = (random - (word_count * 8) as VirtAddr) & !0xF;
+    if (top - sp) as usize > USER_ARGS_MAX {
+        return Err("Argument list too long");
+    }
+    
+    let mut frame = alloc::vec![0u8; (top - sp) as usize];
+    let mut words = Vec::with_capacity(word_count);
+    words.push(args.len() as u64);
+    let mut cursor = strings;
+    for arg in args {
+        let at = (cursor - sp) as usize;
+        frame[at..at + arg.len()].copy_from_slice(arg.as_bytes());
+        words.push(cursor);
+        cursor += arg.len() as VirtAddr + 1;
+    }
+    words.extend_from_slice(&[0, 0]);
+    words.extend_from_slice(&[AT_PAGESZ, PAGE_SIZE as u64, AT_ENTRY, loaded.entry, AT_RANDOM, random, AT_NULL, 0]);
+    for (i, word) in words.iter().enumerate() {
+        frame[i * 8..i * 8 + 8].copy_from_slice(&word.to_ne_bytes());
+    }
+    let at = (random - sp) as usize;
+    crate::rand::get_random_bytes(&mut frame[at..at + RANDOM_BYTES]);
+    
+    write_to(space, sp, &frame)?;
+    Ok(sp)
+}
+
+// Copy into the space's freshly populated pages through the linear map
+fn write_to(space: &mut AddressSpace, mut virt: VirtAddr, mut bytes: &[u8]) -> Result<(), &'static str> {
+    while !bytes.is_empty() {
+        let phys = space.vmm().translate(virt).ok_or("Stack not mapped")?;
+        let len = bytes.len().min(PAGE_SIZE - (virt as usize % PAGE_SIZE));
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), phys_to_virt(phys) as *mut u8, len);
+        }
+        virt += len as VirtAddr;
+        bytes = &bytes[len..];
+    }
+    Ok(())
+}
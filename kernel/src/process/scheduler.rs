@@ -0,0 +1,576 @@
+// Priority scheduler driven by the generic timer
+//
+// The highest-priority ready thread runs; threads of equal priority share
+// the CPU in time slices as the policy decides (policy.rs: round robin or
+// a multilevel feedback queue). A thread that becomes ready with a
+// higher priority than the running one preempts it at the next exception
+// return. A thread holding a SleepMutex inherits the priority of its most
+// urgent waiter until it releases it. Synchronous IPC bypasses the queue:
+// a thread about to block on a server hands the CPU, and the rest of its
+// slice, straight to it.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::interrupts::{counter_ticks, ExceptionContext};
+use crate::pmu::{self, PmuCounts};
+use crate::sync::IrqSafeMutex;
+use crate::tracepoint::{self, Tracepoint};
+use super::policy::Policy;
+use super::thread::{Priority, Thread, ThreadId, ThreadLabel, ThreadName, ThreadState, PRIORITY_MAX};
+use super::SVC_YIELD;
+
+// Timer ticks a thread may run before being preempted (50ms at 100Hz);
+// tunable as sched.timeslice_ticks
+const DEFAULT_TIME_SLICE_TICKS: u32 = 5;
+static TIME_SLICE_TICKS: AtomicU32 = AtomicU32::new(DEFAULT_TIME_SLICE_TICKS);
+
+struct SchedState {
+    threads: Vec<Thread>,
+    // Holds the ready queue
+    policy: Policy,
+    current: ThreadId,
+    // Runs only when nothing else is ready; never sits in the run queue
+    idle: Option<ThreadId>,
+    next_id: ThreadId,
+    slice_remaining: u32,
+    need_resched: bool,
+    // Runs next regardless of priority, on the slice its waker left
+    directed: Option<ThreadId>,
+    // PMU counts and counter ticks when the running thread was switched in
+    pmu_mark: PmuCounts,
+    switch_mark: u64,
+}
+
+impl SchedState {
+    const fn new() -> Self {
+        Self {
+            threads: Vec::new(),
+            policy: Policy::default(),
+            current: 0,
+            idle: None,
+            next_id: 0,
+            slice_remaining: DEFAULT_TIME_SLICE_TICKS,
+            need_resched: false,
+            directed: None,
+            pmu_mark: PmuCounts::ZERO,
+            switch_mark: 0,
+        }
+    }
+    
+    fn thread_mut(&mut self, id: ThreadId) -> Option<&mut Thread> {
+        self.threads.iter_mut().find(|thread| thread.id == id)
+    }
+    
+    fn effective_priority(&self, id: ThreadId) -> Priority {
+        priority_in(&self.threads, id)
+    }
+    
+    // Take the thread the policy runs next
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let Self { threads, policy, .. } = self;
+        policy.get_mut().pick_next(&|id| priority_in(threads, id))
+    }
+    
+    // `id` just became ready: switch to it if it outranks the running thread
+    fn check_preempt(&mut self, id: ThreadId) {
+        if self.idle == Some(self.current)
+            || self.effective_priority(id) > self.effective_priority(self.current)
+        {
+            self.need_resched = true;
+        }
+    }
+    
+    // The running thread's priority dropped: give way to anything queued
+    // that now outranks it
+    fn check_yield(&mut self) {
+        let current = self.effective_priority(self.current);
+        let mut outranked = false;
+        self.policy.get().for_each_queued(&mut |id| outranked |= self.effective_priority(id) > current);
+        if outranked {
+            self.need_resched = true;
+        }
+    }
+    
+    fn allocate_id(&mut self) -> ThreadId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+    
+    // Free exited threads; never called from interrupt context because
+    // dropping a stack takes the frame allocator lock
+    fn reap(&mut self) {
+        let Self { threads, policy, current, .. } = self;
+        threads.retain(|thread| {
+            let keep = thread.state != ThreadState::Exited || thread.id == *current;
+            if !keep {
+                policy.get_mut().forget(thread.id);
+            }
+            keep
+        });
+    }
+}
+
+fn priority_in(threads: &[Thread], id: ThreadId) -> Priority {
+    threads
+        .iter()
+        .find(|thread| thread.id == id)
+        .map_or(0, |thread| thread.effective_priority())
+}
+
+// Locked from both thread and IRQ context
+static SCHEDULER: IrqSafeMutex<SchedState> = IrqSafeMutex::new(SchedState::new());
+
+// Mirror of `Scheduler::current` readable without taking the lock
+static CURRENT: AtomicU32 = AtomicU32::new(0);
+
+// Whether the running thread has a syscall filter, so unfiltered syscalls
+// skip the lock
+static CURRENT_FILTERED: AtomicBool = AtomicBool::new(false);
+
+/// Adopt the currently executing boot code as the first thread.
+pub fn init(boot_name: &str, priority: Priority) {
+    let mut sched = SCHEDULER.lock();
+    let id = sched.allocate_id();
+    sched.threads.push(Thread::boot(id, boot_name, priority));
+    sched.policy.get_mut().admit(id, 1);
+    sched.current = id;
+    CURRENT.store(id, Ordering::Relaxed);
+}
+
+/// Use the policy called `name` (see policy.rs) instead of the default.
+/// Only before `init`: queued threads would not carry over.
+pub fn select_policy(name: &str) -> Result<(), &'static str> {
+    let policy = Policy::by_name(name).ok_or("Unknown scheduling policy")?;
+    let mut sched = SCHEDULER.lock();
+    if !sched.threads.is_empty() {
+        return Err("Scheduler already running");
+    }
+    sched.policy = policy;
+    Ok(())
+}
+
+/// Name of the policy in use.
+pub fn policy_name() -> &'static str {
+    SCHEDULER.lock().policy.get().name()
+}
+
+/// Add a thread built by `build` to the run queue.
+pub fn spawn<F>(build: F) -> Result<ThreadId, &'static str>
+where
+    F: FnOnce(ThreadId) -> Result<Thread, &'static str>,
+{
+    let mut sched = SCHEDULER.lock();
+    sched.reap();
+    
+    let id = sched.allocate_id();
+    let mut thread = build(id)?;
+    thread.parent = Some(sched.current);
+    // A filtered thread cannot shed its filter by spawning
+    let current = sched.current;
+    thread.syscall_filter = sched.thread_mut(current).and_then(|parent| parent.syscall_filter);
+    
+    // Reserve up front so the IRQ path never allocates
+    let capacity = sched.threads.len() + 1;
+    sched.threads.push(thread);
+    sched.policy.get_mut().admit(id, capacity);
+    sched.policy.get_mut().enqueue(id);
+    
+    // Idle yields as soon as there is work; make the switch prompt
+    sched.check_preempt(id);
+    Ok(id)
+}
+
+/// Timer tick accounting; called from the timer interrupt.
+pub fn tick() {
+    let mut sched = SCHEDULER.lock();
+    let current = sched.current;
+    if let Some(thread) = sched.thread_mut(current) {
+        thread.ticks += 1;
+    }
+    
+    // Idle has no time slice, it runs until something is ready
+    if sched.idle == Some(current) {
+        return;
+    }
+    
+    let give_way = sched.policy.get_mut().tick(current);
+    sched.slice_remaining = sched.slice_remaining.saturating_sub(1);
+    if sched.slice_remaining == 0 || give_way {
+        sched.need_resched = true;
+    }
+}
+
+/// Timer ticks a thread runs before another of its priority gets a turn
+/// (the policy may scale it).
+pub fn time_slice() -> u32 {
+    TIME_SLICE_TICKS.load(Ordering::Relaxed)
+}
+
+/// Change the time slice; the running thread keeps what is left of its own.
+pub fn set_time_slice(ticks: u32) -> Result<(), &'static str> {
+    if ticks == 0 {
+        return Err("Time slice must be at least one tick");
+    }
+    TIME_SLICE_TICKS.store(ticks, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Switch away from the running thread on the way out of this exception.
+pub fn request_resched() {
+    SCHEDULER.lock().need_resched = true;
+}
+
+/// Reschedule on interrupt return if the running thread used up its slice.
+/// An open RCU read section, or deferred work running on the way out of
+/// an interrupt, holds the switch off until a later one.
+pub fn preempt(ctx: *mut ExceptionContext) -> *mut ExceptionContext {
+    let need_resched = SCHEDULER.lock().need_resched;
+    if need_resched && !crate::rcu::in_read_section() && !crate::softirq::in_progress() {
+        schedule(ctx)
+    } else {
+        ctx
+    }
+}
+
+/// Save `ctx` as the current thread's state and pick the next thread.
+///
+/// Runs with IRQs masked (exception context). Returns the frame that the
+/// exception exit path should restore.
+pub fn schedule(ctx: *mut ExceptionContext) -> *mut ExceptionContext {
+    crate::rcu::quiescent_state();
+    crate::watchdog::touch();
+    let mut sched = SCHEDULER.lock();
+    sched.need_resched = false;
+    let directed = sched.directed.take();
+    
+    let current = sched.current;
+    let current_is_idle = sched.idle == Some(current);
+    let mut requeue = false;
+    if let Some(thread) = sched.thread_mut(current) {
+        thread.context = ctx;
+        if thread.state == ThreadState::Running {
+            thread.state = ThreadState::Ready;
+            requeue = !current_is_idle;
+        }
+    }
+    if requeue {
+        sched.policy.get_mut().enqueue(current);
+    }
+    
+    if let Some(next) = directed {
+        if sched.policy.get_mut().remove(next) {
+            // A directed switch donates what is left of the slice
+            if sched.slice_remaining == 0 {
+                sched.slice_remaining = sched.policy.get().time_slice(next);
+            }
+            if let Some(context) = switch_to(&mut sched, next) {
+                return context;
+            }
+        }
+    }
+    
+    while let Some(next) = sched.pick_next() {
+        if let Some(context) = switch_to(&mut sched, next) {
+            sched.slice_remaining = sched.policy.get().time_slice(next);
+            return context;
+        }
+    }
+    sched.slice_remaining = time_slice();
+    
+    // Nothing else runnable: fall back to the idle thread
+    if let Some(idle) = sched.idle {
+        if let Some(context) = switch_to(&mut sched, idle) {
+            return context;
+        }
+    }
+    
+    // No idle thread yet (early boot): resume whoever was running
+    if let Some(thread) = sched.thread_mut(current) {
+        if thread.state == ThreadState::Ready {
+            thread.state = ThreadState::Running;
+        }
+    }
+    ctx
+}
+
+// Make `next` the running thread if it is ready, returning its saved frame
+fn switch_to(sched: &mut SchedState, next: ThreadId) -> Option<*mut ExceptionContext> {
+    let thread = sched.thread_mut(next)?;
+    if thread.state != ThreadState::Ready {
+        return None;
+    }
+    thread.state = ThreadState::Running;
+    let context = thread.context;
+    let filtered = thread.syscall_filter.is_some();
+    // Kernel threads keep whatever TTBR0 holds (lazy TLB)
+    if let Some(space) = thread.address_space() {
+        space.vmm().activate();
+    }
+    if sched.current != next {
+        tracepoint::hit(Tracepoint::SchedSwitch, sched.current as u64, next as u64);
+        // Charge the outgoing thread for the events and time since it was
+        // switched in
+        let now = pmu::read();
+        let used = now - sched.pmu_mark;
+        sched.pmu_mark = now;
+        let ticks = counter_ticks();
+        let ran = ticks.saturating_sub(sched.switch_mark);
+        sched.switch_mark = ticks;
+        let previous = sched.current;
+        if let Some(thread) = sched.thread_mut(previous) {
+            thread.pmu += used;
+            thread.cpu_time += ran;
+        }
+    }
+    sched.current = next;
+    CURRENT.store(next, Ordering::Relaxed);
+    CURRENT_FILTERED.store(filtered, Ordering::Relaxed);
+    Some(context)
+}
+
+/// Turn the calling thread into the idle thread.
+pub fn become_idle(name: &str) {
+    let mut sched = SCHEDULER.lock();
+    let current = sched.current;
+    if let Some(thread) = sched.thread_mut(current) {
+        thread.name = ThreadName::new(name);
+    }
+    sched.idle = Some(current);
+}
+
+/// Whether any thread other than idle is waiting to run.
+pub fn has_runnable() -> bool {
+    !SCHEDULER.lock().policy.get().is_empty()
+}
+
+/// Mark the running thread as exited; it never runs again once switched out.
+pub fn exit_current() {
+    let mut sched = SCHEDULER.lock();
+    let current = sched.current;
+    if let Some(thread) = sched.thread_mut(current) {
+        thread.state = ThreadState::Exited;
+    }
+}
+
+/// Take the running thread off the CPU until `wake` is called for it.
+///
+/// Only marks it blocked; the caller yields afterwards. Check the wait
+/// condition and block with IRQs masked so a wakeup from an interrupt
+/// handler cannot fall in between: if it lands after this call the
+/// thread is simply requeued before it yields.
+pub fn block_current() {
+    let mut sched = SCHEDULER.lock();
+    let current = sched.current;
+    if sched.idle == Some(current) {
+        return;
+    }
+    if let Some(thread) = sched.thread_mut(current) {
+        thread.state = ThreadState::Blocked;
+    }
+    sched.policy.get_mut().block(current);
+}
+
+/// Make a blocked thread runnable again. Safe from interrupt context.
+pub fn wake(id: ThreadId) {
+    let mut sched = SCHEDULER.lock();
+    let woken = match sched.thread_mut(id) {
+        Some(thread) if thread.state == ThreadState::Blocked => {
+            thread.state = ThreadState::Ready;
+            true
+        }
+        _ => false,
+    };
+    if woken {
+        // Queue capacity is reserved at spawn, so this never allocates
+        sched.policy.get_mut().wake(id);
+        sched.check_preempt(id);
+    }
+}
+
+/// Wake `next` and have it run in place of the caller at the next switch,
+/// on the rest of the caller's time slice, whatever its priority.
+///
+/// For a caller about to wait on `next` (an IPC call, or a reply to the
+/// caller): block, call this and yield, with IRQs masked up to the yield.
+pub fn switch_directly(next: ThreadId) {
+    wake(next);
+    let mut sched = SCHEDULER.lock();
+    if sched.policy.get().contains(next) {
+        sched.directed = Some(next);
+        sched.need_resched = true;
+    }
+}
+
+/// Give up the CPU to the next ready thread.
+pub fn yield_now() {
+    unsafe {
+        asm!("svc #{}", const SVC_YIELD);
+    }
+}
+
+pub fn current_thread_id() -> ThreadId {
+    CURRENT.load(Ordering::Relaxed)
+}
+
+/// Whether the running thread has a syscall filter.
+pub fn current_is_filtered() -> bool {
+    CURRENT_FILTERED.load(Ordering::Relaxed)
+}
+
+/// Record that the running thread has just installed a syscall filter.
+pub fn mark_current_filtered() {
+    CURRENT_FILTERED.store(true, Ordering::Relaxed);
+}
+
+/// Free the stacks of threads that have exited.
+pub fn reap_exited() {
+    SCHEDULER.lock().reap();
+}
+
+/// Run `f` on thread `id` under the scheduler lock.
+pub fn with_thread<R>(id: ThreadId, f: impl FnOnce(&mut Thread) -> R) -> Option<R> {
+    SCHEDULER.lock().thread_mut(id).map(f)
+}
+
+/// Rename a thread; names longer than THREAD_NAME_LEN are cut short.
+pub fn set_name(id: ThreadId, name: &str) -> Result<(), &'static str> {
+    with_thread(id, |thread| thread.name = ThreadName::new(name)).ok_or("No such thread")
+}
+
+/// A thread's label for diagnostics. Gives up rather than wait for the
+/// scheduler lock, so panic and exception paths can use it; None then, as
+/// for a thread that no longer exists.
+pub fn label_of(id: ThreadId) -> Option<ThreadLabel> {
+    SCHEDULER.try_lock()?.thread_mut(id).map(|thread| thread.label())
+}
+
+/// A thread as messages print it: "7 (blkd/irq-worker)", or the bare ID
+/// when `label_of` has nothing.
+pub struct ThreadRef(ThreadId, Option<ThreadLabel>);
+
+impl core::fmt::Display for ThreadRef {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match &self.1 {
+            Some(label) => write!(f, "{} ({})", self.0, label),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+pub fn thread_ref(id: ThreadId) -> ThreadRef {
+    ThreadRef(id, label_of(id))
+}
+
+/// Visit every live (not exited) thread under the scheduler lock.
+pub fn for_each_thread(mut f: impl FnMut(&Thread)) {
+    let sched = SCHEDULER.lock();
+    for thread in sched.threads.iter().filter(|t| t.state != ThreadState::Exited) {
+        f(thread);
+    }
+}
+
+/// As `for_each_thread`, also passing each thread's CPU time in counter
+/// ticks, the running thread's including its current slice.
+pub fn for_each_thread_cpu(mut f: impl FnMut(&Thread, u64)) {
+    let sched = SCHEDULER.lock();
+    let running = counter_ticks().saturating_sub(sched.switch_mark);
+    for thread in sched.threads.iter().filter(|t| t.state != ThreadState::Exited) {
+        let current = if thread.id == sched.current { running } else { 0 };
+        f(thread, thread.cpu_time + current);
+    }
+}
+
+/// Terminate another thread; its memory is released when it is reaped.
+///
+/// The thread is never switched to again. It must not be the caller or
+/// the idle thread.
+pub fn kill(id: ThreadId) -> Result<(), &'static str> {
+    let mut sched = SCHEDULER.lock();
+    if id == sched.current {
+        return Err("Cannot kill the running thread");
+    }
+    if sched.idle == Some(id) {
+        return Err("Cannot kill the idle thread");
+    }
+    let thread = sched.thread_mut(id).ok_or("No such thread")?;
+    if thread.state == ThreadState::Exited {
+        return Err("Thread already exited");
+    }
+    thread.state = ThreadState::Exited;
+    sched.policy.get_mut().remove(id);
+    Ok(())
+}
+
+/// Change a thread's base priority. Takes effect at the next exception
+/// return if it changes which thread should be running.
+pub fn set_priority(id: ThreadId, priority: Priority) -> Result<(), &'static str> {
+    if priority > PRIORITY_MAX {
+        return Err("Priority out of range");
+    }
+    let mut sched = SCHEDULER.lock();
+    if sched.idle == Some(id) {
+        return Err("Cannot change the idle thread's priority");
+    }
+    let thread = sched.thread_mut(id).ok_or("No such thread")?;
+    if thread.state == ThreadState::Exited {
+        return Err("Thread already exited");
+    }
+    thread.priority = priority;
+    let ready = thread.state == ThreadState::Ready;
+    if id == sched.current {
+        sched.check_yield();
+    } else if ready {
+        sched.check_preempt(id);
+    }
+    Ok(())
+}
+
+pub fn priority(id: ThreadId) -> Option<Priority> {
+    SCHEDULER.lock().thread_mut(id).map(|thread| thread.priority)
+}
+
+/// Priority the scheduler runs `id` at, including any inherited boost.
+pub fn effective_priority(id: ThreadId) -> Priority {
+    SCHEDULER.lock().effective_priority(id)
+}
+
+/// Priority inheritance hook: `owner` holds a lock a thread of
+/// `priority` is about to wait for, so it runs at least that urgently
+/// until `restore_priority`.
+pub fn inherit_priority(owner: ThreadId, priority: Priority) {
+    let mut sched = SCHEDULER.lock();
+    let Some(thread) = sched.thread_mut(owner) else {
+        return;
+    };
+    if thread.effective_priority() >= priority {
+        return;
+    }
+    thread.inherited_priority = Some(priority);
+    if thread.state == ThreadState::Ready {
+        sched.check_preempt(owner);
+    }
+}
+
+/// Priority inheritance hook: the running thread released the lock it
+/// was boosted for and drops back to its own priority.
+pub fn restore_priority() {
+    let mut sched = SCHEDULER.lock();
+    let current = sched.current;
+    let boosted = sched
+        .thread_mut(current)
+        .is_some_and(|thread| thread.inherited_priority.take().is_some());
+    if boosted {
+        sched.check_yield();
+    }
+}
+
+/// Whether `id` is the idle thread.
+pub fn is_idle(id: ThreadId) -> bool {
+    SCHEDULER.lock().idle == Some(id)
+}
+
+pub fn thread_count() -> usize {
+    SCHEDULER.lock().threads.len()
+}
@@ -0,0 +1,276 @@
+// Kernel support for supervised service restarts
+//
+// The process manager marks service threads as supervised with a restart
+// policy. When one exits, the kernel records why, decides whether it may
+// be restarted and when (exponential backoff), and queues an exit event
+// for the process manager. Once it has spawned the replacement it reports
+// the new thread, which gets back every capability the old one held.
+//
+// Restarting itself is the process manager's job; the kernel only keeps
+// the bookkeeping it cannot be trusted to lose across a crash.
+
+use alloc::vec::Vec;
+use crate::interrupts::{counter_frequency, counter_ticks};
+use crate::sync::IrqSafeMutex;
+use super::capability::{self, Capability};
+use super::scheduler::{current_thread_id, with_thread};
+use super::ThreadId;
+
+pub const EXIT_RING_SIZE: usize = 64;
+pub const SERVICE_NAME_LEN: usize = 16;
+
+/// `respawned` error while the restart delay is still running.
+pub const BACKOFF_PENDING: &str = "Restart backoff not expired";
+
+/// Why a thread stopped running.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// Returned or called exit; the status says how it went
+    Exited = 0,
+    /// Terminated by another thread
+    Killed = 1,
+    /// Chosen by the OOM killer
+    OomKilled = 2,
+    /// Took an unhandled fault
+    Faulted = 3,
+}
+
+/// When a supervised service is restarted.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RestartMode {
+    Never = 0,
+    /// Anything but a clean exit with status 0
+    OnFailure = 1,
+    Always = 2,
+}
+
+impl RestartMode {
+    fn from_u32(mode: u32) -> Option<Self> {
+        match mode {
+            0 => Some(RestartMode::Never),
+            1 => Some(RestartMode::OnFailure),
+            2 => Some(RestartMode::Always),
+            _ => None,
+        }
+    }
+}
+
+/// Restart policy; also the layout SYS_SERVICE_SUPERVISE reads.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct RestartPolicy {
+    pub mode: u32,           // RestartMode
+    pub max_restarts: u32,   // Consecutive restarts before giving up, 0 = unlimited
+    pub base_delay_ms: u32,  // Delay before the first restart
+    pub max_delay_ms: u32,   // Cap on the doubled delay
+    pub stable_ms: u32,      // Uptime after which the backoff starts over
+}
+
+/// One exit of a supervised thread; also the layout SYS_SERVICE_EVENTS
+/// copies out.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ExitEvent {
+    pub seq: u64,
+    pub timestamp: u64,  // Counter ticks
+    pub restart_at: u64, // Counter ticks; 0 when the service is not restarted
+    pub tid: u32,
+    pub reason: u32,     // ExitReason
+    pub status: u32,
+    pub attempt: u32,    // Consecutive restarts including this one
+    pub name: [u8; SERVICE_NAME_LEN],
+}
+
+impl ExitEvent {
+    const EMPTY: ExitEvent = ExitEvent {
+        seq: 0,
+        timestamp: 0,
+        restart_at: 0,
+        tid: 0,
+        reason: 0,
+        status: 0,
+        attempt: 0,
+        name: [0; SERVICE_NAME_LEN],
+    };
+}
+
+enum ServiceState {
+    Running { started_at: u64 },
+    // Exited; may be replaced from `restart_at` on
+    AwaitingRestart { restart_at: u64 },
+}
+
+struct Service {
+    tid: ThreadId,
+    policy: RestartPolicy,
+    state: ServiceState,
+    attempt: u32,
+    // Held by the thread when it exited, re-granted to its replacement
+    capabilities: Vec<Capability>,
+}
+
+impl Service {
+    fn should_restart(&self, reason: ExitReason, status: u32) -> bool {
+        match RestartMode::from_u32(self.policy.mode) {
+            Some(RestartMode::Always) => true,
+            Some(RestartMode::OnFailure) => reason != ExitReason::Exited || status != 0,
+            _ => false,
+        }
+    }
+    
+    // Doubles per consecutive restart, capped at max_delay_ms
+    fn backoff_ms(&self) -> u64 {
+        let shift = self.attempt.saturating_sub(1).min(31);
+        ((self.policy.base_delay_ms as u64) << shift).min(self.policy.max_delay_ms as u64)
+    }
+}
+
+struct Supervisor {
+    services: Vec<Service>,
+    events: [ExitEvent; EXIT_RING_SIZE],
+    // Sequence number of the next event; event `seq` lives at seq % size
+    next_seq: u64,
+}
+
+// Exits are recorded from the OOM path, which may run with IRQs masked
+static SUPERVISOR: IrqSafeMutex<Supervisor> = IrqSafeMutex::new(Supervisor {
+    services: Vec::new(),
+    events: [ExitEvent::EMPTY; EXIT_RING_SIZE],
+    next_seq: 0,
+});
+
+fn ms_to_ticks(ms: u64) -> u64 {
+    counter_frequency() * ms / 1000
+}
+
+/// Supervise thread `tid` with `policy`, replacing any earlier policy.
+pub fn supervise(tid: ThreadId, policy: RestartPolicy) -> Result<(), &'static str> {
+    if RestartMode::from_u32(policy.mode).is_none() || policy.max_delay_ms < policy.base_delay_ms {
+        return Err("Invalid restart policy");
+    }
+    with_thread(tid, |_| ()).ok_or("No such thread")?;
+    
+    let mut supervisor = SUPERVISOR.lock();
+    let state = ServiceState::Running { started_at: counter_ticks() };
+    match supervisor.services.iter_mut().find(|service| service.tid == tid) {
+        Some(service) => service.policy = policy,
+        None => supervisor.services.push(Service {
+            tid,
+            policy,
+            state,
+            attempt: 0,
+            capabilities: Vec::new(),
+        }),
+    }
+    Ok(())
+}
+
+/// Stop supervising `tid` (running or awaiting restart). False if it was not.
+pub fn unsupervise(tid: ThreadId) -> bool {
+    let mut supervisor = SUPERVISOR.lock();
+    let before = supervisor.services.len();
+    supervisor.services.retain(|service| service.tid != tid);
+    supervisor.services.len() != before
+}
+
+/// Record that `tid` stopped running. Called on every exit path before the
+/// thread is reaped; unsupervised threads are ignored.
+pub fn thread_exited(tid: ThreadId, reason: ExitReason, status: u32) {
+    let (name, capabilities) = match with_thread(tid, |thread| (thread.label(), thread.capabilities.clone())) {
+        Some(info) => info,
+        None => return,
+    };
+    let now = counter_ticks();
+    
+    let mut supervisor = SUPERVISOR.lock();
+    let Some(index) = supervisor.services.iter().position(|service| service.tid == tid) else { return };
+    let service = &mut supervisor.services[index];
+    let ServiceState::Running { started_at } = service.state else { return };
+    
+    // A service that stayed up long enough starts its backoff over
+    if now - started_at >= ms_to_ticks(service.policy.stable_ms as u64) {
+        service.attempt = 0;
+    }
+    service.attempt += 1;
+    let restart = service.should_restart(reason, status)
+        && (service.policy.max_restarts == 0 || service.attempt <= service.policy.max_restarts);
+    let attempt = service.attempt;
+    
+    let restart_at = if restart {
+        let restart_at = now + ms_to_ticks(service.backoff_ms());
+        service.state = ServiceState::AwaitingRestart { restart_at };
+        service.capabilities = capabilities;
+        crate::println!("Supervisor: '{}' (thread {}) exited ({:?}, status {}), restart {} in {} ms",
+                       name, tid, reason, status, attempt, service.backoff_ms());
+        restart_at
+    } else {
+        supervisor.services.swap_remove(index);
+        crate::println!("Supervisor: '{}' (thread {}) exited ({:?}, status {}), not restarting",
+                       name, tid, reason, status);
+        0
+    };
+    
+    let mut event = ExitEvent {
+        seq: supervisor.next_seq,
+        timestamp: now,
+        restart_at,
+        tid,
+        reason: reason as u32,
+        status,
+        attempt,
+        name: [0; SERVICE_NAME_LEN],
+    };
+    let len = name.as_str().len().min(SERVICE_NAME_LEN);
+    event.name[..len].copy_from_slice(&name.as_str().as_bytes()[..len]);
+    let slot = (event.seq % EXIT_RING_SIZE as u64) as usize;
+    supervisor.events[slot] = event;
+    supervisor.next_seq += 1;
+}
+
+/// `new_tid` replaces exited service thread `old_tid`: it inherits the
+/// policy and gets back the old thread's capabilities. Fails while the
+/// backoff delay is still running.
+pub fn respawned(old_tid: ThreadId, new_tid: ThreadId) -> Result<(), &'static str> {
+    with_thread(new_tid, |_| ()).ok_or("No such thread")?;
+    
+    let capabilities = {
+        let mut supervisor = SUPERVISOR.lock();
+        let service = supervisor
+            .services
+            .iter_mut()
+            .find(|service| service.tid == old_tid)
+            .ok_or("Not a supervised service")?;
+        let now = counter_ticks();
+        match service.state {
+            ServiceState::AwaitingRestart { restart_at } if now >= restart_at => {}
+            ServiceState::AwaitingRestart { .. } => return Err(BACKOFF_PENDING),
+            ServiceState::Running { .. } => return Err("Service still running"),
+        }
+        service.tid = new_tid;
+        service.state = ServiceState::Running { started_at: now };
+        core::mem::take(&mut service.capabilities)
+    };
+    
+    // Outside the lock: granting takes the scheduler lock and audits
+    let granter = current_thread_id();
+    for capability in capabilities {
+        capability::grant(granter, new_tid, capability)?;
+    }
+    Ok(())
+}
+
+/// Copy events with sequence number >= `since` into `out`; returns how
+/// many were copied. Overwritten events are skipped.
+pub fn read_events(since: u64, out: &mut [ExitEvent]) -> usize {
+    let supervisor = SUPERVISOR.lock();
+    let mut seq = since.max(supervisor.next_seq.saturating_sub(EXIT_RING_SIZE as u64));
+    let mut count = 0;
+    while seq < supervisor.next_seq && count < out.len() {
+        out[count] = supervisor.events[(seq % EXIT_RING_SIZE as u64) as usize];
+        seq += 1;
+        count += 1;
+    }
+    count
+}
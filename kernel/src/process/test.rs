@@ -0,0 +1,2201 @@
+// Process management testing utilities
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use crate::memory::frame_allocator::frame_allocator_stats;
+use super::{alloc_pages, free_pages, kthread_spawn, oom, yield_now, Priority, KTHREAD_DEFAULT_PRIORITY};
+use crate::interrupts::counter_ticks;
+use crate::ktest::kernel_test;
+use crate::sync::SleepMutex;
+use super::capability::{self, Capability};
+use super::fault::{self, FaultKind, FaultReport};
+use super::scheduler::{block_current, current_thread_id, effective_priority, reap_exited, set_priority, thread_count, wake};
+use super::supervisor::{self, ExitEvent, ExitReason, RestartMode, RestartPolicy, EXIT_RING_SIZE};
+use super::ThreadId;
+
+static TEST_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+fn counter_thread() {
+    for _ in 0..3 {
+        TEST_COUNTER.fetch_add(1, Ordering::SeqCst);
+        yield_now();
+    }
+}
+
+static STACK_PROBE: AtomicU64 = AtomicU64::new(0);
+
+fn stack_probe_thread() {
+    let local = 0u64;
+    STACK_PROBE.store(&local as *const u64 as u64, Ordering::SeqCst);
+}
+
+#[kernel_test]
+pub fn test_stack_guard() {
+    use crate::memory::mmu::MemoryManagementUnit;
+    use super::thread::{is_stack_guard, stack_bounds, KernelStack, KERNEL_STACK_SHIFT, KERNEL_STACK_SIZE};
+    
+    if !MemoryManagementUnit::is_enabled() {
+        crate::println!("Process Test: MMU disabled, skipping stack guard test");
+        return;
+    }
+    crate::println!("Process Test: Testing kernel stack guard pages...");
+    
+    // Threads run on stacks in the guarded area
+    STACK_PROBE.store(0, Ordering::SeqCst);
+    if kthread_spawn(stack_probe_thread, "stackprobe", KTHREAD_DEFAULT_PRIORITY).is_ok() {
+        for _ in 0..100 {
+            if STACK_PROBE.load(Ordering::SeqCst) != 0 {
+                break;
+            }
+            yield_now();
+        }
+    }
+    let probe = STACK_PROBE.load(Ordering::SeqCst);
+    if stack_bounds(probe).is_some_and(|(base, top)| probe >= base && probe < top) {
+        crate::println!("Process Test: ✓ Thread stack at 0x{:016x} is in the stack area", probe);
+    } else {
+        crate::println!("Process Test: ✗ Thread stack at 0x{:016x} outside the stack area", probe);
+    }
+    yield_now();
+    reap_exited();
+    
+    // Mapped stack, unmapped guard below, and the address bit exceptions.s tests
+    let (free_before, _) = frame_allocator_stats();
+    let Some(stack) = KernelStack::allocate() else {
+        crate::println!("Process Test: ✗ Could not allocate a kernel stack");
+        return;
+    };
+    let top = stack.top() as u64;
+    let base = top - KERNEL_STACK_SIZE as u64;
+    let in_stack = |addr: u64| (addr >> KERNEL_STACK_SHIFT) & 1 == 1;
+    let layout = stack_bounds(base) == Some((base, top))
+        && MemoryManagementUnit::translate(base).is_some() && MemoryManagementUnit::translate(top - 8).is_some()
+        && MemoryManagementUnit::translate(base - 8).is_none()
+        && is_stack_guard(base - 8) && !is_stack_guard(base)
+        && in_stack(base) && in_stack(top - 8) && !in_stack(base - 8);
+    if layout {
+        crate::println!("Process Test: ✓ Stack 0x{:016x}-0x{:016x} mapped, guard below unmapped", base, top);
+    } else {
+        crate::println!("Process Test: ✗ Stack layout wrong at 0x{:016x}", base);
+    }
+    
+    // A stray access into the guard is reported rather than corrupting memory
+    let hit = crate::oops::guard("stack-guard-test", || unsafe { core::ptr::read_volatile((base - 8) as *const u64) });
+    drop(stack);
+    let (free_after, _) = frame_allocator_stats();
+    if hit.is_err() && free_after == free_before {
+        crate::println!("Process Test: ✓ Guard hit caught, stack frames returned");
+    } else {
+        crate::println!("Process Test: ✗ Guard read gave {:?}, free frames {} -> {}", hit, free_before, free_after);
+    }
+    
+    crate::println!("Process Test: Stack guard test completed");
+}
+
+#[kernel_test]
+pub fn test_kthread_spawn() {
+    crate::println!("Process Test: Testing kernel thread spawn...");
+    
+    let threads_before = thread_count();
+    TEST_COUNTER.store(0, Ordering::SeqCst);
+    
+    if let Err(e) = kthread_spawn(counter_thread, "ktest", KTHREAD_DEFAULT_PRIORITY) {
+        crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+        return;
+    }
+    
+    // Yield until the thread has run to completion (bounded)
+    for _ in 0..100 {
+        if TEST_COUNTER.load(Ordering::SeqCst) == 3 {
+            break;
+        }
+        yield_now();
+    }
+    
+    if TEST_COUNTER.load(Ordering::SeqCst) == 3 {
+        crate::println!("Process Test: ✓ Kernel thread ran and interleaved with kmain");
+    } else {
+        crate::println!("Process Test: ✗ Kernel thread did not run to completion");
+    }
+    
+    // One more switch lets the exited thread be switched out for good
+    yield_now();
+    reap_exited();
+    if thread_count() == threads_before {
+        crate::println!("Process Test: ✓ Exited thread reaped");
+    } else {
+        crate::println!("Process Test: ✗ Exited thread still present");
+    }
+    
+    crate::println!("Process Test: Kernel thread test completed");
+}
+
+static NAMED_HELPER: AtomicU32 = AtomicU32::new(0);
+
+fn name_helper() {
+    block_current();
+    yield_now();
+}
+
+// Started by kmain, so it stands alone; its helper is labelled under it
+fn name_service() {
+    let helper = kthread_spawn(name_helper, "worker", KTHREAD_DEFAULT_PRIORITY).unwrap_or(u32::MAX);
+    NAMED_HELPER.store(helper, Ordering::SeqCst);
+}
+
+#[kernel_test]
+pub fn test_thread_names() {
+    use crate::interrupts::ExceptionContext;
+    use crate::syscall::{EINVAL, EPERM, SYS_THREAD_SET_NAME};
+    use super::scheduler::label_of;
+    use super::thread::{ThreadName, THREAD_NAME_LEN};
+    
+    crate::println!("Process Test: Testing thread names...");
+    
+    NAMED_HELPER.store(0, Ordering::SeqCst);
+    let service = match kthread_spawn(name_service, "namesvc", KTHREAD_DEFAULT_PRIORITY) {
+        Ok(service) => service,
+        Err(e) => {
+            crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+            return;
+        }
+    };
+    for _ in 0..100 {
+        if NAMED_HELPER.load(Ordering::SeqCst) != 0 {
+            break;
+        }
+        yield_now();
+    }
+    let helper = NAMED_HELPER.load(Ordering::SeqCst);
+    let labelled = label_of(helper).is_some_and(|label| label.as_str() == "namesvc/worker");
+    let standalone = label_of(current_thread_id()).is_some_and(|label| !label.as_str().contains('/'));
+    if labelled && standalone {
+        crate::println!("Process Test: ✓ Helper of thread {} labelled namesvc/worker", service);
+    } else {
+        crate::println!("Process Test: ✗ Helper {} labelled {:?}", helper, label_of(helper));
+    }
+    
+    let rename = |tid: ThreadId, name: &str, spsr_el1| {
+        let mut ctx = ExceptionContext {
+            x0: tid as u64, x1: name.as_ptr() as u64, x2: name.len() as u64, spsr_el1, ..Default::default()
+        };
+        crate::syscall::dispatch(&mut ctx, SYS_THREAD_SET_NAME);
+        ctx.x0 as i64
+    };
+    let renamed = rename(helper, "irq-worker", 0x3c5) == 0
+        && label_of(helper).is_some_and(|label| label.as_str() == "namesvc/irq-worker");
+    let long = "x".repeat(THREAD_NAME_LEN + 1);
+    let refused = rename(helper, "a/b", 0x3c5) == EINVAL
+        && rename(helper, &long, 0x3c5) == EINVAL
+        && rename(helper, "", 0x3c5) == EINVAL
+        // From EL0, another thread's name is off limits
+        && rename(helper, "user", 0) == EPERM;
+    if renamed && refused {
+        crate::println!("Process Test: ✓ thread_set_name renamed the helper; bad names and callers refused");
+    } else {
+        crate::println!("Process Test: ✗ thread_set_name gave {:?} (renamed {}, refused {})",
+                       label_of(helper), renamed, refused);
+    }
+    
+    // Cut short on a character boundary, never inside one
+    let wide = alloc::format!("a{}", "ä".repeat(THREAD_NAME_LEN / 2));
+    if ThreadName::new(&wide).as_str().len() == THREAD_NAME_LEN - 1 {
+        crate::println!("Process Test: ✓ Long names cut at a character boundary");
+    } else {
+        crate::println!("Process Test: ✗ Long name became {:?}", ThreadName::new(&wide));
+    }
+    
+    wake(helper);
+    for _ in 0..10 {
+        yield_now();
+    }
+    reap_exited();
+    crate::println!("Process Test: Thread name test completed");
+}
+
+static BLOCK_STAGE: AtomicU32 = AtomicU32::new(0);
+
+fn blocking_thread() {
+    BLOCK_STAGE.store(1, Ordering::SeqCst);
+    block_current();
+    yield_now();
+    BLOCK_STAGE.store(2, Ordering::SeqCst);
+}
+
+// Yield until `stage` is reached (bounded)
+fn wait_for_stage(stage: u32) -> bool {
+    for _ in 0..100 {
+        if BLOCK_STAGE.load(Ordering::SeqCst) == stage {
+            return true;
+        }
+        yield_now();
+    }
+    false
+}
+
+#[kernel_test]
+pub fn test_block_wake() {
+    crate::println!("Process Test: Testing thread blocking and wakeup...");
+    
+    BLOCK_STAGE.store(0, Ordering::SeqCst);
+    let id = match kthread_spawn(blocking_thread, "kblock", KTHREAD_DEFAULT_PRIORITY) {
+        Ok(id) => id,
+        Err(e) => {
+            crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+            return;
+        }
+    };
+    
+    // Blocked: it must not run again however often we yield
+    let blocked = wait_for_stage(1) && {
+        for _ in 0..10 {
+            yield_now();
+        }
+        BLOCK_STAGE.load(Ordering::SeqCst) == 1
+    };
+    if blocked {
+        crate::println!("Process Test: ✓ Blocked thread stays off the CPU");
+    } else {
+        crate::println!("Process Test: ✗ Blocked thread kept running");
+    }
+    
+    wake(id);
+    if wait_for_stage(2) {
+        crate::println!("Process Test: ✓ Woken thread resumed");
+    } else {
+        crate::println!("Process Test: ✗ Woken thread never resumed");
+    }
+    
+    yield_now();
+    reap_exited();
+    crate::println!("Process Test: Blocking test completed");
+}
+
+static OOM_READY: AtomicU32 = AtomicU32::new(0);
+static OOM_RELEASE: AtomicBool = AtomicBool::new(false);
+
+// Charge `frames` to the calling thread, then wait to be released or killed
+fn oom_hog(frames: usize) {
+    if alloc_pages(frames).is_ok() {
+        OOM_READY.fetch_add(1, Ordering::SeqCst);
+    }
+    while !OOM_RELEASE.load(Ordering::SeqCst) {
+        yield_now();
+    }
+}
+
+fn oom_big_hog() {
+    oom_hog(64);
+}
+
+fn oom_small_hog() {
+    oom_hog(16);
+}
+
+#[kernel_test]
+pub fn test_oom_killer() {
+    crate::println!("Process Test: Testing OOM victim selection...");
+    
+    let (free_before, _) = frame_allocator_stats();
+    OOM_READY.store(0, Ordering::SeqCst);
+    OOM_RELEASE.store(false, Ordering::SeqCst);
+    
+    let big = kthread_spawn(oom_big_hog, "oom-big", KTHREAD_DEFAULT_PRIORITY);
+    let small = kthread_spawn(oom_small_hog, "oom-small", KTHREAD_DEFAULT_PRIORITY);
+    let (big, small) = match (big, small) {
+        (Ok(big), Ok(small)) => (big, small),
+        _ => {
+            crate::println!("Process Test: ✗ Could not spawn OOM test threads");
+            OOM_RELEASE.store(true, Ordering::SeqCst);
+            return;
+        }
+    };
+    for _ in 0..100 {
+        if OOM_READY.load(Ordering::SeqCst) == 2 {
+            break;
+        }
+        yield_now();
+    }
+    
+    // The biggest consumer goes first unless the process manager protects it
+    let unprotected = oom::select_victim().map(|victim| victim.id);
+    let protected = oom::set_policy(big, 0, true).is_ok();
+    let victim = oom::select_victim().map(|victim| victim.id);
+    if unprotected == Some(big) && protected && victim == Some(small) {
+        crate::println!("Process Test: ✓ Badness ranks by RSS and honours protection");
+    } else {
+        crate::println!("Process Test: ✗ Wrong OOM victim ({:?} then {:?})", unprotected, victim);
+    }
+    
+    // A forced OOM kills the small hog and returns its memory
+    let kills_before = oom::kill_count();
+    let killed = oom::out_of_memory(0);
+    let alive = oom::candidates().iter().any(|candidate| candidate.id == small);
+    if killed == Ok(small) && !alive && oom::kill_count() == kills_before + 1 {
+        crate::println!("Process Test: ✓ OOM killer killed the selected thread");
+    } else {
+        crate::println!("Process Test: ✗ OOM kill failed: {:?}", killed);
+    }
+    
+    OOM_RELEASE.store(true, Ordering::SeqCst);
+    for _ in 0..10 {
+        yield_now();
+    }
+    reap_exited();
+    let (free_after, _) = frame_allocator_stats();
+    if free_after == free_before {
+        crate::println!("Process Test: ✓ Killed and exited threads released their frames");
+    } else {
+        crate::println!("Process Test: ✗ Leaked {} frames", free_before - free_after);
+    }
+    
+    crate::println!("Process Test: OOM killer test completed");
+}
+
+#[kernel_test]
+fn test_charged_pages() {
+    crate::println!("Process Test: Testing charged page allocation...");
+    
+    let (free_before, _) = frame_allocator_stats();
+    let base = match alloc_pages(4) {
+        Ok(base) => base,
+        Err(e) => {
+            crate::println!("Process Test: ✗ alloc_pages failed: {}", e);
+            return;
+        }
+    };
+    let (free_during, _) = frame_allocator_stats();
+    let freed = free_pages(base);
+    let (free_after, _) = frame_allocator_stats();
+    if free_during + 4 <= free_before && freed.is_ok() && free_after == free_before {
+        crate::println!("Process Test: ✓ Freed pages went back to the allocator");
+    } else {
+        crate::println!("Process Test: ✗ Charged pages not returned ({} -> {} -> {})",
+                       free_before, free_during, free_after);
+    }
+    
+    if free_pages(base).is_err() {
+        crate::println!("Process Test: ✓ Second free refused");
+    } else {
+        crate::println!("Process Test: ✗ Pages freed twice");
+    }
+}
+
+// Stand-in service: runs until killed
+fn service_thread() {
+    loop {
+        yield_now();
+    }
+}
+
+// Kill a supervised thread and return the exit event it produced
+fn kill_service(tid: ThreadId) -> Option<ExitEvent> {
+    let mut events = [ExitEvent::default(); EXIT_RING_SIZE];
+    let count = supervisor::read_events(0, &mut events);
+    let since = events[..count].last().map_or(0, |event| event.seq + 1);
+    super::kill(tid).ok()?;
+    supervisor::read_events(since, &mut events[..1]);
+    Some(events[0]).filter(|event| event.tid == tid)
+}
+
+#[kernel_test]
+pub fn test_service_restart() {
+    crate::println!("Process Test: Testing supervised service restart...");
+    
+    let policy = RestartPolicy {
+        mode: RestartMode::OnFailure as u32,
+        max_restarts: 3,
+        base_delay_ms: 20,
+        max_delay_ms: 1000,
+        stable_ms: 60_000,
+    };
+    let capability = Capability::Port(0x7E57);
+    let first = match kthread_spawn(service_thread, "ksvc", KTHREAD_DEFAULT_PRIORITY) {
+        Ok(id) => id,
+        Err(e) => {
+            crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+            return;
+        }
+    };
+    let me = current_thread_id();
+    if supervisor::supervise(first, policy).is_err() || capability::grant(me, first, capability).is_err() {
+        crate::println!("Process Test: ✗ Could not set up the supervised thread");
+        let _ = super::kill(first);
+        return;
+    }
+    
+    let Some(exit) = kill_service(first) else {
+        crate::println!("Process Test: ✗ No exit event for the killed service");
+        return;
+    };
+    if exit.reason == ExitReason::Killed as u32 && exit.attempt == 1 && exit.restart_at > exit.timestamp {
+        crate::println!("Process Test: ✓ Exit recorded as killed, restart scheduled");
+    } else {
+        crate::println!("Process Test: ✗ Exit event reason {} attempt {}", exit.reason, exit.attempt);
+    }
+    
+    let second = match kthread_spawn(service_thread, "ksvc", KTHREAD_DEFAULT_PRIORITY) {
+        Ok(id) => id,
+        Err(e) => {
+            crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+            supervisor::unsupervise(first);
+            return;
+        }
+    };
+    let early = supervisor::respawned(first, second);
+    while counter_ticks() < exit.restart_at {
+        yield_now();
+    }
+    let on_time = supervisor::respawned(first, second);
+    if early == Err(supervisor::BACKOFF_PENDING) && on_time.is_ok() {
+        crate::println!("Process Test: ✓ Respawn refused during backoff, accepted after");
+    } else {
+        crate::println!("Process Test: ✗ Respawn early {:?}, on time {:?}", early, on_time);
+    }
+    if capability::held(second).contains(&capability) {
+        crate::println!("Process Test: ✓ Capabilities re-granted to the replacement");
+    } else {
+        crate::println!("Process Test: ✗ Replacement is missing the capability");
+    }
+    
+    // A second crash without a stable run doubles the delay
+    match kill_service(second) {
+        Some(again) if again.attempt == 2
+            && again.restart_at - again.timestamp > exit.restart_at - exit.timestamp => {
+            crate::println!("Process Test: ✓ Backoff doubled on the second failure");
+        }
+        _ => crate::println!("Process Test: ✗ Backoff did not grow on the second failure"),
+    }
+    
+    supervisor::unsupervise(second);
+    yield_now();
+    reap_exited();
+    crate::println!("Process Test: Service restart test completed");
+}
+
+#[kernel_test]
+pub fn test_capability_manifest() {
+    use crate::manifest::{self, ManifestError};
+    
+    crate::println!("Process Test: Testing the capability manifest...");
+    
+    const TEXT: &str = "
+# test wiring
+service mf-net   # network driver
+    port mf-rx
+    irq 0x30
+    mmio 0x9000000 0x1000
+
+service mf-log
+    port mf-rx
+    sysctl
+";
+    let parsed = manifest::parse(TEXT);
+    let embedded = manifest::parse(manifest::EMBEDDED).is_ok();
+    let shape = parsed.as_ref().is_ok_and(|m| m.services.len() == 2 && m.ports.len() == 1
+        && m.services[0].grants.len() == 3 && m.services[1].grants.len() == 2);
+    if shape && embedded {
+        crate::println!("Process Test: ✓ Manifest parsed, one port shared by two services");
+    } else {
+        crate::println!("Process Test: ✗ Manifest parsed wrong (embedded ok {}): {:?}", embedded, parsed);
+    }
+    
+    let error = |text, line, error| manifest::parse(text).err() == Some(ManifestError { line, error });
+    let rejected = error("irq 5\n", 1, "Capability outside a service")
+        && error("service a\n  mmio 0x1001 0x1000\n", 2, "MMIO window not page aligned")
+        && error("service a\nservice a\n", 2, "Service listed twice")
+        && error("service a\n\n  frob\n", 3, "Unknown directive")
+        && error("service a\n  irq\n", 2, "Wrong number of arguments");
+    if rejected {
+        crate::println!("Process Test: ✓ Bad manifests rejected with their line");
+    } else {
+        crate::println!("Process Test: ✗ A bad manifest was accepted or misreported");
+    }
+    
+    let Ok(mut wiring) = parsed else { return };
+    wiring.create_ports();
+    let me = current_thread_id();
+    let port = wiring.port("mf-rx");
+    let granted = wiring.apply(me, "mf-net");
+    let unlisted = wiring.apply(me, "mf-none");
+    let held = capability::held(me);
+    let expected = [
+        port.map(Capability::Port),
+        Some(Capability::Irq(0x30)),
+        Some(Capability::Mmio { base: 0x900_0000, size: 0x1000 }),
+    ];
+    let wired = expected.iter().all(|capability| capability.is_some_and(|c| held.contains(&c)));
+    if granted == Ok(3) && unlisted == Ok(0) && wired && !held.contains(&Capability::Sysctl) {
+        crate::println!("Process Test: ✓ Listed service granted exactly its capabilities");
+    } else {
+        crate::println!("Process Test: ✗ Manifest grants wrong ({:?}, {:?}, {:?})", granted, unlisted, held);
+    }
+    
+    for capability in expected.into_iter().flatten() {
+        let _ = capability::revoke(me, me, capability);
+    }
+    for named in &wiring.ports {
+        let _ = crate::ipc::destroy_port(named.id);
+    }
+    crate::println!("Process Test: Capability manifest test completed");
+}
+
+#[kernel_test]
+pub fn test_anonymous_mapping() {
+    use crate::memory::paging::{PageFlags, VirtualMemoryManager};
+    use super::thread::{AddressSpace, USER_MMAP_BASE};
+    
+    crate::println!("Process Test: Testing anonymous user mappings...");
+    
+    let (free_before, _) = frame_allocator_stats();
+    let Some(vmm) = VirtualMemoryManager::new_user() else {
+        crate::println!("Process Test: ✗ Could not allocate address space");
+        return;
+    };
+    let mut space = AddressSpace::new(vmm);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER | PageFlags::UXN;
+    
+    let first = space.map_anonymous(3, flags);
+    let second = space.map_anonymous(1, flags);
+    match (first, second) {
+        (Ok(first), Ok(second)) if first == USER_MMAP_BASE && second == first + 3 * 4096 => {
+            let mapped = (0..4).all(|page| space.vmm().translate(first + page * 4096).is_some());
+            if mapped {
+                crate::println!("Process Test: ✓ Anonymous mappings placed back to back");
+            } else {
+                crate::println!("Process Test: ✗ Anonymous pages missing from the tables");
+            }
+            
+            // Page tables stay until the address space goes
+            let (free_mapped, _) = frame_allocator_stats();
+            let unmapped = space.unmap(first, 3).is_ok() && space.vmm().translate(first).is_none();
+            let (free_unmapped, _) = frame_allocator_stats();
+            if unmapped && free_unmapped == free_mapped + 3 {
+                crate::println!("Process Test: ✓ Unmap returned the frames");
+            } else {
+                crate::println!("Process Test: ✗ Unmap kept {} frames", free_mapped + 3 - free_unmapped);
+            }
+        }
+        (first, second) => crate::println!("Process Test: ✗ map_anonymous gave {:?}, {:?}", first, second),
+    }
+    
+    drop(space);
+    let (free_after, _) = frame_allocator_stats();
+    if free_after == free_before {
+        crate::println!("Process Test: ✓ Address space teardown freed everything");
+    } else {
+        crate::println!("Process Test: ✗ {} frames leaked", free_before.abs_diff(free_after));
+    }
+    
+    crate::println!("Process Test: Anonymous mapping test completed");
+}
+
+#[kernel_test]
+pub fn test_shared_memory() {
+    use crate::memory::paging::{PageFlags, VirtualMemoryManager};
+    use crate::shm;
+    use super::capability::{self, Capability};
+    use super::scheduler::current_thread_id;
+    use super::thread::AddressSpace;
+    
+    crate::println!("Process Test: Testing shared memory objects...");
+    
+    let (free_before, _) = frame_allocator_stats();
+    let me = current_thread_id();
+    let id = match shm::create(me, Some("test-shm"), 2) {
+        Ok(id) => id,
+        Err(e) => {
+            crate::println!("Process Test: ✗ shm create failed: {}", e);
+            return;
+        }
+    };
+    if shm::access(me, id) == Some(true) && shm::open(me, "test-shm") == Ok(id)
+        && shm::create(me, Some("test-shm"), 1).is_err()
+    {
+        crate::println!("Process Test: ✓ Creator holds a writable capability; name resolves");
+    } else {
+        crate::println!("Process Test: ✗ Creator access or name lookup wrong");
+    }
+    
+    let (Some(writer_vmm), Some(reader_vmm)) = (VirtualMemoryManager::new_user(), VirtualMemoryManager::new_user()) else {
+        crate::println!("Process Test: ✗ Could not allocate address spaces");
+        let _ = shm::destroy(me, id, false);
+        return;
+    };
+    let mut writer = AddressSpace::new(writer_vmm);
+    let mut reader = AddressSpace::new(reader_vmm);
+    match (shm::map(&mut writer, id, true), shm::map(&mut reader, id, false)) {
+        (Ok(w), Ok(r)) => {
+            let same = (0..2).all(|page| {
+                let offset = page * 4096;
+                writer.vmm().translate(w + offset).is_some()
+                    && writer.vmm().translate(w + offset) == reader.vmm().translate(r + offset)
+            });
+            let read_only = |space: &mut AddressSpace, addr| {
+                space.vmm().leaf_entry(addr).is_some_and(|entry| entry.flags().contains(PageFlags::READ_ONLY))
+            };
+            if same && !read_only(&mut writer, w) && read_only(&mut reader, r) {
+                crate::println!("Process Test: ✓ Both address spaces map the same frames, with their own permissions");
+            } else {
+                crate::println!("Process Test: ✗ Mappings differ in frames or permissions");
+            }
+            
+            let mappings = shm::list().iter().find(|info| info.id == id).map(|info| info.mappings);
+            let destroyed = shm::destroy(me, id, false).is_ok();
+            let kept = writer.vmm().translate(w).is_some() && shm::map(&mut writer, id, true).is_err();
+            if mappings == Some(2) && destroyed && kept {
+                crate::println!("Process Test: ✓ Destroy unnamed the object, mappings kept its pages");
+            } else {
+                crate::println!("Process Test: ✗ {:?} mappings counted; destroy {}", mappings, destroyed);
+            }
+            let _ = writer.unmap(w, 2);
+            let _ = reader.unmap(r, 2);
+        }
+        (w, r) => {
+            crate::println!("Process Test: ✗ shm map gave {:?}, {:?}", w, r);
+            let _ = shm::destroy(me, id, false);
+        }
+    }
+    
+    let _ = capability::revoke(me, me, Capability::Shm { id, writable: true });
+    drop(writer);
+    drop(reader);
+    let (free_after, _) = frame_allocator_stats();
+    if free_after == free_before {
+        crate::println!("Process Test: ✓ Last unmap freed the object's pages");
+    } else {
+        crate::println!("Process Test: ✗ {} frames leaked", free_before.abs_diff(free_after));
+    }
+    
+    crate::println!("Process Test: Shared memory test completed");
+}
+
+#[kernel_test]
+pub fn test_page_grant() {
+    use crate::ipc::{self, GrantMode, Message, PageGrant};
+    use crate::memory::paging::{phys_to_virt, PageFlags, VirtualMemoryManager};
+    use super::thread::AddressSpace;
+    
+    crate::println!("Process Test: Testing IPC page grants...");
+    
+    let (free_before, _) = frame_allocator_stats();
+    let (Some(sender_vmm), Some(receiver_vmm)) = (VirtualMemoryManager::new_user(), VirtualMemoryManager::new_user()) else {
+        crate::println!("Process Test: ✗ Could not allocate address spaces");
+        return;
+    };
+    let mut sender = AddressSpace::new(sender_vmm);
+    let mut receiver = AddressSpace::new(receiver_vmm);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER | PageFlags::UXN;
+    let Ok(base) = sender.map_anonymous(2, flags) else {
+        crate::println!("Process Test: ✗ Could not map sender pages");
+        return;
+    };
+    // Written through the kernel's view of the frames
+    let byte_at = |space: &mut AddressSpace, addr| {
+        space.vmm().translate(addr).map(|phys| phys_to_virt(phys) as *mut u8)
+    };
+    if let Some(ptr) = byte_at(&mut sender, base + 4096) {
+        unsafe { ptr.write(0x5A) };
+    }
+    
+    let moved = PageGrant::take(&mut sender, base, 2, GrantMode::Move).and_then(|grant| {
+        let mode = grant.mode();
+        grant.map_into(&mut receiver).map(|addr| (addr, mode))
+    });
+    let Ok((received, GrantMode::Move)) = moved else {
+        crate::println!("Process Test: ✗ Move grant failed: {:?}", moved);
+        return;
+    };
+    let arrived = byte_at(&mut receiver, received + 4096).map(|ptr| unsafe { ptr.read() });
+    if sender.vmm().translate(base).is_none() && arrived == Some(0x5A) {
+        crate::println!("Process Test: ✓ Moved pages left the sender and arrived with their data");
+    } else {
+        crate::println!("Process Test: ✗ Move left the sender mapped or lost data ({:?})", arrived);
+    }
+    
+    // Share back read-only; a shared page can then no longer be moved
+    let shared = PageGrant::take(&mut receiver, received, 1, GrantMode::Share)
+        .and_then(|grant| grant.map_into(&mut sender));
+    let read_only = shared.is_ok_and(|addr| {
+        sender.vmm().leaf_entry(addr).is_some_and(|entry| entry.flags().contains(PageFlags::READ_ONLY))
+            && sender.vmm().translate(addr) == receiver.vmm().translate(received)
+    });
+    let exclusive = PageGrant::take(&mut receiver, received, 1, GrantMode::Move).is_err();
+    if read_only && exclusive {
+        crate::println!("Process Test: ✓ Shared grant is read-only; shared pages cannot be moved");
+    } else {
+        crate::println!("Process Test: ✗ Share gave {:?}, moving a shared page {}", shared,
+                       if exclusive { "failed" } else { "succeeded" });
+    }
+    
+    // A grant that cannot be sent goes back to where it came from
+    let port = ipc::lookup_port(ipc::create_port(0));
+    let message = |grant| Message { sender: 0, data: alloc::vec::Vec::new(), trace_id: 0, grant, reply: None };
+    let restored = port.as_ref().is_some_and(|port| {
+        let _ = port.send_message(message(None));
+        let Ok(grant) = PageGrant::take(&mut receiver, received + 4096, 1, GrantMode::Move) else {
+            return false;
+        };
+        match port.send_or_return(message(Some(grant))) {
+            Err(mut returned) => returned.grant.take().is_some_and(|grant| grant.restore(&mut receiver, received + 4096).is_ok()),
+            Ok(()) => false,
+        }
+    });
+    if restored && byte_at(&mut receiver, received + 4096).map(|ptr| unsafe { ptr.read() }) == Some(0x5A) {
+        crate::println!("Process Test: ✓ Undeliverable grant restored to the sender");
+    } else {
+        crate::println!("Process Test: ✗ Undeliverable grant lost");
+    }
+    if let Some(port) = port {
+        let _ = port.receive_message();
+        let _ = ipc::destroy_port(port.id());
+    }
+    
+    drop(sender);
+    drop(receiver);
+    let (free_after, _) = frame_allocator_stats();
+    if free_after == free_before {
+        crate::println!("Process Test: ✓ Granted pages freed with their last mapping");
+    } else {
+        crate::println!("Process Test: ✗ {} frames leaked", free_before.abs_diff(free_after));
+    }
+    
+    crate::println!("Process Test: Page grant test completed");
+}
+
+// One PT_LOAD program header: (flags, offset, vaddr, filesz, memsz, align)
+type TestSegment = (u32, u64, u64, u64, u64, u64);
+
+// A minimal AArch64 ELF64 executable: headers, then `body` at offset 0x1000
+fn build_test_elf(kind: u16, entry: u64, segments: &[TestSegment], body: &[u8]) -> alloc::vec::Vec<u8> {
+    let mut image = alloc::vec![0u8; 0x1000];
+    image[..4].copy_from_slice(b"\x7fELF");
+    image[4..7].copy_from_slice(&[2, 1, 1]);
+    image[16..18].copy_from_slice(&kind.to_le_bytes());
+    image[18..20].copy_from_slice(&elf_parser::EM_AARCH64.to_le_bytes());
+    image[20..24].copy_from_slice(&1u32.to_le_bytes());
+    image[24..32].copy_from_slice(&entry.to_le_bytes());
+    image[32..40].copy_from_slice(&64u64.to_le_bytes());
+    image[52..54].copy_from_slice(&64u16.to_le_bytes());
+    image[54..56].copy_from_slice(&56u16.to_le_bytes());
+    image[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+    for (i, &(flags, offset, vaddr, filesz, memsz, align)) in segments.iter().enumerate() {
+        let header = &mut image[64 + i * 56..][..56];
+        header[0..4].copy_from_slice(&elf_parser::PT_LOAD.to_le_bytes());
+        header[4..8].copy_from_slice(&flags.to_le_bytes());
+        for (field, value) in [offset, vaddr, vaddr, filesz, memsz, align].into_iter().enumerate() {
+            header[8 + field * 8..][..8].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    image.extend_from_slice(body);
+    image
+}
+
+#[kernel_test]
+pub fn test_elf_loader() {
+    use elf_parser::{ET_DYN, ET_EXEC, PF_R, PF_W, PF_X};
+    use crate::memory::paging::{phys_to_virt, VirtualMemoryManager};
+    use super::elf::{load, LoadError, PIE_LOAD_BIAS};
+    use super::thread::AddressSpace;
+    
+    crate::println!("Process Test: Testing ELF loader validation...");
+    
+    const TEXT: u64 = 0x40_0000;
+    const DATA: u64 = 0x41_0000;
+    let body: alloc::vec::Vec<u8> = (0..0x1800u32).map(|i| i as u8).collect();
+    let text = (PF_R | PF_X, 0x1000, TEXT, 0x1000, 0x1000, 0x1000);
+    let data = (PF_R | PF_W, 0x2000, DATA, 0x800, 0x3000, 0x1000);
+    
+    let (free_before, _) = frame_allocator_stats();
+    let Some(vmm) = VirtualMemoryManager::new_user() else {
+        crate::println!("Process Test: ✗ Could not allocate address space");
+        return;
+    };
+    let mut space = AddressSpace::new(vmm);
+    
+    let image = build_test_elf(ET_EXEC, TEXT + 0x10, &[text, data], &body);
+    match load(&mut space, &image) {
+        Ok(loaded) if loaded.entry == TEXT + 0x10 && loaded.start == TEXT && loaded.end == DATA + 0x3000 => {
+            let byte_at = |space: &mut AddressSpace, virt: u64| {
+                space.vmm().translate(virt).map(|phys| unsafe { *(phys_to_virt(phys) as *const u8) })
+            };
+            let copied = byte_at(&mut space, TEXT + 5) == Some(5) && byte_at(&mut space, DATA + 0x7FF) == Some(0xFF);
+            let zeroed = byte_at(&mut space, DATA + 0x800) == Some(0) && byte_at(&mut space, DATA + 0x2FFF) == Some(0);
+            if copied && zeroed {
+                crate::println!("Process Test: ✓ Segments loaded with file data and zeroed bss");
+            } else {
+                crate::println!("Process Test: ✗ Loaded segment contents wrong");
+            }
+        }
+        other => crate::println!("Process Test: ✗ Valid image gave {:?}", other),
+    }
+    drop(space);
+    
+    let pie = build_test_elf(ET_DYN, 0x1010, &[(PF_R | PF_X, 0x1000, 0x1000, 0x1000, 0x1000, 0x1000)], &body);
+    let Some(vmm) = VirtualMemoryManager::new_user() else {
+        crate::println!("Process Test: ✗ Could not allocate address space");
+        return;
+    };
+    let mut space = AddressSpace::new(vmm);
+    match load(&mut space, &pie) {
+        Ok(loaded) if loaded.bias == PIE_LOAD_BIAS && loaded.entry == PIE_LOAD_BIAS + 0x1010 => {
+            crate::println!("Process Test: ✓ Position-independent image relocated");
+        }
+        other => crate::println!("Process Test: ✗ PIE image gave {:?}", other),
+    }
+    drop(space);
+    
+    // Each malformed layout must be refused before anything is mapped
+    const KERNEL_HALF: u64 = 0xFFFF_0000_0000_0000;
+    let rejected: [(&str, alloc::vec::Vec<u8>, LoadError); 8] = [
+        ("overlapping segments",
+         build_test_elf(ET_EXEC, TEXT, &[text, (PF_R | PF_W, 0x2800, TEXT + 0x800, 0, 0x800, 0x1000)], &body),
+         LoadError::SegmentOverlap),
+        ("kernel-range segment",
+         build_test_elf(ET_EXEC, TEXT, &[text, (PF_R | PF_W, 0x2000, KERNEL_HALF, 0x800, 0x1000, 0x1000)], &body),
+         LoadError::OutsideUserRange),
+        ("null-page segment",
+         build_test_elf(ET_EXEC, 0x10, &[(PF_R | PF_X, 0x1000, 0, 0x1000, 0x1000, 0x1000)], &body),
+         LoadError::OutsideUserRange),
+        ("sub-page alignment",
+         build_test_elf(ET_EXEC, TEXT, &[(PF_R | PF_X, 0x1000, TEXT, 0x1000, 0x1000, 0x10)], &body),
+         LoadError::BadAlignment),
+        ("writable and executable segment",
+         build_test_elf(ET_EXEC, TEXT, &[(PF_R | PF_W | PF_X, 0x1000, TEXT, 0x1000, 0x1000, 0x1000)], &body),
+         LoadError::WritableExecutable),
+        ("oversized segment",
+         build_test_elf(ET_EXEC, TEXT, &[text, (PF_R | PF_W, 0x2000, DATA, 0x800, 1 << 30, 0x1000)], &body),
+         LoadError::SegmentTooLarge),
+        ("entry outside text",
+         build_test_elf(ET_EXEC, DATA, &[text, data], &body),
+         LoadError::BadEntry),
+        ("truncated segment",
+         build_test_elf(ET_EXEC, TEXT, &[(PF_R | PF_X, 0x1000, TEXT, 0x10000, 0x10000, 0x1000)], &body),
+         LoadError::Malformed(elf_parser::ElfError::SegmentOutOfFile)),
+    ];
+    let Some(vmm) = VirtualMemoryManager::new_user() else {
+        crate::println!("Process Test: ✗ Could not allocate address space");
+        return;
+    };
+    let mut space = AddressSpace::new(vmm);
+    let (free_empty, _) = frame_allocator_stats();
+    let mut failures = 0;
+    for (what, image, expected) in &rejected {
+        match load(&mut space, image) {
+            Err(e) if e == *expected => {}
+            other => {
+                crate::println!("Process Test: ✗ {} gave {:?}", what, other);
+                failures += 1;
+            }
+        }
+    }
+    let (free_rejected, _) = frame_allocator_stats();
+    if failures == 0 && free_rejected == free_empty {
+        crate::println!("Process Test: ✓ {} malformed layouts rejected with nothing mapped", rejected.len());
+    } else if free_rejected != free_empty {
+        crate::println!("Process Test: ✗ Rejected images left {} frames mapped", free_empty.abs_diff(free_rejected));
+    }
+    drop(space);
+    
+    let (free_after, _) = frame_allocator_stats();
+    if free_after == free_before {
+        crate::println!("Process Test: ✓ Loaded images freed with their address space");
+    } else {
+        crate::println!("Process Test: ✗ {} frames leaked", free_before.abs_diff(free_after));
+    }
+    
+    crate::println!("Process Test: ELF loader test completed");
+}
+
+static FORK_CHILD_GO: AtomicBool = AtomicBool::new(false);
+static FORK_CHILD_DONE: AtomicU32 = AtomicU32::new(0);
+
+// Waits for memory from its parent, then execs an empty image
+fn fork_child_thread() {
+    use crate::memory::paging::VirtualMemoryManager;
+    use super::scheduler::with_thread;
+    use super::thread::AddressSpace;
+    
+    let me = current_thread_id();
+    for _ in 0..200 {
+        let has_space = with_thread(me, |thread| thread.address_space().is_some()) == Some(true);
+        if has_space && FORK_CHILD_GO.load(Ordering::SeqCst) {
+            if let Some(vmm) = VirtualMemoryManager::new_user() {
+                super::fork::exec(AddressSpace::new(vmm));
+            }
+            FORK_CHILD_DONE.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        yield_now();
+    }
+}
+
+// First byte of the page mapped at `virt`
+fn user_byte(space: &mut super::thread::AddressSpace, virt: u64) -> Option<u8> {
+    use crate::memory::paging::phys_to_virt;
+    space.vmm().translate(virt).map(|phys| unsafe { *(phys_to_virt(phys) as *const u8) })
+}
+
+#[kernel_test]
+pub fn test_user_program() {
+    use elf_parser::{ET_EXEC, PF_R, PF_X};
+    use super::program;
+    
+    crate::println!("Process Test: Testing user program start...");
+    
+    // ldr x0, [sp]; svc #SYS_EXIT: exits with argc from the initial stack
+    const TEXT: u64 = 0x40_0000;
+    let mut body = alloc::vec![0u8; 0x1000];
+    body[0..4].copy_from_slice(&0xF940_03E0u32.to_le_bytes());
+    body[4..8].copy_from_slice(&(0xD400_0001u32 | (crate::syscall::SYS_EXIT as u32) << 5).to_le_bytes());
+    let image = build_test_elf(ET_EXEC, TEXT, &[(PF_R | PF_X, 0x1000, TEXT, 0x1000, 0x1000, 0x1000)], &body);
+    
+    let mut events = [ExitEvent::default(); EXIT_RING_SIZE];
+    let count = supervisor::read_events(0, &mut events);
+    let since = events[..count].last().map_or(0, |event| event.seq + 1);
+    
+    // Below this thread's priority, so it cannot exit before it is supervised
+    let args = ["argc", "two", "three"];
+    let tid = match program::spawn("uprog", &image, &args, 1, true) {
+        Ok(tid) => tid,
+        Err(e) => {
+            crate::println!("Process Test: ✗ Program start failed: {}", e);
+            return;
+        }
+    };
+    let policy = RestartPolicy {
+        mode: RestartMode::Never as u32,
+        max_restarts: 0,
+        base_delay_ms: 0,
+        max_delay_ms: 0,
+        stable_ms: 0,
+    };
+    if supervisor::supervise(tid, policy).is_err() {
+        crate::println!("Process Test: ✗ Could not supervise the program");
+        let _ = super::kill(tid);
+        return;
+    }
+    
+    let mut exit = None;
+    for _ in 0..100 {
+        if supervisor::read_events(since, &mut events[..1]) == 1 && events[0].tid == tid {
+            exit = Some(events[0]);
+            break;
+        }
+        let _ = crate::timer::sleep_ns(1_000_000);
+    }
+    match exit {
+        Some(event) if event.reason == ExitReason::Exited as u32 && event.status == args.len() as u32 => {
+            crate::println!("Process Test: ✓ Program ran at EL0 and exited with its argc");
+        }
+        Some(event) => crate::println!("Process Test: ✗ Program exit reason {} status {}", event.reason, event.status),
+        None => {
+            crate::println!("Process Test: ✗ Program did not exit");
+            let _ = super::kill(tid);
+        }
+    }
+    supervisor::unsupervise(tid);
+    
+    match program::spawn("uprog", &image[..0x40], &args, 1, true) {
+        Err(_) => crate::println!("Process Test: ✓ Truncated image refused"),
+        Ok(tid) => {
+            crate::println!("Process Test: ✗ Truncated image started as thread {}", tid);
+            let _ = super::kill(tid);
+        }
+    }
+    reap_exited();
+}
+
+#[kernel_test]
+pub fn test_user_aslr() {
+    use elf_parser::{ET_DYN, PF_R, PF_X};
+    use crate::memory::paging::{PageFlags, VirtualMemoryManager, BLOCK_SIZE_2M};
+    use super::aslr::Layout;
+    use super::elf::{load_at, PIE_LOAD_BIAS};
+    use super::thread::{AddressSpace, USER_MMAP_BASE, USER_MMAP_END};
+    
+    crate::println!("Process Test: Testing user address space randomization...");
+    
+    let layouts: [Layout; 4] = core::array::from_fn(|_| Layout::random());
+    let in_bounds = layouts.iter().all(|layout| {
+        layout.pie_bias >= PIE_LOAD_BIAS && layout.pie_bias.is_multiple_of(BLOCK_SIZE_2M)
+            && layout.mmap_base >= USER_MMAP_BASE && layout.stack_top <= USER_MMAP_END
+            && layout.mmap_base < layout.stack_top && layout.randomized
+    });
+    // Any one part may repeat by chance, all three together should not
+    let distinct = layouts.windows(2).all(|pair| pair[0] != pair[1]);
+    if in_bounds && distinct {
+        crate::println!("Process Test: ✓ Random layouts differ and stay in bounds");
+    } else {
+        crate::println!("Process Test: ✗ Random layouts {:x?}", layouts);
+    }
+    let fixed = Layout::new(false);
+    if fixed == Layout::FIXED && fixed.pie_bias == PIE_LOAD_BIAS && fixed.mmap_base == USER_MMAP_BASE {
+        crate::println!("Process Test: ✓ Randomization off gives the fixed layout");
+    } else {
+        crate::println!("Process Test: ✗ Fixed layout {:x?}", fixed);
+    }
+    
+    // A PIE image follows the layout's bias; anonymous memory its base
+    let layout = layouts[0];
+    let body = alloc::vec![0u8; 0x1000];
+    let pie = build_test_elf(ET_DYN, 0x1010, &[(PF_R | PF_X, 0x1000, 0x1000, 0x1000, 0x1000, 0x1000)], &body);
+    let Some(vmm) = VirtualMemoryManager::new_user() else {
+        crate::println!("Process Test: ✗ Could not allocate address space");
+        return;
+    };
+    let mut space = AddressSpace::with_layout(vmm, &layout);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::INNER_SHAREABLE | PageFlags::ACCESSED
+        | PageFlags::USER | PageFlags::PXN | PageFlags::UXN;
+    let loaded = load_at(&mut space, &pie, layout.pie_bias);
+    let anonymous = space.map_anonymous(1, flags);
+    match (loaded, anonymous) {
+        (Ok(loaded), Ok(addr)) if loaded.entry == layout.pie_bias + 0x1010 && addr == layout.mmap_base => {
+            crate::println!("Process Test: ✓ Image and mmap area placed by the layout");
+        }
+        (loaded, anonymous) => crate::println!("Process Test: ✗ Loaded {:x?}, mapped {:x?}", loaded, anonymous),
+    }
+    match space.fork() {
+        Ok(child) if child.is_randomized() => crate::println!("Process Test: ✓ Forked child keeps the random layout"),
+        Ok(_) => crate::println!("Process Test: ✗ Forked child lost the random layout"),
+        Err(e) => crate::println!("Process Test: ✗ fork failed: {}", e),
+    }
+    drop(space);
+    
+    // The stack caps the mmap area from above
+    let Some(vmm) = VirtualMemoryManager::new_user() else {
+        crate::println!("Process Test: ✗ Could not allocate address space");
+        return;
+    };
+    let mut space = AddressSpace::new(vmm);
+    let page = crate::memory::frame_allocator::PAGE_SIZE as u64;
+    let stack = space.map_stack(USER_MMAP_BASE + 4 * page, 2, flags);
+    let crossing = space.map_anonymous(3, flags);
+    let below = space.map_anonymous(2, flags);
+    let overlapping = space.map_stack(USER_MMAP_BASE + page, 1, flags);
+    if stack == Ok(USER_MMAP_BASE + 2 * page) && crossing.is_err() && below == Ok(USER_MMAP_BASE) && overlapping.is_err() {
+        crate::println!("Process Test: ✓ Anonymous mappings stay below the stack");
+    } else {
+        crate::println!("Process Test: ✗ Stack {:x?}, crossing {:x?}, below {:x?}, overlapping {:x?}",
+                       stack, crossing, below, overlapping);
+    }
+}
+
+#[kernel_test]
+pub fn test_fork_cow() {
+    use crate::memory::paging::{phys_to_virt, PageFlags, VirtualMemoryManager};
+    use super::fork::{self, CowCounters, ForkMode, ForkStats};
+    use super::scheduler::with_thread;
+    use super::thread::AddressSpace;
+    
+    crate::println!("Process Test: Testing fork copy-on-write accounting...");
+    
+    let (free_before, _) = frame_allocator_stats();
+    let Some(vmm) = VirtualMemoryManager::new_user() else {
+        crate::println!("Process Test: ✗ Could not allocate address space");
+        return;
+    };
+    let mut space = AddressSpace::new(vmm);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER | PageFlags::UXN;
+    let base = match space.map_anonymous(4, flags) {
+        Ok(base) => base,
+        Err(e) => {
+            crate::println!("Process Test: ✗ map_anonymous failed: {}", e);
+            return;
+        }
+    };
+    for page in 0..4u64 {
+        let phys = space.vmm().translate(base + page * 4096).unwrap_or(0);
+        unsafe { *(phys_to_virt(phys) as *mut u8) = 0xA0 + page as u8 };
+    }
+    
+    let me = current_thread_id();
+    with_thread(me, |thread| thread.set_address_space(space));
+    let stats_before = fork::stats();
+    FORK_CHILD_GO.store(false, Ordering::SeqCst);
+    FORK_CHILD_DONE.store(0, Ordering::SeqCst);
+    let Ok(child) = kthread_spawn(fork_child_thread, "forkchild", KTHREAD_DEFAULT_PRIORITY) else {
+        crate::println!("Process Test: ✗ Could not spawn child");
+        return;
+    };
+    
+    // Copy: both sides share all four pages read-only
+    let forked = fork::fork(child, ForkMode::Copy);
+    let parent_cow = with_thread(me, |thread| thread.cow_counters()).flatten();
+    let child_cow = with_thread(child, |thread| thread.cow_counters()).flatten();
+    let marked = CowCounters { marked: 4, copied: 0, reused: 0 };
+    if forked.is_ok() && parent_cow == Some(marked) && child_cow == Some(marked)
+        && fork::stats().forks == stats_before.forks + 1
+    {
+        crate::println!("Process Test: ✓ Fork shared 4 pages copy-on-write");
+    } else {
+        crate::println!("Process Test: ✗ Fork gave {:?}, counters {:?} / {:?}", forked, parent_cow, child_cow);
+    }
+    
+    // The parent's write copies; the child is then the last mapper and keeps its frame
+    let parent_write = with_thread(me, |thread| {
+        thread.address_space().map(|space| {
+            let resolved = space.resolve_cow_fault(base + 0x10);
+            let phys = space.vmm().translate(base).unwrap_or(0);
+            unsafe { *(phys_to_virt(phys) as *mut u8) = 0x55 };
+            resolved
+        })
+    }).flatten();
+    let child_write = with_thread(child, |thread| {
+        thread.address_space().map(|space| (space.resolve_cow_fault(base), user_byte(space, base)))
+    }).flatten();
+    let parent_cow = with_thread(me, |thread| thread.cow_counters()).flatten();
+    let child_cow = with_thread(child, |thread| thread.cow_counters()).flatten();
+    if parent_write == Some(Ok(true)) && child_write == Some((Ok(true), Some(0xA0)))
+        && parent_cow.is_some_and(|cow| cow.copied == 1) && child_cow.is_some_and(|cow| cow.reused == 1)
+    {
+        crate::println!("Process Test: ✓ First write copied, last mapper reused its frame");
+    } else {
+        crate::println!("Process Test: ✗ Write faults gave {:?} / {:?}", parent_write, child_write);
+    }
+    let unmapped = with_thread(me, |thread| thread.address_space().map(|space| space.resolve_cow_fault(base + 0x10_0000)));
+    if unmapped == Some(Some(Ok(false))) {
+        crate::println!("Process Test: ✓ Fault outside copy-on-write pages left alone");
+    } else {
+        crate::println!("Process Test: ✗ Unmapped fault gave {:?}", unmapped);
+    }
+    
+    // The child execs: its history counts towards the borrow decision
+    FORK_CHILD_GO.store(true, Ordering::SeqCst);
+    for _ in 0..200 {
+        if FORK_CHILD_DONE.load(Ordering::SeqCst) == 1 {
+            break;
+        }
+        yield_now();
+    }
+    let stats = fork::stats();
+    if stats.execs_after_fork == stats_before.execs_after_fork + 1
+        && stats.exec_shared == stats_before.exec_shared + 4
+        && stats.exec_copied == stats_before.exec_copied
+    {
+        crate::println!("Process Test: ✓ Exec after fork recorded with no copies");
+    } else {
+        crate::println!("Process Test: ✗ Exec after fork not recorded: {:?}", stats);
+    }
+    
+    // Borrow: the parent sleeps until the child execs and hands memory back
+    let Ok(child) = kthread_spawn(fork_child_thread, "forkchild", KTHREAD_DEFAULT_PRIORITY) else {
+        crate::println!("Process Test: ✗ Could not spawn child");
+        return;
+    };
+    let borrowed = fork::fork(child, ForkMode::Borrow);
+    for _ in 0..200 {
+        if FORK_CHILD_DONE.load(Ordering::SeqCst) == 2 {
+            break;
+        }
+        yield_now();
+    }
+    let returned = with_thread(me, |thread| thread.address_space().map(|space| user_byte(space, base + 4096))).flatten();
+    if borrowed.is_ok() && FORK_CHILD_DONE.load(Ordering::SeqCst) == 2 && returned == Some(Some(0xA1))
+        && fork::stats().borrows == stats_before.borrows + 1
+    {
+        crate::println!("Process Test: ✓ Borrowed address space returned on exec");
+    } else {
+        crate::println!("Process Test: ✗ Borrow gave {:?}, space back {:?}", borrowed, returned);
+    }
+    
+    let execy = ForkStats { execs_after_fork: 10, exec_shared: 1000, exec_copied: 20, ..ForkStats::default() };
+    let copy_heavy = ForkStats { exec_copied: 400, ..execy };
+    if fork::recommend(&execy) == ForkMode::Borrow && fork::recommend(&copy_heavy) == ForkMode::Copy
+        && fork::recommend(&ForkStats::default()) == ForkMode::Copy
+    {
+        crate::println!("Process Test: ✓ Borrow recommended only for exec-heavy histories");
+    } else {
+        crate::println!("Process Test: ✗ Fork mode recommendation wrong");
+    }
+    
+    drop(with_thread(me, |thread| thread.take_address_space()));
+    yield_now();
+    reap_exited();
+    let (free_after, _) = frame_allocator_stats();
+    if free_after == free_before {
+        crate::println!("Process Test: ✓ Forked address spaces freed everything");
+    } else {
+        crate::println!("Process Test: ✗ {} frames leaked", free_before.abs_diff(free_after));
+    }
+    
+    crate::println!("Process Test: Fork test completed");
+}
+
+static ASYNC_SLEPT: AtomicU64 = AtomicU64::new(0);
+static ASYNC_RECEIVED: AtomicU32 = AtomicU32::new(0);
+
+#[kernel_test]
+pub fn test_async_executor() {
+    use crate::executor::{self, sleep_ms};
+    use crate::interrupts::counter_frequency;
+    use crate::ipc::{create_port, destroy_port, lookup_port, Message};
+    
+    crate::println!("Process Test: Testing async kernel tasks...");
+    
+    let port_id = create_port(0);
+    let Some(port) = lookup_port(port_id) else {
+        crate::println!("Process Test: ✗ Could not create port");
+        return;
+    };
+    let receiver = port.clone();
+    let start = counter_ticks();
+    let sleeper = executor::spawn("test-sleep", async move {
+        sleep_ms(20).await;
+        ASYNC_SLEPT.store(counter_ticks() - start, Ordering::SeqCst);
+    });
+    let listener = executor::spawn("test-port", async move {
+        let message = receiver.receive_async().await;
+        ASYNC_RECEIVED.store(message.data[0] as u32, Ordering::SeqCst);
+    });
+    if sleeper.is_err() || listener.is_err() {
+        crate::println!("Process Test: ✗ executor::spawn failed");
+        return;
+    }
+    
+    // Give the listener time to park before the message arrives
+    for _ in 0..10 {
+        yield_now();
+    }
+    let sent = port.send_message(Message { sender: 0, data: alloc::vec![42], trace_id: 0, grant: None, reply: None });
+    
+    let deadline = counter_ticks() + counter_frequency() / 2;
+    while (ASYNC_SLEPT.load(Ordering::SeqCst) == 0 || ASYNC_RECEIVED.load(Ordering::SeqCst) == 0)
+        && counter_ticks() < deadline
+    {
+        yield_now();
+    }
+    
+    let slept = ASYNC_SLEPT.load(Ordering::SeqCst);
+    if slept >= counter_frequency() / 50 {
+        crate::println!("Process Test: ✓ Sleeping task woken by the timer");
+    } else {
+        crate::println!("Process Test: ✗ Sleeping task finished after {} ticks", slept);
+    }
+    if sent.is_ok() && ASYNC_RECEIVED.load(Ordering::SeqCst) == 42 {
+        crate::println!("Process Test: ✓ Port message woke the waiting task");
+    } else {
+        crate::println!("Process Test: ✗ Waiting task never received the message");
+    }
+    let _ = destroy_port(port_id);
+    
+    crate::println!("Process Test: Async task test completed");
+}
+
+#[kernel_test]
+pub fn test_memory_inspection() {
+    use crate::memory::frame_allocator::{deallocate_frame, PAGE_SIZE};
+    use crate::memory::paging::{PageFlags, VirtualMemoryManager};
+    use super::inspect;
+    use super::scheduler::with_thread;
+    use super::thread::AddressSpace;
+    
+    crate::println!("Process Test: Testing process memory inspection...");
+    
+    let (free_before, _) = frame_allocator_stats();
+    BLOCK_STAGE.store(0, Ordering::SeqCst);
+    let target = match kthread_spawn(blocking_thread, "kinspect", KTHREAD_DEFAULT_PRIORITY) {
+        Ok(id) => id,
+        Err(e) => {
+            crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+            return;
+        }
+    };
+    if !wait_for_stage(1) {
+        crate::println!("Process Test: ✗ Target thread never blocked");
+        return;
+    }
+    let no_space = inspect::read(target, 0x1000, &mut [0; 4]).is_err();
+    
+    let Some(vmm) = VirtualMemoryManager::new_user() else {
+        crate::println!("Process Test: ✗ Could not allocate an address space");
+        return;
+    };
+    let mut space = AddressSpace::new(vmm);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER | PageFlags::UXN;
+    let Ok(base) = space.map_anonymous(2, flags) else {
+        crate::println!("Process Test: ✗ Could not map target pages");
+        return;
+    };
+    // A copy-on-write sibling, and a frame the target maps twice
+    let sibling = space.fork();
+    let doubled = space.take_frames(base, 1, false).and_then(|frames| {
+        let mapped = space.map_shared(frames[0], 1, flags);
+        deallocate_frame(frames[0]);
+        mapped
+    });
+    let (Ok(mut sibling), Ok(doubled)) = (sibling, doubled) else {
+        crate::println!("Process Test: ✗ Could not set up target mappings");
+        return;
+    };
+    with_thread(target, |thread| thread.set_address_space(space));
+    
+    // Straddle the page boundary
+    let addr = base + PAGE_SIZE as u64 - 2;
+    let written = inspect::write(target, addr, b"abcd");
+    let mut back = [0; 4];
+    let read = inspect::read(target, addr, &mut back);
+    if no_space && written.is_ok() && read.is_ok() && &back == b"abcd" {
+        crate::println!("Process Test: ✓ Read back a write across the target's page boundary");
+    } else {
+        crate::println!("Process Test: ✗ Inspection failed ({:?}, {:?}, {:?})", written, read, back);
+    }
+    
+    // The write broke copy-on-write for the target only
+    let sibling_view = sibling.vmm().translate(base + PAGE_SIZE as u64)
+        .map(|phys| unsafe { *(crate::memory::paging::phys_to_virt(phys) as *const u8) });
+    if sibling_view == Some(0) {
+        crate::println!("Process Test: ✓ Writes to copy-on-write pages stay private to the target");
+    } else {
+        crate::println!("Process Test: ✗ Forked sibling sees {:?}", sibling_view);
+    }
+    
+    let unmapped = inspect::read(target, 0x10, &mut back).is_err();
+    let shared = inspect::write(target, doubled, b"no").is_err();
+    if unmapped && shared {
+        crate::println!("Process Test: ✓ Unmapped and shared pages refused");
+    } else {
+        crate::println!("Process Test: ✗ Inspection allowed an unmapped read or shared write");
+    }
+    
+    drop(with_thread(target, |thread| thread.take_address_space()));
+    drop(sibling);
+    wake(target);
+    wait_for_stage(2);
+    yield_now();
+    reap_exited();
+    let (free_after, _) = frame_allocator_stats();
+    if free_after != free_before {
+        crate::println!("Process Test: ✗ {} frames leaked", free_before.abs_diff(free_after));
+    }
+    crate::println!("Process Test: Memory inspection test completed");
+}
+
+#[kernel_test]
+pub fn test_user_copy() {
+    use crate::memory::frame_allocator::PAGE_SIZE;
+    use crate::memory::paging::{PageFlags, VirtualMemoryManager};
+    use crate::uaccess::{copy_from_user, copy_to_user};
+    use super::scheduler::with_thread;
+    use super::thread::{AddressSpace, USER_MMAP_BASE};
+    
+    crate::println!("Process Test: Testing user memory copies...");
+    
+    // Kernel threads pass kernel pointers; user pointers need user memory
+    let source = *b"kernel";
+    let mut copy = [0; 6];
+    let trusted = copy_from_user(true, &mut copy, source.as_ptr() as u64).is_ok() && copy == source;
+    let refused = copy_from_user(true, &mut copy, 0).is_err()
+        && copy_from_user(false, &mut copy, source.as_ptr() as u64).is_err()
+        && copy_from_user(false, &mut copy, USER_MMAP_BASE).is_err()
+        && copy_to_user(false, u64::MAX - 2, &copy).is_err();
+    if trusted && refused {
+        crate::println!("Process Test: ✓ Kernel pointers trusted, bad user pointers refused");
+    } else {
+        crate::println!("Process Test: ✗ Pointer checks wrong (trusted {}, refused {})", trusted, refused);
+    }
+    
+    let (free_before, _) = frame_allocator_stats();
+    let Some(vmm) = VirtualMemoryManager::new_user() else {
+        crate::println!("Process Test: ✗ Could not allocate an address space");
+        return;
+    };
+    let mut space = AddressSpace::new(vmm);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER | PageFlags::UXN;
+    let writable = space.map_anonymous(1, flags);
+    let read_only = space.map_anonymous(1, flags | PageFlags::READ_ONLY);
+    let (Ok(base), Ok(end)) = (writable, read_only) else {
+        crate::println!("Process Test: ✗ Could not map user pages");
+        return;
+    };
+    if end != base + PAGE_SIZE as u64 {
+        crate::println!("Process Test: ✗ User pages not back to back");
+        return;
+    }
+    let me = current_thread_id();
+    with_thread(me, |thread| thread.set_address_space(space));
+    
+    // Run on the test space's tables with interrupts off, so nothing else
+    // sees them; then go back to the empty ones, which the checks cannot
+    // see, so the copy itself faults
+    #[cfg(not(feature = "no-mmu"))]
+    {
+        use crate::interrupts::{local_irq_restore, local_irq_save};
+        use crate::memory::asid;
+        
+        let daif = local_irq_save();
+        with_thread(me, |thread| thread.address_space().map(|space| space.vmm().activate()));
+        let written = copy_to_user(false, end - 3, b"abc");
+        let mut back = [0xFF; 5];
+        let read = copy_from_user(false, &mut back, end - 3);
+        let read_only = copy_to_user(false, end - 1, b"xy").is_err();
+        asid::switch_to_empty();
+        let faulted = copy_from_user(false, &mut [0; 1], base).is_err();
+        local_irq_restore(daif);
+        
+        if written.is_ok() && read.is_ok() && &back == b"abc\0\0" {
+            crate::println!("Process Test: ✓ Copied to and from user memory across a page boundary");
+        } else {
+            crate::println!("Process Test: ✗ User copy failed ({:?}, {:?}, {:?})", written, read, back);
+        }
+        if read_only && faulted {
+            crate::println!("Process Test: ✓ Read-only page refused, faulting copy returned an error");
+        } else {
+            crate::println!("Process Test: ✗ Copy not stopped (read-only {}, faulted {})", read_only, faulted);
+        }
+    }
+    
+    drop(with_thread(me, |thread| thread.take_address_space()));
+    let (free_after, _) = frame_allocator_stats();
+    if free_after != free_before {
+        crate::println!("Process Test: ✗ {} frames leaked", free_before.abs_diff(free_after));
+    }
+    crate::println!("Process Test: User copy test completed");
+}
+
+#[kernel_test]
+pub fn test_pan() {
+    use crate::interrupts::{local_irq_restore, local_irq_save};
+    use crate::memory::asid;
+    use crate::memory::paging::{PageFlags, VirtualMemoryManager};
+    use crate::uaccess::{copy_from_user, pan_active, pan_enabled};
+    use super::scheduler::with_thread;
+    use super::thread::AddressSpace;
+    
+    crate::println!("Process Test: Testing Privileged Access Never...");
+    if !pan_enabled() {
+        crate::println!("Process Test: PAN not implemented by this CPU, skipped");
+        return;
+    }
+    if cfg!(feature = "no-mmu") {
+        crate::println!("Process Test: PAN needs translation (no-mmu), skipped");
+        return;
+    }
+    // Set again on entry to this thread, by its initial SPSR
+    if pan_active() {
+        crate::println!("Process Test: ✓ PAN set in kernel thread context");
+    } else {
+        crate::println!("Process Test: ✗ PAN clear in a kernel thread");
+    }
+    
+    let Some(vmm) = VirtualMemoryManager::new_user() else {
+        crate::println!("Process Test: ✗ Could not allocate an address space");
+        return;
+    };
+    let mut space = AddressSpace::new(vmm);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER | PageFlags::UXN;
+    let Ok(base) = space.map_anonymous(1, flags) else {
+        crate::println!("Process Test: ✗ Could not map a user page");
+        return;
+    };
+    let me = current_thread_id();
+    with_thread(me, |thread| thread.set_address_space(space));
+    
+    let daif = local_irq_save();
+    with_thread(me, |thread| thread.address_space().map(|space| space.vmm().activate()));
+    let copied = copy_from_user(false, &mut [0; 8], base).is_ok();
+    let direct = crate::oops::guard("pan-test", || unsafe { core::ptr::read_volatile(base as *const u64) });
+    asid::switch_to_empty();
+    local_irq_restore(daif);
+    
+    if copied && direct.is_err() {
+        crate::println!("Process Test: ✓ User page readable by copy, direct load faulted");
+    } else {
+        crate::println!("Process Test: ✗ PAN not enforced (copy {}, direct {:?})", copied, direct);
+    }
+    drop(with_thread(me, |thread| thread.take_address_space()));
+    crate::println!("Process Test: PAN test completed");
+}
+
+static TTBR0_PROBE: AtomicU64 = AtomicU64::new(0);
+
+fn ttbr0_probe_thread() {
+    let ttbr0: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, ttbr0_el1", out(reg) ttbr0);
+    }
+    TTBR0_PROBE.store(ttbr0, Ordering::SeqCst);
+}
+
+// Physical address an EL0 read of `virt` reaches through TTBR0
+fn user_translate(virt: u64) -> Option<u64> {
+    let par: u64;
+    unsafe {
+        core::arch::asm!("at s1e0r, {}", in(reg) virt);
+        core::arch::asm!("isb");
+        core::arch::asm!("mrs {}, par_el1", out(reg) par);
+    }
+    (par & 1 == 0).then_some(par & 0x0000_FFFF_FFFF_F000)
+}
+
+#[kernel_test]
+pub fn test_asid_rollover() {
+    use crate::interrupts::{local_irq_restore, local_irq_save};
+    use crate::memory::asid;
+    use crate::memory::frame_allocator::allocate_frame;
+    use crate::memory::mmu::MemoryManagementUnit;
+    use crate::memory::paging::{virt_to_phys, PageFlags, VirtualMemoryManager};
+    
+    if !MemoryManagementUnit::is_enabled() {
+        crate::println!("Process Test: MMU disabled, skipping ASID test");
+        return;
+    }
+    crate::println!("Process Test: Testing ASID allocation and rollover...");
+    
+    const VIRT: u64 = 0x3000_0000;
+    let spaces_before = asid::stats().spaces;
+    let (Some(mut first), Some(second), Some(frame)) =
+        (VirtualMemoryManager::new_user(), VirtualMemoryManager::new_user(), allocate_frame()) else {
+        crate::println!("Process Test: ✗ Could not allocate address spaces");
+        return;
+    };
+    let phys = virt_to_phys(frame.as_ptr() as u64);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::ACCESSED | PageFlags::USER | PageFlags::UXN;
+    if first.map_frame(VIRT, frame, flags).is_err() {
+        crate::println!("Process Test: ✗ Could not map a user page");
+    }
+    let asid_of = |vmm: &VirtualMemoryManager| vmm.space_id().and_then(asid::hardware_asid);
+    
+    // Each space gets its own ASID on first use and keeps it afterwards
+    let daif = local_irq_save();
+    first.activate();
+    let first_asid = asid_of(&first);
+    let mapped = user_translate(VIRT) == Some(phys);
+    second.activate();
+    let second_asid = asid_of(&second);
+    let isolated = user_translate(VIRT).is_none();
+    first.activate();
+    let kept = asid_of(&first) == first_asid;
+    
+    // A new generation while `first` runs: its ASID stays reserved for it,
+    // `second` finds its own still free, and translations survive the flush
+    let before = asid::stats();
+    asid::rollover();
+    second.activate();
+    first.activate();
+    let after = asid::stats();
+    let translated = user_translate(VIRT) == Some(phys);
+    local_irq_restore(daif);
+    
+    let distinct = first_asid.is_some_and(|asid| asid != 0) && second_asid.is_some_and(|asid| asid != 0)
+        && first_asid != second_asid;
+    if distinct && mapped && isolated && kept {
+        crate::println!("Process Test: ✓ Spaces run under ASIDs {:?} and {:?}, isolated", first_asid, second_asid);
+    } else {
+        crate::println!("Process Test: ✗ ASIDs {:?}/{:?}, mapped {}, isolated {}, kept {}",
+                        first_asid, second_asid, mapped, isolated, kept);
+    }
+    let survived = asid_of(&first) == first_asid && asid_of(&second) == second_asid;
+    if after.generation == before.generation + 1 && after.rollovers == before.rollovers + 1 && survived && translated {
+        crate::println!("Process Test: ✓ Rollover to generation {} kept running ASIDs", after.generation);
+    } else {
+        crate::println!("Process Test: ✗ Rollover wrong (generation {} -> {}, kept {}, translated {})",
+                        before.generation, after.generation, survived, translated);
+    }
+    
+    // A kernel thread borrows the loaded tables instead of switching
+    TTBR0_PROBE.store(0, Ordering::SeqCst);
+    if kthread_spawn(ttbr0_probe_thread, "ttbr0probe", KTHREAD_DEFAULT_PRIORITY).is_ok() {
+        for _ in 0..100 {
+            if TTBR0_PROBE.load(Ordering::SeqCst) != 0 {
+                break;
+            }
+            yield_now();
+        }
+    }
+    reap_exited();
+    let borrowed = TTBR0_PROBE.load(Ordering::SeqCst);
+    if borrowed & 0x0000_FFFF_FFFF_FFFE == first.root_table_addr() {
+        crate::println!("Process Test: ✓ Kernel thread ran on the borrowed user tables");
+    } else {
+        crate::println!("Process Test: ✗ Kernel thread TTBR0 0x{:016x}", borrowed);
+    }
+    
+    // Destroying a space unloads it, and its id is reused
+    first.destroy();
+    second.destroy();
+    let unloaded = user_translate(VIRT).is_none();
+    if unloaded && asid::stats().spaces == spaces_before {
+        crate::println!("Process Test: ✓ Destroyed spaces unloaded and released");
+    } else {
+        crate::println!("Process Test: ✗ Teardown left {} spaces (unloaded {})", asid::stats().spaces, unloaded);
+    }
+    crate::println!("Process Test: ASID test completed");
+}
+
+static RPC_PORT: AtomicU32 = AtomicU32::new(0);
+static RPC_LAST_REPLY: AtomicU32 = AtomicU32::new(0);
+static RPC_LAST_SENDER: AtomicU32 = AtomicU32::new(0);
+
+// Answer each call with its first byte plus one, until a plain message
+fn rpc_server() {
+    use crate::ipc::{self, lookup_port};
+    
+    let Some(port) = lookup_port(RPC_PORT.load(Ordering::SeqCst)) else { return };
+    let Ok(mut request) = port.receive_wait() else { return };
+    while let Some(reply) = request.reply {
+        RPC_LAST_REPLY.store(reply, Ordering::SeqCst);
+        RPC_LAST_SENDER.store(request.sender, Ordering::SeqCst);
+        let mut answer = request.clone();
+        answer.data[0] = answer.data[0].wrapping_add(1);
+        match ipc::reply_and_receive(reply, answer, &port) {
+            Ok(next) => request = next,
+            Err(_) => return,
+        }
+    }
+}
+
+#[kernel_test]
+pub fn test_ipc_call() {
+    use crate::interrupts::ExceptionContext;
+    use crate::ipc::{self, create_port, destroy_port, lookup_port, Message, MESSAGE_MAX};
+    use crate::syscall::{IpcMsg, IpcVec, SYS_IPC_CALLV};
+    use crate::time::ticks_to_ns;
+    
+    crate::println!("Process Test: Testing IPC call/reply...");
+    
+    let port_id = create_port(0);
+    let Some(port) = lookup_port(port_id) else {
+        crate::println!("Process Test: ✗ Could not create port");
+        return;
+    };
+    let message = |byte| {
+        Message { sender: current_thread_id(), data: alloc::vec![byte], trace_id: 0, grant: None, reply: None }
+    };
+    RPC_PORT.store(port_id, Ordering::SeqCst);
+    if let Err(e) = kthread_spawn(rpc_server, "krpc", KTHREAD_DEFAULT_PRIORITY) {
+        crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+        let _ = destroy_port(port_id);
+        return;
+    }
+    // Let the server block in receive first, so calls take the fast path
+    for _ in 0..10 {
+        yield_now();
+    }
+    
+    const CALLS: u8 = 100;
+    let start = counter_ticks();
+    let answered = (0..CALLS).all(|i| {
+        ipc::call(&port, message(i)).is_ok_and(|answer| answer.data[0] == i + 1 && answer.reply.is_none())
+    });
+    let per_call = ticks_to_ns(counter_ticks() - start) / CALLS as u64;
+    if answered {
+        crate::println!("Process Test: ✓ {} calls answered, {} ns per round trip", CALLS, per_call);
+    } else {
+        crate::println!("Process Test: ✗ A call went unanswered or got the wrong answer");
+    }
+    let sender = RPC_LAST_SENDER.load(Ordering::SeqCst);
+    if sender == current_thread_id() {
+        crate::println!("Process Test: ✓ Server saw the caller as sender");
+    } else {
+        crate::println!("Process Test: ✗ Server saw sender {}", sender);
+    }
+    
+    let last = RPC_LAST_REPLY.load(Ordering::SeqCst);
+    if last != 0 && ipc::reply(last, message(0)).is_err() {
+        crate::println!("Process Test: ✓ Used reply capability rejected");
+    } else {
+        crate::println!("Process Test: ✗ Reply capability {} answered twice", last);
+    }
+    
+    // A full-size request gathered from two buffers; the answer scattered
+    // over two others
+    let header = [7u8; 16];
+    let body = alloc::vec![0x5Au8; MESSAGE_MAX - header.len()];
+    let (mut head, mut tail) = ([0u8; 16], alloc::vec![0u8; MESSAGE_MAX - 16]);
+    let send = [IpcVec { base: header.as_ptr() as u64, len: 16 }, IpcVec { base: body.as_ptr() as u64, len: body.len() as u64 }];
+    let recv = [IpcVec { base: head.as_mut_ptr() as u64, len: 16 }, IpcVec { base: tail.as_mut_ptr() as u64, len: tail.len() as u64 }];
+    let msg = IpcMsg { send: send.as_ptr() as u64, send_count: 2, recv: recv.as_ptr() as u64, recv_count: 2 };
+    let mut ctx = ExceptionContext { x0: port_id as u64, x1: &msg as *const IpcMsg as u64, spsr_el1: 0x3c5, ..Default::default() };
+    crate::syscall::dispatch(&mut ctx, SYS_IPC_CALLV);
+    let answer_ok = head[0] == 8 && head[1..] == header[1..] && tail == body;
+    // Receive buffers too small: the length says so
+    let short = IpcMsg { recv_count: 1, ..msg };
+    let mut ctx_short = ExceptionContext { x0: port_id as u64, x1: &short as *const IpcMsg as u64, spsr_el1: 0x3c5, ..Default::default() };
+    crate::syscall::dispatch(&mut ctx_short, SYS_IPC_CALLV);
+    if ctx.x0 == MESSAGE_MAX as u64 && answer_ok && ctx_short.x0 == MESSAGE_MAX as u64 {
+        crate::println!("Process Test: ✓ {}-byte call gathered and scattered; truncation reported", MESSAGE_MAX);
+    } else {
+        crate::println!("Process Test: ✗ Vectored call returned {} / {} (answer {})",
+                       ctx.x0 as i64, ctx_short.x0 as i64, if answer_ok { "intact" } else { "wrong" });
+    }
+    
+    // A plain message stops the server
+    let _ = port.send_message(message(0));
+    for _ in 0..10 {
+        yield_now();
+    }
+    let _ = destroy_port(port_id);
+    reap_exited();
+    crate::println!("Process Test: IPC call test completed");
+}
+
+static NOTIFY_PORT: AtomicU32 = AtomicU32::new(0);
+// Bits the blocking and async waiters saw, or u64::MAX on error
+static NOTIFY_BLOCKING: AtomicU64 = AtomicU64::new(0);
+static NOTIFY_ASYNC: AtomicU64 = AtomicU64::new(0);
+
+fn notify_waiter() {
+    let Some(port) = crate::ipc::lookup_port(NOTIFY_PORT.load(Ordering::SeqCst)) else {
+        NOTIFY_BLOCKING.store(u64::MAX, Ordering::SeqCst);
+        return;
+    };
+    let bits = port.wait_bits().unwrap_or(u64::MAX);
+    NOTIFY_BLOCKING.store(bits, Ordering::SeqCst);
+}
+
+#[kernel_test]
+pub fn test_port_notifications() {
+    use crate::executor;
+    use crate::interrupts::counter_frequency;
+    use crate::ipc::{create_port, destroy_port, lookup_port};
+    
+    crate::println!("Process Test: Testing port notification bits...");
+    
+    let port_id = create_port(0);
+    let Some(port) = lookup_port(port_id) else {
+        crate::println!("Process Test: ✗ Could not create port");
+        return;
+    };
+    
+    // Signals before anyone waits accumulate
+    port.signal(1 << 0);
+    port.signal(1 << 63);
+    let bits = port.poll_bits();
+    if bits == (1 << 0) | (1 << 63) && port.poll_bits() == 0 && port.pending() == 0 {
+        crate::println!("Process Test: ✓ Signals OR together and are cleared when taken");
+    } else {
+        crate::println!("Process Test: ✗ Polled {:#x} after two signals", bits);
+    }
+    
+    NOTIFY_PORT.store(port_id, Ordering::SeqCst);
+    NOTIFY_BLOCKING.store(0, Ordering::SeqCst);
+    if let Err(e) = kthread_spawn(notify_waiter, "knotify", KTHREAD_DEFAULT_PRIORITY) {
+        crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+        let _ = destroy_port(port_id);
+        return;
+    }
+    // Let it block first
+    for _ in 0..10 {
+        yield_now();
+    }
+    let parked = NOTIFY_BLOCKING.load(Ordering::SeqCst) == 0;
+    port.signal(0b110);
+    let deadline = counter_ticks() + counter_frequency() / 2;
+    while NOTIFY_BLOCKING.load(Ordering::SeqCst) == 0 && counter_ticks() < deadline {
+        yield_now();
+    }
+    let woke = NOTIFY_BLOCKING.load(Ordering::SeqCst);
+    if parked && woke == 0b110 {
+        crate::println!("Process Test: ✓ Signal woke the blocked thread with its bits");
+    } else {
+        crate::println!("Process Test: ✗ Blocked waiter saw {:#x} (parked: {})", woke, parked);
+    }
+    
+    NOTIFY_ASYNC.store(0, Ordering::SeqCst);
+    let waiter = port.clone();
+    let spawned = executor::spawn("test-notify", async move {
+        NOTIFY_ASYNC.store(waiter.wait_bits_async().await, Ordering::SeqCst);
+    });
+    for _ in 0..10 {
+        yield_now();
+    }
+    port.signal(1 << 8);
+    let deadline = counter_ticks() + counter_frequency() / 2;
+    while NOTIFY_ASYNC.load(Ordering::SeqCst) == 0 && counter_ticks() < deadline {
+        yield_now();
+    }
+    if spawned.is_ok() && NOTIFY_ASYNC.load(Ordering::SeqCst) == 1 << 8 {
+        crate::println!("Process Test: ✓ Signal woke the waiting task");
+    } else {
+        crate::println!("Process Test: ✗ Waiting task never saw the signal");
+    }
+    
+    let _ = destroy_port(port_id);
+    yield_now();
+    reap_exited();
+    crate::println!("Process Test: Port notification test completed");
+}
+
+static RING_PORT: AtomicU32 = AtomicU32::new(0);
+// Bit 0: batch completed in order, bit 1: parked receive completed
+static RING_CHECKS: AtomicU32 = AtomicU32::new(0);
+static RING_DONE: AtomicBool = AtomicBool::new(false);
+
+fn ring_thread() {
+    use crate::ipc::{create_port, destroy_port};
+    use crate::uring::{self, CompletionEntry, RingHeader, SubmissionEntry, OP_NOP, OP_RECV, OP_SEND};
+    
+    let Ok(base) = uring::setup(8) else {
+        RING_DONE.store(true, Ordering::SeqCst);
+        return;
+    };
+    let header = unsafe { &*(base as *const RingHeader) };
+    let sqes = (base + header.sq_offset as u64) as *mut SubmissionEntry;
+    let cqes = (base + header.cq_offset as u64) as *const CompletionEntry;
+    
+    let tid = current_thread_id();
+    let (loopback, remote) = (create_port(tid), create_port(tid));
+    let ping = *b"ping";
+    let mut local_buf = [0u8; 8];
+    let mut remote_buf = [0u8; 8];
+    let batch = [
+        SubmissionEntry { opcode: OP_NOP, user_data: 1, ..Default::default() },
+        SubmissionEntry { opcode: OP_SEND, port: loopback, addr: ping.as_ptr() as u64, len: 4, user_data: 2, ..Default::default() },
+        SubmissionEntry { opcode: OP_RECV, port: loopback, addr: local_buf.as_mut_ptr() as u64, len: 8, user_data: 3, ..Default::default() },
+        SubmissionEntry { opcode: OP_RECV, port: remote, addr: remote_buf.as_mut_ptr() as u64, len: 8, user_data: 4, ..Default::default() },
+    ];
+    for (i, sqe) in batch.iter().enumerate() {
+        unsafe { sqes.add(i).write_volatile(*sqe) };
+    }
+    header.sq_tail.store(batch.len() as u32, Ordering::Release);
+    RING_PORT.store(remote, Ordering::SeqCst);
+    
+    // Three complete at once; the receive on the empty port parks
+    let submitted = uring::enter(4, 3, true);
+    let completions: [CompletionEntry; 3] = core::array::from_fn(|i| unsafe { cqes.add(i).read_volatile() });
+    let in_order = completions.iter().map(|cqe| (cqe.user_data, cqe.result)).eq([(1, 0), (2, 4), (3, 4)]);
+    if submitted == Ok(4) && header.cq_tail.load(Ordering::Acquire) == 3 && in_order && local_buf[..4] == ping {
+        RING_CHECKS.fetch_or(1 << 0, Ordering::SeqCst);
+    }
+    header.cq_head.store(3, Ordering::Release);
+    
+    let waited = uring::enter(0, 1, true);
+    let cqe = unsafe { cqes.add(3).read_volatile() };
+    if waited == Ok(0) && cqe.user_data == 4 && cqe.result == 4 && &remote_buf[..4] == b"pong" {
+        RING_CHECKS.fetch_or(1 << 1, Ordering::SeqCst);
+    }
+    
+    let _ = destroy_port(loopback);
+    let _ = destroy_port(remote);
+    RING_DONE.store(true, Ordering::SeqCst);
+}
+
+#[kernel_test]
+pub fn test_io_ring() {
+    use crate::interrupts::counter_frequency;
+    use crate::ipc::{lookup_port, Message};
+    
+    crate::println!("Process Test: Testing submission/completion rings...");
+    
+    if let Err(e) = kthread_spawn(ring_thread, "ring-test", KTHREAD_DEFAULT_PRIORITY) {
+        crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+        return;
+    }
+    
+    // Reply once the ring thread is waiting on the remote port
+    let deadline = counter_ticks() + counter_frequency() / 2;
+    while RING_PORT.load(Ordering::SeqCst) == 0 && !RING_DONE.load(Ordering::SeqCst) && counter_ticks() < deadline {
+        yield_now();
+    }
+    for _ in 0..10 {
+        yield_now();
+    }
+    let data = b"pong".to_vec();
+    let sent = lookup_port(RING_PORT.load(Ordering::SeqCst))
+        .map(|port| port.send_message(Message { sender: 0, data, trace_id: 0, grant: None, reply: None }));
+    while !RING_DONE.load(Ordering::SeqCst) && counter_ticks() < deadline {
+        yield_now();
+    }
+    
+    let checks = RING_CHECKS.load(Ordering::SeqCst);
+    if checks & (1 << 0) != 0 {
+        crate::println!("Process Test: ✓ Batch of four consumed in one enter, three completed in order");
+    } else {
+        crate::println!("Process Test: ✗ Batch submission did not complete as expected");
+    }
+    if checks & (1 << 1) != 0 && matches!(sent, Some(Ok(()))) {
+        crate::println!("Process Test: ✓ Parked receive completed when the message arrived");
+    } else {
+        crate::println!("Process Test: ✗ Parked receive never completed");
+    }
+    reap_exited();
+    
+    crate::println!("Process Test: Ring test completed");
+}
+
+static PRIO_STAGE: AtomicU32 = AtomicU32::new(0);
+static PRIO_BOOST: AtomicU32 = AtomicU32::new(0);
+static PI_LOCK: SleepMutex<u32> = SleepMutex::new(0);
+
+const LOW_PRIORITY: Priority = KTHREAD_DEFAULT_PRIORITY - 4;
+
+fn low_priority_thread() {
+    PRIO_STAGE.store(1, Ordering::SeqCst);
+    let mut guard = PI_LOCK.lock();
+    *guard += 1;
+    PRIO_STAGE.store(2, Ordering::SeqCst);
+    
+    // Hold the lock until kmain waits for it and lends us its priority
+    let me = current_thread_id();
+    for _ in 0..100 {
+        if effective_priority(me) > LOW_PRIORITY {
+            break;
+        }
+        yield_now();
+    }
+    PRIO_BOOST.store(effective_priority(me) as u32, Ordering::SeqCst);
+    drop(guard);
+    PRIO_STAGE.store(3, Ordering::SeqCst);
+}
+
+// Yield until `stage` is reached (bounded)
+fn wait_for_prio_stage(stage: u32) -> bool {
+    for _ in 0..100 {
+        if PRIO_STAGE.load(Ordering::SeqCst) >= stage {
+            return true;
+        }
+        yield_now();
+    }
+    false
+}
+
+#[kernel_test]
+pub fn test_priorities() {
+    crate::println!("Process Test: Testing priority scheduling...");
+    
+    PRIO_STAGE.store(0, Ordering::SeqCst);
+    let me = current_thread_id();
+    let low = match kthread_spawn(low_priority_thread, "kprio", LOW_PRIORITY) {
+        Ok(id) => id,
+        Err(e) => {
+            crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+            return;
+        }
+    };
+    
+    // Yielding only hands the CPU to threads at least as urgent
+    for _ in 0..10 {
+        yield_now();
+    }
+    if PRIO_STAGE.load(Ordering::SeqCst) == 0 {
+        crate::println!("Process Test: ✓ Lower-priority thread waits while kmain is runnable");
+    } else {
+        crate::println!("Process Test: ✗ Lower-priority thread ran ahead of kmain");
+    }
+    
+    // At equal priority they share the CPU again
+    let _ = set_priority(me, LOW_PRIORITY);
+    let holding = wait_for_prio_stage(2);
+    let _ = set_priority(me, KTHREAD_DEFAULT_PRIORITY);
+    if !holding {
+        crate::println!("Process Test: ✗ Lower-priority thread never took the lock");
+        let _ = super::kill(low);
+        return;
+    }
+    
+    // Waiting for the lock boosts its holder to our priority
+    let count = *PI_LOCK.lock();
+    let boost = PRIO_BOOST.load(Ordering::SeqCst);
+    if count == 1 && boost == KTHREAD_DEFAULT_PRIORITY as u32 {
+        crate::println!("Process Test: ✓ Lock holder inherited priority {} from its waiter", boost);
+    } else {
+        crate::println!("Process Test: ✗ Lock holder ran at priority {} while kmain waited", boost);
+    }
+    
+    let _ = set_priority(me, LOW_PRIORITY);
+    let finished = wait_for_prio_stage(3);
+    let restored = effective_priority(low) == LOW_PRIORITY && PI_LOCK.try_lock().is_some();
+    let _ = set_priority(me, KTHREAD_DEFAULT_PRIORITY);
+    if finished && restored {
+        crate::println!("Process Test: ✓ Priority restored after unlock");
+    } else {
+        crate::println!("Process Test: ✗ Lock holder kept its inherited priority");
+    }
+    
+    yield_now();
+    reap_exited();
+    crate::println!("Process Test: Priority test completed");
+}
+
+// Stands in for a user thread taking a data abort: same path, minus EL0
+const FAULT_TEST_PC: u64 = 0x0001_0040;
+const FAULT_TEST_FAR: u64 = 0xDEAD_0000;
+const FAULT_TEST_ESR: u64 = 0x9200_0047;
+
+fn faulting_thread() {
+    let ctx = crate::interrupts::ExceptionContext { elr_el1: FAULT_TEST_PC, ..Default::default() };
+    fault::kill_current(&ctx, FaultKind::DataAbort, FAULT_TEST_FAR, FAULT_TEST_ESR);
+    loop {
+        yield_now();
+    }
+}
+
+#[kernel_test]
+pub fn test_fault_report() {
+    crate::println!("Process Test: Testing kill-on-fault reports...");
+    
+    let me = current_thread_id();
+    let port = crate::ipc::create_port(me);
+    let foreign = crate::ipc::create_port(me + 1000);
+    if fault::set_fault_port(Some(foreign)).is_err() {
+        crate::println!("Process Test: ✓ Fault port must belong to the caller");
+    } else {
+        crate::println!("Process Test: ✗ Accepted another thread's port");
+    }
+    let _ = crate::ipc::destroy_port(foreign);
+    
+    if let Err(e) = fault::set_fault_port(Some(port)) {
+        crate::println!("Process Test: ✗ set_fault_port failed: {}", e);
+        let _ = crate::ipc::destroy_port(port);
+        return;
+    }
+    let child = match kthread_spawn(faulting_thread, "kfaulter", KTHREAD_DEFAULT_PRIORITY) {
+        Ok(id) => id,
+        Err(e) => {
+            crate::println!("Process Test: ✗ kthread_spawn failed: {}", e);
+            let _ = fault::set_fault_port(None);
+            let _ = crate::ipc::destroy_port(port);
+            return;
+        }
+    };
+    
+    let receiver = crate::ipc::lookup_port(port);
+    let mut message = None;
+    for _ in 0..1000 {
+        message = receiver.as_ref().and_then(|port| port.receive_message());
+        if message.is_some() {
+            break;
+        }
+        yield_now();
+    }
+    let report = message.as_ref().and_then(|message| FaultReport::decode(&message.data));
+    match report {
+        Some(report) if report.tid == child && report.kind == FaultKind::DataAbort as u32
+            && report.pc == FAULT_TEST_PC && report.far == FAULT_TEST_FAR
+            && report.esr == FAULT_TEST_ESR && report.name() == "kfaulter" => {
+            crate::println!("Process Test: ✓ Parent received the fault report");
+        }
+        other => crate::println!("Process Test: ✗ Fault report {:?}", other),
+    }
+    
+    yield_now();
+    let state = super::scheduler::with_thread(child, |thread| thread.state);
+    if state.is_none_or(|state| state == super::thread::ThreadState::Exited) {
+        crate::println!("Process Test: ✓ Faulting thread was killed");
+    } else {
+        crate::println!("Process Test: ✗ Faulting thread still {:?}", state);
+    }
+    
+    let _ = fault::set_fault_port(None);
+    let _ = crate::ipc::destroy_port(port);
+    reap_exited();
+    crate::println!("Process Test: Fault report test completed");
+}
+
+// Policies on their own, fed made-up thread IDs and priorities
+#[kernel_test]
+pub fn test_scheduling_policies() {
+    use super::policy::{Mlfq, RoundRobin, Scheduler, MLFQ_BOOST_TICKS};
+    use super::scheduler::{policy_name, time_slice};
+    
+    crate::println!("Process Test: Testing scheduling policies ({} in use)...", policy_name());
+    let priority = |id| if id == 2 { 20 } else { 10 };
+    let equal = |_| 10;
+    
+    // Round robin: most urgent first, then first come first served
+    let mut rr = RoundRobin::new();
+    for id in 1..=3 {
+        rr.admit(id, id as usize);
+        rr.enqueue(id);
+    }
+    let order = [rr.pick_next(&priority), rr.pick_next(&priority), rr.pick_next(&priority)];
+    if order == [Some(2), Some(1), Some(3)] && rr.is_empty() {
+        crate::println!("Process Test: ✓ Round robin picks by priority, then in turn");
+    } else {
+        crate::println!("Process Test: ✗ Round robin picked {:?}", order);
+    }
+    
+    // Feedback queue: a full slice at a level moves a thread down, where
+    // it waits behind the top level unless it outranks it
+    let mut mlfq = Mlfq::new();
+    mlfq.admit(1, 1);
+    mlfq.admit(2, 2);
+    let slice = time_slice();
+    let early = (1..slice).any(|_| mlfq.tick(1));
+    let demoted = mlfq.tick(1) && mlfq.level(1) == Some(1) && mlfq.time_slice(1) == 2 * slice;
+    mlfq.enqueue(1);
+    mlfq.enqueue(2);
+    let top_first = mlfq.pick_next(&equal) == Some(2);
+    mlfq.enqueue(2);
+    let urgent_first = mlfq.pick_next(&|id| if id == 1 { 20 } else { 10 }) == Some(1);
+    if !early && demoted && top_first && urgent_first {
+        crate::println!("Process Test: ✓ Feedback queue demotes after a slice, longer slices below");
+    } else {
+        crate::println!("Process Test: ✗ Feedback queue early {} demoted {} top first {} urgent first {}",
+                        early, demoted, top_first, urgent_first);
+    }
+    
+    // Periodically everything goes back to the top
+    mlfq.enqueue(1);
+    let boosted = (0..MLFQ_BOOST_TICKS).any(|_| mlfq.tick(1) && mlfq.level(1) == Some(0));
+    let requeued = mlfq.time_slice(1) == slice && mlfq.contains(1) && mlfq.contains(2);
+    mlfq.forget(1);
+    if boosted && requeued && mlfq.level(1).is_none() {
+        crate::println!("Process Test: ✓ Feedback queue boosts every thread within {} ticks", MLFQ_BOOST_TICKS);
+    } else {
+        crate::println!("Process Test: ✗ Feedback queue boosted {} requeued {}", boosted, requeued);
+    }
+    
+    crate::println!("Process Test: Scheduling policy test completed");
+}
+
+// Sets a flag when dropped, so the test can see when RCU frees it
+struct RcuProbe {
+    value: u32,
+    dropped: &'static core::sync::atomic::AtomicBool,
+}
+
+impl Drop for RcuProbe {
+    fn drop(&mut self) {
+        self.dropped.store(true, core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[kernel_test]
+pub fn test_rcu() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use crate::rcu::{self, Rcu};
+    static FIRST_DROPPED: AtomicBool = AtomicBool::new(false);
+    static SECOND_DROPPED: AtomicBool = AtomicBool::new(false);
+    crate::println!("Process Test: Testing RCU...");
+    
+    let cell = Rcu::new(RcuProbe { value: 1, dropped: &FIRST_DROPPED });
+    
+    // A reader keeps the old version alive across an update
+    let guard = rcu::read_lock();
+    let old = cell.read(&guard).map(|probe| probe.value);
+    cell.update(|_| (Some(RcuProbe { value: 2, dropped: &SECOND_DROPPED }), ()));
+    let held = !FIRST_DROPPED.load(Ordering::SeqCst) && old == Some(1);
+    let current = cell.read(&guard).map(|probe| probe.value);
+    drop(guard);
+    if held && current == Some(2) {
+        crate::println!("Process Test: ✓ Update published while a reader held the old version");
+    } else {
+        crate::println!("Process Test: ✗ Update during read: old {:?} held {} current {:?}", old, held, current);
+    }
+    
+    // Once every CPU has passed a quiescent state it is freed
+    rcu::synchronize();
+    if FIRST_DROPPED.load(Ordering::SeqCst) && !SECOND_DROPPED.load(Ordering::SeqCst) {
+        crate::println!("Process Test: ✓ Old version freed after a grace period");
+    } else {
+        crate::println!("Process Test: ✗ Old version not freed after synchronize");
+    }
+    
+    // Port lookups go through the RCU registry
+    let port = crate::ipc::create_port(0);
+    let found = crate::ipc::lookup_port(port).is_some();
+    let destroyed = crate::ipc::destroy_port(port).is_ok();
+    let gone = crate::ipc::lookup_port(port).is_none() && crate::ipc::destroy_port(port).is_err();
+    if found && destroyed && gone {
+        crate::println!("Process Test: ✓ Port registry read under RCU");
+    } else {
+        crate::println!("Process Test: ✗ Port registry: found {} destroyed {} gone {}", found, destroyed, gone);
+    }
+    
+    drop(cell);
+    if !SECOND_DROPPED.load(Ordering::SeqCst) {
+        crate::println!("Process Test: ✗ Dropping the cell leaked its value");
+    }
+    crate::println!("Process Test: RCU test completed");
+}
@@ -0,0 +1,733 @@
+// Kernel thread control blocks and stacks
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr::NonNull;
+use crate::interrupts::ExceptionContext;
+use crate::ipc::PortId;
+use crate::memory::frame_allocator::{allocate_frame, allocate_frames, allocate_zeroed_frame, deallocate_frame, deallocate_frames, frame_get, frame_refcount, PAGE_SIZE};
+use crate::memory::paging::{phys_to_virt, PageFlags, PhysAddr, VirtAddr, VirtualMemoryManager};
+use crate::memory::rmap;
+use crate::pmu::PmuCounts;
+use crate::syscall::SyscallFilter;
+use crate::sync::IrqSafeMutex;
+use crate::uring::IoRing;
+use super::aslr::Layout;
+use super::capability::Capability;
+use super::fork::{self, CowCounters};
+
+pub type ThreadId = u32;
+
+// Scheduling priority, higher values are more urgent
+pub type Priority = u8;
+
+/// Priorities run from 0 to PRIORITY_MAX.
+pub const PRIORITY_LEVELS: usize = 32;
+pub const PRIORITY_MAX: Priority = (PRIORITY_LEVELS - 1) as Priority;
+
+pub const KERNEL_STACK_FRAMES: usize = 4;
+pub const KERNEL_STACK_SIZE: usize = KERNEL_STACK_FRAMES * PAGE_SIZE;
+
+// With the MMU on, kernel stacks live in their own region of the TTBR1
+// half, one per slot of twice the stack size, aligned to it. The stack is
+// the upper half of its slot and the lower half is never mapped, so
+// running off the bottom faults instead of corrupting what lies below.
+// Inside the region (bit KERNEL_STACK_AREA_BIT set, which nothing else in
+// the kernel half has), bit KERNEL_STACK_SHIFT of an address is set
+// exactly when it is in a stack: exceptions.s tests it before pushing a
+// frame, and switches to an overflow stack when it is clear.
+pub const KERNEL_STACK_AREA: VirtAddr = 0xFFFF_A000_0000_0000;
+pub const KERNEL_STACK_AREA_BIT: u32 = 45;
+pub const KERNEL_STACK_SHIFT: u32 = 14;
+const KERNEL_STACK_SLOT: u64 = 2 * KERNEL_STACK_SIZE as u64;
+// Upper bound on live kernel threads
+const KERNEL_STACK_SLOTS: usize = 4096;
+const _: () = assert!(KERNEL_STACK_SIZE == 1 << KERNEL_STACK_SHIFT);
+const _: () = assert!(KERNEL_STACK_AREA & (1 << KERNEL_STACK_AREA_BIT) != 0);
+
+// Which stack slots are taken; stacks can be freed from the scheduler
+static STACK_SLOTS: IrqSafeMutex<[u64; KERNEL_STACK_SLOTS / 64]> = IrqSafeMutex::new([0; KERNEL_STACK_SLOTS / 64]);
+
+// SPSR for a new kernel thread: EL1h, all interrupts unmasked
+const SPSR_EL1H: u64 = 0b0101;
+// SPSR for a new user thread: EL0t, all interrupts unmasked
+const SPSR_EL0T: u64 = 0b0000;
+
+/// Longest thread name kept, in bytes.
+pub const THREAD_NAME_LEN: usize = 24;
+/// "process/name", as diagnostics print a thread.
+pub const THREAD_LABEL_LEN: usize = 2 * THREAD_NAME_LEN + 1;
+
+/// A string stored inline, cut short at a character boundary when too
+/// long. Thread names live in these so they can be renamed without the
+/// heap and copied out from under the scheduler lock.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct InlineName<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> InlineName<N> {
+    pub fn new(name: &str) -> Self {
+        let mut inline = Self { bytes: [0; N], len: 0 };
+        inline.push_str(name);
+        inline
+    }
+    
+    /// Append what fits of `s`.
+    pub fn push_str(&mut self, s: &str) {
+        let mut take = s.len().min(N - self.len);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.bytes[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+    }
+    
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are ever copied in
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("?")
+    }
+}
+
+// Padding and width work as for &str, so names line up in tables
+impl<const N: usize> fmt::Display for InlineName<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for InlineName<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+pub type ThreadName = InlineName<THREAD_NAME_LEN>;
+pub type ThreadLabel = InlineName<THREAD_LABEL_LEN>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+/// A kernel stack: a guarded slot in the stack area, or with the MMU off
+/// a run of contiguous physical frames.
+pub struct KernelStack {
+    base: NonNull<u8>,
+    slot: Option<usize>,
+}
+
+// The stack memory is owned exclusively by its thread
+unsafe impl Send for KernelStack {}
+
+impl KernelStack {
+    pub fn allocate() -> Option<Self> {
+        if !crate::memory::mmu::MemoryManagementUnit::is_enabled() {
+            let base = allocate_frames(KERNEL_STACK_FRAMES)?;
+            return Some(Self { base, slot: None });
+        }
+        
+        let slot = {
+            let mut slots = STACK_SLOTS.lock();
+            let (word, bits) = slots.iter_mut().enumerate().find(|(_, bits)| **bits != u64::MAX)?;
+            let bit = bits.trailing_ones() as usize;
+            *bits |= 1 << bit;
+            word * 64 + bit
+        };
+        let base = KERNEL_STACK_AREA + slot as u64 * KERNEL_STACK_SLOT + KERNEL_STACK_SIZE as u64;
+        let stack = Self { base: NonNull::new(base as *mut u8)?, slot: Some(slot) };
+        // Dropping a half-mapped stack frees what was mapped
+        crate::memory::mmu::map_kernel_range(base, KERNEL_STACK_SIZE as u64).ok()?;
+        Some(stack)
+    }
+    
+    pub fn top(&self) -> usize {
+        self.base.as_ptr() as usize + KERNEL_STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        match self.slot {
+            Some(slot) => {
+                crate::memory::mmu::unmap_kernel_range(self.base.as_ptr() as u64, KERNEL_STACK_SIZE as u64);
+                STACK_SLOTS.lock()[slot / 64] &= !(1 << (slot % 64));
+            }
+            None => deallocate_frames(self.base, KERNEL_STACK_FRAMES),
+        }
+    }
+}
+
+/// For an address in the kernel stack area, `[base, top)` of the stack in
+/// its slot, whether the address is in the stack or in the guard below.
+pub fn stack_bounds(addr: VirtAddr) -> Option<(VirtAddr, VirtAddr)> {
+    let end = KERNEL_STACK_AREA + KERNEL_STACK_SLOTS as u64 * KERNEL_STACK_SLOT;
+    if !(KERNEL_STACK_AREA..end).contains(&addr) {
+        return None;
+    }
+    let base = (addr & !(KERNEL_STACK_SLOT - 1)) + KERNEL_STACK_SIZE as u64;
+    Some((base, base + KERNEL_STACK_SIZE as u64))
+}
+
+/// Whether `addr` is in the unmapped guard below a kernel stack.
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    stack_bounds(addr).is_some_and(|(base, _)| addr < base)
+}
+
+/// Physically contiguous frames allocated on behalf of a thread.
+pub struct PageRun {
+    pub base: NonNull<u8>,
+    pub frames: usize,
+}
+
+unsafe impl Send for PageRun {}
+
+impl Drop for PageRun {
+    fn drop(&mut self) {
+        deallocate_frames(self.base, self.frames);
+    }
+}
+
+// Executable images are loaded between a null guard and the mmap area
+pub const USER_IMAGE_BASE: VirtAddr = 0x0000_0000_0001_0000;
+pub const USER_IMAGE_END: VirtAddr = USER_MMAP_BASE;
+
+// Anonymous mappings (mmap) are placed from here upwards
+pub const USER_MMAP_BASE: VirtAddr = 0x0000_0010_0000_0000;
+pub const USER_MMAP_END: VirtAddr = 0x0000_0080_0000_0000;
+
+/// A user address space owned by a thread, destroyed along with it.
+pub struct AddressSpace {
+    vmm: Option<VirtualMemoryManager>,
+    // Next free address for anonymous mappings; never reused
+    mmap_next: VirtAddr,
+    // Anonymous mappings stay below this: the stack, once there is one
+    mmap_end: VirtAddr,
+    // Created by fork rather than exec
+    forked: bool,
+    // Laid out at random (see aslr.rs)
+    randomized: bool,
+    cow: CowCounters,
+}
+
+impl AddressSpace {
+    pub fn new(vmm: VirtualMemoryManager) -> Self {
+        Self::with_layout(vmm, &Layout::FIXED)
+    }
+    
+    /// An empty space whose anonymous mappings start at `layout.mmap_base`.
+    pub fn with_layout(vmm: VirtualMemoryManager, layout: &Layout) -> Self {
+        Self {
+            vmm: Some(vmm),
+            mmap_next: layout.mmap_base,
+            mmap_end: USER_MMAP_END,
+            forked: false,
+            randomized: layout.randomized,
+            cow: CowCounters::default(),
+        }
+    }
+    
+    pub fn is_forked(&self) -> bool {
+        self.forked
+    }
+    
+    pub fn is_randomized(&self) -> bool {
+        self.randomized
+    }
+    
+    pub fn cow_counters(&self) -> CowCounters {
+        self.cow
+    }
+    
+    pub fn vmm(&mut self) -> &mut VirtualMemoryManager {
+        self.vmm.as_mut().expect("Address space already destroyed")
+    }
+    
+    /// Map `pages` fresh zeroed pages at an address of the kernel's choosing.
+    pub fn map_anonymous(&mut self, pages: usize, flags: PageFlags) -> Result<VirtAddr, &'static str> {
+        let len = (pages * PAGE_SIZE) as VirtAddr;
+        if pages == 0 || self.mmap_next + len > self.mmap_end {
+            return Err("Out of user address space");
+        }
+        let base = self.mmap_next;
+        self.populate(base, pages, flags)?;
+        self.mmap_next += len;
+        Ok(base)
+    }
+    
+    /// Map a stack of `pages` fresh zeroed pages ending at `top`, above
+    /// every anonymous mapping, which from then on stay below it.
+    pub fn map_stack(&mut self, top: VirtAddr, pages: usize, flags: PageFlags) -> Result<VirtAddr, &'static str> {
+        let len = (pages * PAGE_SIZE) as VirtAddr;
+        if pages == 0 || !top.is_multiple_of(PAGE_SIZE as VirtAddr) || top > self.mmap_end
+            || top.checked_sub(len).is_none_or(|base| base < self.mmap_next)
+        {
+            return Err("Stack outside the free mmap area");
+        }
+        let base = top - len;
+        self.populate(base, pages, flags)?;
+        self.mmap_end = base;
+        Ok(base)
+    }
+    
+    /// Map `pages` fresh zeroed pages at `base` in the image window, for
+    /// the ELF loader. The range must be page aligned and unmapped.
+    pub fn map_fixed(&mut self, base: VirtAddr, pages: usize, flags: PageFlags) -> Result<(), &'static str> {
+        let end = base.checked_add((pages * PAGE_SIZE) as VirtAddr);
+        if pages == 0 || !base.is_multiple_of(PAGE_SIZE as VirtAddr) || base < USER_IMAGE_BASE
+            || end.is_none_or(|end| end > USER_IMAGE_END)
+        {
+            return Err("Outside the user image window");
+        }
+        self.populate(base, pages, flags)
+    }
+    
+    /// Undo `map_fixed`.
+    pub fn unmap_fixed(&mut self, base: VirtAddr, pages: usize) -> Result<(), &'static str> {
+        if !base.is_multiple_of(PAGE_SIZE as VirtAddr) || !(USER_IMAGE_BASE..USER_IMAGE_END).contains(&base) {
+            return Err("Not an image mapping");
+        }
+        for page in 0..pages {
+            self.vmm().unmap_frame(base + (page * PAGE_SIZE) as VirtAddr)?;
+        }
+        Ok(())
+    }
+    
+    /// Duplicate the address space for fork. Every page is shared with the
+    /// child copy-on-write: read-only in both, copied by the first write.
+    /// Shared mappings become private in the child.
+    pub fn fork(&mut self) -> Result<AddressSpace, &'static str> {
+        let vmm = VirtualMemoryManager::new_user().ok_or("Out of address spaces")?;
+        let mut child = AddressSpace::new(vmm);
+        child.mmap_next = self.mmap_next;
+        child.mmap_end = self.mmap_end;
+        child.randomized = self.randomized;
+        child.forked = true;
+        
+        let mut pages: Vec<(VirtAddr, PhysAddr, PageFlags)> = Vec::new();
+        let mut marked = 0;
+        self.vmm().for_each_page(|virt, entry| {
+            let mut flags = entry.flags();
+            if !flags.contains(PageFlags::COW) {
+                flags |= PageFlags::COW;
+                if !flags.contains(PageFlags::READ_ONLY) {
+                    flags |= PageFlags::READ_ONLY | PageFlags::COW_WRITE;
+                }
+                *entry = entry.with_flags(flags);
+                marked += 1;
+            }
+            pages.push((virt, entry.physical_addr(), flags));
+        });
+        // Stale writable translations would let the parent write the shared frames
+        self.vmm().flush_all();
+        self.cow.marked += marked;
+        
+        for &(virt, phys, flags) in &pages {
+            let frame = NonNull::new(phys_to_virt(phys) as *mut u8).ok_or("Null frame")?;
+            child.vmm().map_frame(virt, frame, flags)?;
+        }
+        child.cow.marked = pages.len() as u64;
+        fork::record_fork(marked + pages.len() as u64);
+        Ok(child)
+    }
+    
+    /// Resolve a write fault at `virt` on a copy-on-write page: the last
+    /// mapping takes the frame over, anyone else gets a private copy.
+    /// Ok(false) if the fault was not a copy-on-write one.
+    pub fn resolve_cow_fault(&mut self, virt: VirtAddr) -> Result<bool, &'static str> {
+        let page = virt & !(PAGE_SIZE as VirtAddr - 1);
+        let Some(entry) = self.vmm().leaf_entry(page).filter(|entry| entry.is_valid()) else {
+            return Ok(false);
+        };
+        let flags = entry.flags();
+        if !flags.contains(PageFlags::COW | PageFlags::COW_WRITE) {
+            return Ok(false);
+        }
+        let writable = flags - PageFlags::COW - PageFlags::COW_WRITE - PageFlags::READ_ONLY;
+        let phys = entry.physical_addr();
+        let frame = NonNull::new(phys_to_virt(phys) as *mut u8).ok_or("Null frame")?;
+        
+        if frame_refcount(frame) == 1 {
+            *entry = entry.with_flags(writable);
+            self.vmm().flush_page(page);
+            self.cow.reused += 1;
+            fork::record_cow_reuse();
+            return Ok(true);
+        }
+        
+        let copy = allocate_frame().ok_or("Out of memory")?;
+        unsafe { core::ptr::copy_nonoverlapping(frame.as_ptr(), copy.as_ptr(), PAGE_SIZE) };
+        let remapped = self.vmm().unmap_frame(page).and_then(|_| self.vmm().map_frame(page, copy, writable));
+        // The mapping holds the only reference from here on
+        deallocate_frame(copy);
+        remapped?;
+        self.cow.copied += 1;
+        fork::record_cow_copy();
+        Ok(true)
+    }
+    
+    // Back each page with its own zeroed frame, leaving nothing mapped on failure
+    fn populate(&mut self, base: VirtAddr, pages: usize, flags: PageFlags) -> Result<(), &'static str> {
+        for page in 0..pages {
+            let virt = base + (page * PAGE_SIZE) as VirtAddr;
+            let mapped = allocate_zeroed_frame().ok_or("Out of memory").and_then(|frame| {
+                let mapped = self.vmm().map_frame(virt, frame, flags);
+                // The mapping holds the only reference from here on
+                deallocate_frame(frame);
+                mapped
+            });
+            if let Err(e) = mapped {
+                for done in 0..page {
+                    let _ = self.vmm().unmap_frame(base + (done * PAGE_SIZE) as VirtAddr);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    
+    /// Map `pages` frames the kernel already holds, starting at `first`,
+    /// at an address of the kernel's choosing. Each mapping takes its own
+    /// reference, so the kernel's stays valid.
+    pub fn map_shared(&mut self, first: NonNull<u8>, pages: usize, flags: PageFlags) -> Result<VirtAddr, &'static str> {
+        let len = (pages * PAGE_SIZE) as VirtAddr;
+        if pages == 0 || self.mmap_next + len > self.mmap_end {
+            return Err("Out of user address space");
+        }
+        let base = self.mmap_next;
+        
+        for page in 0..pages {
+            let frame = unsafe { NonNull::new_unchecked(first.as_ptr().add(page * PAGE_SIZE)) };
+            if let Err(e) = self.vmm().map_frame(base + (page * PAGE_SIZE) as VirtAddr, frame, flags) {
+                let _ = self.unmap(base, page);
+                return Err(e);
+            }
+        }
+        self.mmap_next += len;
+        Ok(base)
+    }
+    
+    /// Map the given frames, in order, at an address of the kernel's
+    /// choosing. Each mapping takes its own reference.
+    pub fn map_frames(&mut self, frames: &[NonNull<u8>], flags: PageFlags) -> Result<VirtAddr, &'static str> {
+        let len = (frames.len() * PAGE_SIZE) as VirtAddr;
+        if frames.is_empty() || self.mmap_next + len > self.mmap_end {
+            return Err("Out of user address space");
+        }
+        let base = self.mmap_next;
+        self.map_frames_at(base, frames, flags)?;
+        self.mmap_next += len;
+        Ok(base)
+    }
+    
+    /// Map frames back to back from `base`, which must be unmapped (say,
+    /// detached by `take_frames`). Leaves nothing mapped on failure.
+    pub fn map_frames_at(&mut self, base: VirtAddr, frames: &[NonNull<u8>], flags: PageFlags) -> Result<(), &'static str> {
+        for (page, &frame) in frames.iter().enumerate() {
+            if let Err(e) = self.vmm().map_frame(base + (page * PAGE_SIZE) as VirtAddr, frame, flags) {
+                for done in 0..page {
+                    let _ = self.vmm().unmap_frame(base + (done * PAGE_SIZE) as VirtAddr);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    
+    /// Take a reference to each frame behind `pages` user pages from
+    /// `base`, for handing them to another address space. With `detach`
+    /// the pages must be writable and mapped nowhere else, and are
+    /// unmapped here; copy-on-write pages are copied first.
+    pub fn take_frames(&mut self, base: VirtAddr, pages: usize, detach: bool) -> Result<Vec<NonNull<u8>>, &'static str> {
+        if pages == 0 || !base.is_multiple_of(PAGE_SIZE as VirtAddr) {
+            return Err("Bad page range");
+        }
+        
+        // Check every page before unmapping any
+        let mut frames = Vec::with_capacity(pages);
+        for page in 0..pages {
+            let virt = base + (page * PAGE_SIZE) as VirtAddr;
+            if detach {
+                self.resolve_cow_fault(virt)?;
+            }
+            let entry = self.vmm().leaf_entry(virt).filter(|entry| entry.is_valid()).ok_or("Page not mapped")?;
+            let flags = entry.flags();
+            let phys = entry.physical_addr();
+            if !flags.contains(PageFlags::USER) {
+                return Err("Page not mapped");
+            }
+            if detach && (flags.contains(PageFlags::READ_ONLY) || rmap::map_count(phys) != 1) {
+                return Err("Page is shared or read-only");
+            }
+            frames.push(NonNull::new(phys_to_virt(phys) as *mut u8).ok_or("Null frame")?);
+        }
+        
+        for (taken, &frame) in frames.iter().enumerate() {
+            if let Err(e) = frame_get(frame) {
+                frames[..taken].iter().for_each(|&frame| deallocate_frame(frame));
+                return Err(e);
+            }
+        }
+        if detach {
+            for page in 0..pages {
+                self.vmm().unmap_frame(base + (page * PAGE_SIZE) as VirtAddr)?;
+            }
+        }
+        Ok(frames)
+    }
+    
+    /// Unmap `pages` pages from `base`, freeing frames no one else maps.
+    pub fn unmap(&mut self, base: VirtAddr, pages: usize) -> Result<(), &'static str> {
+        if !base.is_multiple_of(PAGE_SIZE as VirtAddr) || base < USER_MMAP_BASE {
+            return Err("Not an anonymous mapping");
+        }
+        for page in 0..pages {
+            self.vmm().unmap_frame(base + (page * PAGE_SIZE) as VirtAddr)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if let Some(vmm) = self.vmm.take() {
+            vmm.destroy();
+        }
+    }
+}
+
+pub struct Thread {
+    pub id: ThreadId,
+    pub name: ThreadName,
+    // Service the thread works for, when another running thread started
+    // it; diagnostics then call it "process/name"
+    pub process: Option<ThreadName>,
+    pub priority: Priority,
+    // Raised to a waiter's priority while holding a contended SleepMutex
+    pub inherited_priority: Option<Priority>,
+    pub state: ThreadState,
+    // Saved exception frame while the thread is not running
+    pub context: *mut ExceptionContext,
+    // CPU time consumed, in timer ticks
+    pub ticks: u64,
+    // The same measured at each switch, in counter ticks, up to its last
+    // switch out
+    pub cpu_time: u64,
+    // PMU events counted while running, up to its last switch out
+    pub pmu: PmuCounts,
+    // OOM badness adjustment, -1000 (never pick) .. 1000 (pick first)
+    pub oom_score_adj: i16,
+    // Set by the process manager for threads that must survive OOM
+    pub oom_protected: bool,
+    // Trace ID of the IPC request being handled, TRACE_ID_NONE if none
+    pub trace_id: u64,
+    // Thread that spawned this one; None for the boot thread
+    pub parent: Option<ThreadId>,
+    // Port told when a child of this thread is killed by a fault
+    pub fault_port: Option<PortId>,
+    // Resources granted beyond the thread's own memory
+    pub capabilities: Vec<Capability>,
+    // Syscalls the thread may make, None for all; only ever narrowed
+    pub syscall_filter: Option<SyscallFilter>,
+    // Submission/completion ring, once set up
+    pub io_ring: Option<Arc<IoRing>>,
+    // Memory charged to the thread, released when it is reaped
+    pages: Vec<PageRun>,
+    // User mappings, torn down when the thread is reaped
+    address_space: Option<AddressSpace>,
+    // None for the boot thread, which runs on the boot stack
+    stack: Option<KernelStack>,
+}
+
+// Threads are only touched under the scheduler lock
+unsafe impl Send for Thread {}
+
+impl Thread {
+    /// The thread that is already running when the scheduler starts.
+    pub fn boot(id: ThreadId, name: &str, priority: Priority) -> Self {
+        Self {
+            id,
+            name: ThreadName::new(name),
+            process: None,
+            priority,
+            inherited_priority: None,
+            state: ThreadState::Running,
+            context: core::ptr::null_mut(),
+            ticks: 0,
+            cpu_time: 0,
+            pmu: PmuCounts::ZERO,
+            oom_score_adj: 0,
+            oom_protected: false,
+            trace_id: crate::trace::TRACE_ID_NONE,
+            parent: None,
+            fault_port: None,
+            capabilities: Vec::new(),
+            syscall_filter: None,
+            io_ring: None,
+            pages: Vec::new(),
+            address_space: None,
+            stack: None,
+        }
+    }
+    
+    /// A new kernel thread whose first run enters `entry` via the trampoline.
+    pub fn new_kernel(
+        id: ThreadId,
+        name: &str,
+        priority: Priority,
+        entry: fn(),
+        trampoline: extern "C" fn(usize) -> !,
+    ) -> Result<Self, &'static str> {
+        let stack = KernelStack::allocate().ok_or("Out of memory for kernel stack")?;
+        
+        // Exception return lands in the trampoline with entry in x0 and the
+        // stack pointer at the top of the new stack
+        let frame = (stack.top() - size_of::<ExceptionContext>()) as *mut ExceptionContext;
+        unsafe {
+            frame.write(ExceptionContext {
+                spsr_el1: SPSR_EL1H | crate::uaccess::thread_spsr_bits(),
+                elr_el1: trampoline as usize as u64,
+                x0: entry as usize as u64,
+                ..ExceptionContext::default()
+            });
+        }
+        
+        Ok(Self {
+            id,
+            name: ThreadName::new(name),
+            process: None,
+            priority,
+            inherited_priority: None,
+            state: ThreadState::Ready,
+            context: frame,
+            ticks: 0,
+            cpu_time: 0,
+            pmu: PmuCounts::ZERO,
+            oom_score_adj: 0,
+            oom_protected: false,
+            trace_id: crate::trace::TRACE_ID_NONE,
+            parent: None,
+            fault_port: None,
+            capabilities: Vec::new(),
+            syscall_filter: None,
+            io_ring: None,
+            pages: Vec::new(),
+            address_space: None,
+            stack: Some(stack),
+        })
+    }
+    
+    /// A new user thread whose first run enters `space` at `entry` in EL0,
+    /// with its stack pointer at `sp`.
+    pub fn new_user(
+        id: ThreadId,
+        name: &str,
+        priority: Priority,
+        space: AddressSpace,
+        entry: VirtAddr,
+        sp: VirtAddr,
+    ) -> Result<Self, &'static str> {
+        let stack = KernelStack::allocate().ok_or("Out of memory for kernel stack")?;
+        
+        // The kernel stack only takes the thread's exceptions; the first
+        // return from one drops to EL0
+        let frame = (stack.top() - size_of::<ExceptionContext>()) as *mut ExceptionContext;
+        unsafe {
+            frame.write(ExceptionContext {
+                spsr_el1: SPSR_EL0T,
+                elr_el1: entry,
+                sp_el0: sp,
+                ..ExceptionContext::default()
+            });
+        }
+        
+        Ok(Self {
+            id,
+            name: ThreadName::new(name),
+            process: None,
+            priority,
+            inherited_priority: None,
+            state: ThreadState::Ready,
+            context: frame,
+            ticks: 0,
+            cpu_time: 0,
+            pmu: PmuCounts::ZERO,
+            oom_score_adj: 0,
+            oom_protected: false,
+            trace_id: crate::trace::TRACE_ID_NONE,
+            parent: None,
+            fault_port: None,
+            capabilities: Vec::new(),
+            syscall_filter: None,
+            io_ring: None,
+            pages: Vec::new(),
+            address_space: Some(space),
+            stack: Some(stack),
+        })
+    }
+    
+    /// How diagnostics name the thread: "process/name", or just its name
+    /// when it belongs to no other.
+    pub fn label(&self) -> ThreadLabel {
+        let mut label = ThreadLabel::new("");
+        if let Some(process) = self.process.filter(|process| *process != self.name) {
+            label.push_str(process.as_str());
+            label.push_str("/");
+        }
+        label.push_str(self.name.as_str());
+        label
+    }
+    
+    /// The priority the scheduler uses: its own or an inherited one.
+    pub fn effective_priority(&self) -> Priority {
+        self.inherited_priority.map_or(self.priority, |inherited| inherited.max(self.priority))
+    }
+    
+    pub fn has_own_stack(&self) -> bool {
+        self.stack.is_some()
+    }
+    
+    /// Resident frames: the kernel stack plus charged pages.
+    pub fn rss_frames(&self) -> usize {
+        let stack = if self.stack.is_some() { KERNEL_STACK_FRAMES } else { 0 };
+        stack + self.pages.iter().map(|run| run.frames).sum::<usize>()
+    }
+    
+    /// Give the thread an address space; any previous one is destroyed.
+    pub fn set_address_space(&mut self, space: AddressSpace) {
+        self.address_space = Some(space);
+    }
+    
+    pub fn address_space(&mut self) -> Option<&mut AddressSpace> {
+        self.address_space.as_mut()
+    }
+    
+    pub fn take_address_space(&mut self) -> Option<AddressSpace> {
+        self.address_space.take()
+    }
+    
+    pub fn cow_counters(&self) -> Option<CowCounters> {
+        self.address_space.as_ref().map(AddressSpace::cow_counters)
+    }
+    
+    pub fn charge(&mut self, run: PageRun) {
+        self.pages.push(run);
+    }
+    
+    /// Give back a charged run, freeing its frames. False if not charged here.
+    pub fn uncharge(&mut self, base: NonNull<u8>) -> bool {
+        match self.pages.iter().position(|run| run.base == base) {
+            Some(index) => {
+                self.pages.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
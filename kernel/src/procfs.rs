@@ -0,0 +1,58 @@
+// Kernel state as read-only generated files (like /proc)
+//
+// Each entry renders its whole content on every read; readers page
+// through it with an offset.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Renders an entry's current content.
+pub type ProcRead = fn(&mut Vec<u8>);
+
+struct ProcEntry {
+    path: String,
+    read: ProcRead,
+}
+
+static ENTRIES: Mutex<Vec<ProcEntry>> = Mutex::new(Vec::new());
+
+/// Register a file such as "lastlog" (seen as /proc/lastlog).
+pub fn register(path: &str, read: ProcRead) -> Result<(), &'static str> {
+    let mut entries = ENTRIES.lock();
+    if entries.iter().any(|entry| entry.path == path) {
+        return Err("Proc path already registered");
+    }
+    entries.push(ProcEntry { path: String::from(path), read });
+    crate::println!("Procfs: Registered /proc/{}", path);
+    Ok(())
+}
+
+/// Copy content starting at `offset` into `buf`; 0 means end of file.
+pub fn read(path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+    let content = read_all(path)?;
+    let rest = content.get(offset..).unwrap_or(&[]);
+    let len = rest.len().min(buf.len());
+    buf[..len].copy_from_slice(&rest[..len]);
+    Ok(len)
+}
+
+/// The whole content of an entry.
+pub fn read_all(path: &str) -> Result<Vec<u8>, &'static str> {
+    let read = ENTRIES
+        .lock()
+        .iter()
+        .find(|entry| entry.path == path)
+        .map(|entry| entry.read)
+        .ok_or("No such file")?;
+    
+    // Rendered outside the lock: entries may take other locks
+    let mut content = Vec::new();
+    read(&mut content);
+    Ok(content)
+}
+
+/// Paths of all registered files.
+pub fn list() -> Vec<String> {
+    ENTRIES.lock().iter().map(|entry| entry.path.clone()).collect()
+}
@@ -0,0 +1,292 @@
+// Persistent kernel log (pstore, after Linux' ramoops)
+//
+// The tail of the kernel log is mirrored into a RAM region the frame
+// allocator never hands out. A warm reset leaves RAM intact, so the next
+// boot finds the previous log there and publishes it as /proc/lastlog,
+// even when a panic or hang left nothing readable on the console. The
+// region is written back from the data cache on panic, and by the idle
+// task whenever the log has grown.
+//
+// The region is the "ramoops" node under /reserved-memory when the device
+// tree has one, otherwise the last PSTORE_DEFAULT_SIZE bytes of RAM. A CRC
+// over the header keeps power-on garbage from being taken for a log.
+// When the region is big enough its last CRASH_ZONE_SIZE bytes are kept
+// out of the log for crash dumps (crashdump.rs).
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::devicetree::{DeviceTree, MemoryRegion};
+use crate::memory::frame_allocator::{self, PAGE_SIZE};
+use crate::memory::paging::phys_to_virt;
+use crate::sync::IrqSafeMutex;
+
+const PSTORE_MAGIC: u32 = 0x5254_5350; // "PSTR"
+const PSTORE_VERSION: u32 = 1;
+const PSTORE_DEFAULT_SIZE: u64 = 32 * 1024;
+pub const CRASH_ZONE_SIZE: usize = 16 * 1024;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PstoreHeader {
+    magic: u32,
+    version: u32,
+    // Bytes of log data following the header
+    size: u32,
+    // Next write offset into the data
+    head: u32,
+    // Nonzero once the data has wrapped around
+    wrapped: u32,
+    // CRC-32 of the fields above
+    crc: u32,
+}
+
+impl PstoreHeader {
+    fn checksum(&self) -> u32 {
+        let fields = [self.magic, self.version, self.size, self.head, self.wrapped];
+        let mut bytes = [0u8; 20];
+        for (chunk, field) in bytes.chunks_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        crc32(&bytes)
+    }
+}
+
+/// CRC-32 (IEEE 802.3, reflected), as computed by zlib and Python's binascii.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0, bytes)
+}
+
+/// Continue a CRC-32 over more data: `crc32_update(crc32(a), b)` is the
+/// CRC of a followed by b, like zlib's `crc32(b, crc)`.
+pub fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+struct Pstore {
+    header: *mut PstoreHeader,
+    data: *mut u8,
+    size: usize,
+    head: usize,
+    wrapped: bool,
+}
+
+// Only reached through PSTORE
+unsafe impl Send for Pstore {}
+
+impl Pstore {
+    fn append(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            unsafe { write_volatile(self.data.add(self.head), byte) };
+            self.head += 1;
+            if self.head == self.size {
+                self.head = 0;
+                self.wrapped = true;
+            }
+        }
+        
+        // Header last, so it never points past data that is not there yet
+        let mut header = PstoreHeader {
+            magic: PSTORE_MAGIC,
+            version: PSTORE_VERSION,
+            size: self.size as u32,
+            head: self.head as u32,
+            wrapped: self.wrapped as u32,
+            crc: 0,
+        };
+        header.crc = header.checksum();
+        unsafe { write_volatile(self.header, header) };
+    }
+}
+
+// Physical base and size, set aside before any frame is allocated
+static REGION: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+static PSTORE: IrqSafeMutex<Option<Pstore>> = IrqSafeMutex::new(None);
+static LASTLOG: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+// Log position the region was last written back at
+static FLUSHED_AT: AtomicUsize = AtomicUsize::new(0);
+
+/// Pick the pstore region and keep the frame allocator off it. Must run
+/// before anything (including memtest) writes to free memory.
+pub fn reserve(dt: Option<&DeviceTree>, ram: &[MemoryRegion]) {
+    // reserved-memory children use the 2/2-cell layout on QEMU virt
+    let region = dt
+        .and_then(|dt| dt.find_compatible("ramoops").next())
+        .and_then(|node| node.reg(0))
+        .or_else(|| {
+            let main = ram.iter().max_by_key(|region| region.size)?;
+            Some((main.start + main.size - PSTORE_DEFAULT_SIZE, PSTORE_DEFAULT_SIZE))
+        });
+    let (base, size) = match region {
+        Some(region) => region,
+        None => return,
+    };
+    
+    // It has to be reachable through the linear map
+    let in_ram = ram.iter().any(|r| base >= r.start && base + size <= r.start + r.size);
+    if !in_ram || size <= size_of::<PstoreHeader>() as u64 || size > u32::MAX as u64 {
+        crate::println!("Pstore: Ignoring unusable region 0x{:x} (+0x{:x})", base, size);
+        return;
+    }
+    
+    let page = PAGE_SIZE as u64;
+    let mut addr = base & !(page - 1);
+    while addr < base + size {
+        if let Some(frame) = core::ptr::NonNull::new(phys_to_virt(addr) as *mut u8) {
+            // Frames the allocator does not track are never handed out anyway
+            frame_allocator::claim_frame(frame);
+        }
+        addr += page;
+    }
+    *REGION.lock() = Some((base, size));
+}
+
+// Previous contents of the region, if its header checks out
+fn recover(header: *const PstoreHeader, data: *const u8, size: usize) -> Option<Vec<u8>> {
+    let saved = unsafe { read_volatile(header) };
+    let valid = saved.magic == PSTORE_MAGIC
+        && saved.version == PSTORE_VERSION
+        && saved.size as usize == size
+        && (saved.head as usize) < size
+        && saved.crc == saved.checksum();
+    if !valid {
+        return None;
+    }
+    
+    let head = saved.head as usize;
+    let read = |range: core::ops::Range<usize>| range.map(|i| unsafe { read_volatile(data.add(i)) });
+    let mut log: Vec<u8> = Vec::with_capacity(size);
+    if saved.wrapped != 0 {
+        log.extend(read(head..size));
+    }
+    log.extend(read(0..head));
+    Some(log)
+}
+
+fn read_lastlog(out: &mut Vec<u8>) {
+    if let Some(log) = LASTLOG.lock().as_ref() {
+        out.extend_from_slice(log);
+    }
+}
+
+/// Rescue the previous boot's log, then start mirroring this one. Needs
+/// the heap.
+pub fn init() {
+    let (base, size) = match *REGION.lock() {
+        Some(region) => region,
+        None => return,
+    };
+    let header = phys_to_virt(base) as *mut PstoreHeader;
+    let data = unsafe { header.add(1) } as *mut u8;
+    let capacity = log_size(size as usize) - size_of::<PstoreHeader>();
+    
+    match recover(header, data, capacity) {
+        Some(log) => {
+            crate::println!("Pstore: Recovered {} bytes of log from the previous boot", log.len());
+            *LASTLOG.lock() = Some(log);
+            let _ = crate::procfs::register("lastlog", read_lastlog);
+        }
+        None => crate::println!("Pstore: No previous log found"),
+    }
+    
+    // Seed with what is already in the log ring, holding it so nothing is
+    // printed in between
+    crate::klog::with_log(|log| {
+        let mut store = Pstore { header, data, size: capacity, head: 0, wrapped: false };
+        store.append(&log.tail(capacity));
+        *PSTORE.lock() = Some(store);
+    });
+    crate::println!("Pstore: Mirroring kernel log to 0x{:x} ({} KiB)", base, size / 1024);
+    if let Err(e) = crate::process::idle::register_idle_work("pstore-flush", flush_if_written) {
+        crate::println!("Pstore: Failed to register idle flushing: {}", e);
+    }
+}
+
+// Bytes of the region the log gets; the rest is the crash zone
+fn log_size(size: usize) -> usize {
+    if size >= 2 * CRASH_ZONE_SIZE { size - CRASH_ZONE_SIZE } else { size }
+}
+
+/// The crash zone through the linear map, if the region has room for one.
+pub fn crash_zone() -> Option<(*mut u8, usize)> {
+    let (base, size) = REGION.try_lock().and_then(|region| *region)?;
+    let size = size as usize;
+    (log_size(size) < size).then(|| ((phys_to_virt(base) as usize + log_size(size)) as *mut u8, CRASH_ZONE_SIZE))
+}
+
+/// Copy the last `out.len()` bytes of the mirrored log into `out`, oldest
+/// first, without allocating; returns how many there were. Gives up
+/// rather than wait for the lock, as the panic path must.
+pub fn copy_tail(out: &mut [u8]) -> usize {
+    let Some(pstore) = PSTORE.try_lock() else {
+        return 0;
+    };
+    let Some(store) = pstore.as_ref() else {
+        return 0;
+    };
+    let stored = if store.wrapped { store.size } else { store.head };
+    let len = stored.min(out.len());
+    for (i, byte) in out[..len].iter_mut().enumerate() {
+        let index = (store.head + store.size - len + i) % store.size;
+        *byte = unsafe { read_volatile(store.data.add(index)) };
+    }
+    len
+}
+
+// Idle hook: write the region back whenever the log has grown, so a reset
+// that loses dirty cache lines still finds what was printed before it
+fn flush_if_written() -> bool {
+    let written = crate::klog::with_log(|log| log.written());
+    if FLUSHED_AT.swap(written, Ordering::Relaxed) != written {
+        flush();
+    }
+    false
+}
+
+/// Mirror console output; called by the log ring with its lock held.
+pub fn write(bytes: &[u8]) {
+    if let Some(mut pstore) = PSTORE.try_lock() {
+        if let Some(store) = pstore.as_mut() {
+            store.append(bytes);
+        }
+    }
+}
+
+/// The log as the next boot would recover it.
+pub fn snapshot() -> Option<Vec<u8>> {
+    let pstore = PSTORE.lock();
+    let store = pstore.as_ref()?;
+    recover(store.header, store.data, store.size)
+}
+
+/// Push the region out of the data cache so it survives a reset that does
+/// not write back dirty lines. Called on panic.
+pub fn flush() {
+    let (base, size) = match REGION.try_lock().and_then(|region| *region) {
+        Some(region) => region,
+        None => return,
+    };
+    unsafe {
+        let ctr: u64;
+        asm!("mrs {}, ctr_el0", out(reg) ctr);
+        let line = 4u64 << ((ctr >> 16) & 0xF);  // DminLine, in words
+        let start = phys_to_virt(base) & !(line - 1);
+        let end = phys_to_virt(base + size);
+        let mut addr = start;
+        while addr < end {
+            asm!("dc cvac, {}", in(reg) addr);
+            addr += line;
+        }
+        asm!("dsb sy");
+    }
+}
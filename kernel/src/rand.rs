@@ -0,0 +1,345 @@
+// Kernel random numbers
+//
+// `get_random_bytes` reads a ChaCha20 keystream. After every request the
+// generator replaces its key with more keystream, so a later look at its
+// state says nothing about what it already handed out. Entropy goes into
+// the key: the counter and the loader's /chosen seeds at boot, the RNDR
+// instruction on every request where the CPU has it, and registered
+// hardware sources (virtio-rng) when one appears and again after every
+// RESEED_BYTES of output, from the idle task since they may poll a device.
+//
+// Before there is anything to hold the generator (KASLR runs before
+// rust_main, the stack canary first thing in it), `early_u64` mixes what
+// is at hand.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::ktest::kernel_test;
+use crate::sync::IrqSafeMutex;
+
+// Output between reseeds from the hardware sources
+const RESEED_BYTES: u64 = 1024 * 1024;
+// Bytes taken from each source per reseed
+const SOURCE_BYTES: usize = 32;
+const MAX_SOURCES: usize = 4;
+
+// ID_AA64ISAR0_EL1.RNDR
+const ISAR0_RNDR_SHIFT: u64 = 60;
+const ID_FIELD_MASK: u64 = 0xF;
+
+// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+
+/// Fills a buffer from a hardware source, returning how much it wrote.
+/// Called from thread context (the idle task or a driver's probe).
+pub type SourceFill = fn(&mut [u8]) -> usize;
+
+#[derive(Copy, Clone)]
+struct Source {
+    name: &'static str,
+    fill: SourceFill,
+}
+
+struct Generator {
+    key: [u32; 8],
+    counter: u64,
+    // Output since the sources last reseeded it
+    since_reseed: u64,
+    reseeds: u64,
+    output: u64,
+}
+
+/// What the generator has done so far.
+#[derive(Copy, Clone, Debug)]
+pub struct RandomStats {
+    pub rndr: bool,
+    pub sources: usize,
+    pub reseeds: u64,
+    pub output: u64,
+}
+
+static GENERATOR: IrqSafeMutex<Generator> = IrqSafeMutex::new(Generator {
+    key: [0; 8],
+    counter: 0,
+    since_reseed: 0,
+    reseeds: 0,
+    output: 0,
+});
+static SOURCES: Mutex<[Option<Source>; MAX_SOURCES]> = Mutex::new([None; MAX_SOURCES]);
+static HAS_RNDR: AtomicBool = AtomicBool::new(false);
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// One ChaCha20 block (RFC 8439) for `key`, block counter and nonce.
+pub fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    input[13..].copy_from_slice(nonce);
+    
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, initial) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(initial);
+    }
+    state
+}
+
+impl Generator {
+    fn block(&mut self) -> [u32; 16] {
+        let counter = self.counter;
+        self.counter += 1;
+        chacha20_block(&self.key, counter as u32, &[(counter >> 32) as u32, 0, 0])
+    }
+    
+    // Fresh key from the keystream: the old one is gone
+    fn rekey(&mut self) {
+        let block = self.block();
+        self.key.copy_from_slice(&block[..8]);
+    }
+    
+    fn mix(&mut self, entropy: &[u8]) {
+        for (i, chunk) in entropy.chunks(4).enumerate() {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.key[i % 8] ^= u32::from_le_bytes(word);
+            // Past 32 bytes, stir before folding in more
+            if i % 8 == 7 {
+                self.rekey();
+            }
+        }
+        self.rekey();
+    }
+    
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(64) {
+            let block = self.block();
+            for (bytes, word) in chunk.chunks_mut(4).zip(block) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        self.rekey();
+        self.output += buf.len() as u64;
+        self.since_reseed += buf.len() as u64;
+    }
+}
+
+// splitmix64's finalizer
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+fn rndr_implemented() -> bool {
+    let isar0: u64;
+    unsafe {
+        asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0);
+    }
+    (isar0 >> ISAR0_RNDR_SHIFT) & ID_FIELD_MASK != 0
+}
+
+/// A value from the RNDR instruction, if the CPU has it and it succeeds.
+pub fn rndr() -> Option<u64> {
+    if !HAS_RNDR.load(Ordering::Relaxed) && !rndr_implemented() {
+        return None;
+    }
+    let (value, ok): (u64, u64);
+    unsafe {
+        // RNDR; Z set when no value was available
+        asm!("mrs {}, s3_3_c2_c4_0", "cset {}, ne", out(reg) value, out(reg) ok, options(nomem, nostack));
+    }
+    (ok != 0).then_some(value)
+}
+
+/// A value from whatever is at hand during early boot: the counter, RNDR
+/// and the loader's /chosen seeds. Needs nothing but the boot tables.
+pub fn early_u64() -> u64 {
+    let mut seed: u64;
+    unsafe {
+        asm!("mrs {}, cntpct_el0", out(reg) seed);
+    }
+    if let Some(value) = rndr() {
+        seed = mix64(seed ^ value);
+    }
+    let chosen = crate::devicetree::device_tree().and_then(|dt| dt.find_by_name("chosen"));
+    for name in ["rng-seed", "kaslr-seed"] {
+        let Some(bytes) = chosen.as_ref().and_then(|chosen| chosen.property(name)) else {
+            continue;
+        };
+        for chunk in bytes.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            seed = mix64(seed ^ u64::from_le_bytes(word));
+        }
+    }
+    mix64(seed)
+}
+
+/// Seed the generator and export /proc/random.
+pub fn init() {
+    let rndr = rndr_implemented();
+    HAS_RNDR.store(rndr, Ordering::Relaxed);
+    let mut seed = [0u8; SOURCE_BYTES];
+    for chunk in seed.chunks_mut(8) {
+        chunk.copy_from_slice(&early_u64().to_le_bytes());
+    }
+    GENERATOR.lock().mix(&seed);
+    
+    if let Err(e) = crate::process::idle::register_idle_work("rand-reseed", reseed_if_due) {
+        crate::println!("Random: {}, hardware sources only read when registered", e);
+    }
+    let _ = crate::procfs::register("random", proc_random);
+    crate::println!("Random: ChaCha20 generator seeded{}", if rndr { ", RNDR mixed into every request" } else { "" });
+}
+
+/// Add a hardware source; it reseeds the generator straight away.
+pub fn register_source(name: &'static str, fill: SourceFill) -> Result<(), &'static str> {
+    {
+        let mut sources = SOURCES.lock();
+        let slot = sources.iter_mut().find(|slot| slot.is_none()).ok_or("Too many entropy sources")?;
+        *slot = Some(Source { name, fill });
+    }
+    crate::println!("Random: Entropy source {} registered", name);
+    reseed();
+    Ok(())
+}
+
+/// Mix SOURCE_BYTES from every hardware source into the generator. Thread
+/// context only. Returns how many bytes went in.
+pub fn reseed() -> usize {
+    let sources = *SOURCES.lock();
+    let mut entropy = Vec::new();
+    for source in sources.iter().flatten() {
+        let mut buf = [0u8; SOURCE_BYTES];
+        let len = (source.fill)(&mut buf);
+        entropy.extend_from_slice(&buf[..len.min(SOURCE_BYTES)]);
+    }
+    if entropy.is_empty() {
+        return 0;
+    }
+    let mut generator = GENERATOR.lock();
+    generator.mix(&entropy);
+    generator.since_reseed = 0;
+    generator.reseeds += 1;
+    entropy.len()
+}
+
+// Idle hook; never more work left over, one reseed is all it does
+fn reseed_if_due() -> bool {
+    if GENERATOR.lock().since_reseed >= RESEED_BYTES {
+        reseed();
+    }
+    false
+}
+
+/// Fill `buf` with random bytes. Safe from any context.
+pub fn get_random_bytes(buf: &mut [u8]) {
+    let mut generator = GENERATOR.lock();
+    if HAS_RNDR.load(Ordering::Relaxed) {
+        let mut hardware = [0u8; 16];
+        for chunk in hardware.chunks_mut(8) {
+            chunk.copy_from_slice(&rndr().unwrap_or(0).to_le_bytes());
+        }
+        generator.mix(&hardware);
+    }
+    generator.fill(buf);
+}
+
+/// A random u64 (`get_random_bytes`).
+pub fn get_random_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    get_random_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+pub fn stats() -> RandomStats {
+    let sources = SOURCES.lock().iter().flatten().count();
+    let generator = GENERATOR.lock();
+    RandomStats {
+        rndr: HAS_RNDR.load(Ordering::Relaxed),
+        sources,
+        reseeds: generator.reseeds,
+        output: generator.output,
+    }
+}
+
+fn proc_random(out: &mut Vec<u8>) {
+    let stats = stats();
+    let mut text = alloc::string::String::new();
+    let _ = write!(text, "sources");
+    if stats.rndr {
+        let _ = write!(text, " rndr");
+    }
+    for source in SOURCES.lock().iter().flatten() {
+        let _ = write!(text, " {}", source.name);
+    }
+    let _ = writeln!(text);
+    let _ = writeln!(text, "reseeds {}", stats.reseeds);
+    let _ = writeln!(text, "output {}", stats.output);
+    out.extend_from_slice(text.as_bytes());
+}
+
+#[kernel_test]
+fn test_random() {
+    
+    crate::println!("Random Test: Testing random number generator...");
+    
+    // RFC 8439 section 2.3.2
+    let key = core::array::from_fn(|i| u32::from_le_bytes([4 * i as u8, 4 * i as u8 + 1, 4 * i as u8 + 2, 4 * i as u8 + 3]));
+    let block = chacha20_block(&key, 1, &[0x0900_0000, 0x4A00_0000, 0]);
+    if block[..4] == [0xE4E7_F110, 0x1559_3BD1, 0x1FDD_0F50, 0xC471_20A3] && block[15] == 0x4E3C_50A2 {
+        crate::println!("Random Test: ✓ ChaCha20 block matches the RFC 8439 test vector");
+    } else {
+        crate::println!("Random Test: ✗ ChaCha20 block starts {:08x?}", &block[..4]);
+    }
+    
+    // Requests never repeat, and odd lengths are filled to the end
+    let before = stats();
+    let mut first = [0u8; 67];
+    let mut second = [0u8; 67];
+    get_random_bytes(&mut first);
+    get_random_bytes(&mut second);
+    let distinct = first != second && first[60..] != [0; 7] && get_random_u64() != get_random_u64();
+    let counted = stats().output == before.output + 2 * 67 + 16;
+    if distinct && counted {
+        crate::println!("Random Test: ✓ Generator output distinct across requests");
+    } else {
+        crate::println!("Random Test: ✗ Generator distinct {} counted {}", distinct, counted);
+    }
+    
+    // Hardware sources, when there are any, feed it
+    let entropy = stats();
+    let mixed = reseed();
+    if entropy.sources == 0 {
+        crate::println!("Random Test: No entropy source (make run RNG=1), RNDR {}", entropy.rndr);
+    } else if mixed > 0 && stats().reseeds == entropy.reseeds + 1 {
+        crate::println!("Random Test: ✓ Reseeded with {} bytes from {} sources, RNDR {}", mixed, entropy.sources, entropy.rndr);
+    } else {
+        crate::println!("Random Test: ✗ Reseed from {} sources took {} bytes", entropy.sources, mixed);
+    }
+    
+    crate::println!("Random Test: Random number test completed");
+}
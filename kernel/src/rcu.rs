@@ -0,0 +1,235 @@
+// Read-copy-update for read-mostly kernel data
+//
+// Readers take no lock: inside a `read_lock()` section they follow
+// `Rcu<T>` pointers and use what they find. A writer builds a new version
+// and publishes it with one pointer store; the old version is freed only
+// after a grace period, once every online CPU has passed a quiescent
+// state, by when no reader can still be looking at it.
+//
+// Quiescent states are a pass through the scheduler and the idle loop,
+// outside any read section. So a read section must not block or yield,
+// and preemption waits for it to end (scheduler::preempt). Deferred work
+// (`call_rcu`) runs from the idle loop, or from the next `call_rcu` or
+// `synchronize` after its grace period.
+//
+// Grace periods are numbered. A callback queued while period n is the
+// latest started needs period n + 1, the first to start after it.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{compiler_fence, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use crate::cpu::{cpu_index, MAX_CPUS};
+use crate::sync::IrqSafeMutex;
+
+// Run callbacks straight from call_rcu once this many are ready
+const CALLBACK_BATCH: usize = 64;
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+// Open read sections on each CPU (they nest)
+static READ_DEPTH: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+// Latest grace period started, latest completed, latest anyone waits for
+static GP_STARTED: AtomicU64 = AtomicU64::new(0);
+static GP_COMPLETED: AtomicU64 = AtomicU64::new(0);
+static GP_REQUESTED: AtomicU64 = AtomicU64::new(0);
+// CPUs yet to pass a quiescent state in the current grace period; 0 when
+// none is in progress
+static QS_PENDING: AtomicU8 = AtomicU8::new(0);
+// Callbacks with the grace period each needs, in queueing order
+static CALLBACKS: IrqSafeMutex<VecDeque<(u64, Callback)>> = IrqSafeMutex::new(VecDeque::new());
+static CALLBACKS_RUN: AtomicU64 = AtomicU64::new(0);
+
+// Mask bits as in the GIC; before it is up only the boot CPU runs
+fn this_cpu() -> u8 {
+    if crate::gic::is_present() { crate::gic::this_cpu() } else { 1 }
+}
+
+fn online_cpus() -> u8 {
+    if crate::gic::is_present() { crate::gic::online_cpus() | this_cpu() } else { 1 }
+}
+
+/// An open read section; `Rcu` contents read through it stay valid until
+/// it is dropped. Tied to the CPU it was taken on.
+pub struct ReadGuard {
+    cpu: usize,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Open a read section. Must not block or yield until the guard drops.
+pub fn read_lock() -> ReadGuard {
+    let cpu = cpu_index();
+    READ_DEPTH[cpu].fetch_add(1, Ordering::Relaxed);
+    compiler_fence(Ordering::SeqCst);
+    ReadGuard { cpu, _not_send: PhantomData }
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        compiler_fence(Ordering::SeqCst);
+        READ_DEPTH[self.cpu].fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Whether this CPU is inside a read section.
+pub fn in_read_section() -> bool {
+    READ_DEPTH[cpu_index()].load(Ordering::Relaxed) != 0
+}
+
+// Begin the next grace period if one is wanted and none is running
+fn start_grace_period() {
+    if GP_REQUESTED.load(Ordering::Acquire) <= GP_STARTED.load(Ordering::Acquire) {
+        return;
+    }
+    if QS_PENDING.compare_exchange(0, online_cpus(), Ordering::AcqRel, Ordering::Acquire).is_ok() {
+        GP_STARTED.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+// Ask for the grace period after the latest started one; returns its number
+fn request_grace_period() -> u64 {
+    let needed = GP_STARTED.load(Ordering::Acquire) + 1;
+    GP_REQUESTED.fetch_max(needed, Ordering::AcqRel);
+    start_grace_period();
+    needed
+}
+
+/// Report that this CPU holds no references from earlier read sections.
+/// Called by the scheduler and the idle loop; cheap when nothing waits.
+pub fn quiescent_state() {
+    let cpu = this_cpu();
+    if QS_PENDING.load(Ordering::Acquire) & cpu == 0 || in_read_section() {
+        return;
+    }
+    if QS_PENDING.fetch_and(!cpu, Ordering::AcqRel) == cpu {
+        GP_COMPLETED.store(GP_STARTED.load(Ordering::Acquire), Ordering::Release);
+        start_grace_period();
+    }
+}
+
+// Run the callbacks whose grace period has completed; whether any wait
+fn run_callbacks() -> bool {
+    let completed = GP_COMPLETED.load(Ordering::Acquire);
+    let (ready, pending): (Vec<Callback>, bool) = {
+        let mut callbacks = CALLBACKS.lock();
+        let count = callbacks.iter().take_while(|(needed, _)| *needed <= completed).count();
+        (callbacks.drain(..count).map(|(_, callback)| callback).collect(), !callbacks.is_empty())
+    };
+    CALLBACKS_RUN.fetch_add(ready.len() as u64, Ordering::Relaxed);
+    for callback in ready {
+        callback();
+    }
+    if pending {
+        start_grace_period();
+    }
+    pending
+}
+
+/// Run `callback` after a grace period, when no reader that could see the
+/// data it frees is left.
+pub fn call_rcu(callback: impl FnOnce() + Send + 'static) {
+    let needed = request_grace_period();
+    let ready = {
+        let mut callbacks = CALLBACKS.lock();
+        callbacks.push_back((needed, Box::new(callback)));
+        callbacks.front().is_some_and(|(needed, _)| *needed <= GP_COMPLETED.load(Ordering::Acquire))
+            && callbacks.len() >= CALLBACK_BATCH
+    };
+    if ready && !in_read_section() {
+        run_callbacks();
+    }
+}
+
+/// Free `value` after a grace period.
+pub fn defer_drop<T: Send + 'static>(value: Box<T>) {
+    call_rcu(move || drop(value));
+}
+
+/// Wait for a full grace period, then run the callbacks it released. From
+/// thread context, outside any read section.
+pub fn synchronize() {
+    if in_read_section() {
+        crate::println!("RCU: synchronize inside a read section");
+        return;
+    }
+    let needed = request_grace_period();
+    while GP_COMPLETED.load(Ordering::Acquire) < needed {
+        crate::process::yield_now();
+        quiescent_state();
+        start_grace_period();
+    }
+    run_callbacks();
+}
+
+/// A pointer readers follow without locking and writers replace whole.
+/// Empty (None) until the first update.
+pub struct Rcu<T> {
+    ptr: AtomicPtr<T>,
+    // Writers copy, modify and publish one at a time
+    writer: Mutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    pub const fn empty() -> Self {
+        Self { ptr: AtomicPtr::new(ptr::null_mut()), writer: Mutex::new(()) }
+    }
+    
+    pub fn new(value: T) -> Self {
+        Self { ptr: AtomicPtr::new(Box::into_raw(Box::new(value))), writer: Mutex::new(()) }
+    }
+    
+    /// The current version, valid as long as the read section.
+    pub fn read<'a>(&self, _guard: &'a ReadGuard) -> Option<&'a T> {
+        unsafe { self.ptr.load(Ordering::Acquire).as_ref() }
+    }
+    
+    /// Build the next version from the current one: `update` returns it
+    /// (None keeps the current one) and a result for the caller. The
+    /// replaced version is freed after a grace period.
+    pub fn update<R>(&self, update: impl FnOnce(Option<&T>) -> (Option<T>, R)) -> R {
+        let _writer = self.writer.lock();
+        let old = self.ptr.load(Ordering::Acquire);
+        let (new, result) = update(unsafe { old.as_ref() });
+        if let Some(new) = new {
+            self.ptr.store(Box::into_raw(Box::new(new)), Ordering::Release);
+            if !old.is_null() {
+                defer_drop(unsafe { Box::from_raw(old) });
+            }
+        }
+        result
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        // Nobody else can hold a reference to an owned Rcu
+        let current = *self.ptr.get_mut();
+        if !current.is_null() {
+            drop(unsafe { Box::from_raw(current) });
+        }
+    }
+}
+
+/// Run ready callbacks when the CPU goes idle, and export /proc/rcu.
+pub fn init() {
+    if let Err(e) = crate::process::idle::register_idle_work("rcu", run_callbacks) {
+        crate::println!("RCU: {}", e);
+    }
+    let _ = crate::procfs::register("rcu", proc_rcu);
+}
+
+fn proc_rcu(out: &mut Vec<u8>) {
+    let mut text = alloc::string::String::new();
+    let _ = writeln!(text, "grace periods {} started {} completed", GP_STARTED.load(Ordering::Relaxed),
+                     GP_COMPLETED.load(Ordering::Relaxed));
+    let _ = writeln!(text, "waiting cpus 0x{:02x}", QS_PENDING.load(Ordering::Relaxed));
+    let _ = writeln!(text, "callbacks {} pending {} run", CALLBACKS.lock().len(), CALLBACKS_RUN.load(Ordering::Relaxed));
+    out.extend_from_slice(text.as_bytes());
+}
@@ -0,0 +1,38 @@
+// Arm semihosting, for reporting to QEMU run with -semihosting
+//
+// A call is HLT #0xF000 with the operation in x0 and the address of its
+// parameter block in x1. With nothing on the other end it is an undefined
+// instruction, so calls are only made when the kernel was booted with
+// `semihosting`. SYS_EXIT is the one used: it ends QEMU with an exit
+// status of the kernel's choosing, which the QEMU virt machine's PSCI
+// power-off cannot carry.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const SYS_EXIT: u64 = 0x18;
+// SYS_EXIT reason: the program finished; the status follows it
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// End QEMU with exit status `status`. Returns only when semihosting is
+/// off (or the host ignored the call).
+pub fn exit(status: u32) {
+    if !is_enabled() {
+        return;
+    }
+    let block = [ADP_STOPPED_APPLICATION_EXIT, status as u64];
+    unsafe {
+        asm!("hlt #0xf000", inout("x0") SYS_EXIT => _, in("x1") block.as_ptr(), options(nostack));
+    }
+}
+
+/// Read `semihosting` from bootargs.
+pub fn init() {
+    ENABLED.store(crate::bootparams::flag("semihosting"), Ordering::Relaxed);
+}
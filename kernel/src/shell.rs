@@ -0,0 +1,966 @@
+// Kernel debug shell on the serial console
+//
+// A kernel thread that sleeps on console input and runs one command per
+// line. Meant for poking at a live kernel under QEMU; it trusts its user
+// completely (peek/poke touch any mapped kernel address).
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::process::ThreadId;
+use crate::process::{kthread_spawn, KTHREAD_DEFAULT_PRIORITY};
+
+const PROMPT: &str = "kshell> ";
+const MAX_LINE: usize = 128;
+const PEEK_MAX_WORDS: usize = 64;
+const DUMP_MAX_BYTES: usize = 1024;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    run: fn(&[&str]) -> Result<(), &'static str>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command { name: "help", usage: "list commands", run: cmd_help },
+    Command { name: "ps", usage: "list threads", run: cmd_ps },
+    Command { name: "top", usage: "[interval_ms] [count]: threads by CPU use, refreshed until a key is pressed", run: cmd_top },
+    Command { name: "tasks", usage: "list async kernel tasks", run: cmd_tasks },
+    Command { name: "mem", usage: "memory usage", run: cmd_mem },
+    Command { name: "date", usage: "wall-clock time and uptime", run: cmd_date },
+    Command { name: "clock", usage: "[real|virtual|advance <ms>]: switch or step the kernel clock", run: cmd_clock },
+    Command { name: "irqstats", usage: "interrupt counts and routing", run: cmd_irqstats },
+    Command { name: "irqlat", usage: "[reset]: interrupt latency and handler times", run: cmd_irqlat },
+    Command { name: "latency", usage: "[iterations]: measure timer interrupt and wakeup latency", run: cmd_latency },
+    Command { name: "irqaffinity", usage: "<irq> <cpumask|auto>: pin an SPI or hand it back to irqbalance", run: cmd_irqaffinity },
+    Command { name: "ports", usage: "list IPC ports", run: cmd_ports },
+    Command { name: "ipctrace", usage: "[on|off|<trace id>]: IPC tracing control and trace dump", run: cmd_ipctrace },
+    Command { name: "trace", usage: "[on|off [points]|clear|<count>]: tracepoint control and event dump", run: cmd_trace },
+    Command { name: "pmu", usage: "PMU event counts per thread", run: cmd_pmu },
+    Command { name: "profile", usage: "[start [bt] [samples]|stop|report [count]]: sampling profiler", run: cmd_profile },
+    Command { name: "lockstat", usage: "[reset]: lock contention by lock and call site (lock-stat builds)", run: cmd_lockstat },
+    Command { name: "textcheck", usage: "verify kernel text and read-only data against their boot checksums", run: cmd_textcheck },
+    Command { name: "audit", usage: "[count]: most recent audit records", run: cmd_audit },
+    Command { name: "sysctl", usage: "[<name> [value]]: list, read or set kernel tunables", run: cmd_sysctl },
+    Command { name: "log", usage: "[pause|resume]: hold back log output while typing (also Ctrl-A p, Ctrl-A r)", run: cmd_log },
+    Command { name: "netconsole", usage: "[off|<config>]: mirror the log over UDP ([sport]@[sip]/[dev],[dport]@<dip>/[dmac])", run: cmd_netconsole },
+    Command { name: "netdump", usage: "[dev]: print frames received since the last netdump", run: cmd_netdump },
+    Command { name: "ls", usage: "[path]: list a directory", run: cmd_ls },
+    Command { name: "cat", usage: "<path>: print a file", run: cmd_cat },
+    Command { name: "run", usage: "[-R] <path> [args]: start a user program from the initramfs or a filesystem (-R: fixed layout)", run: cmd_run },
+    Command { name: "push", usage: "<path> <size> <crc32>: receive a file from tools/push.py", run: cmd_push },
+    Command { name: "pull", usage: "<path> [window]: send a file or /proc entry to tools/pull.py", run: cmd_pull },
+    Command { name: "mounts", usage: "list mounted filesystems", run: cmd_mounts },
+    Command { name: "peek", usage: "[<tid>:]<addr> [words]: dump 32-bit words, a thread's user memory with tid", run: cmd_peek },
+    Command { name: "poke", usage: "[<tid>:]<addr> <value>: write a 32-bit word", run: cmd_poke },
+    Command { name: "dump", usage: "[<tid>:]<addr> [bytes]: hex and ASCII dump", run: cmd_dump },
+    Command { name: "bp", usage: "[<addr>|del <n>]: list or set hardware breakpoints", run: cmd_bp },
+    Command { name: "watch", usage: "[<addr> r|w|rw [len]|del <n>]: list or set watchpoints", run: cmd_watch },
+    Command { name: "mailbox", usage: "[clock <name> [hz]|fb <width> <height> [depth]]: Raspberry Pi firmware clocks and framebuffer", run: cmd_mailbox },
+    Command { name: "reboot", usage: "reset the machine", run: cmd_reboot },
+    Command { name: "poweroff", usage: "turn the machine off (exits QEMU)", run: cmd_poweroff },
+];
+
+/// Start the shell thread.
+pub fn start() {
+    if let Err(e) = kthread_spawn(shell_main, "kshell", KTHREAD_DEFAULT_PRIORITY) {
+        crate::println!("Shell: Failed to start: {}", e);
+    }
+}
+
+fn shell_main() {
+    crate::console::attach_shell();
+    crate::println!("Shell: Kernel shell ready, type 'help'");
+    loop {
+        crate::print!("{}", PROMPT);
+        let line = read_line();
+        let args: Vec<&str> = line.split_ascii_whitespace().collect();
+        let Some((&name, args)) = args.split_first() else { continue };
+        
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => {
+                if let Err(e) = (command.run)(args) {
+                    crate::println!("{}: {}", name, e);
+                }
+            }
+            None => crate::println!("{}: unknown command", name),
+        }
+    }
+}
+
+// Read one line with echo and backspace
+fn read_line() -> String {
+    let mut line = String::new();
+    loop {
+        match crate::console::wait_byte() {
+            b'\r' | b'\n' => {
+                crate::println!();
+                return line;
+            }
+            0x08 | 0x7F if line.pop().is_some() => {
+                crate::print!("\x08 \x08");
+            }
+            byte @ 0x20..=0x7E if line.len() < MAX_LINE => {
+                line.push(byte as char);
+                crate::print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_number(arg: &str) -> Result<u64, &'static str> {
+    let parsed = match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => arg.parse(),
+    };
+    parsed.map_err(|_| "invalid number")
+}
+
+fn cmd_help(_args: &[&str]) -> Result<(), &'static str> {
+    for command in COMMANDS {
+        crate::println!("  {:<12} {}", command.name, command.usage);
+    }
+    Ok(())
+}
+
+fn cmd_ps(_args: &[&str]) -> Result<(), &'static str> {
+    use crate::memory::frame_allocator::PAGE_SIZE;
+    
+    crate::println!("  {:>4}  {:<20} {:<8} {:>4} {:>8} {:>8} {:>5} {:>5}", "ID", "NAME", "STATE", "PRIO", "TICKS",
+                   "RSS(KiB)", "CAPS", "PORTS");
+    for thread in crate::sysinfo::threads() {
+        crate::println!("  {:>4}  {:<20} {:<8} {:>4} {:>8} {:>8} {:>5} {:>5}", thread.id, thread.label,
+                       crate::sysinfo::state_name(thread.state), thread.priority, thread.ticks,
+                       thread.rss_frames * PAGE_SIZE / 1024, thread.capabilities, thread.ports);
+    }
+    Ok(())
+}
+
+fn cmd_top(args: &[&str]) -> Result<(), &'static str> {
+    use crate::interrupts::{counter_frequency, counter_ticks};
+    use crate::memory::frame_allocator::PAGE_SIZE;
+    
+    const USAGE: &str = "usage: top [interval_ms] [count]";
+    const ROWS: usize = 15;
+    // Keyboard checked this often while waiting for the next refresh
+    const POLL_MS: u64 = 50;
+    let interval: u64 = args.first().map(|arg| arg.parse()).transpose().map_err(|_| USAGE)?.unwrap_or(1000).max(POLL_MS);
+    let count: Option<u32> = args.get(1).map(|arg| arg.parse()).transpose().map_err(|_| USAGE)?;
+    crate::println!("  every {} ms, any key stops", interval);
+    
+    let mut before = crate::sysinfo::threads();
+    let mut since = counter_ticks();
+    let mut refreshes = 0;
+    loop {
+        let mut waited = 0;
+        while waited < interval {
+            if crate::console::read_byte().is_some() {
+                return Ok(());
+            }
+            crate::timer::sleep_ms(POLL_MS)?;
+            waited += POLL_MS;
+        }
+        
+        // CPU use over the interval, in tenths of a percent
+        let after = crate::sysinfo::threads();
+        let now = counter_ticks();
+        let elapsed = now.saturating_sub(since).max(1);
+        let mut rows: Vec<_> = after.iter().map(|thread| {
+            let previous = before.iter().find(|old| old.id == thread.id).map_or(0, |old| old.cpu_time);
+            (thread.cpu_time.saturating_sub(previous) * 1000 / elapsed, thread)
+        }).collect();
+        rows.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.id.cmp(&b.1.id)));
+        
+        let system = crate::sysinfo::system();
+        crate::println!("top: up {} s, {} threads, {} of {} KiB free, heap {} KiB used", system.uptime_ns / 1_000_000_000,
+                       system.threads, system.frames_free * PAGE_SIZE / 1024, system.frames_total * PAGE_SIZE / 1024,
+                       system.heap_used / 1024);
+        crate::println!("  {:>4}  {:<20} {:<8} {:>4} {:>6} {:>9} {:>8}", "ID", "NAME", "STATE", "PRIO", "%CPU", "TIME(ms)", "RSS(KiB)");
+        for (permille, thread) in rows.iter().take(ROWS) {
+            crate::println!("  {:>4}  {:<20} {:<8} {:>4} {:>4}.{} {:>9} {:>8}", thread.id, thread.label,
+                           crate::sysinfo::state_name(thread.state), thread.priority, permille / 10, permille % 10,
+                           thread.cpu_time * 1000 / counter_frequency().max(1), thread.rss_frames * PAGE_SIZE / 1024);
+        }
+        
+        before = after;
+        since = now;
+        refreshes += 1;
+        if count.is_some_and(|count| refreshes >= count) {
+            return Ok(());
+        }
+    }
+}
+
+fn cmd_tasks(_args: &[&str]) -> Result<(), &'static str> {
+    // The task being polled right now is not in the list
+    let tasks = crate::executor::task_names();
+    crate::println!("  {} live tasks", crate::executor::task_count());
+    for (id, name) in tasks {
+        crate::println!("  {:>4}  {}", id, name);
+    }
+    Ok(())
+}
+
+fn cmd_date(_args: &[&str]) -> Result<(), &'static str> {
+    use crate::time::{self, Timespec, UtcTime, NSEC_PER_SEC};
+    
+    match time::realtime_ns() {
+        Some(ns) => crate::println!("  {}", UtcTime(ns / NSEC_PER_SEC)),
+        None => crate::println!("  no wall clock (no RTC found)"),
+    }
+    let up = Timespec::from_ns(time::monotonic_ns());
+    crate::println!("  up {}.{:03} s", up.sec, up.nsec / 1_000_000);
+    Ok(())
+}
+
+fn cmd_clock(args: &[&str]) -> Result<(), &'static str> {
+    use crate::time::{self, Timespec};
+    
+    const USAGE: &str = "usage: clock [real|virtual|advance <ms>]";
+    match args {
+        [] => {}
+        ["real"] => time::set_virtual(false),
+        ["virtual"] => time::set_virtual(true),
+        ["advance", ms] => {
+            let ms: u64 = ms.parse().map_err(|_| USAGE)?;
+            time::advance(ms.saturating_mul(1_000_000))?;
+        }
+        _ => return Err(USAGE),
+    }
+    let now = Timespec::from_ns(time::monotonic_ns());
+    let kind = if time::is_virtual() { "virtual" } else { "real" };
+    crate::println!("  {} clock at {}.{:03} s", kind, now.sec, now.nsec / 1_000_000);
+    Ok(())
+}
+
+fn cmd_mem(_args: &[&str]) -> Result<(), &'static str> {
+    use crate::memory::frame_allocator::{frame_allocator_stats, PAGE_SIZE};
+    
+    let (free, total) = frame_allocator_stats();
+    let (heap_used, heap_free) = crate::allocator::heap_stats();
+    let ksm = crate::memory::ksm::stats();
+    crate::println!("  Frames: {} used / {} total ({} KiB free)", total - free, total, free * PAGE_SIZE / 1024);
+    crate::println!("  Heap:   {} used / {} bytes", heap_used, heap_used + heap_free);
+    crate::println!("  KSM:    {} shared frames, {} pages merged", ksm.pages_shared, ksm.pages_merged);
+    crate::println!("  OOM:    {} kills", crate::process::oom::kill_count());
+    Ok(())
+}
+
+fn cmd_textcheck(_args: &[&str]) -> Result<(), &'static str> {
+    use crate::memory::textcheck;
+    
+    let modified = textcheck::verify_all();
+    let stats = textcheck::stats();
+    crate::println!("  {} pages checked, {} modified", stats.pages, modified);
+    crate::println!("  {} background passes, {} modifications found", stats.passes, stats.detections);
+    Ok(())
+}
+
+fn cmd_irqstats(_args: &[&str]) -> Result<(), &'static str> {
+    use crate::gic;
+    
+    let stats = crate::interrupts::get_interrupt_stats();
+    crate::println!("  IRQ {}, sync {}, FIQ {}, SError {}, timer ticks {}", stats.irq_count,
+                   stats.sync_exceptions, stats.fiq_count, stats.serror_count, stats.timer_ticks);
+    if !gic::is_present() {
+        return Ok(());
+    }
+    crate::println!("  {:>5} {:>10}  {:<5} {}", "IRQ", "COUNT", "CPUS", "");
+    for irq in 0..gic::num_irqs() {
+        let count = gic::irq_count(irq);
+        if count == 0 && !gic::has_handler(irq) {
+            continue;
+        }
+        let cpus = if irq < gic::SPI_BASE {
+            String::from("local")
+        } else {
+            alloc::format!("0x{:02x}", gic::affinity(irq).unwrap_or(0))
+        };
+        crate::println!("  {:>5} {:>10}  {:<5} {}", irq, count, cpus,
+                       if gic::is_pinned(irq) { "pinned" } else { "" });
+    }
+    Ok(())
+}
+
+fn cmd_latency(args: &[&str]) -> Result<(), &'static str> {
+    use crate::latency;
+    
+    let iterations = match args {
+        [] => latency::LATENCY_DEFAULT_ITERATIONS,
+        [n] => n.parse().map_err(|_| "usage: latency [iterations]")?,
+        _ => return Err("usage: latency [iterations]"),
+    };
+    let report = latency::run(iterations)?;
+    latency::print_report(&report);
+    Ok(())
+}
+
+fn cmd_irqlat(args: &[&str]) -> Result<(), &'static str> {
+    use crate::gic;
+    use crate::interrupts::{self, HandlerTiming};
+    
+    match args {
+        ["reset"] => {
+            interrupts::reset_interrupt_timing();
+            return Ok(());
+        }
+        [] => {}
+        _ => return Err("usage: irqlat [reset]"),
+    }
+    
+    let frequency = interrupts::counter_frequency().max(1);
+    let ns = |ticks: u64| ticks * 1_000_000_000 / frequency;
+    let row = |name: &str, timing: &HandlerTiming| {
+        if timing.count == 0 {
+            crate::println!("  {:<14} {:>10} {:>10} {:>10} {:>10}", name, 0, "-", "-", "-");
+        } else {
+            crate::println!("  {:<14} {:>10} {:>10} {:>10} {:>10}", name, timing.count,
+                           ns(timing.min), ns(timing.average()), ns(timing.max));
+        }
+    };
+    
+    let stats = interrupts::get_interrupt_stats();
+    crate::println!("  {:<14} {:>10} {:>10} {:>10} {:>10}", "", "COUNT", "MIN ns", "AVG ns", "MAX ns");
+    row("timer latency", &stats.timer_latency);
+    row("irq exception", &stats.irq_time);
+    row("sync exception", &stats.sync_time);
+    if gic::is_present() {
+        for irq in 0..gic::num_irqs() {
+            let timing = gic::handler_timing(irq);
+            if timing.count > 0 {
+                row(&alloc::format!("irq {}", irq), &timing);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cmd_irqaffinity(args: &[&str]) -> Result<(), &'static str> {
+    let (irq, target) = match args {
+        [irq, target] => (parse_number(irq)? as u32, *target),
+        _ => return Err("usage: irqaffinity <irq> <cpumask|auto>"),
+    };
+    if target == "auto" {
+        crate::gic::unpin(irq)?;
+        crate::println!("  IRQ {} returned to irqbalance", irq);
+    } else {
+        let mask = parse_number(target)?;
+        let mask = u8::try_from(mask).map_err(|_| "CPU mask out of range")?;
+        crate::gic::set_affinity(irq, mask)?;
+        crate::println!("  IRQ {} pinned to CPU mask 0x{:02x}", irq, mask);
+    }
+    Ok(())
+}
+
+fn cmd_ports(_args: &[&str]) -> Result<(), &'static str> {
+    let ports = crate::ipc::ports();
+    crate::println!("  {:>6} {:>8}  {:<16} {:<20}", "PORT", "PENDING", "NAME", "OWNER");
+    for port in &ports {
+        let owner = crate::process::scheduler::with_thread(port.owner(), |thread| thread.label());
+        let name = crate::manifest::port_name(port.id());
+        crate::println!("  {:>6} {:>8}  {:<16} {} {}", port.id(), port.pending(), name.as_deref().unwrap_or("-"),
+                       port.owner(), owner.as_ref().map_or("", |label| label.as_str()));
+    }
+    crate::println!("  {} ports", ports.len());
+    Ok(())
+}
+
+fn cmd_audit(args: &[&str]) -> Result<(), &'static str> {
+    use crate::audit::{self, AuditKind, AuditRecord};
+    
+    let count = match args {
+        [] => 16,
+        [count] => parse_number(count)? as usize,
+        _ => return Err("usage: audit [count]"),
+    };
+    let (logged, lost) = audit::stats();
+    crate::println!("  {} records logged, {} overwritten", logged, lost);
+    let mut records = [AuditRecord::default(); 32];
+    let mut since = logged.saturating_sub(count as u64);
+    loop {
+        let copied = audit::read(since, &mut records);
+        for record in &records[..copied] {
+            crate::println!("  {:>6} {:>16} {:<8} thread {:>4} object {:>4} detail {:>8} {}",
+                           record.seq, record.timestamp, AuditKind::try_from(record.kind).map_or("?", AuditKind::name),
+                           record.subject, record.object, record.detail, record.tag_str());
+        }
+        match records[..copied].last() {
+            Some(last) if copied == records.len() => since = last.seq + 1,
+            _ => return Ok(()),
+        }
+    }
+}
+
+fn cmd_ipctrace(args: &[&str]) -> Result<(), &'static str> {
+    use crate::trace::{self, TraceEvent, TraceRecord};
+    
+    let trace_id = match args {
+        [switch @ ("on" | "off")] => {
+            trace::set_enabled(*switch == "on");
+            return Ok(());
+        }
+        [] => None,
+        [id] => Some(parse_number(id)?),
+        _ => return Err("usage: ipctrace [on|off|<trace id>]"),
+    };
+    
+    crate::println!("  Tracing {}", if trace::is_enabled() { "on" } else { "off" });
+    let mut records = [TraceRecord::default(); 32];
+    let mut since = 0;
+    loop {
+        let count = trace::read(since, trace_id, &mut records);
+        for record in &records[..count] {
+            crate::println!("  {:>16} trace {:>6} {:<4} port {:>4} thread {:>4} len {}",
+                           record.timestamp, record.trace_id, TraceEvent::name(record.event),
+                           record.arg0, record.thread, record.arg1);
+        }
+        match records[..count].last() {
+            Some(last) if count == records.len() => since = last.seq + 1,
+            _ => return Ok(()),
+        }
+    }
+}
+
+fn cmd_trace(args: &[&str]) -> Result<(), &'static str> {
+    use alloc::string::String;
+    use crate::tracepoint::{self, Tracepoint};
+    
+    let count = match args {
+        [switch @ ("on" | "off"), points @ ..] => {
+            let enabled = *switch == "on";
+            if points.is_empty() {
+                tracepoint::set_all_enabled(enabled);
+            }
+            for name in points {
+                let point = Tracepoint::from_name(name).ok_or("unknown tracepoint")?;
+                tracepoint::set_enabled(point, enabled);
+            }
+            return Ok(());
+        }
+        ["clear"] => {
+            tracepoint::clear();
+            return Ok(());
+        }
+        [] => 64,
+        [count] => parse_number(count)? as usize,
+        _ => return Err("usage: trace [on|off [points]|clear|<count>]"),
+    };
+    
+    for point in Tracepoint::ALL {
+        crate::println!("  {:<13} {}", point.name(), if tracepoint::is_enabled(point) { "on" } else { "off" });
+    }
+    let events = tracepoint::events();
+    let base = events.first().map_or(0, |event| event.timestamp);
+    let mut line = String::new();
+    for event in &events[events.len().saturating_sub(count)..] {
+        line.clear();
+        let _ = tracepoint::format_event(&mut line, event, base);
+        crate::println!("  {}", line);
+    }
+    crate::println!("  {} events recorded", events.len());
+    Ok(())
+}
+
+fn cmd_pmu(_args: &[&str]) -> Result<(), &'static str> {
+    use crate::pmu::{self, Event};
+    
+    if !pmu::is_present() {
+        return Err("no PMU");
+    }
+    let counted = |event| if pmu::is_counting(event) { "" } else { " (not counted)" };
+    crate::println!("  Instructions{}, cache misses{}, branch mispredicts{}", counted(Event::Instructions),
+                   counted(Event::CacheMisses), counted(Event::BranchMisses));
+    // Counts run up to each thread's last switch out
+    crate::println!("  {:>4}  {:<20} {:>14} {:>14} {:>10} {:>10} {:>5}",
+                   "ID", "NAME", "CYCLES", "INSTRUCTIONS", "L1D MISS", "BR MISS", "IPC");
+    crate::process::scheduler::for_each_thread(|thread| {
+        let counts = thread.pmu;
+        let ipc = counts.instructions as f64 / counts.cycles.max(1) as f64;
+        crate::println!("  {:>4}  {:<20} {:>14} {:>14} {:>10} {:>10} {:>5.2}", thread.id, thread.label(),
+                       counts.cycles, counts.instructions, counts.cache_misses, counts.branch_misses, ipc);
+    });
+    Ok(())
+}
+
+fn cmd_profile(args: &[&str]) -> Result<(), &'static str> {
+    use crate::profile::{self, PROFILE_DEFAULT_SAMPLES};
+    
+    match args {
+        ["start", rest @ ..] => {
+            let backtrace = rest.first() == Some(&"bt");
+            let capacity = match &rest[backtrace as usize..] {
+                [] => PROFILE_DEFAULT_SAMPLES,
+                [samples] => parse_number(samples)? as usize,
+                _ => return Err("usage: profile start [bt] [samples]"),
+            };
+            return profile::start(capacity, backtrace);
+        }
+        ["stop"] => {
+            profile::stop();
+            return Ok(());
+        }
+        ["report", rest @ ..] => {
+            let count = match rest {
+                [] => 20,
+                [count] => parse_number(count)? as usize,
+                _ => return Err("usage: profile report [count]"),
+            };
+            return profile_report(count);
+        }
+        [] => {}
+        _ => return Err("usage: profile [start [bt] [samples]|stop|report [count]]"),
+    }
+    
+    let (samples, dropped) = profile::totals();
+    crate::println!("  Profiler {}, {} samples ({} dropped)",
+                   if profile::is_running() { "running" } else { "stopped" }, samples, dropped);
+    if samples == 0 {
+        return Ok(());
+    }
+    crate::println!("  Top PCs (full stacks in /proc/profile):");
+    for (pc, count) in profile::histogram().into_iter().take(10) {
+        crate::println!("  {:>8} {:>5.1}%  0x{:016x}", count, count as f64 * 100.0 / samples as f64, pc);
+    }
+    crate::println!("  Per thread:");
+    for (thread, kernel, user) in profile::by_thread() {
+        let label = crate::process::scheduler::with_thread(thread, |t| t.label());
+        let name = label.as_ref().map_or("<exited>", |label| label.as_str());
+        crate::println!("  {:>4} {:<20} kernel {:>6} user {:>6}", thread, name, kernel, user);
+    }
+    Ok(())
+}
+
+// Flat profile: the `count` functions with the most samples
+fn profile_report(count: usize) -> Result<(), &'static str> {
+    use crate::profile;
+    
+    let (samples, dropped) = profile::totals();
+    if samples == 0 {
+        return Err("No samples; run profile start, then profile stop");
+    }
+    if crate::symbols::kernel_table().is_none() {
+        crate::println!("  No symbol table in this kernel; build with make to get names");
+    }
+    crate::println!("  {} samples ({} dropped)", samples, dropped);
+    crate::println!("  {:>8} {:>6} {:>6}  function", "samples", "self%", "cum%");
+    let mut cumulative = 0;
+    for (bucket, hits) in profile::by_function().into_iter().take(count) {
+        cumulative += hits;
+        crate::println!("  {:>8} {:>5.1}% {:>5.1}%  {}", hits, hits as f64 * 100.0 / samples as f64,
+                       cumulative as f64 * 100.0 / samples as f64, bucket.name());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "lock-stat")]
+fn cmd_lockstat(args: &[&str]) -> Result<(), &'static str> {
+    use crate::sync::stat::{self, ticks_to_us};
+    
+    match args {
+        ["reset"] => {
+            stat::reset();
+            return Ok(());
+        }
+        [] => {}
+        _ => return Err("usage: lockstat [reset]"),
+    }
+    
+    let (locks, overflow) = stat::report();
+    crate::println!("  {:>18} {:>10} {:>10} {:>10} {:>8}", "lock", "acquired", "contended", "wait us", "max us");
+    for lock in locks.iter().take(10) {
+        crate::println!("  0x{:016x} {:>10} {:>10} {:>10} {:>8}",
+                       lock.lock, lock.acquisitions, lock.contended,
+                       ticks_to_us(lock.wait_ticks), ticks_to_us(lock.max_wait_ticks));
+        for site in lock.sites.iter().take(3) {
+            crate::println!("      {:>10} {:>10} {:>10}  {}",
+                           site.acquisitions, site.contended, ticks_to_us(site.wait_ticks), site.site);
+        }
+    }
+    if overflow > 0 {
+        crate::println!("  {} acquisitions from untracked call sites", overflow);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "lock-stat"))]
+fn cmd_lockstat(_args: &[&str]) -> Result<(), &'static str> {
+    Err("kernel built without the lock-stat feature")
+}
+
+fn cmd_sysctl(args: &[&str]) -> Result<(), &'static str> {
+    use crate::sysctl;
+    
+    match args {
+        [] => {
+            for setting in sysctl::all() {
+                crate::println!("  {} = {} ({})", setting.name, setting.get(), setting.kind.name());
+            }
+        }
+        [name] => {
+            let setting = sysctl::lookup(name).ok_or("unknown setting")?;
+            crate::println!("  {} = {}", setting.name, setting.get());
+        }
+        [name, value] => {
+            // The shell runs in the kernel, so it is privileged
+            sysctl::write(name, parse_number(value)?, true, crate::process::scheduler::current_thread_id())?;
+        }
+        _ => return Err("usage: sysctl [<name> [value]]"),
+    }
+    Ok(())
+}
+
+fn cmd_log(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        ["pause"] => crate::console::pause_log(),
+        ["resume"] => crate::console::resume_log(),
+        [] => crate::println!("  log output {}", if crate::console::log_paused() { "paused" } else { "running" }),
+        _ => return Err("usage: log [pause|resume]"),
+    }
+    Ok(())
+}
+
+fn cmd_netconsole(args: &[&str]) -> Result<(), &'static str> {
+    use crate::net::MacDisplay;
+    use crate::netconsole::{self, NetConsoleConfig};
+    
+    match args {
+        ["off"] => {
+            netconsole::stop();
+            return Ok(());
+        }
+        [config] => return netconsole::start(NetConsoleConfig::parse(config)?),
+        [] => {}
+        _ => return Err("usage: netconsole [off|<config>]"),
+    }
+    
+    let Some((config, device, packets, dropped)) = netconsole::status() else {
+        crate::println!("  Netconsole off; network devices: {:?}", crate::net::list());
+        return Ok(());
+    };
+    let ip = |a: [u8; 4]| alloc::format!("{}.{}.{}.{}", a[0], a[1], a[2], a[3]);
+    crate::println!("  {} {}:{} -> {}:{} ({}){}", device, ip(config.src_ip), config.src_port,
+                   ip(config.dst_ip), config.dst_port, MacDisplay(&config.dst_mac),
+                   if netconsole::is_enabled() { "" } else { ", stopped after a send error" });
+    crate::println!("  {} packets sent, {} bytes dropped", packets, dropped);
+    Ok(())
+}
+
+fn cmd_netdump(args: &[&str]) -> Result<(), &'static str> {
+    use crate::net::{self, MacAddr, MacDisplay, ETH_HEADER_LEN, ETH_MTU};
+    
+    let name = match args {
+        [] => net::list().into_iter().next().ok_or("no network device")?,
+        [name] => String::from(*name),
+        _ => return Err("usage: netdump [dev]"),
+    };
+    let device = net::get(&name).ok_or("no such network device")?;
+    let mut frame = [0u8; ETH_HEADER_LEN + ETH_MTU];
+    let mut count = 0;
+    while let Some(len) = device.receive(&mut frame) {
+        count += 1;
+        if len < ETH_HEADER_LEN {
+            crate::println!("  runt frame, {} bytes", len);
+            continue;
+        }
+        let mac = |at: usize| -> MacAddr { frame[at..at + 6].try_into().unwrap_or_default() };
+        crate::println!("  {} -> {} type 0x{:04x}, {} bytes", MacDisplay(&mac(6)), MacDisplay(&mac(0)),
+                       u16::from_be_bytes([frame[12], frame[13]]), len);
+    }
+    crate::println!("  {}: {} frames", name, count);
+    Ok(())
+}
+
+// Refuse addresses the kernel tables do not map, rather than faulting
+fn check_address(addr: u64) -> Result<(), &'static str> {
+    if !addr.is_multiple_of(4) {
+        return Err("address not 4-byte aligned");
+    }
+    check_mapped(addr)
+}
+
+fn check_mapped(addr: u64) -> Result<(), &'static str> {
+    use crate::memory::mmu::MemoryManagementUnit;
+    
+    if MemoryManagementUnit::is_enabled() && MemoryManagementUnit::translate(addr).is_none() {
+        return Err("address not mapped");
+    }
+    Ok(())
+}
+
+// `<addr>` is kernel memory, `<tid>:<addr>` that thread's user memory
+fn parse_location(arg: &str) -> Result<(Option<ThreadId>, u64), &'static str> {
+    match arg.split_once(':') {
+        Some((tid, addr)) => Ok((Some(tid.parse().map_err(|_| "invalid thread id")?), parse_number(addr)?)),
+        None => Ok((None, parse_number(arg)?)),
+    }
+}
+
+fn read_word(target: Option<ThreadId>, addr: u64) -> Result<u32, &'static str> {
+    match target {
+        None => {
+            check_address(addr)?;
+            Ok(unsafe { core::ptr::read_volatile(addr as *const u32) })
+        }
+        Some(_) if !addr.is_multiple_of(4) => Err("address not 4-byte aligned"),
+        Some(tid) => {
+            let mut bytes = [0; 4];
+            crate::process::inspect::read(tid, addr, &mut bytes)?;
+            Ok(u32::from_ne_bytes(bytes))
+        }
+    }
+}
+
+fn read_bytes(target: Option<ThreadId>, addr: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+    match target {
+        None => {
+            for (offset, byte) in buf.iter_mut().enumerate() {
+                let addr = addr.checked_add(offset as u64).ok_or("address range wraps")?;
+                check_mapped(addr)?;
+                *byte = unsafe { core::ptr::read_volatile(addr as *const u8) };
+            }
+            Ok(())
+        }
+        Some(tid) => crate::process::inspect::read(tid, addr, buf),
+    }
+}
+
+fn cmd_ls(args: &[&str]) -> Result<(), &'static str> {
+    use crate::vfs::NodeKind;
+    
+    let path = match args {
+        [] => "/",
+        [path] => *path,
+        _ => return Err("usage: ls [path]"),
+    };
+    if path.trim_end_matches('/') == "/proc" {
+        for name in crate::procfs::list() {
+            crate::println!("  {:>10}  {}", "", name);
+        }
+        return Ok(());
+    }
+    for entry in crate::vfs::read_dir(path)? {
+        match entry.kind {
+            NodeKind::Directory => crate::println!("  {:>10}  {}/", "", entry.name),
+            NodeKind::File => crate::println!("  {:>10}  {}", entry.size, entry.name),
+        }
+    }
+    Ok(())
+}
+
+fn cmd_cat(args: &[&str]) -> Result<(), &'static str> {
+    let [path] = args else {
+        return Err("usage: cat <path>");
+    };
+    let data = match path.strip_prefix("/proc/") {
+        Some(entry) => crate::procfs::read_all(entry)?,
+        None => crate::vfs::read_all(path)?,
+    };
+    crate::print!("{}", String::from_utf8_lossy(&data));
+    Ok(())
+}
+
+fn cmd_run(args: &[&str]) -> Result<(), &'static str> {
+    use crate::process::program;
+    
+    // -R, as in setarch: no address space randomization, for debugging
+    let (randomize, args) = match args {
+        ["-R", rest @ ..] => (false, rest),
+        _ => (true, args),
+    };
+    let [path, ..] = args else {
+        return Err("usage: run [-R] <path> [args]");
+    };
+    let name = path.rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or(path);
+    // Initramfs files are run in place; anything else is read in first
+    let id = match crate::initramfs::lookup(path) {
+        Some(image) => program::spawn(name, image, args, KTHREAD_DEFAULT_PRIORITY, randomize)?,
+        None => {
+            let image = crate::vfs::read_all(path)?;
+            program::spawn(name, &image, args, KTHREAD_DEFAULT_PRIORITY, randomize)?
+        }
+    };
+    crate::println!("run: {} started as thread {}", path, id);
+    Ok(())
+}
+
+fn cmd_push(args: &[&str]) -> Result<(), &'static str> {
+    let [path, size, crc] = args else {
+        return Err("usage: push <path> <size> <crc32>");
+    };
+    let size = parse_number(size)? as usize;
+    let crc = u32::from_str_radix(crc.trim_start_matches("0x"), 16).map_err(|_| "invalid CRC")?;
+    let written = crate::filexfer::receive(path, size, crc)?;
+    crate::println!("push: {} bytes written to {}", written, path);
+    Ok(())
+}
+
+fn cmd_pull(args: &[&str]) -> Result<(), &'static str> {
+    use crate::filexfer::{self, STREAM_DEFAULT_WINDOW};
+    
+    let (path, window) = match args {
+        [path] => (path, STREAM_DEFAULT_WINDOW),
+        [path, window] => (path, parse_number(window)? as usize),
+        _ => return Err("usage: pull <path> [window]"),
+    };
+    let sent = filexfer::send(path, window)?;
+    crate::println!("pull: {} bytes sent from {}", sent, path);
+    Ok(())
+}
+
+fn cmd_mounts(_args: &[&str]) -> Result<(), &'static str> {
+    for (path, fs) in crate::vfs::mounts() {
+        crate::println!("  {:<16} {}", path, fs);
+    }
+    Ok(())
+}
+
+fn cmd_peek(args: &[&str]) -> Result<(), &'static str> {
+    let ((target, addr), words) = match args {
+        [location] => (parse_location(location)?, 4),
+        [location, words] => (parse_location(location)?, parse_number(words)? as usize),
+        _ => return Err("usage: peek [<tid>:]<addr> [words]"),
+    };
+    let words = words.clamp(1, PEEK_MAX_WORDS);
+    
+    for row in (0..words).step_by(4) {
+        let row_addr = addr + row as u64 * 4;
+        crate::print!("  {:016x}:", row_addr);
+        for word in 0..(words - row).min(4) {
+            let value = read_word(target, row_addr + word as u64 * 4)?;
+            crate::print!(" {:08x}", value);
+        }
+        crate::println!();
+    }
+    Ok(())
+}
+
+fn cmd_poke(args: &[&str]) -> Result<(), &'static str> {
+    let ((target, addr), value) = match args {
+        [location, value] => (parse_location(location)?, parse_number(value)?),
+        _ => return Err("usage: poke [<tid>:]<addr> <value>"),
+    };
+    let value = u32::try_from(value).map_err(|_| "value does not fit in 32 bits")?;
+    if !addr.is_multiple_of(4) {
+        return Err("address not 4-byte aligned");
+    }
+    match target {
+        None => {
+            check_mapped(addr)?;
+            unsafe { core::ptr::write_volatile(addr as *mut u32, value) };
+            Ok(())
+        }
+        Some(tid) => crate::process::inspect::write(tid, addr, &value.to_ne_bytes()),
+    }
+}
+
+fn cmd_dump(args: &[&str]) -> Result<(), &'static str> {
+    let ((target, addr), len) = match args {
+        [location] => (parse_location(location)?, 64),
+        [location, len] => (parse_location(location)?, parse_number(len)? as usize),
+        _ => return Err("usage: dump [<tid>:]<addr> [bytes]"),
+    };
+    let mut bytes = alloc::vec![0; len.clamp(1, DUMP_MAX_BYTES)];
+    read_bytes(target, addr, &mut bytes)?;
+    
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        crate::print!("  {:016x}: ", addr + row as u64 * 16);
+        for column in 0..16 {
+            match chunk.get(column) {
+                Some(byte) => crate::print!("{:02x} ", byte),
+                None => crate::print!("   "),
+            }
+        }
+        let text: String = chunk
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        crate::println!(" {}", text);
+    }
+    Ok(())
+}
+
+fn cmd_bp(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            for (n, bp) in crate::kdebug::breakpoints() {
+                crate::println!("  {:>2}  {:016x}  {} hits", n, bp.addr, bp.hits);
+            }
+        }
+        ["del", n] => crate::kdebug::clear_breakpoint(parse_number(n)? as usize)?,
+        [addr] => {
+            let n = crate::kdebug::set_breakpoint(parse_number(addr)?)?;
+            crate::println!("bp: breakpoint {} set", n);
+        }
+        _ => return Err("usage: bp [<addr>|del <n>]"),
+    }
+    Ok(())
+}
+
+fn cmd_watch(args: &[&str]) -> Result<(), &'static str> {
+    use crate::kdebug::Access;
+    
+    let (addr, access, len) = match args {
+        [] => {
+            for (n, wp) in crate::kdebug::watchpoints() {
+                crate::println!("  {:>2}  {:016x}  {} bytes {:<2}  {} hits",
+                               n, wp.addr, wp.len, wp.access.as_str(), wp.hits);
+            }
+            return Ok(());
+        }
+        ["del", n] => return crate::kdebug::clear_watchpoint(parse_number(n)? as usize),
+        [addr, access] => (addr, access, None),
+        [addr, access, len] => (addr, access, Some(parse_number(len)? as usize)),
+        _ => return Err("usage: watch [<addr> r|w|rw [len]|del <n>]"),
+    };
+    let access = Access::parse(access).ok_or("access must be r, w or rw")?;
+    let addr = parse_number(addr)?;
+    // Default to the widest naturally aligned access at the address
+    let len = len.unwrap_or(1 << addr.trailing_zeros().min(3));
+    let n = crate::kdebug::set_watchpoint(addr, len, access)?;
+    crate::println!("watch: watchpoint {} set", n);
+    Ok(())
+}
+
+fn cmd_mailbox(args: &[&str]) -> Result<(), &'static str> {
+    use crate::drivers::mailbox::{self, Clock};
+    
+    const USAGE: &str = "usage: mailbox [clock <name> [hz]|fb <width> <height> [depth]]";
+    let clock = |name: &str| Clock::ALL.into_iter().find(|clock| clock.name() == name).ok_or("mailbox: Unknown clock");
+    match args {
+        [] => {
+            crate::println!("  Board revision 0x{:x}", mailbox::board_revision()?);
+            for clock in Clock::ALL {
+                match mailbox::clock_rate(clock) {
+                    Ok(hz) => crate::println!("  {:6} {} Hz", clock.name(), hz),
+                    Err(e) => crate::println!("  {:6} {}", clock.name(), e),
+                }
+            }
+        }
+        ["clock", name] => crate::println!("  {} {} Hz", name, mailbox::clock_rate(clock(name)?)?),
+        ["clock", name, hz] => {
+            let hz = hz.parse().map_err(|_| USAGE)?;
+            crate::println!("  {} set to {} Hz", name, mailbox::set_clock_rate(clock(name)?, hz)?);
+        }
+        ["fb", width, height, depth @ ..] => {
+            let width = width.parse().map_err(|_| USAGE)?;
+            let height = height.parse().map_err(|_| USAGE)?;
+            let depth = match depth {
+                [] => 32,
+                [depth] => depth.parse().map_err(|_| USAGE)?,
+                _ => return Err(USAGE),
+            };
+            let fb = mailbox::allocate_framebuffer(width, height, depth)?;
+            crate::println!("  {}x{}x{} at 0x{:x}, {} bytes, pitch {}", fb.width, fb.height, fb.depth, fb.phys, fb.size, fb.pitch);
+        }
+        _ => return Err(USAGE),
+    }
+    Ok(())
+}
+
+fn cmd_reboot(_args: &[&str]) -> Result<(), &'static str> {
+    Err(crate::power::reboot())
+}
+
+fn cmd_poweroff(_args: &[&str]) -> Result<(), &'static str> {
+    Err(crate::power::poweroff())
+}
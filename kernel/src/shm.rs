@@ -0,0 +1,195 @@
+// Shared memory objects
+//
+// An object is a run of zeroed pages that several address spaces map at
+// once, for bulk data that would be clumsy to copy through IPC messages.
+// Access is by capability: the creator holds Shm { id, writable: true }
+// and may pass it on at the same or a lower permission with `grant`.
+// Every mapping is read-only or writable on its own, up to what the
+// mapper's capability allows. An object may also be given a name, which
+// resolves to its id only for threads already holding a capability.
+//
+// Lifetime follows the frame reference counts: the object holds one
+// reference to its pages and every mapping holds another. `destroy` drops
+// the name and the object's reference, so the pages return to the
+// allocator when the last mapping goes, whether through munmap or because
+// the mapper exited.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use crate::memory::frame_allocator::{allocate_frames, frame_refcount, PAGE_SIZE};
+use crate::memory::paging::{PageFlags, VirtAddr};
+use crate::process::capability::{self, Capability};
+use crate::process::scheduler::with_thread;
+use crate::process::thread::{AddressSpace, PageRun};
+use crate::process::ThreadId;
+
+pub type ShmId = u32;
+
+/// Longest object name.
+pub const SHM_NAME_MAX: usize = 32;
+/// Largest object, in pages (4 MiB).
+pub const SHM_MAX_PAGES: usize = 1024;
+
+struct ShmObject {
+    name: Option<String>,
+    pages: PageRun,
+    creator: ThreadId,
+}
+
+/// A snapshot of one object, for /proc/shm and tests.
+pub struct ShmInfo {
+    pub id: ShmId,
+    pub name: Option<String>,
+    pub pages: usize,
+    pub creator: ThreadId,
+    /// Address spaces mapping the object's first page.
+    pub mappings: usize,
+}
+
+// Taken inside the scheduler lock by `map_for`, so never the other way round
+static OBJECTS: Mutex<BTreeMap<ShmId, ShmObject>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Export /proc/shm.
+pub fn init() {
+    let _ = crate::procfs::register("shm", proc_shm);
+}
+
+/// Create an object of `pages` zeroed pages and give `creator` a writable
+/// capability for it.
+pub fn create(creator: ThreadId, name: Option<&str>, pages: usize) -> Result<ShmId, &'static str> {
+    if pages == 0 || pages > SHM_MAX_PAGES {
+        return Err("Bad shared memory size");
+    }
+    if name.is_some_and(|name| name.is_empty() || name.len() > SHM_NAME_MAX) {
+        return Err("Bad shared memory name");
+    }
+    
+    let id = {
+        let mut objects = OBJECTS.lock();
+        if let Some(name) = name {
+            if objects.values().any(|object| object.name.as_deref() == Some(name)) {
+                return Err("Shared memory name in use");
+            }
+        }
+        let base = allocate_frames(pages).ok_or("Out of memory")?;
+        unsafe { core::ptr::write_bytes(base.as_ptr(), 0, pages * PAGE_SIZE) };
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        objects.insert(id, ShmObject {
+            name: name.map(String::from),
+            pages: PageRun { base, frames: pages },
+            creator,
+        });
+        id
+    };
+    
+    if let Err(e) = capability::grant(creator, creator, Capability::Shm { id, writable: true }) {
+        OBJECTS.lock().remove(&id);
+        return Err(e);
+    }
+    Ok(id)
+}
+
+/// The access `holder`'s capabilities give to object `id`: None, or
+/// Some(writable).
+pub fn access(holder: ThreadId, id: ShmId) -> Option<bool> {
+    capability::held(holder).iter()
+        .filter_map(|capability| match *capability {
+            Capability::Shm { id: held, writable } if held == id => Some(writable),
+            _ => None,
+        })
+        .max()
+}
+
+/// Look up a named object the caller holds a capability for.
+pub fn open(holder: ThreadId, name: &str) -> Result<ShmId, &'static str> {
+    let id = OBJECTS.lock().iter()
+        .find(|(_, object)| object.name.as_deref() == Some(name))
+        .map(|(&id, _)| id)
+        .ok_or("No such shared memory object")?;
+    access(holder, id).ok_or("Permission denied")?;
+    Ok(id)
+}
+
+/// Pass access to object `id` on to `holder`. The granter must hold at
+/// least the access it grants.
+pub fn grant(granter: ThreadId, id: ShmId, holder: ThreadId, writable: bool) -> Result<(), &'static str> {
+    match access(granter, id) {
+        Some(held) if held || !writable => {}
+        _ => return Err("Permission denied"),
+    }
+    if !OBJECTS.lock().contains_key(&id) {
+        return Err("No such shared memory object");
+    }
+    capability::grant(granter, holder, Capability::Shm { id, writable })
+}
+
+/// Map object `id` into `space`, checking nothing: callers check access.
+/// Unmapped again with `AddressSpace::unmap`, like anonymous memory.
+pub fn map(space: &mut AddressSpace, id: ShmId, writable: bool) -> Result<VirtAddr, &'static str> {
+    let mut flags = PageFlags::NORMAL_MEMORY | PageFlags::INNER_SHAREABLE | PageFlags::ACCESSED
+        | PageFlags::USER | PageFlags::PXN | PageFlags::UXN;
+    if !writable {
+        flags |= PageFlags::READ_ONLY;
+    }
+    // Held across the mapping so a concurrent destroy cannot free the pages
+    let objects = OBJECTS.lock();
+    let object = objects.get(&id).ok_or("No such shared memory object")?;
+    space.map_shared(object.pages.base, object.pages.frames, flags)
+}
+
+/// Map object `id` into `holder`'s address space if its capabilities
+/// allow the access asked for.
+pub fn map_for(holder: ThreadId, id: ShmId, writable: bool) -> Result<VirtAddr, &'static str> {
+    match access(holder, id) {
+        Some(held) if held || !writable => {}
+        _ => return Err("Permission denied"),
+    }
+    with_thread(holder, |thread| {
+        let space = thread.address_space().ok_or("No user address space")?;
+        map(space, id, writable)
+    })
+    .ok_or("No such thread")?
+}
+
+/// Drop object `id`. Existing mappings keep the pages until they go.
+pub fn destroy(caller: ThreadId, id: ShmId, privileged: bool) -> Result<(), &'static str> {
+    let mut objects = OBJECTS.lock();
+    let object = objects.get(&id).ok_or("No such shared memory object")?;
+    if object.creator != caller && !privileged {
+        return Err("Permission denied");
+    }
+    objects.remove(&id);
+    Ok(())
+}
+
+/// Every live object.
+pub fn list() -> Vec<ShmInfo> {
+    OBJECTS.lock().iter().map(|(&id, object)| ShmInfo {
+        id,
+        name: object.name.clone(),
+        pages: object.pages.frames,
+        creator: object.creator,
+        mappings: mappings(object.pages.base),
+    }).collect()
+}
+
+// References beyond the object's own
+fn mappings(base: NonNull<u8>) -> usize {
+    (frame_refcount(base) as usize).saturating_sub(1)
+}
+
+fn proc_shm(out: &mut Vec<u8>) {
+    let mut text = String::new();
+    let _ = writeln!(text, "{:>5} {:>6} {:>8} {:>7}  name", "id", "pages", "mappings", "creator");
+    for info in list() {
+        let _ = writeln!(text, "{:>5} {:>6} {:>8} {:>7}  {}", info.id, info.pages, info.mappings,
+                         info.creator, info.name.as_deref().unwrap_or("-"));
+    }
+    out.extend_from_slice(text.as_bytes());
+}
@@ -0,0 +1,267 @@
+// Deferred interrupt work ("softirqs"), the bottom half of a handler
+//
+// A handler does what cannot wait (acknowledge the device, grab the data)
+// and leaves the rest here, either by raising a registered vector or by
+// queueing a work item (function plus argument). Neither allocates nor
+// takes a lock, so both are safe from any handler.
+//
+// Pending work runs on the way out of the outermost interrupt, with IRQs
+// unmasked so further interrupts are not held up, and preemption held off
+// until it is done. Each exit runs a bounded batch; whatever is left is
+// handed to the ksoftirqd thread, which competes with other threads like
+// any other, so an interrupt storm cannot starve them.
+//
+// Work runs on the interrupted thread's stack with the same locking rules
+// as the handler that queued it: no sleeping, and only locks that are
+// never held with IRQs enabled elsewhere.
+
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use crate::cpu::{cpu_index, MAX_CPUS};
+use crate::interrupts::{counter_frequency, counter_ticks, irq_depth, local_irq_restore, local_irq_save};
+use crate::ktest::kernel_test;
+use crate::mpsc::MpscQueue;
+use crate::process::scheduler::{block_current, current_thread_id, wake};
+use crate::process::{kthread_spawn, yield_now, ThreadId, KTHREAD_DEFAULT_PRIORITY};
+use crate::sync::IrqSafeMutex;
+
+const MAX_VECTORS: usize = 16;
+const WORK_QUEUE: usize = 64;
+
+// Per batch, on interrupt exit or between ksoftirqd's yields
+const BATCH_ITEMS: usize = 32;
+const BATCH_US: u64 = 2000;
+
+// DAIF.I
+const DAIF_IRQ: u64 = 1 << 7;
+
+const NO_THREAD: ThreadId = ThreadId::MAX;
+
+/// A registered vector, for `raise`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Softirq(u8);
+
+struct Vector {
+    name: &'static str,
+    handler: fn(),
+}
+
+#[derive(Copy, Clone)]
+struct Work {
+    func: fn(usize),
+    arg: usize,
+}
+
+static VECTORS: IrqSafeMutex<[Option<Vector>; MAX_VECTORS]> = IrqSafeMutex::new([const { None }; MAX_VECTORS]);
+// Raised vectors, bit per vector
+static PENDING: AtomicU32 = AtomicU32::new(0);
+static WORK: MpscQueue<Work, WORK_QUEUE> = MpscQueue::new();
+
+// Set while a CPU runs deferred work on interrupt exit
+static IN_EXIT: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+static DAEMON: AtomicU32 = AtomicU32::new(NO_THREAD);
+
+static VECTOR_RUNS: [AtomicU64; MAX_VECTORS] = [const { AtomicU64::new(0) }; MAX_VECTORS];
+static WORK_RUNS: AtomicU64 = AtomicU64::new(0);
+static EXIT_BATCHES: AtomicU64 = AtomicU64::new(0);
+static DAEMON_BATCHES: AtomicU64 = AtomicU64::new(0);
+
+/// Add a vector that runs `handler` each time it has been raised.
+pub fn register(name: &'static str, handler: fn()) -> Result<Softirq, &'static str> {
+    let mut vectors = VECTORS.lock();
+    if vectors.iter().flatten().any(|vector| vector.name == name) {
+        return Err("Softirq: Name already registered");
+    }
+    let index = vectors.iter().position(|vector| vector.is_none()).ok_or("Softirq: No free vector")?;
+    vectors[index] = Some(Vector { name, handler });
+    Ok(Softirq(index as u8))
+}
+
+/// Mark `softirq` pending; raising it again before it runs has no
+/// further effect.
+pub fn raise(softirq: Softirq) {
+    PENDING.fetch_or(1 << softirq.0, Ordering::AcqRel);
+}
+
+/// Queue `func(arg)` to run once.
+pub fn queue_work(func: fn(usize), arg: usize) -> Result<(), &'static str> {
+    WORK.push(Work { func, arg }).map_err(|_| "Softirq: Work queue full")
+}
+
+pub fn is_pending() -> bool {
+    PENDING.load(Ordering::Acquire) != 0 || !WORK.is_empty()
+}
+
+/// Whether this CPU is running deferred work on interrupt exit, when the
+/// scheduler must not switch away.
+pub fn in_progress() -> bool {
+    IN_EXIT[cpu_index()].load(Ordering::Relaxed)
+}
+
+// Run one batch; whether everything pending was done
+fn run_batch() -> bool {
+    let deadline = counter_ticks() + counter_frequency() * BATCH_US / 1_000_000;
+    let mut items = 0;
+    loop {
+        let raised = PENDING.swap(0, Ordering::AcqRel);
+        for index in (0..MAX_VECTORS).filter(|index| raised & (1 << index) != 0) {
+            let handler = VECTORS.lock()[index].as_ref().map(|vector| vector.handler);
+            if let Some(handler) = handler {
+                handler();
+                VECTOR_RUNS[index].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        while let Some(work) = WORK.pop() {
+            (work.func)(work.arg);
+            WORK_RUNS.fetch_add(1, Ordering::Relaxed);
+            items += 1;
+            if items >= BATCH_ITEMS || counter_ticks() > deadline {
+                return !is_pending();
+            }
+        }
+        if !is_pending() {
+            return true;
+        }
+        if counter_ticks() > deadline {
+            return false;
+        }
+    }
+}
+
+fn wake_daemon() {
+    let daemon = DAEMON.load(Ordering::Acquire);
+    if daemon != NO_THREAD {
+        wake(daemon);
+    }
+}
+
+/// Run pending work on the way out of an interrupt. Called with IRQs
+/// masked after the handlers; returns with them masked again.
+pub fn irq_exit() {
+    if irq_depth() != 0 || !is_pending() {
+        return;
+    }
+    // Interrupting a batch on this CPU: that batch picks the work up
+    let cpu = cpu_index();
+    if IN_EXIT[cpu].swap(true, Ordering::Acquire) {
+        return;
+    }
+    EXIT_BATCHES.fetch_add(1, Ordering::Relaxed);
+    let daif = local_irq_save();
+    local_irq_restore(daif & !DAIF_IRQ);
+    let finished = run_batch();
+    local_irq_restore(daif);
+    IN_EXIT[cpu].store(false, Ordering::Release);
+    
+    // Over budget, or queued by an interrupt after the batch looked
+    if !finished || is_pending() {
+        wake_daemon();
+    }
+}
+
+fn ksoftirqd_main() {
+    let me = current_thread_id();
+    loop {
+        if is_pending() {
+            DAEMON_BATCHES.fetch_add(1, Ordering::Relaxed);
+            run_batch();
+            yield_now();
+            continue;
+        }
+        // A wakeup between the check and the yield just requeues us
+        block_current();
+        if is_pending() {
+            wake(me);
+        }
+        yield_now();
+    }
+}
+
+/// Start ksoftirqd and export /proc/softirqs.
+pub fn init() {
+    match kthread_spawn(ksoftirqd_main, "ksoftirqd", KTHREAD_DEFAULT_PRIORITY) {
+        Ok(id) => DAEMON.store(id, Ordering::Release),
+        Err(e) => crate::println!("Softirq: Failed to start ksoftirqd: {}", e),
+    }
+    let _ = crate::procfs::register("softirqs", proc_softirqs);
+}
+
+fn proc_softirqs(out: &mut Vec<u8>) {
+    let mut text = alloc::string::String::new();
+    for (index, vector) in VECTORS.lock().iter().enumerate() {
+        if let Some(vector) = vector {
+            let _ = writeln!(text, "{:<12} {}", vector.name, VECTOR_RUNS[index].load(Ordering::Relaxed));
+        }
+    }
+    let _ = writeln!(text, "{:<12} {} ({} dropped)", "work", WORK_RUNS.load(Ordering::Relaxed), WORK.dropped());
+    let _ = writeln!(text, "batches {} on irq exit, {} in ksoftirqd", EXIT_BATCHES.load(Ordering::Relaxed),
+                     DAEMON_BATCHES.load(Ordering::Relaxed));
+    out.extend_from_slice(text.as_bytes());
+}
+
+#[kernel_test]
+fn test_softirq() {
+    crate::println!("Softirq Test: Testing deferred work...");
+    
+    static VECTOR_RUNS: AtomicU64 = AtomicU64::new(0);
+    static WORK_SUM: AtomicU64 = AtomicU64::new(0);
+    // Work that found IRQs masked or itself inside a handler
+    static WORK_IN_HANDLER: AtomicU64 = AtomicU64::new(0);
+    static TEST_VECTOR: IrqSafeMutex<Option<Softirq>> = IrqSafeMutex::new(None);
+    
+    fn vector_handler() {
+        VECTOR_RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+    fn work(arg: usize) {
+        if crate::interrupts::irqs_disabled() || crate::interrupts::irq_depth() != 0 {
+            WORK_IN_HANDLER.fetch_add(1, Ordering::SeqCst);
+        }
+        WORK_SUM.fetch_add(arg as u64, Ordering::SeqCst);
+    }
+    // The top half only queues
+    fn top_half(_irq: u32) {
+        if let Some(vector) = *TEST_VECTOR.lock() {
+            raise(vector);
+        }
+        let _ = queue_work(work, 5);
+    }
+    
+    match register("test", vector_handler) {
+        Ok(vector) => *TEST_VECTOR.lock() = Some(vector),
+        Err(e) => crate::println!("Softirq Test: ✗ Softirq registration: {}", e),
+    }
+    if register("test", vector_handler).is_ok() {
+        crate::println!("Softirq Test: ✗ Duplicate softirq name accepted");
+    }
+    
+    // Raised and queued from an SGI handler, run on its way out
+    const SOFTIRQ_SGI: u32 = 12;
+    if crate::gic::is_present() && crate::gic::register_handler(SOFTIRQ_SGI, top_half).is_ok() {
+        let _ = crate::gic::send_sgi(SOFTIRQ_SGI, crate::gic::this_cpu());
+        crate::interrupts::delay_us(1000);
+        let _ = crate::gic::disable_irq(SOFTIRQ_SGI);
+        let ran = VECTOR_RUNS.load(Ordering::SeqCst) == 1 && WORK_SUM.load(Ordering::SeqCst) == 5;
+        if ran && WORK_IN_HANDLER.load(Ordering::SeqCst) == 0 {
+            crate::println!("Softirq Test: ✓ Work queued by a handler ran after it, IRQs unmasked");
+        } else {
+            crate::println!("Softirq Test: ✗ Deferred work ran {} in handler context {}", ran,
+                           WORK_IN_HANDLER.load(Ordering::SeqCst));
+        }
+    } else {
+        crate::println!("Softirq Test: SGI {} unavailable, handler path not tested", SOFTIRQ_SGI);
+    }
+    
+    // More than one exit runs: the rest goes to ksoftirqd
+    WORK_SUM.store(0, Ordering::SeqCst);
+    const ITEMS: u64 = 48;
+    let queued = (0..ITEMS).filter(|_| queue_work(work, 1).is_ok()).count() as u64;
+    let _ = crate::timer::sleep_ms(50);
+    if queued == ITEMS && WORK_SUM.load(Ordering::SeqCst) == ITEMS && !is_pending() {
+        crate::println!("Softirq Test: ✓ {} queued items all ran", ITEMS);
+    } else {
+        crate::println!("Softirq Test: ✗ Queued {} of {}, {} ran", queued, ITEMS, WORK_SUM.load(Ordering::SeqCst));
+    }
+    
+    crate::println!("Softirq Test: Deferred work test completed");
+}
@@ -0,0 +1,272 @@
+// Kernel symbol table for naming code addresses
+//
+// tools/ksyms.py reads the linked kernel's .symtab and writes a compressed
+// table over the zero-filled .ksyms section reserved here, so panic
+// backtraces and the debugger can print function names without the ELF.
+// The section has a fixed size: filling it in moves no code, so the
+// recorded addresses stay valid. A kernel that was not post-processed
+// (`cargo run`) has an all-zero section and simply prints raw addresses.
+//
+// Symbols are sorted and grouped in blocks of 32 with an index of each
+// block's first address, so a lookup is a binary search followed by
+// decoding at most one block. Within a block, addresses are deltas and
+// names share a prefix with their predecessor.
+
+use core::fmt;
+use core::ptr::addr_of;
+use core::str;
+use crate::ktest::kernel_test;
+
+/// Bytes reserved for the table; tools/ksyms.py refuses to overflow it.
+pub const KSYMS_SIZE: usize = 512 * 1024;
+
+// Longest decoded name; tools/ksyms.py cuts names to fit
+pub const NAME_MAX: usize = 255;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const VERSION: u32 = 1;
+const BLOCK_SIZE: usize = 32;
+const HEADER_LEN: usize = 24;
+const INDEX_ENTRY_LEN: usize = 16;
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS_SPACE: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+extern "C" {
+    static __ksyms_start: u8;
+    static __ksyms_end: u8;
+}
+
+/// A function covering an address, with its name copied out of the table.
+#[derive(Clone)]
+pub struct Symbol {
+    pub addr: u64,
+    pub size: u64,
+    /// Offset of the looked-up address from the start of the function.
+    pub offset: u64,
+    name: [u8; NAME_MAX],
+    name_len: usize,
+}
+
+impl Symbol {
+    pub fn name(&self) -> &str {
+        // The table may cut a multi-byte character; show the valid part
+        let bytes = &self.name[..self.name_len];
+        match str::from_utf8(bytes) {
+            Ok(name) => name,
+            Err(e) => unsafe { str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+        }
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+0x{:x}", self.name(), self.offset)
+    }
+}
+
+/// A validated view of an encoded table.
+#[derive(Copy, Clone)]
+pub struct SymbolTable<'a> {
+    data: &'a [u8],
+    count: usize,
+    blocks: usize,
+    end: u64,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+// Unsigned LEB128, advancing `pos`
+fn read_uleb(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Check the header and index bounds; block contents are checked as
+    /// they are decoded.
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.get(..4)? != MAGIC || read_u32(data, 4)? != VERSION {
+            return None;
+        }
+        let count = read_u32(data, 8)? as usize;
+        let blocks = read_u32(data, 12)? as usize;
+        let end = read_u64(data, 16)?;
+        let index_end = blocks.checked_mul(INDEX_ENTRY_LEN)?.checked_add(HEADER_LEN)?;
+        if index_end > data.len() || (count == 0) != (blocks == 0) {
+            return None;
+        }
+        Some(SymbolTable { data, count, blocks, end })
+    }
+    
+    pub fn len(&self) -> usize {
+        self.count
+    }
+    
+    // (first address, data offset) of a block
+    fn block(&self, index: usize) -> Option<(u64, usize)> {
+        let entry = HEADER_LEN + index * INDEX_ENTRY_LEN;
+        Some((read_u64(self.data, entry)?, read_u32(self.data, entry + 8)? as usize))
+    }
+    
+    /// The function containing `addr`. Symbols without a size extend to the
+    /// next symbol.
+    pub fn lookup(&self, addr: u64) -> Option<Symbol> {
+        if self.blocks == 0 || addr >= self.end {
+            return None;
+        }
+        
+        // Last block starting at or below addr
+        let (mut low, mut high) = (0, self.blocks);
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if self.block(mid)?.0 <= addr {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        let (first, offset) = self.block(low)?;
+        if addr < first {
+            return None;
+        }
+        // The final block may be short
+        let in_block = (self.count - low * BLOCK_SIZE).min(BLOCK_SIZE);
+        
+        let mut pos = offset;
+        let mut current = Symbol { addr: first, size: 0, offset: 0, name: [0; NAME_MAX], name_len: 0 };
+        let mut found = None;
+        for _ in 0..in_block {
+            let delta = read_uleb(self.data, &mut pos)?;
+            let size = read_uleb(self.data, &mut pos)?;
+            let shared = *self.data.get(pos)? as usize;
+            pos += 1;
+            let suffix_len = read_uleb(self.data, &mut pos)? as usize;
+            let suffix = self.data.get(pos..pos.checked_add(suffix_len)?)?;
+            pos += suffix_len;
+            if shared > current.name_len || shared + suffix_len > NAME_MAX {
+                return None;
+            }
+            
+            let start = current.addr.checked_add(delta)?;
+            if start > addr {
+                break;
+            }
+            current.addr = start;
+            current.size = size;
+            current.name[shared..shared + suffix_len].copy_from_slice(suffix);
+            current.name_len = shared + suffix_len;
+            found = Some(current.clone());
+        }
+        
+        let mut symbol = found?;
+        symbol.offset = addr - symbol.addr;
+        if symbol.size != 0 && symbol.offset >= symbol.size {
+            return None;
+        }
+        Some(symbol)
+    }
+}
+
+/// The table embedded in this kernel, if the build filled it in.
+pub fn kernel_table() -> Option<SymbolTable<'static>> {
+    // Go through the linker symbols: reading KSYMS_SPACE directly would let
+    // the compiler fold the all-zero initializer
+    let data = unsafe {
+        let start = addr_of!(__ksyms_start);
+        let len = addr_of!(__ksyms_end) as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    SymbolTable::parse(data)
+}
+
+/// Look up a kernel address in the embedded table. The table has link
+/// addresses; `addr` and the symbol's address are where the kernel runs.
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    let offset = addr - crate::memory::kaslr::link_address(addr);
+    let mut symbol = kernel_table()?.lookup(addr - offset)?;
+    symbol.addr += offset;
+    Some(symbol)
+}
+
+/// Log whether backtraces will carry names.
+pub fn init() {
+    match kernel_table() {
+        Some(table) => crate::println!("Symbols: {} kernel symbols", table.len()),
+        None => crate::println!("Symbols: No embedded table, backtraces show raw addresses"),
+    }
+}
+
+// Two symbols in one block: kernel::foo at 0x1000 (0x40 bytes) and
+// kernel::foobar at 0x1080, sizeless, running to the table end at 0x1100
+const TEST_SYMBOL_TABLE: [u8; 63] = [
+    b'K', b'S', b'Y', b'M', 1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0,
+    0x00, 0x11, 0, 0, 0, 0, 0, 0,
+    0x00, 0x10, 0, 0, 0, 0, 0, 0, 40, 0, 0, 0, 0, 0, 0, 0,
+    0, 0x40, 0, 11, b'k', b'e', b'r', b'n', b'e', b'l', b':', b':', b'f', b'o', b'o',
+    0x80, 0x01, 0, 11, 3, b'b', b'a', b'r',
+];
+
+#[kernel_test]
+fn test_symbol_table() {
+    use alloc::string::ToString;
+    
+    crate::println!("Symbol Test: Testing symbol table...");
+    
+    match SymbolTable::parse(&TEST_SYMBOL_TABLE) {
+        Some(table) => {
+            let name = |addr| table.lookup(addr).map(|symbol| symbol.to_string());
+            let inside = name(0x1010).as_deref() == Some("kernel::foo+0x10");
+            let sizeless = name(0x10f0).as_deref() == Some("kernel::foobar+0x70");
+            let gaps = name(0x1050).is_none() && name(0xfff).is_none() && name(0x1100).is_none();
+            if inside && sizeless && gaps {
+                crate::println!("Symbol Test: ✓ Symbol lookup decodes shared-prefix names");
+            } else {
+                crate::println!("Symbol Test: ✗ Symbol lookup gave {:?} {:?} {:?}",
+                               name(0x1010), name(0x10f0), name(0x1050));
+            }
+        }
+        None => crate::println!("Symbol Test: ✗ Valid symbol table rejected"),
+    }
+    
+    // Truncated data and an unfilled (all-zero) section are not tables
+    let truncated = SymbolTable::parse(&TEST_SYMBOL_TABLE[..30]).is_none();
+    let zeroed = SymbolTable::parse(&[0; 64]).is_none();
+    let cut_block = SymbolTable::parse(&TEST_SYMBOL_TABLE[..60]).is_some_and(|table| table.lookup(0x10f0).is_none());
+    if truncated && zeroed && cut_block {
+        crate::println!("Symbol Test: ✓ Malformed symbol tables rejected");
+    } else {
+        crate::println!("Symbol Test: ✗ Malformed symbol table accepted");
+    }
+    
+    let here = test_symbol_table as fn() as usize as u64;
+    match kernel_table() {
+        Some(table) => match lookup(here + 4) {
+            Some(symbol) if symbol.name().ends_with("test_symbol_table") && symbol.offset == 4 => {
+                crate::println!("Symbol Test: ✓ Kernel table names {} of {} symbols", symbol, table.len());
+            }
+            other => crate::println!("Symbol Test: ✗ Kernel table gave {:?} for {:x}",
+                                     other.as_ref().map(|symbol| symbol.name()), here),
+        },
+        None => crate::println!("Symbol Test: Kernel symbol table not embedded, skipping lookup"),
+    }
+    
+    crate::println!("Symbol Test: Symbol table test completed");
+}
@@ -0,0 +1,530 @@
+// Interrupt-safe locking
+//
+// A spin::Mutex shared with an interrupt handler deadlocks if the IRQ
+// arrives while the interrupted code holds it. IrqSafeMutex masks IRQs for
+// as long as the guard lives and restores the previous DAIF state on drop,
+// so it nests correctly and is safe to take from handler context too.
+//
+// SleepMutex is for longer critical sections in thread context: waiters
+// block instead of spinning, and the owner inherits the priority of its
+// most urgent waiter so a low-priority holder cannot stall urgent work.
+//
+// With the "lock-debug" feature, acquisitions also check for recursive
+// locking, report waits that look like deadlocks, and report long holds.
+// With "lock-stat", every acquisition is counted per lock and call site
+// along with how long it spun; the shell's `lockstat` prints the result.
+
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use spin::{Mutex, MutexGuard};
+use crate::interrupts::{local_irq_restore, local_irq_save};
+use crate::ktest::kernel_test;
+use crate::process::scheduler::{self, current_thread_id};
+use crate::process::ThreadId;
+
+pub struct IrqSafeMutex<T> {
+    inner: Mutex<T>,
+    #[cfg(feature = "lock-debug")]
+    debug: debug::LockDebug,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            #[cfg(feature = "lock-debug")]
+            debug: debug::LockDebug::new(),
+        }
+    }
+    
+    /// Mask IRQs and spin until the lock is ours.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let caller = Location::caller();
+        let daif = local_irq_save();
+        
+        #[cfg(feature = "lock-stat")]
+        let (start, contended) = (crate::interrupts::counter_ticks(), self.inner.is_locked());
+        
+        #[cfg(feature = "lock-debug")]
+        let guard = self.debug.acquire(&self.inner, caller);
+        #[cfg(not(feature = "lock-debug"))]
+        let guard = self.inner.lock();
+        
+        #[cfg(feature = "lock-stat")]
+        stat::record(self as *const Self as usize, caller, contended,
+                     crate::interrupts::counter_ticks() - start);
+        
+        IrqSafeMutexGuard {
+            lock: self,
+            guard: Some(guard),
+            daif,
+            caller,
+        }
+    }
+    
+    /// Take the lock if it is free, without spinning.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let caller = Location::caller();
+        let daif = local_irq_save();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(feature = "lock-debug")]
+                self.debug.acquired(caller);
+                #[cfg(feature = "lock-stat")]
+                stat::record(self as *const Self as usize, caller, false, 0);
+                Some(IrqSafeMutexGuard {
+                    lock: self,
+                    guard: Some(guard),
+                    daif,
+                    caller,
+                })
+            }
+            None => {
+                local_irq_restore(daif);
+                None
+            }
+        }
+    }
+}
+
+pub struct IrqSafeMutexGuard<'a, T> {
+    #[cfg_attr(not(feature = "lock-debug"), allow(dead_code))]
+    lock: &'a IrqSafeMutex<T>,
+    guard: Option<MutexGuard<'a, T>>,
+    daif: u64,
+    #[cfg_attr(not(feature = "lock-debug"), allow(dead_code))]
+    caller: &'static Location<'static>,
+}
+
+impl<T> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+    
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lock-debug")]
+        self.lock.debug.released(self.caller);
+        
+        // Unlock before unmasking so a pending IRQ cannot find it held
+        self.guard = None;
+        local_irq_restore(self.daif);
+    }
+}
+
+struct SleepState {
+    owner: Option<ThreadId>,
+    waiters: VecDeque<ThreadId>,
+}
+
+/// A mutex whose waiters sleep. Thread context only: never take it from
+/// an interrupt handler or with an IrqSafeMutex held.
+///
+/// A boosted owner drops back to its own priority when it unlocks, so a
+/// thread holding two contended SleepMutexes loses the boost of the
+/// second when it releases the first.
+pub struct SleepMutex<T> {
+    state: IrqSafeMutex<SleepState>,
+    value: UnsafeCell<T>,
+}
+
+// Access to `value` is serialized by ownership of the lock
+unsafe impl<T: Send> Send for SleepMutex<T> {}
+unsafe impl<T: Send> Sync for SleepMutex<T> {}
+
+impl<T> SleepMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: IrqSafeMutex::new(SleepState {
+                owner: None,
+                waiters: VecDeque::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+    
+    /// Sleep until the lock is ours. The owner runs at least at our
+    /// priority meanwhile.
+    #[track_caller]
+    pub fn lock(&self) -> SleepMutexGuard<'_, T> {
+        let me = current_thread_id();
+        let mut waited = false;
+        #[cfg(feature = "lock-stat")]
+        let start = crate::interrupts::counter_ticks();
+        loop {
+            // Block under the state lock so the hand-off wakeup cannot be
+            // lost; unlock passes ownership to us before waking us
+            let mut state = self.state.lock();
+            let acquired = match state.owner {
+                None => {
+                    state.owner = Some(me);
+                    true
+                }
+                Some(owner) if owner == me => {
+                    if !waited {
+                        panic!("SleepMutex: Recursive acquisition at {}", Location::caller());
+                    }
+                    true
+                }
+                Some(owner) => {
+                    if !state.waiters.contains(&me) {
+                        state.waiters.push_back(me);
+                    }
+                    scheduler::inherit_priority(owner, scheduler::effective_priority(me));
+                    scheduler::block_current();
+                    false
+                }
+            };
+            if acquired {
+                // The whole wait, sleeping included; the state lock keeps
+                // IRQs masked as stat::record wants
+                #[cfg(feature = "lock-stat")]
+                stat::record(self.stat_id(), Location::caller(), waited,
+                             crate::interrupts::counter_ticks() - start);
+                break;
+            }
+            drop(state);
+            waited = true;
+            scheduler::yield_now();
+        }
+        SleepMutexGuard { lock: self }
+    }
+    
+    /// Take the lock if it is free, without sleeping.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SleepMutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.owner.is_some() {
+            return None;
+        }
+        state.owner = Some(current_thread_id());
+        #[cfg(feature = "lock-stat")]
+        stat::record(self.stat_id(), Location::caller(), false, 0);
+        Some(SleepMutexGuard { lock: self })
+    }
+    
+    // The lock as lock-stat knows it: the value's address, since `state`
+    // is counted under its own for the short spins around each attempt
+    #[cfg(feature = "lock-stat")]
+    fn stat_id(&self) -> usize {
+        self.value.get() as usize
+    }
+    
+    // Hand the lock to the most urgent waiter (first come among equals)
+    fn unlock(&self) {
+        {
+            let mut state = self.state.lock();
+            let next = state
+                .waiters
+                .iter()
+                .enumerate()
+                .max_by_key(|&(index, &id)| (scheduler::effective_priority(id), core::cmp::Reverse(index)))
+                .map(|(index, _)| index);
+            state.owner = next.and_then(|index| state.waiters.remove(index));
+            if let Some(next) = state.owner {
+                // The new owner now stands in the way of those still waiting
+                if let Some(top) = state.waiters.iter().map(|&id| scheduler::effective_priority(id)).max() {
+                    scheduler::inherit_priority(next, top);
+                }
+                scheduler::wake(next);
+            }
+        }
+        scheduler::restore_priority();
+    }
+}
+
+pub struct SleepMutexGuard<'a, T> {
+    lock: &'a SleepMutex<T>,
+}
+
+impl<T> Deref for SleepMutexGuard<'_, T> {
+    type Target = T;
+    
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SleepMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SleepMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+#[cfg(feature = "lock-debug")]
+mod debug {
+    use core::panic::Location;
+    use core::ptr;
+    use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+    use spin::{Mutex, MutexGuard};
+    use crate::interrupts::{counter_frequency, counter_ticks};
+    use crate::process::scheduler::current_thread_id;
+    
+    // Waiting longer than this is reported as a possible deadlock
+    const SPIN_WARN_US: u64 = 100_000;
+    // IRQs are masked while held, so anything near a timer tick is too long
+    const HOLD_WARN_US: u64 = 5_000;
+    
+    const NO_OWNER: u32 = u32::MAX;
+    
+    pub struct LockDebug {
+        owner: AtomicU32,
+        since: AtomicU64,
+        location: AtomicPtr<Location<'static>>,
+    }
+    
+    fn us_to_ticks(us: u64) -> u64 {
+        counter_frequency() * us / 1_000_000
+    }
+    
+    impl LockDebug {
+        pub const fn new() -> Self {
+            Self {
+                owner: AtomicU32::new(NO_OWNER),
+                since: AtomicU64::new(0),
+                location: AtomicPtr::new(ptr::null_mut()),
+            }
+        }
+        
+        fn holder(&self) -> &'static Location<'static> {
+            let location = self.location.load(Ordering::Relaxed);
+            if location.is_null() {
+                Location::caller()
+            } else {
+                unsafe { &*location }
+            }
+        }
+        
+        pub fn acquire<'a, T>(&self, inner: &'a Mutex<T>, caller: &'static Location<'static>) -> MutexGuard<'a, T> {
+            // IRQs are masked, so the owner cannot be switched out: a lock
+            // held by the running thread can only be a recursive acquire
+            let me = current_thread_id();
+            if inner.is_locked() && self.owner.load(Ordering::Relaxed) == me {
+                panic!("Lock: Recursive acquisition at {} (already held from {})", caller, self.holder());
+            }
+            
+            let start = counter_ticks();
+            let mut warned = false;
+            loop {
+                if let Some(guard) = inner.try_lock() {
+                    self.acquired(caller);
+                    return guard;
+                }
+                if !warned && counter_ticks() - start > us_to_ticks(SPIN_WARN_US) {
+                    crate::println!("Lock: {} waiting over {}ms; held by thread {} from {}",
+                                   caller, SPIN_WARN_US / 1000,
+                                   self.owner.load(Ordering::Relaxed), self.holder());
+                    warned = true;
+                }
+                core::hint::spin_loop();
+            }
+        }
+        
+        pub fn acquired(&self, caller: &'static Location<'static>) {
+            self.owner.store(current_thread_id(), Ordering::Relaxed);
+            self.since.store(counter_ticks(), Ordering::Relaxed);
+            self.location.store(caller as *const _ as *mut _, Ordering::Relaxed);
+        }
+        
+        pub fn released(&self, caller: &'static Location<'static>) {
+            let held = counter_ticks() - self.since.load(Ordering::Relaxed);
+            if held > us_to_ticks(HOLD_WARN_US) {
+                crate::println!("Lock: Held for {}us from {}",
+                               held * 1_000_000 / counter_frequency(), caller);
+            }
+            self.owner.store(NO_OWNER, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "lock-stat")]
+pub mod stat {
+    use alloc::vec::Vec;
+    use core::panic::Location;
+    use core::ptr;
+    use spin::Mutex;
+    use crate::interrupts::{counter_frequency, local_irq_restore, local_irq_save};
+    
+    // Distinct (lock, call site) pairs tracked; further pairs are only counted
+    const MAX_SITES: usize = 256;
+    
+    #[derive(Copy, Clone)]
+    pub struct SiteStat {
+        pub lock: usize,
+        pub site: &'static Location<'static>,
+        pub acquisitions: u64,
+        pub contended: u64,
+        pub wait_ticks: u64,
+        pub max_wait_ticks: u64,
+    }
+    
+    /// One lock's totals and its call sites, most waited-on first.
+    pub struct LockReport {
+        pub lock: usize,
+        pub acquisitions: u64,
+        pub contended: u64,
+        pub wait_ticks: u64,
+        pub max_wait_ticks: u64,
+        pub sites: Vec<SiteStat>,
+    }
+    
+    struct StatTable {
+        sites: [Option<SiteStat>; MAX_SITES],
+        overflow: u64,
+    }
+    
+    // A plain spin lock: callers already have IRQs masked, and an
+    // IrqSafeMutex here would record itself
+    static TABLE: Mutex<StatTable> = Mutex::new(StatTable {
+        sites: [None; MAX_SITES],
+        overflow: 0,
+    });
+    
+    // Called with IRQs masked, right after the lock was taken
+    pub(super) fn record(lock: usize, site: &'static Location<'static>, contended: bool, wait_ticks: u64) {
+        let mut table = TABLE.lock();
+        let hash = lock.wrapping_mul(31) ^ (site as *const Location as usize);
+        for probe in 0..MAX_SITES {
+            let slot = &mut table.sites[hash.wrapping_add(probe) % MAX_SITES];
+            match slot {
+                Some(stat) if stat.lock == lock && ptr::eq(stat.site, site) => {
+                    stat.acquisitions += 1;
+                    stat.contended += contended as u64;
+                    stat.wait_ticks += wait_ticks;
+                    stat.max_wait_ticks = stat.max_wait_ticks.max(wait_ticks);
+                    return;
+                }
+                Some(_) => continue,
+                None => {
+                    *slot = Some(SiteStat {
+                        lock,
+                        site,
+                        acquisitions: 1,
+                        contended: contended as u64,
+                        wait_ticks,
+                        max_wait_ticks: wait_ticks,
+                    });
+                    return;
+                }
+            }
+        }
+        table.overflow += 1;
+    }
+    
+    /// Per-lock statistics, most total wait first, and the number of
+    /// acquisitions that did not fit in the table.
+    pub fn report() -> (Vec<LockReport>, u64) {
+        // Allocate before taking the table lock
+        let mut sites = Vec::with_capacity(MAX_SITES);
+        let daif = local_irq_save();
+        let overflow = {
+            let table = TABLE.lock();
+            sites.extend(table.sites.iter().flatten().copied());
+            table.overflow
+        };
+        local_irq_restore(daif);
+        
+        let mut locks: Vec<LockReport> = Vec::new();
+        for site in sites {
+            let report = match locks.iter_mut().find(|report| report.lock == site.lock) {
+                Some(report) => report,
+                None => {
+                    locks.push(LockReport {
+                        lock: site.lock,
+                        acquisitions: 0,
+                        contended: 0,
+                        wait_ticks: 0,
+                        max_wait_ticks: 0,
+                        sites: Vec::new(),
+                    });
+                    locks.last_mut().unwrap()
+                }
+            };
+            report.acquisitions += site.acquisitions;
+            report.contended += site.contended;
+            report.wait_ticks += site.wait_ticks;
+            report.max_wait_ticks = report.max_wait_ticks.max(site.max_wait_ticks);
+            report.sites.push(site);
+        }
+        for report in &mut locks {
+            report.sites.sort_by_key(|site| core::cmp::Reverse((site.wait_ticks, site.acquisitions)));
+        }
+        locks.sort_by_key(|report| core::cmp::Reverse((report.wait_ticks, report.acquisitions)));
+        (locks, overflow)
+    }
+    
+    /// Forget everything recorded so far.
+    pub fn reset() {
+        let daif = local_irq_save();
+        {
+            let mut table = TABLE.lock();
+            table.sites = [None; MAX_SITES];
+            table.overflow = 0;
+        }
+        local_irq_restore(daif);
+    }
+    
+    pub fn ticks_to_us(ticks: u64) -> u64 {
+        ticks * 1_000_000 / counter_frequency()
+    }
+}
+
+#[kernel_test]
+fn test_irq_safe_mutex() {
+    crate::println!("Sync Test: Testing IrqSafeMutex...");
+    
+    static LOCK_A: IrqSafeMutex<u32> = IrqSafeMutex::new(0);
+    static LOCK_B: IrqSafeMutex<u32> = IrqSafeMutex::new(0);
+    
+    let masked_before = crate::interrupts::irqs_disabled();
+    let (held_masked, nested_ok) = {
+        let mut a = LOCK_A.lock();
+        *a += 1;
+        let nested_ok = {
+            let mut b = LOCK_B.lock();
+            *b += 1;
+            LOCK_A.try_lock().is_none()
+        };
+        // Dropping the inner guard must not unmask while the outer is held
+        (crate::interrupts::irqs_disabled(), nested_ok)
+    };
+    
+    if held_masked && nested_ok && crate::interrupts::irqs_disabled() == masked_before {
+        crate::println!("Sync Test: ✓ IRQs masked while held and restored on release");
+    } else {
+        crate::println!("Sync Test: ✗ IrqSafeMutex DAIF handling wrong");
+    }
+    
+    #[cfg(feature = "lock-stat")]
+    {
+        let (locks, _) = stat::report();
+        let a = locks.iter().find(|lock| lock.lock == &LOCK_A as *const _ as usize);
+        // Taken once above; the failed try_lock is not an acquisition
+        if a.is_some_and(|a| a.acquisitions == 1 && a.sites.len() == 1) {
+            crate::println!("Sync Test: ✓ Lock statistics count acquisitions per call site");
+        } else {
+            crate::println!("Sync Test: ✗ Lock statistics missing for test lock");
+        }
+    }
+    
+    crate::println!("Sync Test: IrqSafeMutex test completed");
+}
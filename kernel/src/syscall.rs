@@ -0,0 +1,1236 @@
+// System call dispatch
+//
+// The SVC immediate selects the call. Arguments arrive in x0-x5 and the
+// result goes back in x0; negative results are errors.
+//
+// Userspace built against another kernel revision checks SYS_ABI_VERSION
+// and the implemented-call bitmap instead of discovering gaps via ENOSYS.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::asm;
+use crate::audit::{self, AuditRecord};
+use crate::interrupts::ExceptionContext;
+use crate::ktest::kernel_test;
+use crate::memory::frame_allocator::PAGE_SIZE;
+use crate::memory::paging::PageFlags;
+use crate::process::capability::{self, Capability};
+use crate::process::scheduler::{self, current_thread_id};
+use crate::process::supervisor::{self, ExitEvent, RestartPolicy};
+use crate::tracepoint::{self, Tracepoint};
+use crate::uaccess;
+
+/// Incremented when an existing call changes incompatibly. Adding a call
+/// only sets its bit in the bitmap.
+pub const SYSCALL_ABI_VERSION: u64 = 1;
+
+/// Syscall numbers are below this; the bitmap has one bit per number.
+pub const MAX_SYSCALLS: u64 = 256;
+const BITMAP_WORDS: u64 = MAX_SYSCALLS / 64;
+
+/// Calls a thread may make, one bit per number as in the bitmap.
+pub type SyscallFilter = [u64; BITMAP_WORDS as usize];
+
+// Syscall numbers (stable once released)
+pub const SYS_ABI_VERSION: u64 = 0;
+pub const SYS_AUDIT_READ: u64 = 1;
+pub const SYS_SYSCALL_BITMAP: u64 = 2;
+pub const SYS_OOM_POLICY: u64 = 3;
+pub const SYS_SERVICE_SUPERVISE: u64 = 4;
+pub const SYS_SERVICE_EVENTS: u64 = 5;
+pub const SYS_SERVICE_RESPAWNED: u64 = 6;
+pub const SYS_EXIT: u64 = 7;
+pub const SYS_DEBUG_WRITE: u64 = 8;
+pub const SYS_MMAP: u64 = 9;
+pub const SYS_MUNMAP: u64 = 10;
+pub const SYS_RING_SETUP: u64 = 11;
+pub const SYS_RING_ENTER: u64 = 12;
+pub const SYS_PROFILE_CONTROL: u64 = 13;
+pub const SYS_PROFILE_READ: u64 = 14;
+pub const SYS_SET_PRIORITY: u64 = 15;
+pub const SYS_NANOSLEEP: u64 = 16;
+pub const SYS_CLOCK_GETTIME: u64 = 17;
+pub const SYS_REBOOT: u64 = 18;
+pub const SYS_FAULT_PORT: u64 = 19;
+pub const SYS_SYSCTL_GET: u64 = 20;
+pub const SYS_SYSCTL_SET: u64 = 21;
+pub const SYS_SHM_CREATE: u64 = 22;
+pub const SYS_SHM_OPEN: u64 = 23;
+pub const SYS_SHM_MAP: u64 = 24;
+pub const SYS_SHM_GRANT: u64 = 25;
+pub const SYS_SHM_DESTROY: u64 = 26;
+pub const SYS_PORT_SIGNAL: u64 = 27;
+pub const SYS_PORT_WAIT: u64 = 28;
+pub const SYS_CLOCK_CONTROL: u64 = 29;
+pub const SYS_IPC_CALL: u64 = 30;
+pub const SYS_IPC_RECEIVE: u64 = 31;
+pub const SYS_IPC_REPLY: u64 = 32;
+pub const SYS_IPC_CALLV: u64 = 33;
+pub const SYS_IPC_RECEIVEV: u64 = 34;
+pub const SYS_IPC_REPLYV: u64 = 35;
+pub const SYS_THREAD_SET_NAME: u64 = 36;
+pub const SYS_SYSINFO: u64 = 37;
+pub const SYS_THREAD_INFO: u64 = 38;
+pub const SYS_DEVICE_IOCTL: u64 = 39;
+pub const SYS_SYSCALL_FILTER: u64 = 40;
+
+// profile_control operations and flags
+pub const PROFILE_STOP: u64 = 0;
+pub const PROFILE_START: u64 = 1;
+pub const PROFILE_BACKTRACE: u64 = 1 << 0;
+
+// clock_control operations
+pub const CLOCK_CONTROL_REAL: u64 = 0;
+pub const CLOCK_CONTROL_VIRTUAL: u64 = 1;
+pub const CLOCK_CONTROL_ADVANCE: u64 = 2;
+
+// port_wait flags
+pub const PORT_WAIT_NONBLOCK: u64 = 1 << 0;
+
+// Buffer size the flat ipc_call/ipc_receive/ipc_reply assume; longer
+// messages need the vectored calls
+pub const MESSAGE_INLINE: usize = 256;
+// Buffers in one direction of a scatter-gather list
+pub const IPC_VEC_MAX: usize = 16;
+
+/// One buffer of a scatter-gather list.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IpcVec {
+    pub base: u64,
+    pub len: u64,
+}
+
+/// Argument of the vectored IPC calls: the buffers a message is gathered
+/// from and those the one received in return is scattered into.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IpcMsg {
+    pub send: u64,
+    pub send_count: u64,
+    pub recv: u64,
+    pub recv_count: u64,
+}
+
+/// sysinfo result: machine-wide figures.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SysInfo {
+    pub uptime_ns: u64,
+    pub frames_total: u64,
+    pub frames_free: u64,
+    pub heap_used: u64,
+    pub heap_free: u64,
+    pub irq_count: u64,
+    pub timer_ticks: u64,
+    pub threads: u64,
+    pub ports: u64,
+}
+
+// thread_info states
+pub const THREAD_STATE_READY: u32 = 0;
+pub const THREAD_STATE_RUNNING: u32 = 1;
+pub const THREAD_STATE_BLOCKED: u32 = 2;
+
+// ThreadInfo::parent of a thread nobody started
+pub const THREAD_NO_PARENT: u32 = u32::MAX;
+// ThreadInfo::name holds "process/name", NUL padded
+pub const THREAD_INFO_NAME_LEN: usize = 56;
+// Records returned by one thread_info call at most
+pub const THREAD_INFO_MAX: usize = 64;
+
+/// One thread_info record.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadInfo {
+    pub tid: u32,
+    pub parent: u32,
+    pub state: u32,
+    pub priority: u32,
+    pub cpu_ns: u64,
+    pub rss_bytes: u64,
+    pub capabilities: u32,
+    pub ports: u32,
+    pub name: [u8; THREAD_INFO_NAME_LEN],
+}
+
+// mmap protection bits
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+// Thread argument meaning the caller
+pub const THREAD_SELF: u32 = u32::MAX;
+
+// reboot commands (Linux's magic values, so a stray call does nothing)
+pub const REBOOT_CMD_RESTART: u64 = 0x0123_4567;
+pub const REBOOT_CMD_POWER_OFF: u64 = 0x4321_FEDC;
+
+// Longest path device_ioctl accepts
+const DEVICE_PATH_MAX: usize = 256;
+
+// Longest debug_write accepted in one call
+const DEBUG_WRITE_MAX: usize = 1024;
+
+// Error returns
+pub const EPERM: i64 = -1;
+pub const ENOENT: i64 = -2;
+pub const EIO: i64 = -5;
+pub const EAGAIN: i64 = -11;
+pub const ENOMEM: i64 = -12;
+pub const EFAULT: i64 = -14;
+pub const EBUSY: i64 = -16;
+pub const EEXIST: i64 = -17;
+pub const ENOTDIR: i64 = -20;
+pub const EISDIR: i64 = -21;
+pub const EINVAL: i64 = -22;
+pub const ENOTTY: i64 = -25;
+pub const ENOSPC: i64 = -28;
+pub const EROFS: i64 = -30;
+pub const ENOSYS: i64 = -38;
+
+// SPSR_EL1.M[3:0] for an exception taken from EL0
+const SPSR_MODE_MASK: u64 = 0xF;
+const SPSR_MODE_EL0T: u64 = 0b0000;
+
+type SyscallHandler = fn(&mut ExceptionContext) -> i64;
+
+struct SyscallEntry {
+    number: u64,
+    name: &'static str,
+    handler: SyscallHandler,
+}
+
+static SYSCALL_TABLE: &[SyscallEntry] = &[
+    SyscallEntry { number: SYS_ABI_VERSION, name: "abi_version", handler: sys_abi_version },
+    SyscallEntry { number: SYS_AUDIT_READ, name: "audit_read", handler: sys_audit_read },
+    SyscallEntry { number: SYS_SYSCALL_BITMAP, name: "syscall_bitmap", handler: sys_syscall_bitmap },
+    SyscallEntry { number: SYS_OOM_POLICY, name: "oom_policy", handler: sys_oom_policy },
+    SyscallEntry { number: SYS_SERVICE_SUPERVISE, name: "service_supervise", handler: sys_service_supervise },
+    SyscallEntry { number: SYS_SERVICE_EVENTS, name: "service_events", handler: sys_service_events },
+    SyscallEntry { number: SYS_SERVICE_RESPAWNED, name: "service_respawned", handler: sys_service_respawned },
+    SyscallEntry { number: SYS_EXIT, name: "exit", handler: sys_exit },
+    SyscallEntry { number: SYS_DEBUG_WRITE, name: "debug_write", handler: sys_debug_write },
+    SyscallEntry { number: SYS_MMAP, name: "mmap", handler: sys_mmap },
+    SyscallEntry { number: SYS_MUNMAP, name: "munmap", handler: sys_munmap },
+    SyscallEntry { number: SYS_RING_SETUP, name: "ring_setup", handler: sys_ring_setup },
+    SyscallEntry { number: SYS_RING_ENTER, name: "ring_enter", handler: sys_ring_enter },
+    SyscallEntry { number: SYS_PROFILE_CONTROL, name: "profile_control", handler: sys_profile_control },
+    SyscallEntry { number: SYS_PROFILE_READ, name: "profile_read", handler: sys_profile_read },
+    SyscallEntry { number: SYS_SET_PRIORITY, name: "set_priority", handler: sys_set_priority },
+    SyscallEntry { number: SYS_NANOSLEEP, name: "nanosleep", handler: sys_nanosleep },
+    SyscallEntry { number: SYS_CLOCK_GETTIME, name: "clock_gettime", handler: sys_clock_gettime },
+    SyscallEntry { number: SYS_REBOOT, name: "reboot", handler: sys_reboot },
+    SyscallEntry { number: SYS_FAULT_PORT, name: "fault_port", handler: sys_fault_port },
+    SyscallEntry { number: SYS_SYSCTL_GET, name: "sysctl_get", handler: sys_sysctl_get },
+    SyscallEntry { number: SYS_SYSCTL_SET, name: "sysctl_set", handler: sys_sysctl_set },
+    SyscallEntry { number: SYS_SHM_CREATE, name: "shm_create", handler: sys_shm_create },
+    SyscallEntry { number: SYS_SHM_OPEN, name: "shm_open", handler: sys_shm_open },
+    SyscallEntry { number: SYS_SHM_MAP, name: "shm_map", handler: sys_shm_map },
+    SyscallEntry { number: SYS_SHM_GRANT, name: "shm_grant", handler: sys_shm_grant },
+    SyscallEntry { number: SYS_SHM_DESTROY, name: "shm_destroy", handler: sys_shm_destroy },
+    SyscallEntry { number: SYS_PORT_SIGNAL, name: "port_signal", handler: sys_port_signal },
+    SyscallEntry { number: SYS_PORT_WAIT, name: "port_wait", handler: sys_port_wait },
+    SyscallEntry { number: SYS_CLOCK_CONTROL, name: "clock_control", handler: sys_clock_control },
+    SyscallEntry { number: SYS_IPC_CALL, name: "ipc_call", handler: sys_ipc_call },
+    SyscallEntry { number: SYS_IPC_RECEIVE, name: "ipc_receive", handler: sys_ipc_receive },
+    SyscallEntry { number: SYS_IPC_REPLY, name: "ipc_reply", handler: sys_ipc_reply },
+    SyscallEntry { number: SYS_IPC_CALLV, name: "ipc_callv", handler: sys_ipc_callv },
+    SyscallEntry { number: SYS_IPC_RECEIVEV, name: "ipc_receivev", handler: sys_ipc_receivev },
+    SyscallEntry { number: SYS_IPC_REPLYV, name: "ipc_replyv", handler: sys_ipc_replyv },
+    SyscallEntry { number: SYS_THREAD_SET_NAME, name: "thread_set_name", handler: sys_thread_set_name },
+    SyscallEntry { number: SYS_SYSINFO, name: "sysinfo", handler: sys_sysinfo },
+    SyscallEntry { number: SYS_THREAD_INFO, name: "thread_info", handler: sys_thread_info },
+    SyscallEntry { number: SYS_DEVICE_IOCTL, name: "device_ioctl", handler: sys_device_ioctl },
+    SyscallEntry { number: SYS_SYSCALL_FILTER, name: "syscall_filter", handler: sys_syscall_filter },
+];
+
+// Every table entry must fit the bitmap
+const _: () = {
+    let mut i = 0;
+    while i < SYSCALL_TABLE.len() {
+        assert!(SYSCALL_TABLE[i].number < MAX_SYSCALLS);
+        i += 1;
+    }
+};
+
+fn lookup(number: u64) -> Option<&'static SyscallEntry> {
+    SYSCALL_TABLE.iter().find(|entry| entry.number == number)
+}
+
+/// Name of an implemented syscall, for diagnostics.
+pub fn syscall_name(number: u64) -> Option<&'static str> {
+    lookup(number).map(|entry| entry.name)
+}
+
+/// 64 bits of the implemented-syscall bitmap, starting at `word * 64`.
+pub fn syscall_bitmap(word: u64) -> u64 {
+    SYSCALL_TABLE
+        .iter()
+        .filter(|entry| entry.number / 64 == word)
+        .fold(0, |bits, entry| bits | 1 << (entry.number % 64))
+}
+
+pub fn dispatch(ctx: &mut ExceptionContext, number: u64) {
+    tracepoint::hit(Tracepoint::SyscallEnter, number, ctx.x0);
+    let result = if !filter_allows(number) {
+        audit::filter_violation(current_thread_id(), number as u32);
+        EPERM
+    } else {
+        match lookup(number) {
+            Some(entry) => (entry.handler)(ctx),
+            None => {
+                crate::println!("Syscall: Unknown system call {} from PC: 0x{:016x}",
+                               number, ctx.elr_el1);
+                ENOSYS
+            }
+        }
+    };
+    ctx.x0 = result as u64;
+    tracepoint::hit(Tracepoint::SyscallExit, number, ctx.x0);
+}
+
+// Whether the caller's syscall filter lets `number` through. Exit always
+// passes, and numbers past the bitmap are left to fail as unknown. Only
+// filtered threads pay for the scheduler lock.
+fn filter_allows(number: u64) -> bool {
+    if number == SYS_EXIT || number >= MAX_SYSCALLS || !scheduler::current_is_filtered() {
+        return true;
+    }
+    let filter = scheduler::with_thread(current_thread_id(), |thread| thread.syscall_filter).flatten();
+    filter.is_none_or(|filter| filter[(number / 64) as usize] & (1 << (number % 64)) != 0)
+}
+
+// Kernel threads trap from EL1 and are trusted
+fn caller_is_privileged(ctx: &ExceptionContext) -> bool {
+    ctx.spsr_el1 & SPSR_MODE_MASK != SPSR_MODE_EL0T
+}
+
+// abi_version() -> SYSCALL_ABI_VERSION
+fn sys_abi_version(_ctx: &mut ExceptionContext) -> i64 {
+    SYSCALL_ABI_VERSION as i64
+}
+
+// syscall_bitmap(word) -> bits for syscalls word*64 .. word*64+63
+fn sys_syscall_bitmap(ctx: &mut ExceptionContext) -> i64 {
+    if ctx.x0 >= BITMAP_WORDS {
+        return EINVAL;
+    }
+    syscall_bitmap(ctx.x0) as i64
+}
+
+// audit_read(since_seq, records, max_records) -> records copied
+fn sys_audit_read(ctx: &mut ExceptionContext) -> i64 {
+    if !caller_is_privileged(ctx) {
+        audit::permission_denied(current_thread_id(), "audit_read");
+        return EPERM;
+    }
+    
+    let buf = ctx.x1 as *mut AuditRecord;
+    let max = ctx.x2 as usize;
+    if buf.is_null() || !buf.is_aligned() {
+        return EINVAL;
+    }
+    let out = unsafe { core::slice::from_raw_parts_mut(buf, max) };
+    audit::read(ctx.x0, out) as i64
+}
+
+// oom_policy(tid, score_adj, protected) -> 0; process manager only
+fn sys_oom_policy(ctx: &mut ExceptionContext) -> i64 {
+    if !caller_is_privileged(ctx) {
+        audit::permission_denied(current_thread_id(), "oom_policy");
+        return EPERM;
+    }
+    
+    let score_adj = match i16::try_from(ctx.x1 as i64) {
+        Ok(score_adj) => score_adj,
+        Err(_) => return EINVAL,
+    };
+    match crate::process::oom::set_policy(ctx.x0 as u32, score_adj, ctx.x2 != 0) {
+        Ok(()) => 0,
+        Err(_) => EINVAL,
+    }
+}
+
+// service_supervise(tid, policy) -> 0; process manager only
+fn sys_service_supervise(ctx: &mut ExceptionContext) -> i64 {
+    if !caller_is_privileged(ctx) {
+        audit::permission_denied(current_thread_id(), "service_supervise");
+        return EPERM;
+    }
+    
+    let policy = ctx.x1 as *const RestartPolicy;
+    if policy.is_null() || !policy.is_aligned() {
+        return EINVAL;
+    }
+    match supervisor::supervise(ctx.x0 as u32, unsafe { policy.read() }) {
+        Ok(()) => 0,
+        Err(_) => EINVAL,
+    }
+}
+
+// service_events(since_seq, events, max_events) -> events copied
+fn sys_service_events(ctx: &mut ExceptionContext) -> i64 {
+    if !caller_is_privileged(ctx) {
+        audit::permission_denied(current_thread_id(), "service_events");
+        return EPERM;
+    }
+    
+    let buf = ctx.x1 as *mut ExitEvent;
+    let max = ctx.x2 as usize;
+    if buf.is_null() || !buf.is_aligned() {
+        return EINVAL;
+    }
+    let out = unsafe { core::slice::from_raw_parts_mut(buf, max) };
+    supervisor::read_events(ctx.x0, out) as i64
+}
+
+// service_respawned(old_tid, new_tid) -> 0, EAGAIN while backing off
+fn sys_service_respawned(ctx: &mut ExceptionContext) -> i64 {
+    if !caller_is_privileged(ctx) {
+        audit::permission_denied(current_thread_id(), "service_respawned");
+        return EPERM;
+    }
+    
+    match supervisor::respawned(ctx.x0 as u32, ctx.x1 as u32) {
+        Ok(()) => 0,
+        Err(supervisor::BACKOFF_PENDING) => EAGAIN,
+        Err(_) => EINVAL,
+    }
+}
+
+// reboot(REBOOT_CMD_*) -> does not return on success; process manager only
+fn sys_reboot(ctx: &mut ExceptionContext) -> i64 {
+    if !caller_is_privileged(ctx) {
+        audit::permission_denied(current_thread_id(), "reboot");
+        return EPERM;
+    }
+    
+    let failure = match ctx.x0 {
+        REBOOT_CMD_RESTART => crate::power::reboot(),
+        REBOOT_CMD_POWER_OFF => crate::power::poweroff(),
+        _ => return EINVAL,
+    };
+    crate::println!("Syscall: reboot failed: {}", failure);
+    ENOSYS
+}
+
+// UTF-8 string of `len` bytes from the caller's memory
+fn user_str(privileged: bool, ptr: u64, len: usize) -> Result<String, i64> {
+    let bytes = uaccess::read_user(privileged, ptr, len).map_err(|_| EFAULT)?;
+    String::from_utf8(bytes).map_err(|_| EINVAL)
+}
+
+/// Errno for a filesystem error string.
+pub fn fs_errno(error: &str) -> i64 {
+    match error {
+        "No such file or directory" => ENOENT,
+        "Not a directory" => ENOTDIR,
+        "Is a directory" => EISDIR,
+        "File exists" => EEXIST,
+        "No space left on device" => ENOSPC,
+        crate::vfs::READ_ONLY => EROFS,
+        "Invalid file name" | "Path not absolute" => EINVAL,
+        _ => EIO,
+    }
+}
+
+// exit(status) -> does not return
+fn sys_exit(ctx: &mut ExceptionContext) -> i64 {
+    let status = ctx.x0 as u32;
+    crate::process::mark_exited(crate::process::supervisor::ExitReason::Exited, status);
+    scheduler::request_resched();
+    0
+}
+
+// debug_write(buf, len) -> bytes written to the kernel console
+fn sys_debug_write(ctx: &mut ExceptionContext) -> i64 {
+    let len = (ctx.x1 as usize).min(DEBUG_WRITE_MAX);
+    let Ok(bytes) = uaccess::read_user(caller_is_privileged(ctx), ctx.x0, len) else { return EFAULT };
+    match core::str::from_utf8(&bytes) {
+        Ok(text) => crate::print!("{}", text),
+        Err(_) => bytes.iter().for_each(|&b| crate::print!("{}", if b.is_ascii() { b as char } else { '?' })),
+    }
+    len as i64
+}
+
+// mmap(len, prot) -> address of zeroed anonymous memory
+fn sys_mmap(ctx: &mut ExceptionContext) -> i64 {
+    let (len, prot) = (ctx.x0 as usize, ctx.x1);
+    if len == 0 || prot & PROT_READ == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return EINVAL;
+    }
+    // W^X: code is mapped read-only
+    if prot & PROT_WRITE != 0 && prot & PROT_EXEC != 0 {
+        audit::permission_denied(current_thread_id(), "mmap writable and executable");
+        return EPERM;
+    }
+    let mut flags = PageFlags::NORMAL_MEMORY | PageFlags::INNER_SHAREABLE | PageFlags::ACCESSED
+        | PageFlags::USER | PageFlags::PXN;
+    if prot & PROT_WRITE == 0 {
+        flags |= PageFlags::READ_ONLY;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageFlags::UXN;
+    }
+    
+    let pages = len.div_ceil(PAGE_SIZE);
+    let mapped = scheduler::with_thread(current_thread_id(), |thread| {
+        thread.address_space().map(|space| space.map_anonymous(pages, flags))
+    });
+    match mapped {
+        Some(Some(Ok(addr))) => addr as i64,
+        Some(Some(Err(_))) => ENOMEM,
+        _ => EINVAL,
+    }
+}
+
+// munmap(addr, len) -> 0
+fn sys_munmap(ctx: &mut ExceptionContext) -> i64 {
+    let (addr, len) = (ctx.x0, ctx.x1 as usize);
+    let unmapped = scheduler::with_thread(current_thread_id(), |thread| {
+        thread.address_space().map(|space| space.unmap(addr, len.div_ceil(PAGE_SIZE)))
+    });
+    match unmapped {
+        Some(Some(Ok(()))) => 0,
+        _ => EINVAL,
+    }
+}
+
+// ring_setup(entries) -> address of the shared ring
+fn sys_ring_setup(ctx: &mut ExceptionContext) -> i64 {
+    match crate::uring::setup(ctx.x0 as u32) {
+        Ok(addr) => addr as i64,
+        Err(e) => e,
+    }
+}
+
+// ring_enter(to_submit, min_complete) -> submissions consumed
+fn sys_ring_enter(ctx: &mut ExceptionContext) -> i64 {
+    let privileged = caller_is_privileged(ctx);
+    match crate::uring::enter(ctx.x0 as u32, ctx.x1 as u32, privileged) {
+        Ok(submitted) => submitted as i64,
+        Err(e) => e,
+    }
+}
+
+// profile_control(op, samples_per_cpu, flags) -> 0; 0 samples = default
+fn sys_profile_control(ctx: &mut ExceptionContext) -> i64 {
+    if !caller_is_privileged(ctx) {
+        audit::permission_denied(current_thread_id(), "profile_control");
+        return EPERM;
+    }
+    
+    match ctx.x0 {
+        PROFILE_STOP => {
+            crate::profile::stop();
+            0
+        }
+        PROFILE_START => {
+            let capacity = match ctx.x1 {
+                0 => crate::profile::PROFILE_DEFAULT_SAMPLES,
+                samples => samples as usize,
+            };
+            match crate::profile::start(capacity, ctx.x2 & PROFILE_BACKTRACE != 0) {
+                Ok(()) => 0,
+                Err(_) if crate::profile::is_running() => EBUSY,
+                Err(_) => ENOMEM,
+            }
+        }
+        _ => EINVAL,
+    }
+}
+
+// profile_read(offset, samples, max_samples) -> samples copied
+fn sys_profile_read(ctx: &mut ExceptionContext) -> i64 {
+    if !caller_is_privileged(ctx) {
+        audit::permission_denied(current_thread_id(), "profile_read");
+        return EPERM;
+    }
+    
+    let buf = ctx.x1 as *mut crate::profile::Sample;
+    let max = ctx.x2 as usize;
+    if buf.is_null() || !buf.is_aligned() {
+        return EINVAL;
+    }
+    let out = unsafe { core::slice::from_raw_parts_mut(buf, max) };
+    crate::profile::read(ctx.x0 as usize, out) as i64
+}
+
+// set_priority(tid or THREAD_SELF, priority) -> 0. User threads may only
+// lower their own.
+fn sys_set_priority(ctx: &mut ExceptionContext) -> i64 {
+    let tid = match ctx.x0 as u32 {
+        THREAD_SELF => current_thread_id(),
+        tid => tid,
+    };
+    let priority = ctx.x1;
+    let Ok(priority) = crate::process::Priority::try_from(priority) else {
+        return EINVAL;
+    };
+    if !caller_is_privileged(ctx) {
+        let me = current_thread_id();
+        if tid != me || scheduler::priority(me).is_none_or(|current| priority > current) {
+            audit::permission_denied(me, "set_priority");
+            return EPERM;
+        }
+    }
+    match scheduler::set_priority(tid, priority) {
+        Ok(()) => 0,
+        Err(_) => EINVAL,
+    }
+}
+
+// thread_set_name(tid or THREAD_SELF, name, len) -> 0. User threads may
+// only rename themselves. Names are UTF-8, 1 to THREAD_NAME_LEN bytes,
+// without '/', which separates a label's process and thread parts.
+fn sys_thread_set_name(ctx: &mut ExceptionContext) -> i64 {
+    let me = current_thread_id();
+    let tid = match ctx.x0 as u32 {
+        THREAD_SELF => me,
+        tid => tid,
+    };
+    let privileged = caller_is_privileged(ctx);
+    if tid != me && !privileged {
+        audit::permission_denied(me, "thread_set_name");
+        return EPERM;
+    }
+    if ctx.x2 as usize > crate::process::thread::THREAD_NAME_LEN {
+        return EINVAL;
+    }
+    let name = match user_str(privileged, ctx.x1, ctx.x2 as usize) {
+        Ok(name) => name,
+        Err(errno) => return errno,
+    };
+    if name.is_empty() || name.contains('/') {
+        return EINVAL;
+    }
+    match scheduler::set_name(tid, &name) {
+        Ok(()) => 0,
+        Err(_) => ENOENT,
+    }
+}
+
+// The bytes of padding-free repr(C) records, for copying out
+fn record_bytes<T: Copy>(records: &[T]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(records.as_ptr() as *const u8, core::mem::size_of_val(records)) }
+}
+
+// sysinfo(*mut SysInfo) -> 0
+fn sys_sysinfo(ctx: &mut ExceptionContext) -> i64 {
+    let system = crate::sysinfo::system();
+    let info = SysInfo {
+        uptime_ns: system.uptime_ns,
+        frames_total: system.frames_total as u64,
+        frames_free: system.frames_free as u64,
+        heap_used: system.heap_used as u64,
+        heap_free: system.heap_free as u64,
+        irq_count: system.irq_count,
+        timer_ticks: system.timer_ticks,
+        threads: system.threads as u64,
+        ports: system.ports as u64,
+    };
+    match uaccess::copy_to_user(caller_is_privileged(ctx), ctx.x0, record_bytes(core::slice::from_ref(&info))) {
+        Ok(()) => 0,
+        Err(_) => EFAULT,
+    }
+}
+
+// thread_info(first_tid, *mut ThreadInfo, count) -> records written, for
+// threads from first_tid up in ID order. Unprivileged callers see
+// themselves and the threads they started.
+fn sys_thread_info(ctx: &mut ExceptionContext) -> i64 {
+    use crate::memory::frame_allocator::PAGE_SIZE;
+    use crate::process::thread::ThreadState;
+    
+    let count = (ctx.x2 as usize).min(THREAD_INFO_MAX);
+    let privileged = caller_is_privileged(ctx);
+    let me = current_thread_id();
+    let records: Vec<ThreadInfo> = crate::sysinfo::threads()
+        .into_iter()
+        .filter(|thread| thread.id as u64 >= ctx.x0)
+        .filter(|thread| privileged || thread.id == me || thread.parent == Some(me))
+        .take(count)
+        .map(|thread| {
+            let mut name = [0; THREAD_INFO_NAME_LEN];
+            let label = thread.label.as_str().as_bytes();
+            let len = label.len().min(THREAD_INFO_NAME_LEN);
+            name[..len].copy_from_slice(&label[..len]);
+            ThreadInfo {
+                tid: thread.id,
+                parent: thread.parent.unwrap_or(THREAD_NO_PARENT),
+                state: match thread.state {
+                    ThreadState::Running => THREAD_STATE_RUNNING,
+                    ThreadState::Blocked => THREAD_STATE_BLOCKED,
+                    _ => THREAD_STATE_READY,
+                },
+                priority: thread.priority as u32,
+                cpu_ns: thread.cpu_ns(),
+                rss_bytes: (thread.rss_frames * PAGE_SIZE) as u64,
+                capabilities: thread.capabilities as u32,
+                ports: thread.ports as u32,
+                name,
+            }
+        })
+        .collect();
+    match uaccess::copy_to_user(privileged, ctx.x1, record_bytes(&records)) {
+        Ok(()) => records.len() as i64,
+        Err(_) => EFAULT,
+    }
+}
+
+// device_ioctl(path, path_len, cmd, arg) -> the request's result, for a
+// device file under /dev. `arg` is passed by value. EL0 callers need the
+// Device capability.
+fn sys_device_ioctl(ctx: &mut ExceptionContext) -> i64 {
+    let privileged = caller_is_privileged(ctx);
+    if !privileged && !capability::held(current_thread_id()).contains(&Capability::Device) {
+        audit::permission_denied(current_thread_id(), "device_ioctl");
+        return EPERM;
+    }
+    let len = ctx.x1 as usize;
+    if len > DEVICE_PATH_MAX {
+        return EINVAL;
+    }
+    let path = match user_str(privileged, ctx.x0, len) {
+        Ok(path) => path,
+        Err(errno) => return errno,
+    };
+    let device = crate::vfs::normalize(&path).ok().and_then(|path| {
+        path.strip_prefix(crate::devfs::MOUNT_POINT)?.strip_prefix('/').map(String::from)
+    });
+    let Some(device) = device else { return ENOENT };
+    match crate::devfs::ioctl(&device, ctx.x2 as u32, ctx.x3 as usize) {
+        Ok(result) => result as i64,
+        Err("No such device") => ENOENT,
+        Err("Operation not supported") => ENOTTY,
+        Err(_) => EINVAL,
+    }
+}
+
+// syscall_filter(word, allowed) -> 0. Of calls word*64 .. word*64+63 the
+// caller keeps only those set in `allowed`. Filters only narrow, and
+// threads the caller spawns start with its filter.
+fn sys_syscall_filter(ctx: &mut ExceptionContext) -> i64 {
+    if ctx.x0 >= BITMAP_WORDS {
+        return EINVAL;
+    }
+    let narrowed = scheduler::with_thread(current_thread_id(), |thread| {
+        let filter = thread.syscall_filter.get_or_insert([u64::MAX; BITMAP_WORDS as usize]);
+        filter[ctx.x0 as usize] &= ctx.x1;
+    });
+    if narrowed.is_none() {
+        return EINVAL;
+    }
+    scheduler::mark_current_filtered();
+    0
+}
+
+// nanosleep(ns) -> 0 once at least `ns` nanoseconds have passed
+fn sys_nanosleep(ctx: &mut ExceptionContext) -> i64 {
+    match crate::timer::sleep_ns(ctx.x0) {
+        Ok(()) => 0,
+        Err(_) => EAGAIN,
+    }
+}
+
+// clock_gettime(clock, *mut Timespec) -> 0. EAGAIN for CLOCK_REALTIME
+// before an RTC has been read.
+fn sys_clock_gettime(ctx: &mut ExceptionContext) -> i64 {
+    use crate::time::{self, Timespec};
+    
+    let clock = ctx.x0 as u32;
+    if clock != time::CLOCK_REALTIME && clock != time::CLOCK_MONOTONIC {
+        return EINVAL;
+    }
+    let privileged = caller_is_privileged(ctx);
+    if !uaccess::access_ok(privileged, ctx.x1, core::mem::size_of::<Timespec>(), true) {
+        return EFAULT;
+    }
+    let now = match time::clock_gettime(clock) {
+        Ok(now) => now,
+        Err(_) => return EAGAIN,
+    };
+    let mut out = [0; 16];
+    out[..8].copy_from_slice(&now.sec.to_ne_bytes());
+    out[8..].copy_from_slice(&now.nsec.to_ne_bytes());
+    match uaccess::copy_to_user(privileged, ctx.x1, &out) {
+        Ok(()) => 0,
+        Err(_) => EFAULT,
+    }
+}
+
+// Caller's scatter-gather list of `count` entries at `ptr`
+fn user_vecs(privileged: bool, ptr: u64, count: u64) -> Result<Vec<IpcVec>, i64> {
+    if count as usize > IPC_VEC_MAX {
+        return Err(EINVAL);
+    }
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    let entry = core::mem::size_of::<IpcVec>();
+    let bytes = uaccess::read_user(privileged, ptr, count as usize * entry).map_err(|_| EFAULT)?;
+    let word = |chunk: &[u8], i: usize| u64::from_ne_bytes(chunk[i * 8..i * 8 + 8].try_into().unwrap());
+    Ok(bytes.chunks_exact(entry).map(|chunk| IpcVec { base: word(chunk, 0), len: word(chunk, 1) }).collect())
+}
+
+// The vectored calls' IpcMsg, with both of its lists
+fn user_ipc_msg(privileged: bool, ptr: u64) -> Result<(Vec<IpcVec>, Vec<IpcVec>), i64> {
+    let mut bytes = [0; core::mem::size_of::<IpcMsg>()];
+    uaccess::copy_from_user(privileged, &mut bytes, ptr).map_err(|_| EFAULT)?;
+    let word = |i: usize| u64::from_ne_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+    let msg = IpcMsg { send: word(0), send_count: word(1), recv: word(2), recv_count: word(3) };
+    Ok((user_vecs(privileged, msg.send, msg.send_count)?, user_vecs(privileged, msg.recv, msg.recv_count)?))
+}
+
+// Message gathered from the caller's buffers
+fn user_message(privileged: bool, vecs: &[IpcVec]) -> Result<crate::ipc::Message, i64> {
+    use crate::ipc::{Message, MESSAGE_MAX};
+    
+    let total = vecs.iter().try_fold(0u64, |total, vec| total.checked_add(vec.len)).ok_or(EINVAL)?;
+    if total as usize > MESSAGE_MAX {
+        return Err(EINVAL);
+    }
+    let mut data = Vec::with_capacity(total as usize);
+    for vec in vecs {
+        let start = data.len();
+        data.resize(start + vec.len as usize, 0);
+        uaccess::copy_from_user(privileged, &mut data[start..], vec.base).map_err(|_| EFAULT)?;
+    }
+    Ok(Message {
+        sender: current_thread_id(),
+        data,
+        trace_id: crate::trace::TRACE_ID_NONE,
+        grant: None,
+        reply: None,
+    })
+}
+
+// Checked before blocking, so a message is never taken only to be lost
+fn vecs_writable(privileged: bool, vecs: &[IpcVec]) -> bool {
+    vecs.iter().all(|vec| vec.len == 0 || uaccess::access_ok(privileged, vec.base, vec.len as usize, true))
+}
+
+// Scatter a received message over the caller's buffers, cutting it short
+// if they run out. Page grants are only delivered through the ring and
+// are dropped here.
+fn scatter_message(privileged: bool, vecs: &[IpcVec], message: &crate::ipc::Message) -> Result<usize, i64> {
+    let mut rest = message.data.as_slice();
+    for vec in vecs {
+        if rest.is_empty() {
+            break;
+        }
+        let len = rest.len().min(vec.len as usize);
+        uaccess::copy_to_user(privileged, vec.base, &rest[..len]).map_err(|_| EFAULT)?;
+        rest = &rest[len..];
+    }
+    Ok(message.data.len() - rest.len())
+}
+
+// Flat calls: one MESSAGE_INLINE-byte buffer, and the return value is
+// reply ID << 16 | length delivered, reply ID 0 for a plain message
+fn deliver_message(privileged: bool, ptr: u64, message: &crate::ipc::Message) -> i64 {
+    let buffer = [IpcVec { base: ptr, len: MESSAGE_INLINE as u64 }];
+    match scatter_message(privileged, &buffer, message) {
+        Ok(len) => (message.reply.unwrap_or(0) as i64) << 16 | len as i64,
+        Err(errno) => errno,
+    }
+}
+
+// Vectored calls: reply ID << 32 | full length, which exceeds the buffers'
+// total when the message was cut short
+fn deliver_vectored(privileged: bool, vecs: &[IpcVec], message: &crate::ipc::Message) -> i64 {
+    match scatter_message(privileged, vecs, message) {
+        Ok(_) => (message.reply.unwrap_or(0) as i64) << 32 | message.data.len() as i64,
+        Err(errno) => errno,
+    }
+}
+
+fn ipc_errno(error: &str) -> i64 {
+    match error {
+        "Port buffer full" => EAGAIN,
+        "Port already has a receiver" => EBUSY,
+        "No such reply" => ENOENT,
+        "Reply belongs to another thread" => EPERM,
+        _ => EINVAL,
+    }
+}
+
+// ipc_call(port, buf, len) -> answer length. Sends buf[..len] and blocks
+// until the receiver replies; the answer overwrites buf, which must have
+// room for MESSAGE_INLINE bytes.
+fn sys_ipc_call(ctx: &mut ExceptionContext) -> i64 {
+    let privileged = caller_is_privileged(ctx);
+    let Ok(id) = u32::try_from(ctx.x0) else { return ENOENT };
+    let Some(port) = crate::ipc::lookup_port_for(id, current_thread_id(), privileged) else { return EPERM };
+    if !uaccess::access_ok(privileged, ctx.x1, MESSAGE_INLINE, true) {
+        return EFAULT;
+    }
+    let message = match user_message(privileged, &[IpcVec { base: ctx.x1, len: ctx.x2 }]) {
+        Ok(message) => message,
+        Err(errno) => return errno,
+    };
+    match crate::ipc::call(&port, message) {
+        Ok(answer) => deliver_message(privileged, ctx.x1, &answer),
+        Err(e) => ipc_errno(e),
+    }
+}
+
+// ipc_receive(port, buf) -> reply ID << 16 | length. Blocks for the next
+// message; buf must have room for MESSAGE_INLINE bytes, and a longer
+// message is cut short.
+fn sys_ipc_receive(ctx: &mut ExceptionContext) -> i64 {
+    let privileged = caller_is_privileged(ctx);
+    let Ok(id) = u32::try_from(ctx.x0) else { return ENOENT };
+    let Some(port) = crate::ipc::lookup_port_for(id, current_thread_id(), privileged) else { return EPERM };
+    if !uaccess::access_ok(privileged, ctx.x1, MESSAGE_INLINE, true) {
+        return EFAULT;
+    }
+    match port.receive_wait() {
+        Ok(message) => deliver_message(privileged, ctx.x1, &message),
+        Err(e) => ipc_errno(e),
+    }
+}
+
+// ipc_reply(reply, buf, len | port << 32). Answers a call with buf[..len];
+// returns 0, or with a port, waits there next and returns as ipc_receive,
+// the next message overwriting buf.
+fn sys_ipc_reply(ctx: &mut ExceptionContext) -> i64 {
+    let privileged = caller_is_privileged(ctx);
+    let (len, port_id) = (ctx.x2 & 0xFFFF_FFFF, (ctx.x2 >> 32) as u32);
+    let Ok(reply) = u32::try_from(ctx.x0) else { return ENOENT };
+    let port = match port_id {
+        0 => None,
+        id => match crate::ipc::lookup_port_for(id, current_thread_id(), privileged) {
+            Some(port) => Some(port),
+            None => return EPERM,
+        },
+    };
+    if port.is_some() && !uaccess::access_ok(privileged, ctx.x1, MESSAGE_INLINE, true) {
+        return EFAULT;
+    }
+    let answer = match user_message(privileged, &[IpcVec { base: ctx.x1, len }]) {
+        Ok(answer) => answer,
+        Err(errno) => return errno,
+    };
+    let Some(port) = port else {
+        return match crate::ipc::reply(reply, answer) {
+            Ok(()) => 0,
+            Err(e) => ipc_errno(e),
+        };
+    };
+    match crate::ipc::reply_and_receive(reply, answer, &port) {
+        Ok(message) => deliver_message(privileged, ctx.x1, &message),
+        Err(e) => ipc_errno(e),
+    }
+}
+
+// ipc_callv(port, msg) -> answer length. As ipc_call, but the request is
+// gathered from msg's send buffers and the answer scattered over its
+// receive buffers. A length beyond their total means the answer was cut
+// short.
+fn sys_ipc_callv(ctx: &mut ExceptionContext) -> i64 {
+    let privileged = caller_is_privileged(ctx);
+    let Ok(id) = u32::try_from(ctx.x0) else { return ENOENT };
+    let Some(port) = crate::ipc::lookup_port_for(id, current_thread_id(), privileged) else { return EPERM };
+    let (send, recv) = match user_ipc_msg(privileged, ctx.x1) {
+        Ok(lists) => lists,
+        Err(errno) => return errno,
+    };
+    if !vecs_writable(privileged, &recv) {
+        return EFAULT;
+    }
+    let message = match user_message(privileged, &send) {
+        Ok(message) => message,
+        Err(errno) => return errno,
+    };
+    match crate::ipc::call(&port, message) {
+        Ok(answer) => deliver_vectored(privileged, &recv, &answer),
+        Err(e) => ipc_errno(e),
+    }
+}
+
+// ipc_receivev(port, msg) -> reply ID << 32 | length. As ipc_receive,
+// scattering the message over msg's receive buffers.
+fn sys_ipc_receivev(ctx: &mut ExceptionContext) -> i64 {
+    let privileged = caller_is_privileged(ctx);
+    let Ok(id) = u32::try_from(ctx.x0) else { return ENOENT };
+    let Some(port) = crate::ipc::lookup_port_for(id, current_thread_id(), privileged) else { return EPERM };
+    let recv = match user_ipc_msg(privileged, ctx.x1) {
+        Ok((_, recv)) => recv,
+        Err(errno) => return errno,
+    };
+    if !vecs_writable(privileged, &recv) {
+        return EFAULT;
+    }
+    match port.receive_wait() {
+        Ok(message) => deliver_vectored(privileged, &recv, &message),
+        Err(e) => ipc_errno(e),
+    }
+}
+
+// ipc_replyv(reply, msg, port) -> 0. As ipc_reply, gathering the answer
+// from msg's send buffers; with a port, waits there next and returns as
+// ipc_receivev.
+fn sys_ipc_replyv(ctx: &mut ExceptionContext) -> i64 {
+    let privileged = caller_is_privileged(ctx);
+    let Ok(reply) = u32::try_from(ctx.x0) else { return ENOENT };
+    let port = match u32::try_from(ctx.x2) {
+        Ok(0) => None,
+        Ok(id) => match crate::ipc::lookup_port_for(id, current_thread_id(), privileged) {
+            Some(port) => Some(port),
+            None => return EPERM,
+        },
+        Err(_) => return ENOENT,
+    };
+    let (send, recv) = match user_ipc_msg(privileged, ctx.x1) {
+        Ok(lists) => lists,
+        Err(errno) => return errno,
+    };
+    if port.is_some() && !vecs_writable(privileged, &recv) {
+        return EFAULT;
+    }
+    let answer = match user_message(privileged, &send) {
+        Ok(answer) => answer,
+        Err(errno) => return errno,
+    };
+    let Some(port) = port else {
+        return match crate::ipc::reply(reply, answer) {
+            Ok(()) => 0,
+            Err(e) => ipc_errno(e),
+        };
+    };
+    match crate::ipc::reply_and_receive(reply, answer, &port) {
+        Ok(message) => deliver_vectored(privileged, &recv, &message),
+        Err(e) => ipc_errno(e),
+    }
+}
+
+// clock_control(op, ns) -> CLOCK_MONOTONIC in ns. Privileged. Switches
+// the kernel clock between real and virtual time, or advances the
+// virtual clock by `ns`, firing the timers that fall due.
+fn sys_clock_control(ctx: &mut ExceptionContext) -> i64 {
+    use crate::time;
+    
+    if !caller_is_privileged(ctx) {
+        audit::permission_denied(current_thread_id(), "clock_control");
+        return EPERM;
+    }
+    
+    match ctx.x0 {
+        CLOCK_CONTROL_REAL => time::set_virtual(false),
+        CLOCK_CONTROL_VIRTUAL => time::set_virtual(true),
+        CLOCK_CONTROL_ADVANCE => {
+            if time::advance(ctx.x1).is_err() {
+                return EINVAL;
+            }
+        }
+        _ => return EINVAL,
+    }
+    time::monotonic_ns() as i64
+}
+
+// fault_port(port) -> 0. Children killed by a fault are reported to
+// `port` (owned by the caller) as FaultReport messages; 0 turns it off.
+fn sys_fault_port(ctx: &mut ExceptionContext) -> i64 {
+    let port = match ctx.x0 {
+        0 => None,
+        port => match u32::try_from(port) {
+            Ok(port) => Some(port),
+            Err(_) => return ENOENT,
+        },
+    };
+    match crate::process::fault::set_fault_port(port) {
+        Ok(()) => 0,
+        Err("Port not owned by the caller") => EPERM,
+        Err(_) => ENOENT,
+    }
+}
+
+// Setting name passed as (ptr, len) in x0/x1
+fn sysctl_name(ctx: &ExceptionContext) -> Result<String, i64> {
+    let len = ctx.x1 as usize;
+    if len > crate::sysctl::SYSCTL_NAME_MAX {
+        return Err(EINVAL);
+    }
+    user_str(caller_is_privileged(ctx), ctx.x0, len)
+}
+
+// sysctl_get(name, name_len) -> value
+fn sys_sysctl_get(ctx: &mut ExceptionContext) -> i64 {
+    let name = match sysctl_name(ctx) {
+        Ok(name) => name,
+        Err(errno) => return errno,
+    };
+    match crate::sysctl::lookup(&name) {
+        Some(sysctl) => sysctl.get() as i64,
+        None => ENOENT,
+    }
+}
+
+// sysctl_set(name, name_len, value) -> 0; privileged or Sysctl capability
+fn sys_sysctl_set(ctx: &mut ExceptionContext) -> i64 {
+    let name = match sysctl_name(ctx) {
+        Ok(name) => name,
+        Err(errno) => return errno,
+    };
+    match crate::sysctl::write(&name, ctx.x2, caller_is_privileged(ctx), current_thread_id()) {
+        Ok(()) => 0,
+        Err("No such setting") => ENOENT,
+        Err("Permission denied") => EPERM,
+        Err(_) => EINVAL,
+    }
+}
+
+// Shared memory object name passed as (ptr, len); None when len is 0
+fn shm_name(ctx: &ExceptionContext, ptr: u64, len: u64) -> Result<Option<String>, i64> {
+    if len == 0 {
+        return Ok(None);
+    }
+    if len as usize > crate::shm::SHM_NAME_MAX {
+        return Err(EINVAL);
+    }
+    user_str(caller_is_privileged(ctx), ptr, len as usize).map(Some)
+}
+
+fn shm_errno(error: &str) -> i64 {
+    match error {
+        "No such shared memory object" | "No such thread" => ENOENT,
+        "Permission denied" => EPERM,
+        "Shared memory name in use" => EEXIST,
+        "Out of memory" | "Out of user address space" => ENOMEM,
+        _ => EINVAL,
+    }
+}
+
+// shm_create(pages, name, name_len) -> id; the caller gets a writable capability
+fn sys_shm_create(ctx: &mut ExceptionContext) -> i64 {
+    let name = match shm_name(ctx, ctx.x1, ctx.x2) {
+        Ok(name) => name,
+        Err(errno) => return errno,
+    };
+    match crate::shm::create(current_thread_id(), name.as_deref(), ctx.x0 as usize) {
+        Ok(id) => id as i64,
+        Err(e) => shm_errno(e),
+    }
+}
+
+// shm_open(name, name_len) -> id of a named object the caller may map
+fn sys_shm_open(ctx: &mut ExceptionContext) -> i64 {
+    let name = match shm_name(ctx, ctx.x0, ctx.x1) {
+        Ok(Some(name)) => name,
+        Ok(None) => return EINVAL,
+        Err(errno) => return errno,
+    };
+    match crate::shm::open(current_thread_id(), &name) {
+        Ok(id) => id as i64,
+        Err(e) => shm_errno(e),
+    }
+}
+
+// shm_map(id, prot) -> address; unmapped with munmap
+fn sys_shm_map(ctx: &mut ExceptionContext) -> i64 {
+    let prot = ctx.x1;
+    if prot & PROT_READ == 0 || prot & !(PROT_READ | PROT_WRITE) != 0 {
+        return EINVAL;
+    }
+    match crate::shm::map_for(current_thread_id(), ctx.x0 as u32, prot & PROT_WRITE != 0) {
+        Ok(addr) => addr as i64,
+        Err(e) => shm_errno(e),
+    }
+}
+
+// shm_grant(id, thread, prot) -> 0; at most the access the caller holds
+fn sys_shm_grant(ctx: &mut ExceptionContext) -> i64 {
+    let prot = ctx.x2;
+    if prot & PROT_READ == 0 || prot & !(PROT_READ | PROT_WRITE) != 0 {
+        return EINVAL;
+    }
+    match crate::shm::grant(current_thread_id(), ctx.x0 as u32, ctx.x1 as u32, prot & PROT_WRITE != 0) {
+        Ok(()) => 0,
+        Err(e) => shm_errno(e),
+    }
+}
+
+// shm_destroy(id) -> 0; the creator or a privileged caller
+fn sys_shm_destroy(ctx: &mut ExceptionContext) -> i64 {
+    match crate::shm::destroy(current_thread_id(), ctx.x0 as u32, caller_is_privileged(ctx)) {
+        Ok(()) => 0,
+        Err(e) => shm_errno(e),
+    }
+}
+
+// port_signal(port, bits) -> 0. Needs a capability for the port.
+fn sys_port_signal(ctx: &mut ExceptionContext) -> i64 {
+    let Ok(id) = u32::try_from(ctx.x0) else { return ENOENT };
+    match crate::ipc::lookup_port_for(id, current_thread_id(), caller_is_privileged(ctx)) {
+        Some(port) => {
+            port.signal(ctx.x1);
+            0
+        }
+        None => EPERM,
+    }
+}
+
+// port_wait(port, *mut u64, flags) -> 0. Blocks until bits are raised
+// unless flags has PORT_WAIT_NONBLOCK, in which case EAGAIN means none.
+// The bits go through memory since all 64 may be set.
+fn sys_port_wait(ctx: &mut ExceptionContext) -> i64 {
+    let privileged = caller_is_privileged(ctx);
+    let Ok(id) = u32::try_from(ctx.x0) else { return ENOENT };
+    if ctx.x2 & !PORT_WAIT_NONBLOCK != 0 {
+        return EINVAL;
+    }
+    if !uaccess::access_ok(privileged, ctx.x1, 8, true) {
+        return EFAULT;
+    }
+    let Some(port) = crate::ipc::lookup_port_for(id, current_thread_id(), privileged) else { return EPERM };
+    let bits = if ctx.x2 & PORT_WAIT_NONBLOCK != 0 {
+        match port.poll_bits() {
+            0 => return EAGAIN,
+            bits => bits,
+        }
+    } else {
+        match port.wait_bits() {
+            Ok(bits) => bits,
+            Err(_) => return EBUSY,
+        }
+    };
+    // Checked again by the copy: the caller's mappings may have changed
+    // while it slept
+    match uaccess::copy_to_user(privileged, ctx.x1, &bits.to_ne_bytes()) {
+        Ok(()) => 0,
+        Err(_) => {
+            // Don't lose them
+            port.signal(bits);
+            EFAULT
+        }
+    }
+}
+
+#[kernel_test]
+fn test_syscall_probe() {
+    crate::println!("Syscall Test: Testing syscall feature probing...");
+    
+    let version: u64;
+    let bitmap: u64;
+    unsafe {
+        asm!("svc #{nr}", nr = const SYS_ABI_VERSION, out("x0") version);
+        asm!("svc #{nr}", nr = const SYS_SYSCALL_BITMAP, inout("x0") 0u64 => bitmap);
+    }
+    
+    if version == SYSCALL_ABI_VERSION {
+        crate::println!("Syscall Test: ✓ ABI version {}", version);
+    } else {
+        crate::println!("Syscall Test: ✗ ABI version {} (expected {})", version, SYSCALL_ABI_VERSION);
+    }
+    
+    let expected = (1 << SYS_ABI_VERSION) | (1 << SYS_AUDIT_READ) | (1 << SYS_SYSCALL_BITMAP);
+    if bitmap & expected == expected && bitmap & (1 << 42) == 0 {
+        crate::println!("Syscall Test: ✓ Syscall bitmap 0x{:x}", bitmap);
+    } else {
+        crate::println!("Syscall Test: ✗ Syscall bitmap 0x{:x} wrong", bitmap);
+    }
+    
+    crate::println!("Syscall Test: Syscall probe test completed");
+}
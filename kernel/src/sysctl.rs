@@ -0,0 +1,252 @@
+// Runtime tunables
+//
+// A fixed table of named, typed kernel settings, read and written through
+// the sysctl syscalls, the `sysctl` shell command and /proc/sysctl. Each
+// entry has a getter and a setter into the subsystem that owns the
+// setting; the type is checked here before the setter sees a value.
+//
+// Anyone may read a setting. Writing one takes a privileged caller or the
+// Sysctl capability, and every write is audited.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt::Write;
+use crate::ktest::kernel_test;
+use crate::process::capability::{self, Capability};
+use crate::process::ThreadId;
+use crate::tracepoint::{self, Tracepoint};
+
+/// Longest setting name the syscalls accept.
+pub const SYSCTL_NAME_MAX: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SysctlType {
+    /// 0 or 1
+    Bool,
+    /// An integer in [min, max]
+    Int { min: u64, max: u64 },
+    /// A bit set; only bits in the mask may be set
+    Mask(u64),
+}
+
+impl SysctlType {
+    fn check(self, value: u64) -> Result<(), &'static str> {
+        let ok = match self {
+            SysctlType::Bool => value <= 1,
+            SysctlType::Int { min, max } => (min..=max).contains(&value),
+            SysctlType::Mask(mask) => value & !mask == 0,
+        };
+        if ok { Ok(()) } else { Err("Value out of range for this setting") }
+    }
+    
+    pub fn name(self) -> &'static str {
+        match self {
+            SysctlType::Bool => "bool",
+            SysctlType::Int { .. } => "int",
+            SysctlType::Mask(_) => "mask",
+        }
+    }
+}
+
+pub struct Sysctl {
+    pub name: &'static str,
+    pub kind: SysctlType,
+    get: fn() -> u64,
+    set: fn(u64) -> Result<(), &'static str>,
+}
+
+// Bits of trace.tracepoints, one per Tracepoint
+const TRACEPOINT_MASK: u64 = (1 << Tracepoint::ALL.len()) - 1;
+
+static SYSCTLS: &[Sysctl] = &[
+    Sysctl {
+        name: "kernel.log_level",
+        kind: SysctlType::Int { min: 0, max: crate::klog::LOG_DEBUG as u64 },
+        get: || crate::klog::level() as u64,
+        set: |value| crate::klog::set_level(value as u8),
+    },
+    Sysctl {
+        name: "sched.timeslice_ticks",
+        kind: SysctlType::Int { min: 1, max: 100 },
+        get: || crate::process::scheduler::time_slice() as u64,
+        set: |value| crate::process::scheduler::set_time_slice(value as u32),
+    },
+    Sysctl {
+        name: "vm.min_free_frames",
+        kind: SysctlType::Int { min: 0, max: u32::MAX as u64 },
+        get: || crate::process::watermarks().0 as u64,
+        set: |value| crate::process::set_min_free_frames(value as usize),
+    },
+    Sysctl {
+        name: "vm.low_free_frames",
+        kind: SysctlType::Int { min: 0, max: u32::MAX as u64 },
+        get: || crate::process::watermarks().1 as u64,
+        set: |value| crate::process::set_low_free_frames(value as usize),
+    },
+    Sysctl {
+        name: "vm.ksm",
+        kind: SysctlType::Bool,
+        get: || crate::memory::ksm::is_enabled() as u64,
+        set: |value| {
+            crate::memory::ksm::set_enabled(value != 0);
+            Ok(())
+        },
+    },
+    Sysctl {
+        name: "vm.ksm_pages_per_scan",
+        kind: SysctlType::Int { min: 1, max: 4096 },
+        get: || crate::memory::ksm::pages_per_scan() as u64,
+        set: |value| {
+            crate::memory::ksm::set_pages_per_scan(value as usize);
+            Ok(())
+        },
+    },
+    Sysctl {
+        name: "kernel.textcheck_interval_ms",
+        kind: SysctlType::Int { min: 0, max: 3_600_000 },
+        get: crate::memory::textcheck::interval_ms,
+        set: |value| {
+            crate::memory::textcheck::set_interval_ms(value);
+            Ok(())
+        },
+    },
+    Sysctl {
+        name: "kernel.watchdog",
+        kind: SysctlType::Bool,
+        get: || crate::watchdog::is_enabled() as u64,
+        set: |value| {
+            crate::watchdog::set_enabled(value != 0);
+            Ok(())
+        },
+    },
+    Sysctl {
+        name: "kernel.watchdog_thresh_ms",
+        kind: SysctlType::Int { min: crate::watchdog::THRESHOLD_MIN_MS, max: crate::watchdog::THRESHOLD_MAX_MS },
+        get: crate::watchdog::threshold_ms,
+        set: crate::watchdog::set_threshold_ms,
+    },
+    Sysctl {
+        name: "trace.ipc",
+        kind: SysctlType::Bool,
+        get: || crate::trace::is_enabled() as u64,
+        set: |value| {
+            crate::trace::set_enabled(value != 0);
+            Ok(())
+        },
+    },
+    Sysctl {
+        name: "trace.tracepoints",
+        kind: SysctlType::Mask(TRACEPOINT_MASK),
+        get: || {
+            Tracepoint::ALL.iter().enumerate()
+                .filter(|(_, &point)| tracepoint::is_enabled(point))
+                .fold(0, |mask, (bit, _)| mask | 1 << bit)
+        },
+        set: |value| {
+            for (bit, &point) in Tracepoint::ALL.iter().enumerate() {
+                tracepoint::set_enabled(point, value & 1 << bit != 0);
+            }
+            Ok(())
+        },
+    },
+];
+
+/// Export /proc/sysctl.
+pub fn init() {
+    let _ = crate::procfs::register("sysctl", proc_sysctl);
+}
+
+pub fn lookup(name: &str) -> Option<&'static Sysctl> {
+    SYSCTLS.iter().find(|sysctl| sysctl.name == name)
+}
+
+/// Every setting, in table order.
+pub fn all() -> &'static [Sysctl] {
+    SYSCTLS
+}
+
+impl Sysctl {
+    pub fn get(&self) -> u64 {
+        (self.get)()
+    }
+    
+    /// Check `value` against the setting's type and apply it. Callers
+    /// check permission first.
+    pub fn set(&self, value: u64) -> Result<(), &'static str> {
+        self.kind.check(value)?;
+        (self.set)(value)
+    }
+}
+
+/// Whether `caller` may change settings: privileged callers always may,
+/// others only with the Sysctl capability.
+pub fn may_write(privileged: bool, caller: ThreadId) -> bool {
+    privileged || capability::held(caller).contains(&Capability::Sysctl)
+}
+
+/// Set `name` on behalf of `caller`, checking permission and auditing
+/// the change.
+pub fn write(name: &str, value: u64, privileged: bool, caller: ThreadId) -> Result<(), &'static str> {
+    let sysctl = lookup(name).ok_or("No such setting")?;
+    if !may_write(privileged, caller) {
+        crate::audit::permission_denied(caller, "sysctl");
+        return Err("Permission denied");
+    }
+    sysctl.set(value)?;
+    crate::audit::sysctl_write(caller, value, sysctl.name);
+    Ok(())
+}
+
+fn proc_sysctl(out: &mut Vec<u8>) {
+    let mut text = String::new();
+    for sysctl in SYSCTLS {
+        let _ = writeln!(text, "{} = {} ({})", sysctl.name, sysctl.get(), sysctl.kind.name());
+    }
+    out.extend_from_slice(text.as_bytes());
+}
+
+#[kernel_test]
+fn test_sysctl() {
+    use crate::process::scheduler;
+    use crate::syscall::{EINVAL, ENOENT, SYS_SYSCTL_GET, SYS_SYSCTL_SET};
+    
+    crate::println!("Sysctl Test: Testing sysctl...");
+    
+    let get = |name: &str| -> i64 {
+        let value: i64;
+        unsafe {
+            asm!("svc #{nr}", nr = const SYS_SYSCTL_GET,
+                 inout("x0") name.as_ptr() => value, in("x1") name.len());
+        }
+        value
+    };
+    let set = |name: &str, value: u64| -> i64 {
+        let result: i64;
+        unsafe {
+            asm!("svc #{nr}", nr = const SYS_SYSCTL_SET,
+                 inout("x0") name.as_ptr() => result, in("x1") name.len(), in("x2") value);
+        }
+        result
+    };
+    
+    let original = scheduler::time_slice();
+    let read_back = get("sched.timeslice_ticks") == original as i64;
+    let applied = set("sched.timeslice_ticks", 7) == 0 && scheduler::time_slice() == 7;
+    let range_checked = set("sched.timeslice_ticks", 0) == EINVAL && scheduler::time_slice() == 7;
+    let unknown = get("sched.no_such_knob") == ENOENT && set("sched.no_such_knob", 1) == ENOENT;
+    set("sched.timeslice_ticks", original as u64);
+    
+    // A user thread without the Sysctl capability may only read
+    let denied = write("sched.timeslice_ticks", 9, false, scheduler::current_thread_id()).is_err()
+        && scheduler::time_slice() == original;
+    
+    if read_back && applied && range_checked && unknown && denied {
+        crate::println!("Sysctl Test: ✓ Tunables read, set, range-checked and permission-checked");
+    } else {
+        crate::println!("Sysctl Test: ✗ sysctl: read {} set {} range {} unknown {} denied {}",
+                        read_back, applied, range_checked, unknown, denied);
+    }
+    
+    crate::println!("Sysctl Test: Sysctl test completed");
+}
@@ -0,0 +1,201 @@
+// System and per-thread statistics for monitoring
+//
+// Snapshots taken here back the sysinfo and thread_info syscalls, the
+// shell's `ps`, and /proc/stat, so every view counts the same way. A
+// snapshot is consistent per thread (taken under the scheduler lock) but
+// the global figures are read one after another.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt::Write;
+use crate::interrupts::counter_frequency;
+use crate::ktest::kernel_test;
+use crate::process::thread::{ThreadLabel, ThreadState};
+use crate::process::{Priority, ThreadId};
+
+/// One thread as monitoring tools see it.
+#[derive(Copy, Clone, Debug)]
+pub struct ThreadStats {
+    pub id: ThreadId,
+    pub parent: Option<ThreadId>,
+    pub label: ThreadLabel,
+    pub state: ThreadState,
+    pub priority: Priority,
+    // CPU time in timer ticks, sampled by the tick
+    pub ticks: u64,
+    // CPU time in counter ticks, measured at context switch
+    pub cpu_time: u64,
+    pub rss_frames: usize,
+    pub capabilities: usize,
+    // Ports the thread owns
+    pub ports: usize,
+}
+
+impl ThreadStats {
+    pub fn cpu_ns(&self) -> u64 {
+        (self.cpu_time as u128 * 1_000_000_000 / counter_frequency().max(1) as u128) as u64
+    }
+}
+
+/// Machine-wide figures.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemStats {
+    pub uptime_ns: u64,
+    pub frames_total: usize,
+    pub frames_free: usize,
+    pub heap_used: usize,
+    pub heap_free: usize,
+    pub irq_count: u64,
+    pub timer_ticks: u64,
+    pub threads: usize,
+    pub ports: usize,
+}
+
+pub fn state_name(state: ThreadState) -> &'static str {
+    match state {
+        ThreadState::Ready => "ready",
+        ThreadState::Running => "running",
+        ThreadState::Blocked => "blocked",
+        ThreadState::Exited => "exited",
+    }
+}
+
+/// Every live thread, by ID.
+pub fn threads() -> Vec<ThreadStats> {
+    let mut owned: BTreeMap<ThreadId, usize> = BTreeMap::new();
+    for port in crate::ipc::ports() {
+        *owned.entry(port.owner()).or_default() += 1;
+    }
+    let mut threads = Vec::new();
+    crate::process::scheduler::for_each_thread_cpu(|thread, cpu_time| {
+        threads.push(ThreadStats {
+            id: thread.id,
+            parent: thread.parent,
+            label: thread.label(),
+            state: thread.state,
+            priority: thread.priority,
+            ticks: thread.ticks,
+            cpu_time,
+            rss_frames: thread.rss_frames(),
+            capabilities: thread.capabilities.len(),
+            ports: owned.get(&thread.id).copied().unwrap_or(0),
+        });
+    });
+    threads.sort_by_key(|thread| thread.id);
+    threads
+}
+
+pub fn system() -> SystemStats {
+    let (frames_free, frames_total) = crate::memory::frame_allocator::frame_allocator_stats();
+    let (heap_used, heap_free) = crate::allocator::heap_stats();
+    let interrupts = crate::interrupts::get_interrupt_stats();
+    let mut threads = 0;
+    crate::process::scheduler::for_each_thread(|_| threads += 1);
+    SystemStats {
+        uptime_ns: crate::time::monotonic_ns(),
+        frames_total,
+        frames_free,
+        heap_used,
+        heap_free,
+        irq_count: interrupts.irq_count,
+        timer_ticks: interrupts.timer_ticks,
+        threads,
+        ports: crate::ipc::ports().len(),
+    }
+}
+
+/// Export /proc/stat.
+pub fn init() {
+    let _ = crate::procfs::register("stat", proc_stat);
+}
+
+fn proc_stat(out: &mut Vec<u8>) {
+    use crate::memory::frame_allocator::PAGE_SIZE;
+    
+    let system = system();
+    let mut text = alloc::string::String::new();
+    let _ = writeln!(text, "uptime_ns {}", system.uptime_ns);
+    let _ = writeln!(text, "frames {} free {}", system.frames_total, system.frames_free);
+    let _ = writeln!(text, "heap {} used {} free", system.heap_used, system.heap_free);
+    let _ = writeln!(text, "irqs {} timer_ticks {}", system.irq_count, system.timer_ticks);
+    let _ = writeln!(text, "threads {} ports {}", system.threads, system.ports);
+    for thread in threads() {
+        let _ = writeln!(text, "thread {} {} {} prio {} cpu_ns {} rss_kib {} caps {} ports {}", thread.id,
+                         thread.label, state_name(thread.state), thread.priority, thread.cpu_ns(),
+                         thread.rss_frames * PAGE_SIZE / 1024, thread.capabilities, thread.ports);
+    }
+    out.extend_from_slice(text.as_bytes());
+}
+
+#[kernel_test]
+fn test_sysinfo() {
+    use crate::syscall::{SysInfo, ThreadInfo, SYS_SYSINFO, SYS_THREAD_INFO, THREAD_INFO_NAME_LEN, THREAD_STATE_RUNNING};
+    
+    crate::println!("Sysinfo Test: Testing system statistics...");
+    
+    let mut info = SysInfo::default();
+    let result: i64;
+    unsafe {
+        asm!("svc #{nr}", nr = const SYS_SYSINFO, inout("x0") &mut info as *mut SysInfo => result);
+    }
+    let (free, total) = crate::memory::frame_allocator::frame_allocator_stats();
+    if result == 0 && info.frames_total == total as u64 && info.frames_free <= info.frames_total
+        && info.heap_used > 0 && info.threads > 0 && info.uptime_ns > 0 {
+        crate::println!("Sysinfo Test: ✓ sysinfo: {} threads, {}/{} frames free, {} heap bytes used",
+                       info.threads, free, total, info.heap_used);
+    } else {
+        crate::println!("Sysinfo Test: ✗ sysinfo returned {} {:?}", result, info);
+    }
+    
+    // Every thread in ID order, the caller marked running
+    let empty = ThreadInfo {
+        tid: 0, parent: 0, state: 0, priority: 0, cpu_ns: 0, rss_bytes: 0, capabilities: 0, ports: 0,
+        name: [0; THREAD_INFO_NAME_LEN],
+    };
+    let mut records = [empty; 16];
+    let thread_info = |first: u32, records: &mut [ThreadInfo]| -> i64 {
+        let count: i64;
+        unsafe {
+            asm!("svc #{nr}", nr = const SYS_THREAD_INFO,
+                 inout("x0") first as u64 => count, in("x1") records.as_mut_ptr(), in("x2") records.len());
+        }
+        count
+    };
+    let count = thread_info(0, &mut records);
+    let snapshot = threads();
+    let me = crate::process::scheduler::current_thread_id();
+    let listed = &records[..count.clamp(0, 16) as usize];
+    let ordered = listed.windows(2).all(|pair| pair[0].tid < pair[1].tid)
+        && listed.iter().zip(&snapshot).all(|(record, thread)| record.tid == thread.id);
+    let caller = listed.iter().find(|record| record.tid == me);
+    let named = caller.is_some_and(|record| record.state == THREAD_STATE_RUNNING && record.name[0] != 0);
+    // Paging on from the last ID returns just that one
+    let last = listed.last().map_or(0, |record| record.tid);
+    let paged = thread_info(last, &mut records[..4]) == 1 && records[0].tid == last;
+    if count > 0 && ordered && named && paged {
+        crate::println!("Sysinfo Test: ✓ thread_info listed {} threads in order, paging from an ID", count);
+    } else {
+        crate::println!("Sysinfo Test: ✗ thread_info: count {} ordered {} caller {} paged {}", count, ordered, named, paged);
+    }
+    
+    // CPU time is charged while spinning, including the running slice, but not while asleep
+    use crate::interrupts::{counter_frequency, counter_ticks};
+    let own_ns = || threads().iter().find(|thread| thread.id == me).map_or(0, |thread| thread.cpu_ns());
+    let before = own_ns();
+    let spin_until = counter_ticks() + counter_frequency() / 100;
+    while counter_ticks() < spin_until {
+        core::hint::spin_loop();
+    }
+    let spun = own_ns();
+    let _ = crate::timer::sleep_ms(30);
+    let slept = own_ns();
+    if spun >= before + 9_000_000 && slept < spun + 15_000_000 {
+        crate::println!("Sysinfo Test: ✓ CPU time charged for a 10ms spin ({}us), not for a 30ms sleep ({}us)",
+                       (spun - before) / 1000, (slept - spun) / 1000);
+    } else {
+        crate::println!("Sysinfo Test: ✗ CPU time: before {} spun {} slept {} ns", before, spun, slept);
+    }
+    
+    crate::println!("Sysinfo Test: System statistics test completed");
+}
@@ -0,0 +1,219 @@
+// Monotonic and wall-clock time
+//
+// Monotonic time is the generic counter (CNTPCT_EL0) scaled by CNTFRQ_EL0:
+// it starts near zero at reset and never jumps. Wall-clock time is the
+// monotonic clock plus an offset, set once an RTC has been read; until
+// then only the monotonic clock is available.
+//
+// For deterministic tests the kernel clock can be switched to a virtual
+// one that stands still until advanced by hand. Software timers, sleeps
+// and CLOCK_MONOTONIC all follow the kernel clock, so a test can step a
+// timeout to its deadline instead of waiting for it. Switching back
+// resumes counting from wherever the virtual clock stopped, so the clock
+// never runs backwards.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::interrupts::{counter_frequency, counter_ticks, request_timer_event, without_interrupts};
+use crate::ktest::kernel_test;
+
+/// Clock IDs, numbered as on Linux.
+pub const CLOCK_REALTIME: u32 = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+// Realtime minus monotonic, in ns; 0 = no wall clock yet
+static REALTIME_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Whether the kernel clock is virtual, and its reading if so
+static VIRTUAL: AtomicBool = AtomicBool::new(false);
+static VIRTUAL_NOW: AtomicU64 = AtomicU64::new(0);
+// Kernel clock minus the counter (wrapping), left behind by virtual stretches
+static COUNTER_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Seconds and nanoseconds, as returned by clock_gettime.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub sec: u64,
+    pub nsec: u64,
+}
+
+impl Timespec {
+    pub fn from_ns(ns: u64) -> Self {
+        Self { sec: ns / NSEC_PER_SEC, nsec: ns % NSEC_PER_SEC }
+    }
+}
+
+/// The kernel clock in counter ticks: what timers and sleeps are
+/// measured against. The generic counter, unless the clock is virtual.
+pub fn now_ticks() -> u64 {
+    if VIRTUAL.load(Ordering::SeqCst) {
+        VIRTUAL_NOW.load(Ordering::SeqCst)
+    } else {
+        counter_ticks().wrapping_add(COUNTER_OFFSET.load(Ordering::SeqCst))
+    }
+}
+
+pub fn is_virtual() -> bool {
+    VIRTUAL.load(Ordering::SeqCst)
+}
+
+/// Switch the kernel clock between virtual and real time. Either way it
+/// carries on from its current reading.
+pub fn set_virtual(enable: bool) {
+    without_interrupts(|| {
+        if enable == is_virtual() {
+            return;
+        }
+        if enable {
+            VIRTUAL_NOW.store(now_ticks(), Ordering::SeqCst);
+        } else {
+            let offset = VIRTUAL_NOW.load(Ordering::SeqCst).wrapping_sub(counter_ticks());
+            COUNTER_OFFSET.store(offset, Ordering::SeqCst);
+        }
+        VIRTUAL.store(enable, Ordering::SeqCst);
+    });
+    if !enable {
+        // Timers armed while virtual asked for no hardware event: look
+        // at them on the next interrupt
+        request_timer_event(counter_ticks());
+    }
+}
+
+/// Move the virtual clock forward by `ns` and fire whatever fell due.
+/// Returns the new monotonic time in ns.
+pub fn advance(ns: u64) -> Result<u64, &'static str> {
+    if !is_virtual() {
+        return Err("Clock is not virtual");
+    }
+    let ticks = crate::timer::ns_to_ticks(ns);
+    let now = without_interrupts(|| {
+        let now = VIRTUAL_NOW.fetch_add(ticks, Ordering::SeqCst) + ticks;
+        crate::timer::tick(now);
+        crate::executor::timer_tick(now);
+        now
+    });
+    Ok(ticks_to_ns(now))
+}
+
+/// Ask for a timer interrupt by kernel-clock `deadline`. Nothing to ask
+/// for while the clock is virtual: `advance` fires what falls due.
+pub fn request_event(deadline: u64) {
+    if !is_virtual() {
+        request_timer_event(deadline.wrapping_sub(COUNTER_OFFSET.load(Ordering::SeqCst)));
+    }
+}
+
+/// Nanoseconds since the counter started, on the kernel clock.
+pub fn monotonic_ns() -> u64 {
+    ticks_to_ns(now_ticks())
+}
+
+/// Convert generic counter ticks to nanoseconds.
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    // u128: ticks * 1e9 overflows u64 after ~5 minutes at 62.5 MHz
+    (ticks as u128 * NSEC_PER_SEC as u128 / counter_frequency() as u128) as u64
+}
+
+/// Nanoseconds since the Unix epoch, if a wall clock has been set.
+pub fn realtime_ns() -> Option<u64> {
+    match REALTIME_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(monotonic_ns() + offset),
+    }
+}
+
+/// Set the wall clock from an RTC reading in seconds since the epoch.
+pub fn set_realtime(unix_secs: u64) {
+    let offset = (unix_secs * NSEC_PER_SEC).saturating_sub(monotonic_ns()).max(1);
+    REALTIME_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Current time on `clock`.
+pub fn clock_gettime(clock: u32) -> Result<Timespec, &'static str> {
+    let ns = match clock {
+        CLOCK_REALTIME => realtime_ns().ok_or("No wall clock")?,
+        CLOCK_MONOTONIC => monotonic_ns(),
+        _ => return Err("Unknown clock"),
+    };
+    Ok(Timespec::from_ns(ns))
+}
+
+/// Seconds since the epoch, printed as an ISO 8601 UTC time.
+pub struct UtcTime(pub u64);
+
+impl UtcTime {
+    /// (year, month, day, hour, minute, second)
+    fn fields(&self) -> (u64, u64, u64, u64, u64, u64) {
+        let days = self.0 / 86400;
+        let secs = self.0 % 86400;
+        // Howard Hinnant's civil_from_days, with eras starting 0000-03-01
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z % 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u64;
+        (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+impl fmt::Display for UtcTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (year, month, day, hour, minute, second) = self.fields();
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+    }
+}
+
+/// Register /proc/uptime.
+pub fn init() {
+    let _ = crate::procfs::register("uptime", proc_uptime);
+}
+
+fn proc_uptime(out: &mut alloc::vec::Vec<u8>) {
+    let now = Timespec::from_ns(monotonic_ns());
+    out.extend_from_slice(alloc::format!("{}.{:02}\n", now.sec, now.nsec / 10_000_000).as_bytes());
+}
+
+#[kernel_test]
+fn test_clocks() {
+    
+    crate::println!("Time Test: Testing clocks...");
+    
+    let first = monotonic_ns();
+    crate::interrupts::delay_us(100);
+    let second = monotonic_ns();
+    if second >= first + 100_000 {
+        crate::println!("Time Test: ✓ Monotonic clock advanced {} ns over 100 us", second - first);
+    } else {
+        crate::println!("Time Test: ✗ Monotonic clock went {} -> {}", first, second);
+    }
+    
+    // Epoch, a leap day and a recent time
+    let formatted = [0, 951_782_400, 1_700_000_000].map(|secs| alloc::format!("{}", UtcTime(secs)));
+    if formatted == ["1970-01-01T00:00:00Z", "2000-02-29T00:00:00Z", "2023-11-14T22:13:20Z"] {
+        crate::println!("Time Test: ✓ UTC conversion handles the epoch and leap days");
+    } else {
+        crate::println!("Time Test: ✗ UTC conversion gave {:?}", formatted);
+    }
+    
+    match clock_gettime(CLOCK_REALTIME) {
+        Ok(now) if now.sec > 1_600_000_000 => {
+            crate::println!("Time Test: ✓ Wall clock reads {}", UtcTime(now.sec));
+        }
+        Ok(now) => crate::println!("Time Test: ✗ Wall clock implausible: {} s", now.sec),
+        Err(e) => crate::println!("Time Test: Wall clock skipped: {}", e),
+    }
+    if clock_gettime(CLOCK_MONOTONIC).is_ok() && clock_gettime(7).is_err() {
+        crate::println!("Time Test: ✓ Unknown clock IDs rejected");
+    } else {
+        crate::println!("Time Test: ✗ Clock ID handling wrong");
+    }
+    
+    crate::println!("Time Test: Clock test completed");
+}
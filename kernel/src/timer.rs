@@ -0,0 +1,391 @@
+// Software timers on a hashed timer wheel
+//
+// Each slot of the wheel covers one scheduler tick; a timer hangs off the
+// slot its deadline falls in and fires when the tick sweeps past that slot
+// in the right revolution. Timers live in a fixed table linked through
+// indices, so arming, cancelling and re-arming periodic timers never
+// allocate and all of it works from interrupt context. Deadlines are
+// counter ticks on the kernel clock (time::now_ticks), and every timer also
+// asks for a timer event, so they fire on time even while the tick is
+// stopped in idle.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::interrupts::{counter_frequency, without_interrupts, TIMER_FREQ_HZ};
+use crate::ktest::kernel_test;
+use crate::process::scheduler::{block_current, current_thread_id, wake, yield_now};
+use crate::process::ThreadId;
+use crate::sync::IrqSafeMutex;
+use crate::time::{now_ticks, request_event};
+
+pub const MAX_TIMERS: usize = 256;
+
+// One revolution is WHEEL_SLOTS ticks (2.56s at 100Hz)
+const WHEEL_SLOTS: usize = 256;
+
+/// Handle for `cancel`: table index plus a generation, so a stale handle
+/// cannot cancel a timer that reused the entry.
+pub type TimerId = u32;
+
+/// Runs in interrupt context with the argument it was armed with.
+pub type TimerCallback = fn(usize);
+
+#[derive(Copy, Clone)]
+enum TimerAction {
+    Callback(TimerCallback, usize),
+    Wake(ThreadId),
+}
+
+#[derive(Copy, Clone)]
+struct Timer {
+    deadline: u64,
+    // 0 for one-shot timers
+    period: u64,
+    action: TimerAction,
+    // Wheel slot and the next timer in it
+    slot: u16,
+    next: Option<u16>,
+}
+
+struct TimerWheel {
+    timers: [Option<Timer>; MAX_TIMERS],
+    generations: [u16; MAX_TIMERS],
+    slots: [Option<u16>; WHEEL_SLOTS],
+    // Last tick whose slot has been swept
+    swept: u64,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        Self {
+            timers: [None; MAX_TIMERS],
+            generations: [0; MAX_TIMERS],
+            slots: [None; WHEEL_SLOTS],
+            swept: 0,
+        }
+    }
+    
+    // Link entry `index` into the slot of its deadline. A deadline in a
+    // tick already swept goes into the next slot to be swept instead.
+    fn link(&mut self, index: u16) {
+        let tick = match &self.timers[index as usize] {
+            Some(timer) => (timer.deadline / tick_period()).max(self.swept + 1),
+            None => return,
+        };
+        let slot = (tick % WHEEL_SLOTS as u64) as usize;
+        let head = self.slots[slot];
+        if let Some(timer) = self.timers[index as usize].as_mut() {
+            timer.slot = slot as u16;
+            timer.next = head;
+        }
+        self.slots[slot] = Some(index);
+    }
+    
+    fn unlink(&mut self, index: u16) {
+        let Some(timer) = self.timers[index as usize] else {
+            return;
+        };
+        let slot = timer.slot as usize;
+        let mut prev: Option<u16> = None;
+        let mut cursor = self.slots[slot];
+        while let Some(current) = cursor {
+            let next = self.timers[current as usize].and_then(|timer| timer.next);
+            if current == index {
+                match prev.and_then(|prev| self.timers[prev as usize].as_mut()) {
+                    Some(prev) => prev.next = next,
+                    None => self.slots[slot] = next,
+                }
+                return;
+            }
+            prev = cursor;
+            cursor = next;
+        }
+    }
+    
+    // Take one timer due at `now` from the slots up to `now`'s tick,
+    // re-arming it if periodic. Advances `swept` as slots empty.
+    fn pop_expired(&mut self, now: u64) -> Option<TimerAction> {
+        let now_tick = now / tick_period();
+        // After a long tickless stretch one revolution covers every slot
+        let start = self.swept.max(now_tick.saturating_sub(WHEEL_SLOTS as u64));
+        for tick in start + 1..=now_tick {
+            let slot = (tick % WHEEL_SLOTS as u64) as usize;
+            let mut cursor = self.slots[slot];
+            while let Some(index) = cursor {
+                let timer = self.timers[index as usize]?;
+                if timer.deadline <= now {
+                    self.unlink(index);
+                    match (now - timer.deadline).checked_div(timer.period) {
+                        None => {
+                            self.timers[index as usize] = None;
+                            self.generations[index as usize] = self.generations[index as usize].wrapping_add(1);
+                        }
+                        // Keep the phase; skip periods missed entirely
+                        Some(missed) => {
+                            let deadline = timer.deadline + (missed + 1) * timer.period;
+                            if let Some(entry) = self.timers[index as usize].as_mut() {
+                                entry.deadline = deadline;
+                            }
+                            self.link(index);
+                            request_event(deadline);
+                        }
+                    }
+                    return Some(timer.action);
+                }
+                cursor = timer.next;
+            }
+            // Nothing due here before `now`: later revolutions only
+            if tick < now_tick {
+                self.swept = tick;
+            }
+        }
+        None
+    }
+}
+
+static WHEEL: IrqSafeMutex<TimerWheel> = IrqSafeMutex::new(TimerWheel::new());
+
+// Earliest deadline ever armed and not yet swept past, so ticks with
+// nothing due skip the lock
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn tick_period() -> u64 {
+    counter_frequency() / TIMER_FREQ_HZ
+}
+
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    counter_frequency() * ms / 1000
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    (counter_frequency() as u128 * ns as u128 / 1_000_000_000) as u64
+}
+
+fn arm(deadline: u64, period: u64, action: TimerAction) -> Result<TimerId, &'static str> {
+    let mut wheel = WHEEL.lock();
+    let index = wheel.timers.iter().position(Option::is_none).ok_or("Timer table full")?;
+    wheel.timers[index] = Some(Timer { deadline, period, action, slot: 0, next: None });
+    wheel.link(index as u16);
+    NEXT_DEADLINE.fetch_min(deadline, Ordering::SeqCst);
+    request_event(deadline);
+    Ok((wheel.generations[index] as u32) << 16 | index as u32)
+}
+
+/// Run `callback(arg)` once the clock reaches `deadline`, then every
+/// `period` ticks after that if `period` is not 0.
+pub fn add_timer(deadline: u64, period: u64, callback: TimerCallback, arg: usize) -> Result<TimerId, &'static str> {
+    arm(deadline, period, TimerAction::Callback(callback, arg))
+}
+
+/// Run `callback(arg)` once, `ms` milliseconds from now.
+pub fn after_ms(ms: u64, callback: TimerCallback, arg: usize) -> Result<TimerId, &'static str> {
+    add_timer(now_ticks() + ms_to_ticks(ms), 0, callback, arg)
+}
+
+/// Run `callback(arg)` every `ms` milliseconds until cancelled.
+pub fn every_ms(ms: u64, callback: TimerCallback, arg: usize) -> Result<TimerId, &'static str> {
+    let period = ms_to_ticks(ms).max(1);
+    add_timer(now_ticks() + period, period, callback, arg)
+}
+
+/// Disarm a timer. False if it already fired (one-shot) or was cancelled.
+pub fn cancel(id: TimerId) -> bool {
+    let index = (id & 0xFFFF) as usize;
+    let mut wheel = WHEEL.lock();
+    if index >= MAX_TIMERS || wheel.generations[index] != (id >> 16) as u16 || wheel.timers[index].is_none() {
+        return false;
+    }
+    wheel.unlink(index as u16);
+    wheel.timers[index] = None;
+    wheel.generations[index] = wheel.generations[index].wrapping_add(1);
+    true
+}
+
+/// Fire due timers; called from the timer interrupt.
+pub fn tick(now: u64) {
+    if NEXT_DEADLINE.load(Ordering::SeqCst) > now {
+        return;
+    }
+    // Callbacks run without the wheel held so they can arm and cancel
+    loop {
+        let action = WHEEL.lock().pop_expired(now);
+        match action {
+            Some(TimerAction::Callback(callback, arg)) => callback(arg),
+            Some(TimerAction::Wake(thread)) => wake(thread),
+            None => break,
+        }
+    }
+    let next = {
+        let wheel = WHEEL.lock();
+        wheel.timers.iter().flatten().map(|timer| timer.deadline).min().unwrap_or(u64::MAX)
+    };
+    NEXT_DEADLINE.store(next, Ordering::SeqCst);
+}
+
+/// Block the calling thread until the clock reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Result<(), &'static str> {
+    let me = current_thread_id();
+    while now_ticks() < deadline {
+        // Arm and block with IRQs masked so the wakeup cannot come first
+        let id = without_interrupts(|| {
+            let id = arm(deadline, 0, TimerAction::Wake(me))?;
+            block_current();
+            Ok::<_, &'static str>(id)
+        })?;
+        yield_now();
+        // Woken early by someone else: the timer may still be armed
+        cancel(id);
+    }
+    Ok(())
+}
+
+/// Block the calling thread for `ms` milliseconds.
+pub fn sleep_ms(ms: u64) -> Result<(), &'static str> {
+    sleep_until(now_ticks() + ms_to_ticks(ms))
+}
+
+/// Block the calling thread for `ns` nanoseconds (at least a counter tick).
+pub fn sleep_ns(ns: u64) -> Result<(), &'static str> {
+    sleep_until(now_ticks() + ns_to_ticks(ns).max(1))
+}
+
+/// Armed timers.
+pub fn active_count() -> usize {
+    WHEEL.lock().timers.iter().flatten().count()
+}
+
+static TIMER_FIRED: AtomicU64 = AtomicU64::new(0);
+
+fn count_timer(arg: usize) {
+    TIMER_FIRED.fetch_add(arg as u64, Ordering::SeqCst);
+}
+
+#[kernel_test]
+fn test_timer_wheel() {
+    use crate::interrupts::{counter_frequency, counter_ticks};
+    
+    crate::println!("Timer Test: Testing software timers...");
+    
+    TIMER_FIRED.store(0, Ordering::SeqCst);
+    let armed_before = active_count();
+    let once = after_ms(20, count_timer, 1000);
+    let periodic = every_ms(10, count_timer, 1);
+    let cancelled = after_ms(30, count_timer, 1_000_000);
+    let (Ok(_), Ok(periodic), Ok(cancelled)) = (once, periodic, cancelled) else {
+        crate::println!("Timer Test: ✗ Could not arm timers");
+        return;
+    };
+    let cancel_ok = cancel(cancelled) && !cancel(cancelled);
+    
+    // Sleep across several ticks and the one-shot deadline
+    let start = counter_ticks();
+    let slept = sleep_ms(55);
+    let elapsed_ms = (counter_ticks() - start) * 1000 / counter_frequency();
+    cancel(periodic);
+    
+    if slept.is_ok() && elapsed_ms >= 55 {
+        crate::println!("Timer Test: ✓ sleep_ms(55) returned after {}ms", elapsed_ms);
+    } else {
+        crate::println!("Timer Test: ✗ sleep_ms(55) returned after {}ms", elapsed_ms);
+    }
+    let fired = TIMER_FIRED.load(Ordering::SeqCst);
+    let (oneshots, periods) = (fired / 1000, fired % 1000);
+    if cancel_ok && oneshots == 1 && (3..=6).contains(&periods) {
+        crate::println!("Timer Test: ✓ One-shot fired once, periodic fired {} times, cancelled never", periods);
+    } else {
+        crate::println!("Timer Test: ✗ Timers fired wrongly ({} one-shot, {} periodic)", oneshots, periods);
+    }
+    if active_count() == armed_before {
+        crate::println!("Timer Test: ✓ Expired and cancelled timers released");
+    } else {
+        crate::println!("Timer Test: ✗ Timer table leaked entries");
+    }
+    
+    crate::println!("Timer Test: Software timer test completed");
+}
+
+// Monotonic ns at which the sleeper woke, 0 while asleep
+static VCLOCK_WOKE: AtomicU64 = AtomicU64::new(0);
+
+fn vclock_sleeper() {
+    let _ = sleep_ms(1000);
+    VCLOCK_WOKE.store(crate::time::monotonic_ns(), Ordering::SeqCst);
+}
+
+#[kernel_test]
+fn test_virtual_clock() {
+    use crate::interrupts::delay_us;
+    use crate::process::{kthread_spawn, yield_now, KTHREAD_DEFAULT_PRIORITY};
+    use crate::time;
+    
+    crate::println!("Timer Test: Testing virtual clock...");
+    
+    const MS: u64 = 1_000_000;
+    TIMER_FIRED.store(0, Ordering::SeqCst);
+    VCLOCK_WOKE.store(0, Ordering::SeqCst);
+    time::set_virtual(true);
+    let start = time::monotonic_ns();
+    let once = after_ms(20, count_timer, 1000);
+    let periodic = every_ms(10, count_timer, 1);
+    let sleeper = kthread_spawn(vclock_sleeper, "kvclock", KTHREAD_DEFAULT_PRIORITY);
+    let (Ok(_), Ok(periodic), Ok(_)) = (once, periodic, sleeper) else {
+        crate::println!("Timer Test: ✗ Could not arm timers");
+        time::set_virtual(false);
+        return;
+    };
+    
+    // Real time passing moves nothing
+    delay_us(30_000);
+    for _ in 0..10 {
+        yield_now();
+    }
+    if time::monotonic_ns() == start && TIMER_FIRED.load(Ordering::SeqCst) == 0 {
+        crate::println!("Timer Test: ✓ Virtual clock stands still");
+    } else {
+        crate::println!("Timer Test: ✗ Virtual clock moved on its own");
+    }
+    
+    // Periodic timers keep their phase only when stepped within a period
+    let at_19 = time::advance(19 * MS).map(|_| TIMER_FIRED.load(Ordering::SeqCst));
+    let at_20 = time::advance(MS).map(|_| TIMER_FIRED.load(Ordering::SeqCst));
+    for _ in 0..7 {
+        let _ = time::advance(5 * MS);
+    }
+    let at_55 = TIMER_FIRED.load(Ordering::SeqCst);
+    cancel(periodic);
+    if at_19 == Ok(1) && at_20 == Ok(1002) && at_55 == 1005 {
+        crate::println!("Timer Test: ✓ Timers fired exactly on the virtual deadlines");
+    } else {
+        crate::println!("Timer Test: ✗ Timer counts {:?}, {:?}, {} at 19, 20 and 55ms", at_19, at_20, at_55);
+    }
+    
+    // The sleeper went to sleep at `start`, so it wakes at start + 1s
+    let _ = time::advance(944 * MS);
+    for _ in 0..10 {
+        yield_now();
+    }
+    let early = VCLOCK_WOKE.load(Ordering::SeqCst);
+    let _ = time::advance(MS);
+    for _ in 0..10 {
+        yield_now();
+    }
+    let woke = VCLOCK_WOKE.load(Ordering::SeqCst);
+    // Within a counter tick: ns are rounded from ticks
+    if early == 0 && woke.abs_diff(start + 1000 * MS) < 1_000 {
+        crate::println!("Timer Test: ✓ Sleeping thread woke on the virtual second");
+    } else {
+        crate::println!("Timer Test: ✗ Sleeper woke at {} (early: {})", woke, early != 0);
+    }
+    
+    time::set_virtual(false);
+    let resumed = time::monotonic_ns();
+    delay_us(1_000);
+    if resumed >= start + 1000 * MS && time::monotonic_ns() > resumed {
+        crate::println!("Timer Test: ✓ Real clock resumed from the virtual time");
+    } else {
+        crate::println!("Timer Test: ✗ Clock went backwards or stopped after leaving virtual time");
+    }
+    yield_now();
+    crate::process::scheduler::reap_exited();
+    
+    crate::println!("Timer Test: Virtual clock test completed");
+}
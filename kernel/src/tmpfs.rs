@@ -0,0 +1,250 @@
+// RAM-backed filesystem (mounted at /tmp)
+//
+// The whole tree lives on the kernel heap and is lost on reboot. Total
+// file content is capped so a runaway writer cannot exhaust the heap.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::ktest::kernel_test;
+use crate::vfs::{self, DirEntry, FileSystem, Metadata, NodeKind};
+
+/// Default cap on file content per instance.
+pub const TMPFS_DEFAULT_LIMIT: usize = 16 * 1024 * 1024;
+
+enum Node {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, Node>),
+}
+
+impl Node {
+    fn kind(&self) -> NodeKind {
+        match self {
+            Node::File(_) => NodeKind::File,
+            Node::Dir(_) => NodeKind::Directory,
+        }
+    }
+    
+    fn size(&self) -> u64 {
+        match self {
+            Node::File(data) => data.len() as u64,
+            Node::Dir(_) => 0,
+        }
+    }
+}
+
+struct Tree {
+    root: Node,
+    used: usize,
+}
+
+pub struct Tmpfs {
+    tree: Mutex<Tree>,
+    limit: usize,
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+// Parent directory path and final name
+fn split_parent(path: &str) -> Result<(&str, &str), &'static str> {
+    let path = path.trim_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() {
+        return Err("Invalid file name");
+    }
+    Ok((parent, name))
+}
+
+impl Tree {
+    fn lookup(&mut self, path: &str) -> Result<&mut Node, &'static str> {
+        let mut node = &mut self.root;
+        for component in components(path) {
+            node = match node {
+                Node::Dir(children) => children.get_mut(component).ok_or("No such file or directory")?,
+                Node::File(_) => return Err("Not a directory"),
+            };
+        }
+        Ok(node)
+    }
+    
+    fn dir(&mut self, path: &str) -> Result<&mut BTreeMap<String, Node>, &'static str> {
+        match self.lookup(path)? {
+            Node::Dir(children) => Ok(children),
+            Node::File(_) => Err("Not a directory"),
+        }
+    }
+    
+    fn file(&mut self, path: &str) -> Result<&mut Vec<u8>, &'static str> {
+        match self.lookup(path)? {
+            Node::File(data) => Ok(data),
+            Node::Dir(_) => Err("Is a directory"),
+        }
+    }
+    
+    fn insert(&mut self, path: &str, node: Node) -> Result<(), &'static str> {
+        let (parent, name) = split_parent(path)?;
+        let children = self.dir(parent)?;
+        if children.contains_key(name) {
+            return Err("File exists");
+        }
+        children.insert(String::from(name), node);
+        Ok(())
+    }
+}
+
+impl Tmpfs {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            tree: Mutex::new(Tree { root: Node::Dir(BTreeMap::new()), used: 0 }),
+            limit,
+        }
+    }
+    
+    /// Bytes of file content stored.
+    pub fn used(&self) -> usize {
+        self.tree.lock().used
+    }
+    
+    // Resize a file, charging the difference against the cap
+    fn resize(&self, tree: &mut Tree, path: &str, size: usize) -> Result<(), &'static str> {
+        let old = tree.file(path)?.len();
+        if size > old && tree.used + (size - old) > self.limit {
+            return Err("No space left on device");
+        }
+        let data = tree.file(path)?;
+        data.try_reserve(size.saturating_sub(old)).map_err(|_| "No space left on device")?;
+        data.resize(size, 0);
+        tree.used = tree.used + size - old;
+        Ok(())
+    }
+}
+
+impl FileSystem for Tmpfs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+    
+    fn stat(&self, path: &str) -> Result<Metadata, &'static str> {
+        let mut tree = self.tree.lock();
+        let node = tree.lookup(path)?;
+        Ok(Metadata { kind: node.kind(), size: node.size() })
+    }
+    
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let mut tree = self.tree.lock();
+        let data = tree.file(path)?;
+        let rest = data.get(offset as usize..).unwrap_or(&[]);
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        Ok(len)
+    }
+    
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, &'static str> {
+        let mut tree = self.tree.lock();
+        Ok(tree
+            .dir(path)?
+            .iter()
+            .map(|(name, node)| DirEntry { name: name.clone(), kind: node.kind(), size: node.size() })
+            .collect())
+    }
+    
+    fn write(&self, path: &str, offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        let mut tree = self.tree.lock();
+        let offset = usize::try_from(offset).map_err(|_| "File too large")?;
+        let end = offset.checked_add(buf.len()).ok_or("File too large")?;
+        if end > tree.file(path)?.len() {
+            self.resize(&mut tree, path, end)?;
+        }
+        tree.file(path)?[offset..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+    
+    fn create(&self, path: &str) -> Result<(), &'static str> {
+        self.tree.lock().insert(path, Node::File(Vec::new()))
+    }
+    
+    fn mkdir(&self, path: &str) -> Result<(), &'static str> {
+        self.tree.lock().insert(path, Node::Dir(BTreeMap::new()))
+    }
+    
+    fn unlink(&self, path: &str) -> Result<(), &'static str> {
+        let mut tree = self.tree.lock();
+        let (parent, name) = split_parent(path)?;
+        let children = tree.dir(parent)?;
+        match children.get(name) {
+            None => return Err("No such file or directory"),
+            Some(Node::Dir(grandchildren)) if !grandchildren.is_empty() => return Err("Directory not empty"),
+            Some(_) => {}
+        }
+        let freed = children.remove(name).map_or(0, |node| node.size() as usize);
+        tree.used -= freed;
+        Ok(())
+    }
+    
+    fn truncate(&self, path: &str, size: u64) -> Result<(), &'static str> {
+        let mut tree = self.tree.lock();
+        let size = usize::try_from(size).map_err(|_| "File too large")?;
+        self.resize(&mut tree, path, size)?;
+        if size == 0 {
+            // Give the memory back rather than keeping the capacity
+            tree.file(path)?.shrink_to_fit();
+        }
+        Ok(())
+    }
+}
+
+/// Mount an empty tmpfs at /tmp.
+pub fn init() {
+    if let Err(e) = vfs::mount("/tmp", Arc::new(Tmpfs::new(TMPFS_DEFAULT_LIMIT))) {
+        crate::println!("Tmpfs: Failed to mount /tmp: {}", e);
+    }
+}
+
+#[kernel_test]
+fn test_tmpfs() {
+    use alloc::sync::Arc;
+    use crate::vfs;
+    
+    crate::println!("Tmpfs Test: Testing tmpfs...");
+    
+    // A private instance with a small cap, so the limit is reachable
+    let fs = Arc::new(Tmpfs::new(8192));
+    if let Err(e) = vfs::mount("/test-tmp", fs.clone()) {
+        crate::println!("Tmpfs Test: ✗ Mount failed: {}", e);
+        return;
+    }
+    
+    let written = vfs::mkdir("/test-tmp/run")
+        .and_then(|()| vfs::write_all("/test-tmp/run/pid", b"42\n"))
+        .and_then(|()| vfs::write("/test-tmp/run/pid", 8, b"x").map(|_| ()));
+    let content = vfs::read_all("/test-tmp/run/pid");
+    match (written, content) {
+        (Ok(()), Ok(data)) if data == b"42\n\0\0\0\0\0x" => {
+            crate::println!("Tmpfs Test: ✓ Write past the end zero-filled the gap");
+        }
+        (written, content) => crate::println!("Tmpfs Test: ✗ Write/read gave {:?}, {:?}", written, content),
+    }
+    
+    let over = vfs::write("/test-tmp/run/pid", 0, &[0u8; 8193]);
+    if over.is_err() && fs.used() == 9 {
+        crate::println!("Tmpfs Test: ✓ Size cap enforced");
+    } else {
+        crate::println!("Tmpfs Test: ✗ Size cap not enforced ({} bytes used)", fs.used());
+    }
+    
+    let busy = vfs::unlink("/test-tmp/run");
+    let removed = vfs::unlink("/test-tmp/run/pid").and_then(|()| vfs::unlink("/test-tmp/run"));
+    let listing = vfs::read_dir("/test-tmp").map(|entries| entries.len());
+    if busy.is_err() && removed.is_ok() && listing == Ok(0) && fs.used() == 0 {
+        crate::println!("Tmpfs Test: ✓ Unlink emptied the tree and released the space");
+    } else {
+        crate::println!("Tmpfs Test: ✗ Unlink left {:?} entries, {} bytes", listing, fs.used());
+    }
+    let _ = vfs::unmount("/test-tmp");
+    
+    crate::println!("Tmpfs Test: Tmpfs test completed");
+}
@@ -0,0 +1,310 @@
+// Static tracepoints
+//
+// Fixed points in the scheduler, interrupt, syscall and frame allocator
+// paths record an event when their tracepoint is switched on. Each CPU
+// has its own ring, and recording takes no lock: a writer reserves a slot
+// with one atomic add and publishes it with a sequence stamp, so
+// tracepoints are safe in any context, including nested interrupts.
+// A switched-off tracepoint costs one atomic load.
+//
+// Readers copy slots out seqlock-style, dropping any that were being
+// overwritten meanwhile, and merge the CPUs' rings by timestamp.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use crate::cpu::{cpu_index, MAX_CPUS};
+use crate::interrupts::{counter_frequency, counter_ticks};
+use crate::ktest::kernel_test;
+use crate::process::scheduler::{current_thread_id, label_of, thread_ref};
+use crate::process::ThreadId;
+
+/// Events kept per CPU; older ones are overwritten.
+pub const TRACEPOINT_RING_SIZE: usize = 512;
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tracepoint {
+    SchedSwitch = 0,    // arg0: previous thread, arg1: next thread
+    IrqEntry = 1,       // arg0: IRQ number
+    IrqExit = 2,        // arg0: IRQ number
+    SyscallEnter = 3,   // arg0: syscall number, arg1: x0
+    SyscallExit = 4,    // arg0: syscall number, arg1: result
+    FrameAlloc = 5,     // arg0: first frame address, arg1: frame count
+}
+
+impl Tracepoint {
+    pub const ALL: [Tracepoint; 6] = [
+        Tracepoint::SchedSwitch,
+        Tracepoint::IrqEntry,
+        Tracepoint::IrqExit,
+        Tracepoint::SyscallEnter,
+        Tracepoint::SyscallExit,
+        Tracepoint::FrameAlloc,
+    ];
+    
+    pub fn name(self) -> &'static str {
+        match self {
+            Tracepoint::SchedSwitch => "sched_switch",
+            Tracepoint::IrqEntry => "irq_entry",
+            Tracepoint::IrqExit => "irq_exit",
+            Tracepoint::SyscallEnter => "syscall_enter",
+            Tracepoint::SyscallExit => "syscall_exit",
+            Tracepoint::FrameAlloc => "frame_alloc",
+        }
+    }
+    
+    pub fn from_name(name: &str) -> Option<Tracepoint> {
+        Self::ALL.into_iter().find(|point| point.name() == name)
+    }
+    
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TraceEvent {
+    pub timestamp: u64, // Counter ticks
+    pub point: Tracepoint,
+    pub cpu: u32,
+    pub thread: u32,
+    pub arg0: u64,
+    pub arg1: u64,
+}
+
+struct Slot {
+    // seq + 1 of the event in the slot once complete, 0 while it is written
+    stamp: AtomicU64,
+    timestamp: AtomicU64,
+    // Tracepoint in the low half, thread in the high half
+    header: AtomicU64,
+    arg0: AtomicU64,
+    arg1: AtomicU64,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            stamp: AtomicU64::new(0),
+            timestamp: AtomicU64::new(0),
+            header: AtomicU64::new(0),
+            arg0: AtomicU64::new(0),
+            arg1: AtomicU64::new(0),
+        }
+    }
+}
+
+struct CpuRing {
+    // Sequence number of the next event; event `seq` lives at seq % size
+    head: AtomicU64,
+    // Events before this one were cleared
+    start: AtomicU64,
+    slots: [Slot; TRACEPOINT_RING_SIZE],
+}
+
+impl CpuRing {
+    const fn new() -> Self {
+        Self {
+            head: AtomicU64::new(0),
+            start: AtomicU64::new(0),
+            slots: [const { Slot::new() }; TRACEPOINT_RING_SIZE],
+        }
+    }
+}
+
+static RINGS: [CpuRing; MAX_CPUS] = [const { CpuRing::new() }; MAX_CPUS];
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+
+/// Export /proc/tracepoints.
+pub fn init() {
+    let _ = crate::procfs::register("tracepoints", proc_tracepoints);
+}
+
+pub fn set_enabled(point: Tracepoint, enabled: bool) {
+    if enabled {
+        ENABLED.fetch_or(point.bit(), Ordering::Relaxed);
+    } else {
+        ENABLED.fetch_and(!point.bit(), Ordering::Relaxed);
+    }
+}
+
+/// Switch every tracepoint on or off.
+pub fn set_all_enabled(enabled: bool) {
+    for point in Tracepoint::ALL {
+        set_enabled(point, enabled);
+    }
+}
+
+pub fn is_enabled(point: Tracepoint) -> bool {
+    ENABLED.load(Ordering::Relaxed) & point.bit() != 0
+}
+
+/// Record `point` on this CPU if it is switched on.
+#[inline]
+pub fn hit(point: Tracepoint, arg0: u64, arg1: u64) {
+    if is_enabled(point) {
+        record(point, arg0, arg1);
+    }
+}
+
+fn record(point: Tracepoint, arg0: u64, arg1: u64) {
+    let Some(ring) = RINGS.get(cpu_index()) else {
+        return;
+    };
+    let seq = ring.head.fetch_add(1, Ordering::Relaxed);
+    let slot = &ring.slots[(seq % TRACEPOINT_RING_SIZE as u64) as usize];
+    
+    slot.stamp.store(0, Ordering::Relaxed);
+    fence(Ordering::Release);
+    slot.timestamp.store(counter_ticks(), Ordering::Relaxed);
+    slot.header.store(point as u64 | (current_thread_id() as u64) << 32, Ordering::Relaxed);
+    slot.arg0.store(arg0, Ordering::Relaxed);
+    slot.arg1.store(arg1, Ordering::Relaxed);
+    slot.stamp.store(seq + 1, Ordering::Release);
+}
+
+// Copy event `seq` out of `ring`, unless it was overwritten or is still
+// being written
+fn read_slot(ring: &CpuRing, cpu: usize, seq: u64) -> Option<TraceEvent> {
+    let slot = &ring.slots[(seq % TRACEPOINT_RING_SIZE as u64) as usize];
+    let stamp = slot.stamp.load(Ordering::Acquire);
+    if stamp != seq + 1 {
+        return None;
+    }
+    let timestamp = slot.timestamp.load(Ordering::Relaxed);
+    let header = slot.header.load(Ordering::Relaxed);
+    let arg0 = slot.arg0.load(Ordering::Relaxed);
+    let arg1 = slot.arg1.load(Ordering::Relaxed);
+    fence(Ordering::Acquire);
+    if slot.stamp.load(Ordering::Relaxed) != stamp {
+        return None;
+    }
+    Some(TraceEvent {
+        timestamp,
+        point: *Tracepoint::ALL.get(header as u32 as usize)?,
+        cpu: cpu as u32,
+        thread: (header >> 32) as u32,
+        arg0,
+        arg1,
+    })
+}
+
+/// The recorded events of every CPU, oldest first.
+pub fn events() -> Vec<TraceEvent> {
+    let mut events = Vec::new();
+    for (cpu, ring) in RINGS.iter().enumerate() {
+        let head = ring.head.load(Ordering::Acquire);
+        let first = head.saturating_sub(TRACEPOINT_RING_SIZE as u64).max(ring.start.load(Ordering::Relaxed));
+        events.extend((first..head).filter_map(|seq| read_slot(ring, cpu, seq)));
+    }
+    events.sort_by_key(|event| event.timestamp);
+    events
+}
+
+/// Forget the events recorded so far.
+pub fn clear() {
+    for ring in &RINGS {
+        ring.start.store(ring.head.load(Ordering::Acquire), Ordering::Relaxed);
+    }
+}
+
+/// One event as a line of text, with its time relative to `base` ticks.
+/// Threads are named as they are now: events only record IDs, so one that
+/// has exited since shows as a bare number.
+pub fn format_event(out: &mut impl Write, event: &TraceEvent, base: u64) -> core::fmt::Result {
+    let ticks = event.timestamp.saturating_sub(base);
+    let us = ticks * 1_000_000 / counter_frequency().max(1);
+    let label = label_of(event.thread);
+    write!(out, "{:>6}.{:06} cpu{} thread {:>4} {:<20} {:<13} ", us / 1_000_000, us % 1_000_000,
+           event.cpu, event.thread, label.as_ref().map_or("-", |label| label.as_str()), event.point.name())?;
+    match event.point {
+        Tracepoint::SchedSwitch => {
+            write!(out, "{} -> {}", thread_ref(event.arg0 as ThreadId), thread_ref(event.arg1 as ThreadId))
+        }
+        Tracepoint::IrqEntry | Tracepoint::IrqExit => write!(out, "irq {}", event.arg0),
+        Tracepoint::SyscallEnter => {
+            let name = crate::syscall::syscall_name(event.arg0).unwrap_or("?");
+            write!(out, "{}({}) x0 0x{:x}", name, event.arg0, event.arg1)
+        }
+        Tracepoint::SyscallExit => {
+            let name = crate::syscall::syscall_name(event.arg0).unwrap_or("?");
+            write!(out, "{}({}) = {}", name, event.arg0, event.arg1 as i64)
+        }
+        Tracepoint::FrameAlloc => write!(out, "0x{:x} x{}", event.arg0, event.arg1),
+    }
+}
+
+fn proc_tracepoints(out: &mut Vec<u8>) {
+    let mut text = alloc::string::String::new();
+    let events = events();
+    let base = events.first().map_or(0, |event| event.timestamp);
+    for event in &events {
+        let _ = format_event(&mut text, event, base);
+        text.push('\n');
+    }
+    out.extend_from_slice(text.as_bytes());
+}
+
+#[kernel_test]
+fn test_tracepoints() {
+    use crate::memory::frame_allocator::{allocate_frame, deallocate_frame};
+    use crate::memory::paging::virt_to_phys;
+    use crate::syscall::SYS_ABI_VERSION;
+    
+    crate::println!("Tracepoint Test: Testing tracepoints...");
+    
+    let traced = |point: Tracepoint, addr: u64| {
+        events().iter().any(|event| event.point == point && event.arg0 == addr)
+    };
+    
+    // Switched off, a tracepoint records nothing
+    clear();
+    let Some(quiet) = allocate_frame() else {
+        crate::println!("Tracepoint Test: ✗ No frame for the tracepoint test");
+        return;
+    };
+    let quiet_addr = virt_to_phys(quiet.as_ptr() as u64);
+    
+    set_enabled(Tracepoint::FrameAlloc, true);
+    let frame = allocate_frame();
+    set_enabled(Tracepoint::FrameAlloc, false);
+    deallocate_frame(quiet);
+    let Some(frame) = frame else {
+        crate::println!("Tracepoint Test: ✗ No frame for the tracepoint test");
+        return;
+    };
+    let frame_addr = virt_to_phys(frame.as_ptr() as u64);
+    deallocate_frame(frame);
+    
+    if traced(Tracepoint::FrameAlloc, frame_addr) && !traced(Tracepoint::FrameAlloc, quiet_addr) {
+        crate::println!("Tracepoint Test: ✓ frame_alloc recorded only while enabled");
+    } else {
+        crate::println!("Tracepoint Test: ✗ frame_alloc events do not match the allocations");
+    }
+    
+    // Syscalls trap through the same path as user code
+    set_enabled(Tracepoint::SyscallEnter, true);
+    set_enabled(Tracepoint::SyscallExit, true);
+    let version: u64;
+    unsafe {
+        asm!("svc #{nr}", nr = const SYS_ABI_VERSION, out("x0") version);
+    }
+    set_enabled(Tracepoint::SyscallEnter, false);
+    set_enabled(Tracepoint::SyscallExit, false);
+    let events = events();
+    let enter = events.iter().position(|event| event.point == Tracepoint::SyscallEnter && event.arg0 == SYS_ABI_VERSION);
+    let exit = events.iter().position(|event| {
+        event.point == Tracepoint::SyscallExit && event.arg0 == SYS_ABI_VERSION && event.arg1 == version
+    });
+    match (enter, exit) {
+        (Some(enter), Some(exit)) if enter < exit => {
+            crate::println!("Tracepoint Test: ✓ syscall_enter/exit recorded in order");
+        }
+        _ => crate::println!("Tracepoint Test: ✗ syscall tracepoints missing or out of order"),
+    }
+    clear();
+    
+    crate::println!("Tracepoint Test: Tracepoint test completed");
+}
@@ -0,0 +1,261 @@
+// Copying to and from the memory of a syscall's caller
+//
+// Every pointer a syscall takes goes through copy_from_user/copy_to_user.
+// A user range must lie in the user half, between the image base and the
+// end of the mmap area, and every page of it must be mapped in the
+// caller's address space: writable for copy_to_user, where a
+// copy-on-write page counts as writable. Kernel threads pass kernel
+// pointers and are trusted.
+//
+// User memory is only touched by the copy loops below, which use the
+// unprivileged LDTR/STTR forms: the MMU checks each access with EL0
+// permissions, so a user pointer can never reach kernel-only memory, and
+// the loops keep working once PAN forbids ordinary EL1 accesses to user
+// pages. A page the caller unmaps between the check and the copy faults;
+// the abort handler sees the faulting PC inside the loops and resumes at
+// their fault exit, so the copy fails with EFAULT instead of panicking.
+// Write faults on copy-on-write pages are resolved first and retried, as
+// for user code.
+//
+// On CPUs with PAN (ARMv8.1), `init` turns it on for good: any other
+// kernel load or store to a user page takes a permission fault, reported
+// as such, instead of silently reading what a user pointer chose. Entry
+// from any exception sets PAN again (SCTLR_EL1.SPAN clear) and new kernel
+// threads start with it set. UAO is kept clear so LDTR/STTR stay
+// unprivileged.
+
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::interrupts::ExceptionContext;
+use crate::memory::frame_allocator::PAGE_SIZE;
+use crate::memory::paging::PageFlags;
+use crate::process::scheduler::{current_thread_id, with_thread};
+use crate::process::thread::{USER_IMAGE_BASE, USER_MMAP_END};
+
+/// Error from the copy helpers; syscalls report it as EFAULT.
+pub const BAD_ADDRESS: &str = "Bad user address";
+
+/// PSTATE.PAN as saved in SPSR_EL1.
+pub const SPSR_PAN: u64 = 1 << 22;
+
+// ID_AA64MMFR1_EL1.PAN and ID_AA64MMFR2_EL1.UAO fields
+const MMFR1_PAN_SHIFT: u64 = 20;
+const MMFR2_UAO_SHIFT: u64 = 4;
+const ID_FIELD_MASK: u64 = 0xF;
+
+// SCTLR_EL1.SPAN: when clear, taking an exception to EL1 sets PAN
+const SCTLR_SPAN: u64 = 1 << 23;
+
+// MSR PAN, #1 / MSR UAO, #0, encoded by hand since the baseline target
+// does not know the registers; MRS of PAN by its system register encoding
+const MSR_PAN_SET: u32 = 0xD500_419F;
+const MSR_UAO_CLEAR: u32 = 0xD500_407F;
+
+static PAN_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// x0 = destination, x1 = source, x2 = length. Both return the number of
+// bytes left uncopied in x0: 0, or what remained when a user access
+// faulted. Words first, then the tail a byte at a time.
+global_asm!(
+    ".global uaccess_start",
+    "uaccess_start:",
+    "",
+    ".global uaccess_copy_in",
+    ".type uaccess_copy_in, %function",
+    "uaccess_copy_in:",
+    "1: cmp x2, #8",
+    "b.lo 2f",
+    "ldtr x3, [x1]",
+    "str x3, [x0], #8",
+    "add x1, x1, #8",
+    "sub x2, x2, #8",
+    "b 1b",
+    "2: cbz x2, 3f",
+    "ldtrb w3, [x1]",
+    "strb w3, [x0], #1",
+    "add x1, x1, #1",
+    "sub x2, x2, #1",
+    "b 2b",
+    "3: mov x0, #0",
+    "ret",
+    ".size uaccess_copy_in, . - uaccess_copy_in",
+    "",
+    ".global uaccess_copy_out",
+    ".type uaccess_copy_out, %function",
+    "uaccess_copy_out:",
+    "1: cmp x2, #8",
+    "b.lo 2f",
+    "ldr x3, [x1], #8",
+    "sttr x3, [x0]",
+    "add x0, x0, #8",
+    "sub x2, x2, #8",
+    "b 1b",
+    "2: cbz x2, 3f",
+    "ldrb w3, [x1], #1",
+    "sttrb w3, [x0]",
+    "add x0, x0, #1",
+    "sub x2, x2, #1",
+    "b 2b",
+    "3: mov x0, #0",
+    "ret",
+    ".size uaccess_copy_out, . - uaccess_copy_out",
+    "",
+    ".global uaccess_end",
+    "uaccess_end:",
+    "",
+    // Exception return lands here from a fault in either loop
+    ".global uaccess_fault",
+    ".type uaccess_fault, %function",
+    "uaccess_fault:",
+    "mov x0, x2",
+    "ret",
+    ".size uaccess_fault, . - uaccess_fault",
+);
+
+extern "C" {
+    fn uaccess_copy_in(dst: *mut u8, src: u64, len: usize) -> usize;
+    fn uaccess_copy_out(dst: u64, src: *const u8, len: usize) -> usize;
+    fn uaccess_start();
+    fn uaccess_end();
+    fn uaccess_fault();
+}
+
+/// Turn on PAN where the CPU has it, and make sure UAO is off.
+pub fn init() {
+    let (mmfr1, mmfr2): (u64, u64);
+    unsafe {
+        asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1);
+        asm!("mrs {}, id_aa64mmfr2_el1", out(reg) mmfr2);
+    }
+    if (mmfr2 >> MMFR2_UAO_SHIFT) & ID_FIELD_MASK != 0 {
+        unsafe { asm!(".inst {}", const MSR_UAO_CLEAR) };
+    }
+    if (mmfr1 >> MMFR1_PAN_SHIFT) & ID_FIELD_MASK == 0 {
+        crate::println!("Uaccess: PAN not implemented, user pointers guarded by checks only");
+        return;
+    }
+    unsafe {
+        let mut sctlr: u64;
+        asm!("mrs {}, sctlr_el1", out(reg) sctlr);
+        sctlr &= !SCTLR_SPAN;
+        asm!("msr sctlr_el1, {}", "isb", in(reg) sctlr);
+        asm!(".inst {}", const MSR_PAN_SET);
+    }
+    PAN_ENABLED.store(true, Ordering::Relaxed);
+    crate::println!("Uaccess: PAN enabled, kernel access to user memory only through copies");
+}
+
+/// Whether PAN was turned on at boot.
+pub fn pan_enabled() -> bool {
+    PAN_ENABLED.load(Ordering::Relaxed)
+}
+
+/// PSTATE.PAN of the running code.
+pub fn pan_active() -> bool {
+    if !pan_enabled() {
+        return false;
+    }
+    let pan: u64;
+    unsafe { asm!("mrs {}, s3_0_c4_c2_3", out(reg) pan) };
+    pan & SPSR_PAN != 0
+}
+
+/// SPSR bits a new kernel thread starts with.
+pub fn thread_spsr_bits() -> u64 {
+    if pan_enabled() { SPSR_PAN } else { 0 }
+}
+
+/// True if the caller may read (or, with `write`, write) [ptr, ptr + len).
+/// Checked up front by calls that must not fail after they block.
+pub fn access_ok(privileged: bool, ptr: u64, len: usize, write: bool) -> bool {
+    let Some(end) = ptr.checked_add(len as u64) else { return false };
+    if ptr == 0 {
+        return false;
+    }
+    if privileged {
+        return true;
+    }
+    if ptr < USER_IMAGE_BASE || end > USER_MMAP_END {
+        return false;
+    }
+    let page = PAGE_SIZE as u64;
+    let mapped = with_thread(current_thread_id(), |thread| {
+        let Some(space) = thread.address_space() else { return false };
+        let mut addr = ptr & !(page - 1);
+        while addr < end {
+            match space.vmm().leaf_entry(addr) {
+                Some(entry) if entry.is_valid() && entry.flags().contains(PageFlags::USER) => {
+                    let flags = entry.flags();
+                    if write && flags.contains(PageFlags::READ_ONLY) && !flags.contains(PageFlags::COW_WRITE) {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+            addr += page;
+        }
+        true
+    });
+    mapped == Some(true)
+}
+
+/// Fill `dst` from the caller's memory at `src`.
+pub fn copy_from_user(privileged: bool, dst: &mut [u8], src: u64) -> Result<(), &'static str> {
+    if dst.is_empty() {
+        return Ok(());
+    }
+    if !access_ok(privileged, src, dst.len(), false) {
+        return Err(BAD_ADDRESS);
+    }
+    if privileged {
+        unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+        return Ok(());
+    }
+    match unsafe { uaccess_copy_in(dst.as_mut_ptr(), src, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(BAD_ADDRESS),
+    }
+}
+
+/// Write `src` to the caller's memory at `dst`.
+pub fn copy_to_user(privileged: bool, dst: u64, src: &[u8]) -> Result<(), &'static str> {
+    if src.is_empty() {
+        return Ok(());
+    }
+    if !access_ok(privileged, dst, src.len(), true) {
+        return Err(BAD_ADDRESS);
+    }
+    if privileged {
+        unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()) };
+        return Ok(());
+    }
+    match unsafe { uaccess_copy_out(dst, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(BAD_ADDRESS),
+    }
+}
+
+/// `len` bytes of the caller's memory at `src`, copied to the heap.
+pub fn read_user(privileged: bool, src: u64, len: usize) -> Result<Vec<u8>, &'static str> {
+    let mut bytes = alloc::vec![0; len];
+    copy_from_user(privileged, &mut bytes, src)?;
+    Ok(bytes)
+}
+
+/// Whether a kernel permission fault at `far` was PAN stopping a direct
+/// access to user memory.
+pub fn is_pan_fault(ctx: &ExceptionContext, far: u64) -> bool {
+    ctx.spsr_el1 & SPSR_PAN != 0 && far < USER_MMAP_END
+}
+
+/// Called for a kernel data abort before it is treated as a kernel bug.
+/// A fault in the copy loops resumes at their fault exit.
+pub fn fixup(ctx: &mut ExceptionContext) -> bool {
+    let (start, end) = (uaccess_start as *const () as u64, uaccess_end as *const () as u64);
+    if ctx.elr_el1 < start || ctx.elr_el1 >= end {
+        return false;
+    }
+    ctx.elr_el1 = uaccess_fault as *const () as u64;
+    true
+}
@@ -1,18 +1,33 @@
-// ARM64 PL011 UART driver for QEMU virt machine
+// ARM64 UART driver: PL011, and the Raspberry Pi's mini-UART
+//
+// Output starts on the board's early console (see board). Once the device
+// tree can be read, `probe` numbers every UART as serialN (by /aliases
+// where present, then in tree order) and moves the console to the one
+// `console=` names (`serial1`, or a physical address), else
+// /chosen/stdout-path's. A console outside the device memory the boot
+// page tables map stays silent until memory::init has mapped it; the
+// kernel log keeps what it missed.
 
 use core::fmt::{Arguments, Write};
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::ktest::kernel_test;
+use crate::memory::paging::phys_to_virt;
 
-// QEMU virt machine UART base address
-const UART_BASE: *mut u32 = 0x09000000 as *mut u32;
+/// Serial ports numbered from the device tree.
+pub const MAX_UARTS: usize = 8;
 
-// UART register offsets
+// PL011 register offsets
 const UART_DR: isize = 0x00;     // Data Register
 const UART_FR: isize = 0x06;     // Flag Register
 const UART_IBRD: isize = 0x09;   // Integer Baud Rate Divisor
 const UART_FBRD: isize = 0x0A;   // Fractional Baud Rate Divisor
 const UART_LCRH: isize = 0x0B;   // Line Control Register
 const UART_CR: isize = 0x0C;     // Control Register
+const UART_IMSC: isize = 0x0E;   // Interrupt Mask Set/Clear
+const UART_ICR: isize = 0x11;    // Interrupt Clear
 
 // Flag register bits
 const UART_FR_TXFF: u32 = 1 << 5; // Transmit FIFO full
@@ -23,23 +38,71 @@ const UART_CR_UARTEN: u32 = 1 << 0; // UART enable
 const UART_CR_TXE: u32 = 1 << 8;    // Transmit enable
 const UART_CR_RXE: u32 = 1 << 9;    // Receive enable
 
+// Interrupt bits (IMSC/ICR)
+const UART_INT_RX: u32 = 1 << 4;  // Receive FIFO reached its level
+const UART_INT_RT: u32 = 1 << 6;  // Receive timeout: data left below the level
+
 // Line control register bits
 const UART_LCRH_WLEN_8: u32 = 3 << 5; // 8-bit words
 const UART_LCRH_FEN: u32 = 1 << 4;    // FIFO enable
 
+// Mini-UART register offsets, from AUX_MU_IO
+const MU_IO: isize = 0x00;        // Data
+const MU_IER: isize = 0x01;       // Interrupt enable
+const MU_IIR: isize = 0x02;       // Interrupt identify, FIFO clear
+const MU_LCR: isize = 0x03;       // Line control
+const MU_LSR: isize = 0x05;       // Line status
+const MU_CNTL: isize = 0x08;      // Extra control: receiver/transmitter enable
+const MU_BAUD: isize = 0x0A;      // Baud rate counter
+const AUX_ENABLES: isize = -0x0F; // In the AUX block, 0x3c before AUX_MU_IO
+
+const MU_LSR_DATA_READY: u32 = 1 << 0;
+const MU_LSR_TX_EMPTY: u32 = 1 << 5;  // Room for at least one byte
+const MU_LCR_8BIT: u32 = 3;
+const MU_IIR_CLEAR_FIFOS: u32 = 0xC6;
+const MU_CNTL_RX_TX: u32 = 3;
+// Receive interrupt; bits 3:2 are needed too, whatever the datasheet says
+const MU_IER_RX: u32 = 0b1101;
+const AUX_ENABLES_MINI_UART: u32 = 1 << 0;
+// 115200 baud from the 500MHz core clock the firmware fixes with enable_uart
+const MU_BAUD_115200: u32 = 541;
+
+/// Register layout of a UART.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UartKind {
+    Pl011,
+    /// BCM2835 auxiliary mini-UART (Raspberry Pi), a cut-down 16550
+    MiniUart,
+}
+
 pub struct Uart {
     base: *mut u32,
+    kind: UartKind,
 }
 
 impl Uart {
-    pub const fn new() -> Self {
-        Self {
-            base: UART_BASE,
-        }
+    /// A PL011 at `base`, a mapped kernel virtual address.
+    pub const fn at(base: *mut u32) -> Self {
+        Self::of_kind(base, UartKind::Pl011)
     }
     
+    /// A UART of either kind at `base`, a mapped kernel virtual address.
+    pub const fn of_kind(base: *mut u32, kind: UartKind) -> Self {
+        Self { base, kind }
+    }
+    
+    /// Set up 8N1 with FIFOs, unless firmware has already enabled the
+    /// UART; its baud rate is then kept, since the clock is its business.
     pub fn init(&self) {
+        if self.kind == UartKind::MiniUart {
+            self.init_mini();
+            return;
+        }
         unsafe {
+            if read_volatile(self.base.offset(UART_CR)) & UART_CR_UARTEN != 0 {
+                return;
+            }
+            
             // Disable UART
             write_volatile(self.base.offset(UART_CR), 0);
             
@@ -57,7 +120,30 @@ impl Uart {
         }
     }
     
+    fn init_mini(&self) {
+        unsafe {
+            let enables = read_volatile(self.base.offset(AUX_ENABLES));
+            if enables & AUX_ENABLES_MINI_UART != 0 {
+                return;
+            }
+            write_volatile(self.base.offset(AUX_ENABLES), enables | AUX_ENABLES_MINI_UART);
+            write_volatile(self.base.offset(MU_CNTL), 0);
+            write_volatile(self.base.offset(MU_IER), 0);
+            write_volatile(self.base.offset(MU_LCR), MU_LCR_8BIT);
+            write_volatile(self.base.offset(MU_IIR), MU_IIR_CLEAR_FIFOS);
+            write_volatile(self.base.offset(MU_BAUD), MU_BAUD_115200);
+            write_volatile(self.base.offset(MU_CNTL), MU_CNTL_RX_TX);
+        }
+    }
+    
     pub fn put_char(&self, c: u8) {
+        if self.kind == UartKind::MiniUart {
+            unsafe {
+                while read_volatile(self.base.offset(MU_LSR)) & MU_LSR_TX_EMPTY == 0 {}
+                write_volatile(self.base.offset(MU_IO), c as u32);
+            }
+            return;
+        }
         unsafe {
             // Wait until transmit FIFO is not full
             while read_volatile(self.base.offset(UART_FR)) & UART_FR_TXFF != 0 {}
@@ -68,6 +154,12 @@ impl Uart {
     }
     
     pub fn get_char(&self) -> Option<u8> {
+        if self.kind == UartKind::MiniUart {
+            return unsafe {
+                (read_volatile(self.base.offset(MU_LSR)) & MU_LSR_DATA_READY != 0)
+                    .then(|| read_volatile(self.base.offset(MU_IO)) as u8)
+            };
+        }
         unsafe {
             // Check if receive FIFO is empty
             if read_volatile(self.base.offset(UART_FR)) & UART_FR_RXFE != 0 {
@@ -78,6 +170,27 @@ impl Uart {
         }
     }
     
+    /// Raise an interrupt when received data is waiting.
+    pub fn enable_rx_interrupt(&self) {
+        if self.kind == UartKind::MiniUart {
+            unsafe { write_volatile(self.base.offset(MU_IER), MU_IER_RX) };
+            return;
+        }
+        unsafe {
+            write_volatile(self.base.offset(UART_ICR), UART_INT_RX | UART_INT_RT);
+            write_volatile(self.base.offset(UART_IMSC), UART_INT_RX | UART_INT_RT);
+        }
+    }
+    
+    /// Acknowledge receive interrupts once the FIFO has been drained.
+    pub fn clear_rx_interrupt(&self) {
+        // The mini-UART's interrupt drops by itself once the FIFO is empty
+        if self.kind == UartKind::MiniUart {
+            return;
+        }
+        unsafe { write_volatile(self.base.offset(UART_ICR), UART_INT_RX | UART_INT_RT) };
+    }
+    
     pub fn puts(&self, s: &str) {
         for byte in s.bytes() {
             if byte == b'\n' {
@@ -95,19 +208,205 @@ impl Write for Uart {
     }
 }
 
-// Global UART instance
-static mut UART: Uart = Uart::new();
+/// A UART the device tree describes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Port {
+    pub phys: u64,
+    pub kind: UartKind,
+}
+
+// Device tree compatibles for each kind
+const COMPATIBLES: [(&str, UartKind); 2] = [("arm,pl011", UartKind::Pl011), ("brcm,bcm2835-aux-uart", UartKind::MiniUart)];
+
+// Console UART: kernel virtual base, 0 while it cannot be reached yet
+static CONSOLE_BASE: AtomicUsize = AtomicUsize::new(0);
+static CONSOLE_PHYS: AtomicU64 = AtomicU64::new(crate::board::BUILD.early_console.0);
+static CONSOLE_KIND: AtomicU8 = AtomicU8::new(crate::board::BUILD.early_console.1 as u8);
+// serialN of the console; MAX_UARTS until probed or when not in the tree
+static CONSOLE_INDEX: AtomicUsize = AtomicUsize::new(MAX_UARTS);
+static PORTS: Mutex<[Option<Port>; MAX_UARTS]> = Mutex::new([None; MAX_UARTS]);
+
+fn console_kind() -> UartKind {
+    match CONSOLE_KIND.load(Ordering::Relaxed) {
+        kind if kind == UartKind::MiniUart as u8 => UartKind::MiniUart,
+        _ => UartKind::Pl011,
+    }
+}
+
+fn console() -> Option<Uart> {
+    match CONSOLE_BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(Uart::of_kind(base as *mut u32, console_kind())),
+    }
+}
+
+// Initialize the console UART and send output there
+fn attach_console() {
+    let base = phys_to_virt(console_phys()) as usize;
+    Uart::of_kind(base as *mut u32, console_kind()).init();
+    CONSOLE_BASE.store(base, Ordering::Release);
+}
 
+/// Bring up the board's early console for the first messages.
 pub fn init_uart() {
-    unsafe {
-        UART.init();
+    attach_console();
+}
+
+/// Physical address of the console UART.
+pub fn console_phys() -> u64 {
+    CONSOLE_PHYS.load(Ordering::Relaxed)
+}
+
+/// Each serialN the device tree describes.
+pub fn ports() -> [Option<Port>; MAX_UARTS] {
+    *PORTS.lock()
+}
+
+// Output goes to `port` from now on, once it can be reached
+fn switch_console(port: Port, index: usize) {
+    CONSOLE_INDEX.store(index, Ordering::Relaxed);
+    if port.phys == console_phys() && port.kind == console_kind() && CONSOLE_BASE.load(Ordering::Relaxed) != 0 {
+        return;
+    }
+    crate::println!("Console: Moving to serial{} at 0x{:x}", index, port.phys);
+    CONSOLE_BASE.store(0, Ordering::Release);
+    CONSOLE_PHYS.store(port.phys, Ordering::Relaxed);
+    CONSOLE_KIND.store(port.kind as u8, Ordering::Relaxed);
+    let mapped = cfg!(feature = "no-mmu") || crate::memory::mmu::MemoryManagementUnit::is_enabled();
+    if mapped || crate::board::BUILD.early_device(port.phys) {
+        attach_console();
+        crate::println!("Console: serial{} at 0x{:x} ({:?})", index, port.phys, port.kind);
     }
 }
 
-pub fn print_args(args: Arguments) {
-    unsafe {
-        let _ = UART.write_fmt(args);
+/// Number the UARTs in the device tree and pick the console. Runs before
+/// the heap exists.
+pub fn probe() {
+    let Some(dt) = crate::devicetree::device_tree() else {
+        return;
+    };
+    let mut ports = [None; MAX_UARTS];
+    let port = |node: &crate::devicetree::DeviceNode| {
+        let (_, kind) = COMPATIBLES.into_iter().find(|&(compatible, _)| node.is_compatible(compatible))?;
+        let (phys, _) = node.reg(0).filter(|_| node.is_enabled())?;
+        Some(Port { phys, kind })
+    };
+    for (index, slot) in ports.iter_mut().enumerate() {
+        let mut alias = *b"serial0";
+        alias[6] += index as u8;
+        let alias = core::str::from_utf8(&alias).unwrap_or_default();
+        *slot = dt.find_by_path(alias).as_ref().and_then(port);
+    }
+    for found in dt.nodes().filter_map(|node| port(&node)) {
+        if ports.contains(&Some(found)) {
+            continue;
+        }
+        if let Some(free) = ports.iter_mut().find(|slot| slot.is_none()) {
+            *free = Some(found);
+        }
     }
+    *PORTS.lock() = ports;
+    
+    let index_of = |phys: u64| ports.iter().position(|slot| slot.is_some_and(|port| port.phys == phys));
+    let requested = match crate::bootparams::get("console") {
+        Some(arg) => {
+            let port = match arg.strip_prefix("serial") {
+                Some(number) => number.parse::<usize>().ok().and_then(|index| ports.get(index).copied().flatten()),
+                // An address the tree does not list is taken to be a PL011
+                None => arg.strip_prefix("0x").and_then(|hex| u64::from_str_radix(hex, 16).ok()).map(|phys| {
+                    index_of(phys).and_then(|index| ports[index]).unwrap_or(Port { phys, kind: UartKind::Pl011 })
+                }),
+            };
+            if port.is_none() {
+                crate::println!("Console: No serial port {}, keeping 0x{:x}", arg, console_phys());
+            }
+            port
+        }
+        None => None,
+    };
+    // stdout-path may carry options after a colon ("serial0:115200n8")
+    let stdout = || {
+        let path = dt.find_by_name("chosen")?.property_str("stdout-path")?;
+        port(&dt.find_by_path(path.split(':').next()?)?)
+    };
+    let chosen = requested
+        .or_else(stdout)
+        .or_else(|| index_of(console_phys()).and_then(|index| ports[index]))
+        .or(ports[0]);
+    if let Some(chosen) = chosen {
+        switch_console(chosen, index_of(chosen.phys).unwrap_or(MAX_UARTS));
+    }
+}
+
+/// Attach a console that probe found outside the boot mappings, now that
+/// memory::init has mapped every device. Exports /proc/serial.
+pub fn late_init() {
+    if CONSOLE_BASE.load(Ordering::Acquire) == 0 {
+        attach_console();
+        crate::println!("Console: serial{} at 0x{:x} ({:?})",
+                       CONSOLE_INDEX.load(Ordering::Relaxed), console_phys(), console_kind());
+    }
+    let _ = crate::procfs::register("serial", proc_serial);
+}
+
+fn proc_serial(out: &mut Vec<u8>) {
+    let console = console_phys();
+    let mut text = alloc::string::String::new();
+    for (index, port) in ports().iter().enumerate() {
+        if let Some(port) = port {
+            let marker = if port.phys == console { " console" } else { "" };
+            let _ = writeln!(text, "serial{} 0x{:x} {:?}{}", index, port.phys, port.kind, marker);
+        }
+    }
+    if !ports().iter().flatten().any(|port| port.phys == console) {
+        let _ = writeln!(text, "console 0x{:x} {:?}", console, console_kind());
+    }
+    out.extend_from_slice(text.as_bytes());
+}
+
+/// Raise an interrupt when received data is waiting.
+pub fn enable_rx_interrupt() {
+    if let Some(uart) = console() {
+        uart.enable_rx_interrupt();
+    }
+}
+
+/// Acknowledge receive interrupts once the FIFO has been drained.
+pub fn clear_rx_interrupt() {
+    if let Some(uart) = console() {
+        uart.clear_rx_interrupt();
+    }
+}
+
+/// Poll the UART receiver.
+pub fn get_char() -> Option<u8> {
+    console()?.get_char()
+}
+
+/// Write to the UART only, bypassing the kernel log (host protocol replies).
+pub fn put_raw(bytes: &[u8]) {
+    let Some(uart) = console() else {
+        return;
+    };
+    for &byte in bytes {
+        uart.put_char(byte);
+    }
+}
+
+// Console output goes to the UART, shared with the shell, and into the
+// kernel log
+struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        crate::console::write_output(s.as_bytes());
+        crate::klog::write(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub fn print_args(args: Arguments) {
+    let _ = ConsoleWriter.write_fmt(args);
 }
 
 // Export macros for early printing
@@ -126,4 +425,45 @@ macro_rules! println {
     ($($arg:tt)*) => {
         $crate::print!("{}\n", format_args!($($arg)*))
     };
-}
\ No newline at end of file
+}
+
+#[kernel_test]
+fn test_serial_ports() {
+    use crate::devicetree::device_tree;
+    
+    crate::println!("UART Test: Testing serial port discovery...");
+    
+    let Some(dt) = device_tree() else {
+        crate::println!("UART Test: Serial ports skipped: no device tree");
+        return;
+    };
+    let root = dt.find_by_path("/").map(|node| node.name());
+    let chosen = dt.find_by_path("/chosen").map(|node| node.name());
+    if root == Some("") && chosen == Some("chosen") && dt.find_by_path("/no-such-node").is_none() {
+        crate::println!("UART Test: ✓ Device tree paths resolved");
+    } else {
+        crate::println!("UART Test: ✗ Path lookup gave {:?} and {:?}", root, chosen);
+    }
+    // A top-level UART by its full name and without the unit address
+    if let Some(node) = dt.find_compatible("arm,pl011").find(|node| node.depth() == 1) {
+        let full = dt.find_by_path(&alloc::format!("/{}", node.name())).map(|node| node.name());
+        let short = node.name().split('@').next().and_then(|name| dt.find_by_path(&alloc::format!("/{}", name)));
+        if full == Some(node.name()) && short.is_some_and(|short| short.name().split('@').next() == node.name().split('@').next()) {
+            crate::println!("UART Test: ✓ /{} found with and without its unit address", node.name());
+        } else {
+            crate::println!("UART Test: ✗ /{} lookup gave {:?}", node.name(), full);
+        }
+    }
+    
+    let ports = ports();
+    let count = ports.iter().flatten().count();
+    let expected = (dt.find_compatible("arm,pl011").count() + dt.find_compatible("brcm,bcm2835-aux-uart").count()).min(MAX_UARTS);
+    let console_listed = ports.iter().flatten().any(|port| port.phys == console_phys());
+    if count == expected && (count == 0 || console_listed) {
+        crate::println!("UART Test: ✓ {} serial ports, console at 0x{:x}", count, console_phys());
+    } else {
+        crate::println!("UART Test: ✗ Serial ports {:x?}, console 0x{:x}", ports, console_phys());
+    }
+    
+    crate::println!("UART Test: Serial port test completed");
+}
@@ -0,0 +1,643 @@
+// DWARF call frame information unwinder (eh-unwind feature)
+//
+// Every function the compiler emits has an FDE in .eh_frame describing,
+// for each instruction, where the caller's frame starts (the CFA) and where
+// each callee-saved register and the return address were stored. Running
+// that program up to a pc gives the caller's registers exactly, whether or
+// not the function set up a frame record. The exception vector stubs carry
+// hand-written CFI for the exception frame, so a walk that starts in a
+// handler continues into the interrupted code.
+//
+// The linker's .eh_frame_hdr search table finds the FDE for a pc by binary
+// search; without one the FDEs are scanned in order. Only the rules Rust
+// and the assembler emit are handled: a DWARF expression ends the walk.
+// Saved registers are only read from inside the walk's stack bounds.
+
+use crate::backtrace::StartFrame;
+
+extern "C" {
+    static __eh_frame_start: u8;
+    static __eh_frame_end: u8;
+    static __eh_frame_hdr_start: u8;
+    static __eh_frame_hdr_end: u8;
+}
+
+// Pointer encodings (DW_EH_PE_*): value format in the low nibble, what it
+// is relative to in the high one
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0A;
+const DW_EH_PE_SDATA4: u8 = 0x0B;
+const DW_EH_PE_SDATA8: u8 = 0x0C;
+const DW_EH_PE_PCREL: u8 = 0x10;
+const DW_EH_PE_DATAREL: u8 = 0x30;
+const DW_EH_PE_INDIRECT: u8 = 0x80;
+const DW_EH_PE_OMIT: u8 = 0xFF;
+
+// Call frame instructions; the first three carry an operand in the low six bits
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_RESTORE: u8 = 0xC0;
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_SET_LOC: u8 = 0x01;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_OFFSET_EXTENDED: u8 = 0x05;
+const DW_CFA_RESTORE_EXTENDED: u8 = 0x06;
+const DW_CFA_UNDEFINED: u8 = 0x07;
+const DW_CFA_SAME_VALUE: u8 = 0x08;
+const DW_CFA_REGISTER: u8 = 0x09;
+const DW_CFA_REMEMBER_STATE: u8 = 0x0A;
+const DW_CFA_RESTORE_STATE: u8 = 0x0B;
+const DW_CFA_DEF_CFA: u8 = 0x0C;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0D;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0E;
+const DW_CFA_OFFSET_EXTENDED_SF: u8 = 0x11;
+const DW_CFA_DEF_CFA_SF: u8 = 0x12;
+const DW_CFA_DEF_CFA_OFFSET_SF: u8 = 0x13;
+const DW_CFA_VAL_OFFSET: u8 = 0x14;
+const DW_CFA_VAL_OFFSET_SF: u8 = 0x15;
+const DW_CFA_GNU_ARGS_SIZE: u8 = 0x2E;
+// Return address signing state; the kernel does not sign return addresses
+const DW_CFA_AARCH64_NEGATE_RA_STATE: u8 = 0x2D;
+
+// DWARF register numbers: x0-x30, sp, then pc, which the exception stubs
+// use as their return address column. FP/SIMD registers are not tracked.
+const REG_SP: usize = 31;
+const REG_PC: usize = 32;
+const NUM_REGS: usize = 33;
+
+// Depth of DW_CFA_remember_state nesting
+const MAX_SAVED_ROWS: usize = 4;
+
+#[derive(Clone)]
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    // Address of data[0], for pc-relative pointers
+    base: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], base: u64) -> Self {
+        Reader { data, pos: 0, base }
+    }
+    
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+    
+    fn address(&self) -> u64 {
+        self.base + self.pos as u64
+    }
+    
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+    
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = self.data.get(self.pos..).unwrap_or(&[]);
+        self.pos = self.data.len();
+        rest
+    }
+    
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+    
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+    
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+    
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+    
+    fn uleb(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+    
+    fn sleb(&mut self) -> Option<i64> {
+        let mut value = 0i64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as i64) << shift;
+            if byte & 0x80 == 0 {
+                // Sign-extend from the last byte's top bit
+                if shift + 7 < 64 && byte & 0x40 != 0 {
+                    value |= -1i64 << (shift + 7);
+                }
+                return Some(value);
+            }
+        }
+        None
+    }
+    
+    fn cstr(&mut self) -> Option<&'a [u8]> {
+        let len = self.data.get(self.pos..)?.iter().position(|&b| b == 0)?;
+        let text = self.bytes(len)?;
+        self.pos += 1;
+        Some(text)
+    }
+    
+    // An encoded pointer; `datarel` is the base for DW_EH_PE_datarel
+    fn pointer(&mut self, encoding: u8, datarel: u64) -> Option<u64> {
+        if encoding == DW_EH_PE_OMIT || encoding & DW_EH_PE_INDIRECT != 0 {
+            return None;
+        }
+        let field = self.address();
+        let value = match encoding & 0x0F {
+            DW_EH_PE_ABSPTR | DW_EH_PE_UDATA8 | DW_EH_PE_SDATA8 => self.u64()?,
+            DW_EH_PE_ULEB128 => self.uleb()?,
+            DW_EH_PE_SLEB128 => self.sleb()? as u64,
+            DW_EH_PE_UDATA2 => self.u16()? as u64,
+            DW_EH_PE_UDATA4 => self.u32()? as u64,
+            DW_EH_PE_SDATA2 => self.u16()? as i16 as u64,
+            DW_EH_PE_SDATA4 => self.u32()? as i32 as u64,
+            _ => return None,
+        };
+        match encoding & 0x70 {
+            0 => Some(value),
+            DW_EH_PE_PCREL => Some(field.wrapping_add(value)),
+            DW_EH_PE_DATAREL => Some(datarel.wrapping_add(value)),
+            _ => None,
+        }
+    }
+}
+
+/// Common information shared by the FDEs of one object.
+#[derive(Copy, Clone)]
+struct Cie<'a> {
+    code_align: u64,
+    data_align: i64,
+    ra_reg: usize,
+    fde_encoding: u8,
+    // 'z': FDEs carry augmentation data to skip
+    has_augmentation: bool,
+    // 'S': an exception frame, whose caller resumes at an exact pc
+    signal: bool,
+    instructions: &'a [u8],
+}
+
+/// The unwind rules for one function.
+#[derive(Copy, Clone)]
+struct Fde<'a> {
+    cie: Cie<'a>,
+    pc_begin: u64,
+    pc_end: u64,
+    instructions: &'a [u8],
+}
+
+/// An .eh_frame section and its optional search table.
+#[derive(Copy, Clone)]
+pub struct Tables<'a> {
+    frame: &'a [u8],
+    frame_base: u64,
+    hdr: &'a [u8],
+    hdr_base: u64,
+}
+
+impl<'a> Tables<'a> {
+    // Body of the record at `offset`, as a reader positioned on its ID
+    fn record(&self, offset: usize) -> Option<Reader<'a>> {
+        let mut reader = Reader::new(self.frame, self.frame_base);
+        reader.pos = offset;
+        let len = reader.u32()?;
+        // A zero length terminates the section; 64-bit records are not emitted
+        if len == 0 || len == u32::MAX {
+            return None;
+        }
+        let body = reader.bytes(len as usize)?;
+        Some(Reader::new(body, self.frame_base + offset as u64 + 4))
+    }
+    
+    fn cie(&self, offset: usize) -> Option<Cie<'a>> {
+        let mut reader = self.record(offset)?;
+        if reader.u32()? != 0 {
+            return None;
+        }
+        let version = reader.u8()?;
+        if version != 1 && version != 3 {
+            return None;
+        }
+        let augmentation = reader.cstr()?;
+        let code_align = reader.uleb()?;
+        let data_align = reader.sleb()?;
+        let ra_reg = if version == 1 { reader.u8()? as usize } else { reader.uleb()? as usize };
+        
+        let mut cie = Cie {
+            code_align,
+            data_align,
+            ra_reg,
+            fde_encoding: DW_EH_PE_ABSPTR,
+            has_augmentation: augmentation.first() == Some(&b'z'),
+            signal: false,
+            instructions: &[],
+        };
+        if cie.has_augmentation {
+            let len = reader.uleb()? as usize;
+            let base = reader.address();
+            let mut data = Reader::new(reader.bytes(len)?, base);
+            for &letter in &augmentation[1..] {
+                match letter {
+                    b'R' => cie.fde_encoding = data.u8()?,
+                    b'P' => {
+                        let encoding = data.u8()?;
+                        data.pointer(encoding & !DW_EH_PE_INDIRECT, 0)?;
+                    }
+                    b'L' => {
+                        data.u8()?;
+                    }
+                    b'S' => cie.signal = true,
+                    // Pointer authentication key; nothing to read
+                    b'B' => {}
+                    // The data length lets the rest be skipped
+                    _ => break,
+                }
+            }
+        } else if !augmentation.is_empty() {
+            return None;
+        }
+        cie.instructions = reader.rest();
+        Some(cie)
+    }
+    
+    fn fde(&self, offset: usize) -> Option<Fde<'a>> {
+        let mut reader = self.record(offset)?;
+        // The CIE pointer counts back from its own position
+        let cie_pointer = reader.u32()? as usize;
+        if cie_pointer == 0 {
+            return None;
+        }
+        let cie = self.cie((offset + 4).checked_sub(cie_pointer)?)?;
+        let pc_begin = reader.pointer(cie.fde_encoding, 0)?;
+        let pc_range = reader.pointer(cie.fde_encoding & 0x0F, 0)?;
+        if cie.has_augmentation {
+            let len = reader.uleb()? as usize;
+            reader.bytes(len)?;
+        }
+        Some(Fde { cie, pc_begin, pc_end: pc_begin.checked_add(pc_range)?, instructions: reader.rest() })
+    }
+    
+    // Binary search of .eh_frame_hdr; None if there is no usable table
+    fn search(&self, pc: u64) -> Option<Option<Fde<'a>>> {
+        let mut reader = Reader::new(self.hdr, self.hdr_base);
+        if reader.u8()? != 1 {
+            return None;
+        }
+        let (frame_encoding, count_encoding, table_encoding) = (reader.u8()?, reader.u8()?, reader.u8()?);
+        reader.pointer(frame_encoding, self.hdr_base)?;
+        let count = reader.pointer(count_encoding, self.hdr_base)? as usize;
+        if table_encoding != DW_EH_PE_DATAREL | DW_EH_PE_SDATA4 {
+            return None;
+        }
+        let table = reader.bytes(count.checked_mul(8)?)?;
+        let entry = |index: usize| {
+            let field = |at: usize| {
+                let value = i32::from_le_bytes(table[at..at + 4].try_into().unwrap());
+                self.hdr_base.wrapping_add(value as i64 as u64)
+            };
+            (field(index * 8), field(index * 8 + 4))
+        };
+        
+        // Entries are sorted: find the first one starting above pc
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if entry(mid).0 <= pc {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low == 0 {
+            return Some(None);
+        }
+        let fde_address = entry(low - 1).1;
+        let offset = fde_address.checked_sub(self.frame_base)? as usize;
+        Some(self.fde(offset).filter(|fde| pc < fde.pc_end))
+    }
+    
+    fn scan(&self, pc: u64) -> Option<Fde<'a>> {
+        let mut offset = 0;
+        while let Some(mut reader) = self.record(offset) {
+            let id = reader.u32()?;
+            if id != 0 {
+                if let Some(fde) = self.fde(offset).filter(|fde| (fde.pc_begin..fde.pc_end).contains(&pc)) {
+                    return Some(fde);
+                }
+            }
+            offset += 4 + reader.data.len();
+        }
+        None
+    }
+    
+    fn find_fde(&self, pc: u64) -> Option<Fde<'a>> {
+        match self.search(pc) {
+            Some(found) => found,
+            None => self.scan(pc),
+        }
+    }
+    
+    /// FDEs in the search table, if there is one.
+    pub fn table_len(&self) -> Option<usize> {
+        let mut reader = Reader::new(self.hdr, self.hdr_base);
+        if reader.u8()? != 1 {
+            return None;
+        }
+        let (frame_encoding, count_encoding) = (reader.u8()?, reader.u8()?);
+        reader.u8()?;
+        reader.pointer(frame_encoding, self.hdr_base)?;
+        Some(reader.pointer(count_encoding, self.hdr_base)? as usize)
+    }
+    
+    pub fn frame_len(&self) -> usize {
+        self.frame.len()
+    }
+}
+
+fn section(start: *const u8, end: *const u8) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) }
+}
+
+/// The kernel's own unwind tables.
+pub fn kernel_tables() -> Tables<'static> {
+    let frame = section(&raw const __eh_frame_start, &raw const __eh_frame_end);
+    let hdr = section(&raw const __eh_frame_hdr_start, &raw const __eh_frame_hdr_end);
+    Tables { frame, frame_base: frame.as_ptr() as u64, hdr, hdr_base: hdr.as_ptr() as u64 }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Rule {
+    Undefined,
+    SameValue,
+    /// Saved at CFA + offset.
+    Offset(i64),
+    /// The value is CFA + offset.
+    ValOffset(i64),
+    Register(usize),
+}
+
+/// Where the CFA and each register are at one instruction.
+#[derive(Copy, Clone)]
+struct Row {
+    cfa_reg: usize,
+    cfa_offset: i64,
+    rules: [Rule; NUM_REGS],
+}
+
+impl Row {
+    const fn new() -> Self {
+        Row { cfa_reg: REG_SP, cfa_offset: 0, rules: [Rule::SameValue; NUM_REGS] }
+    }
+    
+    // FP/SIMD registers are saved too but never needed to find a caller
+    fn set(&mut self, reg: u64, rule: Rule) {
+        if let Some(slot) = self.rules.get_mut(reg as usize) {
+            *slot = rule;
+        }
+    }
+}
+
+// Run `instructions` from `loc`, stopping before the first row that starts
+// above `target`. `initial` is the CIE's row, for DW_CFA_restore.
+fn execute(instructions: &[u8], cie: &Cie, row: &mut Row, initial: &Row, mut loc: u64, target: u64) -> Option<()> {
+    let mut reader = Reader::new(instructions, 0);
+    let mut saved = [Row::new(); MAX_SAVED_ROWS];
+    let mut depth = 0;
+    let data_offset = |factored: i64| factored.checked_mul(cie.data_align);
+    
+    while !reader.is_empty() {
+        let op = reader.u8()?;
+        let operand = (op & 0x3F) as u64;
+        let advance = match op & 0xC0 {
+            DW_CFA_ADVANCE_LOC => Some(operand),
+            DW_CFA_OFFSET => {
+                let offset = data_offset(reader.uleb()? as i64)?;
+                row.set(operand, Rule::Offset(offset));
+                None
+            }
+            DW_CFA_RESTORE => {
+                row.set(operand, *initial.rules.get(operand as usize).unwrap_or(&Rule::SameValue));
+                None
+            }
+            _ => match op {
+                DW_CFA_NOP | DW_CFA_AARCH64_NEGATE_RA_STATE => None,
+                DW_CFA_SET_LOC => {
+                    loc = reader.pointer(cie.fde_encoding, 0)?;
+                    if loc > target {
+                        return Some(());
+                    }
+                    None
+                }
+                DW_CFA_ADVANCE_LOC1 => Some(reader.u8()? as u64),
+                DW_CFA_ADVANCE_LOC2 => Some(reader.u16()? as u64),
+                DW_CFA_ADVANCE_LOC4 => Some(reader.u32()? as u64),
+                DW_CFA_OFFSET_EXTENDED => {
+                    let reg = reader.uleb()?;
+                    row.set(reg, Rule::Offset(data_offset(reader.uleb()? as i64)?));
+                    None
+                }
+                DW_CFA_OFFSET_EXTENDED_SF => {
+                    let reg = reader.uleb()?;
+                    row.set(reg, Rule::Offset(data_offset(reader.sleb()?)?));
+                    None
+                }
+                DW_CFA_VAL_OFFSET => {
+                    let reg = reader.uleb()?;
+                    row.set(reg, Rule::ValOffset(data_offset(reader.uleb()? as i64)?));
+                    None
+                }
+                DW_CFA_VAL_OFFSET_SF => {
+                    let reg = reader.uleb()?;
+                    row.set(reg, Rule::ValOffset(data_offset(reader.sleb()?)?));
+                    None
+                }
+                DW_CFA_RESTORE_EXTENDED => {
+                    let reg = reader.uleb()?;
+                    row.set(reg, *initial.rules.get(reg as usize).unwrap_or(&Rule::SameValue));
+                    None
+                }
+                DW_CFA_UNDEFINED => {
+                    row.set(reader.uleb()?, Rule::Undefined);
+                    None
+                }
+                DW_CFA_SAME_VALUE => {
+                    row.set(reader.uleb()?, Rule::SameValue);
+                    None
+                }
+                DW_CFA_REGISTER => {
+                    let (reg, other) = (reader.uleb()?, reader.uleb()? as usize);
+                    if other >= NUM_REGS {
+                        return None;
+                    }
+                    row.set(reg, Rule::Register(other));
+                    None
+                }
+                DW_CFA_REMEMBER_STATE => {
+                    *saved.get_mut(depth)? = *row;
+                    depth += 1;
+                    None
+                }
+                DW_CFA_RESTORE_STATE => {
+                    depth = depth.checked_sub(1)?;
+                    *row = saved[depth];
+                    None
+                }
+                DW_CFA_DEF_CFA => {
+                    row.cfa_reg = reader.uleb()? as usize;
+                    row.cfa_offset = reader.uleb()? as i64;
+                    None
+                }
+                DW_CFA_DEF_CFA_SF => {
+                    row.cfa_reg = reader.uleb()? as usize;
+                    row.cfa_offset = data_offset(reader.sleb()?)?;
+                    None
+                }
+                DW_CFA_DEF_CFA_REGISTER => {
+                    row.cfa_reg = reader.uleb()? as usize;
+                    None
+                }
+                DW_CFA_DEF_CFA_OFFSET => {
+                    row.cfa_offset = reader.uleb()? as i64;
+                    None
+                }
+                DW_CFA_DEF_CFA_OFFSET_SF => {
+                    row.cfa_offset = data_offset(reader.sleb()?)?;
+                    None
+                }
+                DW_CFA_GNU_ARGS_SIZE => {
+                    reader.uleb()?;
+                    None
+                }
+                // DWARF expressions and anything newer
+                _ => return None,
+            },
+        };
+        if let Some(delta) = advance {
+            loc = loc.checked_add(delta.checked_mul(cie.code_align)?)?;
+            if loc > target {
+                return Some(());
+            }
+        }
+    }
+    if row.cfa_reg > REG_SP {
+        return None;
+    }
+    Some(())
+}
+
+/// Return addresses up the stack, found from the unwind tables.
+pub struct Unwinder {
+    tables: Tables<'static>,
+    regs: [u64; NUM_REGS],
+    stack_low: u64,
+    stack_high: u64,
+    // The pc is where execution stopped rather than a return address: the
+    // starting frame, or one resumed from an exception frame
+    exact: bool,
+    done: bool,
+}
+
+/// Unwind from `start`, reading saved registers only from
+/// `[stack_low, stack_high)`.
+pub fn unwind(start: &StartFrame, stack_low: u64, stack_high: u64) -> Unwinder {
+    let tables = kernel_tables();
+    let mut regs = [0u64; NUM_REGS];
+    regs[..=REG_SP].copy_from_slice(&start.regs);
+    regs[REG_PC] = start.pc;
+    Unwinder { tables, regs, stack_low, stack_high, exact: true, done: false }
+}
+
+impl Unwinder {
+    fn read(&self, addr: u64) -> Option<u64> {
+        if addr < self.stack_low || addr.checked_add(8)? > self.stack_high || !addr.is_multiple_of(8) {
+            return None;
+        }
+        Some(unsafe { (addr as *const u64).read() })
+    }
+    
+    fn step(&mut self) -> Option<u64> {
+        // A return address can sit just past the end of a call to a
+        // function that never returns
+        let pc = self.regs[REG_PC];
+        let lookup = if self.exact { pc } else { pc.wrapping_sub(1) };
+        let fde = self.tables.find_fde(lookup)?;
+        let mut initial = Row::new();
+        execute(fde.cie.instructions, &fde.cie, &mut initial, &Row::new(), fde.pc_begin, u64::MAX)?;
+        let mut row = initial;
+        execute(fde.instructions, &fde.cie, &mut row, &initial, fde.pc_begin, lookup)?;
+        
+        // Frames only move up the stack; only a frame that stopped exactly
+        // (a leaf) may share its caller's stack pointer
+        let sp = self.regs[REG_SP];
+        let cfa = self.regs[row.cfa_reg].checked_add_signed(row.cfa_offset)?;
+        if cfa < sp || (cfa == sp && !self.exact) || cfa > self.stack_high {
+            return None;
+        }
+        
+        let ra = fde.cie.ra_reg;
+        if ra >= NUM_REGS || row.rules[ra] == Rule::Undefined {
+            return None;
+        }
+        let mut next = self.regs;
+        for (reg, rule) in row.rules.iter().enumerate() {
+            next[reg] = match *rule {
+                Rule::Undefined | Rule::SameValue => self.regs[reg],
+                Rule::Offset(offset) => self.read(cfa.checked_add_signed(offset)?)?,
+                Rule::ValOffset(offset) => cfa.checked_add_signed(offset)?,
+                Rule::Register(other) => self.regs[other],
+            };
+        }
+        next[REG_PC] = next[ra];
+        next[REG_SP] = cfa;
+        if next[REG_PC] == 0 {
+            return None;
+        }
+        
+        self.regs = next;
+        self.exact = fde.cie.signal;
+        Some(if self.exact { next[REG_PC] + 4 } else { next[REG_PC] })
+    }
+}
+
+impl Iterator for Unwinder {
+    type Item = u64;
+    
+    fn next(&mut self) -> Option<u64> {
+        if self.done {
+            return None;
+        }
+        let next = self.step();
+        self.done = next.is_none();
+        next
+    }
+}
+
+/// Report the size of the embedded tables.
+pub fn init() {
+    let tables = kernel_tables();
+    match tables.table_len() {
+        Some(count) => crate::println!("Unwind: {} FDEs in {} KB of .eh_frame", count, tables.frame_len() / 1024),
+        None => crate::println!("Unwind: No .eh_frame_hdr search table, FDEs are scanned"),
+    }
+}
@@ -0,0 +1,483 @@
+// Submission/completion rings for batched asynchronous syscalls
+//
+// A thread sets up one ring: a header, a submission queue (SQ) it fills
+// and a completion queue (CQ) the kernel fills, all in pages mapped into
+// both the kernel and the thread. One ring_enter consumes any number of
+// submissions and can wait for completions, so a busy service makes one
+// syscall per batch instead of one per operation.
+//
+// Indices are free-running u32s; slot = index & (entries - 1). Each side
+// only writes its own producer or consumer index. A submission is only
+// consumed while the CQ has room for its completion, so the CQ cannot
+// overflow. Receives from an empty port park on the async executor and
+// complete on a later ring_enter.
+//
+// A send flagged SQE_GRANT_MOVE or SQE_GRANT_SHARE passes the pages at
+// `addr`/`len` instead of copying bytes (see ipc::PageGrant). The receive
+// that takes it maps them and gets a GrantDescriptor in its buffer, with
+// CQE_GRANT set in the result.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use crate::interrupts::without_interrupts;
+use crate::ipc::{self, GrantMode, Message, PageGrant, PortId};
+use crate::memory::frame_allocator::{allocate_frames, deallocate_frames, PAGE_SIZE};
+use crate::memory::paging::PageFlags;
+use crate::process::scheduler::{self, block_current, current_thread_id, wake};
+use crate::process::{yield_now, ThreadId};
+use crate::syscall::{fs_errno, EAGAIN, EBUSY, EFAULT, EINVAL, ENOENT, ENOMEM, EPERM};
+use crate::uaccess;
+
+pub const RING_MAX_ENTRIES: u32 = 256;
+// The CQ has room for completions still in flight when the SQ refills
+const CQ_PER_SQ: u32 = 2;
+const SQ_OFFSET: usize = 64;
+
+// Operation codes
+pub const OP_NOP: u8 = 0;
+/// Read from VFS file `path` at `offset` into `addr`/`len`.
+pub const OP_READ: u8 = 1;
+/// Write `addr`/`len` to VFS file `path` at `offset`.
+pub const OP_WRITE: u8 = 2;
+/// Send `addr`/`len` as a message on `port`.
+pub const OP_SEND: u8 = 3;
+/// Receive a message from `port` into `addr`/`len`, waiting if none.
+pub const OP_RECV: u8 = 4;
+
+// Submission flags for OP_SEND
+/// Move the pages at `addr`/`len` to the receiver.
+pub const SQE_GRANT_MOVE: u8 = 1 << 0;
+/// Share the pages at `addr`/`len` with the receiver, read-only.
+pub const SQE_GRANT_SHARE: u8 = 1 << 1;
+
+/// Set in a receive's result when the message was a page grant.
+pub const CQE_GRANT: i64 = 1 << 62;
+
+// Longest path a submission may name
+const PATH_MAX: usize = 256;
+// File reads and writes are copied through the kernel this much at a time
+const IO_CHUNK: usize = 512;
+
+/// Start of the shared region. `sq_offset` and `cq_offset` are from the
+/// start of the region.
+#[repr(C)]
+pub struct RingHeader {
+    pub sq_head: AtomicU32,   // Kernel consumes
+    pub sq_tail: AtomicU32,   // Thread produces
+    pub cq_head: AtomicU32,   // Thread consumes
+    pub cq_tail: AtomicU32,   // Kernel produces
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    pub sq_offset: u32,
+    pub cq_offset: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SubmissionEntry {
+    pub opcode: u8,
+    pub flags: u8,
+    pub reserved: u16,
+    pub port: u32,
+    pub path: u64,
+    pub path_len: u32,
+    pub len: u32,
+    pub addr: u64,
+    pub offset: u64,
+    pub user_data: u64,
+}
+
+/// Written to a receive buffer in place of the bytes of a page grant.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GrantDescriptor {
+    pub addr: u64,
+    pub len: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CompletionEntry {
+    pub user_data: u64,
+    // Bytes transferred, or a negative errno
+    pub result: i64,
+}
+
+pub struct IoRing {
+    // Kernel address of the shared pages
+    base: NonNull<u8>,
+    pages: usize,
+    sq_entries: u32,
+    cq_entries: u32,
+    cq_offset: usize,
+    owner: ThreadId,
+    // Parked receives not yet completed
+    in_flight: AtomicU32,
+    parked_ports: Mutex<Vec<PortId>>,
+    // Messages for parked receives, posted by the owner
+    arrived: Mutex<Vec<(SubmissionEntry, Message)>>,
+}
+
+// The shared pages are only touched through atomics and volatile copies
+unsafe impl Send for IoRing {}
+unsafe impl Sync for IoRing {}
+
+impl Drop for IoRing {
+    fn drop(&mut self) {
+        // The user mapping holds its own references
+        deallocate_frames(self.base, self.pages);
+    }
+}
+
+impl IoRing {
+    fn new(entries: u32, owner: ThreadId) -> Result<Self, i64> {
+        if !entries.is_power_of_two() || entries > RING_MAX_ENTRIES {
+            return Err(EINVAL);
+        }
+        let cq_entries = entries * CQ_PER_SQ;
+        let cq_offset = (SQ_OFFSET + entries as usize * size_of::<SubmissionEntry>())
+            .next_multiple_of(size_of::<CompletionEntry>());
+        let len = cq_offset + cq_entries as usize * size_of::<CompletionEntry>();
+        let pages = len.div_ceil(PAGE_SIZE);
+        let base = allocate_frames(pages).ok_or(ENOMEM)?;
+        unsafe {
+            core::ptr::write_bytes(base.as_ptr(), 0, pages * PAGE_SIZE);
+            base.as_ptr().cast::<RingHeader>().write(RingHeader {
+                sq_head: AtomicU32::new(0),
+                sq_tail: AtomicU32::new(0),
+                cq_head: AtomicU32::new(0),
+                cq_tail: AtomicU32::new(0),
+                sq_entries: entries,
+                cq_entries,
+                sq_offset: SQ_OFFSET as u32,
+                cq_offset: cq_offset as u32,
+            });
+        }
+        Ok(Self {
+            base,
+            pages,
+            sq_entries: entries,
+            cq_entries,
+            cq_offset,
+            owner,
+            in_flight: AtomicU32::new(0),
+            parked_ports: Mutex::new(Vec::new()),
+            arrived: Mutex::new(Vec::new()),
+        })
+    }
+    
+    fn header(&self) -> &RingHeader {
+        unsafe { &*self.base.as_ptr().cast::<RingHeader>() }
+    }
+    
+    // Completions posted and not yet consumed
+    fn cq_pending(&self) -> u32 {
+        let header = self.header();
+        header.cq_tail.load(Ordering::Relaxed).wrapping_sub(header.cq_head.load(Ordering::Acquire))
+    }
+    
+    fn post(&self, user_data: u64, result: i64) {
+        let header = self.header();
+        let tail = header.cq_tail.load(Ordering::Relaxed);
+        let slot = (tail & (self.cq_entries - 1)) as usize;
+        unsafe {
+            let cqes = self.base.as_ptr().add(self.cq_offset).cast::<CompletionEntry>();
+            cqes.add(slot).write_volatile(CompletionEntry { user_data, result });
+        }
+        header.cq_tail.store(tail.wrapping_add(1), Ordering::Release);
+    }
+    
+    // Give a received message to its receive: copy the bytes, or map the
+    // pages of a grant and describe them
+    fn deliver(&self, sqe: &SubmissionEntry, mut message: Message, privileged: bool) -> i64 {
+        let Some(grant) = message.grant.take() else {
+            return copy_message(sqe, &message, privileged);
+        };
+        let size = size_of::<GrantDescriptor>();
+        if (sqe.len as usize) < size {
+            return EINVAL;
+        }
+        if !uaccess::access_ok(privileged, sqe.addr, size, true) {
+            return EFAULT;
+        }
+        let len = (grant.pages() * PAGE_SIZE) as u64;
+        let mapped = scheduler::with_thread(self.owner, |thread| {
+            thread.address_space().map(|space| grant.map_into(space))
+        });
+        match mapped {
+            Some(Some(Ok(addr))) => {
+                let mut descriptor = [0; 16];
+                descriptor[..8].copy_from_slice(&addr.to_le_bytes());
+                descriptor[8..].copy_from_slice(&len.to_le_bytes());
+                match uaccess::copy_to_user(privileged, sqe.addr, &descriptor) {
+                    Ok(()) => size as i64 | CQE_GRANT,
+                    Err(_) => EFAULT,
+                }
+            }
+            Some(Some(Err(_))) => ENOMEM,
+            _ => EINVAL,
+        }
+    }
+    
+    // Copy messages for parked receives out to their buffers
+    fn complete_arrived(&self, privileged: bool) {
+        let arrived = core::mem::take(&mut *self.arrived.lock());
+        for (sqe, message) in arrived {
+            self.parked_ports.lock().retain(|&port| port != sqe.port);
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let result = self.deliver(&sqe, message, privileged);
+            self.post(sqe.user_data, result);
+        }
+    }
+    
+    // Room for one more completion, counting parked receives
+    fn cq_has_room(&self) -> bool {
+        self.cq_pending() + self.in_flight.load(Ordering::SeqCst) < self.cq_entries
+    }
+    
+    fn submit(self: &Arc<Self>, sqe: &SubmissionEntry, privileged: bool) {
+        let result = match sqe.opcode {
+            OP_NOP => 0,
+            OP_READ => match sqe_path(sqe, privileged) {
+                Some(path) => read_file(&path, sqe, privileged),
+                None => EFAULT,
+            },
+            OP_WRITE => match sqe_path(sqe, privileged) {
+                Some(path) => write_file(&path, sqe, privileged),
+                None => EFAULT,
+            },
+            OP_SEND => self.send(sqe, privileged),
+            OP_RECV => match self.recv(sqe, privileged) {
+                Some(result) => result,
+                // Completes when a message arrives
+                None => return,
+            },
+            _ => EINVAL,
+        };
+        self.post(sqe.user_data, result);
+    }
+    
+    fn send(&self, sqe: &SubmissionEntry, privileged: bool) -> i64 {
+        let Some(port) = ipc::lookup_port_for(sqe.port, self.owner, privileged) else { return EPERM };
+        match sqe.flags {
+            0 => {}
+            SQE_GRANT_MOVE => return self.send_grant(&port, sqe, GrantMode::Move),
+            SQE_GRANT_SHARE => return self.send_grant(&port, sqe, GrantMode::Share),
+            _ => return EINVAL,
+        }
+        if sqe.len as usize > ipc::MESSAGE_MAX {
+            return EINVAL;
+        }
+        let Ok(data) = uaccess::read_user(privileged, sqe.addr, sqe.len as usize) else { return EFAULT };
+        let message = Message {
+            sender: self.owner,
+            data,
+            trace_id: crate::trace::TRACE_ID_NONE,
+            grant: None,
+            reply: None,
+        };
+        match port.send_message(message) {
+            Ok(()) => sqe.len as i64,
+            // The single-slot port is still full
+            Err(_) => EAGAIN,
+        }
+    }
+    
+    fn send_grant(&self, port: &ipc::Port, sqe: &SubmissionEntry, mode: GrantMode) -> i64 {
+        let pages = (sqe.len as usize).div_ceil(PAGE_SIZE);
+        let taken = scheduler::with_thread(self.owner, |thread| {
+            thread.address_space().map(|space| PageGrant::take(space, sqe.addr, pages, mode))
+        });
+        let grant = match taken {
+            Some(Some(Ok(grant))) => grant,
+            Some(Some(Err("Page is shared or read-only"))) => return EBUSY,
+            Some(Some(Err(_))) => return EFAULT,
+            // Kernel threads have no pages to grant
+            _ => return EINVAL,
+        };
+        let message = Message {
+            sender: self.owner,
+            data: Vec::new(),
+            trace_id: crate::trace::TRACE_ID_NONE,
+            grant: Some(grant),
+            reply: None,
+        };
+        let Err(mut message) = port.send_or_return(message) else {
+            return (pages * PAGE_SIZE) as i64;
+        };
+        // Moved pages go back where they were
+        if let Some(grant) = message.grant.take() {
+            scheduler::with_thread(self.owner, |thread| {
+                thread.address_space().map(|space| grant.restore(space, sqe.addr))
+            });
+        }
+        EAGAIN
+    }
+    
+    // None when the receive was parked
+    fn recv(self: &Arc<Self>, sqe: &SubmissionEntry, privileged: bool) -> Option<i64> {
+        let Some(port) = ipc::lookup_port_for(sqe.port, self.owner, privileged) else { return Some(EPERM) };
+        if let Some(message) = port.receive_message() {
+            return Some(self.deliver(sqe, message, privileged));
+        }
+        
+        // A port wakes one async waiter, so one parked receive per port
+        {
+            let mut parked = self.parked_ports.lock();
+            if parked.contains(&sqe.port) {
+                return Some(EBUSY);
+            }
+            parked.push(sqe.port);
+        }
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let ring = self.clone();
+        let sqe = *sqe;
+        let spawned = crate::executor::spawn("ring-recv", async move {
+            let message = port.receive_async().await;
+            ring.arrived.lock().push((sqe, message));
+            wake(ring.owner);
+        });
+        if spawned.is_err() {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.parked_ports.lock().retain(|&port| port != sqe.port);
+            return Some(EAGAIN);
+        }
+        None
+    }
+}
+
+fn sqe_path(sqe: &SubmissionEntry, privileged: bool) -> Option<String> {
+    if sqe.path_len as usize > PATH_MAX {
+        return None;
+    }
+    let bytes = uaccess::read_user(privileged, sqe.path, sqe.path_len as usize).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+// The submission's file read, into its buffer
+fn read_file(path: &str, sqe: &SubmissionEntry, privileged: bool) -> i64 {
+    let len = sqe.len as usize;
+    if !uaccess::access_ok(privileged, sqe.addr, len, true) {
+        return EFAULT;
+    }
+    let mut chunk = [0; IO_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let want = (len - done).min(IO_CHUNK);
+        let n = match crate::vfs::read(path, sqe.offset + done as u64, &mut chunk[..want]) {
+            Ok(n) => n,
+            Err(e) if done == 0 => return fs_errno(e),
+            Err(_) => break,
+        };
+        if uaccess::copy_to_user(privileged, sqe.addr + done as u64, &chunk[..n]).is_err() {
+            return EFAULT;
+        }
+        done += n;
+        if n < want {
+            break;
+        }
+    }
+    done as i64
+}
+
+fn write_file(path: &str, sqe: &SubmissionEntry, privileged: bool) -> i64 {
+    let len = sqe.len as usize;
+    let mut chunk = [0; IO_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let want = (len - done).min(IO_CHUNK);
+        if uaccess::copy_from_user(privileged, &mut chunk[..want], sqe.addr + done as u64).is_err() {
+            return EFAULT;
+        }
+        let n = match crate::vfs::write(path, sqe.offset + done as u64, &chunk[..want]) {
+            Ok(n) => n,
+            Err(e) if done == 0 => return fs_errno(e),
+            Err(_) => break,
+        };
+        done += n;
+        if n < want {
+            break;
+        }
+    }
+    done as i64
+}
+
+fn copy_message(sqe: &SubmissionEntry, message: &Message, privileged: bool) -> i64 {
+    let len = message.data.len().min(sqe.len as usize);
+    match uaccess::copy_to_user(privileged, sqe.addr, &message.data[..len]) {
+        Ok(()) => len as i64,
+        Err(_) => EFAULT,
+    }
+}
+
+/// Give the calling thread a ring with `entries` submission slots.
+///
+/// Returns the ring's address as the caller sees it: mapped into user
+/// threads' address spaces, the kernel's own address for kernel threads.
+pub fn setup(entries: u32) -> Result<u64, i64> {
+    let tid = current_thread_id();
+    let ring = Arc::new(IoRing::new(entries, tid)?);
+    let flags = PageFlags::NORMAL_MEMORY | PageFlags::INNER_SHAREABLE | PageFlags::ACCESSED
+        | PageFlags::USER | PageFlags::PXN | PageFlags::UXN;
+    let installed = scheduler::with_thread(tid, |thread| {
+        if thread.io_ring.is_some() {
+            return Err(EBUSY);
+        }
+        let addr = match thread.address_space() {
+            Some(space) => space.map_shared(ring.base, ring.pages, flags).map_err(|_| ENOMEM)?,
+            None => ring.base.as_ptr() as u64,
+        };
+        thread.io_ring = Some(ring.clone());
+        Ok(addr)
+    });
+    installed.unwrap_or(Err(ENOENT))
+}
+
+/// Consume up to `to_submit` submissions, then wait until at least
+/// `min_complete` completions are posted or nothing is left in flight.
+/// Returns how many submissions were consumed.
+pub fn enter(to_submit: u32, min_complete: u32, privileged: bool) -> Result<u32, i64> {
+    let tid = current_thread_id();
+    let ring = scheduler::with_thread(tid, |thread| thread.io_ring.clone())
+        .flatten()
+        .ok_or(EINVAL)?;
+    ring.complete_arrived(privileged);
+    
+    let header = ring.header();
+    let mut submitted = 0;
+    while submitted < to_submit && ring.cq_has_room() {
+        let head = header.sq_head.load(Ordering::Relaxed);
+        if head == header.sq_tail.load(Ordering::Acquire) {
+            break;
+        }
+        // Copied once, so later writes by the thread cannot change it
+        let slot = (head & (ring.sq_entries - 1)) as usize;
+        let sqe = unsafe {
+            let sqes = ring.base.as_ptr().add(SQ_OFFSET).cast::<SubmissionEntry>();
+            sqes.add(slot).read_volatile()
+        };
+        header.sq_head.store(head.wrapping_add(1), Ordering::Release);
+        ring.submit(&sqe, privileged);
+        submitted += 1;
+    }
+    
+    let wanted = min_complete.min(ring.cq_entries);
+    loop {
+        ring.complete_arrived(privileged);
+        if ring.cq_pending() >= wanted || ring.in_flight.load(Ordering::SeqCst) == 0 {
+            break;
+        }
+        without_interrupts(|| {
+            if ring.arrived.lock().is_empty() {
+                block_current();
+            }
+        });
+        yield_now();
+    }
+    Ok(submitted)
+}
@@ -0,0 +1,270 @@
+// Virtual filesystem: mount table and path-based file operations
+//
+// Filesystems see paths relative to their mount point with no leading
+// slash ("" is their root). A path resolves to the longest mount point
+// that is a prefix of it.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const READ_ONLY: &str = "Read-only file system";
+
+// Buffer growth step of read_all for files that report no size
+const READ_ALL_CHUNK: usize = 512;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Metadata {
+    pub kind: NodeKind,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: NodeKind,
+    pub size: u64,
+}
+
+/// A mounted filesystem. Anything that cannot change only implements the
+/// read side.
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn stat(&self, path: &str) -> Result<Metadata, &'static str>;
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str>;
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, &'static str>;
+    
+    /// Write at `offset`, growing the file as needed.
+    fn write(&self, _path: &str, _offset: u64, _buf: &[u8]) -> Result<usize, &'static str> {
+        Err(READ_ONLY)
+    }
+    
+    /// Create an empty file.
+    fn create(&self, _path: &str) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+    
+    fn mkdir(&self, _path: &str) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+    
+    /// Remove a file or an empty directory.
+    fn unlink(&self, _path: &str) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+    
+    fn truncate(&self, _path: &str, _size: u64) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+    
+    /// Push cached changes to the backing store.
+    fn sync(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+pub fn init() {
+    crate::println!("VFS: Mount table ready");
+    crate::tmpfs::init();
+    let count = crate::fat32::mount_all();
+    if count > 0 {
+        crate::println!("VFS: {} FAT32 volumes mounted", count);
+    }
+}
+
+/// Absolute path with ".", ".." and repeated slashes resolved.
+pub fn normalize(path: &str) -> Result<String, &'static str> {
+    if !path.starts_with('/') {
+        return Err("Path not absolute");
+    }
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    let mut normal = String::new();
+    for component in &components {
+        normal.push('/');
+        normal.push_str(component);
+    }
+    if normal.is_empty() {
+        normal.push('/');
+    }
+    Ok(normal)
+}
+
+// Part of `path` below `mount`, if it is inside it
+fn strip_mount<'a>(path: &'a str, mount: &str) -> Option<&'a str> {
+    if mount == "/" {
+        return Some(path.trim_start_matches('/'));
+    }
+    match path.strip_prefix(mount)? {
+        "" => Some(""),
+        rest => rest.strip_prefix('/'),
+    }
+}
+
+fn resolve(path: &str) -> Result<(Arc<dyn FileSystem>, String), &'static str> {
+    let path = normalize(path)?;
+    let mounts = MOUNTS.lock();
+    mounts
+        .iter()
+        .filter_map(|mount| strip_mount(&path, &mount.path).map(|rest| (mount, rest)))
+        .max_by_key(|(mount, _)| mount.path.len())
+        .map(|(mount, rest)| (mount.fs.clone(), String::from(rest)))
+        .ok_or("No such file or directory")
+}
+
+/// Attach `fs` at `path`; the directory need not exist anywhere.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), &'static str> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err("Mount point busy");
+    }
+    crate::println!("VFS: Mounted {} at {}", fs.name(), path);
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// Detach the filesystem at `path` after syncing it.
+pub fn unmount(path: &str) -> Result<(), &'static str> {
+    let path = normalize(path)?;
+    let fs = {
+        let mut mounts = MOUNTS.lock();
+        let index = mounts
+            .iter()
+            .position(|mount| mount.path == path)
+            .ok_or("Not mounted")?;
+        mounts.remove(index).fs
+    };
+    fs.sync()
+}
+
+/// Mount points and their filesystem names.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS.lock().iter().map(|mount| (mount.path.clone(), mount.fs.name())).collect()
+}
+
+pub fn stat(path: &str) -> Result<Metadata, &'static str> {
+    let (fs, rest) = resolve(path)?;
+    fs.stat(&rest)
+}
+
+pub fn read(path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+    let (fs, rest) = resolve(path)?;
+    fs.read(&rest, offset, buf)
+}
+
+pub fn write(path: &str, offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+    let (fs, rest) = resolve(path)?;
+    fs.write(&rest, offset, buf)
+}
+
+pub fn create(path: &str) -> Result<(), &'static str> {
+    let (fs, rest) = resolve(path)?;
+    fs.create(&rest)
+}
+
+pub fn mkdir(path: &str) -> Result<(), &'static str> {
+    let (fs, rest) = resolve(path)?;
+    fs.mkdir(&rest)
+}
+
+pub fn unlink(path: &str) -> Result<(), &'static str> {
+    let (fs, rest) = resolve(path)?;
+    fs.unlink(&rest)
+}
+
+pub fn truncate(path: &str, size: u64) -> Result<(), &'static str> {
+    let (fs, rest) = resolve(path)?;
+    fs.truncate(&rest, size)
+}
+
+/// Directory listing, with mount points directly below `path` included.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, &'static str> {
+    let normal = normalize(path)?;
+    let listed = resolve(&normal).and_then(|(fs, rest)| fs.read_dir(&rest));
+    let (mut entries, error) = match listed {
+        Ok(entries) => (Some(entries), None),
+        Err(e) => (None, Some(e)),
+    };
+    
+    for (mount, _) in mounts() {
+        let Some(rest) = strip_mount(&mount, &normal).filter(|rest| !rest.is_empty()) else {
+            continue;
+        };
+        let child = rest.split('/').next().unwrap_or(rest);
+        let listing = entries.get_or_insert_with(Vec::new);
+        if !listing.iter().any(|entry| entry.name == child) {
+            listing.push(DirEntry { name: String::from(child), kind: NodeKind::Directory, size: 0 });
+        }
+    }
+    entries.ok_or(error.unwrap_or("No such file or directory"))
+}
+
+/// Whole content of a file. A file of size 0 may be a device that cannot
+/// tell its size up front, so it is read until a read returns nothing.
+pub fn read_all(path: &str) -> Result<Vec<u8>, &'static str> {
+    let (fs, rest) = resolve(path)?;
+    let meta = fs.stat(&rest)?;
+    if meta.kind != NodeKind::File {
+        return Err("Is a directory");
+    }
+    let sizeless = meta.size == 0;
+    let mut data = alloc::vec![0; if sizeless { READ_ALL_CHUNK } else { meta.size as usize }];
+    let mut done = 0;
+    loop {
+        if done == data.len() {
+            if !sizeless {
+                break;
+            }
+            data.resize(done + READ_ALL_CHUNK, 0);
+        }
+        match fs.read(&rest, done as u64, &mut data[done..])? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    data.truncate(done);
+    Ok(data)
+}
+
+/// Replace a file's content, creating it if needed.
+pub fn write_all(path: &str, data: &[u8]) -> Result<(), &'static str> {
+    let (fs, rest) = resolve(path)?;
+    if fs.stat(&rest).is_err() {
+        fs.create(&rest)?;
+    }
+    fs.truncate(&rest, 0)?;
+    let mut done = 0;
+    while done < data.len() {
+        done += fs.write(&rest, done as u64, &data[done..])?;
+    }
+    Ok(())
+}
+
+/// Sync every mounted filesystem.
+pub fn sync_all() -> Result<(), &'static str> {
+    let filesystems: Vec<_> = MOUNTS.lock().iter().map(|mount| mount.fs.clone()).collect();
+    filesystems.iter().try_for_each(|fs| fs.sync())
+}
@@ -0,0 +1,367 @@
+// Lockup detection and the hardware watchdog
+//
+// The software watchdog panics, with the stuck code's registers and
+// backtrace, once a CPU has gone the threshold without passing through
+// the scheduler or the idle loop: a thread spinning where preemption is
+// held off (an RCU read section, a softirq batch) never lets either run.
+// Kernel code with its own notion of progress can also register a task
+// and pet it; one left unpetted past its timeout panics as well. Both are
+// checked on the way out of every interrupt, and a heartbeat timer keeps
+// one arriving while the tick is stopped in idle.
+//
+// Neither catches a CPU wedged with IRQs masked, which takes no
+// interrupts at all. That is left to a hardware watchdog
+// (drivers/sbsa_gwdt.rs): the heartbeat refreshes it only while every
+// CPU and task is making progress, so it resets the machine once the
+// threshold passes without one.
+//
+// The GDB stub freezes the kernel for as long as gdb likes, so it pauses
+// both around a stop. `nowatchdog` at boot, or kernel.watchdog=0, turns
+// them off.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::cpu::{cpu_index, MAX_CPUS};
+use crate::interrupts::{counter_frequency, counter_ticks, ExceptionContext};
+use crate::ktest::kernel_test;
+use crate::sync::IrqSafeMutex;
+
+const MAX_TASKS: usize = 16;
+
+pub const THRESHOLD_DEFAULT_MS: u64 = 10_000;
+// Twice the heartbeat, so an idle CPU is never mistaken for a stuck one
+pub const THRESHOLD_MIN_MS: u64 = 2 * HEARTBEAT_MS;
+pub const THRESHOLD_MAX_MS: u64 = 600_000;
+const HEARTBEAT_MS: u64 = 1000;
+
+/// A hardware watchdog that resets the machine unless refreshed.
+pub trait HardwareWatchdog: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Start counting down from `timeout_ms`, or restart with it if
+    /// running; returns the timeout actually programmed.
+    fn start(&self, timeout_ms: u64) -> u64;
+    fn stop(&self);
+    fn refresh(&self);
+}
+
+/// A registered task, for `pet` and `unregister`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WatchdogTask(u8);
+
+/// What the watchdog found stuck, and for how long.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stall {
+    Cpu { cpu: usize, ms: u64 },
+    Task { name: &'static str, ms: u64 },
+}
+
+#[derive(Copy, Clone)]
+struct Task {
+    name: &'static str,
+    timeout: u64,
+    // Counter value of the last pet
+    petted: u64,
+}
+
+struct Hardware {
+    device: Box<dyn HardwareWatchdog>,
+    timeout_ms: u64,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static THRESHOLD_MS: AtomicU64 = AtomicU64::new(THRESHOLD_DEFAULT_MS);
+// Counter value of each CPU's last pass through the scheduler or idle
+// loop; 0 for a CPU never seen there
+static PROGRESS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static TASKS: IrqSafeMutex<[Option<Task>; MAX_TASKS]> = IrqSafeMutex::new([None; MAX_TASKS]);
+static HARDWARE: IrqSafeMutex<Option<Hardware>> = IrqSafeMutex::new(None);
+static REFRESHES: AtomicU64 = AtomicU64::new(0);
+
+fn ms_to_counter(ms: u64) -> u64 {
+    counter_frequency() * ms / 1000
+}
+
+fn counter_to_ms(ticks: u64) -> u64 {
+    ticks * 1000 / counter_frequency().max(1)
+}
+
+/// Record that this CPU has made scheduling progress. Called from the
+/// scheduler and the idle loop.
+pub fn touch() {
+    PROGRESS[cpu_index()].store(counter_ticks(), Ordering::Relaxed);
+}
+
+// Restart every clock: after a pause, or on being switched on
+fn touch_all() {
+    let now = counter_ticks();
+    for progress in PROGRESS.iter().filter(|progress| progress.load(Ordering::Relaxed) != 0) {
+        progress.store(now, Ordering::Relaxed);
+    }
+    PROGRESS[cpu_index()].store(now, Ordering::Relaxed);
+    for task in TASKS.lock().iter_mut().flatten() {
+        task.petted = now;
+    }
+}
+
+/// Watch a task that must call `pet` at least every `timeout_ms`.
+pub fn register(name: &'static str, timeout_ms: u64) -> Result<WatchdogTask, &'static str> {
+    if timeout_ms == 0 {
+        return Err("Watchdog: Timeout must be nonzero");
+    }
+    let mut tasks = TASKS.lock();
+    if tasks.iter().flatten().any(|task| task.name == name) {
+        return Err("Watchdog: Name already registered");
+    }
+    let index = tasks.iter().position(|task| task.is_none()).ok_or("Watchdog: No free task slot")?;
+    tasks[index] = Some(Task { name, timeout: ms_to_counter(timeout_ms), petted: counter_ticks() });
+    Ok(WatchdogTask(index as u8))
+}
+
+pub fn unregister(task: WatchdogTask) {
+    TASKS.lock()[task.0 as usize] = None;
+}
+
+/// Report progress on `task`.
+pub fn pet(task: WatchdogTask) {
+    if let Some(task) = TASKS.lock()[task.0 as usize].as_mut() {
+        task.petted = counter_ticks();
+    }
+}
+
+/// What would count as stuck at counter value `now`: this CPU first, then
+/// the first overdue task.
+pub fn stall_at(now: u64) -> Option<Stall> {
+    let cpu = cpu_index();
+    let since = now.saturating_sub(PROGRESS[cpu].load(Ordering::Relaxed));
+    if since > ms_to_counter(THRESHOLD_MS.load(Ordering::Relaxed)) {
+        return Some(Stall::Cpu { cpu, ms: counter_to_ms(since) });
+    }
+    TASKS.lock().iter().flatten()
+        .find(|task| now.saturating_sub(task.petted) > task.timeout)
+        .map(|task| Stall::Task { name: task.name, ms: counter_to_ms(now - task.petted) })
+}
+
+// Whether any CPU seen so far has gone the threshold without progress;
+// unlike stall_at this looks at the others too, which may be the ones
+// with IRQs masked
+fn any_cpu_stuck(now: u64) -> bool {
+    let threshold = ms_to_counter(THRESHOLD_MS.load(Ordering::Relaxed));
+    PROGRESS.iter()
+        .map(|progress| progress.load(Ordering::Relaxed))
+        .any(|last| last != 0 && now.saturating_sub(last) > threshold)
+}
+
+fn active() -> bool {
+    ENABLED.load(Ordering::Relaxed) && !PAUSED.load(Ordering::Relaxed)
+}
+
+/// Called on the way out of every interrupt with the interrupted context;
+/// panics if anything is stuck.
+pub fn check(ctx: &ExceptionContext) {
+    if !active() {
+        return;
+    }
+    let Some(stall) = stall_at(counter_ticks()) else {
+        return;
+    };
+    // One report; the panic path takes interrupts of its own
+    ENABLED.store(false, Ordering::Relaxed);
+    match stall {
+        Stall::Cpu { cpu, ms } => crate::panic::exception_panic(ctx, format_args!(
+            "Watchdog: CPU {} made no scheduling progress for {}ms", cpu, ms)),
+        Stall::Task { name, ms } => panic!("Watchdog: Task {} made no progress for {}ms", name, ms),
+    }
+}
+
+// Timer callback: keeps an interrupt, and so a check, arriving while the
+// tick is stopped, and refreshes the hardware while all is well
+fn heartbeat(_arg: usize) {
+    let now = counter_ticks();
+    if !active() || stall_at(now).is_some() || any_cpu_stuck(now) {
+        return;
+    }
+    if let Some(hardware) = HARDWARE.lock().as_ref() {
+        hardware.device.refresh();
+        REFRESHES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Hand the watchdog a hardware device; it is started with the threshold
+/// as its timeout if the watchdog is on.
+pub fn register_hardware(device: Box<dyn HardwareWatchdog>) -> Result<(), &'static str> {
+    let mut hardware = HARDWARE.lock();
+    if hardware.is_some() {
+        return Err("Watchdog: Hardware watchdog already registered");
+    }
+    let mut timeout_ms = 0;
+    if active() {
+        timeout_ms = device.start(THRESHOLD_MS.load(Ordering::Relaxed));
+        crate::println!("Watchdog: {} started, {}ms timeout", device.name(), timeout_ms);
+    } else {
+        // Firmware may have left it running, and nothing would refresh it
+        device.stop();
+    }
+    *hardware = Some(Hardware { device, timeout_ms });
+    Ok(())
+}
+
+// Start or stop the hardware to match the software watchdog
+fn sync_hardware() {
+    let threshold = THRESHOLD_MS.load(Ordering::Relaxed);
+    if let Some(hardware) = HARDWARE.lock().as_mut() {
+        if active() {
+            hardware.timeout_ms = hardware.device.start(threshold);
+        } else {
+            hardware.device.stop();
+            hardware.timeout_ms = 0;
+        }
+    }
+}
+
+/// Name of the hardware watchdog, its timeout (0 while stopped) and how
+/// often it has been refreshed.
+pub fn hardware() -> Option<(&'static str, u64, u64)> {
+    HARDWARE.lock().as_ref().map(|hardware| {
+        (hardware.device.name(), hardware.timeout_ms, REFRESHES.load(Ordering::Relaxed))
+    })
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    if enabled {
+        touch_all();
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+    sync_hardware();
+}
+
+pub fn threshold_ms() -> u64 {
+    THRESHOLD_MS.load(Ordering::Relaxed)
+}
+
+pub fn set_threshold_ms(ms: u64) -> Result<(), &'static str> {
+    if !(THRESHOLD_MIN_MS..=THRESHOLD_MAX_MS).contains(&ms) {
+        return Err("Watchdog: Threshold out of range");
+    }
+    THRESHOLD_MS.store(ms, Ordering::Relaxed);
+    sync_hardware();
+    Ok(())
+}
+
+/// Stop both watchdogs while the kernel is deliberately frozen.
+pub fn pause() {
+    PAUSED.store(true, Ordering::Relaxed);
+    sync_hardware();
+}
+
+/// Undo `pause`, as if everything had just made progress.
+pub fn resume() {
+    touch_all();
+    PAUSED.store(false, Ordering::Relaxed);
+    sync_hardware();
+}
+
+/// Start the heartbeat and export /proc/watchdog. Hardware registered
+/// later is started as it arrives.
+pub fn init() {
+    if crate::bootparams::flag("nowatchdog") {
+        crate::println!("Watchdog: Disabled (nowatchdog)");
+    } else {
+        touch_all();
+        ENABLED.store(true, Ordering::Relaxed);
+        crate::println!("Watchdog: Lockup detection on, {}ms threshold", threshold_ms());
+    }
+    if let Err(e) = crate::timer::every_ms(HEARTBEAT_MS, heartbeat, 0) {
+        crate::println!("Watchdog: No heartbeat timer: {}", e);
+    }
+    let _ = crate::procfs::register("watchdog", proc_watchdog);
+}
+
+fn proc_watchdog(out: &mut Vec<u8>) {
+    let now = counter_ticks();
+    let mut text = alloc::string::String::new();
+    let _ = writeln!(text, "enabled {}{}", is_enabled() as u8, if PAUSED.load(Ordering::Relaxed) { " (paused)" } else { "" });
+    let _ = writeln!(text, "threshold_ms {}", threshold_ms());
+    match hardware() {
+        Some((name, timeout_ms, refreshes)) => {
+            let _ = writeln!(text, "hardware {} timeout_ms {} refreshes {}", name, timeout_ms, refreshes);
+        }
+        None => {
+            let _ = writeln!(text, "hardware none");
+        }
+    }
+    for (cpu, progress) in PROGRESS.iter().enumerate() {
+        let last = progress.load(Ordering::Relaxed);
+        if last != 0 {
+            let _ = writeln!(text, "cpu{} last progress {}ms ago", cpu, counter_to_ms(now.saturating_sub(last)));
+        }
+    }
+    for task in TASKS.lock().iter().flatten() {
+        let _ = writeln!(text, "task {:<12} timeout {}ms, petted {}ms ago", task.name,
+                         counter_to_ms(task.timeout), counter_to_ms(now.saturating_sub(task.petted)));
+    }
+    out.extend_from_slice(text.as_bytes());
+}
+
+#[kernel_test]
+fn test_watchdog() {
+    use crate::interrupts::{counter_frequency, counter_ticks};
+    
+    crate::println!("Watchdog Test: Testing the watchdog...");
+    
+    // Yielding passes through the scheduler, so nothing is stuck now, but
+    // this CPU would be once the threshold had gone by
+    crate::process::yield_now();
+    let now = counter_ticks();
+    let threshold = counter_frequency() * (threshold_ms() + 100) / 1000;
+    match (stall_at(now), stall_at(now + threshold)) {
+        (None, Some(Stall::Cpu { ms, .. })) if ms > threshold_ms() => {
+            crate::println!("Watchdog Test: ✓ Scheduler progress seen, a stall past the threshold caught");
+        }
+        other => crate::println!("Watchdog Test: ✗ Scheduler progress check: {:?}", other),
+    }
+    
+    // A task is overdue only once its timeout passes unpetted
+    let ms = |ms: u64| counter_frequency() * ms / 1000;
+    match register("wdtest", 50) {
+        Ok(task) => {
+            let duplicate = register("wdtest", 50).is_err();
+            pet(task);
+            let now = counter_ticks();
+            let early = stall_at(now + ms(40));
+            let late = stall_at(now + ms(60));
+            unregister(task);
+            let gone = stall_at(now + ms(60));
+            if duplicate && early.is_none() && matches!(late, Some(Stall::Task { name: "wdtest", .. })) && gone.is_none() {
+                crate::println!("Watchdog Test: ✓ Petted task overdue only after its 50ms timeout");
+            } else {
+                crate::println!("Watchdog Test: ✗ Task check: duplicate {} early {:?} late {:?} gone {:?}",
+                               duplicate, early, late, gone);
+            }
+        }
+        Err(e) => crate::println!("Watchdog Test: ✗ Watchdog task registration failed: {}", e),
+    }
+    
+    // The heartbeat refreshes hardware while all is well
+    match hardware() {
+        Some((name, timeout_ms, before)) if is_enabled() => {
+            let _ = crate::timer::sleep_ms(1100);
+            let after = hardware().map_or(0, |(_, _, refreshes)| refreshes);
+            if timeout_ms > 0 && after > before {
+                crate::println!("Watchdog Test: ✓ {} running with a {}ms timeout and refreshed", name, timeout_ms);
+            } else {
+                crate::println!("Watchdog Test: ✗ {} timeout {}ms, refreshes {} -> {}", name, timeout_ms, before, after);
+            }
+        }
+        Some((name, _, _)) => crate::println!("Watchdog Test: {} present, watchdog disabled", name),
+        None => crate::println!("Watchdog Test: No hardware watchdog (none on QEMU virt)"),
+    }
+    
+    crate::println!("Watchdog Test: Watchdog test completed");
+}
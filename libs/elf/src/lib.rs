@@ -0,0 +1,219 @@
+#![no_std]
+
+// ELF64 executable parsing that never trusts the file
+//
+// Only what a loader needs: the file header and the program headers,
+// for little-endian AArch64 executables (ET_EXEC) and position-independent
+// ones (ET_DYN). `Elf::parse` checks every header field and every segment's
+// file range up front, so the accessors afterwards cannot fail. Section
+// headers are never looked at.
+
+use core::fmt;
+
+pub const EM_AARCH64: u16 = 183;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+const EHDR_LEN: usize = 64;
+const PHDR_LEN: usize = 56;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+/// More program headers than this is treated as a malformed file.
+pub const MAX_PROGRAM_HEADERS: usize = 64;
+
+/// Why a file was rejected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    Unsupported,
+    WrongMachine,
+    NotExecutable,
+    BadHeaderSize,
+    TooManySegments,
+    ProgramHeadersOutOfFile,
+    SegmentOutOfFile,
+    FileSizeExceedsMemSize,
+    AddressOverflow,
+    BadAlignment,
+    NoLoadableSegment,
+}
+
+impl ElfError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Truncated => "ELF: File truncated",
+            Self::BadMagic => "ELF: Bad magic",
+            Self::Unsupported => "ELF: Not a little-endian ELF64 version 1 file",
+            Self::WrongMachine => "ELF: Not an AArch64 binary",
+            Self::NotExecutable => "ELF: Not an executable",
+            Self::BadHeaderSize => "ELF: Unexpected header size",
+            Self::TooManySegments => "ELF: Too many program headers",
+            Self::ProgramHeadersOutOfFile => "ELF: Program headers outside the file",
+            Self::SegmentOutOfFile => "ELF: Segment data outside the file",
+            Self::FileSizeExceedsMemSize => "ELF: Segment file size exceeds memory size",
+            Self::AddressOverflow => "ELF: Segment address range overflows",
+            Self::BadAlignment => "ELF: Segment alignment invalid",
+            Self::NoLoadableSegment => "ELF: No loadable segment",
+        }
+    }
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// One program header.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    fn parse(raw: &[u8]) -> Self {
+        Self {
+            p_type: read_u32(raw, 0),
+            flags: read_u32(raw, 4),
+            offset: read_u64(raw, 8),
+            vaddr: read_u64(raw, 16),
+            filesz: read_u64(raw, 32),
+            memsz: read_u64(raw, 40),
+            align: read_u64(raw, 48),
+        }
+    }
+    
+    /// One past the last byte of the segment in memory (checked at parse).
+    pub fn vaddr_end(&self) -> u64 {
+        self.vaddr + self.memsz
+    }
+    
+    // File range in bounds, sizes consistent, alignment a power of two
+    // congruent between file and memory
+    fn check(&self, file_len: usize) -> Result<(), ElfError> {
+        let file_end = self.offset.checked_add(self.filesz).ok_or(ElfError::SegmentOutOfFile)?;
+        if file_end > file_len as u64 {
+            return Err(ElfError::SegmentOutOfFile);
+        }
+        if self.filesz > self.memsz {
+            return Err(ElfError::FileSizeExceedsMemSize);
+        }
+        if self.vaddr.checked_add(self.memsz).is_none() {
+            return Err(ElfError::AddressOverflow);
+        }
+        if self.align > 1 && (!self.align.is_power_of_two() || self.vaddr % self.align != self.offset % self.align) {
+            return Err(ElfError::BadAlignment);
+        }
+        Ok(())
+    }
+}
+
+/// A validated ELF64 AArch64 executable.
+#[derive(Copy, Clone, Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub kind: u16,
+    pub entry: u64,
+    phoff: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Check the file header and every program header.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < EHDR_LEN {
+            return Err(ElfError::Truncated);
+        }
+        if data[..4] != *b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT
+            || read_u32(data, 20) != EV_CURRENT as u32
+        {
+            return Err(ElfError::Unsupported);
+        }
+        let kind = read_u16(data, 16);
+        if read_u16(data, 18) != EM_AARCH64 {
+            return Err(ElfError::WrongMachine);
+        }
+        if kind != ET_EXEC && kind != ET_DYN {
+            return Err(ElfError::NotExecutable);
+        }
+        
+        let phoff = read_u64(data, 32);
+        let phnum = read_u16(data, 56) as usize;
+        if read_u16(data, 52) as usize != EHDR_LEN || (phnum > 0 && read_u16(data, 54) as usize != PHDR_LEN) {
+            return Err(ElfError::BadHeaderSize);
+        }
+        if phnum > MAX_PROGRAM_HEADERS {
+            return Err(ElfError::TooManySegments);
+        }
+        let table_end = phoff.checked_add((phnum * PHDR_LEN) as u64);
+        if table_end.is_none_or(|end| end > data.len() as u64) {
+            return Err(ElfError::ProgramHeadersOutOfFile);
+        }
+        
+        let elf = Self { data, kind, entry: read_u64(data, 24), phoff: phoff as usize, phnum };
+        let mut loadable = false;
+        for header in elf.program_headers() {
+            if header.p_type == PT_LOAD {
+                header.check(data.len())?;
+                loadable = true;
+            }
+        }
+        if !loadable {
+            return Err(ElfError::NoLoadableSegment);
+        }
+        Ok(elf)
+    }
+    
+    /// Position independent: segments may be placed at any base.
+    pub fn is_pie(&self) -> bool {
+        self.kind == ET_DYN
+    }
+    
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let phoff = self.phoff;
+        (0..self.phnum).map(move |i| ProgramHeader::parse(&data[phoff + i * PHDR_LEN..][..PHDR_LEN]))
+    }
+    
+    /// PT_LOAD segments, in file order.
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers().filter(|header| header.p_type == PT_LOAD)
+    }
+    
+    /// File bytes of a segment returned by `load_segments`.
+    pub fn segment_data(&self, header: &ProgramHeader) -> &'a [u8] {
+        &self.data[header.offset as usize..][..header.filesz as usize]
+    }
+}
@@ -0,0 +1,413 @@
+#![no_std]
+
+// Flattened device tree (FDT) parsing that never trusts the blob
+//
+// The kernel's boot-time walker (kernel/src/devicetree.rs) follows raw
+// pointers for speed and runs before the heap exists, so it relies on
+// `validate` having checked the blob once. The unflattened `Node` tree
+// here serves overlays, which need owned, editable nodes.
+//
+// Every offset read from the blob is checked against the block it points
+// into; malformed input is an error, never a panic. fuzz/ drives both
+// entry points on the host.
+
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+pub const FDT_MAGIC: u32 = 0xd00dfeed;
+pub const FDT_BEGIN_NODE: u32 = 0x00000001;
+pub const FDT_END_NODE: u32 = 0x00000002;
+pub const FDT_PROP: u32 = 0x00000003;
+pub const FDT_NOP: u32 = 0x00000004;
+pub const FDT_END: u32 = 0x00000009;
+
+pub const FDT_HEADER_LEN: usize = 40;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+/// Deeper nesting is treated as a corrupt blob, not recursed into.
+pub const MAX_DEPTH: usize = 32;
+
+fn be32(blob: &[u8], offset: usize) -> Result<u32, &'static str> {
+    let bytes = blob.get(offset..offset.checked_add(4).ok_or("FDT: Truncated")?).ok_or("FDT: Truncated")?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn be64(blob: &[u8], offset: usize) -> Result<u64, &'static str> {
+    Ok((be32(blob, offset)? as u64) << 32 | be32(blob, offset.checked_add(4).ok_or("FDT: Truncated")?)? as u64)
+}
+
+fn cstr(bytes: &[u8], offset: usize) -> Result<&str, &'static str> {
+    let tail = bytes.get(offset..).ok_or("FDT: Name out of bounds")?;
+    let len = tail.iter().position(|&b| b == 0).ok_or("FDT: Unterminated name")?;
+    core::str::from_utf8(&tail[..len]).map_err(|_| "FDT: Name not UTF-8")
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// The blocks a header describes, each checked to lie inside the blob.
+#[derive(Clone, Copy, Debug)]
+pub struct Layout<'a> {
+    pub structs: &'a [u8],
+    pub strings: &'a [u8],
+    pub rsvmap: usize,
+    pub boot_cpuid: u32,
+}
+
+/// Total size claimed by a header; needs only the first FDT_HEADER_LEN bytes.
+pub fn total_size(header: &[u8]) -> Result<usize, &'static str> {
+    if header.len() < FDT_HEADER_LEN {
+        return Err("FDT: Truncated header");
+    }
+    if be32(header, 0)? != FDT_MAGIC {
+        return Err("FDT: Bad magic");
+    }
+    let totalsize = be32(header, 4)? as usize;
+    if totalsize < FDT_HEADER_LEN {
+        return Err("FDT: Total size smaller than the header");
+    }
+    Ok(totalsize)
+}
+
+/// Check the header and locate the blocks.
+pub fn layout(blob: &[u8]) -> Result<Layout<'_>, &'static str> {
+    let totalsize = total_size(blob)?;
+    let blob = blob.get(..totalsize).ok_or("FDT: Truncated")?;
+    let field = |index: usize| be32(blob, index * 4).map(|value| value as usize);
+    let (off_struct, off_strings, off_rsvmap, version) = (field(2)?, field(3)?, field(4)?, field(5)?);
+    if version < 16 || field(6)? > FDT_VERSION as usize {
+        return Err("FDT: Unsupported version");
+    }
+    // Block sizes arrived in version 17; before that the blocks run to the end
+    let (size_strings, size_struct) = if version >= 17 {
+        (field(8)?, field(9)?)
+    } else {
+        (totalsize.saturating_sub(off_strings), totalsize.saturating_sub(off_struct))
+    };
+    if off_struct % 4 != 0 || off_rsvmap % 8 != 0 {
+        return Err("FDT: Misaligned block");
+    }
+    let block = |offset: usize, size: usize| blob.get(offset..offset.checked_add(size)?);
+    Ok(Layout {
+        structs: block(off_struct, size_struct).ok_or("FDT: Struct block out of bounds")?,
+        strings: block(off_strings, size_strings).ok_or("FDT: Strings block out of bounds")?,
+        rsvmap: off_rsvmap,
+        boot_cpuid: field(7)? as u32,
+    })
+}
+
+/// Memory reservation entries (address, size).
+pub fn reserved(blob: &[u8]) -> Result<Vec<(u64, u64)>, &'static str> {
+    let layout = layout(blob)?;
+    let blob = &blob[..total_size(blob)?];
+    let mut entries = Vec::new();
+    let mut entry = layout.rsvmap;
+    loop {
+        let (addr, size) = (be64(blob, entry)?, be64(blob, entry + 8)?);
+        if addr == 0 && size == 0 {
+            return Ok(entries);
+        }
+        entries.push((addr, size));
+        entry += 16;
+    }
+}
+
+/// Walk the whole structure block without allocating. Once this passes,
+/// pointer-based walkers can trust every length, name offset and token.
+pub fn validate(blob: &[u8]) -> Result<(), &'static str> {
+    let layout = layout(blob)?;
+    let blob = &blob[..total_size(blob)?];
+    // Terminated reservation map inside the blob
+    let mut entry = layout.rsvmap;
+    while be64(blob, entry)? != 0 || be64(blob, entry + 8)? != 0 {
+        entry += 16;
+    }
+    
+    let structs = layout.structs;
+    let mut pos = 0;
+    let mut depth = 0;
+    let mut seen_root = false;
+    loop {
+        let token = be32(structs, pos)?;
+        pos += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                if depth == 0 && seen_root {
+                    return Err("FDT: More than one root node");
+                }
+                if depth == MAX_DEPTH {
+                    return Err("FDT: Nodes nested too deeply");
+                }
+                let name = cstr(structs, pos)?;
+                pos = align4(pos + name.len() + 1);
+                depth += 1;
+                seen_root = true;
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return Err("FDT: Unbalanced end of node");
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                if depth == 0 {
+                    return Err("FDT: Property outside a node");
+                }
+                let len = be32(structs, pos)? as usize;
+                cstr(layout.strings, be32(structs, pos + 4)? as usize)?;
+                let end = (pos + 8).checked_add(len).filter(|&end| end <= structs.len());
+                pos = align4(end.ok_or("FDT: Property out of bounds")?);
+            }
+            FDT_NOP => {}
+            FDT_END if depth == 0 && seen_root => return Ok(()),
+            FDT_END => return Err("FDT: End inside a node"),
+            _ => return Err("FDT: Bad structure token"),
+        }
+    }
+}
+
+/// A device tree node with owned names and values.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Node {
+    pub name: String,
+    pub props: Vec<(String, Vec<u8>)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), ..Self::default() }
+    }
+    
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.props.iter().find(|(prop, _)| prop == name).map(|(_, value)| value.as_slice())
+    }
+    
+    /// Add or replace a property.
+    pub fn set_property(&mut self, name: &str, value: &[u8]) {
+        match self.props.iter_mut().find(|(prop, _)| prop == name) {
+            Some((_, old)) => *old = value.to_vec(),
+            None => self.props.push((name.to_string(), value.to_vec())),
+        }
+    }
+    
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|child| child.name == name)
+    }
+    
+    // Path components match exactly, or ignoring the unit address
+    fn child_by_component_mut(&mut self, component: &str) -> Option<&mut Node> {
+        let exact = self.children.iter().position(|child| child.name == component);
+        let index = exact.or_else(|| {
+            self.children.iter().position(|child| {
+                !component.contains('@') && child.name.split('@').next() == Some(component)
+            })
+        })?;
+        Some(&mut self.children[index])
+    }
+}
+
+/// An unflattened tree plus the header fields that survive a round trip.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fdt {
+    pub root: Node,
+    pub reserved: Vec<(u64, u64)>,
+    pub boot_cpuid: u32,
+}
+
+struct Parser<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn token(&mut self) -> Result<u32, &'static str> {
+        loop {
+            let token = be32(self.structs, self.pos)?;
+            self.pos += 4;
+            if token != FDT_NOP {
+                return Ok(token);
+            }
+        }
+    }
+    
+    // Called just after the node's FDT_BEGIN_NODE
+    fn node(&mut self, depth: usize) -> Result<Node, &'static str> {
+        if depth >= MAX_DEPTH {
+            return Err("FDT: Nodes nested too deeply");
+        }
+        let name = cstr(self.structs, self.pos)?;
+        self.pos = align4(self.pos + name.len() + 1);
+        let mut node = Node::new(name);
+        
+        loop {
+            match self.token()? {
+                FDT_PROP => {
+                    let len = be32(self.structs, self.pos)? as usize;
+                    let name = cstr(self.strings, be32(self.structs, self.pos + 4)? as usize)?;
+                    let start = self.pos + 8;
+                    let value = start.checked_add(len).and_then(|end| self.structs.get(start..end));
+                    node.props.push((name.to_string(), value.ok_or("FDT: Property out of bounds")?.to_vec()));
+                    self.pos = align4(start + len);
+                }
+                FDT_BEGIN_NODE => node.children.push(self.node(depth + 1)?),
+                FDT_END_NODE => return Ok(node),
+                _ => return Err("FDT: Bad structure token"),
+            }
+        }
+    }
+}
+
+/// Parse a flattened blob into owned nodes.
+pub fn unflatten(blob: &[u8]) -> Result<Fdt, &'static str> {
+    let layout = layout(blob)?;
+    let reserved = reserved(blob)?;
+    let mut parser = Parser { structs: layout.structs, strings: layout.strings, pos: 0 };
+    if parser.token()? != FDT_BEGIN_NODE {
+        return Err("FDT: No root node");
+    }
+    let root = parser.node(0)?;
+    if parser.token()? != FDT_END {
+        return Err("FDT: Data after root node");
+    }
+    Ok(Fdt { root, reserved, boot_cpuid: layout.boot_cpuid })
+}
+
+struct Flattener {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl Flattener {
+    fn push32(&mut self, value: u32) {
+        self.structs.extend_from_slice(&value.to_be_bytes());
+    }
+    
+    fn pad(&mut self) {
+        self.structs.resize(align4(self.structs.len()), 0);
+    }
+    
+    // Property names are shared, as dtc does
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for existing in self.strings.split(|&b| b == 0) {
+            if existing == name.as_bytes() && offset < self.strings.len() {
+                return offset as u32;
+            }
+            offset += existing.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+    
+    fn node(&mut self, node: &Node) {
+        self.push32(FDT_BEGIN_NODE);
+        self.structs.extend_from_slice(node.name.as_bytes());
+        self.structs.push(0);
+        self.pad();
+        for (name, value) in &node.props {
+            let nameoff = self.string_offset(name);
+            self.push32(FDT_PROP);
+            self.push32(value.len() as u32);
+            self.push32(nameoff);
+            self.structs.extend_from_slice(value);
+            self.pad();
+        }
+        for child in &node.children {
+            self.node(child);
+        }
+        self.push32(FDT_END_NODE);
+    }
+}
+
+/// Build a version 17 blob.
+pub fn flatten(fdt: &Fdt) -> Vec<u8> {
+    let mut flat = Flattener { structs: Vec::new(), strings: Vec::new() };
+    flat.node(&fdt.root);
+    flat.push32(FDT_END);
+    
+    let off_rsvmap = FDT_HEADER_LEN;
+    let off_struct = off_rsvmap + (fdt.reserved.len() + 1) * 16;
+    let off_strings = off_struct + flat.structs.len();
+    let totalsize = off_strings + flat.strings.len();
+    
+    let mut blob = Vec::with_capacity(totalsize);
+    let header = [FDT_MAGIC, totalsize as u32, off_struct as u32, off_strings as u32, off_rsvmap as u32,
+                  FDT_VERSION, FDT_LAST_COMP_VERSION, fdt.boot_cpuid, flat.strings.len() as u32,
+                  flat.structs.len() as u32];
+    for field in header {
+        blob.extend_from_slice(&field.to_be_bytes());
+    }
+    for &(addr, size) in fdt.reserved.iter().chain([(0, 0)].iter()) {
+        blob.extend_from_slice(&addr.to_be_bytes());
+        blob.extend_from_slice(&size.to_be_bytes());
+    }
+    blob.extend_from_slice(&flat.structs);
+    blob.extend_from_slice(&flat.strings);
+    blob
+}
+
+fn find_path_mut<'a>(root: &'a mut Node, path: &str) -> Option<&'a mut Node> {
+    if !path.starts_with('/') {
+        return None;
+    }
+    path.split('/')
+        .filter(|component| !component.is_empty())
+        .try_fold(root, |node, component| node.child_by_component_mut(component))
+}
+
+fn find_phandle_mut(node: &mut Node, phandle: u32) -> Option<&mut Node> {
+    let matches = node.property("phandle").is_some_and(|value| be32(value, 0) == Ok(phandle));
+    if matches {
+        return Some(node);
+    }
+    node.children.iter_mut().find_map(|child| find_phandle_mut(child, phandle))
+}
+
+// Properties replace, children merge by name
+fn merge(target: &mut Node, content: &Node) {
+    for (name, value) in &content.props {
+        target.set_property(name, value);
+    }
+    for child in &content.children {
+        match target.children.iter_mut().find(|existing| existing.name == child.name) {
+            Some(existing) => merge(existing, child),
+            None => target.children.push(child.clone()),
+        }
+    }
+}
+
+/// Merge an overlay into `base`, returning the number of fragments.
+///
+/// Each fragment names its target with target-path or a literal target
+/// phandle. Overlays that need symbol resolution (__fixups__) are refused.
+/// On error `base` may be partly modified; apply to a copy to keep it
+/// intact.
+pub fn apply(base: &mut Node, overlay: &Node) -> Result<usize, &'static str> {
+    if overlay.child("__fixups__").is_some() {
+        return Err("Overlay has unresolved references (__fixups__)");
+    }
+    let mut applied = 0;
+    // __symbols__ and __local_fixups__ only matter for resolution
+    for fragment in overlay.children.iter().filter(|node| !node.name.starts_with("__")) {
+        let content = fragment.child("__overlay__").ok_or("Fragment without __overlay__")?;
+        let target = if let Some(path) = fragment.property("target-path") {
+            let path = cstr(path, 0)?;
+            find_path_mut(base, path).ok_or("Fragment target path not found")?
+        } else if let Some(phandle) = fragment.property("target") {
+            find_phandle_mut(base, be32(phandle, 0)?).ok_or("Fragment target phandle not found")?
+        } else {
+            return Err("Fragment has no target");
+        };
+        merge(target, content);
+        applied += 1;
+    }
+    Ok(applied)
+}
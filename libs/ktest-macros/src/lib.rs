@@ -0,0 +1,75 @@
+// #[kernel_test]: register a boot self-test
+//
+//     #[kernel_test]
+//     fn test_frame_allocation() { ... }
+//
+//     #[kernel_test(should_panic)]
+//     fn test_lock_recursion() { ... }
+//
+// The function is left as it is, and a `crate::ktest::KernelTest` naming
+// it goes into the .kernel_tests linker section, where the harness finds
+// every test without a list to keep up to date. The test is known by the
+// function's name without its `test_` prefix ("frame_allocation"), which
+// is what `test=` on the command line matches.
+
+use proc_macro::{TokenStream, TokenTree};
+
+#[proc_macro_attribute]
+pub fn kernel_test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let should_panic = match parse_attr(attr) {
+        Ok(should_panic) => should_panic,
+        Err(e) => return error(e),
+    };
+    let Some(function) = function_name(&item) else {
+        return error("#[kernel_test] goes on a function");
+    };
+    let name = function.strip_prefix("test_").unwrap_or(&function);
+    
+    let registration = format!(
+        "#[used]
+         #[link_section = \".kernel_tests\"]
+         static __KERNEL_TEST_{upper}: crate::ktest::KernelTest = crate::ktest::KernelTest {{
+             name: \"{name}\",
+             file: file!(),
+             line: line!(),
+             run: {function},
+             should_panic: {should_panic},
+         }};",
+        upper = function.to_ascii_uppercase(),
+    );
+    let mut out = item;
+    out.extend(registration.parse::<TokenStream>().expect("registration tokens"));
+    out
+}
+
+// Nothing, or `should_panic`
+fn parse_attr(attr: TokenStream) -> Result<bool, &'static str> {
+    let mut tokens = attr.into_iter();
+    let should_panic = match tokens.next() {
+        None => false,
+        Some(TokenTree::Ident(ident)) if ident.to_string() == "should_panic" => true,
+        Some(_) => return Err("#[kernel_test] takes nothing or should_panic"),
+    };
+    match tokens.next() {
+        None => Ok(should_panic),
+        Some(_) => Err("#[kernel_test] takes nothing or should_panic"),
+    }
+}
+
+// The identifier after `fn`
+fn function_name(item: &TokenStream) -> Option<String> {
+    let mut tokens = item.clone().into_iter();
+    while let Some(token) = tokens.next() {
+        if matches!(&token, TokenTree::Ident(ident) if ident.to_string() == "fn") {
+            return match tokens.next() {
+                Some(TokenTree::Ident(name)) => Some(name.to_string()),
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+fn error(message: &str) -> TokenStream {
+    format!("compile_error!({:?});", message).parse().expect("compile_error tokens")
+}
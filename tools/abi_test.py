@@ -0,0 +1,140 @@
+#!/usr/bin/env python3
+"""Run the syscall ABI conformance suite in QEMU.
+
+Builds userland/tests/abi-conformance, packs it into an initramfs as
+bin/abi-conformance, boots the kernel with it in QEMU test mode and
+starts it from the kernel shell over the serial console:
+    
+    tools/abi_test.py                  # every group
+    tools/abi_test.py mmap shm         # just these groups
+    make abi-test GROUPS="mmap shm"
+
+Each case reports one line (see the binary's main.rs); failures are
+printed, and the exit status is 0 only if every case passed. Build the
+kernel first (`make build`); --kernel picks another image.
+"""
+
+import argparse
+import os
+import socket
+import subprocess
+import sys
+import time
+
+from push import Console
+
+ROOT = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+PACKAGE = os.path.join(ROOT, "userland", "tests", "abi-conformance")
+LINK_SCRIPT = os.path.join(ROOT, "userland", "runtime", "link.ld")
+BINARY = os.path.join(PACKAGE, "target", "aarch64-unknown-none", "release", "abi-conformance")
+KERNEL = os.path.join(ROOT, "target", "aarch64-unknown-none", "debug", "rustkernel")
+INITRD_PATH = "bin/abi-conformance"
+
+QEMU = ["qemu-system-aarch64", "-machine", "virt", "-cpu", "cortex-a72", "-smp", "2", "-m", "1G",
+        "-nographic", "-monitor", "none"]
+
+
+def build():
+    # The workspace rustflags link with the kernel's script; RUSTFLAGS
+    # replaces them
+    env = dict(os.environ, RUSTFLAGS=f"-C link-arg=-T{LINK_SCRIPT}")
+    subprocess.run(["cargo", "build", "--release"], cwd=PACKAGE, env=env, check=True)
+    with open(BINARY, "rb") as f:
+        return f.read()
+
+
+def cpio_newc(files):
+    """A newc archive of (name, data) regular files."""
+    out = bytearray()
+    
+    def member(ino, name, mode, data):
+        name = name.encode() + b"\0"
+        fields = [ino, mode, 0, 0, 1, int(time.time()), len(data), 0, 0, 0, 0, len(name), 0]
+        out.extend(b"070701" + b"".join(b"%08x" % field for field in fields) + name)
+        out.extend(b"\0" * (-len(out) % 4))
+        out.extend(data)
+        out.extend(b"\0" * (-len(out) % 4))
+    
+    for ino, (name, data) in enumerate(files, 1):
+        member(ino, name, 0o100755, data)
+    member(0, "TRAILER!!!", 0, b"")
+    return bytes(out)
+
+
+def connect(port, timeout):
+    deadline = time.monotonic() + timeout
+    while True:
+        try:
+            return socket.create_connection(("localhost", port))
+        except OSError:
+            if time.monotonic() > deadline:
+                raise
+            time.sleep(0.1)
+
+
+def run_suite(console, groups):
+    """Start the binary and collect its report; returns (passed, failures)."""
+    console.expect("Shell: Kernel shell ready")
+    console.send(f"run /{INITRD_PATH} {' '.join(groups)}\r".encode())
+    line = console.expect("run:")
+    if "started as thread" not in line:
+        raise RuntimeError(line)
+    tid = line.rsplit(" ", 1)[1]
+    
+    passed, failures = [], []
+    while True:
+        line = console.expect("ABI ", f"Process: Thread {tid} ")
+        if line.startswith("Process:"):
+            raise RuntimeError(f"test binary died: {line}")
+        kind, _, rest = line[len("ABI "):].partition(" ")
+        if kind == "PASS":
+            passed.append(rest)
+        elif kind == "FAIL":
+            failures.append(rest)
+            print(f"FAIL {rest}")
+        elif kind == "DONE":
+            reported = [int(count) for count in rest.split()]
+            if reported != [len(passed), len(failures)]:
+                raise RuntimeError(f"summary {rest} does not match {len(passed)} passed, {len(failures)} failed")
+            return passed, failures
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("groups", nargs="*", help="groups to run (default: all)")
+    parser.add_argument("--kernel", default=KERNEL)
+    parser.add_argument("--port", type=int, default=4446, help="TCP port for the serial console")
+    parser.add_argument("--timeout", type=float, default=120, help="seconds for boot and the whole run")
+    parser.add_argument("--append", default="", help="extra kernel bootargs")
+    args = parser.parse_args()
+    
+    initrd = os.path.join(PACKAGE, "target", "abi-initramfs.cpio")
+    with open(initrd, "wb") as f:
+        f.write(cpio_newc([(INITRD_PATH, build())]))
+    
+    # wait=on: QEMU holds the guest until we are connected, so no output is lost
+    qemu = subprocess.Popen(QEMU + [
+        "-kernel", args.kernel, "-initrd", initrd,
+        "-append", f"qemu_test {args.append}".strip(),
+        "-serial", f"tcp:localhost:{args.port},server=on,wait=on",
+    ])
+    try:
+        with connect(args.port, 10) as sock:
+            sock.settimeout(args.timeout)
+            console = Console(sock)
+            passed, failures = run_suite(console, args.groups)
+            console.send(b"poweroff\r")
+    except (OSError, RuntimeError) as e:
+        sys.exit(f"abi_test: {e}")
+    finally:
+        try:
+            qemu.wait(timeout=10)
+        except subprocess.TimeoutExpired:
+            qemu.kill()
+    
+    print(f"abi_test: {len(passed)} passed, {len(failures)} failed")
+    sys.exit(1 if failures else 0)
+
+
+if __name__ == "__main__":
+    main()
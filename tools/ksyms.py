@@ -0,0 +1,189 @@
+#!/usr/bin/env python3
+"""Embed the kernel symbol table into a linked kernel image.
+
+Reads the function symbols from the kernel ELF's .symtab, packs them into
+the compressed format decoded by kernel/src/symbols.rs, and writes the
+result over the reserved .ksyms section in place:
+
+    tools/ksyms.py target/aarch64-unknown-none/debug/rustkernel
+
+`make build` runs this after linking. The section has a fixed size, so
+embedding the table moves nothing and the addresses it records stay valid.
+
+Table layout (little endian):
+
+    header   "KSYM", u32 version, u32 symbol count, u32 block count,
+             u64 end of the last symbol
+    index    per block of BLOCK_SIZE symbols: u64 first address,
+             u32 offset of the block's data from the table start, u32 0
+    data     per symbol: uleb128 address delta from the previous symbol in
+             the block (0 for the first), uleb128 size, u8 bytes shared with
+             the previous name (0 for the first), uleb128 suffix length,
+             suffix bytes
+"""
+
+import argparse
+import re
+import struct
+import subprocess
+import sys
+
+MAGIC = b"KSYM"
+VERSION = 1
+BLOCK_SIZE = 32
+# Longest name the kernel decodes; longer ones are cut
+NAME_MAX = 255
+
+SHT_SYMTAB = 2
+STT_NOTYPE = 0
+STT_FUNC = 2
+SHN_UNDEF = 0
+
+# Legacy mangling appends a hash segment, which only adds noise
+HASH_SUFFIX = re.compile(r"::h[0-9a-f]{16}$")
+
+# An Itanium demangler leaves the punctuation escapes of Rust's legacy
+# mangling in place
+RUST_ESCAPES = {"SP": "@", "BP": "*", "RF": "&", "LT": "<", "GT": ">",
+                "LP": "(", "RP": ")", "C": ","}
+RUST_ESCAPE = re.compile(r"\$(SP|BP|RF|LT|GT|LP|RP|C|u[0-9a-f]{2,6})\$")
+
+
+def sections(image):
+    shoff, = struct.unpack_from("<Q", image, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", image, 0x3A)
+    headers = []
+    for i in range(shnum):
+        fields = struct.unpack_from("<IIQQQQIIQQ", image, shoff + i * shentsize)
+        headers.append(fields)
+    strtab = headers[shstrndx]
+    found = {}
+    for fields in headers:
+        name_offset = strtab[4] + fields[0]
+        name = image[name_offset:image.index(b"\0", name_offset)].decode()
+        found[name] = {"type": fields[1], "addr": fields[3], "offset": fields[4],
+                       "size": fields[5], "link": fields[6]}
+    return found, headers
+
+
+def function_symbols(image):
+    found, headers = sections(image)
+    symtab = next(s for s in found.values() if s["type"] == SHT_SYMTAB)
+    strtab = headers[symtab["link"]]
+    text = found[".text"]
+    text_end = text["addr"] + text["size"]
+
+    symbols = {}
+    for offset in range(symtab["offset"], symtab["offset"] + symtab["size"], 24):
+        st_name, st_info, _, st_shndx, value, size = struct.unpack_from("<IBBHQQ", image, offset)
+        kind = st_info & 0xF
+        if st_shndx == SHN_UNDEF or kind not in (STT_FUNC, STT_NOTYPE):
+            continue
+        if not text["addr"] <= value < text_end:
+            continue
+        start = strtab[4] + st_name
+        name = image[start:image.index(b"\0", start)].decode(errors="replace")
+        # Skip mapping symbols ($x, $d) and local labels
+        if not name or name.startswith("$") or name.startswith(".L"):
+            continue
+        # Prefer a typed function over an assembler label at the same address
+        if value not in symbols or (kind == STT_FUNC and symbols[value][2] != STT_FUNC):
+            symbols[value] = (name, size, kind)
+    return sorted((addr, size, name) for addr, (name, size, _) in symbols.items())
+
+
+def demangle(names, demangler):
+    if not demangler:
+        return names
+    try:
+        result = subprocess.run([demangler], input="\n".join(names), capture_output=True,
+                                text=True, check=True)
+    except (OSError, subprocess.CalledProcessError) as e:
+        print(f"ksyms: {demangler} failed ({e}), keeping mangled names", file=sys.stderr)
+        return names
+    demangled = result.stdout.split("\n")[:len(names)]
+    return [unescape(HASH_SUFFIX.sub("", name)) for name in demangled]
+
+
+def unescape(name):
+    def replace(match):
+        code = match.group(1)
+        return RUST_ESCAPES.get(code) or chr(int(code[1:], 16))
+    # Path components that start with an escape carry a leading underscore
+    name = re.sub(r"(^|::)_(?=\$)", r"\1", name)
+    return RUST_ESCAPE.sub(replace, name).replace("..", "::")
+
+
+def uleb128(value):
+    out = bytearray()
+    while True:
+        byte = value & 0x7F
+        value >>= 7
+        if value:
+            out.append(byte | 0x80)
+        else:
+            out.append(byte)
+            return bytes(out)
+
+
+def shared_prefix(a, b):
+    limit = min(len(a), len(b), 255)
+    n = 0
+    while n < limit and a[n] == b[n]:
+        n += 1
+    return n
+
+
+def pack(symbols):
+    blocks = [symbols[i:i + BLOCK_SIZE] for i in range(0, len(symbols), BLOCK_SIZE)]
+    header_len = 24
+    data_start = header_len + 16 * len(blocks)
+
+    index = bytearray()
+    data = bytearray()
+    for block in blocks:
+        index += struct.pack("<QII", block[0][0], data_start + len(data), 0)
+        prev_addr, prev_name = block[0][0], b""
+        for addr, size, name in block:
+            name = name.encode()[:NAME_MAX]
+            shared = shared_prefix(prev_name, name)
+            data += uleb128(addr - prev_addr) + uleb128(size)
+            data += bytes([shared]) + uleb128(len(name) - shared) + name[shared:]
+            prev_addr, prev_name = addr, name
+
+    end = max((addr + size for addr, size, _ in symbols), default=0)
+    header = MAGIC + struct.pack("<IIIQ", VERSION, len(symbols), len(blocks), end)
+    return header + bytes(index) + bytes(data)
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("kernel", help="linked kernel ELF, patched in place")
+    parser.add_argument("--demangler", default="llvm-cxxfilt",
+                        help="symbol demangler read from stdin (empty to keep mangled names)")
+    args = parser.parse_args()
+
+    with open(args.kernel, "rb") as f:
+        image = bytearray(f.read())
+    found, _ = sections(image)
+    if ".ksyms" not in found:
+        sys.exit("ksyms: no .ksyms section in the kernel image")
+    reserved = found[".ksyms"]
+
+    symbols = function_symbols(image)
+    names = demangle([name for _, _, name in symbols], args.demangler)
+    symbols = [(addr, size, name) for (addr, size, _), name in zip(symbols, names)]
+    table = pack(symbols)
+    if len(table) > reserved["size"]:
+        sys.exit(f"ksyms: table is {len(table)} bytes but .ksyms holds {reserved['size']}; "
+                 "raise KSYMS_SIZE in kernel/src/symbols.rs")
+
+    start = reserved["offset"]
+    image[start:start + reserved["size"]] = table + bytes(reserved["size"] - len(table))
+    with open(args.kernel, "wb") as f:
+        f.write(image)
+    print(f"ksyms: {len(symbols)} symbols, {len(table)} of {reserved['size']} bytes")
+
+
+if __name__ == "__main__":
+    main()
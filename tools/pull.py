@@ -0,0 +1,90 @@
+#!/usr/bin/env python3
+"""Pull a file or /proc entry out of a running kernel over its serial console.
+
+Start QEMU with the serial port on TCP (`make run SERIAL_PORT=4444`), then:
+
+    tools/pull.py /proc/profile profile.txt
+    make pull SRC=/tmp/trace.bin FILE=trace.bin
+
+The kernel sends large base64 chunks, each with a sequence number and CRC.
+Every good chunk is acknowledged; at most WINDOW chunks are in flight, and
+a damaged or missing one is asked for again. The kernel shell must be at
+its prompt, and nothing else may be attached to the serial socket.
+"""
+
+import argparse
+import base64
+import binascii
+import socket
+import sys
+import zlib
+
+from push import Console
+
+
+def pull(console, src, window):
+    console.send(b"\r")
+    console.send(f"pull {src} {window}\r".encode())
+    line = console.expect("PULL BEGIN", "PULL ERR", "pull:")
+    if not line.startswith("PULL BEGIN"):
+        raise RuntimeError(line)
+    console.send(b"A 0\n")
+
+    data = bytearray()
+    expected = 0
+    resend_asked = False
+    while True:
+        line = console.expect("PULL DATA", "PULL END", "PULL ERR")
+        if line.startswith("PULL ERR"):
+            raise RuntimeError(line)
+        if line.startswith("PULL END"):
+            break
+
+        try:
+            _, _, seq, crc, payload = line.split(" ", 4)
+            seq, crc = int(seq), int(crc, 16)
+            chunk = base64.b64decode(payload, validate=True)
+            good = zlib.crc32(chunk) == crc
+        except (ValueError, binascii.Error):
+            seq, good = expected, False
+        if seq == expected and good:
+            data += chunk
+            expected += 1
+            resend_asked = False
+            console.send(f"A {expected}\n".encode())
+            print(f"\r{len(data)} bytes", end="", file=sys.stderr)
+        elif seq >= expected and not resend_asked:
+            # Go back to the first chunk we lack; later ones are discarded
+            console.send(f"R {expected}\n".encode())
+            resend_asked = True
+    print(file=sys.stderr)
+
+    _, _, size, crc = line.split()
+    if int(size) != len(data) or int(crc, 16) != zlib.crc32(data):
+        console.send(b"ERR checksum\n")
+        raise RuntimeError("stream checksum mismatch")
+    console.send(b"OK\n")
+    return bytes(data)
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("src", help="path in the kernel, e.g. /proc/profile")
+    parser.add_argument("file", help="local file to write")
+    parser.add_argument("--host", default="localhost")
+    parser.add_argument("--port", type=int, default=4444)
+    parser.add_argument("--window", type=int, default=8, help="chunks in flight (1-64)")
+    args = parser.parse_args()
+
+    try:
+        with socket.create_connection((args.host, args.port)) as sock:
+            data = pull(Console(sock), args.src, args.window)
+    except (OSError, RuntimeError) as e:
+        sys.exit(f"pull: {e}")
+    with open(args.file, "wb") as f:
+        f.write(data)
+    print(f"pull: {len(data)} bytes written to {args.file}")
+
+
+if __name__ == "__main__":
+    main()
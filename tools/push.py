@@ -0,0 +1,88 @@
+#!/usr/bin/env python3
+"""Push a file into a running kernel over its serial console.
+
+Start QEMU with the serial port on TCP (`make run SERIAL_PORT=4444`), then:
+
+    tools/push.py build/hello /tmp/hello
+    make push FILE=build/hello DEST=/tmp/hello
+
+The kernel shell must be at its prompt. QEMU accepts one client per serial
+socket, so disconnect any terminal attached to the port first.
+"""
+
+import argparse
+import base64
+import socket
+import sys
+import zlib
+
+# Raw bytes per line; 128 base64 characters fit the kernel's line buffer
+CHUNK = 96
+
+
+class Console:
+    def __init__(self, sock):
+        self.sock = sock
+        self.pending = b""
+
+    def send(self, data):
+        self.sock.sendall(data)
+
+    def expect(self, *prefixes):
+        """Skip output until a protocol line with one of the prefixes."""
+        while True:
+            while b"\n" not in self.pending:
+                data = self.sock.recv(4096)
+                if not data:
+                    raise ConnectionError("serial connection closed")
+                self.pending += data
+            line, self.pending = self.pending.split(b"\n", 1)
+            line = line.strip().decode(errors="replace")
+            # The shell echoes our command on the same line as its prompt
+            for prefix in prefixes:
+                if line.startswith(prefix):
+                    return line
+
+
+def push(console, data, dest):
+    console.send(b"\r")
+    crc = zlib.crc32(data)
+    console.send(f"push {dest} {len(data)} {crc:08x}\r".encode())
+    line = console.expect("PUSH READY", "PUSH ERR", "push:")
+    if not line.startswith("PUSH READY"):
+        raise RuntimeError(line)
+
+    for offset in range(0, len(data), CHUNK):
+        console.send(base64.b64encode(data[offset:offset + CHUNK]) + b"\n")
+        line = console.expect("PUSH ACK", "PUSH ERR")
+        if line.startswith("PUSH ERR"):
+            raise RuntimeError(line)
+        sent = min(offset + CHUNK, len(data))
+        print(f"\r{sent}/{len(data)} bytes", end="", file=sys.stderr)
+    print(file=sys.stderr)
+
+    line = console.expect("PUSH OK", "PUSH ERR")
+    if not line.startswith("PUSH OK"):
+        raise RuntimeError(line)
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("file", help="local file to send")
+    parser.add_argument("dest", help="path in the kernel VFS, e.g. /tmp/app")
+    parser.add_argument("--host", default="localhost")
+    parser.add_argument("--port", type=int, default=4444)
+    args = parser.parse_args()
+
+    with open(args.file, "rb") as f:
+        data = f.read()
+    try:
+        with socket.create_connection((args.host, args.port)) as sock:
+            push(Console(sock), data, args.dest)
+    except (OSError, RuntimeError) as e:
+        sys.exit(f"push: {e}")
+    print(f"push: {len(data)} bytes written to {args.dest}")
+
+
+if __name__ == "__main__":
+    main()
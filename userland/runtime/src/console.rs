@@ -0,0 +1,31 @@
+// print!/println! to the kernel console via debug_write
+
+use core::fmt::{self, Write};
+
+pub struct DebugConsole;
+
+impl Write for DebugConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut rest = s.as_bytes();
+        while !rest.is_empty() {
+            let written = crate::syscalls::debug_write(rest).map_err(|_| fmt::Error)?;
+            rest = &rest[written..];
+        }
+        Ok(())
+    }
+}
+
+pub fn print_args(args: fmt::Arguments) {
+    let _ = DebugConsole.write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::print_args(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
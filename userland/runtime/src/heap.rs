@@ -0,0 +1,75 @@
+// Heap over anonymous mmap
+//
+// Memory comes from the kernel in arenas of at least ARENA_SIZE bytes,
+// each managed by its own linked-list heap. Arenas are never returned;
+// a service's heap only grows.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{null_mut, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use crate::syscalls::{mmap, PROT_READ, PROT_WRITE};
+
+pub const ARENA_SIZE: usize = 64 * 1024;
+pub const MAX_ARENAS: usize = 32;
+const PAGE_SIZE: usize = 4096;
+
+pub struct MmapHeap {
+    arenas: Mutex<[Option<Heap>; MAX_ARENAS]>,
+}
+
+impl Default for MmapHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MmapHeap {
+    pub const fn new() -> Self {
+        const EMPTY: Option<Heap> = None;
+        Self { arenas: Mutex::new([EMPTY; MAX_ARENAS]) }
+    }
+    
+    /// Bytes allocated and free across all arenas.
+    pub fn stats(&self) -> (usize, usize) {
+        self.arenas
+            .lock()
+            .iter()
+            .flatten()
+            .fold((0, 0), |(used, free), heap| (used + heap.used(), free + heap.free()))
+    }
+}
+
+unsafe impl GlobalAlloc for MmapHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut arenas = self.arenas.lock();
+        for heap in arenas.iter_mut().flatten() {
+            if let Ok(ptr) = heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+        }
+        
+        // Grow: a new arena big enough for this request and its alignment
+        let Some(slot) = arenas.iter_mut().find(|slot| slot.is_none()) else { return null_mut() };
+        let size = (layout.size() + layout.align()).next_multiple_of(PAGE_SIZE).max(ARENA_SIZE);
+        let Ok(base) = mmap(size, PROT_READ | PROT_WRITE) else { return null_mut() };
+        let heap = slot.insert(Heap::new(base, size));
+        heap.allocate_first_fit(layout).map_or(null_mut(), |ptr| ptr.as_ptr())
+    }
+    
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut arenas = self.arenas.lock();
+        let addr = ptr as usize;
+        if let Some(heap) = arenas
+            .iter_mut()
+            .flatten()
+            .find(|heap| addr >= heap.bottom() as usize && addr < heap.top() as usize)
+        {
+            heap.deallocate(NonNull::new_unchecked(ptr), layout);
+        }
+    }
+}
+
+#[cfg(feature = "rt")]
+#[global_allocator]
+pub static HEAP: MmapHeap = MmapHeap::new();
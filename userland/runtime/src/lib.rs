@@ -1,7 +1,27 @@
 #![no_std]
 
 // Userspace runtime library for microkernel services
+//
+// Everything a service needs to run on this kernel without a libc:
+// syscall stubs, the `_start` entry that decodes the initial stack, a
+// heap over mmap, and a panic handler that reports to the console and
+// exits so the process manager restarts the service.
+//
+// A service binary enables the "rt" feature, names its entry point with
+// `entry!`, and links with link.ld:
+//
+//     userland_runtime::entry!(service_main);
+//     fn service_main(info: &StartupInfo) -> i32 { ... }
 
 pub mod syscalls;
+pub mod start;
+pub mod heap;
+pub mod console;
+pub mod ring;
+#[cfg(feature = "rt")]
+mod panic;
 
-// TODO: Implement userspace runtime
\ No newline at end of file
+pub use start::StartupInfo;
+
+/// Exit status of a thread that panicked.
+pub const EXIT_STATUS_PANIC: u32 = 101;
@@ -0,0 +1,12 @@
+// Panic handler: report on the console, then exit with EXIT_STATUS_PANIC
+//
+// The exit reaches the process manager as an exit event with that status,
+// which its restart policy treats as a failure.
+
+use core::panic::PanicInfo;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crate::println!("panic: {}", info);
+    crate::syscalls::exit(crate::EXIT_STATUS_PANIC)
+}
@@ -0,0 +1,163 @@
+// Submission/completion ring client
+//
+// Layout mirrors kernel/src/uring.rs. Queue operations with `push`, hand
+// them to the kernel with `submit`, and drain results with `pop`; only
+// `submit` makes a syscall.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::syscalls::{ring_enter, ring_setup};
+
+pub const OP_NOP: u8 = 0;
+pub const OP_READ: u8 = 1;
+pub const OP_WRITE: u8 = 2;
+pub const OP_SEND: u8 = 3;
+pub const OP_RECV: u8 = 4;
+
+// OP_SEND flags: pass the pages at addr/len instead of copying bytes
+pub const SQE_GRANT_MOVE: u8 = 1 << 0;
+pub const SQE_GRANT_SHARE: u8 = 1 << 1;
+
+/// Set in a receive's result when it got a page grant; the buffer then
+/// holds a GrantDescriptor.
+pub const CQE_GRANT: i64 = 1 << 62;
+
+pub const RING_MAX_ENTRIES: u32 = 256;
+
+#[repr(C)]
+pub struct RingHeader {
+    pub sq_head: AtomicU32,
+    pub sq_tail: AtomicU32,
+    pub cq_head: AtomicU32,
+    pub cq_tail: AtomicU32,
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    pub sq_offset: u32,
+    pub cq_offset: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SubmissionEntry {
+    pub opcode: u8,
+    pub flags: u8,
+    pub reserved: u16,
+    pub port: u32,
+    pub path: u64,
+    pub path_len: u32,
+    pub len: u32,
+    pub addr: u64,
+    pub offset: u64,
+    pub user_data: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GrantDescriptor {
+    pub addr: u64,
+    pub len: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CompletionEntry {
+    pub user_data: u64,
+    pub result: i64,
+}
+
+impl SubmissionEntry {
+    pub fn read(path: &str, offset: u64, buf: &mut [u8], user_data: u64) -> Self {
+        Self::file(OP_READ, path, offset, buf.as_mut_ptr() as u64, buf.len(), user_data)
+    }
+    
+    pub fn write(path: &str, offset: u64, buf: &[u8], user_data: u64) -> Self {
+        Self::file(OP_WRITE, path, offset, buf.as_ptr() as u64, buf.len(), user_data)
+    }
+    
+    pub fn send(port: u32, buf: &[u8], user_data: u64) -> Self {
+        Self { opcode: OP_SEND, port, addr: buf.as_ptr() as u64, len: buf.len() as u32, user_data, ..Self::default() }
+    }
+    
+    /// Send the pages holding `pages` (page aligned), moving them out of
+    /// this address space or, with `share`, lending them read-only.
+    pub fn send_pages(port: u32, pages: &[u8], share: bool, user_data: u64) -> Self {
+        let flags = if share { SQE_GRANT_SHARE } else { SQE_GRANT_MOVE };
+        Self { flags, ..Self::send(port, pages, user_data) }
+    }
+    
+    pub fn recv(port: u32, buf: &mut [u8], user_data: u64) -> Self {
+        Self { opcode: OP_RECV, port, addr: buf.as_mut_ptr() as u64, len: buf.len() as u32, user_data, ..Self::default() }
+    }
+    
+    fn file(opcode: u8, path: &str, offset: u64, addr: u64, len: usize, user_data: u64) -> Self {
+        Self {
+            opcode,
+            path: path.as_ptr() as u64,
+            path_len: path.len() as u32,
+            addr,
+            len: len as u32,
+            offset,
+            user_data,
+            ..Self::default()
+        }
+    }
+}
+
+/// The calling thread's ring. Buffers named by queued entries must stay
+/// valid until their completion is popped.
+pub struct Ring {
+    base: *mut u8,
+    // Pushed but not yet handed to the kernel
+    unsubmitted: u32,
+}
+
+impl Ring {
+    /// Set up the ring; `entries` is a power of two up to RING_MAX_ENTRIES.
+    pub fn new(entries: u32) -> Result<Self, i64> {
+        Ok(Self { base: ring_setup(entries)?, unsubmitted: 0 })
+    }
+    
+    fn header(&self) -> &RingHeader {
+        unsafe { &*(self.base as *const RingHeader) }
+    }
+    
+    /// Queue an entry; false if the submission queue is full.
+    pub fn push(&mut self, sqe: SubmissionEntry) -> bool {
+        let header = self.header();
+        let tail = header.sq_tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(header.sq_head.load(Ordering::Acquire)) == header.sq_entries {
+            return false;
+        }
+        let slot = (tail & (header.sq_entries - 1)) as usize;
+        unsafe {
+            let sqes = self.base.add(header.sq_offset as usize) as *mut SubmissionEntry;
+            sqes.add(slot).write_volatile(sqe);
+        }
+        header.sq_tail.store(tail.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+        true
+    }
+    
+    /// Hand queued entries to the kernel and wait for `min_complete`
+    /// completions. Returns entries consumed.
+    pub fn submit(&mut self, min_complete: u32) -> Result<u32, i64> {
+        let consumed = ring_enter(self.unsubmitted, min_complete)?;
+        self.unsubmitted -= consumed;
+        Ok(consumed)
+    }
+    
+    /// Next completion, if any.
+    pub fn pop(&mut self) -> Option<CompletionEntry> {
+        let header = self.header();
+        let head = header.cq_head.load(Ordering::Relaxed);
+        if head == header.cq_tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = (head & (header.cq_entries - 1)) as usize;
+        let cqe = unsafe {
+            let cqes = self.base.add(header.cq_offset as usize) as *const CompletionEntry;
+            cqes.add(slot).read_volatile()
+        };
+        header.cq_head.store(head.wrapping_add(1), Ordering::Release);
+        Some(cqe)
+    }
+}
@@ -0,0 +1,144 @@
+// Program entry and the initial stack layout
+//
+// The kernel starts a program at `_start` with sp pointing at:
+//
+//     argc
+//     argv[0] .. argv[argc - 1], NULL
+//     envp[0] .. envp[n - 1], NULL
+//     auxv: (type, value) pairs ending with AT_NULL
+//
+// all 8-byte words, strings NUL-terminated (the System V layout).
+
+use core::ffi::CStr;
+
+// Auxiliary vector types
+pub const AT_NULL: u64 = 0;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+/// What the kernel passed on the initial stack.
+#[derive(Copy, Clone, Debug)]
+pub struct StartupInfo {
+    argc: usize,
+    argv: *const *const u8,
+    envp: *const *const u8,
+    auxv: *const u64,
+}
+
+impl StartupInfo {
+    /// Decode the layout at the initial stack pointer.
+    ///
+    /// # Safety
+    /// `sp` must point at a well-formed initial stack.
+    pub unsafe fn from_stack(sp: *const u64) -> Self {
+        let argc = *sp as usize;
+        let argv = sp.add(1) as *const *const u8;
+        let envp = argv.add(argc + 1);
+        let mut end = envp;
+        while !(*end).is_null() {
+            end = end.add(1);
+        }
+        Self { argc, argv, envp, auxv: end.add(1) as *const u64 }
+    }
+    
+    pub fn argc(&self) -> usize {
+        self.argc
+    }
+    
+    /// Argument `index`, if present and UTF-8.
+    pub fn arg(&self, index: usize) -> Option<&'static str> {
+        if index >= self.argc {
+            return None;
+        }
+        unsafe { c_str(*self.argv.add(index)) }
+    }
+    
+    pub fn args(&self) -> impl Iterator<Item = &'static str> + '_ {
+        (0..self.argc).filter_map(|index| self.arg(index))
+    }
+    
+    /// Environment entries, "KEY=value".
+    pub fn env(&self) -> impl Iterator<Item = &'static str> + '_ {
+        let mut entry = self.envp;
+        core::iter::from_fn(move || unsafe {
+            while !(*entry).is_null() {
+                let s = c_str(*entry);
+                entry = entry.add(1);
+                if s.is_some() {
+                    return s;
+                }
+            }
+            None
+        })
+    }
+    
+    /// Value of environment variable `key`.
+    pub fn var(&self, key: &str) -> Option<&'static str> {
+        self.env().find_map(|entry| {
+            entry.split_once('=').filter(|(name, _)| *name == key).map(|(_, value)| value)
+        })
+    }
+    
+    /// Value of auxiliary vector entry `kind`.
+    pub fn aux(&self, kind: u64) -> Option<u64> {
+        let mut entry = self.auxv;
+        unsafe {
+            while *entry != AT_NULL {
+                if *entry == kind {
+                    return Some(*entry.add(1));
+                }
+                entry = entry.add(2);
+            }
+        }
+        None
+    }
+}
+
+unsafe fn c_str(ptr: *const u8) -> Option<&'static str> {
+    if ptr.is_null() {
+        return None;
+    }
+    CStr::from_ptr(ptr as *const core::ffi::c_char).to_str().ok()
+}
+
+/// Name the service's entry point: `fn(&StartupInfo) -> i32`. Its return
+/// value becomes the exit status.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        extern "Rust" fn __runtime_main(info: &$crate::StartupInfo) -> i32 {
+            let main: fn(&$crate::StartupInfo) -> i32 = $main;
+            main(info)
+        }
+    };
+}
+
+#[cfg(feature = "rt")]
+mod entry {
+    use super::StartupInfo;
+    
+    extern "Rust" {
+        fn __runtime_main(info: &StartupInfo) -> i32;
+    }
+    
+    // sp is 16-byte aligned at entry; hand it over before touching the stack
+    core::arch::global_asm!(
+        ".section .text._start",
+        ".global _start",
+        "_start:",
+        "    mov x0, sp",
+        "    mov x29, xzr",
+        "    mov x30, xzr",
+        "    bl {start}",
+        "    brk #0",
+        start = sym runtime_start,
+    );
+    
+    unsafe extern "C" fn runtime_start(sp: *const u64) -> ! {
+        let info = StartupInfo::from_stack(sp);
+        let status = __runtime_main(&info);
+        crate::syscalls::exit(status as u32)
+    }
+}
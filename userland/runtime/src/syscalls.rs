@@ -1,3 +1,638 @@
 // System call interface for userspace services
+//
+// Numbers and ABI version mirror kernel/src/syscall.rs. Probe with
+// `has_syscall` before relying on calls newer than SYSCALL_ABI_VERSION 1.
 
-// TODO: Define syscall numbers and wrappers
\ No newline at end of file
+use core::arch::asm;
+
+/// ABI version these wrappers were written against.
+pub const SYSCALL_ABI_VERSION: u64 = 1;
+pub const MAX_SYSCALLS: u64 = 256;
+
+pub const SYS_ABI_VERSION: u64 = 0;
+pub const SYS_AUDIT_READ: u64 = 1;
+pub const SYS_SYSCALL_BITMAP: u64 = 2;
+pub const SYS_OOM_POLICY: u64 = 3;
+pub const SYS_SERVICE_SUPERVISE: u64 = 4;
+pub const SYS_SERVICE_EVENTS: u64 = 5;
+pub const SYS_SERVICE_RESPAWNED: u64 = 6;
+pub const SYS_EXIT: u64 = 7;
+pub const SYS_DEBUG_WRITE: u64 = 8;
+pub const SYS_MMAP: u64 = 9;
+pub const SYS_MUNMAP: u64 = 10;
+pub const SYS_RING_SETUP: u64 = 11;
+pub const SYS_RING_ENTER: u64 = 12;
+pub const SYS_PROFILE_CONTROL: u64 = 13;
+pub const SYS_PROFILE_READ: u64 = 14;
+pub const SYS_SET_PRIORITY: u64 = 15;
+pub const SYS_NANOSLEEP: u64 = 16;
+pub const SYS_CLOCK_GETTIME: u64 = 17;
+pub const SYS_REBOOT: u64 = 18;
+pub const SYS_FAULT_PORT: u64 = 19;
+pub const SYS_SYSCTL_GET: u64 = 20;
+pub const SYS_SYSCTL_SET: u64 = 21;
+pub const SYS_SHM_CREATE: u64 = 22;
+pub const SYS_SHM_OPEN: u64 = 23;
+pub const SYS_SHM_MAP: u64 = 24;
+pub const SYS_SHM_GRANT: u64 = 25;
+pub const SYS_SHM_DESTROY: u64 = 26;
+pub const SYS_PORT_SIGNAL: u64 = 27;
+pub const SYS_PORT_WAIT: u64 = 28;
+pub const SYS_CLOCK_CONTROL: u64 = 29;
+pub const SYS_IPC_CALL: u64 = 30;
+pub const SYS_IPC_RECEIVE: u64 = 31;
+pub const SYS_IPC_REPLY: u64 = 32;
+pub const SYS_IPC_CALLV: u64 = 33;
+pub const SYS_IPC_RECEIVEV: u64 = 34;
+pub const SYS_IPC_REPLYV: u64 = 35;
+pub const SYS_THREAD_SET_NAME: u64 = 36;
+pub const SYS_SYSINFO: u64 = 37;
+pub const SYS_THREAD_INFO: u64 = 38;
+pub const SYS_DEVICE_IOCTL: u64 = 39;
+pub const SYS_SYSCALL_FILTER: u64 = 40;
+
+/// Largest IPC message payload.
+pub const MESSAGE_MAX: usize = 8 * 1024;
+/// Buffer size of the flat IPC calls; longer messages need the vectored ones.
+pub const MESSAGE_INLINE: usize = 256;
+/// Buffers in one direction of a scatter-gather list.
+pub const IPC_VEC_MAX: usize = 16;
+
+// clock_control operations
+pub const CLOCK_CONTROL_REAL: u64 = 0;
+pub const CLOCK_CONTROL_VIRTUAL: u64 = 1;
+pub const CLOCK_CONTROL_ADVANCE: u64 = 2;
+
+// port_wait flags
+pub const PORT_WAIT_NONBLOCK: u64 = 1 << 0;
+
+// mmap protection bits
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+pub const EPERM: i64 = -1;
+pub const ENOENT: i64 = -2;
+pub const EIO: i64 = -5;
+pub const EAGAIN: i64 = -11;
+pub const ENOMEM: i64 = -12;
+pub const EFAULT: i64 = -14;
+pub const EBUSY: i64 = -16;
+pub const EEXIST: i64 = -17;
+pub const ENOTDIR: i64 = -20;
+pub const EISDIR: i64 = -21;
+pub const EINVAL: i64 = -22;
+pub const ENOTTY: i64 = -25;
+pub const ENOSPC: i64 = -28;
+pub const EROFS: i64 = -30;
+pub const ENOSYS: i64 = -38;
+
+/// Issue syscall `NR` (the SVC immediate) with up to three arguments.
+#[inline(always)]
+pub unsafe fn syscall3<const NR: u64>(a0: u64, a1: u64, a2: u64) -> i64 {
+    let ret: i64;
+    asm!("svc #{nr}",
+         nr = const NR,
+         inout("x0") a0 => ret,
+         in("x1") a1,
+         in("x2") a2,
+         options(nostack));
+    ret
+}
+
+/// Issue syscall `NR` with four arguments.
+#[inline(always)]
+pub unsafe fn syscall4<const NR: u64>(a0: u64, a1: u64, a2: u64, a3: u64) -> i64 {
+    let ret: i64;
+    asm!("svc #{nr}",
+         nr = const NR,
+         inout("x0") a0 => ret,
+         in("x1") a1,
+         in("x2") a2,
+         in("x3") a3,
+         options(nostack));
+    ret
+}
+
+/// ABI version of the running kernel.
+pub fn abi_version() -> u64 {
+    unsafe { syscall3::<SYS_ABI_VERSION>(0, 0, 0) as u64 }
+}
+
+/// Bits for syscalls `word * 64 ..= word * 64 + 63`.
+pub fn syscall_bitmap(word: u64) -> Result<u64, i64> {
+    if word >= MAX_SYSCALLS / 64 {
+        return Err(EINVAL);
+    }
+    Ok(unsafe { syscall3::<SYS_SYSCALL_BITMAP>(word, 0, 0) } as u64)
+}
+
+/// Of syscalls `word * 64 ..= word * 64 + 63`, keep only those set in
+/// `allowed` callable by this thread and the threads it starts; others
+/// fail with EPERM. Filters cannot be widened again; exit is always
+/// allowed.
+pub fn syscall_filter(word: u64, allowed: u64) -> Result<(), i64> {
+    let ret = unsafe { syscall3::<SYS_SYSCALL_FILTER>(word, allowed, 0) };
+    if ret < 0 { Err(ret) } else { Ok(()) }
+}
+
+/// Set a thread's OOM badness adjustment (-1000..=1000) and protection.
+pub fn oom_policy(tid: u32, score_adj: i16, protected: bool) -> Result<(), i64> {
+    let ret = unsafe { syscall3::<SYS_OOM_POLICY>(tid as u64, score_adj as i64 as u64, protected as u64) };
+    if ret < 0 { Err(ret) } else { Ok(()) }
+}
+
+// ExitEvent::reason values
+pub const EXIT_REASON_EXITED: u32 = 0;
+pub const EXIT_REASON_KILLED: u32 = 1;
+pub const EXIT_REASON_OOM_KILLED: u32 = 2;
+pub const EXIT_REASON_FAULTED: u32 = 3;
+
+// RestartPolicy::mode values
+pub const RESTART_NEVER: u32 = 0;
+pub const RESTART_ON_FAILURE: u32 = 1;
+pub const RESTART_ALWAYS: u32 = 2;
+
+/// Mirrors the kernel's RestartPolicy.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct RestartPolicy {
+    pub mode: u32,
+    pub max_restarts: u32,   // 0 = unlimited
+    pub base_delay_ms: u32,
+    pub max_delay_ms: u32,
+    pub stable_ms: u32,
+}
+
+/// Mirrors the kernel's ExitEvent.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ExitEvent {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub restart_at: u64,     // Counter ticks; 0 = not restarted
+    pub tid: u32,
+    pub reason: u32,
+    pub status: u32,
+    pub attempt: u32,
+    pub name: [u8; 16],
+}
+
+/// Supervise thread `tid` under `policy`.
+pub fn service_supervise(tid: u32, policy: &RestartPolicy) -> Result<(), i64> {
+    let ret = unsafe { syscall3::<SYS_SERVICE_SUPERVISE>(tid as u64, policy as *const _ as u64, 0) };
+    if ret < 0 { Err(ret) } else { Ok(()) }
+}
+
+/// Exit events with sequence number >= `since`; returns how many were filled.
+pub fn service_events(since: u64, events: &mut [ExitEvent]) -> Result<usize, i64> {
+    let ret = unsafe {
+        syscall3::<SYS_SERVICE_EVENTS>(since, events.as_mut_ptr() as u64, events.len() as u64)
+    };
+    if ret < 0 { Err(ret) } else { Ok(ret as usize) }
+}
+
+/// Report `new_tid` as the replacement of `old_tid`. EAGAIN until the
+/// event's `restart_at`.
+pub fn service_respawned(old_tid: u32, new_tid: u32) -> Result<(), i64> {
+    let ret = unsafe { syscall3::<SYS_SERVICE_RESPAWNED>(old_tid as u64, new_tid as u64, 0) };
+    if ret < 0 { Err(ret) } else { Ok(()) }
+}
+
+/// End the calling thread. Its supervisor sees ExitReason::Exited with `status`.
+pub fn exit(status: u32) -> ! {
+    unsafe { syscall3::<SYS_EXIT>(status as u64, 0, 0) };
+    // The kernel never resumes an exited thread
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Write to the kernel console; returns bytes written (at most 1024 per call).
+pub fn debug_write(bytes: &[u8]) -> Result<usize, i64> {
+    let ret = unsafe { syscall3::<SYS_DEBUG_WRITE>(bytes.as_ptr() as u64, bytes.len() as u64, 0) };
+    if ret < 0 { Err(ret) } else { Ok(ret as usize) }
+}
+
+/// Map `len` bytes (rounded up to pages) of zeroed memory. Asking for
+/// both `PROT_WRITE` and `PROT_EXEC` fails with `EPERM`.
+pub fn mmap(len: usize, prot: u64) -> Result<*mut u8, i64> {
+    let ret = unsafe { syscall3::<SYS_MMAP>(len as u64, prot, 0) };
+    if ret < 0 { Err(ret) } else { Ok(ret as *mut u8) }
+}
+
+/// Unmap memory returned by `mmap`.
+pub fn munmap(addr: *mut u8, len: usize) -> Result<(), i64> {
+    let ret = unsafe { syscall3::<SYS_MUNMAP>(addr as u64, len as u64, 0) };
+    if ret < 0 { Err(ret) } else { Ok(()) }
+}
+
+/// Set up the calling thread's submission/completion ring; returns its
+/// address. See `ring::Ring` for a wrapper.
+pub fn ring_setup(entries: u32) -> Result<*mut u8, i64> {
+    let ret = unsafe { syscall3::<SYS_RING_SETUP>(entries as u64, 0, 0) };
+    if ret < 0 { Err(ret) } else { Ok(ret as *mut u8) }
+}
+
+/// Consume up to `to_submit` submissions and wait for `min_complete`
+/// completions; returns submissions consumed.
+pub fn ring_enter(to_submit: u32, min_complete: u32) -> Result<u32, i64> {
+    let ret = unsafe { syscall3::<SYS_RING_ENTER>(to_submit as u64, min_complete as u64, 0) };
+    if ret < 0 { Err(ret) } else { Ok(ret as u32) }
+}
+
+// profile_control operations and flags
+pub const PROFILE_STOP: u64 = 0;
+pub const PROFILE_START: u64 = 1;
+pub const PROFILE_BACKTRACE: u64 = 1 << 0;
+
+// ProfileSample::flags bits
+pub const SAMPLE_USER: u32 = 1 << 0;
+
+/// Mirrors the kernel's profile::Sample.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ProfileSample {
+    pub pc: u64,
+    pub callers: [u64; 4],  // Innermost first; unused slots are 0
+    pub thread: u32,
+    pub flags: u32,
+}
+
+/// Start the sampling profiler with `samples_per_cpu` (0 = default) room.
+pub fn profile_start(samples_per_cpu: u64, backtrace: bool) -> Result<(), i64> {
+    let flags = if backtrace { PROFILE_BACKTRACE } else { 0 };
+    let ret = unsafe { syscall3::<SYS_PROFILE_CONTROL>(PROFILE_START, samples_per_cpu, flags) };
+    if ret < 0 { Err(ret) } else { Ok(()) }
+}
+
+pub fn profile_stop() -> Result<(), i64> {
+    let ret = unsafe { syscall3::<SYS_PROFILE_CONTROL>(PROFILE_STOP, 0, 0) };
+    if ret < 0 { Err(ret) } else { Ok(()) }
+}
+
+/// Samples from `offset` on; returns how many were filled.
+pub fn profile_read(offset: u64, samples: &mut [ProfileSample]) -> Result<usize, i64> {
+    let ret = unsafe {
+        syscall3::<SYS_PROFILE_READ>(offset, samples.as_mut_ptr() as u64, samples.len() as u64)
+    };
+    if ret < 0 { Err(ret) } else { Ok(ret as usize) }
+}
+
+/// Switch the kernel clock to virtual time (privileged). Timers and
+/// sleeps then only move when `clock_advance` is called.
+pub fn clock_virtual(enable: bool) -> Result<u64, i64> {
+    let op = if enable { CLOCK_CONTROL_VIRTUAL } else { CLOCK_CONTROL_REAL };
+    let ret = unsafe { syscall3::<SYS_CLOCK_CONTROL>(op, 0, 0) };
+    if ret < 0 { Err(ret) } else { Ok(ret as u64) }
+}
+
+/// Step the virtual clock by `ns`; returns the new CLOCK_MONOTONIC ns.
+pub fn clock_advance(ns: u64) -> Result<u64, i64> {
+    let ret = unsafe { syscall3::<SYS_CLOCK_CONTROL>(CLOCK_CONTROL_ADVANCE, ns, 0) };
+    if ret < 0 { Err(ret) } else { Ok(ret as u64) }
+}
+
+/// Highest thread priority; higher values are more urgent.
+pub const PRIORITY_MAX: u8 = 31;
+
+/// Thread argument meaning the calling thread.
+pub const THREAD_SELF: u32 = u32::MAX;
+
+/// Change thread `tid`'s priority. Services may only lower their own.
+pub fn set_priority(tid: u32, priority: u8) -> Result<(), i64> {
+    let ret = unsafe { syscall3::<SYS_SET_PRIORITY>(tid as u64, priority as u64, 0) };
+    if ret < 0 { Err(ret) } else { Ok(()) }
+}
+
+/// Longest thread name, in bytes.
+pub const THREAD_NAME_LEN: usize = 24;
+
+/// Rename thread `tid`, as kernel diagnostics show it. Services may only
+/// rename themselves; names must not contain '/'.
+pub fn thread_set_name(tid: u32, name: &str) -> Result<(), i64> {
+    let ret = unsafe { syscall3::<SYS_THREAD_SET_NAME>(tid as u64, name.as_ptr() as u64, name.len() as u64) };
+    if ret < 0 { Err(ret) } else { Ok(()) }
+}
+
+/// Mirrors the kernel's syscall::SysInfo.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SysInfo {
+    pub uptime_ns: u64,
+    pub frames_total: u64,
+    pub frames_free: u64,
+    pub heap_used: u64,
+    pub heap_free: u64,
+    pub irq_count: u64,
+    pub timer_ticks: u64,
+    pub threads: u64,
+    pub ports: u64,
+}
+
+/// Machine-wide memory, interrupt and thread figures.
+pub fn sysinfo() -> Result<SysInfo, i64> {
+    let mut info = SysInfo::default();
+    let ret = unsafe { syscall3::<SYS_SYSINFO>(&mut info as *mut _ as u64, 0, 0) };
+    if ret < 0 { Err(ret) } else { Ok(info) }
+}
+
+// ThreadInfo::state values
+pub const THREAD_STATE_READY: u32 = 0;
+pub const THREAD_STATE_RUNNING: u32 = 1;
+pub const THREAD_STATE_BLOCKED: u32 = 2;
+
+/// ThreadInfo::parent of a thread nobody started.
+pub const THREAD_NO_PARENT: u32 = u32::MAX;
+pub const THREAD_INFO_NAME_LEN: usize = 56;
+/// Records one thread_info call returns at most.
+pub const THREAD_INFO_MAX: usize = 64;
+
+/// Mirrors the kernel's syscall::ThreadInfo.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ThreadInfo {
+    pub tid: u32,
+    pub parent: u32,
+    pub state: u32,
+    pub priority: u32,
+    pub cpu_ns: u64,
+    pub rss_bytes: u64,
+    pub capabilities: u32,
+    pub ports: u32,
+    pub name: [u8; THREAD_INFO_NAME_LEN],
+}
+
+impl ThreadInfo {
+    pub const EMPTY: ThreadInfo = ThreadInfo {
+        tid: 0,
+        parent: 0,
+        state: 0,
+        priority: 0,
+        cpu_ns: 0,
+        rss_bytes: 0,
+        capabilities: 0,
+        ports: 0,
+        name: [0; THREAD_INFO_NAME_LEN],
+    };
+    
+    /// "process/name", as kernel diagnostics print it.
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+}
+
+/// Fill `out` with threads from `first_tid` up, in ID order; returns how
+/// many. Page on from the last ID + 1. Services only see themselves and
+/// the threads they started.
+pub fn thread_info(first_tid: u32, out: &mut [ThreadInfo]) -> Result<usize, i64> {
+    let ret = unsafe { syscall3::<SYS_THREAD_INFO>(first_tid as u64, out.as_mut_ptr() as u64, out.len() as u64) };
+    if ret < 0 { Err(ret) } else { Ok(ret as usize) }
+}
+
+/// Control request `cmd` on the device at `path` under /dev; `arg` is
+/// the request's scalar argument. EPERM without the Device capability,
+/// ENOTTY if the device has no such request.
+pub fn device_ioctl(path: &str, cmd: u32, arg: u64) -> Result<i64, i64> {
+    let ret = unsafe { syscall4::<SYS_DEVICE_IOCTL>(path.as_ptr() as u64, path.len() as u64, cmd as u64, arg) };
+    if ret < 0 { Err(ret) } else { Ok(ret) }
+}
+
+/// Block for at least `ns` nanoseconds. EAGAIN if the kernel's timer
+/// table is full.
+pub fn nanosleep(ns: u64) -> Result<(), i64> {
+    let ret = unsafe { syscall3::<SYS_NANOSLEEP>(ns, 0, 0) };
+    if ret < 0 { Err(ret) } else { Ok(()) }
+}
+
+pub fn sleep_ms(ms: u64) -> Result<(), i64> {
+    nanosleep(ms.saturating_mul(1_000_000))
+}
+
+/// Clock IDs, numbered as on Linux.
+pub const CLOCK_REALTIME: u32 = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+
+/// Mirrors the kernel's time::Timespec.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub sec: u64,
+    pub nsec: u64,
+}
+
+/// Current time on `clock`. CLOCK_REALTIME is seconds since the Unix
+/// epoch; EAGAIN if the machine has no RTC.
+pub fn clock_gettime(clock: u32) -> Result<Timespec, i64> {
+    let mut now = Timespec::default();
+    let ret = unsafe { syscall3::<SYS_CLOCK_GETTIME>(clock as u64, &mut now as *mut _ as u64, 0) };
+    if ret < 0 { Err(ret) } else { Ok(now) }
+}
+
+pub const REBOOT_CMD_RESTART: u64 = 0x0123_4567;
+pub const REBOOT_CMD_POWER_OFF: u64 = 0x4321_FEDC;
+
+/// Reset or power off the machine (REBOOT_CMD_*). Only returns on
+/// failure; process manager only.
+pub fn reboot(cmd: u64) -> i64 {
+    unsafe { syscall3::<SYS_REBOOT>(cmd, 0, 0) }
+}
+
+/// Encoded size of a fault report message (the kernel's FaultReport):
+/// tid u32, kind u32, pc, far, esr, sp (u64 each), then a 16-byte name,
+/// all little-endian.
+pub const FAULT_REPORT_LEN: usize = 56;
+
+/// Have children killed by a fault reported to `port`, which the caller
+/// owns; 0 turns reports off.
+pub fn fault_port(port: u32) -> i64 {
+    unsafe { syscall3::<SYS_FAULT_PORT>(port as u64, 0, 0) }
+}
+
+/// Read a kernel tunable such as "sched.timeslice_ticks".
+pub fn sysctl_get(name: &str) -> Result<u64, i64> {
+    let ret = unsafe { syscall3::<SYS_SYSCTL_GET>(name.as_ptr() as u64, name.len() as u64, 0) };
+    if ret < 0 { Err(ret) } else { Ok(ret as u64) }
+}
+
+/// Change a kernel tunable; needs the Sysctl capability.
+pub fn sysctl_set(name: &str, value: u64) -> i64 {
+    unsafe { syscall3::<SYS_SYSCTL_SET>(name.as_ptr() as u64, name.len() as u64, value) }
+}
+
+/// Create a shared memory object of `pages` pages, optionally named
+/// (empty name for none). The caller may map it writable.
+pub fn shm_create(pages: usize, name: &str) -> Result<u32, i64> {
+    let ret = unsafe { syscall3::<SYS_SHM_CREATE>(pages as u64, name.as_ptr() as u64, name.len() as u64) };
+    if ret < 0 { Err(ret) } else { Ok(ret as u32) }
+}
+
+/// Id of a named object the caller already has access to.
+pub fn shm_open(name: &str) -> Result<u32, i64> {
+    let ret = unsafe { syscall3::<SYS_SHM_OPEN>(name.as_ptr() as u64, name.len() as u64, 0) };
+    if ret < 0 { Err(ret) } else { Ok(ret as u32) }
+}
+
+/// Map an object with `prot` (PROT_READ, optionally PROT_WRITE); undo
+/// with `munmap`.
+pub fn shm_map(id: u32, prot: u64) -> Result<*mut u8, i64> {
+    let ret = unsafe { syscall3::<SYS_SHM_MAP>(id as u64, prot, 0) };
+    if ret < 0 { Err(ret) } else { Ok(ret as *mut u8) }
+}
+
+/// Let `thread` map an object with up to `prot`.
+pub fn shm_grant(id: u32, thread: u32, prot: u64) -> i64 {
+    unsafe { syscall3::<SYS_SHM_GRANT>(id as u64, thread as u64, prot) }
+}
+
+/// Drop an object the caller created; mappings stay until unmapped.
+pub fn shm_destroy(id: u32) -> i64 {
+    unsafe { syscall3::<SYS_SHM_DESTROY>(id as u64, 0, 0) }
+}
+
+/// A message taken by `ipc_receive`: `len` bytes of the buffer, and the
+/// reply capability to answer with if it was a call.
+#[derive(Copy, Clone, Debug)]
+pub struct Received {
+    pub len: usize,
+    pub reply: Option<u32>,
+}
+
+fn received(ret: i64) -> Result<Received, i64> {
+    if ret < 0 {
+        return Err(ret);
+    }
+    let reply = (ret >> 16) as u32;
+    Ok(Received { len: (ret & 0xFFFF) as usize, reply: (reply != 0).then_some(reply) })
+}
+
+// The vectored calls return reply ID << 32 | full length
+fn received_vectored(ret: i64) -> Result<Received, i64> {
+    if ret < 0 {
+        return Err(ret);
+    }
+    let reply = (ret >> 32) as u32;
+    Ok(Received { len: ret as u32 as usize, reply: (reply != 0).then_some(reply) })
+}
+
+/// One buffer of a scatter-gather list. Like an iovec, it does not keep
+/// the buffer borrowed; it must outlive the call it is passed to.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IpcVec {
+    pub base: u64,
+    pub len: u64,
+}
+
+impl IpcVec {
+    /// A buffer to send from.
+    pub fn of(buf: &[u8]) -> Self {
+        IpcVec { base: buf.as_ptr() as u64, len: buf.len() as u64 }
+    }
+    
+    /// A buffer to receive into.
+    pub fn of_mut(buf: &mut [u8]) -> Self {
+        IpcVec { base: buf.as_mut_ptr() as u64, len: buf.len() as u64 }
+    }
+}
+
+/// Argument of the vectored IPC calls.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IpcMsg {
+    pub send: u64,
+    pub send_count: u64,
+    pub recv: u64,
+    pub recv_count: u64,
+}
+
+impl IpcMsg {
+    pub fn new(send: &[IpcVec], recv: &[IpcVec]) -> Self {
+        IpcMsg {
+            send: send.as_ptr() as u64,
+            send_count: send.len() as u64,
+            recv: recv.as_ptr() as u64,
+            recv_count: recv.len() as u64,
+        }
+    }
+}
+
+/// Send `buf[..len]` to `port` and wait for the answer, which replaces
+/// the buffer contents; returns its length.
+pub fn ipc_call(port: u32, buf: &mut [u8; MESSAGE_INLINE], len: usize) -> Result<usize, i64> {
+    let ret = unsafe { syscall3::<SYS_IPC_CALL>(port as u64, buf.as_mut_ptr() as u64, len as u64) };
+    if ret < 0 { Err(ret) } else { Ok(ret as usize) }
+}
+
+/// Block for the next message on `port`; one longer than the buffer is
+/// cut short.
+pub fn ipc_receive(port: u32, buf: &mut [u8; MESSAGE_INLINE]) -> Result<Received, i64> {
+    received(unsafe { syscall3::<SYS_IPC_RECEIVE>(port as u64, buf.as_mut_ptr() as u64, 0) })
+}
+
+/// Answer a call with `buf`.
+pub fn ipc_reply(reply: u32, buf: &[u8]) -> i64 {
+    unsafe { syscall3::<SYS_IPC_REPLY>(reply as u64, buf.as_ptr() as u64, buf.len() as u64) }
+}
+
+/// Answer a call with `buf[..len]` and wait for the next message on
+/// `port` in the same trip; the server loop's fast path.
+pub fn ipc_reply_receive(reply: u32, port: u32, buf: &mut [u8; MESSAGE_INLINE], len: usize) -> Result<Received, i64> {
+    let arg = (port as u64) << 32 | len as u64;
+    received(unsafe { syscall3::<SYS_IPC_REPLY>(reply as u64, buf.as_mut_ptr() as u64, arg) })
+}
+
+/// `ipc_call` with the request gathered from `send` and the answer
+/// scattered over `recv`. Returns the answer's full length, which exceeds
+/// the buffers' total if it was cut short.
+pub fn ipc_callv(port: u32, send: &[IpcVec], recv: &[IpcVec]) -> Result<usize, i64> {
+    let msg = IpcMsg::new(send, recv);
+    let ret = unsafe { syscall3::<SYS_IPC_CALLV>(port as u64, &msg as *const IpcMsg as u64, 0) };
+    if ret < 0 { Err(ret) } else { Ok(ret as usize) }
+}
+
+/// `ipc_receive` scattering the message over `recv`; `len` is its full
+/// length.
+pub fn ipc_receivev(port: u32, recv: &[IpcVec]) -> Result<Received, i64> {
+    let msg = IpcMsg::new(&[], recv);
+    received_vectored(unsafe { syscall3::<SYS_IPC_RECEIVEV>(port as u64, &msg as *const IpcMsg as u64, 0) })
+}
+
+/// `ipc_reply` gathering the answer from `send`.
+pub fn ipc_replyv(reply: u32, send: &[IpcVec]) -> i64 {
+    let msg = IpcMsg::new(send, &[]);
+    unsafe { syscall3::<SYS_IPC_REPLYV>(reply as u64, &msg as *const IpcMsg as u64, 0) }
+}
+
+/// `ipc_reply_receive` with scatter-gather lists on both sides.
+pub fn ipc_replyv_receive(reply: u32, port: u32, send: &[IpcVec], recv: &[IpcVec]) -> Result<Received, i64> {
+    let msg = IpcMsg::new(send, recv);
+    received_vectored(unsafe { syscall3::<SYS_IPC_REPLYV>(reply as u64, &msg as *const IpcMsg as u64, port as u64) })
+}
+
+/// Raise notification `bits` on `port`, waking its waiter.
+pub fn port_signal(port: u32, bits: u64) -> i64 {
+    unsafe { syscall3::<SYS_PORT_SIGNAL>(port as u64, bits, 0) }
+}
+
+/// Block until notification bits are raised on `port` and take them.
+pub fn port_wait(port: u32) -> Result<u64, i64> {
+    port_wait_flags(port, 0)
+}
+
+/// Take the raised bits without blocking; EAGAIN if there are none.
+pub fn port_poll(port: u32) -> Result<u64, i64> {
+    port_wait_flags(port, PORT_WAIT_NONBLOCK)
+}
+
+fn port_wait_flags(port: u32, flags: u64) -> Result<u64, i64> {
+    let mut bits = 0u64;
+    let ret = unsafe { syscall3::<SYS_PORT_WAIT>(port as u64, &mut bits as *mut u64 as u64, flags) };
+    if ret < 0 { Err(ret) } else { Ok(bits) }
+}
+
+/// Whether the running kernel implements syscall `number`.
+pub fn has_syscall(number: u64) -> bool {
+    syscall_bitmap(number / 64).is_ok_and(|bits| bits & (1 << (number % 64)) != 0)
+}
@@ -1,5 +1,117 @@
 #![no_std]
 
 // Process manager service - runs in userspace
+//
+// Supervision is the part implemented so far: the reference restart
+// policy for the bundled services, and the loop that turns kernel exit
+// events into respawns once their backoff has expired.
 
-// TODO: Implement process management service
\ No newline at end of file
+use userland_runtime::syscalls::{self, ExitEvent, RestartPolicy, RESTART_ALWAYS, RESTART_ON_FAILURE};
+
+/// Restart policy for a bundled service.
+pub struct ServicePolicy {
+    pub name: &'static str,
+    pub policy: RestartPolicy,
+}
+
+/// Reference policy: the memory manager is essential and always comes
+/// back; other services are restarted on failure with a bounded budget.
+pub const REFERENCE_POLICY: &[ServicePolicy] = &[
+    ServicePolicy {
+        name: "memory-manager",
+        policy: RestartPolicy {
+            mode: RESTART_ALWAYS,
+            max_restarts: 0,
+            base_delay_ms: 10,
+            max_delay_ms: 1_000,
+            stable_ms: 10_000,
+        },
+    },
+];
+
+/// Used for services the table does not name.
+pub const DEFAULT_POLICY: RestartPolicy = RestartPolicy {
+    mode: RESTART_ON_FAILURE,
+    max_restarts: 5,
+    base_delay_ms: 100,
+    max_delay_ms: 30_000,
+    stable_ms: 60_000,
+};
+
+pub fn policy_for(name: &str) -> RestartPolicy {
+    REFERENCE_POLICY
+        .iter()
+        .find(|service| service.name == name)
+        .map_or(DEFAULT_POLICY, |service| service.policy)
+}
+
+// Restarts waiting for their backoff to expire
+const MAX_PENDING: usize = 16;
+
+pub struct Supervisor {
+    next_seq: u64,
+    pending: [Option<ExitEvent>; MAX_PENDING],
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Supervisor {
+    pub const fn new() -> Self {
+        Self { next_seq: 0, pending: [None; MAX_PENDING] }
+    }
+    
+    /// Start supervising a freshly spawned service thread.
+    pub fn supervise(&self, tid: u32, name: &str) -> Result<(), i64> {
+        syscalls::service_supervise(tid, &policy_for(name))
+    }
+    
+    /// Collect new exit events and respawn every service whose backoff has
+    /// expired by `now` (counter ticks). `spawn` starts a service by name
+    /// and returns its thread. Call periodically; returns how many
+    /// services were respawned.
+    pub fn poll(&mut self, now: u64, mut spawn: impl FnMut(&str) -> Result<u32, i64>) -> usize {
+        let mut events = [ExitEvent::default(); 8];
+        while let Ok(count) = syscalls::service_events(self.next_seq, &mut events) {
+            for event in &events[..count] {
+                self.next_seq = event.seq + 1;
+                if event.restart_at != 0 {
+                    if let Some(slot) = self.pending.iter_mut().find(|slot| slot.is_none()) {
+                        *slot = Some(*event);
+                    }
+                }
+            }
+            if count < events.len() {
+                break;
+            }
+        }
+        
+        let mut respawned = 0;
+        for slot in self.pending.iter_mut() {
+            let Some(event) = *slot else { continue };
+            if now < event.restart_at {
+                continue;
+            }
+            let name = service_name(&event);
+            let Ok(tid) = spawn(name) else { continue };
+            *slot = None;
+            respawned += 1;
+            
+            // Losing the old record (e.g. supervision dropped meanwhile)
+            // leaves the replacement without its capabilities, but it
+            // still runs under a fresh policy
+            if syscalls::service_respawned(event.tid, tid).is_err() {
+                let _ = syscalls::service_supervise(tid, &policy_for(name));
+            }
+        }
+        respawned
+    }
+}
+
+fn service_name(event: &ExitEvent) -> &str {
+    let len = event.name.iter().position(|&b| b == 0).unwrap_or(event.name.len());
+    core::str::from_utf8(&event.name[..len]).unwrap_or("")
+}
@@ -0,0 +1,405 @@
+// The test cases, one group per area of the ABI
+//
+// Calls go through syscall3 directly wherever the wrappers in
+// userland_runtime would check or reshape the arguments first: the point
+// is what the kernel does with them.
+
+use userland_runtime::start::{AT_ENTRY, AT_PAGESZ, AT_RANDOM};
+use userland_runtime::syscalls::*;
+use userland_runtime::StartupInfo;
+use crate::report::Report;
+
+pub struct Group {
+    pub name: &'static str,
+    pub run: fn(&mut Report, &StartupInfo),
+}
+
+pub static GROUPS: &[Group] = &[
+    Group { name: "startup", run: startup },
+    Group { name: "abi", run: abi },
+    Group { name: "privilege", run: privilege },
+    Group { name: "debug_write", run: debug_write_cases },
+    Group { name: "mmap", run: mmap_cases },
+    Group { name: "ring", run: ring },
+    Group { name: "thread", run: thread },
+    Group { name: "time", run: time },
+    Group { name: "sysctl", run: sysctl },
+    Group { name: "sysinfo", run: sysinfo_cases },
+    Group { name: "shm", run: shm },
+    Group { name: "port", run: port },
+    Group { name: "ipc", run: ipc },
+    Group { name: "device", run: device },
+    // Narrows this binary's own filter, so it runs last
+    Group { name: "filter", run: filter },
+];
+
+const PAGE_SIZE: usize = 4096;
+
+// Addresses no user buffer may name: the kernel half, the null page, and
+// the top of the user half, which nothing maps
+const KERNEL_ADDR: u64 = 0xFFFF_0000_4000_0000;
+const NULL_PAGE_ADDR: u64 = 0x10;
+const UNMAPPED_ADDR: u64 = 0x0000_7FFF_FFFF_F000;
+
+// No thread or port has these IDs
+const NO_SUCH_ID: u64 = 0xFFFF_FF00;
+// Does not fit the 32-bit ID the calls take
+const OVERSIZED_ID: u64 = 1 << 32;
+
+// Thread the tests may not touch: the boot thread
+const OTHER_THREAD: u64 = 0;
+
+const THREAD_NAME: &str = "abi-conformance";
+
+fn result<T>(result: Result<T, i64>) -> i64 {
+    result.map_or_else(|errno| errno, |_| 0)
+}
+
+fn startup(report: &mut Report, info: &StartupInfo) {
+    extern "C" {
+        fn _start();
+    }
+    
+    report.check("argc", info.argc() >= 1, format_args!("argc {}", info.argc()));
+    report.check("argv0", info.arg(0).is_some_and(|arg| !arg.is_empty()), format_args!("argv[0] missing"));
+    report.check("argv_end", info.args().count() == info.argc(), format_args!("argv shorter than argc"));
+    let page = info.aux(AT_PAGESZ);
+    report.check("at_pagesz", page == Some(PAGE_SIZE as u64), format_args!("AT_PAGESZ {:?}", page));
+    let entry = info.aux(AT_ENTRY);
+    report.check("at_entry", entry == Some(_start as *const () as u64), format_args!("AT_ENTRY {:x?}", entry));
+    let random = info.aux(AT_RANDOM).map(|addr| unsafe { core::ptr::read(addr as *const [u8; 16]) });
+    report.check("at_random", random.is_some_and(|bytes| bytes != [0; 16]), format_args!("AT_RANDOM {:?}", random));
+}
+
+fn abi(report: &mut Report, _info: &StartupInfo) {
+    report.returns("version", abi_version() as i64, SYSCALL_ABI_VERSION as i64);
+    
+    // Every call this binary knows of is implemented
+    let known = (1u64 << (SYS_SYSCALL_FILTER + 1)) - 1;
+    let bits = unsafe { syscall3::<SYS_SYSCALL_BITMAP>(0, 0, 0) } as u64;
+    report.check("bitmap_known", bits & known == known, format_args!("word 0 is {:#x}", bits));
+    let last = MAX_SYSCALLS / 64 - 1;
+    report.returns("bitmap_last_word", unsafe { syscall3::<SYS_SYSCALL_BITMAP>(last, 0, 0) }, 0);
+    report.returns("bitmap_past_end", unsafe { syscall3::<SYS_SYSCALL_BITMAP>(last + 1, 0, 0) }, EINVAL);
+    report.returns("bitmap_huge_word", unsafe { syscall3::<SYS_SYSCALL_BITMAP>(u64::MAX, 0, 0) }, EINVAL);
+    
+    report.returns("unknown_call", unsafe { syscall3::<200>(0, 0, 0) }, ENOSYS);
+    report.returns("last_number", unsafe { syscall3::<{ MAX_SYSCALLS - 1 }>(0, 0, 0) }, ENOSYS);
+    report.check("unknown_not_in_bitmap", !has_syscall(200), format_args!("bit 200 set"));
+}
+
+// Privileged calls refuse an unprivileged caller before looking at their
+// arguments, which are invalid here in case they did not
+fn privilege(report: &mut Report, _info: &StartupInfo) {
+    unsafe {
+        report.returns("audit_read", syscall3::<SYS_AUDIT_READ>(0, 0, 0), EPERM);
+        report.returns("oom_policy", syscall3::<SYS_OOM_POLICY>(NO_SUCH_ID, 0, 0), EPERM);
+        report.returns("service_supervise", syscall3::<SYS_SERVICE_SUPERVISE>(NO_SUCH_ID, 0, 0), EPERM);
+        report.returns("service_events", syscall3::<SYS_SERVICE_EVENTS>(0, 0, 0), EPERM);
+        report.returns("service_respawned", syscall3::<SYS_SERVICE_RESPAWNED>(NO_SUCH_ID, NO_SUCH_ID, 0), EPERM);
+        report.returns("reboot", syscall3::<SYS_REBOOT>(0, 0, 0), EPERM);
+        report.returns("profile_control", syscall3::<SYS_PROFILE_CONTROL>(u64::MAX, 0, 0), EPERM);
+        report.returns("profile_read", syscall3::<SYS_PROFILE_READ>(0, 0, 0), EPERM);
+        report.returns("clock_control", syscall3::<SYS_CLOCK_CONTROL>(u64::MAX, 0, 0), EPERM);
+    }
+}
+
+fn debug_write_cases(report: &mut Report, _info: &StartupInfo) {
+    let text = b"abi-conformance: debug_write\n";
+    let raw = |ptr: u64, len: usize| unsafe { syscall3::<SYS_DEBUG_WRITE>(ptr, len as u64, 0) };
+    
+    report.returns("whole", raw(text.as_ptr() as u64, text.len()), text.len() as i64);
+    report.returns("empty", raw(text.as_ptr() as u64, 0), 0);
+    report.returns("null", raw(0, 8), EFAULT);
+    report.returns("null_page", raw(NULL_PAGE_ADDR, 8), EFAULT);
+    report.returns("kernel", raw(KERNEL_ADDR, 8), EFAULT);
+    report.returns("unmapped", raw(UNMAPPED_ADDR, 8), EFAULT);
+    report.returns("wraps", raw(u64::MAX - 3, 8), EFAULT);
+    
+    // At most 1024 bytes go out per call
+    let spaces = [b' '; 1100];
+    report.returns("capped", raw(spaces.as_ptr() as u64, spaces.len()), 1024);
+    let _ = debug_write(b"\n");
+    
+    // A buffer running off the end of a mapping is refused whole
+    match mmap(PAGE_SIZE, PROT_READ | PROT_WRITE) {
+        Ok(page) => {
+            let tail = page as u64 + PAGE_SIZE as u64 - 4;
+            report.returns("past_mapping", raw(tail, 8), EFAULT);
+            let _ = munmap(page, PAGE_SIZE);
+        }
+        Err(errno) => report.succeeds("past_mapping", errno),
+    }
+}
+
+fn mmap_cases(report: &mut Report, info: &StartupInfo) {
+    let raw = |len: u64, prot: u64| unsafe { syscall3::<SYS_MMAP>(len, prot, 0) };
+    
+    report.returns("zero_length", raw(0, PROT_READ), EINVAL);
+    report.returns("no_read", raw(PAGE_SIZE as u64, PROT_WRITE), EINVAL);
+    report.returns("unknown_prot", raw(PAGE_SIZE as u64, PROT_READ | 1 << 3), EINVAL);
+    report.returns("write_exec", raw(PAGE_SIZE as u64, PROT_READ | PROT_WRITE | PROT_EXEC), EPERM);
+    report.returns("too_large", raw(1 << 40, PROT_READ), ENOMEM);
+    
+    // Lengths round up to whole pages of zeroes
+    let len = PAGE_SIZE + 1;
+    let addr = raw(len as u64, PROT_READ | PROT_WRITE);
+    report.succeeds("read_write", addr);
+    if addr >= 0 {
+        report.check("page_aligned", (addr as usize).is_multiple_of(PAGE_SIZE), format_args!("address {:#x}", addr));
+        let pages = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, 2 * PAGE_SIZE) };
+        report.check("zeroed", pages.iter().all(|&b| b == 0), format_args!("nonzero bytes"));
+        pages[2 * PAGE_SIZE - 1] = 0xA5;
+        report.check("writable", pages[2 * PAGE_SIZE - 1] == 0xA5, format_args!("write lost"));
+        
+        let unmap = |addr: u64, len: usize| unsafe { syscall3::<SYS_MUNMAP>(addr, len as u64, 0) };
+        report.returns("munmap_unaligned", unmap(addr as u64 + 1, len), EINVAL);
+        report.returns("munmap", unmap(addr as u64, len), 0);
+        report.returns("munmap_twice", unmap(addr as u64, len), EINVAL);
+    }
+    
+    let code = raw(PAGE_SIZE as u64, PROT_READ | PROT_EXEC);
+    report.succeeds("read_exec", code);
+    if code >= 0 {
+        let _ = munmap(code as *mut u8, PAGE_SIZE);
+    }
+    
+    // Only anonymous mappings can be unmapped: not the program image
+    let image = info.aux(AT_ENTRY).unwrap_or(0) & !(PAGE_SIZE as u64 - 1);
+    report.returns("munmap_image", unsafe { syscall3::<SYS_MUNMAP>(image, PAGE_SIZE as u64, 0) }, EINVAL);
+}
+
+fn ring(report: &mut Report, _info: &StartupInfo) {
+    let setup = |entries: u64| unsafe { syscall3::<SYS_RING_SETUP>(entries, 0, 0) };
+    
+    report.returns("enter_without_ring", unsafe { syscall3::<SYS_RING_ENTER>(0, 0, 0) }, EINVAL);
+    report.returns("zero_entries", setup(0), EINVAL);
+    report.returns("not_power_of_two", setup(3), EINVAL);
+    report.returns("too_many_entries", setup(512), EINVAL);
+    report.succeeds("setup", setup(8));
+    report.returns("setup_twice", setup(8), EBUSY);
+    report.returns("enter_empty", unsafe { syscall3::<SYS_RING_ENTER>(0, 0, 0) }, 0);
+}
+
+fn thread(report: &mut Report, _info: &StartupInfo) {
+    let priority = |tid: u64, priority: u64| unsafe { syscall3::<SYS_SET_PRIORITY>(tid, priority, 0) };
+    let me = THREAD_SELF as u64;
+    
+    report.returns("priority_out_of_range", priority(me, 256), EINVAL);
+    report.returns("priority_raise", priority(me, PRIORITY_MAX as u64), EPERM);
+    report.returns("priority_other_thread", priority(OTHER_THREAD, 0), EPERM);
+    
+    let name = |tid: u64, ptr: u64, len: usize| unsafe { syscall3::<SYS_THREAD_SET_NAME>(tid, ptr, len as u64) };
+    let set = |text: &[u8]| name(me, text.as_ptr() as u64, text.len());
+    report.returns("name", set(THREAD_NAME.as_bytes()), 0);
+    report.returns("name_longest", set(&[b'n'; THREAD_NAME_LEN]), 0);
+    report.returns("name_too_long", set(&[b'n'; THREAD_NAME_LEN + 1]), EINVAL);
+    report.returns("name_empty", set(b""), EINVAL);
+    report.returns("name_slash", set(b"abi/conformance"), EINVAL);
+    report.returns("name_not_utf8", set(b"abi\xff"), EINVAL);
+    report.returns("name_kernel_pointer", name(me, KERNEL_ADDR, 4), EFAULT);
+    report.returns("name_other_thread", name(OTHER_THREAD, THREAD_NAME.as_ptr() as u64, THREAD_NAME.len()), EPERM);
+    let _ = set(THREAD_NAME.as_bytes());
+}
+
+fn time(report: &mut Report, _info: &StartupInfo) {
+    let raw = |clock: u32, ptr: u64| unsafe { syscall3::<SYS_CLOCK_GETTIME>(clock as u64, ptr, 0) };
+    
+    let first = clock_gettime(CLOCK_MONOTONIC);
+    report.succeeds("monotonic", result(first));
+    if let Ok(now) = first {
+        report.check("nsec_in_range", now.nsec < 1_000_000_000, format_args!("nsec {}", now.nsec));
+    }
+    let realtime = raw(CLOCK_REALTIME, &mut Timespec::default() as *mut Timespec as u64);
+    report.check("realtime", realtime == 0 || realtime == EAGAIN, format_args!("got {}", realtime));
+    
+    report.returns("unknown_clock", raw(2, &mut Timespec::default() as *mut Timespec as u64), EINVAL);
+    report.returns("null", raw(CLOCK_MONOTONIC, 0), EFAULT);
+    report.returns("kernel", raw(CLOCK_MONOTONIC, KERNEL_ADDR), EFAULT);
+    match mmap(PAGE_SIZE, PROT_READ) {
+        Ok(page) => {
+            report.returns("read_only", raw(CLOCK_MONOTONIC, page as u64), EFAULT);
+            let _ = munmap(page, PAGE_SIZE);
+        }
+        Err(errno) => report.succeeds("read_only", errno),
+    }
+    
+    // Sleeps last at least as long as asked
+    report.returns("sleep_zero", unsafe { syscall3::<SYS_NANOSLEEP>(0, 0, 0) }, 0);
+    const SLEEP_NS: u64 = 2_000_000;
+    let before = clock_gettime(CLOCK_MONOTONIC);
+    report.returns("sleep", result(nanosleep(SLEEP_NS)), 0);
+    let after = clock_gettime(CLOCK_MONOTONIC);
+    if let (Ok(before), Ok(after)) = (before, after) {
+        let ns = |t: Timespec| t.sec * 1_000_000_000 + t.nsec;
+        let slept = ns(after).saturating_sub(ns(before));
+        report.check("sleep_duration", slept >= SLEEP_NS, format_args!("slept {} ns", slept));
+    }
+}
+
+fn sysctl(report: &mut Report, _info: &StartupInfo) {
+    // Longest name accepted (kernel SYSCTL_NAME_MAX)
+    const NAME_MAX: usize = 64;
+    const EXISTING: &str = "kernel.log_level";
+    let get = |name: &[u8]| unsafe { syscall3::<SYS_SYSCTL_GET>(name.as_ptr() as u64, name.len() as u64, 0) };
+    let set = |name: &[u8], value: u64| unsafe {
+        syscall3::<SYS_SYSCTL_SET>(name.as_ptr() as u64, name.len() as u64, value)
+    };
+    
+    let value = get(EXISTING.as_bytes());
+    report.succeeds("get", value);
+    report.returns("get_unknown", get(b"abi.no_such_setting"), ENOENT);
+    report.returns("get_name_too_long", get(&[b'a'; NAME_MAX + 1]), EINVAL);
+    report.returns("get_not_utf8", get(b"kernel.\xff"), EINVAL);
+    report.returns("get_kernel_pointer", unsafe { syscall3::<SYS_SYSCTL_GET>(KERNEL_ADDR, 8, 0) }, EFAULT);
+    report.returns("set_unknown", set(b"abi.no_such_setting", 0), ENOENT);
+    report.returns("set_unprivileged", set(EXISTING.as_bytes(), value.max(0) as u64), EPERM);
+}
+
+fn sysinfo_cases(report: &mut Report, _info: &StartupInfo) {
+    let info = sysinfo();
+    report.succeeds("sysinfo", result(info));
+    if let Ok(info) = info {
+        report.check("frames", info.frames_free <= info.frames_total && info.frames_total > 0,
+                     format_args!("{} of {} free", info.frames_free, info.frames_total));
+        report.check("threads", info.threads > 0, format_args!("{} threads", info.threads));
+    }
+    report.returns("sysinfo_null", unsafe { syscall3::<SYS_SYSINFO>(0, 0, 0) }, EFAULT);
+    report.returns("sysinfo_kernel", unsafe { syscall3::<SYS_SYSINFO>(KERNEL_ADDR, 0, 0) }, EFAULT);
+    
+    // An unprivileged caller sees itself, running, and not the boot thread
+    let mut threads = [ThreadInfo::EMPTY; 8];
+    let listed = thread_info(0, &mut threads);
+    report.succeeds("thread_info", result(listed));
+    let listed = &threads[..listed.unwrap_or(0)];
+    let me = listed.iter().find(|thread| thread.name().ends_with(THREAD_NAME));
+    report.check("thread_info_self", me.is_some_and(|me| me.state == THREAD_STATE_RUNNING),
+                 format_args!("{:?}", me));
+    report.check("thread_info_hidden", listed.iter().all(|thread| thread.tid as u64 != OTHER_THREAD),
+                 format_args!("boot thread listed"));
+    report.returns("thread_info_kernel", unsafe { syscall3::<SYS_THREAD_INFO>(0, KERNEL_ADDR, 1) }, EFAULT);
+}
+
+fn shm(report: &mut Report, _info: &StartupInfo) {
+    // Kernel limits: SHM_MAX_PAGES and SHM_NAME_MAX
+    const MAX_PAGES: u64 = 1024;
+    const NAME_MAX: usize = 32;
+    const NAME: &[u8] = b"abi-conformance";
+    let create = |pages: u64, name: &[u8]| unsafe {
+        syscall3::<SYS_SHM_CREATE>(pages, name.as_ptr() as u64, name.len() as u64)
+    };
+    let open = |name: &[u8]| unsafe { syscall3::<SYS_SHM_OPEN>(name.as_ptr() as u64, name.len() as u64, 0) };
+    let map = |id: i64, prot: u64| unsafe { syscall3::<SYS_SHM_MAP>(id as u64, prot, 0) };
+    let destroy = |id: i64| unsafe { syscall3::<SYS_SHM_DESTROY>(id as u64, 0, 0) };
+    
+    report.returns("zero_pages", create(0, b""), EINVAL);
+    report.returns("too_many_pages", create(MAX_PAGES + 1, b""), EINVAL);
+    report.returns("name_too_long", create(1, &[b's'; NAME_MAX + 1]), EINVAL);
+    report.returns("open_empty_name", open(b""), EINVAL);
+    report.returns("open_unknown", open(b"abi.no_such_object"), ENOENT);
+    report.returns("map_unknown", map(NO_SUCH_ID as i64, PROT_READ), EPERM);
+    
+    let id = create(1, b"");
+    report.succeeds("create", id);
+    if id >= 0 {
+        report.returns("map_write_only", map(id, PROT_WRITE), EINVAL);
+        report.returns("map_exec", map(id, PROT_READ | PROT_EXEC), EINVAL);
+        let addr = map(id, PROT_READ | PROT_WRITE);
+        report.succeeds("map", addr);
+        if addr >= 0 {
+            let page = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, PAGE_SIZE) };
+            report.check("map_zeroed", page.iter().all(|&b| b == 0), format_args!("nonzero bytes"));
+            page[0] = 0x5A;
+            report.check("map_writable", page[0] == 0x5A, format_args!("write lost"));
+            let _ = munmap(addr as *mut u8, PAGE_SIZE);
+        }
+        let grant = unsafe { syscall3::<SYS_SHM_GRANT>(id as u64, NO_SUCH_ID, PROT_READ) };
+        report.returns("grant_no_such_thread", grant, ENOENT);
+        report.returns("destroy", destroy(id), 0);
+        report.returns("destroy_twice", destroy(id), ENOENT);
+    }
+    
+    let named = create(1, NAME);
+    report.succeeds("create_named", named);
+    if named >= 0 {
+        report.returns("create_name_in_use", create(1, NAME), EEXIST);
+        report.returns("open", open(NAME), named);
+        let _ = destroy(named);
+    }
+}
+
+fn port(report: &mut Report, _info: &StartupInfo) {
+    let mut bits = 0u64;
+    let bits_ptr = &mut bits as *mut u64 as u64;
+    let wait = |port: u64, ptr: u64, flags: u64| unsafe { syscall3::<SYS_PORT_WAIT>(port, ptr, flags) };
+    
+    report.returns("signal_oversized_id", unsafe { syscall3::<SYS_PORT_SIGNAL>(OVERSIZED_ID, 1, 0) }, ENOENT);
+    report.returns("signal_no_capability", unsafe { syscall3::<SYS_PORT_SIGNAL>(NO_SUCH_ID, 1, 0) }, EPERM);
+    report.returns("wait_oversized_id", wait(OVERSIZED_ID, bits_ptr, PORT_WAIT_NONBLOCK), ENOENT);
+    report.returns("wait_unknown_flags", wait(NO_SUCH_ID, bits_ptr, 1 << 1), EINVAL);
+    report.returns("wait_null", wait(NO_SUCH_ID, 0, PORT_WAIT_NONBLOCK), EFAULT);
+    report.returns("wait_no_capability", wait(NO_SUCH_ID, bits_ptr, PORT_WAIT_NONBLOCK), EPERM);
+    
+    let fault = |port: u64| unsafe { syscall3::<SYS_FAULT_PORT>(port, 0, 0) };
+    report.returns("fault_port_oversized_id", fault(OVERSIZED_ID), ENOENT);
+    report.returns("fault_port_unknown", fault(NO_SUCH_ID), ENOENT);
+    report.returns("fault_port_off", fault(0), 0);
+}
+
+fn ipc(report: &mut Report, _info: &StartupInfo) {
+    let mut buf = [0u8; MESSAGE_INLINE];
+    let buf_ptr = buf.as_mut_ptr() as u64;
+    let empty = IpcMsg::default();
+    let empty_ptr = &empty as *const IpcMsg as u64;
+    
+    // Without a capability for the port nothing else is looked at
+    unsafe {
+        report.returns("call_oversized_id", syscall3::<SYS_IPC_CALL>(OVERSIZED_ID, buf_ptr, 0), ENOENT);
+        report.returns("call_no_capability", syscall3::<SYS_IPC_CALL>(NO_SUCH_ID, buf_ptr, 0), EPERM);
+        report.returns("receive_no_capability", syscall3::<SYS_IPC_RECEIVE>(NO_SUCH_ID, buf_ptr, 0), EPERM);
+        report.returns("callv_no_capability", syscall3::<SYS_IPC_CALLV>(NO_SUCH_ID, empty_ptr, 0), EPERM);
+        report.returns("receivev_no_capability", syscall3::<SYS_IPC_RECEIVEV>(NO_SUCH_ID, empty_ptr, 0), EPERM);
+    }
+    
+    let reply = |id: u64, ptr: u64, arg: u64| unsafe { syscall3::<SYS_IPC_REPLY>(id, ptr, arg) };
+    report.returns("reply_oversized_id", reply(OVERSIZED_ID, buf_ptr, 0), ENOENT);
+    report.returns("reply_unknown", reply(NO_SUCH_ID, buf_ptr, 0), ENOENT);
+    report.returns("reply_too_long", reply(NO_SUCH_ID, buf_ptr, MESSAGE_MAX as u64 + 1), EINVAL);
+    report.returns("reply_kernel_buffer", reply(NO_SUCH_ID, KERNEL_ADDR, 8), EFAULT);
+    report.returns("reply_receive_no_capability", reply(NO_SUCH_ID, buf_ptr, NO_SUCH_ID << 32), EPERM);
+    
+    let replyv = |msg: &IpcMsg| unsafe { syscall3::<SYS_IPC_REPLYV>(NO_SUCH_ID, msg as *const IpcMsg as u64, 0) };
+    report.returns("replyv_unknown", replyv(&empty), ENOENT);
+    report.returns("replyv_null_msg", unsafe { syscall3::<SYS_IPC_REPLYV>(NO_SUCH_ID, 0, 0) }, EFAULT);
+    report.returns("replyv_oversized_port", unsafe { syscall3::<SYS_IPC_REPLYV>(NO_SUCH_ID, empty_ptr, OVERSIZED_ID) }, ENOENT);
+    let vecs = [IpcVec::of(&buf); IPC_VEC_MAX + 1];
+    report.returns("replyv_too_many_vecs", replyv(&IpcMsg::new(&vecs, &[])), EINVAL);
+    let kernel = [IpcVec { base: KERNEL_ADDR, len: 8 }];
+    report.returns("replyv_kernel_vec", replyv(&IpcMsg::new(&kernel, &[])), EFAULT);
+    let wrapping = [IpcVec { base: buf_ptr, len: u64::MAX }, IpcVec { base: buf_ptr, len: 2 }];
+    report.returns("replyv_length_overflow", replyv(&IpcMsg::new(&wrapping, &[])), EINVAL);
+    let halves = [IpcVec { base: buf_ptr, len: MESSAGE_MAX as u64 / 2 + 1 }; 2];
+    report.returns("replyv_too_long", replyv(&IpcMsg::new(&halves, &[])), EINVAL);
+}
+
+// Without the Device capability every request is refused before its path
+// is looked at
+fn device(report: &mut Report, _info: &StartupInfo) {
+    report.returns("missing", result(device_ioctl("/dev/abi-conformance", 0, 0)), EPERM);
+    report.returns("outside_dev", result(device_ioctl("/etc/abi-conformance", 0, 0)), EPERM);
+    report.returns("null", unsafe { syscall4::<SYS_DEVICE_IOCTL>(0, 8, 0, 0) }, EPERM);
+    report.returns("too_long", unsafe { syscall4::<SYS_DEVICE_IOCTL>(0, 4096, 0, 0) }, EPERM);
+}
+
+// Only the unimplemented top word is filtered, which every group before
+// has finished with
+fn filter(report: &mut Report, _info: &StartupInfo) {
+    let last = MAX_SYSCALLS / 64 - 1;
+    report.returns("word_past_end", result(syscall_filter(last + 1, 0)), EINVAL);
+    report.returns("unknown_before", unsafe { syscall3::<200>(0, 0, 0) }, ENOSYS);
+    report.succeeds("narrow", result(syscall_filter(last, 0)));
+    report.returns("unknown_filtered", unsafe { syscall3::<200>(0, 0, 0) }, EPERM);
+    report.succeeds("widen_ignored", result(syscall_filter(last, u64::MAX)));
+    report.returns("still_filtered", unsafe { syscall3::<200>(0, 0, 0) }, EPERM);
+    report.returns("unfiltered_word", abi_version() as i64, SYSCALL_ABI_VERSION as i64);
+}
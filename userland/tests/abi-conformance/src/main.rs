@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+// Syscall ABI conformance tests
+//
+// Runs as an ordinary user program, unprivileged and holding no
+// capabilities, and checks each syscall's documented results, error codes
+// and boundary conditions against the comments in kernel/src/syscall.rs.
+// tools/abi_test.py boots the kernel with this binary in the initramfs,
+// starts it from the shell and reads the report off the serial console,
+// one line per case:
+//
+//     ABI PASS <group>.<case>
+//     ABI FAIL <group>.<case>: <what went wrong>
+//     ABI DONE <passed> <failed>
+//
+// Arguments name the groups to run; with none, all of them run. The exit
+// status is the number of failures.
+
+mod cases;
+mod report;
+
+use report::Report;
+use userland_runtime::StartupInfo;
+
+userland_runtime::entry!(main);
+
+fn main(info: &StartupInfo) -> i32 {
+    let mut report = Report::new();
+    let selected = |name: &str| info.argc() <= 1 || info.args().skip(1).any(|arg| arg == name);
+    for group in cases::GROUPS.iter().filter(|group| selected(group.name)) {
+        report.begin(group.name);
+        (group.run)(&mut report, info);
+    }
+    report.finish()
+}
@@ -0,0 +1,102 @@
+// Result lines for tools/abi_test.py
+//
+// Each line goes out in a single debug_write so kernel log output cannot
+// land in the middle of it. Formatting uses a buffer on the stack rather
+// than the heap, which would take mmap calls of its own.
+
+use core::fmt::{self, Write};
+use userland_runtime::syscalls::*;
+
+const LINE_MAX: usize = 256;
+
+struct Line {
+    buf: [u8; LINE_MAX],
+    len: usize,
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Leave room for the newline; an overlong line is cut short
+        let room = LINE_MAX - 1 - self.len;
+        let take = s.len().min(room);
+        self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
+
+fn emit(args: fmt::Arguments) {
+    let mut line = Line { buf: [0; LINE_MAX], len: 0 };
+    let _ = line.write_fmt(args);
+    line.buf[line.len] = b'\n';
+    let _ = debug_write(&line.buf[..line.len + 1]);
+}
+
+/// Name of an errno, or the number for anything else.
+struct Errno(i64);
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.0 {
+            EPERM => "EPERM",
+            ENOENT => "ENOENT",
+            EIO => "EIO",
+            EAGAIN => "EAGAIN",
+            ENOMEM => "ENOMEM",
+            EFAULT => "EFAULT",
+            EBUSY => "EBUSY",
+            EEXIST => "EEXIST",
+            ENOTDIR => "ENOTDIR",
+            EISDIR => "EISDIR",
+            EINVAL => "EINVAL",
+            ENOSPC => "ENOSPC",
+            EROFS => "EROFS",
+            ENOSYS => "ENOSYS",
+            value => return write!(f, "{}", value),
+        };
+        f.write_str(name)
+    }
+}
+
+pub struct Report {
+    group: &'static str,
+    passed: u32,
+    failed: u32,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self { group: "", passed: 0, failed: 0 }
+    }
+    
+    pub fn begin(&mut self, group: &'static str) {
+        self.group = group;
+    }
+    
+    /// Pass if `ok`; otherwise fail with `detail`.
+    pub fn check(&mut self, case: &str, ok: bool, detail: fmt::Arguments) {
+        if ok {
+            self.passed += 1;
+            emit(format_args!("ABI PASS {}.{}", self.group, case));
+        } else {
+            self.failed += 1;
+            emit(format_args!("ABI FAIL {}.{}: {}", self.group, case, detail));
+        }
+    }
+    
+    /// The call returned exactly `want` (an errno, or a value).
+    pub fn returns(&mut self, case: &str, got: i64, want: i64) {
+        self.check(case, got == want, format_args!("expected {}, got {}", Errno(want), Errno(got)));
+    }
+    
+    /// The call succeeded: a result of 0 or more.
+    pub fn succeeds(&mut self, case: &str, got: i64) {
+        self.check(case, got >= 0, format_args!("expected success, got {}", Errno(got)));
+    }
+    
+    /// Print the summary; the exit status is the failure count.
+    pub fn finish(self) -> i32 {
+        emit(format_args!("ABI DONE {} {}", self.passed, self.failed));
+        self.failed.min(255) as i32
+    }
+}